@@ -0,0 +1,76 @@
+//! Parser throughput, the parser of `MidiIn` against the `midi-convert` parser it replaced
+//!
+//! `parser` and `midi_convert` time only the parsers on the same byte streams. `midi_in` times the
+//! full read path, which adds the serial read, system common tracking and family filtering.
+
+use core::convert::Infallible;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use embedded_hal_nb::serial;
+use embedded_midi::{parse, MidiIn};
+use midi_convert::parse::MidiParser;
+
+/// Serial receiver endlessly repeating a byte stream
+struct Loop {
+    bytes: &'static [u8],
+    position: usize,
+}
+
+impl serial::ErrorType for Loop {
+    type Error = Infallible;
+}
+
+impl serial::Read<u8> for Loop {
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        let byte = self.bytes[self.position];
+        self.position = (self.position + 1) % self.bytes.len();
+        Ok(byte)
+    }
+}
+
+/// Note ons using running status, the common case
+const NOTES: &[u8] = &[0x90, 0x40, 0x7f, 0x41, 0x7f, 0x42, 0x7f, 0x43, 0x00];
+
+/// A mix of channel voice, system common and real time messages
+const MIXED: &[u8] = &[
+    0x90, 0x40, 0x7f, 0xf8, 0xb1, 0x07, 0x64, 0xe2, 0x00, 0x40, 0xc3, 0x05, 0xf2, 0x10, 0x20, 0xd4,
+    0x30, 0xf8, 0x80, 0x40, 0x00,
+];
+
+fn parse(c: &mut Criterion) {
+    for (name, bytes) in [("notes", NOTES), ("mixed", MIXED)] {
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+
+        group.bench_function("parser", |b| {
+            let mut parser = parse::MidiParser::new();
+            b.iter(|| {
+                for byte in bytes {
+                    black_box(parser.parse(black_box(*byte)));
+                }
+            })
+        });
+
+        group.bench_function("midi_in", |b| {
+            let mut midi_in = MidiIn::new(Loop { bytes, position: 0 });
+            b.iter(|| {
+                for _ in 0..bytes.len() {
+                    let _ = black_box(midi_in.read());
+                }
+            })
+        });
+
+        group.bench_function("midi_convert", |b| {
+            let mut parser = MidiParser::new();
+            b.iter(|| {
+                for byte in bytes {
+                    black_box(parser.parse(black_box(*byte)));
+                }
+            })
+        });
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -2,8 +2,7 @@
 #![no_std]
 
 use cortex_m_rt::entry;
-use embedded_midi::{MidiIn, MidiOut};
-use nb::block;
+use embedded_midi::{pipelines::passthrough_poll, Eh02Rx, Eh02Tx, MidiIn, MidiOut};
 use stm32f1xx_hal::{
     pac,
     prelude::*,
@@ -42,12 +41,10 @@ fn main() -> ! {
     // Configure Midi
     let (tx, rx) = usart.split();
 
-    let mut midi_in = MidiIn::new(rx);
-    let mut midi_out = MidiOut::new(tx);
+    let mut midi_in = MidiIn::new(Eh02Rx::new(rx));
+    let mut midi_out = MidiOut::new(Eh02Tx::new(tx));
 
     loop {
-        if let Ok(event) = block!(midi_in.read()) {
-            midi_out.write(&event).ok();
-        }
+        passthrough_poll(&mut midi_in, &mut midi_out, Some).ok();
     }
 }
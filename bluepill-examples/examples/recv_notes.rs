@@ -2,9 +2,8 @@
 #![no_std]
 
 use cortex_m_rt::entry;
-use cortex_m_semihosting::hprintln;
-use embedded_midi::MidiIn;
-use nb::block;
+use cortex_m_semihosting::hio;
+use embedded_midi::{pipelines::monitor_poll, Eh02Rx, MidiIn};
 use panic_semihosting as _;
 use stm32f1xx_hal::{
     pac,
@@ -41,10 +40,10 @@ fn main() -> ! {
 
     // Configure Midi
     let (_tx, rx) = usart.split();
-    let mut midi_in = MidiIn::new(rx);
+    let mut midi_in = MidiIn::new(Eh02Rx::new(rx));
+    let mut stdout = hio::hstdout().unwrap();
 
     loop {
-        let event = block!(midi_in.read());
-        hprintln!("event {:?}", event).ok();
+        monitor_poll(&mut midi_in, &mut stdout).ok();
     }
 }
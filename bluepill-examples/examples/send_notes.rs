@@ -2,10 +2,13 @@
 #![no_std]
 
 use cortex_m_rt::entry;
-use cortex_m_semihosting::hprintln;
-use embedded_midi::{MidiMessage, MidiOut};
+use embedded_midi::{
+    pipelines::{metronome_poll, Click},
+    Eh02Tx, MidiOut,
+};
 use panic_semihosting as _;
 use stm32f1xx_hal::{
+    delay::Delay,
     pac,
     prelude::*,
     serial::{Config, Serial},
@@ -14,6 +17,7 @@ use stm32f1xx_hal::{
 #[entry]
 fn main() -> ! {
     let dp = pac::Peripherals::take().unwrap();
+    let cp = pac::CorePeripherals::take().unwrap();
 
     // Configure the clock
     let mut rcc = dp.RCC.constrain();
@@ -40,15 +44,16 @@ fn main() -> ! {
 
     // Configure Midi
     let (tx, _rx) = usart.split();
-    let mut midi_out = MidiOut::new(tx);
+    let mut midi_out = MidiOut::new(Eh02Tx::new(tx));
 
-    loop {
-        let event = MidiMessage::NoteOn(0u8.into(), 50u8.into(), 0x40u8.into());
-        hprintln!("on {:?}", event).ok();
-        midi_out.write(&event).ok();
+    // Count milliseconds with the system timer
+    let mut delay = Delay::new(cp.SYST, clocks);
+    let mut now_ms: u32 = 0;
+    let mut metronome = Click::new(120);
 
-        let event = MidiMessage::NoteOff(0u8.into(), 50u8.into(), 0x40u8.into());
-        hprintln!("off {:?}", event).ok();
-        midi_out.write(&event).ok();
+    loop {
+        metronome_poll(&mut metronome, now_ms, &mut midi_out).ok();
+        delay.delay_ms(1u8);
+        now_ms = now_ms.wrapping_add(1);
     }
 }
@@ -0,0 +1,73 @@
+//! Midi thru between two ports of this computer, moving all channel messages to one channel
+//!
+//! ```text
+//! cargo run --example host_thru --features host-midir -- <input> <output> [channel]
+//! ```
+//!
+//! `<input>` and `<output>` are parts of the port names, run without arguments to list the ports.
+//! The channel is 1 to 16, 1 when left out.
+
+use embedded_midi::midi_types::Channel;
+use embedded_midi::pipelines::{passthrough_poll, PipelineError};
+use embedded_midi::{MidirMidiIn, MidirMidiOut, Omni, SourceError};
+use midir::{MidiInput, MidiOutput};
+use std::error::Error;
+use std::time::Duration;
+
+const CLIENT: &str = "embedded-midi host_thru";
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let input = MidiInput::new(CLIENT)?;
+    let output = MidiOutput::new(CLIENT)?;
+    let mut args = std::env::args().skip(1);
+    let (input_name, output_name) = match (args.next(), args.next()) {
+        (Some(input_name), Some(output_name)) => (input_name, output_name),
+        _ => {
+            println!("inputs:");
+            for port in input.ports() {
+                println!("  {}", input.port_name(&port)?);
+            }
+            println!("outputs:");
+            for port in output.ports() {
+                println!("  {}", output.port_name(&port)?);
+            }
+            return Ok(());
+        }
+    };
+    let channel: u8 = args.next().map_or(Ok(1), |arg| arg.parse())?;
+    if !(1..=16).contains(&channel) {
+        return Err("the channel is 1 to 16".into());
+    }
+
+    let input_port = input
+        .ports()
+        .into_iter()
+        .find(|port| {
+            input
+                .port_name(port)
+                .is_ok_and(|name| name.contains(&input_name))
+        })
+        .ok_or("no such input")?;
+    let output_port = output
+        .ports()
+        .into_iter()
+        .find(|port| {
+            output
+                .port_name(port)
+                .is_ok_and(|name| name.contains(&output_name))
+        })
+        .ok_or("no such output")?;
+    let mut source = MidirMidiIn::connect(input, &input_port, "in", 256)?;
+    let mut sink = MidirMidiOut::connect(output, &output_port, "out")?;
+
+    let mut omni = Omni::new();
+    omni.set_base_channel(Channel::from(channel - 1));
+    println!("forwarding to channel {}, stop with ctrl-c", channel);
+    loop {
+        match passthrough_poll(&mut source, &mut sink, |message| omni.process(message)) {
+            Ok(_) => std::thread::sleep(Duration::from_millis(1)),
+            Err(PipelineError::Source(SourceError::Overrun)) => eprintln!("messages lost"),
+            Err(error) => return Err(error.into()),
+        }
+    }
+}
@@ -0,0 +1,27 @@
+#!/bin/sh
+# Text size of the passthrough with and without the `opt-size` feature, and parsing only notes
+#
+# Usage: size-report/report.sh [target], for example thumbv6m-none-eabi, defaults to the host.
+# Needs `rustup target add <target>` and a `size` that reads the target's objects, like
+# `arm-none-eabi-size` set in `SIZE`.
+set -e
+cd "$(dirname "$0")"
+target=${1:+--target $1}
+dir=target/${1:-.}/release
+size=${SIZE:-size}
+
+text() {
+    cargo build --release --quiet $target "$@"
+    mkdir -p target/objects
+    rm -f target/objects/*.o
+    lib=$(ls $dir/libsize_report.a)
+    (cd target/objects && ar x "../../$lib" $(ar t "../../$lib" | grep '^size_report'))
+    $size target/objects/*.o | awk 'NR > 1 { text += $1 } END { print text }'
+}
+
+default=$(text)
+opt_size=$(text --features opt-size)
+notes_only=$(text --features notes-only)
+echo "default:    $default bytes"
+echo "opt-size:   $opt_size bytes, $(( (default - opt_size) * 100 / default ))% saved"
+echo "notes only: $notes_only bytes, $(( (default - notes_only) * 100 / default ))% saved"
@@ -0,0 +1,61 @@
+//! Passthrough firmware reduced to what `embedded-midi` contributes to its size
+//!
+//! Built as a static library so no target support crate or linker script is needed, the text size
+//! of the library is the size of the passthrough loop and everything it pulls in.
+
+#![no_std]
+
+use core::convert::Infallible;
+use embedded_hal_nb::serial;
+use embedded_midi::{family, MidiIn, MidiOut};
+
+/// Message families the passthrough parses
+#[cfg(not(feature = "notes-only"))]
+const FAMILIES: u16 = family::ALL;
+#[cfg(feature = "notes-only")]
+const FAMILIES: u16 = family::NOTES;
+
+/// Data register of a memory mapped uart
+struct Uart(*mut u8);
+
+impl serial::ErrorType for Uart {
+    type Error = Infallible;
+}
+
+impl serial::Read<u8> for Uart {
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        Ok(unsafe { self.0.read_volatile() })
+    }
+}
+
+impl serial::Write<u8> for Uart {
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        unsafe { self.0.write_volatile(word) };
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Forward every message received on the uart at `rx` to the uart at `tx`
+///
+/// # Safety
+///
+/// Both addresses have to be valid for volatile reads and writes.
+#[no_mangle]
+pub unsafe extern "C" fn passthrough(rx: *mut u8, tx: *mut u8) -> ! {
+    let mut midi_in = MidiIn::<_, FAMILIES>::with_families(Uart(rx));
+    let mut midi_out = MidiOut::new(Uart(tx));
+    loop {
+        if let Ok(message) = nb::block!(midi_in.read()) {
+            midi_out.write(&message).ok();
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
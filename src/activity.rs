@@ -0,0 +1,167 @@
+//! Per channel traffic counters and activity indicators, for monitors and patchbays
+
+use crate::message::channel;
+use midi_convert::midi_types::{Channel, MidiMessage};
+
+/// Messages received on a channel, counters wrap around
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChannelCounts {
+    /// Note ons and note offs
+    pub notes: u32,
+    pub controls: u32,
+    /// All other channel voice messages
+    pub other: u32,
+}
+
+/// Counts received messages per channel and remembers when each channel was last active
+///
+/// Timestamps are in milliseconds and may wrap around.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelActivity {
+    counts: [ChannelCounts; 16],
+    last_active: [Option<u32>; 16],
+    system_common: u32,
+    realtime: u32,
+}
+
+impl ChannelActivity {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a message received at time `now_ms`
+    pub fn record(&mut self, now_ms: u32, message: &MidiMessage) {
+        let channel = match channel(message) {
+            Some(channel) => channel,
+            None => {
+                let counter = match message {
+                    MidiMessage::TimingClock
+                    | MidiMessage::Start
+                    | MidiMessage::Continue
+                    | MidiMessage::Stop
+                    | MidiMessage::ActiveSensing
+                    | MidiMessage::Reset => &mut self.realtime,
+                    _ => &mut self.system_common,
+                };
+                *counter = counter.wrapping_add(1);
+                return;
+            }
+        };
+
+        let index = u8::from(channel) as usize;
+        let counts = &mut self.counts[index];
+        let counter = match message {
+            MidiMessage::NoteOn(..) | MidiMessage::NoteOff(..) => &mut counts.notes,
+            MidiMessage::ControlChange(..) => &mut counts.controls,
+            _ => &mut counts.other,
+        };
+        *counter = counter.wrapping_add(1);
+        self.last_active[index] = Some(now_ms);
+    }
+
+    pub fn counts(&self, channel: Channel) -> ChannelCounts {
+        self.counts[u8::from(channel) as usize]
+    }
+
+    pub fn system_common(&self) -> u32 {
+        self.system_common
+    }
+
+    pub fn realtime(&self) -> u32 {
+        self.realtime
+    }
+
+    /// The channel received a message less than `window_ms` before `now_ms`
+    pub fn active_within(&self, channel: Channel, now_ms: u32, window_ms: u32) -> bool {
+        self.last_active[u8::from(channel) as usize]
+            .is_some_and(|last| now_ms.wrapping_sub(last) < window_ms)
+    }
+
+    /// Channels that received a message less than `window_ms` before `now_ms`, for lighting
+    /// activity LEDs
+    pub fn iter_active(&self, now_ms: u32, window_ms: u32) -> impl Iterator<Item = Channel> + '_ {
+        (0..16u8)
+            .map(Channel::from)
+            .filter(move |channel| self.active_within(*channel, now_ms, window_ms))
+    }
+
+    /// Clear all counters and activity
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_off, note_on, pitch_bend, program_change, song_select};
+    use std::vec::Vec;
+
+    #[test]
+    fn should_count_messages_per_channel() {
+        let mut activity = ChannelActivity::new();
+        let stream = [
+            note_on(0, 60, 100),
+            MidiMessage::TimingClock,
+            cc(0, 7, 100),
+            note_off(0, 60, 0),
+            pitch_bend(9, 0),
+            program_change(9, 3),
+            song_select(1),
+            MidiMessage::ActiveSensing,
+            note_on(15, 36, 1),
+        ];
+        for message in stream.iter() {
+            activity.record(0, message);
+        }
+
+        assert_eq!(
+            activity.counts(Channel::C1),
+            ChannelCounts {
+                notes: 2,
+                controls: 1,
+                other: 0
+            }
+        );
+        assert_eq!(activity.counts(Channel::C10).other, 2);
+        assert_eq!(activity.counts(Channel::C16).notes, 1);
+        assert_eq!(activity.counts(Channel::C2), ChannelCounts::default());
+        assert_eq!(activity.system_common(), 1);
+        assert_eq!(activity.realtime(), 2);
+
+        activity.reset();
+        assert_eq!(activity.counts(Channel::C1), ChannelCounts::default());
+        assert_eq!(activity.realtime(), 0);
+    }
+
+    #[test]
+    fn should_decay_activity_at_window_end() {
+        let mut activity = ChannelActivity::new();
+        activity.record(1_000, &note_on(2, 60, 100));
+        activity.record(1_050, &cc(5, 1, 0));
+        activity.record(1_060, &MidiMessage::TimingClock);
+
+        assert!(activity.active_within(Channel::C3, 1_099, 100));
+        assert!(!activity.active_within(Channel::C3, 1_100, 100));
+        assert!(!activity.active_within(Channel::C1, 1_000, 100));
+        assert_eq!(
+            activity.iter_active(1_099, 100).collect::<Vec<_>>(),
+            [Channel::C3, Channel::C6]
+        );
+        assert_eq!(
+            activity.iter_active(1_100, 100).collect::<Vec<_>>(),
+            [Channel::C6]
+        );
+        assert_eq!(activity.iter_active(1_150, 100).count(), 0);
+    }
+
+    #[test]
+    fn should_follow_wrapping_timestamps() {
+        let mut activity = ChannelActivity::new();
+        activity.record(u32::MAX - 10, &note_on(0, 60, 100));
+
+        assert!(activity.active_within(Channel::C1, 20, 50));
+        assert!(!activity.active_within(Channel::C1, 39, 50));
+    }
+}
@@ -0,0 +1,272 @@
+//! Driving activity LEDs from the traffic on an input and an output
+
+use embedded_hal::digital::OutputPin;
+use midi_convert::midi_types::MidiMessage;
+
+#[derive(Debug)]
+struct Blinker<PIN> {
+    pin: PIN,
+    lit: bool,
+    /// Time the LED last turned on or off
+    since: Option<u32>,
+    /// Traffic arrived while the LED was off
+    pending: bool,
+}
+
+impl<PIN: OutputPin> Blinker<PIN> {
+    fn new(pin: PIN) -> Self {
+        Blinker {
+            pin,
+            lit: false,
+            since: None,
+            pending: false,
+        }
+    }
+
+    fn trigger(&mut self, now_ms: u32, off_ms: u32) -> Result<(), PIN::Error> {
+        if self.lit {
+            return Ok(());
+        }
+        match self.since {
+            Some(since) if now_ms.wrapping_sub(since) < off_ms => {
+                self.pending = true;
+                Ok(())
+            }
+            _ => self.turn_on(now_ms),
+        }
+    }
+
+    fn tick(&mut self, now_ms: u32, on_ms: u32, off_ms: u32) -> Result<(), PIN::Error> {
+        let elapsed = match self.since {
+            Some(since) => now_ms.wrapping_sub(since),
+            None => return Ok(()),
+        };
+        if self.lit && elapsed >= on_ms {
+            self.lit = false;
+            self.since = Some(now_ms);
+            self.pin.set_low()
+        } else if !self.lit && self.pending && elapsed >= off_ms {
+            self.turn_on(now_ms)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn turn_on(&mut self, now_ms: u32) -> Result<(), PIN::Error> {
+        self.lit = true;
+        self.pending = false;
+        self.since = Some(now_ms);
+        self.pin.set_high()
+    }
+}
+
+/// Lights an LED for received and one for sent messages, so that traffic is visible
+///
+/// Every message lights its LED for at least the on time, messages while it is lit don't extend
+/// it. The LED then stays off for at least the off time, so a continuous stream blinks the LED
+/// instead of keeping it lit, and lights again after the off time if messages arrived meanwhile.
+/// Timing clock and active sensing don't light the LEDs, unless `set_show_clock` is set, as they
+/// are sent all the time by many devices. `tick` must be called every few milliseconds to end
+/// the blinks. Both pins should be low, high lights the LED. Timestamps are in milliseconds and
+/// may wrap around.
+#[derive(Debug)]
+pub struct MidiActivityLed<IN, OUT> {
+    input: Blinker<IN>,
+    output: Blinker<OUT>,
+    on_ms: u32,
+    off_ms: u32,
+    show_clock: bool,
+}
+
+impl<IN, OUT, E> MidiActivityLed<IN, OUT>
+where
+    IN: OutputPin<Error = E>,
+    OUT: OutputPin<Error = E>,
+{
+    /// LEDs lit for 30 ms and off for 30 ms between blinks
+    pub fn new(input: IN, output: OUT) -> Self {
+        MidiActivityLed {
+            input: Blinker::new(input),
+            output: Blinker::new(output),
+            on_ms: 30,
+            off_ms: 30,
+            show_clock: false,
+        }
+    }
+
+    pub fn release(self) -> (IN, OUT) {
+        (self.input.pin, self.output.pin)
+    }
+
+    /// Shortest time a message lights the LED
+    pub fn set_on_ms(&mut self, on_ms: u32) {
+        self.on_ms = on_ms;
+    }
+
+    /// Shortest time the LED is off between two blinks
+    pub fn set_off_ms(&mut self, off_ms: u32) {
+        self.off_ms = off_ms;
+    }
+
+    /// Light the LEDs for timing clock and active sensing too
+    pub fn set_show_clock(&mut self, show: bool) {
+        self.show_clock = show;
+    }
+
+    /// Record a message received at time `now_ms`
+    pub fn on_received(&mut self, now_ms: u32, message: &MidiMessage) -> Result<(), E> {
+        if !self.shows(message) {
+            return Ok(());
+        }
+        self.input.trigger(now_ms, self.off_ms)
+    }
+
+    /// Record a message sent at time `now_ms`
+    pub fn on_sent(&mut self, now_ms: u32, message: &MidiMessage) -> Result<(), E> {
+        if !self.shows(message) {
+            return Ok(());
+        }
+        self.output.trigger(now_ms, self.off_ms)
+    }
+
+    /// End and restart blinks that are due at time `now_ms`, call this often
+    pub fn tick(&mut self, now_ms: u32) -> Result<(), E> {
+        self.input.tick(now_ms, self.on_ms, self.off_ms)?;
+        self.output.tick(now_ms, self.on_ms, self.off_ms)
+    }
+
+    fn shows(&self, message: &MidiMessage) -> bool {
+        self.show_clock
+            || !matches!(
+                message,
+                MidiMessage::TimingClock | MidiMessage::ActiveSensing
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::note_on;
+    use embedded_hal_mock::eh1::digital::{Mock, State, Transaction};
+    use std::vec::Vec;
+
+    fn pin(states: &[State]) -> Mock {
+        let expectations: Vec<Transaction> = states
+            .iter()
+            .map(|state| Transaction::set(*state))
+            .collect();
+        Mock::new(&expectations)
+    }
+
+    fn done(leds: MidiActivityLed<Mock, Mock>) {
+        let (mut input, mut output) = leds.release();
+        input.done();
+        output.done();
+    }
+
+    /// Receive `messages` at their times and tick every millisecond up to `to_ms`, returns the
+    /// times the input LED turned on and off
+    fn run(
+        leds: &mut MidiActivityLed<Mock, Mock>,
+        messages: &[(u32, MidiMessage)],
+        to_ms: u32,
+    ) -> Vec<(u32, bool)> {
+        let mut changes = Vec::new();
+        for now in 0..to_ms {
+            for (_, message) in messages.iter().filter(|(time, _)| *time == now) {
+                leds.on_received(now, message).unwrap();
+            }
+            leds.tick(now).unwrap();
+            if matches!(changes.last(), Some((_, true))) != leds.input.lit {
+                changes.push((now, leds.input.lit));
+            }
+        }
+        changes
+    }
+
+    #[test]
+    fn should_show_a_single_message_for_the_on_time() {
+        let mut leds = MidiActivityLed::new(pin(&[State::High, State::Low]), pin(&[]));
+        let changes = run(&mut leds, &[(100, note_on(0, 60, 100))], 1_000);
+        assert_eq!(changes, [(100, true), (130, false)]);
+        done(leds);
+    }
+
+    #[test]
+    fn should_blink_under_dense_traffic() {
+        let states = [State::High, State::Low].repeat(4);
+        let mut leds = MidiActivityLed::new(pin(&states), pin(&[]));
+        leds.set_on_ms(20);
+        leds.set_off_ms(40);
+        let stream: Vec<_> = (0..=190)
+            .step_by(2)
+            .map(|now| (now, note_on(0, 60, 100)))
+            .collect();
+        let changes = run(&mut leds, &stream, 1_000);
+        assert_eq!(
+            changes,
+            [
+                (0, true),
+                (20, false),
+                (60, true),
+                (80, false),
+                (120, true),
+                (140, false),
+                (180, true),
+                (200, false),
+            ]
+        );
+        done(leds);
+    }
+
+    #[test]
+    fn should_light_again_for_messages_during_the_off_time() {
+        let states = [State::High, State::Low, State::High, State::Low];
+        let mut leds = MidiActivityLed::new(pin(&states), pin(&[]));
+        let messages = [(0, note_on(0, 60, 100)), (40, note_on(0, 61, 100))];
+        let changes = run(&mut leds, &messages, 1_000);
+        assert_eq!(changes, [(0, true), (30, false), (60, true), (90, false)]);
+        done(leds);
+    }
+
+    #[test]
+    fn should_ignore_clock_unless_shown() {
+        let mut leds = MidiActivityLed::new(pin(&[]), pin(&[]));
+        let clocks: Vec<_> = (0..1_000)
+            .step_by(20)
+            .map(|now| (now, MidiMessage::TimingClock))
+            .chain([(500, MidiMessage::ActiveSensing)])
+            .collect();
+        assert_eq!(run(&mut leds, &clocks, 1_000), []);
+        done(leds);
+
+        let mut leds = MidiActivityLed::new(pin(&[State::High, State::Low]), pin(&[]));
+        leds.set_show_clock(true);
+        assert_eq!(
+            run(&mut leds, &[(10, MidiMessage::TimingClock)], 100),
+            [(10, true), (40, false)]
+        );
+        done(leds);
+    }
+
+    #[test]
+    fn should_drive_both_leds_independently() {
+        let input = pin(&[State::High, State::Low]);
+        let output = pin(&[State::High, State::Low, State::High, State::Low]);
+        let mut leds = MidiActivityLed::new(input, output);
+        leds.on_received(u32::MAX - 10, &note_on(0, 60, 100))
+            .unwrap();
+        leds.on_sent(u32::MAX - 5, &note_on(1, 60, 100)).unwrap();
+        leds.tick(18).unwrap();
+        leds.tick(19).unwrap();
+        leds.tick(25).unwrap();
+        // The output LED is off for the off time before it lights again
+        leds.on_sent(40, &note_on(1, 61, 100)).unwrap();
+        leds.tick(54).unwrap();
+        leds.tick(55).unwrap();
+        leds.tick(85).unwrap();
+        done(leds);
+    }
+}
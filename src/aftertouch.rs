@@ -0,0 +1,377 @@
+//! Converting between aftertouch and control changes with a slew limiter
+
+use crate::MidiProcessor;
+use midi_convert::midi_types::{Channel, Control, MidiMessage, Note};
+
+/// Moves a 7 bit value towards a target in limited steps
+#[derive(Debug, Clone)]
+struct Slew {
+    target: u8,
+    sent: u8,
+    max_step: u8,
+    interval_ms: u32,
+    last_sent_ms: Option<u32>,
+}
+
+impl Slew {
+    fn new() -> Self {
+        Slew {
+            target: 0,
+            sent: 0,
+            max_step: 8,
+            interval_ms: 10,
+            last_sent_ms: None,
+        }
+    }
+
+    /// Next value to send at `now_ms`, if one is due
+    fn tick(&mut self, now_ms: u32) -> Option<u8> {
+        let due = self
+            .last_sent_ms
+            .map_or(true, |last| now_ms.wrapping_sub(last) >= self.interval_ms);
+        if self.sent == self.target || !due {
+            return None;
+        }
+        self.sent = if self.target > self.sent {
+            self.sent.saturating_add(self.max_step).min(self.target)
+        } else {
+            self.sent.saturating_sub(self.max_step).max(self.target)
+        };
+        self.last_sent_ms = Some(now_ms);
+        Some(self.sent)
+    }
+}
+
+/// Sends channel pressure, and optionally the highest key pressure, as control changes
+///
+/// Pressure received on the input channel is replaced by control changes for `control`, on the
+/// input channel or the output channel if one is set. The control value follows the pressure at
+/// most `max_step` per message and at most one message every `interval_ms`, which smooths the
+/// steps of coarse pressure sensors. Messages are sent by `tick`. Some keyboards don't send a
+/// pressure of 0 when the keys are released, so the value also glides back to 0 when no note was
+/// held on the input channel for `snap_ms`. All other messages are passed on.
+#[derive(Debug, Clone)]
+pub struct AftertouchToCc {
+    input: Channel,
+    output: Channel,
+    control: Control,
+    slew: Slew,
+    channel_pressure: u8,
+    key_pressure: Option<[u8; 128]>,
+    held: u128,
+    snap_ms: Option<u32>,
+    /// Time all notes were found released, `None` while notes are held or after snapping
+    released_ms: Option<u32>,
+    released: bool,
+}
+
+impl AftertouchToCc {
+    /// Converter sending pressure on `channel` as control changes for `control` on the same
+    /// channel, moving at most 8 steps every 10 ms and snapping to 0 100 ms after release
+    pub fn new(channel: Channel, control: Control) -> Self {
+        AftertouchToCc {
+            input: channel,
+            output: channel,
+            control,
+            slew: Slew::new(),
+            channel_pressure: 0,
+            key_pressure: None,
+            held: 0,
+            snap_ms: Some(100),
+            released_ms: None,
+            released: false,
+        }
+    }
+
+    pub fn set_output_channel(&mut self, channel: Channel) {
+        self.output = channel;
+    }
+
+    /// Also follow the highest key pressure of the held notes, key pressure is passed on when
+    /// disabled
+    pub fn set_key_pressure(&mut self, enabled: bool) {
+        self.key_pressure = enabled.then_some([0; 128]);
+        self.update_target();
+    }
+
+    /// Largest change of the control value per message, at least 1
+    pub fn set_max_step(&mut self, max_step: u8) {
+        self.slew.max_step = max_step.max(1);
+    }
+
+    /// Shortest time between two control changes
+    pub fn set_interval_ms(&mut self, interval_ms: u32) {
+        self.slew.interval_ms = interval_ms;
+    }
+
+    /// Time without held notes after which the value returns to 0, `None` to wait for a pressure
+    /// of 0
+    pub fn set_snap_ms(&mut self, snap_ms: Option<u32>) {
+        self.snap_ms = snap_ms;
+    }
+
+    /// Last control value sent
+    pub fn value(&self) -> u8 {
+        self.slew.sent
+    }
+
+    fn update_target(&mut self) {
+        let keys = self
+            .key_pressure
+            .map_or(0, |keys| keys.iter().copied().max().unwrap_or(0));
+        self.slew.target = self.channel_pressure.max(keys);
+    }
+
+    fn note(&mut self, note: Note, on: bool) {
+        let bit = 1 << u8::from(note);
+        if on {
+            self.held |= bit;
+            self.released = false;
+            self.released_ms = None;
+        } else {
+            self.held &= !bit;
+            self.released = self.held == 0;
+            if let Some(keys) = self.key_pressure.as_mut() {
+                keys[u8::from(note) as usize] = 0;
+                self.update_target();
+            }
+        }
+    }
+
+    /// Send the next control change if one is due at `now_ms`
+    pub fn tick(&mut self, now_ms: u32, emit: &mut dyn FnMut(MidiMessage)) {
+        if let (true, Some(snap_ms)) = (self.released, self.snap_ms) {
+            let released = *self.released_ms.get_or_insert(now_ms);
+            if now_ms.wrapping_sub(released) >= snap_ms {
+                self.released = false;
+                self.released_ms = None;
+                self.channel_pressure = 0;
+                if let Some(keys) = self.key_pressure.as_mut() {
+                    *keys = [0; 128];
+                }
+                self.update_target();
+            }
+        }
+        if let Some(value) = self.slew.tick(now_ms) {
+            emit(MidiMessage::ControlChange(
+                self.output,
+                self.control,
+                value.into(),
+            ));
+        }
+    }
+}
+
+impl MidiProcessor for AftertouchToCc {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        match message {
+            MidiMessage::ChannelPressure(channel, value) if channel == self.input => {
+                self.channel_pressure = value.into();
+                self.update_target();
+            }
+            MidiMessage::KeyPressure(channel, note, value)
+                if channel == self.input && self.key_pressure.is_some() =>
+            {
+                if let Some(keys) = self.key_pressure.as_mut() {
+                    keys[u8::from(note) as usize] = value.into();
+                }
+                self.update_target();
+            }
+            MidiMessage::NoteOn(channel, note, velocity) if channel == self.input => {
+                self.note(note, u8::from(velocity) > 0);
+                emit(message);
+            }
+            MidiMessage::NoteOff(channel, note, _) if channel == self.input => {
+                self.note(note, false);
+                emit(message);
+            }
+            _ => emit(message),
+        }
+    }
+}
+
+/// Sends control changes for a controller as channel pressure, for synths that respond to
+/// aftertouch but can't map controllers
+///
+/// Control changes for `control` on the input channel are replaced by channel pressure on the input
+/// or output channel, sent by `tick` with the same slew limiting as `AftertouchToCc`. All other
+/// messages are passed on.
+#[derive(Debug, Clone)]
+pub struct CcToAftertouch {
+    input: Channel,
+    output: Channel,
+    control: Control,
+    slew: Slew,
+}
+
+impl CcToAftertouch {
+    /// Converter sending control changes for `control` on `channel` as channel pressure on the
+    /// same channel, moving at most 8 steps every 10 ms
+    pub fn new(channel: Channel, control: Control) -> Self {
+        CcToAftertouch {
+            input: channel,
+            output: channel,
+            control,
+            slew: Slew::new(),
+        }
+    }
+
+    pub fn set_output_channel(&mut self, channel: Channel) {
+        self.output = channel;
+    }
+
+    /// Largest change of the pressure per message, at least 1
+    pub fn set_max_step(&mut self, max_step: u8) {
+        self.slew.max_step = max_step.max(1);
+    }
+
+    /// Shortest time between two pressure messages
+    pub fn set_interval_ms(&mut self, interval_ms: u32) {
+        self.slew.interval_ms = interval_ms;
+    }
+
+    /// Last pressure sent
+    pub fn value(&self) -> u8 {
+        self.slew.sent
+    }
+
+    /// Send the next pressure message if one is due at `now_ms`
+    pub fn tick(&mut self, now_ms: u32, emit: &mut dyn FnMut(MidiMessage)) {
+        if let Some(value) = self.slew.tick(now_ms) {
+            emit(MidiMessage::ChannelPressure(self.output, value.into()));
+        }
+    }
+}
+
+impl MidiProcessor for CcToAftertouch {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        match message {
+            MidiMessage::ControlChange(channel, control, value)
+                if channel == self.input && control == self.control =>
+            {
+                self.slew.target = value.into();
+            }
+            _ => emit(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_off, note_on};
+    use crate::test_util::process;
+    use std::vec::Vec;
+
+    fn pressure(channel: u8, value: u8) -> MidiMessage {
+        MidiMessage::ChannelPressure(channel.into(), value.into())
+    }
+
+    /// Values of the control changes sent by ticks every 5 ms from `from_ms` until `to_ms`
+    fn run(converter: &mut AftertouchToCc, from_ms: u32, to_ms: u32) -> Vec<(u32, u8)> {
+        let mut values = Vec::new();
+        for now in (from_ms..to_ms).step_by(5) {
+            converter.tick(now, &mut |message| match message {
+                MidiMessage::ControlChange(_, _, value) => values.push((now, value.into())),
+                _ => unreachable!(),
+            });
+        }
+        values
+    }
+
+    #[test]
+    fn should_limit_slew_of_control_value() {
+        let mut converter = AftertouchToCc::new(Channel::C1, Control::from(74));
+        assert!(process(&mut converter, &[pressure(0, 100)]).is_empty());
+        let up = run(&mut converter, 0, 200);
+        let values: Vec<_> = up.iter().map(|(_, value)| *value).collect();
+        assert_eq!(values, [8, 16, 24, 32, 40, 48, 56, 64, 72, 80, 88, 96, 100]);
+        assert!(up.windows(2).all(|pair| pair[1].0 - pair[0].0 == 10));
+
+        converter.set_max_step(30);
+        process(&mut converter, &[pressure(0, 0)]);
+        let down: Vec<_> = run(&mut converter, 200, 300)
+            .iter()
+            .map(|(_, value)| *value)
+            .collect();
+        assert_eq!(down, [70, 40, 10, 0]);
+    }
+
+    #[test]
+    fn should_always_reach_zero_pressure() {
+        for max_step in [1, 7, 64, 127] {
+            let mut converter = AftertouchToCc::new(Channel::C2, Control::from(1));
+            converter.set_max_step(max_step);
+            converter.set_interval_ms(0);
+            let mut values = Vec::new();
+            for value in [127, 3, 90, 0] {
+                process(&mut converter, &[pressure(1, value)]);
+                values.extend(run(&mut converter, 0, 20));
+            }
+            values.extend(run(&mut converter, 0, 1_000));
+            assert_eq!(values.last().map(|(_, value)| *value), Some(0));
+            assert_eq!(converter.value(), 0);
+        }
+    }
+
+    #[test]
+    fn should_snap_to_zero_after_release() {
+        let mut converter = AftertouchToCc::new(Channel::C1, Control::from(1));
+        converter.set_output_channel(Channel::C3);
+        converter.set_max_step(127);
+        assert_eq!(
+            process(&mut converter, &[note_on(0, 60, 100)]),
+            [note_on(0, 60, 100)]
+        );
+        process(&mut converter, &[pressure(0, 60)]);
+        let mut output = Vec::new();
+        converter.tick(0, &mut |message| output.push(message));
+        assert_eq!(output, [cc(2, 1, 60)]);
+
+        process(&mut converter, &[note_off(0, 60, 0)]);
+        assert!(run(&mut converter, 10, 105).is_empty());
+        assert_eq!(run(&mut converter, 110, 120), [(110, 0)]);
+    }
+
+    #[test]
+    fn should_follow_highest_key_pressure() {
+        let mut converter = AftertouchToCc::new(Channel::C1, Control::from(74));
+        converter.set_max_step(127);
+        let key_pressure =
+            |note: u8, value: u8| MidiMessage::KeyPressure(Channel::C1, note.into(), value.into());
+
+        assert_eq!(process(&mut converter, &[key_pressure(60, 50)]).len(), 1);
+        converter.set_key_pressure(true);
+        process(&mut converter, &[note_on(0, 60, 100)]);
+        process(&mut converter, &[note_on(0, 64, 100)]);
+        process(&mut converter, &[key_pressure(60, 50)]);
+        process(&mut converter, &[key_pressure(64, 90)]);
+        assert_eq!(run(&mut converter, 0, 5), [(0, 90)]);
+
+        process(&mut converter, &[note_off(0, 64, 0)]);
+        assert_eq!(run(&mut converter, 10, 15), [(10, 50)]);
+        // Pressure on other channels is passed on
+        assert_eq!(
+            process(&mut converter, &[pressure(5, 10)]),
+            [pressure(5, 10)]
+        );
+    }
+
+    #[test]
+    fn should_convert_control_changes_to_pressure() {
+        let mut converter = CcToAftertouch::new(Channel::C1, Control::from(1));
+        converter.set_max_step(50);
+        assert!(process(&mut converter, &[cc(0, 1, 120)]).is_empty());
+        assert_eq!(process(&mut converter, &[cc(0, 2, 120)]), [cc(0, 2, 120)]);
+
+        let mut output = Vec::new();
+        for now in (0..50).step_by(10) {
+            converter.tick(now, &mut |message| output.push(message));
+        }
+        assert_eq!(
+            output,
+            [pressure(0, 50), pressure(0, 100), pressure(0, 120)]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Parsing with framing details for wire analyzers
+
+use crate::parse::MidiParser;
+use midi_convert::midi_types::MidiMessage;
+
+/// A parsed message with details of how it was framed on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParsedWithMeta {
+    pub message: MidiMessage,
+    /// The message was sent without its status byte
+    pub used_running_status: bool,
+    /// Status byte of the message, whether it was sent or not
+    pub status_byte: u8,
+    /// Index of the first byte of the message in the parsed stream, the status byte or the first
+    /// data byte when running status was used
+    pub first_byte_index: u32,
+}
+
+/// Parser reporting the framing of every message, for monitors and analyzers
+///
+/// Bytes are counted from 0 and the index wraps around after `u32::MAX`. Real time messages
+/// interleaved into another message don't affect its framing. The plain parser used by `MidiIn`
+/// doesn't track any of this.
+#[derive(Debug, Clone)]
+pub struct AnalyzerParser {
+    parser: MidiParser,
+    index: u32,
+    /// Index of the first byte of the message being received
+    start: Option<u32>,
+    /// The status byte of the message being received was sent
+    fresh: bool,
+}
+
+impl Default for AnalyzerParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AnalyzerParser {
+    pub fn new() -> Self {
+        AnalyzerParser {
+            parser: MidiParser::new(),
+            index: 0,
+            start: None,
+            fresh: false,
+        }
+    }
+
+    /// Parse a byte, returns a message with its framing when the byte completes one
+    pub fn parse(&mut self, byte: u8) -> Option<ParsedWithMeta> {
+        let index = self.index;
+        self.index = self.index.wrapping_add(1);
+
+        if byte >= 0xf8 {
+            return self.parser.parse(byte).map(|message| ParsedWithMeta {
+                message,
+                used_running_status: false,
+                status_byte: byte,
+                first_byte_index: index,
+            });
+        }
+        if byte & 0x80 != 0 {
+            self.start = Some(index);
+            self.fresh = true;
+        }
+        let start = *self.start.get_or_insert(index);
+        let message = self.parser.parse(byte)?;
+        let parsed = ParsedWithMeta {
+            message,
+            used_running_status: !self.fresh,
+            status_byte: self.parser.status(),
+            first_byte_index: start,
+        };
+        self.start = None;
+        self.fresh = false;
+        Some(parsed)
+    }
+
+    /// Status byte data bytes are currently parsed with, `None` when data bytes are ignored
+    pub fn running_status(&self) -> Option<u8> {
+        match self.parser.status() {
+            status @ 0x80..=0xef => Some(status),
+            _ => None,
+        }
+    }
+
+    /// Number of bytes parsed
+    pub fn byte_index(&self) -> u32 {
+        self.index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_on, program_change};
+    use std::vec::Vec;
+
+    fn meta(message: MidiMessage, running: bool, status: u8, index: u32) -> ParsedWithMeta {
+        ParsedWithMeta {
+            message,
+            used_running_status: running,
+            status_byte: status,
+            first_byte_index: index,
+        }
+    }
+
+    #[test]
+    fn should_report_framing_of_messages() {
+        let mut parser = AnalyzerParser::new();
+        assert_eq!(parser.running_status(), None);
+        let bytes = [
+            0x92, 0x40, 0x10, 0x41, 0xf8, 0x11, 0xc3, 0x05, 0x06, 0xf6, 0x42, 0xb1, 0x07, 0xfe,
+            0x64,
+        ];
+        let parsed: Vec<_> = bytes
+            .iter()
+            .filter_map(|byte| parser.parse(*byte))
+            .collect();
+
+        assert_eq!(
+            parsed,
+            [
+                meta(note_on(2, 0x40, 0x10), false, 0x92, 0),
+                meta(MidiMessage::TimingClock, false, 0xf8, 4),
+                meta(note_on(2, 0x41, 0x11), true, 0x92, 3),
+                meta(program_change(3, 5), false, 0xc3, 6),
+                meta(program_change(3, 6), true, 0xc3, 8),
+                // The tune request ends running status, the next data byte is ignored
+                meta(MidiMessage::TuneRequest, false, 0xf6, 9),
+                meta(MidiMessage::ActiveSensing, false, 0xfe, 13),
+                meta(cc(1, 7, 0x64), false, 0xb1, 11),
+            ]
+        );
+        assert_eq!(parser.running_status(), Some(0xb1));
+        assert_eq!(parser.byte_index(), 15);
+    }
+
+    #[test]
+    fn should_parse_like_plain_parser() {
+        let bytes: Vec<u8> = (0..=255)
+            .chain((0..512).map(|i| (i * 37 % 256) as u8))
+            .collect();
+        let mut plain = MidiParser::new();
+        let mut analyzer = AnalyzerParser::new();
+
+        let expected: Vec<_> = bytes.iter().filter_map(|byte| plain.parse(*byte)).collect();
+        let parsed: Vec<_> = bytes
+            .iter()
+            .filter_map(|byte| analyzer.parse(*byte))
+            .map(|parsed| parsed.message)
+            .collect();
+        assert_eq!(parsed, expected);
+    }
+}
@@ -0,0 +1,129 @@
+//! `arbitrary::Arbitrary` support for midi messages, enabled with the `arbitrary` feature
+//!
+//! The message and value types live in the `midi-types` crate so they can't implement
+//! `Arbitrary` directly, `ArbitraryMidi` wraps them instead. All generated values respect the
+//! 7-bit and 4-bit invariants of the midi protocol so they can be rendered and parsed losslessly.
+
+use crate::Value14Ext;
+use arbitrary::{Arbitrary, Result, Unstructured};
+use midi_convert::midi_types::{
+    Channel, Control, MidiMessage, Note, Program, QuarterFrame, Value14, Value7,
+};
+
+/// Wrapper that implements `Arbitrary` for midi types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArbitraryMidi<T>(pub T);
+
+impl<T> ArbitraryMidi<T> {
+    /// Unwrap the generated value
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+fn data_byte(u: &mut Unstructured<'_>) -> Result<u8> {
+    u.int_in_range(0..=127)
+}
+
+fn value<'a, T: From<u8>>(u: &mut Unstructured<'a>) -> Result<T> {
+    Ok(data_byte(u)?.into())
+}
+
+fn channel(u: &mut Unstructured<'_>) -> Result<Channel> {
+    Ok(u.int_in_range(0..=15u8)?.into())
+}
+
+fn value14(u: &mut Unstructured<'_>) -> Result<Value14> {
+    Ok(Value14::from_msb_lsb(data_byte(u)?, data_byte(u)?))
+}
+
+impl<'a> Arbitrary<'a> for ArbitraryMidi<Channel> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        channel(u).map(ArbitraryMidi)
+    }
+}
+
+impl<'a> Arbitrary<'a> for ArbitraryMidi<Note> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        value(u).map(ArbitraryMidi)
+    }
+}
+
+impl<'a> Arbitrary<'a> for ArbitraryMidi<Control> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        value(u).map(ArbitraryMidi)
+    }
+}
+
+impl<'a> Arbitrary<'a> for ArbitraryMidi<Program> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        value(u).map(ArbitraryMidi)
+    }
+}
+
+impl<'a> Arbitrary<'a> for ArbitraryMidi<Value7> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        value(u).map(ArbitraryMidi)
+    }
+}
+
+impl<'a> Arbitrary<'a> for ArbitraryMidi<QuarterFrame> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        value(u).map(ArbitraryMidi)
+    }
+}
+
+impl<'a> Arbitrary<'a> for ArbitraryMidi<Value14> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        value14(u).map(ArbitraryMidi)
+    }
+}
+
+impl<'a> Arbitrary<'a> for ArbitraryMidi<MidiMessage> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let message = match u.int_in_range(0..=17u8)? {
+            0 => MidiMessage::NoteOff(channel(u)?, value(u)?, value(u)?),
+            1 => MidiMessage::NoteOn(channel(u)?, value(u)?, value(u)?),
+            2 => MidiMessage::KeyPressure(channel(u)?, value(u)?, value(u)?),
+            3 => MidiMessage::ControlChange(channel(u)?, value(u)?, value(u)?),
+            4 => MidiMessage::ProgramChange(channel(u)?, value(u)?),
+            5 => MidiMessage::ChannelPressure(channel(u)?, value(u)?),
+            6 => MidiMessage::PitchBendChange(channel(u)?, value14(u)?),
+            7 => MidiMessage::QuarterFrame(value(u)?),
+            8 => MidiMessage::SongPositionPointer(value14(u)?),
+            9 => MidiMessage::SongSelect(value(u)?),
+            10 => MidiMessage::TuneRequest,
+            11 => MidiMessage::TimingClock,
+            12 => MidiMessage::Start,
+            13 => MidiMessage::Continue,
+            14 => MidiMessage::Stop,
+            15 => MidiMessage::ActiveSensing,
+            _ => MidiMessage::Reset,
+        };
+
+        Ok(ArbitraryMidi(message))
+    }
+
+    fn size_hint(_depth: usize) -> (usize, Option<usize>) {
+        (1, Some(4))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_generate_valid_data_bytes() {
+        let bytes = [0xffu8; 64];
+        let mut u = Unstructured::new(&bytes);
+
+        let ArbitraryMidi(note) = ArbitraryMidi::<Note>::arbitrary(&mut u).unwrap();
+        let ArbitraryMidi(channel) = ArbitraryMidi::<Channel>::arbitrary(&mut u).unwrap();
+        let ArbitraryMidi(value) = ArbitraryMidi::<Value14>::arbitrary(&mut u).unwrap();
+
+        assert!(u8::from(note) <= 127);
+        assert!(u8::from(channel) <= 15);
+        assert!(u16::from(value) < 0x4000);
+    }
+}
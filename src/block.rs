@@ -0,0 +1,294 @@
+//! Output scheduling aligned to the sample blocks of an audio callback
+
+use crate::{diag::log_debug, MidiError, MidiOut, RenderedMessage, WireRate};
+use core::fmt::Debug;
+use embedded_hal_nb::serial;
+use midi_convert::midi_types::MidiMessage;
+
+/// Sample positions are kept in thousandths of a sample
+const SAMPLE: i64 = 1000;
+
+#[derive(Debug, Clone, Copy)]
+struct Event {
+    /// Sample offset from the start of the current block, negative once the event is overdue
+    offset: i64,
+    /// Order the event was scheduled in, breaks ties between events at the same offset
+    sequence: u32,
+    message: MidiMessage,
+}
+
+/// Collects messages at sample offsets and hands them out block by block, for firmware that
+/// renders audio in blocks and wants midi output aligned to it
+///
+/// Messages are scheduled with `schedule_in_block` at a sample offset from the start of the
+/// current block, offsets past the end of the block land in a later block. At the block boundary
+/// `render_block` sends the messages of the block in offset order, and in scheduling order at the
+/// same offset. A message goes out at its offset, or as soon as the wire finished sending the
+/// message before it. Messages the wire can't send before the end of the block move to the next
+/// block ahead of the messages scheduled there. Up to `N` messages are held, messages that don't
+/// fit are dropped and counted.
+#[derive(Debug)]
+pub struct BlockScheduler<const N: usize = 32> {
+    block_len: u16,
+    /// Duration of a byte on the wire, in thousandths of a sample
+    byte_time: i64,
+    /// Wire is busy sending until this position in the current block
+    busy_until: i64,
+    events: [Option<Event>; N],
+    sequence: u32,
+    dropped: u32,
+}
+
+impl<const N: usize> BlockScheduler<N> {
+    /// Scheduler for blocks of `block_len` samples at `sample_rate`, sending to a wire running at
+    /// `rate`
+    pub fn new(block_len: u16, sample_rate: u32, rate: WireRate) -> Self {
+        // Every byte takes 10 bits on the wire
+        let byte_time = i64::from(sample_rate) * 10 * SAMPLE / i64::from(rate.baud().max(1));
+        BlockScheduler {
+            block_len: block_len.max(1),
+            byte_time,
+            busy_until: 0,
+            events: [None; N],
+            sequence: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn block_len(&self) -> u16 {
+        self.block_len
+    }
+
+    /// Schedule a message at `offset_samples` from the start of the current block, it is dropped
+    /// if the scheduler is full
+    pub fn schedule_in_block(&mut self, offset_samples: u16, message: &MidiMessage) {
+        let event = Event {
+            offset: i64::from(offset_samples),
+            sequence: self.sequence,
+            message: *message,
+        };
+        match self.events.iter_mut().find(|event| event.is_none()) {
+            Some(slot) => {
+                *slot = Some(event);
+                self.sequence = self.sequence.wrapping_add(1);
+            }
+            None => {
+                log_debug!("block: scheduler full, dropped {:?}", message);
+                self.dropped = self.dropped.wrapping_add(1);
+            }
+        }
+    }
+
+    /// Hand out the messages of the current block with the sample offset they go out at, then
+    /// move on to the next block
+    pub fn render_block_with(&mut self, emit: &mut dyn FnMut(u16, MidiMessage)) {
+        self.render(|offset, message| {
+            emit(offset, message);
+            true
+        });
+        self.advance();
+    }
+
+    /// Write the bytes of the messages of the current block to `buffer`, then move on to the
+    /// next block, returns the number of bytes written
+    ///
+    /// Messages that don't fit in `buffer` move to the next block like messages the wire has no
+    /// time for.
+    pub fn render_block(&mut self, buffer: &mut [u8]) -> usize {
+        let mut len = 0;
+        self.render(|_, message| {
+            let rendered = RenderedMessage::from(message);
+            let bytes = rendered.as_bytes();
+            match buffer.get_mut(len..len + bytes.len()) {
+                Some(slot) => {
+                    slot.copy_from_slice(bytes);
+                    len += bytes.len();
+                    true
+                }
+                None => false,
+            }
+        });
+        self.advance();
+        len
+    }
+
+    /// Write the messages of the current block to `midi_out`, then move on to the next block
+    ///
+    /// Messages after a failed write stay in the block, they are sent with the next block.
+    pub fn render_block_to<TX, E>(&mut self, midi_out: &mut MidiOut<TX>) -> Result<(), MidiError<E>>
+    where
+        TX: serial::Write<u8, Error = E>,
+        E: Debug,
+    {
+        let mut result = Ok(());
+        self.render(|_, message| {
+            result = midi_out.write(&message);
+            result.is_ok()
+        });
+        self.advance();
+        result
+    }
+
+    /// Number of messages waiting to be sent
+    pub fn pending(&self) -> usize {
+        self.events.iter().flatten().count()
+    }
+
+    /// Number of messages dropped because the scheduler was full
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Send the due messages in order until the block ends or `send` refuses one
+    fn render(&mut self, mut send: impl FnMut(u16, MidiMessage) -> bool) {
+        let block_end = i64::from(self.block_len) * SAMPLE;
+        while let Some(slot) = self
+            .events
+            .iter_mut()
+            .filter(|slot| slot.is_some())
+            .min_by_key(|slot| slot.map(|event| (event.offset, event.sequence)))
+        {
+            let event = match slot {
+                Some(event) => *event,
+                None => break,
+            };
+            let start = (event.offset * SAMPLE).max(self.busy_until);
+            if start >= block_end {
+                break;
+            }
+            if !send((start / SAMPLE) as u16, event.message) {
+                break;
+            }
+            *slot = None;
+            let len = RenderedMessage::from(event.message).as_bytes().len() as i64;
+            self.busy_until = start + len * self.byte_time;
+        }
+    }
+
+    /// Move the offsets of the remaining messages and the wire to the next block
+    fn advance(&mut self) {
+        let block_len = i64::from(self.block_len);
+        for event in self.events.iter_mut().flatten() {
+            event.offset -= block_len;
+        }
+        self.busy_until = (self.busy_until - block_len * SAMPLE).max(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_off, note_on};
+    use embedded_hal_mock::eh1::serial::{Mock, Transaction};
+    use std::vec::Vec;
+
+    fn render<const N: usize>(scheduler: &mut BlockScheduler<N>) -> Vec<(u16, MidiMessage)> {
+        let mut output = Vec::new();
+        scheduler.render_block_with(&mut |offset, message| output.push((offset, message)));
+        output
+    }
+
+    /// 64 sample blocks at 48 kHz, a byte takes 15.36 samples at 31250 baud
+    fn scheduler() -> BlockScheduler<8> {
+        BlockScheduler::new(64, 48_000, WireRate::Midi31250)
+    }
+
+    #[test]
+    fn should_send_in_offset_order() {
+        let mut scheduler = BlockScheduler::<8>::new(64, 48_000, WireRate::Custom(1_000_000));
+        scheduler.schedule_in_block(40, &note_on(0, 62, 100));
+        scheduler.schedule_in_block(3, &note_on(0, 60, 100));
+        scheduler.schedule_in_block(40, &note_off(0, 60, 0));
+        scheduler.schedule_in_block(20, &cc(0, 7, 1));
+        assert_eq!(
+            render(&mut scheduler),
+            [
+                (3, note_on(0, 60, 100)),
+                (20, cc(0, 7, 1)),
+                (40, note_on(0, 62, 100)),
+                // A byte takes 0.48 samples at 1 Mbaud
+                (41, note_off(0, 60, 0)),
+            ]
+        );
+        assert_eq!(scheduler.pending(), 0);
+    }
+
+    #[test]
+    fn should_split_offsets_across_blocks() {
+        let mut scheduler = scheduler();
+        scheduler.schedule_in_block(150, &note_on(0, 64, 100));
+        scheduler.schedule_in_block(10, &note_on(0, 60, 100));
+        scheduler.schedule_in_block(64, &note_on(0, 62, 100));
+
+        assert_eq!(render(&mut scheduler), [(10, note_on(0, 60, 100))]);
+        assert_eq!(render(&mut scheduler), [(0, note_on(0, 62, 100))]);
+        assert_eq!(render(&mut scheduler), [(22, note_on(0, 64, 100))]);
+        assert_eq!(render(&mut scheduler), []);
+    }
+
+    #[test]
+    fn should_carry_messages_the_wire_cant_keep_up_with() {
+        let mut scheduler = scheduler();
+        // Six 3 byte messages need 276 samples of wire time
+        for note in 0..6 {
+            scheduler.schedule_in_block(0, &note_on(0, note, 100));
+        }
+        scheduler.schedule_in_block(70, &note_off(0, 0, 0));
+
+        let mut output = Vec::new();
+        for block in 0..6u32 {
+            for (offset, message) in render(&mut scheduler) {
+                output.push((block * 64 + u32::from(offset), message));
+            }
+            // Messages scheduled in a later block wait for the overdue ones
+            if block == 1 {
+                scheduler.schedule_in_block(0, &note_off(0, 1, 0));
+            }
+        }
+        assert_eq!(
+            output,
+            [
+                (0, note_on(0, 0, 100)),
+                (46, note_on(0, 1, 100)),
+                (92, note_on(0, 2, 100)),
+                (138, note_on(0, 3, 100)),
+                (184, note_on(0, 4, 100)),
+                (230, note_on(0, 5, 100)),
+                (276, note_off(0, 0, 0)),
+                (322, note_off(0, 1, 0)),
+            ]
+        );
+        assert_eq!(scheduler.pending(), 0);
+    }
+
+    #[test]
+    fn should_carry_messages_that_dont_fit_the_buffer() {
+        let mut scheduler = BlockScheduler::<2>::new(64, 48_000, WireRate::Custom(1_000_000));
+        scheduler.schedule_in_block(1, &note_on(0, 60, 100));
+        scheduler.schedule_in_block(2, &cc(1, 7, 1));
+        scheduler.schedule_in_block(3, &cc(1, 7, 2));
+        assert_eq!(scheduler.dropped(), 1);
+
+        let mut buffer = [0; 4];
+        assert_eq!(scheduler.render_block(&mut buffer), 3);
+        assert_eq!(buffer[..3], [0x90, 60, 100]);
+        assert_eq!(scheduler.render_block(&mut buffer), 3);
+        assert_eq!(buffer[..3], [0xb1, 7, 1]);
+    }
+
+    #[test]
+    fn should_render_to_midi_out() {
+        let mut scheduler = scheduler();
+        scheduler.schedule_in_block(5, &note_on(0, 60, 100));
+        scheduler.schedule_in_block(60, &note_on(0, 62, 100));
+        let expectations = [
+            Transaction::write_many([0x90, 60, 100]),
+            Transaction::write_many([62, 100]),
+        ];
+        let mut midi_out = MidiOut::new(Mock::new(&expectations));
+        scheduler.render_block_to(&mut midi_out).unwrap();
+        assert_eq!(scheduler.pending(), 0);
+        midi_out.release().done();
+    }
+}
@@ -0,0 +1,212 @@
+//! Handing every received message to several consumers
+
+use crate::{diag::log_debug, FullTable};
+use core::cell::RefCell;
+use midi_convert::midi_types::MidiMessage;
+
+/// Queue of the messages a consumer did not receive yet
+#[derive(Debug)]
+struct ConsumerQueue<const DEPTH: usize> {
+    messages: [Option<MidiMessage>; DEPTH],
+    head: usize,
+    len: usize,
+    dropped: u32,
+}
+
+impl<const DEPTH: usize> ConsumerQueue<DEPTH> {
+    fn new() -> Self {
+        ConsumerQueue {
+            messages: [None; DEPTH],
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Add a message, dropping the oldest message if the queue is full
+    fn push(&mut self, message: MidiMessage) {
+        if DEPTH == 0 {
+            self.dropped = self.dropped.wrapping_add(1);
+            return;
+        }
+        if self.len == DEPTH {
+            log_debug!("broadcast: consumer queue full, dropped its oldest message");
+            self.pop();
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+        self.messages[(self.head + self.len) % DEPTH] = Some(message);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<MidiMessage> {
+        if self.len == 0 {
+            return None;
+        }
+        let message = self.messages[self.head].take();
+        self.head = (self.head + 1) % DEPTH;
+        self.len -= 1;
+        message
+    }
+}
+
+/// Copies published messages to up to `N` consumers, each with its own queue of `DEPTH` messages
+///
+/// Consumers `subscribe` to get a `Subscriber` handle and receive the messages published after
+/// that at their own pace. When a consumer falls behind its oldest message is dropped and counted,
+/// other consumers are not affected. Dropping a handle frees its place for another consumer. The
+/// queues are in a `RefCell`, so the broadcast can't be shared with interrupt handlers.
+#[derive(Debug)]
+pub struct Broadcast<const N: usize = 4, const DEPTH: usize = 16> {
+    queues: RefCell<[Option<ConsumerQueue<DEPTH>>; N]>,
+}
+
+impl<const N: usize, const DEPTH: usize> Default for Broadcast<N, DEPTH> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const DEPTH: usize> Broadcast<N, DEPTH> {
+    pub fn new() -> Self {
+        Broadcast {
+            queues: RefCell::new(core::array::from_fn(|_| None)),
+        }
+    }
+
+    /// Register a consumer, fails when `N` consumers are registered
+    pub fn subscribe(&self) -> Result<Subscriber<'_, N, DEPTH>, FullTable> {
+        let mut queues = self.queues.borrow_mut();
+        let index = queues.iter().position(Option::is_none).ok_or(FullTable)?;
+        queues[index] = Some(ConsumerQueue::new());
+        Ok(Subscriber {
+            broadcast: self,
+            index,
+        })
+    }
+
+    /// Queue a copy of `message` for every consumer
+    pub fn publish(&self, message: &MidiMessage) {
+        for queue in self.queues.borrow_mut().iter_mut().flatten() {
+            queue.push(*message);
+        }
+    }
+
+    /// Number of registered consumers
+    pub fn subscribers(&self) -> usize {
+        self.queues.borrow().iter().flatten().count()
+    }
+
+    fn with_queue<T>(&self, index: usize, f: impl FnOnce(&mut ConsumerQueue<DEPTH>) -> T) -> T {
+        match &mut self.queues.borrow_mut()[index] {
+            Some(queue) => f(queue),
+            // Handles only exist for registered consumers
+            None => unreachable!(),
+        }
+    }
+}
+
+/// Handle of a consumer registered with `Broadcast::subscribe`
+#[derive(Debug)]
+pub struct Subscriber<'a, const N: usize, const DEPTH: usize> {
+    broadcast: &'a Broadcast<N, DEPTH>,
+    index: usize,
+}
+
+impl<const N: usize, const DEPTH: usize> Subscriber<'_, N, DEPTH> {
+    /// Oldest message this consumer did not receive yet
+    pub fn recv(&self) -> Option<MidiMessage> {
+        self.broadcast.with_queue(self.index, ConsumerQueue::pop)
+    }
+
+    /// Number of messages waiting for this consumer
+    pub fn len(&self) -> usize {
+        self.broadcast.with_queue(self.index, |queue| queue.len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of messages dropped because this consumer fell behind
+    pub fn dropped(&self) -> u32 {
+        self.broadcast.with_queue(self.index, |queue| queue.dropped)
+    }
+}
+
+impl<const N: usize, const DEPTH: usize> Drop for Subscriber<'_, N, DEPTH> {
+    fn drop(&mut self) {
+        self.broadcast.queues.borrow_mut()[self.index] = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_on};
+    use std::vec::Vec;
+
+    fn recv_all<const N: usize, const DEPTH: usize>(
+        subscriber: &Subscriber<'_, N, DEPTH>,
+    ) -> Vec<MidiMessage> {
+        core::iter::from_fn(|| subscriber.recv()).collect()
+    }
+
+    #[test]
+    fn should_copy_messages_to_every_consumer() {
+        let broadcast = Broadcast::<3, 8>::new();
+        let display = broadcast.subscribe().unwrap();
+        let engine = broadcast.subscribe().unwrap();
+
+        broadcast.publish(&note_on(0, 60, 100));
+        broadcast.publish(&cc(0, 7, 90));
+
+        assert_eq!(recv_all(&display), [note_on(0, 60, 100), cc(0, 7, 90)]);
+        assert_eq!(recv_all(&engine), [note_on(0, 60, 100), cc(0, 7, 90)]);
+        assert_eq!(display.recv(), None);
+    }
+
+    #[test]
+    fn should_let_consumers_receive_at_their_own_rate() {
+        let broadcast = Broadcast::<3, 4>::new();
+        let fast = broadcast.subscribe().unwrap();
+        let slow = broadcast.subscribe().unwrap();
+
+        let mut fast_received = Vec::new();
+        for note in 0..10 {
+            broadcast.publish(&note_on(0, note, 100));
+            fast_received.extend(fast.recv());
+        }
+        let slow_received = recv_all(&slow);
+
+        assert_eq!(fast_received.len(), 10);
+        assert_eq!(fast.dropped(), 0);
+        // The slow consumer keeps the newest messages
+        assert_eq!(
+            slow_received,
+            (6..10)
+                .map(|note| note_on(0, note, 100))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(slow.dropped(), 6);
+        assert!(slow.is_empty());
+    }
+
+    #[test]
+    fn should_limit_consumers_and_free_dropped_handles() {
+        let broadcast = Broadcast::<2, 4>::new();
+        let first = broadcast.subscribe().unwrap();
+        let second = broadcast.subscribe().unwrap();
+        assert_eq!(broadcast.subscribe().err(), Some(FullTable));
+
+        broadcast.publish(&note_on(0, 60, 100));
+        drop(first);
+        assert_eq!(broadcast.subscribers(), 1);
+
+        // A new consumer only receives messages published after it subscribed
+        let third = broadcast.subscribe().unwrap();
+        broadcast.publish(&note_on(0, 61, 100));
+        assert_eq!(recv_all(&third), [note_on(0, 61, 100)]);
+        assert_eq!(second.len(), 2);
+    }
+}
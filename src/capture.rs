@@ -0,0 +1,330 @@
+//! Recording received messages to flash and replaying them
+
+use crate::{
+    diag::log_debug,
+    parse::{data_length, MidiParser},
+    CompactMessage, DecodeError, TooSmall,
+};
+use midi_convert::midi_types::MidiMessage;
+
+/// Number of bytes of an encoded `CaptureRecord`
+pub const CAPTURE_RECORD_LEN: usize = 8;
+
+/// Check byte seed, so erased flash doesn't decode as a record
+const CHECK_SEED: u8 = 0x5a;
+
+/// A captured message and the tick it was received at
+///
+/// Records are encoded into `CAPTURE_RECORD_LEN` bytes: the tick in little endian, the message in
+/// its 3 byte wire format padded with 0 and a check byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureRecord {
+    pub tick: u32,
+    pub message: MidiMessage,
+}
+
+impl CaptureRecord {
+    pub fn new(tick: u32, message: MidiMessage) -> Self {
+        CaptureRecord { tick, message }
+    }
+
+    pub fn encode(&self) -> [u8; CAPTURE_RECORD_LEN] {
+        let mut bytes = [0; CAPTURE_RECORD_LEN];
+        bytes[..4].copy_from_slice(&self.tick.to_le_bytes());
+        let message = CompactMessage::from(&self.message);
+        bytes[4..4 + message.as_bytes().len()].copy_from_slice(message.as_bytes());
+        bytes[7] = check(&bytes[..7]);
+        bytes
+    }
+
+    /// Decode a record written by `encode`, fails for erased or corrupted records
+    pub fn decode(bytes: &[u8; CAPTURE_RECORD_LEN]) -> Result<Self, DecodeError> {
+        if bytes[7] != check(&bytes[..7]) {
+            return Err(DecodeError::Invalid);
+        }
+        let status = bytes[4];
+        let len = data_length(status) as usize + 1;
+        // Unused data bytes are 0, so a record has only one encoding
+        if status < 0x80
+            || bytes[5..4 + len].iter().any(|byte| *byte >= 0x80)
+            || bytes[4 + len..7].iter().any(|byte| *byte != 0)
+        {
+            return Err(DecodeError::Invalid);
+        }
+        let mut parser = MidiParser::new();
+        let mut message = None;
+        for byte in &bytes[4..4 + len] {
+            message = parser.parse(*byte);
+        }
+        let tick = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        message
+            .map(|message| CaptureRecord { tick, message })
+            .ok_or(DecodeError::Invalid)
+    }
+}
+
+fn check(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(CHECK_SEED, |check, byte| check ^ byte)
+}
+
+/// Appends records to a page buffer, to be written to flash when full
+///
+/// Pages are filled with whole records, the bytes left at the end of a page whose length isn't a
+/// multiple of `CAPTURE_RECORD_LEN` are not used.
+#[derive(Debug)]
+pub struct CaptureWriter<'a> {
+    page: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> CaptureWriter<'a> {
+    pub fn new(page: &'a mut [u8]) -> Self {
+        CaptureWriter { page, len: 0 }
+    }
+
+    /// Append a record, returns `true` when the page is full afterwards
+    ///
+    /// Fails when the page was already full.
+    pub fn append(&mut self, record: &CaptureRecord) -> Result<bool, TooSmall> {
+        let slot = self
+            .page
+            .get_mut(self.len..self.len + CAPTURE_RECORD_LEN)
+            .ok_or(TooSmall)?;
+        slot.copy_from_slice(&record.encode());
+        self.len += CAPTURE_RECORD_LEN;
+        Ok(self.is_full())
+    }
+
+    /// Append `message` received at `tick`, see `append`
+    pub fn record(&mut self, tick: u32, message: &MidiMessage) -> Result<bool, TooSmall> {
+        self.append(&CaptureRecord::new(tick, *message))
+    }
+
+    /// No further record fits into the page
+    pub fn is_full(&self) -> bool {
+        self.page.len() - self.len < CAPTURE_RECORD_LEN
+    }
+
+    /// The records appended to the page
+    pub fn written(&self) -> &[u8] {
+        &self.page[..self.len]
+    }
+
+    /// Continue in `page`, returns the previous page
+    pub fn swap_page(&mut self, page: &'a mut [u8]) -> &'a mut [u8] {
+        self.len = 0;
+        core::mem::replace(&mut self.page, page)
+    }
+}
+
+/// Replays captured records with their original timing
+///
+/// The first record is played when `start` is called, every following record as long after that
+/// as its tick is after the tick of the first record. Records that don't decode are skipped and
+/// counted. As an iterator the player returns all records that decode, ignoring the timing.
+#[derive(Debug, Clone)]
+pub struct CapturePlayer<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    /// Time of `start` minus the tick of the first record
+    offset: Option<u32>,
+    skipped: u32,
+}
+
+impl<'a> CapturePlayer<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        CapturePlayer {
+            bytes,
+            position: 0,
+            offset: None,
+            skipped: 0,
+        }
+    }
+
+    /// Start playing from the next record at time `now`
+    pub fn start(&mut self, now: u32) {
+        self.offset = self.peek().map(|record| now.wrapping_sub(record.tick));
+    }
+
+    /// Call `emit` with every record due at time `now`
+    ///
+    /// Nothing is played before `start`.
+    pub fn poll(&mut self, now: u32, emit: &mut dyn FnMut(MidiMessage)) {
+        let offset = match self.offset {
+            Some(offset) => offset,
+            None => return,
+        };
+        let tick = now.wrapping_sub(offset);
+        while let Some(record) = self.peek() {
+            // Compared as a signed difference, so ticks may wrap around
+            if (tick.wrapping_sub(record.tick) as i32) < 0 {
+                break;
+            }
+            self.next();
+            emit(record.message);
+        }
+    }
+
+    /// All records were played
+    pub fn is_finished(&self) -> bool {
+        self.peek().is_none()
+    }
+
+    /// Number of records skipped because they don't decode
+    pub fn skipped(&self) -> u32 {
+        self.skipped
+    }
+
+    /// Next record that decodes, skipping the ones before it
+    fn peek(&self) -> Option<CaptureRecord> {
+        self.clone().next()
+    }
+}
+
+impl Iterator for CapturePlayer<'_> {
+    type Item = CaptureRecord;
+
+    fn next(&mut self) -> Option<CaptureRecord> {
+        while let Some(bytes) = self
+            .bytes
+            .get(self.position..self.position + CAPTURE_RECORD_LEN)
+        {
+            self.position += CAPTURE_RECORD_LEN;
+            let mut record = [0; CAPTURE_RECORD_LEN];
+            record.copy_from_slice(bytes);
+            match CaptureRecord::decode(&record) {
+                Ok(record) => return Some(record),
+                Err(_) => {
+                    log_debug!("capture: skipped undecodable record");
+                    self.skipped = self.skipped.wrapping_add(1);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_off, note_on, pitch_bend, program_change};
+    use midi_convert::midi_types::{QuarterFrame, Value14};
+    use std::vec::Vec;
+
+    fn stream() -> Vec<CaptureRecord> {
+        [
+            (1_000, MidiMessage::Start),
+            (1_000, note_on(0, 60, 100)),
+            (1_005, cc(3, 7, 90)),
+            (1_010, MidiMessage::TimingClock),
+            (1_020, pitch_bend(15, -8192)),
+            (1_020, program_change(9, 127)),
+            (
+                1_500,
+                MidiMessage::SongPositionPointer(Value14::from(300u16)),
+            ),
+            (1_500, MidiMessage::QuarterFrame(QuarterFrame::from(0x35))),
+            (2_000, note_off(0, 60, 0)),
+            (2_000, MidiMessage::TuneRequest),
+        ]
+        .iter()
+        .map(|(tick, message)| CaptureRecord::new(*tick, *message))
+        .collect()
+    }
+
+    fn capture(records: &[CaptureRecord], page_len: usize) -> Vec<Vec<u8>> {
+        let mut pages = Vec::new();
+        let mut first = std::vec![0xff; page_len];
+        let mut second = std::vec![0xff; page_len];
+        let mut writer = CaptureWriter::new(&mut first);
+        let mut spare = &mut second[..];
+        for record in records {
+            if writer.append(record).unwrap() {
+                pages.push(writer.written().to_vec());
+                spare = writer.swap_page(spare);
+            }
+        }
+        pages.push(writer.written().to_vec());
+        pages
+    }
+
+    #[test]
+    fn should_round_trip_records() {
+        for record in stream() {
+            assert_eq!(CaptureRecord::decode(&record.encode()), Ok(record));
+        }
+        assert_eq!(
+            CaptureRecord::decode(&[0xff; CAPTURE_RECORD_LEN]),
+            Err(DecodeError::Invalid)
+        );
+    }
+
+    #[test]
+    fn should_fill_pages_and_replay_them() {
+        let records = stream();
+        // 3 records per page, 4 bytes left unused
+        let pages = capture(&records, 28);
+        assert_eq!(pages.len(), 4);
+        assert_eq!(pages[3].len(), CAPTURE_RECORD_LEN);
+
+        let flash = pages.concat();
+        assert_eq!(CapturePlayer::new(&flash).collect::<Vec<_>>(), records);
+    }
+
+    #[test]
+    fn should_reject_appending_to_full_page() {
+        let mut page = [0; 12];
+        let mut writer = CaptureWriter::new(&mut page);
+        assert_eq!(writer.record(0, &MidiMessage::Start), Ok(true));
+        assert_eq!(writer.record(1, &MidiMessage::Stop), Err(TooSmall));
+        assert_eq!(writer.written().len(), CAPTURE_RECORD_LEN);
+    }
+
+    #[test]
+    fn should_replay_at_original_timing() {
+        let flash = capture(&stream(), 256).concat();
+        let mut player = CapturePlayer::new(&flash);
+        let mut output = Vec::new();
+
+        player.poll(0, &mut |message| output.push(message));
+        assert!(output.is_empty());
+
+        player.start(u32::MAX - 9);
+        let mut played = Vec::new();
+        for now in [u32::MAX - 9, 0, 9, 10, 489, 490, 990] {
+            player.poll(now, &mut |message| output.push(message));
+            played.push(output.len());
+        }
+
+        assert_eq!(played, [2, 4, 4, 6, 6, 8, 10]);
+        assert!(player.is_finished());
+    }
+
+    #[test]
+    fn should_skip_corrupted_records() {
+        let records = stream();
+        let mut flash = capture(&records, 256).concat();
+        // Flipped bit, sysex status and a data byte where a status is expected
+        flash[2 * CAPTURE_RECORD_LEN] ^= 0x01;
+        let mut sysex = CaptureRecord::new(0, MidiMessage::Start).encode();
+        sysex[4] = 0xf0;
+        sysex[7] = check(&sysex[..7]);
+        flash[4 * CAPTURE_RECORD_LEN..5 * CAPTURE_RECORD_LEN].copy_from_slice(&sysex);
+        flash[9 * CAPTURE_RECORD_LEN + 4] = 0x10;
+        // A partial record at the end
+        flash.extend_from_slice(&[0x00, 0x01]);
+
+        let mut player = CapturePlayer::new(&flash);
+        let replayed: Vec<_> = player.by_ref().collect();
+
+        let expected: Vec<_> = records
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| ![2, 4, 9].contains(index))
+            .map(|(_, record)| *record)
+            .collect();
+        assert_eq!(replayed, expected);
+        assert_eq!(player.skipped(), 3);
+    }
+}
@@ -0,0 +1,369 @@
+//! Remapping control changes to other controllers, channel pressure or pitch bend
+
+use crate::{
+    persist::{Reader, Writer},
+    DecodeError, FullTable, MidiProcessor, TooSmall,
+};
+use midi_convert::midi_types::{Channel, Control, MidiMessage, Value14};
+
+/// Highest 14 bit value
+const MAX_VALUE14: u16 = 0x3fff;
+
+/// What a remapped control change is turned into, on the channel it was received on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CcTarget {
+    /// Control change for another controller with the same value
+    Control(Control),
+    ChannelPressure,
+    /// Pitch bend scaled from `low` for value 0 to `high` for value 127
+    ///
+    /// Values are 0 to 16383 with 8192 meaning no bend, `low: 8192, high: 16383` bends up.
+    PitchBend {
+        low: u16,
+        high: u16,
+    },
+}
+
+/// Remaps control changes for `control` on `channel`, or on every channel when `channel` is `None`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CcRule {
+    pub channel: Option<Channel>,
+    pub control: Control,
+    pub target: CcTarget,
+}
+
+impl CcRule {
+    fn matches(&self, channel: Channel, control: Control) -> bool {
+        self.control == control && self.channel.map_or(true, |rule| rule == channel)
+    }
+}
+
+/// Expand a 7 bit value to 14 bits by repeating its bits, so 127 maps to 16383
+fn expand(value: u8) -> u16 {
+    let value = value as u16 & 0x7f;
+    (value << 7) | value
+}
+
+/// Remaps control changes using a table of up to `RULES` rules
+///
+/// Rules are tried in the order they were added, the first matching rule is applied. Control
+/// changes without a matching rule and all other messages are passed on.
+#[derive(Debug, Clone)]
+pub struct CcRemap<const RULES: usize = 8> {
+    rules: [Option<CcRule>; RULES],
+}
+
+impl<const RULES: usize> Default for CcRemap<RULES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const RULES: usize> CcRemap<RULES> {
+    /// Largest number of bytes written by `save`
+    pub const MAX_SAVED_LEN: usize = 3 + 7 * RULES;
+
+    pub fn new() -> Self {
+        CcRemap {
+            rules: [None; RULES],
+        }
+    }
+
+    /// Add a rule after the existing rules, replacing the rule for the same channel and control
+    pub fn add(&mut self, rule: CcRule) -> Result<(), FullTable> {
+        let slot = match self.position(rule.channel, rule.control) {
+            Some(index) => &mut self.rules[index],
+            None => self
+                .rules
+                .iter_mut()
+                .find(|slot| slot.is_none())
+                .ok_or(FullTable)?,
+        };
+        *slot = Some(rule);
+        Ok(())
+    }
+
+    /// Remove the rule for a channel and control, returns the removed rule
+    pub fn remove(&mut self, channel: Option<Channel>, control: Control) -> Option<CcRule> {
+        let index = self.position(channel, control)?;
+        let rule = self.rules[index].take();
+        // Keep the remaining rules in order without gaps
+        self.rules[index..].rotate_left(1);
+        rule
+    }
+
+    pub fn clear(&mut self) {
+        self.rules = [None; RULES];
+    }
+
+    /// The rule applied to control changes for `control` on `channel`
+    pub fn rule_for(&self, channel: Channel, control: Control) -> Option<&CcRule> {
+        self.rules
+            .iter()
+            .flatten()
+            .find(|rule| rule.matches(channel, control))
+    }
+
+    /// Save the rules into `buffer`, returns the number of bytes written
+    pub fn save(&self, buffer: &mut [u8]) -> Result<usize, TooSmall> {
+        let mut writer = Writer::new(buffer)?;
+        writer.u16(self.rules.iter().flatten().count() as u16)?;
+        for rule in self.rules.iter().flatten() {
+            // 0xff stands for any channel
+            writer.byte(rule.channel.map_or(0xff, u8::from))?;
+            writer.byte(rule.control.into())?;
+            match rule.target {
+                CcTarget::Control(control) => {
+                    writer.byte(0)?;
+                    writer.byte(control.into())?;
+                }
+                CcTarget::ChannelPressure => writer.byte(1)?,
+                CcTarget::PitchBend { low, high } => {
+                    writer.byte(2)?;
+                    // Larger values behave like the highest value, so they are saved as it
+                    writer.u16(low.min(MAX_VALUE14))?;
+                    writer.u16(high.min(MAX_VALUE14))?;
+                }
+            }
+        }
+        Ok(writer.finish())
+    }
+
+    /// Load rules saved by `save`, in the same order
+    pub fn load(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes)?;
+        let mut remap = Self::new();
+        for _ in 0..reader.u16()? {
+            let channel = match reader.byte()? {
+                0xff => None,
+                channel @ 0..=0x0f => Some(channel.into()),
+                _ => return Err(DecodeError::Invalid),
+            };
+            let control = reader.value7()?.into();
+            let target = match reader.byte()? {
+                0 => CcTarget::Control(reader.value7()?.into()),
+                1 => CcTarget::ChannelPressure,
+                2 => CcTarget::PitchBend {
+                    low: reader.value14()?,
+                    high: reader.value14()?,
+                },
+                _ => return Err(DecodeError::Invalid),
+            };
+            let rule = CcRule {
+                channel,
+                control,
+                target,
+            };
+            remap.add(rule).map_err(|_| DecodeError::Invalid)?;
+        }
+        Ok(remap)
+    }
+
+    fn position(&self, channel: Option<Channel>, control: Control) -> Option<usize> {
+        self.rules
+            .iter()
+            .position(|r| matches!(r, Some(r) if r.channel == channel && r.control == control))
+    }
+}
+
+impl<const RULES: usize> MidiProcessor for CcRemap<RULES> {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        let (channel, control, value) = match message {
+            MidiMessage::ControlChange(channel, control, value) => (channel, control, value),
+            _ => return emit(message),
+        };
+
+        let remapped = match self.rule_for(channel, control).map(|rule| rule.target) {
+            Some(CcTarget::Control(control)) => MidiMessage::ControlChange(channel, control, value),
+            Some(CcTarget::ChannelPressure) => MidiMessage::ChannelPressure(channel, value),
+            Some(CcTarget::PitchBend { low, high }) => {
+                let low = low.min(MAX_VALUE14) as i32;
+                let high = high.min(MAX_VALUE14) as i32;
+                let scaled = low + (high - low) * expand(value.into()) as i32 / MAX_VALUE14 as i32;
+                MidiMessage::PitchBendChange(channel, Value14::from(scaled as u16))
+            }
+            None => message,
+        };
+        emit(remapped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{cc, channel_pressure, note_on};
+    use crate::test_util::process;
+
+    fn bend(channel: u8, value: u16) -> MidiMessage {
+        MidiMessage::PitchBendChange(channel.into(), Value14::from(value))
+    }
+
+    fn rule(channel: Option<u8>, control: u8, target: CcTarget) -> CcRule {
+        CcRule {
+            channel: channel.map(Channel::from),
+            control: control.into(),
+            target,
+        }
+    }
+
+    #[test]
+    fn should_expand_with_bit_replication() {
+        assert_eq!(expand(0), 0);
+        assert_eq!(expand(1), 0x81);
+        assert_eq!(expand(64), 0x2040);
+        assert_eq!(expand(127), 0x3fff);
+    }
+
+    #[test]
+    fn should_remap_to_control_and_channel_pressure() {
+        let mut remap = CcRemap::<4>::new();
+        remap.add(rule(None, 2, CcTarget::ChannelPressure)).unwrap();
+        remap
+            .add(rule(None, 11, CcTarget::Control(7.into())))
+            .unwrap();
+
+        assert_eq!(
+            process(
+                &mut remap,
+                &[
+                    cc(3, 2, 90),
+                    cc(3, 11, 40),
+                    cc(3, 12, 40),
+                    note_on(3, 2, 90)
+                ]
+            ),
+            [
+                channel_pressure(3, 90),
+                cc(3, 7, 40),
+                cc(3, 12, 40),
+                note_on(3, 2, 90)
+            ]
+        );
+    }
+
+    #[test]
+    fn should_scale_pitch_bend_extremes() {
+        let mut remap = CcRemap::<4>::new();
+        let full = CcTarget::PitchBend {
+            low: 0,
+            high: 16383,
+        };
+        let up = CcTarget::PitchBend {
+            low: 8192,
+            high: 16383,
+        };
+        let inverted = CcTarget::PitchBend {
+            low: 16383,
+            high: 0,
+        };
+        remap.add(rule(None, 1, full)).unwrap();
+        remap.add(rule(None, 2, up)).unwrap();
+        remap.add(rule(None, 3, inverted)).unwrap();
+
+        assert_eq!(
+            process(
+                &mut remap,
+                &[
+                    cc(0, 1, 0),
+                    cc(0, 1, 127),
+                    cc(0, 1, 64),
+                    cc(0, 2, 0),
+                    cc(0, 2, 127),
+                    cc(0, 3, 0),
+                    cc(0, 3, 127),
+                ]
+            ),
+            [
+                bend(0, 0),
+                bend(0, 16383),
+                bend(0, 0x2040),
+                bend(0, 8192),
+                bend(0, 16383),
+                bend(0, 16383),
+                bend(0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_apply_first_matching_rule() {
+        let mut remap = CcRemap::<4>::new();
+        remap
+            .add(rule(Some(1), 1, CcTarget::ChannelPressure))
+            .unwrap();
+        remap
+            .add(rule(None, 1, CcTarget::Control(74.into())))
+            .unwrap();
+
+        assert_eq!(
+            process(&mut remap, &[cc(1, 1, 10), cc(2, 1, 10)]),
+            [channel_pressure(1, 10), cc(2, 74, 10)]
+        );
+
+        // Replacing a rule keeps its place, removing it lets later rules match
+        remap
+            .add(rule(Some(1), 1, CcTarget::Control(71.into())))
+            .unwrap();
+        assert_eq!(process(&mut remap, &[cc(1, 1, 10)]), [cc(1, 71, 10)]);
+        assert!(remap.remove(Some(1.into()), 1.into()).is_some());
+        assert_eq!(process(&mut remap, &[cc(1, 1, 10)]), [cc(1, 74, 10)]);
+    }
+
+    #[test]
+    fn should_keep_rule_order_after_remove() {
+        let mut remap = CcRemap::<2>::new();
+        remap
+            .add(rule(Some(1), 1, CcTarget::ChannelPressure))
+            .unwrap();
+        remap
+            .add(rule(None, 1, CcTarget::Control(74.into())))
+            .unwrap();
+        assert_eq!(
+            remap.add(rule(None, 2, CcTarget::ChannelPressure)),
+            Err(FullTable)
+        );
+
+        remap.remove(Some(1.into()), 1.into());
+        remap
+            .add(rule(Some(1), 1, CcTarget::ChannelPressure))
+            .unwrap();
+        assert_eq!(process(&mut remap, &[cc(1, 1, 10)]), [cc(1, 74, 10)]);
+
+        remap.clear();
+        assert_eq!(process(&mut remap, &[cc(1, 1, 10)]), [cc(1, 1, 10)]);
+    }
+
+    #[test]
+    fn should_round_trip_saved_rules() {
+        let mut remap = CcRemap::<4>::new();
+        remap
+            .add(rule(Some(1), 1, CcTarget::ChannelPressure))
+            .unwrap();
+        remap
+            .add(rule(None, 1, CcTarget::Control(74.into())))
+            .unwrap();
+        let up = CcTarget::PitchBend {
+            low: 8192,
+            high: 16383,
+        };
+        remap.add(rule(Some(15), 2, up)).unwrap();
+
+        let mut buffer = [0; CcRemap::<4>::MAX_SAVED_LEN];
+        let len = remap.save(&mut buffer).unwrap();
+        assert_eq!(len, 3 + 3 + 4 + 7);
+        assert_eq!(remap.save(&mut buffer[..len - 1]), Err(TooSmall));
+
+        let loaded = CcRemap::<4>::load(&buffer[..len]).unwrap();
+        assert_eq!(loaded.rules, remap.rules);
+        assert_eq!(
+            CcRemap::<2>::load(&buffer[..len]).err(),
+            Some(DecodeError::Invalid)
+        );
+
+        buffer[0] = 2;
+        assert_eq!(
+            CcRemap::<4>::load(&buffer).err(),
+            Some(DecodeError::UnsupportedVersion(2))
+        );
+    }
+}
@@ -0,0 +1,60 @@
+//! Converting channels from and to the 1 based numbers users see
+
+use midi_convert::midi_types::Channel;
+
+/// Converts channels from and to the numbers 1 to 16 shown by instruments and DAWs
+///
+/// `Channel` holds the 0 based value sent on the wire, `Channel::C1` to `Channel::C16` name the
+/// channels by their 1 based number. Use `display` when logging a channel so it matches what users
+/// see, the `Debug` output of `Channel` shows the wire value.
+///
+/// ```
+/// use embedded_midi::midi_types::Channel;
+/// use embedded_midi::ChannelExt;
+///
+/// let drums = Channel::from_display(10).unwrap();
+/// assert_eq!(drums, Channel::C10);
+/// assert_eq!(u8::from(drums), 9);
+/// assert_eq!(drums.display(), 10);
+/// ```
+pub trait ChannelExt: Sized {
+    /// Channel from its number from 1 to 16, `None` for other numbers
+    fn from_display(number: u8) -> Option<Self>;
+
+    /// Number of the channel from 1 to 16
+    fn display(&self) -> u8;
+}
+
+impl ChannelExt for Channel {
+    fn from_display(number: u8) -> Option<Self> {
+        match number {
+            1..=16 => Some(Channel::new(number - 1)),
+            _ => None,
+        }
+    }
+
+    fn display(&self) -> u8 {
+        u8::from(*self) + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::format;
+
+    #[test]
+    fn should_convert_display_numbers() {
+        assert_eq!(u8::from(Channel::C10), 9);
+        assert_eq!(format!("{}", Channel::C10.display()), "10");
+        for number in 1..=16 {
+            let channel = Channel::from_display(number).unwrap();
+            assert_eq!(u8::from(channel), number - 1);
+            assert_eq!(channel.display(), number);
+        }
+        assert_eq!(Channel::from_display(0), None);
+        assert_eq!(Channel::from_display(17), None);
+        assert_eq!(Channel::from_display(0xff), None);
+    }
+}
@@ -0,0 +1,160 @@
+//! Channel mode messages, the control changes 120 to 127
+
+use crate::{MidiError, MidiOut};
+use core::fmt::Debug;
+use embedded_hal_nb::serial;
+use midi_convert::midi_types::{Channel, MidiMessage};
+
+const ALL_SOUND_OFF: u8 = 120;
+const RESET_ALL_CONTROLLERS: u8 = 121;
+const LOCAL_CONTROL: u8 = 122;
+const ALL_NOTES_OFF: u8 = 123;
+const OMNI_OFF: u8 = 124;
+const OMNI_ON: u8 = 125;
+const MONO_ON: u8 = 126;
+const POLY_ON: u8 = 127;
+
+/// A channel mode message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelModeEvent {
+    AllSoundOff,
+    ResetAllControllers,
+    /// Local control on or off
+    LocalControl(bool),
+    AllNotesOff,
+    /// Omni mode on or off
+    OmniMode(bool),
+    /// Mono mode using the given number of channels, 0 for as many channels as there are voices
+    MonoMode(u8),
+    PolyMode,
+}
+
+impl ChannelModeEvent {
+    /// Classify a control change as channel mode message, `None` for other messages
+    pub fn from_message(message: &MidiMessage) -> Option<(Channel, ChannelModeEvent)> {
+        let (channel, control, value) = match *message {
+            MidiMessage::ControlChange(channel, control, value) => {
+                (channel, u8::from(control), u8::from(value))
+            }
+            _ => return None,
+        };
+
+        let event = match control {
+            ALL_SOUND_OFF => ChannelModeEvent::AllSoundOff,
+            RESET_ALL_CONTROLLERS => ChannelModeEvent::ResetAllControllers,
+            LOCAL_CONTROL => ChannelModeEvent::LocalControl(value >= 64),
+            ALL_NOTES_OFF => ChannelModeEvent::AllNotesOff,
+            OMNI_OFF => ChannelModeEvent::OmniMode(false),
+            OMNI_ON => ChannelModeEvent::OmniMode(true),
+            MONO_ON => ChannelModeEvent::MonoMode(value),
+            POLY_ON => ChannelModeEvent::PolyMode,
+            _ => return None,
+        };
+        Some((channel, event))
+    }
+
+    /// Control change for this event on `channel`
+    pub fn to_message(self, channel: Channel) -> MidiMessage {
+        let (control, value) = match self {
+            ChannelModeEvent::AllSoundOff => (ALL_SOUND_OFF, 0),
+            ChannelModeEvent::ResetAllControllers => (RESET_ALL_CONTROLLERS, 0),
+            ChannelModeEvent::LocalControl(on) => (LOCAL_CONTROL, if on { 127 } else { 0 }),
+            ChannelModeEvent::AllNotesOff => (ALL_NOTES_OFF, 0),
+            ChannelModeEvent::OmniMode(false) => (OMNI_OFF, 0),
+            ChannelModeEvent::OmniMode(true) => (OMNI_ON, 0),
+            ChannelModeEvent::MonoMode(channels) => (MONO_ON, channels.min(16)),
+            ChannelModeEvent::PolyMode => (POLY_ON, 0),
+        };
+        MidiMessage::ControlChange(channel, control.into(), value.into())
+    }
+}
+
+impl<TX, E> MidiOut<TX>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+{
+    pub fn all_sound_off(&mut self, channel: Channel) -> Result<(), MidiError<E>> {
+        self.write(&ChannelModeEvent::AllSoundOff.to_message(channel))
+    }
+
+    pub fn reset_all_controllers(&mut self, channel: Channel) -> Result<(), MidiError<E>> {
+        self.write(&ChannelModeEvent::ResetAllControllers.to_message(channel))
+    }
+
+    pub fn local_control(&mut self, channel: Channel, on: bool) -> Result<(), MidiError<E>> {
+        self.write(&ChannelModeEvent::LocalControl(on).to_message(channel))
+    }
+
+    pub fn all_notes_off(&mut self, channel: Channel) -> Result<(), MidiError<E>> {
+        self.write(&ChannelModeEvent::AllNotesOff.to_message(channel))
+    }
+
+    pub fn omni_mode(&mut self, channel: Channel, on: bool) -> Result<(), MidiError<E>> {
+        self.write(&ChannelModeEvent::OmniMode(on).to_message(channel))
+    }
+
+    /// Switch to mono mode using `channels` channels, 0 for as many channels as there are voices
+    pub fn mono_mode(&mut self, channel: Channel, channels: u8) -> Result<(), MidiError<E>> {
+        self.write(&ChannelModeEvent::MonoMode(channels).to_message(channel))
+    }
+
+    pub fn poly_mode(&mut self, channel: Channel) -> Result<(), MidiError<E>> {
+        self.write(&ChannelModeEvent::PolyMode.to_message(channel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{cc, note_on};
+    use crate::test_util::mock_writes;
+
+    #[test]
+    fn should_send_channel_mode_messages() {
+        let mut out = MidiOut::new(mock_writes(&[
+            0xb2, 120, 0, 121, 0, 122, 0, 122, 127, 123, 0, 124, 0, 125, 0, 126, 4, 126, 16, 127, 0,
+        ]));
+
+        out.all_sound_off(Channel::C3).unwrap();
+        out.reset_all_controllers(Channel::C3).unwrap();
+        out.local_control(Channel::C3, false).unwrap();
+        out.local_control(Channel::C3, true).unwrap();
+        out.all_notes_off(Channel::C3).unwrap();
+        out.omni_mode(Channel::C3, false).unwrap();
+        out.omni_mode(Channel::C3, true).unwrap();
+        out.mono_mode(Channel::C3, 4).unwrap();
+        out.mono_mode(Channel::C3, 20).unwrap();
+        out.poly_mode(Channel::C3).unwrap();
+        out.release().done();
+    }
+
+    #[test]
+    fn should_classify_channel_mode_messages() {
+        let events = [
+            (cc(2, 120, 0), ChannelModeEvent::AllSoundOff),
+            (cc(2, 121, 0), ChannelModeEvent::ResetAllControllers),
+            (cc(2, 122, 0), ChannelModeEvent::LocalControl(false)),
+            (cc(2, 122, 127), ChannelModeEvent::LocalControl(true)),
+            (cc(2, 123, 0), ChannelModeEvent::AllNotesOff),
+            (cc(2, 124, 0), ChannelModeEvent::OmniMode(false)),
+            (cc(2, 125, 0), ChannelModeEvent::OmniMode(true)),
+            (cc(2, 126, 3), ChannelModeEvent::MonoMode(3)),
+            (cc(2, 127, 0), ChannelModeEvent::PolyMode),
+        ];
+
+        for (message, event) in events.iter() {
+            assert_eq!(
+                ChannelModeEvent::from_message(message),
+                Some((Channel::C3, *event))
+            );
+            assert_eq!(event.to_message(Channel::C3), *message);
+        }
+    }
+
+    #[test]
+    fn should_not_classify_other_messages() {
+        assert_eq!(ChannelModeEvent::from_message(&cc(2, 119, 0)), None);
+        assert_eq!(ChannelModeEvent::from_message(&note_on(2, 120, 0)), None);
+    }
+}
@@ -0,0 +1,297 @@
+//! Chord memory processor playing a captured chord shape from single notes
+
+use crate::{MidiProcessor, NoteTracker};
+use midi_convert::midi_types::{Channel, MidiMessage, Note, Value7};
+
+/// Largest number of notes in a chord shape
+pub const MAX_CHORD_NOTES: usize = 8;
+
+/// Intervals of up to `MAX_CHORD_NOTES` notes in semitones above the root, in increasing order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChordShape {
+    intervals: [u8; MAX_CHORD_NOTES],
+    len: u8,
+}
+
+impl ChordShape {
+    /// Shape of the given notes relative to the lowest one, only the lowest `MAX_CHORD_NOTES`
+    /// distinct notes are kept
+    pub fn from_notes(notes: &[Note]) -> Option<Self> {
+        let root = notes.iter().map(|note| u8::from(*note)).min()?;
+        let mut shape = ChordShape {
+            intervals: [0; MAX_CHORD_NOTES],
+            len: 0,
+        };
+        // Walk up from the root so intervals end up sorted without duplicates
+        for note in root..=127 {
+            if shape.len as usize == MAX_CHORD_NOTES {
+                break;
+            }
+            if notes.iter().any(|held| u8::from(*held) == note) {
+                shape.intervals[shape.len as usize] = note - root;
+                shape.len += 1;
+            }
+        }
+        Some(shape)
+    }
+
+    pub fn intervals(&self) -> &[u8] {
+        &self.intervals[..self.len as usize]
+    }
+}
+
+/// Notes played for a held root
+#[derive(Debug, Clone, Copy)]
+struct Held {
+    channel: Channel,
+    root: Note,
+    played: [Option<Note>; MAX_CHORD_NOTES],
+}
+
+/// Plays the selected chord shape, stored in one of `SLOTS` slots, for every note on
+///
+/// In capture mode notes pass unchanged while the shape of the held notes is recorded. Chord
+/// notes above the midi note range are dropped. The notes played for each held root are
+/// remembered, so the note off releases exactly those even when the shape changed in between. At
+/// most `HELD` roots are expanded at the same time, further notes pass unchanged.
+#[derive(Debug, Clone)]
+pub struct ChordMemory<const SLOTS: usize = 8, const HELD: usize = 16> {
+    shapes: [Option<ChordShape>; SLOTS],
+    selected: usize,
+    /// Notes held while capturing
+    capture: Option<NoteTracker>,
+    held: [Option<Held>; HELD],
+}
+
+impl<const SLOTS: usize, const HELD: usize> Default for ChordMemory<SLOTS, HELD> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SLOTS: usize, const HELD: usize> ChordMemory<SLOTS, HELD> {
+    /// Chord memory with empty slots and the first slot selected
+    pub fn new() -> Self {
+        ChordMemory {
+            shapes: [None; SLOTS],
+            selected: 0,
+            capture: None,
+            held: [None; HELD],
+        }
+    }
+
+    /// Pass notes unchanged and track them until `finish_capture`
+    pub fn start_capture(&mut self) {
+        self.capture = Some(NoteTracker::new());
+    }
+
+    /// Store the shape of the notes held on any channel into the selected slot and leave capture
+    /// mode, the slot is unchanged when no notes are held
+    pub fn finish_capture(&mut self) -> Option<ChordShape> {
+        let tracker = self.capture.take()?;
+        let mut notes = [Note::C2m; MAX_CHORD_NOTES];
+        let mut count = 0;
+        for note in (0..=127).map(Note::from) {
+            let held = (0..16).any(|channel| tracker.is_held(Channel::from(channel), note));
+            if held && count < MAX_CHORD_NOTES {
+                notes[count] = note;
+                count += 1;
+            }
+        }
+        let shape = ChordShape::from_notes(&notes[..count])?;
+        self.set_shape(self.selected, Some(shape));
+        Some(shape)
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    /// Play the shape in `slot`, an empty slot passes notes unchanged
+    pub fn select(&mut self, slot: usize) {
+        self.selected = slot.min(SLOTS.saturating_sub(1));
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    pub fn shape(&self, slot: usize) -> Option<ChordShape> {
+        self.shapes.get(slot).copied().flatten()
+    }
+
+    pub fn set_shape(&mut self, slot: usize, shape: Option<ChordShape>) {
+        if let Some(stored) = self.shapes.get_mut(slot) {
+            *stored = shape;
+        }
+    }
+
+    fn note_on(
+        &mut self,
+        channel: Channel,
+        root: Note,
+        velocity: Value7,
+        emit: &mut dyn FnMut(MidiMessage),
+    ) {
+        let shape = self.shape(self.selected);
+        let slot = self.held.iter_mut().find(|held| held.is_none());
+        let (shape, slot) = match (shape, slot) {
+            (Some(shape), Some(slot)) => (shape, slot),
+            _ => return emit(MidiMessage::NoteOn(channel, root, velocity)),
+        };
+
+        let mut played = [None; MAX_CHORD_NOTES];
+        for (interval, played) in shape.intervals().iter().zip(played.iter_mut()) {
+            let note = u8::from(root) as u16 + *interval as u16;
+            if note > 127 {
+                break;
+            }
+            let note = Note::from(note as u8);
+            emit(MidiMessage::NoteOn(channel, note, velocity));
+            *played = Some(note);
+        }
+        *slot = Some(Held {
+            channel,
+            root,
+            played,
+        });
+    }
+
+    fn note_off(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        let (channel, root) = match message {
+            MidiMessage::NoteOff(channel, note, _) | MidiMessage::NoteOn(channel, note, _) => {
+                (channel, note)
+            }
+            _ => return emit(message),
+        };
+
+        let held = self.held.iter_mut().find(
+            |held| matches!(held, Some(held) if held.channel == channel && held.root == root),
+        );
+        let held = match held.and_then(|held| held.take()) {
+            Some(held) => held,
+            None => return emit(message),
+        };
+        for note in held.played.iter().flatten() {
+            emit(match message {
+                MidiMessage::NoteOff(_, _, velocity) => {
+                    MidiMessage::NoteOff(channel, *note, velocity)
+                }
+                _ => MidiMessage::NoteOn(channel, *note, 0.into()),
+            });
+        }
+    }
+}
+
+impl<const SLOTS: usize, const HELD: usize> MidiProcessor for ChordMemory<SLOTS, HELD> {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        if let Some(capture) = self.capture.as_mut() {
+            capture.track(&message);
+        }
+        match message {
+            MidiMessage::NoteOn(channel, note, velocity)
+                if u8::from(velocity) > 0 && self.capture.is_none() =>
+            {
+                self.note_on(channel, note, velocity, emit)
+            }
+            MidiMessage::NoteOn(..) | MidiMessage::NoteOff(..) => self.note_off(message, emit),
+            _ => emit(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{note_off, note_on};
+    use crate::test_util::process;
+    use std::vec::Vec;
+
+    fn capture(memory: &mut ChordMemory, notes: &[u8]) -> Option<ChordShape> {
+        memory.start_capture();
+        let played: Vec<_> = notes.iter().map(|note| note_on(0, *note, 100)).collect();
+        assert_eq!(process(memory, &played), played);
+        let shape = memory.finish_capture();
+        let released: Vec<_> = notes.iter().map(|note| note_off(0, *note, 0)).collect();
+        assert_eq!(process(memory, &released), released);
+        shape
+    }
+
+    #[test]
+    fn should_capture_intervals_above_lowest_note() {
+        let mut memory = ChordMemory::<8>::new();
+        let shape = capture(&mut memory, &[67, 60, 64, 70, 64]).unwrap();
+
+        assert_eq!(shape.intervals(), [0, 4, 7, 10]);
+        assert_eq!(memory.shape(0), Some(shape));
+        assert!(!memory.is_capturing());
+
+        memory.select(1);
+        assert_eq!(capture(&mut memory, &[]), None);
+        assert_eq!(memory.shape(1), None);
+    }
+
+    #[test]
+    fn should_play_shape_at_played_root() {
+        let mut memory = ChordMemory::<8>::new();
+        capture(&mut memory, &[48, 51, 55]);
+
+        assert_eq!(
+            process(&mut memory, &[note_on(2, 62, 90), note_on(2, 57, 80)]),
+            [
+                note_on(2, 62, 90),
+                note_on(2, 65, 90),
+                note_on(2, 69, 90),
+                note_on(2, 57, 80),
+                note_on(2, 60, 80),
+                note_on(2, 64, 80),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_release_chord_played_for_root() {
+        let mut memory = ChordMemory::<8>::new();
+        capture(&mut memory, &[60, 64, 79]);
+        memory.select(1);
+        capture(&mut memory, &[60, 72]);
+        memory.select(0);
+
+        let played = process(&mut memory, &[note_on(0, 110, 100), note_on(1, 60, 100)]);
+        assert_eq!(
+            played,
+            [
+                note_on(0, 110, 100),
+                note_on(0, 114, 100),
+                note_on(1, 60, 100),
+                note_on(1, 64, 100),
+                note_on(1, 79, 100),
+            ]
+        );
+
+        // Switching the shape doesn't change which notes are released
+        memory.select(1);
+        assert_eq!(
+            process(&mut memory, &[note_off(0, 110, 30), note_on(1, 60, 0)]),
+            [
+                note_off(0, 110, 30),
+                note_off(0, 114, 30),
+                note_on(1, 60, 0),
+                note_on(1, 64, 0),
+                note_on(1, 79, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_pass_notes_with_empty_slot() {
+        let mut memory = ChordMemory::<2>::new();
+        let messages = [
+            note_on(0, 60, 100),
+            MidiMessage::TimingClock,
+            note_off(0, 60, 0),
+        ];
+        assert_eq!(process(&mut memory, &messages), messages);
+    }
+}
@@ -0,0 +1,304 @@
+//! Smoothing a forwarded midi clock
+
+use crate::diag::log_debug;
+use midi_convert::midi_types::MidiMessage;
+
+/// Intervals longer than this mean the clock stopped rather than slowed down, in microseconds
+const MAX_INTERVAL_US: u32 = 250_000;
+
+/// Receive times of the clocks not sent yet that are remembered
+const BACKLOG: usize = 8;
+
+/// Re-sends a received midi clock from a local timer at the measured rate, so the jitter of
+/// queues in front of it doesn't reach the output
+///
+/// Received messages are passed to `receive`. Timing clocks are counted and not forwarded, all
+/// other messages are forwarded right away. `tick` sends the clocks from a timer, at the average
+/// rate of the received clocks and about half a clock period behind them, so received clocks
+/// that are late by less than that don't move the clocks sent. Every clock sent corrects its
+/// period by at most an eighth to stay locked to the received clocks.
+///
+/// Exactly as many clocks are sent as were received, a clock is never sent before it was
+/// received. Start, continue, stop and song position pointer first send the clocks still
+/// pending, then are forwarded and restart the phase at the next received clock. Timestamps are
+/// in microseconds and may wrap around.
+#[derive(Debug, Default)]
+pub struct ClockRegenerator {
+    /// Average interval of the received clocks in 1/16 microseconds
+    period: Option<u32>,
+    last_received_us: Option<u32>,
+    /// Receive times of the pending clocks, oldest first
+    backlog: [u32; BACKLOG],
+    backlog_head: usize,
+    backlog_len: usize,
+    /// Clocks received and not sent yet
+    pending: u32,
+    next_send_us: Option<u32>,
+    received: u32,
+    sent: u32,
+}
+
+impl ClockRegenerator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Average interval of the received clocks in microseconds, once two were received
+    pub fn period_us(&self) -> Option<u32> {
+        self.period.map(|period| period / 16)
+    }
+
+    /// Number of clocks received
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+
+    /// Number of clocks sent
+    pub fn sent(&self) -> u32 {
+        self.sent
+    }
+
+    /// Number of clocks received and not sent yet
+    pub fn pending(&self) -> u32 {
+        self.pending
+    }
+
+    /// Handle a message received at `now_us`, calling `emit` with the messages to forward
+    pub fn receive(
+        &mut self,
+        now_us: u32,
+        message: MidiMessage,
+        emit: &mut dyn FnMut(MidiMessage),
+    ) {
+        match message {
+            MidiMessage::TimingClock => self.receive_clock(now_us),
+            MidiMessage::Start
+            | MidiMessage::Continue
+            | MidiMessage::Stop
+            | MidiMessage::SongPositionPointer(_) => {
+                self.flush(emit);
+                emit(message);
+                self.next_send_us = None;
+                self.last_received_us = None;
+            }
+            message => emit(message),
+        }
+    }
+
+    /// Send a clock if one is due at `now_us`, call at least once per clock period
+    pub fn tick(&mut self, now_us: u32, emit: &mut dyn FnMut(MidiMessage)) {
+        let due = match self.next_send_us {
+            Some(due) if self.pending > 0 => due,
+            _ => return,
+        };
+        if now_us.wrapping_sub(due) > u32::MAX / 2 {
+            return;
+        }
+        let received_us = self.send(emit);
+        let period = match self.period {
+            Some(period) => period,
+            None => {
+                self.next_send_us = Some(now_us);
+                return;
+            }
+        };
+        // Keep the clocks sent half a period behind the received clocks
+        let delay = received_us.map_or(0, |received| {
+            i64::from(due.wrapping_sub(received) as i32) * 16
+        });
+        let error = delay - i64::from(period / 2);
+        let max_correction = i64::from(period / 8);
+        let correction = (error / 4).clamp(-max_correction, max_correction);
+        let next = (i64::from(period) - correction) / 16;
+        self.next_send_us = Some(due.wrapping_add(next as u32));
+    }
+
+    fn receive_clock(&mut self, now_us: u32) {
+        if let Some(last) = self.last_received_us.replace(now_us) {
+            let interval = now_us.wrapping_sub(last);
+            if interval > 0 && interval <= MAX_INTERVAL_US {
+                let interval = interval * 16;
+                self.period = Some(match self.period {
+                    Some(period) => {
+                        (i64::from(period) + (i64::from(interval) - i64::from(period)) / 8) as u32
+                    }
+                    None => interval,
+                });
+            }
+        }
+        self.received = self.received.wrapping_add(1);
+        self.pending += 1;
+        if self.backlog_len == BACKLOG {
+            log_debug!("clock regenerator: backlog full");
+            self.backlog_head = (self.backlog_head + 1) % BACKLOG;
+            self.backlog_len -= 1;
+        }
+        self.backlog[(self.backlog_head + self.backlog_len) % BACKLOG] = now_us;
+        self.backlog_len += 1;
+        if self.next_send_us.is_none() {
+            let delay = self.period_us().map_or(0, |period| period / 2);
+            self.next_send_us = Some(now_us.wrapping_add(delay));
+        }
+    }
+
+    /// Send a pending clock, returns when it was received if that is remembered
+    fn send(&mut self, emit: &mut dyn FnMut(MidiMessage)) -> Option<u32> {
+        emit(MidiMessage::TimingClock);
+        self.pending -= 1;
+        self.sent = self.sent.wrapping_add(1);
+        // Once the backlog overflowed the oldest receive times are gone
+        if self.backlog_len == 0 || self.backlog_len as u32 <= self.pending {
+            return None;
+        }
+        let received = self.backlog[self.backlog_head];
+        self.backlog_head = (self.backlog_head + 1) % BACKLOG;
+        self.backlog_len -= 1;
+        Some(received)
+    }
+
+    /// Send all pending clocks right away
+    fn flush(&mut self, emit: &mut dyn FnMut(MidiMessage)) {
+        while self.pending > 0 {
+            self.send(emit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::note_on;
+    use std::vec::Vec;
+
+    /// 120 bpm
+    const PERIOD_US: u32 = 20_833;
+
+    /// Pseudo random jitter from -3 to 3 ms
+    fn jitter(seed: &mut u32) -> i64 {
+        *seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        i64::from(*seed >> 16) % 6001 - 3000
+    }
+
+    /// Receive times of `count` jittery clocks
+    fn jittery_clocks(count: u32) -> Vec<u32> {
+        let mut seed = 1;
+        (1..=count)
+            .map(|n| (i64::from(n * PERIOD_US) + jitter(&mut seed)) as u32)
+            .collect()
+    }
+
+    /// Run the regenerator with a timer tick every 10 µs, returns the times clocks were sent
+    fn run(regenerator: &mut ClockRegenerator, received: &[u32], end_us: u32) -> Vec<u32> {
+        let mut sent = Vec::new();
+        let mut received = received.iter().peekable();
+        for now in (0..end_us).step_by(10) {
+            while let Some(time) = received.next_if(|time| **time <= now) {
+                regenerator.receive(*time, MidiMessage::TimingClock, &mut |_| {
+                    panic!("clock forwarded")
+                });
+            }
+            regenerator.tick(now, &mut |message| {
+                assert_eq!(message, MidiMessage::TimingClock);
+                sent.push(now);
+            });
+        }
+        sent
+    }
+
+    fn intervals(times: &[u32]) -> Vec<u32> {
+        times.windows(2).map(|pair| pair[1] - pair[0]).collect()
+    }
+
+    #[test]
+    fn should_send_as_many_clocks_as_received() {
+        let mut regenerator = ClockRegenerator::new();
+        let received = jittery_clocks(2000);
+        let sent = run(&mut regenerator, &received, 2001 * PERIOD_US);
+        assert_eq!(regenerator.received(), 2000);
+        assert_eq!(regenerator.sent(), 2000);
+        assert_eq!(sent.len(), 2000);
+        assert_eq!(regenerator.pending(), 0);
+        // Never ahead of the received clocks
+        assert!(sent
+            .iter()
+            .zip(&received)
+            .all(|(sent, received)| sent >= received));
+    }
+
+    #[test]
+    fn should_smooth_jitter_with_bounded_corrections() {
+        let mut regenerator = ClockRegenerator::new();
+        let received = jittery_clocks(1000);
+        let sent = run(&mut regenerator, &received, 1001 * PERIOD_US);
+        let period = regenerator.period_us().unwrap();
+        assert!(period.abs_diff(PERIOD_US) < 300, "{}", period);
+
+        let deviation = |intervals: &[u32]| {
+            intervals
+                .iter()
+                .map(|interval| interval.abs_diff(PERIOD_US))
+                .max()
+                .unwrap()
+        };
+        let received_intervals = intervals(&received);
+        // Once the average settled
+        let sent_intervals = &intervals(&sent)[100..];
+        assert!(deviation(sent_intervals) < deviation(&received_intervals[100..]) / 2);
+        // An eighth of the period, the estimate and the timer tick
+        assert!(deviation(sent_intervals) <= PERIOD_US / 8 + 300 + 10);
+    }
+
+    #[test]
+    fn should_forward_transport_right_away_and_restart_phase() {
+        let mut regenerator = ClockRegenerator::new();
+        let mut output = Vec::new();
+        for n in 0..4 {
+            regenerator.receive(n * PERIOD_US, MidiMessage::TimingClock, &mut |m| {
+                output.push(m)
+            });
+        }
+        regenerator.tick(0, &mut |m| output.push(m));
+        assert_eq!(regenerator.pending(), 3);
+
+        regenerator.receive(4 * PERIOD_US, note_on(0, 60, 100), &mut |m| output.push(m));
+        regenerator.receive(4 * PERIOD_US, MidiMessage::Stop, &mut |m| output.push(m));
+        assert_eq!(
+            output,
+            [
+                MidiMessage::TimingClock,
+                note_on(0, 60, 100),
+                MidiMessage::TimingClock,
+                MidiMessage::TimingClock,
+                MidiMessage::TimingClock,
+                MidiMessage::Stop,
+            ]
+        );
+        assert_eq!(regenerator.sent(), regenerator.received());
+
+        // The next clock goes out half a period after it is received
+        output.clear();
+        let start = 10 * PERIOD_US;
+        regenerator.receive(start, MidiMessage::TimingClock, &mut |m| output.push(m));
+        regenerator.tick(start + PERIOD_US / 2 - 10, &mut |m| output.push(m));
+        assert_eq!(output, []);
+        regenerator.tick(start + PERIOD_US / 2, &mut |m| output.push(m));
+        assert_eq!(output, [MidiMessage::TimingClock]);
+    }
+
+    #[test]
+    fn should_catch_up_with_a_faster_clock() {
+        let mut regenerator = ClockRegenerator::new();
+        let mut received: Vec<u32> = (1..=200).map(|n| n * PERIOD_US).collect();
+        // 10% faster
+        let faster = PERIOD_US * 9 / 10;
+        received.extend((1..=200).map(|n| 200 * PERIOD_US + n * faster));
+        let end = *received.last().unwrap();
+        let sent = run(&mut regenerator, &received, end + PERIOD_US);
+        assert_eq!(sent.len(), 400);
+        assert!(regenerator.period_us().unwrap().abs_diff(faster) < 50);
+        // The delay behind the received clocks settles again
+        let delay = sent[399] - received[399];
+        assert!(delay < PERIOD_US, "{}", delay);
+    }
+}
@@ -0,0 +1,571 @@
+//! Dumping and restoring the configuration of a device with system exclusive messages
+//!
+//! The packet layout is described at `ConfigSysex`. A dump starts with `DUMP_BEGIN` at index 0,
+//! followed by the configuration in `DUMP_DATA` packets numbered from 0 and `DUMP_END` with the
+//! number of data packets as index. `DUMP_REQUEST` asks a device for a dump of its configuration.
+//! Dumps are not acknowledged, a librarian checks that the device took a dump by requesting it
+//! back.
+//!
+//! The configuration is a format version byte followed by sections, each a 2 byte length, low
+//! byte first, and the bytes saved by a processor, see `SectionWriter` and `SectionReader`.
+
+use crate::{
+    packet::{send_packet, PacketDecoder, Received},
+    DecodeError, MidiError, MidiOut, SysexHandler, TooSmall,
+};
+use core::convert::TryFrom;
+use core::fmt::{self, Debug, Display, Formatter};
+use embedded_hal_nb::serial;
+
+/// Ask for a dump of the configuration
+pub const DUMP_REQUEST: u8 = 0x10;
+/// Start of a dump
+pub const DUMP_BEGIN: u8 = 0x11;
+/// A chunk of the configuration
+pub const DUMP_DATA: u8 = 0x12;
+/// End of a dump
+pub const DUMP_END: u8 = 0x13;
+
+/// Format version of dumped configurations
+const VERSION: u8 = 1;
+
+/// Configuration of a device, the processors it is made of
+pub trait ConfigTarget {
+    /// Save the configuration into `buffer`, returns the number of bytes written
+    fn save_config(&self, buffer: &mut [u8]) -> Result<usize, TooSmall>;
+
+    /// Replace the configuration by one saved by `save_config`
+    ///
+    /// Nothing must change unless all of `bytes` decodes, so decode every processor before
+    /// replacing any of them.
+    fn load_config(&mut self, bytes: &[u8]) -> Result<(), DecodeError>;
+}
+
+/// Writes the sections of a configuration, one per processor
+#[derive(Debug)]
+pub struct SectionWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SectionWriter<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        SectionWriter { buffer, len: 0 }
+    }
+
+    /// Add a section written by `save`, like the `save` function of a processor
+    pub fn section(
+        &mut self,
+        save: impl FnOnce(&mut [u8]) -> Result<usize, TooSmall>,
+    ) -> Result<(), TooSmall> {
+        let start = self.len + 2;
+        let buffer = self.buffer.get_mut(start..).ok_or(TooSmall)?;
+        let len = save(buffer)?;
+        let header = u16::try_from(len).map_err(|_| TooSmall)?.to_le_bytes();
+        self.buffer[self.len..start].copy_from_slice(&header);
+        self.len = start + len;
+        Ok(())
+    }
+
+    /// Number of bytes written
+    pub fn finish(self) -> usize {
+        self.len
+    }
+}
+
+/// Reads the sections written by a `SectionWriter`
+#[derive(Debug, Clone)]
+pub struct SectionReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> SectionReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        SectionReader { bytes }
+    }
+
+    /// The bytes of the next section, for the `load` function of a processor
+    pub fn section(&mut self) -> Result<&'a [u8], DecodeError> {
+        let (header, rest) = self
+            .bytes
+            .split_first_chunk::<2>()
+            .ok_or(DecodeError::TooShort)?;
+        let len = usize::from(u16::from_le_bytes(*header));
+        if rest.len() < len {
+            return Err(DecodeError::TooShort);
+        }
+        let (section, rest) = rest.split_at(len);
+        self.bytes = rest;
+        Ok(section)
+    }
+}
+
+/// Reason a received dump was not applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// A packet of the dump failed its checksum
+    Corrupt,
+    /// A packet of the dump is missing or out of order
+    Sequence,
+    /// The configuration is larger than the buffer
+    TooLarge,
+    /// The configuration didn't decode
+    Decode(DecodeError),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Corrupt => f.write_str("corrupt packet"),
+            ConfigError::Sequence => f.write_str("packet missing or out of order"),
+            ConfigError::TooLarge => f.write_str("configuration too large"),
+            ConfigError::Decode(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl core::error::Error for ConfigError {}
+
+/// What `ConfigSysex::process` did with a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigEvent {
+    /// A dump started
+    Started,
+    /// A received configuration was applied
+    Applied,
+    /// A received dump was dropped, the configuration is unchanged
+    Rejected(ConfigError),
+    /// The configuration was dumped for a request
+    Dumped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    /// Receiving a dump, expecting data packet `next`
+    Receiving {
+        next: u16,
+    },
+}
+
+/// Sends the configuration of a device as a dump on request and applies received dumps
+///
+/// Received dumps are collected in a buffer of `SIZE` bytes and only applied once the last packet
+/// arrived and the whole configuration decoded, so a dump with a corrupt or missing packet leaves
+/// the configuration as it was. Dumps are sent in data packets of `CHUNK` bytes, `CHUNK` must not
+/// be 0, which is checked at compile time, and received in packets of up to `CHUNK` bytes. Feed
+/// the system exclusive messages to it, for example through a `SysexStream`, and call `process`
+/// after every message.
+///
+/// Every packet is a system exclusive message with these data bytes, the firmware update protocol
+/// of the `fwup` module uses the same packets with other commands so both can share a
+/// manufacturer id:
+///
+/// | Bytes | Content |
+/// |-------|---------|
+/// | 1 or 3 | Manufacturer id |
+/// | 1 | Command, one of `DUMP_REQUEST`, `DUMP_BEGIN`, `DUMP_DATA` and `DUMP_END` |
+/// | 2 | Packet index, low 7 bits first |
+/// | any | Payload, 7 bit encoded, see [`seven_bit`](crate::seven_bit) |
+/// | 1 | Checksum, the low 7 bits of the sum of the command, index, payload and checksum bytes are 0 |
+#[derive(Debug)]
+pub struct ConfigSysex<const SIZE: usize, const CHUNK: usize = 32> {
+    decoder: PacketDecoder<CHUNK>,
+    buffer: [u8; SIZE],
+    len: usize,
+    state: State,
+}
+
+impl<const SIZE: usize, const CHUNK: usize> ConfigSysex<SIZE, CHUNK> {
+    const HAS_CHUNK: () = assert!(CHUNK > 0, "data packets must carry at least one byte");
+
+    /// Dump and receive packets with the `manufacturer` id
+    pub fn new(manufacturer: &'static [u8]) -> Self {
+        let () = Self::HAS_CHUNK;
+        ConfigSysex {
+            decoder: PacketDecoder::new(manufacturer),
+            buffer: [0; SIZE],
+            len: 0,
+            state: State::Idle,
+        }
+    }
+
+    /// A dump is being received
+    pub fn is_receiving(&self) -> bool {
+        self.state != State::Idle
+    }
+
+    /// Handle the last received packet, applying a complete dump to `target` and answering a
+    /// dump request on `out`
+    ///
+    /// A dump request drops a dump being received.
+    pub fn process<TX, E>(
+        &mut self,
+        target: &mut impl ConfigTarget,
+        out: &mut MidiOut<TX>,
+    ) -> Result<Option<ConfigEvent>, MidiError<E>>
+    where
+        TX: serial::Write<u8, Error = E>,
+        E: Debug,
+    {
+        let packet = match self.decoder.take() {
+            Some(Received::Valid(packet)) => packet,
+            Some(Received::Corrupt) => return Ok(self.reject(ConfigError::Corrupt)),
+            None => return Ok(None),
+        };
+        let event = match (packet.command, self.state) {
+            (DUMP_REQUEST, _) => {
+                self.state = State::Idle;
+                self.send_dump(target, out)?;
+                Some(ConfigEvent::Dumped)
+            }
+            (DUMP_BEGIN, _) => {
+                self.state = State::Receiving { next: 0 };
+                self.len = 0;
+                Some(ConfigEvent::Started)
+            }
+            (DUMP_DATA, State::Receiving { next }) if packet.index == next => {
+                let payload = self.decoder.payload();
+                let end = self.len + payload.len();
+                match self.buffer.get_mut(self.len..end) {
+                    Some(buffer) => buffer.copy_from_slice(payload),
+                    None => return Ok(self.reject(ConfigError::TooLarge)),
+                }
+                self.len = end;
+                self.state = State::Receiving { next: next + 1 };
+                None
+            }
+            (DUMP_END, State::Receiving { next }) if packet.index == next => {
+                self.state = State::Idle;
+                Some(match self.apply(target) {
+                    Ok(()) => ConfigEvent::Applied,
+                    Err(error) => ConfigEvent::Rejected(ConfigError::Decode(error)),
+                })
+            }
+            (DUMP_DATA | DUMP_END, State::Receiving { .. }) => self.reject(ConfigError::Sequence),
+            _ => None,
+        };
+        Ok(event)
+    }
+
+    /// Send the configuration of `target` as a dump
+    ///
+    /// The configuration is saved into the buffer, dropping a dump being received.
+    pub fn send_dump<TX, E>(
+        &mut self,
+        target: &impl ConfigTarget,
+        out: &mut MidiOut<TX>,
+    ) -> Result<(), MidiError<E>>
+    where
+        TX: serial::Write<u8, Error = E>,
+        E: Debug,
+    {
+        self.state = State::Idle;
+        let (version, config) = self.buffer.split_first_mut().ok_or(MidiError::BufferFull)?;
+        *version = VERSION;
+        let len = 1 + target
+            .save_config(config)
+            .map_err(|_| MidiError::BufferFull)?;
+
+        let manufacturer = self.decoder.manufacturer;
+        send_packet(out, manufacturer, DUMP_BEGIN, 0, &[])?;
+        let mut packets = 0;
+        for payload in self.buffer[..len].chunks(CHUNK) {
+            send_packet(out, manufacturer, DUMP_DATA, packets, payload)?;
+            packets += 1;
+        }
+        send_packet(out, manufacturer, DUMP_END, packets, &[])
+    }
+
+    /// Ask the device on the other end of `out` for a dump
+    pub fn send_dump_request<TX, E>(&self, out: &mut MidiOut<TX>) -> Result<(), MidiError<E>>
+    where
+        TX: serial::Write<u8, Error = E>,
+        E: Debug,
+    {
+        send_packet(out, self.decoder.manufacturer, DUMP_REQUEST, 0, &[])
+    }
+
+    fn reject(&mut self, error: ConfigError) -> Option<ConfigEvent> {
+        let receiving = self.is_receiving();
+        self.state = State::Idle;
+        // Packets of a dump already dropped are ignored
+        receiving.then_some(ConfigEvent::Rejected(error))
+    }
+
+    fn apply(&self, target: &mut impl ConfigTarget) -> Result<(), DecodeError> {
+        match self.buffer[..self.len].split_first() {
+            Some((&VERSION, config)) => target.load_config(config),
+            Some((version, _)) => Err(DecodeError::UnsupportedVersion(*version)),
+            None => Err(DecodeError::TooShort),
+        }
+    }
+}
+
+impl<const SIZE: usize, const CHUNK: usize> SysexHandler for ConfigSysex<SIZE, CHUNK> {
+    fn on_sysex_chunk(&mut self, chunk: &[u8], first: bool, last: bool) {
+        self.decoder.on_sysex_chunk(chunk, first, last);
+    }
+
+    fn on_sysex_abort(&mut self) {
+        self.decoder.on_sysex_abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::test_util::Wire;
+    use crate::{CcRemap, CcRule, CcTarget, PortId, RoutingMatrix, SysexStream};
+    use midi_convert::midi_types::Channel;
+    use std::vec::Vec;
+
+    const MANUFACTURER: &[u8] = &[0x7d];
+
+    /// A device routing two inputs to two outputs, remapping some controllers
+    #[derive(Debug, Default)]
+    struct Device {
+        matrix: RoutingMatrix<2, 2>,
+        remap: CcRemap<4>,
+    }
+
+    impl ConfigTarget for Device {
+        fn save_config(&self, buffer: &mut [u8]) -> Result<usize, TooSmall> {
+            let mut writer = SectionWriter::new(buffer);
+            writer.section(|buffer| self.matrix.save(buffer))?;
+            writer.section(|buffer| self.remap.save(buffer))?;
+            Ok(writer.finish())
+        }
+
+        fn load_config(&mut self, bytes: &[u8]) -> Result<(), DecodeError> {
+            let mut reader = SectionReader::new(bytes);
+            let matrix = RoutingMatrix::load(reader.section()?)?;
+            let remap = CcRemap::load(reader.section()?)?;
+            self.matrix = matrix;
+            self.remap = remap;
+            Ok(())
+        }
+    }
+
+    fn configured() -> Device {
+        let mut device = Device::default();
+        device.matrix.connect(PortId(0), PortId(1));
+        device.matrix.connect(PortId(1), PortId(0));
+        for control in 1..=4 {
+            let rule = CcRule {
+                channel: Some(Channel::C2),
+                control: control.into(),
+                target: CcTarget::Control((control + 20).into()),
+            };
+            device.remap.add(rule).unwrap();
+        }
+        device
+    }
+
+    fn is_configured(device: &Device) -> bool {
+        device.matrix.route_filter(PortId(1), PortId(0)).is_some()
+            && device
+                .remap
+                .rule_for(Channel::C2, 4.into())
+                .is_some_and(|rule| rule.target == CcTarget::Control(24.into()))
+    }
+
+    /// The packets written to `out`, one system exclusive message each
+    fn packets(out: &mut MidiOut<Wire>) -> Vec<Vec<u8>> {
+        let bytes: Vec<u8> = out.tx.0.drain(..).collect();
+        bytes
+            .split_inclusive(|byte| *byte == 0xf7)
+            .map(Vec::from)
+            .collect()
+    }
+
+    /// Deliver `packets`, processing after each one
+    fn deliver(
+        packets: &[Vec<u8>],
+        config: &mut ConfigSysex<64, 8>,
+        device: &mut Device,
+        out: &mut MidiOut<Wire>,
+    ) -> Vec<ConfigEvent> {
+        let mut stream = SysexStream::<16>::new();
+        let mut events = Vec::new();
+        for packet in packets {
+            for byte in packet {
+                stream.feed(0, *byte, config);
+            }
+            events.extend(config.process(device, out).unwrap());
+        }
+        events
+    }
+
+    #[test]
+    fn should_round_trip_a_configuration() {
+        let mut source = configured();
+        let librarian = ConfigSysex::<64, 8>::new(MANUFACTURER);
+        let mut device_config = ConfigSysex::<64, 8>::new(MANUFACTURER);
+        let mut librarian_out = MidiOut::new(Wire::default());
+        let mut device_out = MidiOut::new(Wire::default());
+
+        // The librarian asks the configured device for its dump
+        librarian.send_dump_request(&mut librarian_out).unwrap();
+        let request = packets(&mut librarian_out);
+        let events = deliver(&request, &mut device_config, &mut source, &mut device_out);
+        assert_eq!(events, [ConfigEvent::Dumped]);
+        let dump = packets(&mut device_out);
+        // Begin, end and more than one data packet
+        assert!(dump.len() > 3, "{} packets", dump.len());
+
+        let mut blank = Device::default();
+        let mut blank_config = ConfigSysex::<64, 8>::new(MANUFACTURER);
+        let events = deliver(&dump, &mut blank_config, &mut blank, &mut device_out);
+        assert_eq!(events, [ConfigEvent::Started, ConfigEvent::Applied]);
+        assert!(is_configured(&blank));
+        assert!(!blank_config.is_receiving());
+    }
+
+    #[test]
+    fn should_reject_a_corrupted_chunk_without_applying_any_of_it() {
+        let source = configured();
+        let mut config = ConfigSysex::<64, 8>::new(MANUFACTURER);
+        let mut out = MidiOut::new(Wire::default());
+        config.send_dump(&source, &mut out).unwrap();
+        let dump = packets(&mut out);
+
+        let mut corrupted = dump.clone();
+        // A data byte of the second data packet, after the id, command and index
+        corrupted[2][6] ^= 0x01;
+        let mut device = Device::default();
+        let events = deliver(&corrupted, &mut config, &mut device, &mut out);
+        assert_eq!(
+            events,
+            [
+                ConfigEvent::Started,
+                ConfigEvent::Rejected(ConfigError::Corrupt)
+            ]
+        );
+        assert!(device.matrix.route_filter(PortId(1), PortId(0)).is_none());
+        assert!(device.remap.rule_for(Channel::C2, 1.into()).is_none());
+
+        // A missing packet
+        let mut missing = dump.clone();
+        missing.remove(3);
+        let events = deliver(&missing, &mut config, &mut device, &mut out);
+        assert_eq!(
+            events,
+            [
+                ConfigEvent::Started,
+                ConfigEvent::Rejected(ConfigError::Sequence)
+            ]
+        );
+        assert!(device.matrix.route_filter(PortId(1), PortId(0)).is_none());
+
+        // Sent again intact
+        let events = deliver(&dump, &mut config, &mut device, &mut out);
+        assert_eq!(events, [ConfigEvent::Started, ConfigEvent::Applied]);
+        assert!(is_configured(&device));
+    }
+
+    /// The packets of a dump of `config` in chunks of 8 bytes
+    fn dump_of(config: &[u8]) -> Vec<Vec<u8>> {
+        let mut out = MidiOut::new(Wire::default());
+        send_packet(&mut out, MANUFACTURER, DUMP_BEGIN, 0, &[]).unwrap();
+        let mut index = 0;
+        for chunk in config.chunks(8) {
+            send_packet(&mut out, MANUFACTURER, DUMP_DATA, index, chunk).unwrap();
+            index += 1;
+        }
+        send_packet(&mut out, MANUFACTURER, DUMP_END, index, &[]).unwrap();
+        packets(&mut out)
+    }
+
+    #[test]
+    fn should_reject_configurations_that_dont_decode() {
+        let mut config = ConfigSysex::<64, 8>::new(MANUFACTURER);
+        let mut out = MidiOut::new(Wire::default());
+        let mut device = configured();
+
+        // Valid packets, but the second section is cut off
+        let mut buffer = [0; 64];
+        let mut writer = SectionWriter::new(&mut buffer);
+        writer.section(|buffer| device.matrix.save(buffer)).unwrap();
+        let len = writer.finish();
+        let mut truncated = Vec::from([VERSION]);
+        truncated.extend_from_slice(&buffer[..len]);
+        truncated.extend_from_slice(&[5, 0, 1]);
+        device.matrix.disconnect(PortId(1), PortId(0));
+        let events = deliver(&dump_of(&truncated), &mut config, &mut device, &mut out);
+        assert_eq!(
+            events,
+            [
+                ConfigEvent::Started,
+                ConfigEvent::Rejected(ConfigError::Decode(DecodeError::TooShort))
+            ]
+        );
+        // The first section decoded but was not applied either
+        assert!(device.matrix.route_filter(PortId(1), PortId(0)).is_none());
+
+        // Another format version
+        truncated[0] = VERSION + 1;
+        let events = deliver(&dump_of(&truncated), &mut config, &mut device, &mut out);
+        assert_eq!(
+            events[1],
+            ConfigEvent::Rejected(ConfigError::Decode(DecodeError::UnsupportedVersion(2)))
+        );
+    }
+
+    #[test]
+    fn should_reject_dumps_larger_than_the_buffer() {
+        let source = configured();
+        let mut out = MidiOut::new(Wire::default());
+        ConfigSysex::<64, 8>::new(MANUFACTURER)
+            .send_dump(&source, &mut out)
+            .unwrap();
+        let dump = packets(&mut out);
+
+        let mut small = ConfigSysex::<16, 8>::new(MANUFACTURER);
+        let mut device = Device::default();
+        let mut stream = SysexStream::<16>::new();
+        let mut events = Vec::new();
+        for byte in dump.iter().flatten() {
+            stream.feed(0, *byte, &mut small);
+            if *byte == 0xf7 {
+                events.extend(small.process(&mut device, &mut out).unwrap());
+            }
+        }
+        assert_eq!(
+            events,
+            [
+                ConfigEvent::Started,
+                ConfigEvent::Rejected(ConfigError::TooLarge)
+            ]
+        );
+        assert!(small
+            .send_dump(&source, &mut MidiOut::new(Wire::default()))
+            .is_err());
+    }
+
+    #[test]
+    fn should_split_sections() {
+        let mut buffer = [0; 8];
+        let mut writer = SectionWriter::new(&mut buffer);
+        writer
+            .section(|buffer| {
+                buffer[..2].copy_from_slice(&[1, 2]);
+                Ok(2)
+            })
+            .unwrap();
+        writer.section(|_| Ok(0)).unwrap();
+        assert_eq!(writer.section(|_| Err(TooSmall)), Err(TooSmall));
+        assert_eq!(writer.finish(), 6);
+        assert_eq!(buffer[..6], [2, 0, 1, 2, 0, 0]);
+
+        let mut reader = SectionReader::new(&buffer[..6]);
+        assert_eq!(reader.section(), Ok(&[1, 2][..]));
+        assert_eq!(reader.section(), Ok(&[][..]));
+        assert_eq!(reader.section(), Err(DecodeError::TooShort));
+        assert_eq!(
+            SectionReader::new(&[3, 0, 1]).section(),
+            Err(DecodeError::TooShort)
+        );
+    }
+}
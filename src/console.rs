@@ -0,0 +1,338 @@
+//! A text console over system exclusive, enabled with the `console` feature
+//!
+//! Text is sent in frames, system exclusive messages with these data bytes:
+//!
+//! | Bytes | Content |
+//! |-------|---------|
+//! | 1 or 3 | Manufacturer id |
+//! | 1 | `TEXT` |
+//! | up to the frame size | ASCII text |
+//!
+//! Lines end with `\n` and may span several frames, a frame ends at the latest with the end of a
+//! line so every line reaches the host as soon as it is written.
+
+use crate::{MidiError, MidiOut, SysexHandler};
+use core::fmt::{self, Debug};
+use embedded_hal_nb::serial;
+
+/// Marks a console frame after the manufacturer id
+pub const TEXT: u8 = 0x0c;
+
+/// Writes text to a `MidiOut` in frames of up to `N` characters
+///
+/// Use with `write!` and `writeln!`. Text is sent once a line ends or a frame is full, call
+/// `flush` to send the rest of an unfinished line. Text with characters other than ASCII is
+/// rejected with `fmt::Error` and nothing of it is sent. When writing to the port fails the write
+/// returns `fmt::Error`, `take_error` tells why.
+#[derive(Debug)]
+pub struct ConsoleTx<'a, TX, const N: usize = 32>
+where
+    TX: serial::ErrorType,
+{
+    out: &'a mut MidiOut<TX>,
+    manufacturer: &'static [u8],
+    buffer: [u8; N],
+    len: usize,
+    error: Option<MidiError<TX::Error>>,
+}
+
+impl<'a, TX, E, const N: usize> ConsoleTx<'a, TX, N>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+{
+    pub fn new(out: &'a mut MidiOut<TX>, manufacturer: &'static [u8]) -> Self {
+        ConsoleTx {
+            out,
+            manufacturer,
+            buffer: [0; N],
+            len: 0,
+            error: None,
+        }
+    }
+
+    /// Send the text not sent yet
+    pub fn flush(&mut self) -> Result<(), MidiError<E>> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        let text = &self.buffer[..self.len];
+        self.len = 0;
+        let data = self
+            .manufacturer
+            .iter()
+            .copied()
+            .chain(core::iter::once(TEXT))
+            .chain(text.iter().copied());
+        self.out.write_sysex_bytes(data)
+    }
+
+    /// The error writing to the port failed with, since the last call
+    pub fn take_error(&mut self) -> Option<MidiError<E>> {
+        self.error.take()
+    }
+}
+
+impl<TX, E, const N: usize> fmt::Write for ConsoleTx<'_, TX, N>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+{
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        if !text.is_ascii() {
+            return Err(fmt::Error);
+        }
+        for byte in text.bytes() {
+            if let Some(slot) = self.buffer.get_mut(self.len) {
+                *slot = byte;
+                self.len += 1;
+            }
+            if byte == b'\n' || self.len >= N {
+                self.flush().map_err(|error| {
+                    self.error = Some(error);
+                    fmt::Error
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Puts the text of console frames back together and calls `on_line` with every line
+///
+/// Feed the system exclusive messages to the receiver, for example with `MidiIn::read_sysex`.
+/// Lines are handed out without the line end, lines longer than `LINE` characters are handed out
+/// in parts. The text of an aborted frame is dropped.
+#[derive(Debug)]
+pub struct ConsoleRx<F, const LINE: usize = 64> {
+    manufacturer: &'static [u8],
+    on_line: F,
+    /// Position in the current frame
+    position: usize,
+    matched: bool,
+    line: [u8; LINE],
+    len: usize,
+    /// Length of the line when the current frame started
+    frame_start: usize,
+}
+
+impl<F, const LINE: usize> ConsoleRx<F, LINE>
+where
+    F: FnMut(&str),
+{
+    pub fn new(manufacturer: &'static [u8], on_line: F) -> Self {
+        ConsoleRx {
+            manufacturer,
+            on_line,
+            position: 0,
+            matched: false,
+            line: [0; LINE],
+            len: 0,
+            frame_start: 0,
+        }
+    }
+
+    /// Release the callback
+    pub fn release(self) -> F {
+        self.on_line
+    }
+
+    fn push(&mut self, byte: u8) {
+        let position = self.position;
+        self.position += 1;
+        let header_len = self.manufacturer.len();
+        match position.cmp(&header_len) {
+            core::cmp::Ordering::Less => self.matched &= self.manufacturer[position] == byte,
+            core::cmp::Ordering::Equal => self.matched &= byte == TEXT,
+            core::cmp::Ordering::Greater if self.matched => self.push_text(byte),
+            core::cmp::Ordering::Greater => {}
+        }
+    }
+
+    fn push_text(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.deliver(),
+            b'\r' => {}
+            _ => {
+                if self.len >= LINE {
+                    self.deliver();
+                }
+                if let Some(slot) = self.line.get_mut(self.len) {
+                    *slot = byte;
+                    self.len += 1;
+                }
+            }
+        }
+    }
+
+    fn deliver(&mut self) {
+        // Frames only carry data bytes, which are ASCII
+        if let Ok(line) = core::str::from_utf8(&self.line[..self.len]) {
+            (self.on_line)(line);
+        }
+        self.len = 0;
+        self.frame_start = 0;
+    }
+}
+
+impl<F, const LINE: usize> SysexHandler for ConsoleRx<F, LINE>
+where
+    F: FnMut(&str),
+{
+    fn on_sysex_chunk(&mut self, chunk: &[u8], first: bool, _last: bool) {
+        if first {
+            self.position = 0;
+            self.matched = true;
+            self.frame_start = self.len;
+        }
+        for byte in chunk {
+            self.push(*byte);
+        }
+    }
+
+    fn on_sysex_abort(&mut self) {
+        if self.matched {
+            self.len = self.frame_start;
+        }
+        self.matched = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{note_on, program_change};
+    use crate::test_util::Wire;
+    use crate::{MidiIn, SysexStream};
+    use core::fmt::Write;
+    use embedded_hal_mock::eh1::serial::{Mock, Transaction};
+    use midi_convert::midi_types::MidiMessage;
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    /// Non-commercial manufacturer id
+    const MANUFACTURER: &[u8] = &[0x7d];
+
+    fn frames(bytes: &[u8]) -> Vec<&[u8]> {
+        bytes.split_inclusive(|byte| *byte == 0xf7).collect()
+    }
+
+    fn receive(bytes: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut rx =
+            ConsoleRx::<_, 16>::new(MANUFACTURER, |line: &str| lines.push(line.to_string()));
+        let mut stream = SysexStream::<8>::new();
+        for byte in bytes {
+            stream.feed(0, *byte, &mut rx);
+        }
+        lines
+    }
+
+    #[test]
+    fn should_send_a_frame_per_line() {
+        let mut out = MidiOut::new(Wire::default());
+        let mut console = ConsoleTx::<_, 32>::new(&mut out, MANUFACTURER);
+        writeln!(console, "adc={}", 517).unwrap();
+        write!(console, "ok").unwrap();
+        console.flush().unwrap();
+
+        let bytes = out.tx.0;
+        assert_eq!(
+            frames(&bytes),
+            [
+                &b"\xf0\x7d\x0cadc=517\n\xf7"[..],
+                &b"\xf0\x7d\x0cok\xf7"[..]
+            ]
+        );
+        assert_eq!(receive(&bytes), ["adc=517"]);
+    }
+
+    #[test]
+    fn should_split_long_lines_across_frames() {
+        let mut out = MidiOut::new(Wire::default());
+        let mut console = ConsoleTx::<_, 8>::new(&mut out, MANUFACTURER);
+        writeln!(console, "quick brown fox").unwrap();
+
+        let bytes = out.tx.0;
+        let frames = frames(&bytes);
+        assert_eq!(frames.len(), 2);
+        // Manufacturer id, marker and at most 8 characters
+        assert!(frames.iter().all(|frame| frame.len() <= 2 + 2 + 8));
+        assert_eq!(receive(&bytes), ["quick brown fox"]);
+    }
+
+    #[test]
+    fn should_hand_out_overlong_lines_in_parts() {
+        let mut out = MidiOut::new(Wire::default());
+        let mut console = ConsoleTx::<_, 32>::new(&mut out, MANUFACTURER);
+        writeln!(console, "0123456789abcdefXYZ\r").unwrap();
+        assert_eq!(receive(&out.tx.0), ["0123456789abcdef", "XYZ"]);
+    }
+
+    #[test]
+    fn should_reject_non_ascii_text() {
+        let mut out = MidiOut::new(Wire::default());
+        let mut console = ConsoleTx::<_, 32>::new(&mut out, MANUFACTURER);
+        assert!(console.write_str("21°C").is_err());
+        console.write_str("ok").unwrap();
+        console.flush().unwrap();
+        assert_eq!(out.tx.0, b"\xf0\x7d\x0cok\xf7");
+    }
+
+    #[test]
+    fn should_report_port_errors() {
+        let expectations = [Transaction::write_error(
+            0xf0,
+            nb::Error::Other(serial::ErrorKind::Other),
+        )];
+        let mut out = MidiOut::new(Mock::new(&expectations));
+        let mut console = ConsoleTx::<_, 32>::new(&mut out, MANUFACTURER);
+        assert!(writeln!(console, "x").is_err());
+        assert!(matches!(console.take_error(), Some(MidiError::Serial(_))));
+        assert!(console.take_error().is_none());
+        out.release().done();
+    }
+
+    #[test]
+    fn should_interleave_with_normal_traffic() {
+        let mut out = MidiOut::new(Wire::default());
+        out.write(&note_on(0, 60, 100)).unwrap();
+        writeln!(ConsoleTx::<_, 4>::new(&mut out, MANUFACTURER), "boot ok").unwrap();
+        out.write(&note_on(0, 62, 100)).unwrap();
+        let mut bytes = out.tx.0;
+        // A clock in the middle of a frame, another manufacturer's message and an aborted frame
+        bytes.insert(5, 0xf8);
+        bytes.extend([0xf0, 0x7e, 0x0c, b'n', b'o', 0xf7]);
+        bytes.extend([0xf0, 0x7d, 0x0c, b'l', b'o', 0xc0, 0x05]);
+
+        let mut lines = Vec::new();
+        let mut rx =
+            ConsoleRx::<_, 16>::new(MANUFACTURER, |line: &str| lines.push(line.to_string()));
+        let mut sysex = SysexStream::<8>::new();
+        let expectations = [Transaction::read_many(&bytes)];
+        let mut midi_in = MidiIn::new(Mock::new(&expectations));
+        let mut messages = Vec::new();
+        for _ in 0..bytes.len() {
+            if let Ok(message) = midi_in.read_sysex(0, &mut sysex, &mut rx) {
+                messages.push(message);
+            }
+        }
+        // The rest of a line after an aborted frame still makes it
+        for byte in b"\xf0\x7d\x0cend\n\xf7" {
+            sysex.feed(0, *byte, &mut rx);
+        }
+        midi_in.rx.done();
+
+        assert_eq!(
+            messages,
+            [
+                note_on(0, 60, 100),
+                MidiMessage::TimingClock,
+                note_on(0, 62, 100),
+                program_change(0, 5),
+            ]
+        );
+        assert_eq!(lines, ["boot ok", "end"]);
+    }
+}
@@ -0,0 +1,214 @@
+//! Dropping repeated controller values from chatty controllers
+
+use crate::{family, MidiProcessor};
+use midi_convert::midi_types::MidiMessage;
+
+/// Last value seen for a channel and controller
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    /// Family bit and channel
+    key: (u16, u8),
+    controller: u8,
+    value: u16,
+    used: u32,
+}
+
+/// Processor dropping exact repeats of controller values
+///
+/// The last value of control changes, channel pressure, pitch bend and program changes is
+/// remembered per channel, and per controller for control changes. A message carrying the value
+/// already remembered is dropped. Up to `ENTRIES` values are remembered, the least recently used
+/// one is forgotten to make room. Notes and all other messages are always passed on.
+#[derive(Debug, Clone)]
+pub struct Dedup<const ENTRIES: usize = 32> {
+    entries: [Option<Entry>; ENTRIES],
+    families: u16,
+    clock: u32,
+}
+
+impl<const ENTRIES: usize> Default for Dedup<ENTRIES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const ENTRIES: usize> Dedup<ENTRIES> {
+    /// Dedup for control change, channel pressure, pitch bend and program change
+    pub fn new() -> Self {
+        Dedup {
+            entries: [None; ENTRIES],
+            families: family::CONTROL_CHANGE
+                | family::CHANNEL_PRESSURE
+                | family::PITCH_BEND
+                | family::PROGRAM_CHANGE,
+            clock: 0,
+        }
+    }
+
+    /// Only dedup the families in `families`, other families are ignored
+    ///
+    /// Only control change, channel pressure, pitch bend and program change can be deduped.
+    pub fn set_families(&mut self, families: u16) {
+        self.families = families;
+    }
+
+    /// Forget all remembered values, so the next value of every controller is passed on
+    pub fn invalidate(&mut self) {
+        self.entries = [None; ENTRIES];
+    }
+
+    /// Process a received message, returns `None` if it repeats the remembered value
+    pub fn process(&mut self, message: MidiMessage) -> Option<MidiMessage> {
+        let (family, channel, controller, value) = match message {
+            MidiMessage::ControlChange(channel, control, value) => (
+                family::CONTROL_CHANGE,
+                channel,
+                u8::from(control),
+                u8::from(value) as u16,
+            ),
+            MidiMessage::ChannelPressure(channel, value) => {
+                (family::CHANNEL_PRESSURE, channel, 0, u8::from(value) as u16)
+            }
+            MidiMessage::PitchBendChange(channel, value) => {
+                (family::PITCH_BEND, channel, 0, u16::from(value))
+            }
+            MidiMessage::ProgramChange(channel, program) => {
+                (family::PROGRAM_CHANGE, channel, 0, u8::from(program) as u16)
+            }
+            _ => return Some(message),
+        };
+        if self.families & family == 0 {
+            return Some(message);
+        }
+
+        self.clock = self.clock.wrapping_add(1);
+        let key = (family, u8::from(channel));
+        let clock = self.clock;
+        let found = self
+            .entries
+            .iter_mut()
+            .flatten()
+            .find(|entry| entry.key == key && entry.controller == controller);
+
+        if let Some(entry) = found {
+            entry.used = clock;
+            if entry.value == value {
+                return None;
+            }
+            entry.value = value;
+            return Some(message);
+        }
+
+        let entry = Entry {
+            key,
+            controller,
+            value,
+            used: clock,
+        };
+        let slot = self.entries.iter_mut().max_by_key(|slot| match slot {
+            Some(entry) => clock.wrapping_sub(entry.used),
+            None => u32::MAX,
+        });
+        if let Some(slot) = slot {
+            *slot = Some(entry);
+        }
+        Some(message)
+    }
+}
+
+impl<const ENTRIES: usize> MidiProcessor for Dedup<ENTRIES> {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        if let Some(message) = Dedup::process(self, message) {
+            emit(message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{cc, channel_pressure, note_on, pitch_bend, program_change};
+
+    #[test]
+    fn should_drop_repeated_values() {
+        let mut dedup = Dedup::<8>::new();
+
+        for message in [
+            cc(0, 7, 100),
+            channel_pressure(0, 20),
+            pitch_bend(0, 300),
+            program_change(0, 5),
+        ] {
+            assert_eq!(dedup.process(message), Some(message));
+            assert_eq!(dedup.process(message), None);
+        }
+        assert_eq!(dedup.process(cc(0, 7, 101)), Some(cc(0, 7, 101)));
+        assert_eq!(dedup.process(cc(0, 7, 101)), None);
+    }
+
+    #[test]
+    fn should_keep_channels_and_controllers_apart() {
+        let mut dedup = Dedup::<8>::new();
+
+        dedup.process(cc(0, 7, 100));
+        assert_eq!(dedup.process(cc(1, 7, 100)), Some(cc(1, 7, 100)));
+        assert_eq!(dedup.process(cc(0, 10, 100)), Some(cc(0, 10, 100)));
+        assert_eq!(
+            dedup.process(channel_pressure(0, 100)),
+            Some(channel_pressure(0, 100))
+        );
+    }
+
+    #[test]
+    fn should_never_drop_notes() {
+        let mut dedup = Dedup::<8>::new();
+
+        assert_eq!(
+            dedup.process(note_on(0, 60, 100)),
+            Some(note_on(0, 60, 100))
+        );
+        assert_eq!(
+            dedup.process(note_on(0, 60, 100)),
+            Some(note_on(0, 60, 100))
+        );
+        assert_eq!(
+            dedup.process(MidiMessage::ActiveSensing),
+            Some(MidiMessage::ActiveSensing)
+        );
+    }
+
+    #[test]
+    fn should_pass_value_again_after_invalidate() {
+        let mut dedup = Dedup::<8>::new();
+
+        dedup.process(cc(0, 7, 100));
+        assert_eq!(dedup.process(cc(0, 7, 100)), None);
+        dedup.invalidate();
+        assert_eq!(dedup.process(cc(0, 7, 100)), Some(cc(0, 7, 100)));
+        assert_eq!(dedup.process(cc(0, 7, 100)), None);
+    }
+
+    #[test]
+    fn should_evict_least_recently_used() {
+        let mut dedup = Dedup::<2>::new();
+
+        dedup.process(cc(0, 1, 10));
+        dedup.process(cc(0, 2, 20));
+        assert_eq!(dedup.process(cc(0, 1, 10)), None);
+        dedup.process(cc(0, 3, 30));
+
+        assert_eq!(dedup.process(cc(0, 1, 10)), None);
+        assert_eq!(dedup.process(cc(0, 2, 20)), Some(cc(0, 2, 20)));
+    }
+
+    #[test]
+    fn should_only_dedup_configured_families() {
+        let mut dedup = Dedup::<8>::new();
+        dedup.set_families(family::CONTROL_CHANGE);
+
+        dedup.process(pitch_bend(0, 300));
+        assert_eq!(dedup.process(pitch_bend(0, 300)), Some(pitch_bend(0, 300)));
+        dedup.process(cc(0, 7, 100));
+        assert_eq!(dedup.process(cc(0, 7, 100)), None);
+    }
+}
@@ -0,0 +1,294 @@
+//! Channel addressing for several devices daisy chained on one midi line
+
+use crate::{
+    message::channel,
+    persist::{Reader, Writer},
+    DecodeError, MidiProcessor, SysexHandler, TooSmall,
+};
+use midi_convert::midi_types::{Channel, MidiMessage};
+
+/// Longest manufacturer id plus device id, command, base channel and count
+const CONFIG_LEN: usize = 3 + 4;
+
+/// Bit mask of `count` channels starting at `base`, limited to channel 16
+fn range(base: Channel, count: u8) -> u16 {
+    let mask = if count >= 16 {
+        0xffff
+    } else {
+        (1 << count) - 1
+    };
+    mask << u8::from(base)
+}
+
+/// Passes on the channel messages addressed to this device and drops the others, so several
+/// devices can share one midi line
+///
+/// Each device on the line owns a set of channels, initially all 16. System messages are passed
+/// on to every device. The filter only decides what this device acts on, forward the line with
+/// `SoftThru` so devices further down the chain still receive everything.
+///
+/// The channels can be changed at runtime with a system exclusive message for `manufacturer`,
+/// made by `set_channels_sysex`: the device id of the filter or `ALL_DEVICES`, `SET_CHANNELS`,
+/// the base channel from 0 to 15 and the number of channels from 1 to 16. Feed received system
+/// exclusive messages to the filter as a `SysexHandler`, then store the new channels with `save`.
+#[derive(Debug, Clone)]
+pub struct DeviceFilter {
+    manufacturer: &'static [u8],
+    device_id: u8,
+    channels: u16,
+    /// System exclusive message being received, `None` when it is too long to be for the filter
+    config: [u8; CONFIG_LEN],
+    config_len: Option<usize>,
+}
+
+impl DeviceFilter {
+    /// Largest number of bytes written by `save`
+    pub const MAX_SAVED_LEN: usize = 4;
+    /// Command byte of the set channels configuration message
+    pub const SET_CHANNELS: u8 = 0x01;
+    /// Device id addressing every device in a configuration message
+    pub const ALL_DEVICES: u8 = 0x7f;
+
+    /// Filter for the device `device_id` owning all channels, configured by system exclusive
+    /// messages for `manufacturer`
+    pub fn new(manufacturer: &'static [u8], device_id: u8) -> Self {
+        DeviceFilter {
+            manufacturer,
+            device_id: device_id & 0x7f,
+            channels: 0xffff,
+            config: [0; CONFIG_LEN],
+            config_len: Some(0),
+        }
+    }
+
+    /// Data bytes of a system exclusive message making the device `device_id` use `count` channels
+    /// starting at `base`, for sending with `MidiOut::write_sysex`
+    ///
+    /// Returns the number of bytes written to `buffer`, which needs the length of the manufacturer
+    /// id plus 4 bytes.
+    pub fn set_channels_sysex(
+        manufacturer: &[u8],
+        device_id: u8,
+        base: Channel,
+        count: u8,
+        buffer: &mut [u8],
+    ) -> Result<usize, TooSmall> {
+        let len = manufacturer.len() + 4;
+        let buffer = buffer.get_mut(..len).ok_or(TooSmall)?;
+        buffer[..manufacturer.len()].copy_from_slice(manufacturer);
+        buffer[manufacturer.len()..].copy_from_slice(&[
+            device_id & 0x7f,
+            Self::SET_CHANNELS,
+            base.into(),
+            count.clamp(1, 16),
+        ]);
+        Ok(len)
+    }
+
+    /// Device id the filter takes configuration messages for, from 0 to 127
+    pub fn device_id(&self) -> u8 {
+        self.device_id
+    }
+
+    /// Own `count` channels starting at `base`
+    pub fn set_channels(&mut self, base: Channel, count: u8) {
+        self.channels = range(base, count.clamp(1, 16));
+    }
+
+    /// Own the channels in a mask with bit 0 for channel 1
+    pub fn set_channel_mask(&mut self, mask: u16) {
+        self.channels = mask;
+    }
+
+    /// Mask of the owned channels with bit 0 for channel 1
+    pub fn channel_mask(&self) -> u16 {
+        self.channels
+    }
+
+    /// Check if this device owns `channel`, its bit in `channel_mask` is set, bit 0 for channel 1
+    pub fn owns(&self, channel: Channel) -> bool {
+        self.channels & (1 << u8::from(channel)) != 0
+    }
+
+    /// Check if this device acts on `message`
+    pub fn accepts(&self, message: &MidiMessage) -> bool {
+        channel(message).map_or(true, |channel| self.owns(channel))
+    }
+
+    /// Apply a configuration message, `data` are the bytes between 0xf0 and 0xf7
+    ///
+    /// Returns `true` if the message was for this device and changed its channels.
+    pub fn apply_sysex(&mut self, data: &[u8]) -> bool {
+        let rest = match data.strip_prefix(self.manufacturer) {
+            Some(rest) => rest,
+            None => return false,
+        };
+        match *rest {
+            [device, Self::SET_CHANNELS, base @ 0..=0x0f, count @ 1..=16]
+                if device == self.device_id || device == Self::ALL_DEVICES =>
+            {
+                self.set_channels(base.into(), count);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Save the device id and channels into `buffer`, returns the number of bytes written
+    pub fn save(&self, buffer: &mut [u8]) -> Result<usize, TooSmall> {
+        let mut writer = Writer::new(buffer)?;
+        writer.byte(self.device_id)?;
+        writer.u16(self.channels)?;
+        Ok(writer.finish())
+    }
+
+    /// Load a filter saved by `save`, configured by system exclusive messages for `manufacturer`
+    pub fn load(manufacturer: &'static [u8], bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes)?;
+        let mut filter = Self::new(manufacturer, reader.value7()?);
+        filter.channels = reader.u16()?;
+        Ok(filter)
+    }
+}
+
+impl SysexHandler for DeviceFilter {
+    fn on_sysex_chunk(&mut self, chunk: &[u8], first: bool, last: bool) {
+        if first {
+            self.config_len = Some(0);
+        }
+        self.config_len = self.config_len.and_then(|len| {
+            let end = len + chunk.len();
+            self.config.get_mut(len..end)?.copy_from_slice(chunk);
+            Some(end)
+        });
+        if let (true, Some(len)) = (last, self.config_len) {
+            let config = self.config;
+            self.apply_sysex(&config[..len]);
+        }
+    }
+
+    fn on_sysex_abort(&mut self) {
+        self.config_len = None;
+    }
+}
+
+impl MidiProcessor for DeviceFilter {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        if self.accepts(&message) {
+            emit(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_on, program_change};
+    use crate::SysexStream;
+    use std::vec::Vec;
+
+    const MANUFACTURER: &[u8] = &[0x7d];
+
+    fn config(device_id: u8, base: u8, count: u8) -> Vec<u8> {
+        let mut buffer = [0; 8];
+        let len = DeviceFilter::set_channels_sysex(
+            MANUFACTURER,
+            device_id,
+            base.into(),
+            count,
+            &mut buffer,
+        )
+        .unwrap();
+        let mut bytes = std::vec![0xf0];
+        bytes.extend_from_slice(&buffer[..len]);
+        bytes.push(0xf7);
+        bytes
+    }
+
+    #[test]
+    fn should_split_one_stream_between_devices() {
+        let mut filters = [
+            DeviceFilter::new(MANUFACTURER, 1),
+            DeviceFilter::new(MANUFACTURER, 2),
+            DeviceFilter::new(MANUFACTURER, 3),
+        ];
+        let mut sysex = SysexStream::<4>::new();
+
+        // Configuration messages arrive on the shared line, in chunks smaller than the messages
+        let mut line = Vec::new();
+        line.extend(config(DeviceFilter::ALL_DEVICES, 0, 16));
+        line.extend(config(1, 0, 6));
+        line.extend(config(2, 6, 5));
+        line.extend(config(3, 11, 5));
+        line.extend(config(4, 0, 1));
+        for filter in filters.iter_mut() {
+            for byte in line.iter() {
+                sysex.feed(0, *byte, filter);
+            }
+        }
+
+        let mut messages = Vec::new();
+        for channel in 0..16 {
+            messages.extend([
+                note_on(channel, 60, 100),
+                cc(channel, 7, 100),
+                program_change(channel, 3),
+            ]);
+        }
+        messages.push(MidiMessage::TimingClock);
+
+        let mut received: [Vec<MidiMessage>; 3] = Default::default();
+        for (filter, received) in filters.iter_mut().zip(received.iter_mut()) {
+            for message in messages.iter() {
+                filter.process(*message, &mut |message| received.push(message));
+            }
+        }
+
+        // Every channel message reaches exactly one device, system messages reach all of them
+        for message in messages.iter() {
+            let count = received
+                .iter()
+                .filter(|received| received.contains(message))
+                .count();
+            let expected = if channel(message).is_some() { 1 } else { 3 };
+            assert_eq!(count, expected, "{:?}", message);
+        }
+        assert_eq!(received[0].len(), 6 * 3 + 1);
+        assert_eq!(received[1].len(), 5 * 3 + 1);
+        assert!(received[2].contains(&note_on(15, 60, 100)));
+    }
+
+    #[test]
+    fn should_ignore_foreign_and_invalid_messages() {
+        let mut filter = DeviceFilter::new(MANUFACTURER, 5);
+        assert!(!filter.apply_sysex(&[0x7e, 5, DeviceFilter::SET_CHANNELS, 0, 1]));
+        assert!(!filter.apply_sysex(&[0x7d, 6, DeviceFilter::SET_CHANNELS, 0, 1]));
+        assert!(!filter.apply_sysex(&[0x7d, 5, DeviceFilter::SET_CHANNELS, 0x10, 1]));
+        assert!(!filter.apply_sysex(&[0x7d, 5, DeviceFilter::SET_CHANNELS, 0, 0]));
+        assert!(!filter.apply_sysex(&[0x7d, 5, DeviceFilter::SET_CHANNELS, 0, 1, 0]));
+        assert_eq!(filter.channel_mask(), 0xffff);
+
+        assert!(filter.apply_sysex(&[0x7d, 5, DeviceFilter::SET_CHANNELS, 14, 16]));
+        assert_eq!(filter.channel_mask(), 0xc000);
+        assert!(filter.owns(Channel::C16));
+        assert!(!filter.owns(Channel::C13));
+    }
+
+    #[test]
+    fn should_save_and_load_channels() {
+        let mut filter = DeviceFilter::new(MANUFACTURER, 9);
+        filter.set_channel_mask(0b1010_0000_0000_0101);
+        let mut buffer = [0; DeviceFilter::MAX_SAVED_LEN];
+        assert_eq!(filter.save(&mut buffer), Ok(4));
+
+        let loaded = DeviceFilter::load(MANUFACTURER, &buffer).unwrap();
+        assert_eq!(loaded.device_id(), 9);
+        assert_eq!(loaded.channel_mask(), filter.channel_mask());
+        assert_eq!(
+            DeviceFilter::load(MANUFACTURER, &buffer[..3]).err(),
+            Some(DecodeError::TooShort)
+        );
+        assert_eq!(filter.save(&mut buffer[..3]), Err(TooSmall));
+    }
+}
@@ -0,0 +1,41 @@
+//! Diagnostic records through the `log` crate, enabled with the `log` feature
+//!
+//! Without the feature the macros only type check their arguments and compile to nothing.
+//! Records for every message are logged at trace level, unusual but harmless events at debug
+//! level and lost data at warn level.
+
+#[cfg(feature = "log")]
+macro_rules! log_trace {
+    ($($arg:tt)*) => { log::trace!(target: "embedded_midi", $($arg)*) };
+}
+
+#[cfg(feature = "log")]
+macro_rules! log_debug {
+    ($($arg:tt)*) => { log::debug!(target: "embedded_midi", $($arg)*) };
+}
+
+#[cfg(feature = "log")]
+macro_rules! log_warn {
+    ($($arg:tt)*) => { log::warn!(target: "embedded_midi", $($arg)*) };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! log_trace {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! log_debug {
+    ($($arg:tt)*) => { $crate::diag::log_trace!($($arg)*) };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! log_warn {
+    ($($arg:tt)*) => { $crate::diag::log_trace!($($arg)*) };
+}
+
+pub(crate) use {log_debug, log_trace, log_warn};
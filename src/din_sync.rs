@@ -0,0 +1,208 @@
+//! Bridging midi clock and DIN sync
+
+use crate::diag::log_debug;
+use embedded_hal::digital::OutputPin;
+use midi_convert::midi_types::MidiMessage;
+
+/// Drives DIN sync run and clock outputs from midi transport and clock messages, and turns DIN
+/// sync input edges into midi messages
+///
+/// The run pin goes high on start and continue and low on stop. Every timing clock raises the
+/// clock pin, `tick` lowers it again once the pulse width has passed, so no blocking delays are
+/// needed. Both directions use 24 pulses per quarter note, timestamps are in microseconds and may
+/// wrap around.
+#[derive(Debug)]
+pub struct DinSyncBridge<RUN, CLOCK> {
+    run: RUN,
+    clock: CLOCK,
+    pulse_width_us: u32,
+    /// Time the clock pin went high, while a pulse is being sent
+    pulse_start: Option<u32>,
+    min_edge_interval_us: u32,
+    last_edge: Option<u32>,
+    running: bool,
+}
+
+impl<RUN, CLOCK, E> DinSyncBridge<RUN, CLOCK>
+where
+    RUN: OutputPin<Error = E>,
+    CLOCK: OutputPin<Error = E>,
+{
+    /// Bridge with 2 ms clock pulses, both pins should be low
+    pub fn new(run: RUN, clock: CLOCK) -> Self {
+        DinSyncBridge {
+            run,
+            clock,
+            pulse_width_us: 2000,
+            pulse_start: None,
+            min_edge_interval_us: 0,
+            last_edge: None,
+            running: false,
+        }
+    }
+
+    pub fn release(self) -> (RUN, CLOCK) {
+        (self.run, self.clock)
+    }
+
+    pub fn set_pulse_width_us(&mut self, width: u32) {
+        self.pulse_width_us = width;
+    }
+
+    /// Ignore clock edges less than `interval` microseconds after the previous edge, to debounce
+    /// noisy inputs
+    pub fn set_min_edge_interval_us(&mut self, interval: u32) {
+        self.min_edge_interval_us = interval;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Update the outputs for a received message at time `now_us`, other messages are ignored
+    pub fn receive(&mut self, message: &MidiMessage, now_us: u32) -> Result<(), E> {
+        match message {
+            MidiMessage::Start | MidiMessage::Continue => {
+                log_debug!("din sync: {:?}", message);
+                self.running = true;
+                self.run.set_high()
+            }
+            MidiMessage::Stop => {
+                log_debug!("din sync: stop");
+                self.running = false;
+                self.run.set_low()
+            }
+            MidiMessage::TimingClock => {
+                if self.pulse_start.is_some() {
+                    // The previous pulse is still high, end it so every clock shows as an edge
+                    self.clock.set_low()?;
+                }
+                self.pulse_start = Some(now_us);
+                self.clock.set_high()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// End the clock pulse once the pulse width has passed, call this often
+    pub fn tick(&mut self, now_us: u32) -> Result<(), E> {
+        match self.pulse_start {
+            Some(start) if now_us.wrapping_sub(start) >= self.pulse_width_us => {
+                self.pulse_start = None;
+                self.clock.set_low()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Timing clock for a rising edge of the DIN sync clock input at time `now`, `None` when
+    /// the edge is ignored by the debounce interval
+    pub fn on_clock_edge(&mut self, now_us: u32) -> Option<MidiMessage> {
+        match self.last_edge {
+            Some(last) if now_us.wrapping_sub(last) < self.min_edge_interval_us => None,
+            _ => {
+                self.last_edge = Some(now_us);
+                Some(MidiMessage::TimingClock)
+            }
+        }
+    }
+
+    /// Start or stop for a change of the DIN sync run input
+    pub fn on_run_edge(&mut self, high: bool) -> MidiMessage {
+        self.last_edge = None;
+        if high {
+            MidiMessage::Start
+        } else {
+            MidiMessage::Stop
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use embedded_hal_mock::eh1::digital::{Mock, State, Transaction};
+    use std::vec::Vec;
+
+    fn pin(states: &[State]) -> Mock {
+        let expectations: Vec<Transaction> = states
+            .iter()
+            .map(|state| Transaction::set(*state))
+            .collect();
+        Mock::new(&expectations)
+    }
+
+    #[test]
+    fn should_follow_transport_on_run_pin() {
+        let run = pin(&[State::High, State::Low, State::High]);
+        let mut bridge = DinSyncBridge::new(run, pin(&[]));
+
+        bridge.receive(&MidiMessage::Start, 0).unwrap();
+        assert!(bridge.is_running());
+        bridge.receive(&MidiMessage::Stop, 10).unwrap();
+        assert!(!bridge.is_running());
+        bridge.receive(&MidiMessage::Continue, 20).unwrap();
+        bridge
+            .receive(&MidiMessage::NoteOn(0.into(), 60.into(), 100.into()), 30)
+            .unwrap();
+
+        let (mut run, mut clock) = bridge.release();
+        run.done();
+        clock.done();
+    }
+
+    #[test]
+    fn should_pulse_clock_pin_for_pulse_width() {
+        let clock = pin(&[State::High, State::Low, State::High, State::Low]);
+        let mut bridge = DinSyncBridge::new(pin(&[]), clock);
+        bridge.set_pulse_width_us(1000);
+
+        bridge.receive(&MidiMessage::TimingClock, 0).unwrap();
+        bridge.tick(999).unwrap();
+        bridge.tick(1000).unwrap();
+        bridge.tick(1500).unwrap();
+        bridge
+            .receive(&MidiMessage::TimingClock, u32::MAX - 100)
+            .unwrap();
+        bridge.tick(200).unwrap();
+        bridge.tick(898).unwrap();
+        bridge.tick(899).unwrap();
+
+        let (mut run, mut clock) = bridge.release();
+        run.done();
+        clock.done();
+    }
+
+    #[test]
+    fn should_end_pulse_early_for_fast_clocks() {
+        let clock = pin(&[State::High, State::Low, State::High, State::Low]);
+        let mut bridge = DinSyncBridge::new(pin(&[]), clock);
+
+        bridge.receive(&MidiMessage::TimingClock, 0).unwrap();
+        bridge.receive(&MidiMessage::TimingClock, 1000).unwrap();
+        bridge.tick(3000).unwrap();
+
+        let (mut run, mut clock) = bridge.release();
+        run.done();
+        clock.done();
+    }
+
+    #[test]
+    fn should_generate_midi_from_din_sync_edges() {
+        let mut bridge = DinSyncBridge::new(pin(&[]), pin(&[]));
+        bridge.set_min_edge_interval_us(500);
+
+        assert_eq!(bridge.on_run_edge(true), MidiMessage::Start);
+        let clocks = [0, 100, 20_000, 40_000, 40_499, 60_000]
+            .iter()
+            .filter_map(|now| bridge.on_clock_edge(*now))
+            .count();
+        assert_eq!(clocks, 4);
+        assert_eq!(bridge.on_run_edge(false), MidiMessage::Stop);
+
+        let (mut run, mut clock) = bridge.release();
+        run.done();
+        clock.done();
+    }
+}
@@ -0,0 +1,365 @@
+//! Midi input parsing a ring buffer filled by a circular DMA transfer
+
+use crate::diag::log_debug;
+use crate::wire::Receiver;
+use crate::{family, ParseEvent, SourceError};
+use core::ops::Range;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use midi_convert::midi_types::MidiMessage;
+
+/// The ring and the count of bytes the DMA filled, shared by the interrupt handler and the reader
+#[derive(Debug)]
+struct Ring<const N: usize> {
+    buffer: [AtomicU8; N],
+    /// Bytes ever filled, wrapping
+    produced: AtomicU32,
+    /// Ring index the next filled region starts at
+    write_index: AtomicU32,
+}
+
+impl<const N: usize> Ring<N> {
+    fn notify_filled(&self, range: Range<usize>) {
+        if range.is_empty() || range.end > N {
+            log_debug!("dma ring midi in: ignoring region {:?}", range);
+            return;
+        }
+        let write_index = self.write_index.load(Ordering::Relaxed) as usize;
+        // A region ending where the last one ended went around the whole ring
+        let count = if range.end > write_index {
+            range.end - write_index
+        } else {
+            range.end + N - write_index
+        };
+        self.write_index
+            .store((range.end % N) as u32, Ordering::Relaxed);
+        let produced = self.produced.load(Ordering::Relaxed);
+        self.produced
+            .store(produced.wrapping_add(count as u32), Ordering::Release);
+    }
+}
+
+/// Parser state and position of the reader
+#[derive(Debug)]
+struct ReadState {
+    receiver: Receiver<{ family::ALL }>,
+    read_index: usize,
+    /// Bytes ever read, wrapping
+    consumed: u32,
+}
+
+impl ReadState {
+    fn poll<const N: usize>(
+        &mut self,
+        ring: &Ring<N>,
+        emit: &mut dyn FnMut(MidiMessage),
+    ) -> Result<(), SourceError> {
+        let produced = ring.produced.load(Ordering::Acquire);
+        while self.consumed != produced {
+            let byte = ring.buffer[self.read_index].load(Ordering::Relaxed);
+            // The byte was overwritten if the DMA filled the whole ring since it was filled
+            let behind = ring
+                .produced
+                .load(Ordering::Acquire)
+                .wrapping_sub(self.consumed);
+            if behind > N as u32 {
+                self.skip_lost(ring);
+                return Err(SourceError::Overrun);
+            }
+            self.read_index = (self.read_index + 1) % N;
+            self.consumed = self.consumed.wrapping_add(1);
+            if let Some(ParseEvent::Message(message)) = self.receiver.push(byte) {
+                emit(message);
+            }
+        }
+        Ok(())
+    }
+
+    /// Continue after the last filled byte and forget the message being received
+    fn skip_lost<const N: usize>(&mut self, ring: &Ring<N>) {
+        let produced = ring.produced.load(Ordering::Acquire);
+        let lost = produced.wrapping_sub(self.consumed);
+        log_debug!("dma ring midi in: overrun, {} bytes skipped", lost);
+        self.read_index = (self.read_index + lost as usize % N) % N;
+        self.consumed = produced;
+        self.receiver.resync();
+    }
+}
+
+/// Midi input owning the ring buffer of a circular DMA receive transfer
+///
+/// The DMA is set up to fill the `len` bytes at `DmaRingNotifier::buffer_ptr` in circular mode.
+/// Its half transfer and transfer complete interrupts, or the idle line interrupt, call
+/// `notify_filled` with the region filled since the last call, `poll` parses the bytes filled
+/// since the last poll. Only the end of a region is used, so a region that doesn't start where
+/// the last one ended includes the bytes in between, and a region ending where the last one ended
+/// is a whole turn around the ring.
+///
+/// The ring must stay at the same address while the DMA writes it, so the address is only handed
+/// out by a notifier split from a `&'static mut DmaRingMidiIn`, like one from
+/// `cortex_m::singleton!` or a `static_cell::StaticCell`.
+///
+/// When more than `N` bytes were filled since a byte was read it was overwritten, `poll` then
+/// skips to the last filled byte, forgets the message being received and fails with
+/// `SourceError::Overrun`. Overruns are detected from the notified regions, so bytes the DMA
+/// overwrites before the region is notified go unnoticed, poll at least once per half ring.
+///
+/// `split` hands out a `DmaRingNotifier` for the interrupt handler and a `DmaRingReader` for the
+/// thread parsing the bytes. Notifications must all come from the same interrupt handler, or
+/// from handlers that don't interrupt each other.
+#[derive(Debug)]
+pub struct DmaRingMidiIn<const N: usize> {
+    ring: Ring<N>,
+    state: ReadState,
+}
+
+impl<const N: usize> Default for DmaRingMidiIn<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> DmaRingMidiIn<N> {
+    pub fn new() -> Self {
+        DmaRingMidiIn {
+            ring: Ring {
+                buffer: core::array::from_fn(|_| AtomicU8::new(0)),
+                produced: AtomicU32::new(0),
+                write_index: AtomicU32::new(0),
+            },
+            state: ReadState {
+                receiver: Receiver::new(),
+                read_index: 0,
+                consumed: 0,
+            },
+        }
+    }
+
+    /// Size of the ring buffer, the number of bytes of the DMA transfer
+    pub const fn len(&self) -> usize {
+        N
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        N == 0
+    }
+
+    /// See `MidiIn::set_strict_system_common`
+    pub fn set_strict_system_common(&mut self, strict: bool) {
+        self.state.receiver.strict_system_common = strict;
+    }
+
+    /// Record that the DMA filled `range` of the ring
+    pub fn notify_filled(&self, range: Range<usize>) {
+        self.ring.notify_filled(range);
+    }
+
+    /// Parse the bytes filled since the last poll, calling `emit` with every message they
+    /// complete
+    pub fn poll(&mut self, emit: &mut dyn FnMut(MidiMessage)) -> Result<(), SourceError> {
+        self.state.poll(&self.ring, emit)
+    }
+
+    /// Split into a handle for the interrupt handler and one for the reader
+    pub fn split(&mut self) -> (DmaRingNotifier<'_, N>, DmaRingReader<'_, N>) {
+        (
+            DmaRingNotifier { ring: &self.ring },
+            DmaRingReader {
+                ring: &self.ring,
+                state: &mut self.state,
+            },
+        )
+    }
+}
+
+/// Handle of a `DmaRingMidiIn` for the DMA interrupt handler
+#[derive(Debug, Clone, Copy)]
+pub struct DmaRingNotifier<'a, const N: usize> {
+    ring: &'a Ring<N>,
+}
+
+impl<const N: usize> DmaRingNotifier<'_, N> {
+    /// See `DmaRingMidiIn::notify_filled`
+    pub fn notify_filled(&self, range: Range<usize>) {
+        self.ring.notify_filled(range);
+    }
+}
+
+impl<const N: usize> DmaRingNotifier<'static, N> {
+    /// Start of the ring buffer, the memory address for the DMA transfer
+    ///
+    /// Only available for a ring borrowed for `'static`, so it stays at this address.
+    pub fn buffer_ptr(&self) -> *mut u8 {
+        self.ring.buffer.as_ptr() as *mut u8
+    }
+}
+
+/// Handle of a `DmaRingMidiIn` for the thread parsing the received bytes
+#[derive(Debug)]
+pub struct DmaRingReader<'a, const N: usize> {
+    ring: &'a Ring<N>,
+    state: &'a mut ReadState,
+}
+
+impl<const N: usize> DmaRingReader<'_, N> {
+    /// See `DmaRingMidiIn::poll`
+    pub fn poll(&mut self, emit: &mut dyn FnMut(MidiMessage)) -> Result<(), SourceError> {
+        self.state.poll(self.ring, emit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_off, note_on};
+    use std::{boxed::Box, vec::Vec};
+
+    /// Write `bytes` into the ring at `at` like the DMA does
+    fn fill<const N: usize>(ring: &Ring<N>, at: usize, bytes: &[u8]) {
+        for (offset, byte) in bytes.iter().enumerate() {
+            ring.buffer[(at + offset) % N].store(*byte, Ordering::Relaxed);
+        }
+    }
+
+    fn poll<const N: usize>(
+        midi_in: &mut DmaRingMidiIn<N>,
+    ) -> (Vec<MidiMessage>, Result<(), SourceError>) {
+        let mut received = Vec::new();
+        let result = midi_in.poll(&mut |message| received.push(message));
+        (received, result)
+    }
+
+    #[test]
+    fn should_parse_messages_across_the_end_of_the_ring() {
+        let mut midi_in = DmaRingMidiIn::<8>::new();
+        assert_eq!(midi_in.len(), 8);
+
+        fill(&midi_in.ring, 0, &[0x90, 60, 100, 0x80]);
+        midi_in.notify_filled(0..4);
+        assert_eq!(
+            poll(&mut midi_in),
+            (Vec::from([note_on(0, 60, 100)]), Ok(()))
+        );
+
+        fill(&midi_in.ring, 4, &[60, 0, 0xb0, 7]);
+        midi_in.notify_filled(4..8);
+        assert_eq!(
+            poll(&mut midi_in),
+            (Vec::from([note_off(0, 60, 0)]), Ok(()))
+        );
+
+        // The control change started at the end of the ring completes at its start
+        fill(&midi_in.ring, 0, &[100, 8, 50, 0xf8]);
+        midi_in.notify_filled(0..4);
+        assert_eq!(
+            poll(&mut midi_in),
+            (
+                Vec::from([cc(0, 7, 100), cc(0, 8, 50), MidiMessage::TimingClock]),
+                Ok(())
+            )
+        );
+        assert_eq!(poll(&mut midi_in), (Vec::new(), Ok(())));
+    }
+
+    #[test]
+    fn should_count_regions_from_the_last_one() {
+        let mut midi_in = DmaRingMidiIn::<8>::new();
+        // Idle line interrupts notify regions of any length, a missed notification is covered
+        // by the next one
+        fill(&midi_in.ring, 0, &[0x90, 60, 100, 0x90, 62, 100]);
+        midi_in.notify_filled(0..2);
+        midi_in.notify_filled(3..6);
+        assert_eq!(
+            poll(&mut midi_in),
+            (
+                Vec::from([note_on(0, 60, 100), note_on(0, 62, 100)]),
+                Ok(())
+            ),
+        );
+
+        // A region ending where the last one ended is a whole turn around the ring
+        fill(&midi_in.ring, 6, &[0xfa, 0xfc, 0xb0, 1, 2, 0xb0, 3, 4]);
+        midi_in.notify_filled(0..6);
+        let (received, result) = poll(&mut midi_in);
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            received,
+            [
+                MidiMessage::Start,
+                MidiMessage::Stop,
+                cc(0, 1, 2),
+                cc(0, 3, 4)
+            ]
+        );
+        // Regions outside of the ring are ignored
+        midi_in.notify_filled(9..12);
+        midi_in.notify_filled(2..2);
+        assert_eq!(poll(&mut midi_in), (Vec::new(), Ok(())));
+    }
+
+    #[test]
+    fn should_report_an_overrun_and_resync() {
+        let mut midi_in = DmaRingMidiIn::<8>::new();
+        fill(&midi_in.ring, 0, &[0x90, 60, 100, 62]);
+        midi_in.notify_filled(0..4);
+        fill(&midi_in.ring, 4, &[100, 64, 100, 66]);
+        midi_in.notify_filled(4..8);
+        // The first half is overwritten before the reader gets to it
+        fill(&midi_in.ring, 0, &[100, 68, 100, 0x80]);
+        midi_in.notify_filled(0..4);
+        assert_eq!(poll(&mut midi_in), (Vec::new(), Err(SourceError::Overrun)));
+
+        // Running status was forgotten, the data bytes are dropped until the next status byte
+        fill(&midi_in.ring, 4, &[60, 0, 70, 0x80]);
+        midi_in.notify_filled(4..8);
+        fill(&midi_in.ring, 0, &[62, 0, 0, 0]);
+        midi_in.notify_filled(0..2);
+        assert_eq!(
+            poll(&mut midi_in),
+            (Vec::from([note_off(0, 62, 0)]), Ok(()))
+        );
+    }
+
+    #[test]
+    fn should_parse_bytes_written_at_the_buffer_pointer() {
+        let midi_in: &'static mut DmaRingMidiIn<4> = Box::leak(Box::new(DmaRingMidiIn::new()));
+        let (notifier, mut reader) = midi_in.split();
+        let buffer = notifier.buffer_ptr();
+        for (index, byte) in [0x90, 60, 100, 0xf8].iter().enumerate() {
+            // Safety: the DMA writes the same way, the ring is atomic bytes that never move
+            unsafe { buffer.add(index).write_volatile(*byte) };
+        }
+        notifier.notify_filled(0..4);
+
+        let mut received = Vec::new();
+        reader.poll(&mut |message| received.push(message)).unwrap();
+        assert_eq!(received, [note_on(0, 60, 100), MidiMessage::TimingClock]);
+    }
+
+    #[test]
+    fn should_detect_the_dma_overtaking_the_reader() {
+        let mut midi_in = DmaRingMidiIn::<8>::new();
+        let (notifier, mut reader) = midi_in.split();
+        fill(notifier.ring, 0, &[0x90, 60, 100, 0x90]);
+        notifier.notify_filled(0..4);
+
+        // The interrupt handler fills the ring twice while the first message is handled
+        let mut received = Vec::new();
+        let result = reader.poll(&mut |message| {
+            if received.is_empty() {
+                fill(notifier.ring, 4, &[0xf8; 8]);
+                notifier.notify_filled(4..8);
+                notifier.notify_filled(0..4);
+            }
+            received.push(message);
+        });
+        assert_eq!(result, Err(SourceError::Overrun));
+        assert_eq!(received, [note_on(0, 60, 100)]);
+
+        fill(notifier.ring, 4, &[0x90, 61, 100]);
+        notifier.notify_filled(4..7);
+        let mut received = Vec::new();
+        reader.poll(&mut |message| received.push(message)).unwrap();
+        assert_eq!(received, [note_on(0, 61, 100)]);
+    }
+}
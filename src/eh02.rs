@@ -0,0 +1,219 @@
+//! Adapters for serial ports implementing the `embedded-hal` 0.2 traits, enabled with the `eh0`
+//! feature
+//!
+//! ```
+//! use eh0::serial;
+//! use embedded_midi::{Eh02Rx, MidiIn};
+//!
+//! fn legacy_input<RX>(rx: RX) -> MidiIn<Eh02Rx<RX>>
+//! where
+//!     RX: serial::Read<u8>,
+//!     RX::Error: core::fmt::Debug,
+//! {
+//!     MidiIn::new(Eh02Rx::new(rx))
+//! }
+//! ```
+
+use core::fmt::Debug;
+use embedded_hal_nb::serial::{self, ErrorKind};
+
+/// Error of an `embedded-hal` 0.2 serial port together with its kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Eh02Error<E> {
+    pub error: E,
+    pub kind: ErrorKind,
+}
+
+impl<E: Debug> serial::Error for Eh02Error<E> {
+    fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+/// Errors of `embedded-hal` 0.2 serial ports have no kind, they are reported as `ErrorKind::Other`
+fn other<E>(_: &E) -> ErrorKind {
+    ErrorKind::Other
+}
+
+/// Serial receiver implementing the `embedded-hal` 1.0 traits for an `embedded-hal` 0.2 receiver
+#[derive(Debug)]
+pub struct Eh02Rx<RX: eh0::serial::Read<u8>> {
+    rx: RX,
+    kind: fn(&RX::Error) -> ErrorKind,
+}
+
+impl<RX: eh0::serial::Read<u8>> Eh02Rx<RX> {
+    pub fn new(rx: RX) -> Self {
+        Self::with_error_kind(rx, other)
+    }
+
+    /// Receiver using `kind` to classify errors, mapping overruns to `ErrorKind::Overrun` lets
+    /// `MidiIn` recover from them
+    pub fn with_error_kind(rx: RX, kind: fn(&RX::Error) -> ErrorKind) -> Self {
+        Eh02Rx { rx, kind }
+    }
+
+    pub fn release(self) -> RX {
+        self.rx
+    }
+}
+
+impl<RX> serial::ErrorType for Eh02Rx<RX>
+where
+    RX: eh0::serial::Read<u8>,
+    RX::Error: Debug,
+{
+    type Error = Eh02Error<RX::Error>;
+}
+
+impl<RX> serial::Read<u8> for Eh02Rx<RX>
+where
+    RX: eh0::serial::Read<u8>,
+    RX::Error: Debug,
+{
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        let kind = self.kind;
+        self.rx.read().map_err(|error| {
+            error.map(|error| Eh02Error {
+                kind: kind(&error),
+                error,
+            })
+        })
+    }
+}
+
+/// Serial transmitter implementing the `embedded-hal` 1.0 traits for an `embedded-hal` 0.2
+/// transmitter
+#[derive(Debug)]
+pub struct Eh02Tx<TX: eh0::serial::Write<u8>> {
+    tx: TX,
+    kind: fn(&TX::Error) -> ErrorKind,
+}
+
+impl<TX: eh0::serial::Write<u8>> Eh02Tx<TX> {
+    pub fn new(tx: TX) -> Self {
+        Self::with_error_kind(tx, other)
+    }
+
+    /// Transmitter using `kind` to classify errors
+    pub fn with_error_kind(tx: TX, kind: fn(&TX::Error) -> ErrorKind) -> Self {
+        Eh02Tx { tx, kind }
+    }
+
+    pub fn release(self) -> TX {
+        self.tx
+    }
+
+    fn map_error(&self, error: nb::Error<TX::Error>) -> nb::Error<Eh02Error<TX::Error>> {
+        error.map(|error| Eh02Error {
+            kind: (self.kind)(&error),
+            error,
+        })
+    }
+}
+
+impl<TX> serial::ErrorType for Eh02Tx<TX>
+where
+    TX: eh0::serial::Write<u8>,
+    TX::Error: Debug,
+{
+    type Error = Eh02Error<TX::Error>;
+}
+
+impl<TX> serial::Write<u8> for Eh02Tx<TX>
+where
+    TX: eh0::serial::Write<u8>,
+    TX::Error: Debug,
+{
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.tx.write(word).map_err(|error| self.map_error(error))
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        self.tx.flush().map_err(|error| self.map_error(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::{message::note_on, MidiError, MidiIn, MidiOut};
+    use embedded_hal_mock::eh0::{
+        serial::{Mock, Transaction},
+        MockError,
+    };
+    use std::io;
+
+    #[test]
+    fn should_read_messages_from_eh0_receiver() {
+        let serial = Mock::new(&[
+            Transaction::read_many([0x92, 0x76]),
+            Transaction::read_error(nb::Error::WouldBlock),
+            Transaction::read(0x34),
+        ]);
+        let mut midi_in = MidiIn::new(Eh02Rx::new(serial));
+
+        assert_eq!(midi_in.read(), Err(nb::Error::WouldBlock));
+        assert_eq!(midi_in.read(), Err(nb::Error::WouldBlock));
+        assert_eq!(midi_in.read(), Err(nb::Error::WouldBlock));
+        assert_eq!(midi_in.read(), Ok(note_on(2, 0x76, 0x34)));
+        midi_in.rx.release().done();
+    }
+
+    #[test]
+    fn should_write_messages_to_eh0_transmitter() {
+        let serial = Mock::new(&[Transaction::write_many([0x92, 0x76, 0x34])]);
+        let mut midi_out = MidiOut::new(Eh02Tx::new(serial));
+
+        midi_out.write(&note_on(2, 0x76, 0x34)).unwrap();
+        midi_out.release().release().done();
+    }
+
+    #[test]
+    fn should_map_error_kinds() {
+        fn overrun(error: &MockError) -> ErrorKind {
+            match error {
+                MockError::Io(io::ErrorKind::Interrupted) => ErrorKind::Overrun,
+                _ => ErrorKind::Other,
+            }
+        }
+        let interrupted = MockError::Io(io::ErrorKind::Interrupted);
+        let serial = Mock::new(&[
+            Transaction::read_error(nb::Error::Other(interrupted.clone())),
+            Transaction::read_error(nb::Error::Other(interrupted.clone())),
+        ]);
+        let mut rx = Eh02Rx::new(serial);
+        assert_eq!(
+            serial::Read::read(&mut rx),
+            Err(nb::Error::Other(Eh02Error {
+                error: interrupted.clone(),
+                kind: ErrorKind::Other
+            }))
+        );
+
+        let mut midi_in = MidiIn::new(Eh02Rx::with_error_kind(rx.release(), overrun));
+        assert_eq!(midi_in.read(), Err(nb::Error::Other(MidiError::Overrun)));
+        assert_eq!(midi_in.overruns(), 1);
+        midi_in.rx.release().done();
+    }
+
+    #[test]
+    fn should_map_write_errors() {
+        let error = MockError::Io(io::ErrorKind::Other);
+        let serial = Mock::new(&[Transaction::write_error(
+            0xf8,
+            nb::Error::Other(error.clone()),
+        )]);
+        let mut tx = Eh02Tx::new(serial);
+
+        assert_eq!(
+            serial::Write::write(&mut tx, 0xf8),
+            Err(nb::Error::Other(Eh02Error {
+                error,
+                kind: ErrorKind::Other
+            }))
+        );
+        tx.release().done();
+    }
+}
@@ -0,0 +1,183 @@
+//! Tasks connecting midi inputs and outputs to `embassy-sync` channels, enabled with the
+//! `embassy` feature
+//!
+//! ```ignore
+//! static MESSAGES: Channel<CriticalSectionRawMutex, MidiMessage, 8> = Channel::new();
+//!
+//! #[embassy_executor::task]
+//! async fn midi_in(rx: UartRx) {
+//!     let error = run_midi_in(rx, MESSAGES.sender()).await;
+//!     defmt::error!("midi in stopped: {}", error);
+//! }
+//! ```
+//!
+//! The serial ports are non-blocking `embedded-hal-nb` ports, while they are not ready the tasks
+//! yield to the executor.
+
+use crate::{MidiError, MidiIn, MidiOut};
+use core::fmt::Debug;
+use embassy_futures::yield_now;
+use embassy_sync::{
+    blocking_mutex::raw::RawMutex,
+    channel::{Receiver, Sender},
+};
+use embedded_hal_nb::serial;
+use midi_convert::midi_types::MidiMessage;
+
+/// Read messages from `rx` and send them to a channel, only returns when reading fails
+///
+/// Overruns don't stop the task, `MidiIn` already resynced when it reports one.
+pub async fn run_midi_in<RX, E, M, const N: usize>(
+    rx: RX,
+    sender: Sender<'_, M, MidiMessage, N>,
+) -> MidiError<E>
+where
+    RX: serial::Read<u8, Error = E>,
+    E: serial::Error,
+    M: RawMutex,
+{
+    let mut midi_in = MidiIn::new(rx);
+    loop {
+        match midi_in.read() {
+            Ok(message) => sender.send(message).await,
+            Err(nb::Error::WouldBlock) => yield_now().await,
+            Err(nb::Error::Other(MidiError::Overrun)) => {}
+            Err(nb::Error::Other(error)) => return error,
+        }
+    }
+}
+
+/// Receive messages from a channel and write them to `tx`, only returns when writing fails
+pub async fn run_midi_out<TX, E, M, const N: usize>(
+    tx: TX,
+    receiver: Receiver<'_, M, MidiMessage, N>,
+) -> MidiError<E>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+    M: RawMutex,
+{
+    let mut midi_out = MidiOut::new(tx);
+    loop {
+        let message = receiver.receive().await;
+        if let Err(error) = midi_out.write(&message) {
+            return error;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_on};
+    use embassy_futures::block_on;
+    use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel};
+    use std::vec::Vec;
+
+    #[derive(Debug, PartialEq)]
+    enum WireError {
+        Overrun,
+        Disconnected,
+    }
+
+    /// Serial port that reads and writes until `bytes` runs out, then fails
+    #[derive(Debug, Default)]
+    struct Wire {
+        bytes: Vec<u8>,
+        limit: usize,
+        /// Number of bytes left when reading reports an overrun
+        overrun_at: Option<usize>,
+    }
+
+    impl serial::ErrorType for Wire {
+        type Error = WireError;
+    }
+
+    impl serial::Read<u8> for Wire {
+        fn read(&mut self) -> nb::Result<u8, Self::Error> {
+            if self.overrun_at == Some(self.bytes.len()) {
+                self.overrun_at = None;
+                Err(nb::Error::Other(WireError::Overrun))
+            } else if self.bytes.is_empty() {
+                Err(nb::Error::Other(WireError::Disconnected))
+            } else {
+                Ok(self.bytes.remove(0))
+            }
+        }
+    }
+
+    impl serial::Write<u8> for Wire {
+        fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+            if self.bytes.len() == self.limit {
+                return Err(nb::Error::Other(WireError::Disconnected));
+            }
+            self.bytes.push(word);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    impl serial::Error for WireError {
+        fn kind(&self) -> serial::ErrorKind {
+            match self {
+                WireError::Overrun => serial::ErrorKind::Overrun,
+                WireError::Disconnected => serial::ErrorKind::Other,
+            }
+        }
+    }
+
+    #[test]
+    fn should_send_received_messages_to_channel() {
+        let channel = Channel::<NoopRawMutex, MidiMessage, 4>::new();
+        let wire = Wire {
+            bytes: [0x92, 0x76, 0x34, 0xb2, 0x07, 0x10].to_vec(),
+            ..Wire::default()
+        };
+
+        let error = block_on(run_midi_in(wire, channel.sender()));
+
+        assert_eq!(error, MidiError::Serial(WireError::Disconnected));
+        assert_eq!(channel.try_receive(), Ok(note_on(2, 0x76, 0x34)));
+        assert_eq!(channel.try_receive(), Ok(cc(2, 0x07, 0x10)));
+        assert!(channel.try_receive().is_err());
+    }
+
+    #[test]
+    fn should_keep_reading_after_an_overrun() {
+        let channel = Channel::<NoopRawMutex, MidiMessage, 4>::new();
+        // The overrun lost the rest of the note on
+        let wire = Wire {
+            bytes: [0x92, 0x76, 0xb2, 0x07, 0x10].to_vec(),
+            overrun_at: Some(3),
+            ..Wire::default()
+        };
+
+        let error = block_on(run_midi_in(wire, channel.sender()));
+
+        assert_eq!(error, MidiError::Serial(WireError::Disconnected));
+        assert_eq!(channel.try_receive(), Ok(cc(2, 0x07, 0x10)));
+        assert!(channel.try_receive().is_err());
+    }
+
+    #[test]
+    fn should_write_messages_from_channel() {
+        let channel = Channel::<NoopRawMutex, MidiMessage, 4>::new();
+        channel.try_send(note_on(2, 0x76, 0x34)).unwrap();
+        channel.try_send(note_on(2, 0x33, 0x65)).unwrap();
+        channel.try_send(note_on(3, 0x33, 0x65)).unwrap();
+        let mut wire = Wire {
+            bytes: Vec::new(),
+            limit: 5,
+            ..Wire::default()
+        };
+
+        let error = block_on(run_midi_out(&mut wire, channel.receiver()));
+
+        assert_eq!(error, MidiError::Serial(WireError::Disconnected));
+        assert_eq!(wire.bytes, [0x92, 0x76, 0x34, 0x33, 0x65]);
+    }
+}
@@ -0,0 +1,453 @@
+//! Object safe traits for midi endpoints, so code can send to and receive from any transport
+
+#[cfg(feature = "critical-section")]
+use crate::shared::{MidiSender, SharedMidiOut};
+use crate::wire::Receiver;
+use crate::{
+    family, BlockScheduler, FixedChannelOut, MidiIn, MidiOut, ParseEvent, RateConverter, SinkError,
+    SoftThru, SourceError, TapMidiIn,
+};
+use core::fmt::Debug;
+use embedded_hal_nb::serial;
+use midi_convert::midi_types::MidiMessage;
+
+/// Endpoint messages can be sent to, like a serial port, a USB endpoint or a test recorder
+pub trait MidiSink {
+    fn send(&mut self, message: &MidiMessage) -> Result<(), SinkError>;
+}
+
+/// Endpoint messages are received from
+pub trait MidiSource {
+    /// Next received message, `None` when no message is complete yet
+    fn poll(&mut self) -> Result<Option<MidiMessage>, SourceError>;
+}
+
+impl<T: MidiSink + ?Sized> MidiSink for &mut T {
+    fn send(&mut self, message: &MidiMessage) -> Result<(), SinkError> {
+        (**self).send(message)
+    }
+}
+
+impl<T: MidiSource + ?Sized> MidiSource for &mut T {
+    fn poll(&mut self) -> Result<Option<MidiMessage>, SourceError> {
+        (**self).poll()
+    }
+}
+
+impl<TX, E> MidiSink for MidiOut<TX>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+{
+    fn send(&mut self, message: &MidiMessage) -> Result<(), SinkError> {
+        Ok(self.write(message)?)
+    }
+}
+
+impl<TX, E> MidiSink for FixedChannelOut<TX>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+{
+    fn send(&mut self, message: &MidiMessage) -> Result<(), SinkError> {
+        Ok(self.write(message)?)
+    }
+}
+
+#[cfg(feature = "critical-section")]
+impl<TX, E, const N: usize> MidiSink for SharedMidiOut<TX, N>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+{
+    fn send(&mut self, message: &MidiMessage) -> Result<(), SinkError> {
+        Ok(SharedMidiOut::send(self, message)?)
+    }
+}
+
+#[cfg(feature = "critical-section")]
+impl<TX, E, const N: usize> MidiSink for MidiSender<'_, TX, N>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+{
+    fn send(&mut self, message: &MidiMessage) -> Result<(), SinkError> {
+        Ok(MidiSender::send(self, message)?)
+    }
+}
+
+/// Messages are sent when the converter hands them out
+impl<const N: usize> MidiSink for RateConverter<N> {
+    fn send(&mut self, message: &MidiMessage) -> Result<(), SinkError> {
+        let dropped = self.dropped();
+        self.push(message);
+        if self.dropped() == dropped {
+            Ok(())
+        } else {
+            Err(SinkError::Full)
+        }
+    }
+}
+
+/// Messages are scheduled at the start of the current block
+impl<const N: usize> MidiSink for BlockScheduler<N> {
+    fn send(&mut self, message: &MidiMessage) -> Result<(), SinkError> {
+        let dropped = self.dropped();
+        self.schedule_in_block(0, message);
+        if self.dropped() == dropped {
+            Ok(())
+        } else {
+            Err(SinkError::Full)
+        }
+    }
+}
+
+impl<RX, TX, E, const N: usize> MidiSink for SoftThru<RX, TX, N>
+where
+    RX: serial::Read<u8, Error = E>,
+    TX: serial::Write<u8, Error = E>,
+    E: serial::Error,
+{
+    fn send(&mut self, message: &MidiMessage) -> Result<(), SinkError> {
+        Ok(SoftThru::send(self, message)?)
+    }
+}
+
+/// Polling reads a byte and forwards it
+impl<RX, TX, E, const N: usize> MidiSource for SoftThru<RX, TX, N>
+where
+    RX: serial::Read<u8, Error = E>,
+    TX: serial::Write<u8, Error = E>,
+    E: serial::Error,
+{
+    fn poll(&mut self) -> Result<Option<MidiMessage>, SourceError> {
+        match self.read() {
+            Ok(message) => Ok(Some(message)),
+            Err(nb::Error::WouldBlock) => Ok(None),
+            Err(nb::Error::Other(error)) => Err(error.into()),
+        }
+    }
+}
+
+impl<RX, E, const FAMILIES: u16> MidiSource for MidiIn<RX, FAMILIES>
+where
+    RX: serial::Read<u8, Error = E>,
+    E: serial::Error,
+{
+    fn poll(&mut self) -> Result<Option<MidiMessage>, SourceError> {
+        Ok(self.try_read()?)
+    }
+}
+
+impl<RX, E, const N: usize> MidiSource for TapMidiIn<RX, N>
+where
+    RX: serial::Read<u8, Error = E>,
+    E: serial::Error,
+{
+    fn poll(&mut self) -> Result<Option<MidiMessage>, SourceError> {
+        match self.read() {
+            Ok(message) => Ok(Some(message)),
+            Err(nb::Error::WouldBlock) => Ok(None),
+            Err(nb::Error::Other(error)) => Err(error.into()),
+        }
+    }
+}
+
+#[cfg(feature = "io")]
+impl<R: embedded_io::Read> MidiSource for crate::IoMidiIn<R> {
+    /// Blocks until a message is complete, `None` at the end of the stream
+    fn poll(&mut self) -> Result<Option<MidiMessage>, SourceError> {
+        Ok(self.read()?)
+    }
+}
+
+#[cfg(feature = "io")]
+impl<W: embedded_io::Write> MidiSink for crate::IoMidiOut<W> {
+    fn send(&mut self, message: &MidiMessage) -> Result<(), SinkError> {
+        Ok(self.write(message)?)
+    }
+}
+
+/// Source parsing the messages in a byte slice, like a recorded stream in a test
+#[derive(Debug)]
+pub struct SliceMidiIn<'a> {
+    bytes: &'a [u8],
+    receiver: Receiver<{ family::ALL }>,
+}
+
+impl<'a> SliceMidiIn<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        SliceMidiIn {
+            bytes,
+            receiver: Receiver::new(),
+        }
+    }
+
+    /// Bytes not parsed yet
+    pub fn remaining(&self) -> &'a [u8] {
+        self.bytes
+    }
+}
+
+impl MidiSource for SliceMidiIn<'_> {
+    /// Parse up to the next message, `None` at the end of the slice
+    fn poll(&mut self) -> Result<Option<MidiMessage>, SourceError> {
+        while let Some((byte, rest)) = self.bytes.split_first() {
+            self.bytes = rest;
+            if let Some(ParseEvent::Message(message)) = self.receiver.push(*byte) {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Sink keeping up to `N` sent messages, like a test recorder
+///
+/// Messages sent when it is full are rejected with `SinkError::Full`.
+#[derive(Debug)]
+pub struct RecordingMidiOut<const N: usize = 32> {
+    messages: [Option<MidiMessage>; N],
+    len: usize,
+}
+
+impl<const N: usize> Default for RecordingMidiOut<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> RecordingMidiOut<N> {
+    pub fn new() -> Self {
+        RecordingMidiOut {
+            messages: [None; N],
+            len: 0,
+        }
+    }
+
+    /// Recorded messages, oldest first
+    pub fn messages(&self) -> impl Iterator<Item = &MidiMessage> + '_ {
+        self.messages[..self.len].iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.messages = [None; N];
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> MidiSink for RecordingMidiOut<N> {
+    fn send(&mut self, message: &MidiMessage) -> Result<(), SinkError> {
+        let slot = self.messages.get_mut(self.len).ok_or(SinkError::Full)?;
+        *slot = Some(*message);
+        self.len += 1;
+        Ok(())
+    }
+}
+
+/// How a `Tee` handles a child that fails to send
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TeePolicy {
+    /// Stop at the first failing child, the second child doesn't get the message
+    FailFast,
+    /// Send to both children and return the first error
+    #[default]
+    BestEffort,
+}
+
+/// Sink sending every message to two sinks, like a synth and a logger
+///
+/// Each child renders the message itself, so serial outputs keep their own running status. Tees
+/// nest to send to more sinks, `Tee<Tee<A, B>, C>` sends to three.
+#[derive(Debug)]
+pub struct Tee<A, B> {
+    first: A,
+    second: B,
+    policy: TeePolicy,
+}
+
+impl<A: MidiSink, B: MidiSink> Tee<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Tee {
+            first,
+            second,
+            policy: TeePolicy::default(),
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: TeePolicy) {
+        self.policy = policy;
+    }
+
+    pub fn release(self) -> (A, B) {
+        (self.first, self.second)
+    }
+
+    pub fn first(&mut self) -> &mut A {
+        &mut self.first
+    }
+
+    pub fn second(&mut self) -> &mut B {
+        &mut self.second
+    }
+
+    /// Send a message to both children regardless of the policy, returns both results
+    pub fn send_both(
+        &mut self,
+        message: &MidiMessage,
+    ) -> (Result<(), SinkError>, Result<(), SinkError>) {
+        (self.first.send(message), self.second.send(message))
+    }
+}
+
+impl<A: MidiSink, B: MidiSink> MidiSink for Tee<A, B> {
+    fn send(&mut self, message: &MidiMessage) -> Result<(), SinkError> {
+        match self.policy {
+            TeePolicy::FailFast => {
+                self.first.send(message)?;
+                self.second.send(message)
+            }
+            TeePolicy::BestEffort => {
+                let (first, second) = self.send_both(message);
+                first.and(second)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_on};
+    use crate::{MidiRouter, PortId, Routed, WireRate};
+    use embedded_hal_mock::eh1::serial::{Mock, Transaction};
+    use midi_convert::midi_types::Channel;
+    use std::vec::Vec;
+
+    /// Move every message of `source` to `sink`
+    fn pump(source: &mut dyn MidiSource, sink: &mut dyn MidiSink) -> Result<(), SinkError> {
+        while let Some(message) = source.poll().unwrap() {
+            sink.send(&message)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_route_between_trait_objects() {
+        let bytes = [0x90, 60, 100, 0xf8, 0x91, 62, 100, 0xb0, 7, 90, 0xfa];
+        let mut slice_in = SliceMidiIn::new(&bytes);
+        let mut low = RecordingMidiOut::<8>::new();
+        let mut high = RecordingMidiOut::<8>::new();
+
+        let source: &mut dyn MidiSource = &mut slice_in;
+        let outputs: [&mut dyn MidiSink; 2] = [&mut low, &mut high];
+        let mut router = MidiRouter::<_, 2, 1>::new(outputs);
+        router.set_channel_route(PortId(0), Channel::C2, Some(PortId(1)));
+        while let Some(message) = source.poll().unwrap() {
+            router.forward(&Routed::new(PortId(0), message)).unwrap();
+        }
+
+        assert_eq!(
+            low.messages().copied().collect::<Vec<_>>(),
+            [
+                note_on(0, 60, 100),
+                MidiMessage::TimingClock,
+                cc(0, 7, 90),
+                MidiMessage::Start
+            ]
+        );
+        assert_eq!(
+            high.messages().copied().collect::<Vec<_>>(),
+            [note_on(1, 62, 100)]
+        );
+        assert!(slice_in.remaining().is_empty());
+    }
+
+    #[test]
+    fn should_send_to_serial_and_scheduled_outputs() {
+        let bytes = [0x90, 60, 100, 62, 100];
+        let mut midi_out = MidiOut::new(Mock::new(&[Transaction::write_many(bytes)]));
+        pump(&mut SliceMidiIn::new(&bytes), &mut midi_out).unwrap();
+        midi_out.release().done();
+
+        let mut converter = RateConverter::<1>::new(WireRate::Midi31250);
+        assert_eq!(
+            pump(&mut SliceMidiIn::new(&bytes), &mut converter),
+            Err(SinkError::Full)
+        );
+        assert_eq!(converter.pending(), 1);
+
+        let mut scheduler = BlockScheduler::<2>::new(64, 48_000, WireRate::Midi31250);
+        pump(&mut SliceMidiIn::new(&bytes), &mut scheduler).unwrap();
+        assert_eq!(scheduler.pending(), 2);
+    }
+
+    #[test]
+    fn should_poll_serial_input() {
+        let mut midi_in = MidiIn::new(Mock::new(&[
+            Transaction::read_many([0xc0, 5]),
+            Transaction::read_error(nb::Error::WouldBlock),
+        ]));
+        assert_eq!(MidiSource::poll(&mut midi_in), Ok(None));
+        assert_eq!(
+            MidiSource::poll(&mut midi_in),
+            Ok(Some(MidiMessage::ProgramChange(Channel::C1, 5.into())))
+        );
+        assert_eq!(MidiSource::poll(&mut midi_in), Ok(None));
+        midi_in.rx.done();
+    }
+
+    #[test]
+    fn should_reject_messages_when_recording_is_full() {
+        let mut recording = RecordingMidiOut::<1>::new();
+        assert_eq!(recording.send(&MidiMessage::Start), Ok(()));
+        assert_eq!(recording.send(&MidiMessage::Stop), Err(SinkError::Full));
+        assert_eq!(recording.len(), 1);
+        recording.clear();
+        assert!(recording.is_empty());
+    }
+
+    #[test]
+    fn should_send_same_messages_to_both_sinks() {
+        let bytes = [0x90, 60, 100, 62, 100, 0xf8, 0x80, 60, 0];
+        let expected = [0x90, 60, 100, 62, 100, 0xf8, 0x80, 60, 0];
+        let synth = MidiOut::new(Mock::new(&[Transaction::write_many(expected)]));
+        let mut tee = Tee::new(
+            Tee::new(synth, RecordingMidiOut::<8>::new()),
+            RecordingMidiOut::<8>::new(),
+        );
+        pump(&mut SliceMidiIn::new(&bytes), &mut tee).unwrap();
+
+        let (first, logger) = tee.release();
+        let (synth, recording) = first.release();
+        synth.release().done();
+        assert_eq!(recording.len(), 4);
+        assert!(recording.messages().eq(logger.messages()));
+    }
+
+    #[test]
+    fn should_apply_error_policy() {
+        let mut tee = Tee::new(RecordingMidiOut::<1>::new(), RecordingMidiOut::<2>::new());
+        assert_eq!(tee.send(&MidiMessage::Start), Ok(()));
+        assert_eq!(tee.send(&MidiMessage::Stop), Err(SinkError::Full));
+        assert_eq!(tee.second().len(), 2);
+        assert_eq!(
+            tee.send_both(&MidiMessage::Continue),
+            (Err(SinkError::Full), Err(SinkError::Full))
+        );
+
+        tee.second().clear();
+        tee.set_policy(TeePolicy::FailFast);
+        assert_eq!(tee.send(&MidiMessage::Stop), Err(SinkError::Full));
+        assert!(tee.second().is_empty());
+    }
+}
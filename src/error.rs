@@ -0,0 +1,289 @@
+//! Error type for all fallible operations in this crate
+
+use core::fmt::{self, Debug, Display, Formatter};
+use midi_convert::parse::MidiParseError;
+
+/// Reason parsing midi bytes failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// Not enough bytes were available to parse a message
+    BufferTooShort,
+    /// The bytes did not contain a valid message
+    MessageNotFound,
+}
+
+impl From<MidiParseError> for ParseErrorKind {
+    fn from(error: MidiParseError) -> Self {
+        match error {
+            MidiParseError::BufferTooShort => ParseErrorKind::BufferTooShort,
+            MidiParseError::MessageNotFound => ParseErrorKind::MessageNotFound,
+        }
+    }
+}
+
+impl Display for ParseErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::BufferTooShort => f.write_str("not enough bytes to parse a message"),
+            ParseErrorKind::MessageNotFound => f.write_str("no valid message found"),
+        }
+    }
+}
+
+/// Error returned by midi inputs, outputs and processors, `E` is the error type of the serial
+/// port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiError<E> {
+    /// The serial port returned an error
+    Serial(E),
+    /// The serial port lost received bytes because they were not read in time
+    Overrun,
+    /// Received bytes could not be parsed
+    Parse(ParseErrorKind),
+    /// A buffer has no room for more data
+    BufferFull,
+    /// A value does not fit in the range allowed by the midi protocol
+    ValueOutOfRange,
+    /// A system exclusive message is larger than the buffer it is collected in
+    SysexOverflow,
+}
+
+impl<E> From<E> for MidiError<E> {
+    fn from(error: E) -> Self {
+        MidiError::Serial(error)
+    }
+}
+
+impl<E: Debug> Display for MidiError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MidiError::Serial(error) => write!(f, "serial error: {:?}", error),
+            MidiError::Overrun => f.write_str("receive overrun"),
+            MidiError::Parse(kind) => write!(f, "parse error: {}", kind),
+            MidiError::BufferFull => f.write_str("buffer full"),
+            MidiError::ValueOutOfRange => f.write_str("value out of range"),
+            MidiError::SysexOverflow => f.write_str("system exclusive message too large"),
+        }
+    }
+}
+
+impl<E: Debug> core::error::Error for MidiError<E> {}
+
+/// Reason a `MidiSink` failed to send a message
+///
+/// Errors of the underlying transport are reduced to `Transport` so sinks of different types can
+/// be used as trait objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkError {
+    /// The transport returned an error
+    Transport,
+    /// The message was dropped because a queue or buffer is full
+    Full,
+    /// The message can't be sent by this sink
+    Invalid,
+}
+
+impl<E> From<MidiError<E>> for SinkError {
+    fn from(error: MidiError<E>) -> Self {
+        match error {
+            MidiError::BufferFull | MidiError::SysexOverflow => SinkError::Full,
+            MidiError::ValueOutOfRange => SinkError::Invalid,
+            MidiError::Serial(_) | MidiError::Overrun | MidiError::Parse(_) => SinkError::Transport,
+        }
+    }
+}
+
+impl Display for SinkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkError::Transport => f.write_str("transport error"),
+            SinkError::Full => f.write_str("sink full"),
+            SinkError::Invalid => f.write_str("message not supported by sink"),
+        }
+    }
+}
+
+impl core::error::Error for SinkError {}
+
+/// Reason a `MidiSource` failed to receive a message, see `SinkError`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceError {
+    /// The transport returned an error
+    Transport,
+    /// Received bytes were lost because they were not read in time
+    Overrun,
+    /// Received bytes could not be parsed
+    Parse(ParseErrorKind),
+}
+
+impl<E> From<MidiError<E>> for SourceError {
+    fn from(error: MidiError<E>) -> Self {
+        match error {
+            MidiError::Overrun | MidiError::BufferFull | MidiError::SysexOverflow => {
+                SourceError::Overrun
+            }
+            MidiError::Parse(kind) => SourceError::Parse(kind),
+            MidiError::Serial(_) | MidiError::ValueOutOfRange => SourceError::Transport,
+        }
+    }
+}
+
+impl Display for SourceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::Transport => f.write_str("transport error"),
+            SourceError::Overrun => f.write_str("receive overrun"),
+            SourceError::Parse(kind) => write!(f, "parse error: {}", kind),
+        }
+    }
+}
+
+impl core::error::Error for SourceError {}
+
+/// A fixed capacity table has no room for another entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FullTable;
+
+impl Display for FullTable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("table full")
+    }
+}
+
+impl core::error::Error for FullTable {}
+
+/// A buffer is too small for the data written to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooSmall;
+
+impl Display for TooSmall {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("buffer too small")
+    }
+}
+
+impl core::error::Error for TooSmall {}
+
+/// A rendered message does not fit in the rest of a buffer, none of it was written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overflow;
+
+impl Display for Overflow {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("message does not fit in the buffer")
+    }
+}
+
+impl core::error::Error for Overflow {}
+
+/// Reason loading a saved configuration failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The data ends before the configuration does
+    TooShort,
+    /// The data was saved in a format version this version of the crate doesn't read
+    UnsupportedVersion(u8),
+    /// The data contains an out of range value or more entries than fit
+    Invalid,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooShort => f.write_str("configuration data too short"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported configuration version {}", version)
+            }
+            DecodeError::Invalid => f.write_str("invalid configuration data"),
+        }
+    }
+}
+
+impl core::error::Error for DecodeError {}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::string::ToString;
+
+    #[derive(Debug, PartialEq)]
+    struct Overrun;
+
+    #[test]
+    fn should_display_errors() {
+        assert_eq!(
+            MidiError::Serial(Overrun).to_string(),
+            "serial error: Overrun"
+        );
+        assert_eq!(
+            MidiError::<Overrun>::Parse(ParseErrorKind::BufferTooShort).to_string(),
+            "parse error: not enough bytes to parse a message"
+        );
+        assert_eq!(MidiError::<Overrun>::Overrun.to_string(), "receive overrun");
+        assert_eq!(MidiError::<Overrun>::BufferFull.to_string(), "buffer full");
+        assert_eq!(
+            MidiError::<Overrun>::ValueOutOfRange.to_string(),
+            "value out of range"
+        );
+        assert_eq!(
+            MidiError::<Overrun>::SysexOverflow.to_string(),
+            "system exclusive message too large"
+        );
+        assert_eq!(FullTable.to_string(), "table full");
+        assert_eq!(TooSmall.to_string(), "buffer too small");
+        assert_eq!(Overflow.to_string(), "message does not fit in the buffer");
+        assert_eq!(SinkError::Full.to_string(), "sink full");
+        assert_eq!(
+            SourceError::Parse(ParseErrorKind::MessageNotFound).to_string(),
+            "parse error: no valid message found"
+        );
+        assert_eq!(
+            DecodeError::UnsupportedVersion(2).to_string(),
+            "unsupported configuration version 2"
+        );
+    }
+
+    #[test]
+    fn should_convert_serial_errors() {
+        fn fails() -> Result<(), MidiError<Overrun>> {
+            Err(Overrun)?
+        }
+
+        assert_eq!(fails(), Err(MidiError::Serial(Overrun)));
+    }
+
+    #[test]
+    fn should_reduce_midi_errors_for_endpoints() {
+        assert_eq!(
+            SinkError::from(MidiError::Serial(Overrun)),
+            SinkError::Transport
+        );
+        assert_eq!(
+            SinkError::from(MidiError::<Overrun>::BufferFull),
+            SinkError::Full
+        );
+        assert_eq!(
+            SourceError::from(MidiError::<Overrun>::Overrun),
+            SourceError::Overrun
+        );
+        assert_eq!(
+            SourceError::from(MidiError::Serial(Overrun)),
+            SourceError::Transport
+        );
+    }
+
+    #[test]
+    fn should_convert_parse_errors() {
+        assert_eq!(
+            ParseErrorKind::from(MidiParseError::MessageNotFound),
+            ParseErrorKind::MessageNotFound
+        );
+    }
+
+    #[test]
+    fn should_implement_error() {
+        let error: &dyn core::error::Error = &MidiError::Serial(Overrun);
+        assert!(error.source().is_none());
+    }
+}
@@ -0,0 +1,60 @@
+//! Message families for configuring which messages a `MidiIn` parses
+//!
+//! Families are bits of a mask passed as the `FAMILIES` parameter of `MidiIn`. Bytes of excluded
+//! families are skipped before they reach the parser, so they never desync the stream.
+//!
+//! ```
+//! use embedded_hal_nb::serial;
+//! use embedded_midi::{family, MidiIn};
+//!
+//! const BOOTLOADER: u16 = family::PROGRAM_CHANGE | family::SYSTEM_COMMON;
+//!
+//! fn bootloader_input<RX: serial::Read<u8>>(rx: RX) -> MidiIn<RX, BOOTLOADER> {
+//!     MidiIn::with_families(rx)
+//! }
+//! ```
+
+pub const NOTE_OFF: u16 = 1 << 0;
+pub const NOTE_ON: u16 = 1 << 1;
+pub const KEY_PRESSURE: u16 = 1 << 2;
+pub const CONTROL_CHANGE: u16 = 1 << 3;
+pub const PROGRAM_CHANGE: u16 = 1 << 4;
+pub const CHANNEL_PRESSURE: u16 = 1 << 5;
+pub const PITCH_BEND: u16 = 1 << 6;
+/// System common messages, including system exclusive
+pub const SYSTEM_COMMON: u16 = 1 << 7;
+pub const REALTIME: u16 = 1 << 8;
+
+/// Note on and note off
+pub const NOTES: u16 = NOTE_OFF | NOTE_ON;
+pub const CHANNEL_VOICE: u16 =
+    NOTES | KEY_PRESSURE | CONTROL_CHANGE | PROGRAM_CHANGE | CHANNEL_PRESSURE | PITCH_BEND;
+pub const ALL: u16 = CHANNEL_VOICE | SYSTEM_COMMON | REALTIME;
+
+/// Family of a status byte
+pub(crate) const fn of(status: u8) -> u16 {
+    match status {
+        0xf8..=0xff => REALTIME,
+        0xf0..=0xf7 => SYSTEM_COMMON,
+        _ => 1 << ((status >> 4) & 0x07),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_find_family_of_status_bytes() {
+        assert_eq!(of(0x80), NOTE_OFF);
+        assert_eq!(of(0x9f), NOTE_ON);
+        assert_eq!(of(0xa0), KEY_PRESSURE);
+        assert_eq!(of(0xb0), CONTROL_CHANGE);
+        assert_eq!(of(0xc0), PROGRAM_CHANGE);
+        assert_eq!(of(0xd0), CHANNEL_PRESSURE);
+        assert_eq!(of(0xef), PITCH_BEND);
+        assert_eq!(of(0xf0), SYSTEM_COMMON);
+        assert_eq!(of(0xf7), SYSTEM_COMMON);
+        assert_eq!(of(0xf8), REALTIME);
+    }
+}
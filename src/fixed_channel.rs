@@ -0,0 +1,118 @@
+//! Output wrapper that forces all channel voice messages onto a single channel
+
+use crate::{message::with_channel, MidiError, MidiOut};
+use core::fmt::Debug;
+use embedded_hal_nb::serial;
+use midi_convert::midi_types::{Channel, MidiMessage};
+
+/// Wraps a `MidiOut` and rewrites the channel of every channel voice message before rendering
+///
+/// System messages are passed on untouched. Because all channel voice messages end up on the same
+/// channel this also lets running status elide more status bytes.
+#[derive(Debug)]
+pub struct FixedChannelOut<TX> {
+    out: MidiOut<TX>,
+    channel: Option<Channel>,
+}
+
+impl<TX, E> FixedChannelOut<TX>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+{
+    pub fn new(out: MidiOut<TX>, channel: Channel) -> Self {
+        FixedChannelOut {
+            out,
+            channel: Some(channel),
+        }
+    }
+
+    /// Release the wrapped `MidiOut`
+    pub fn release(self) -> MidiOut<TX> {
+        self.out
+    }
+
+    /// The channel messages are forced onto, `None` when messages are passed through unchanged
+    pub fn channel(&self) -> Option<Channel> {
+        self.channel
+    }
+
+    /// Retarget the output to another channel, `None` passes messages through unchanged
+    pub fn set_channel(&mut self, channel: Option<Channel>) {
+        self.channel = channel;
+    }
+
+    pub fn write(&mut self, message: &MidiMessage) -> Result<(), MidiError<E>> {
+        self.write_counted(message).map(|_| ())
+    }
+
+    /// Like `write`, returns the number of bytes written, see `MidiOut::write_counted`
+    pub fn write_counted(&mut self, message: &MidiMessage) -> Result<usize, MidiError<E>> {
+        match self.channel {
+            Some(channel) => self.out.write_counted(&with_channel(*message, channel)),
+            None => self.out.write_counted(message),
+        }
+    }
+}
+
+impl<TX, E> MidiOut<TX>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+{
+    /// Wrap this output so all channel voice messages are sent on `channel`
+    pub fn with_forced_channel(self, channel: Channel) -> FixedChannelOut<TX> {
+        FixedChannelOut::new(self, channel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock_writes;
+
+    fn alternating_notes() -> [MidiMessage; 4] {
+        [
+            MidiMessage::NoteOn(0x01.into(), 0x40.into(), 0x7f.into()),
+            MidiMessage::NoteOn(0x02.into(), 0x41.into(), 0x7f.into()),
+            MidiMessage::NoteOn(0x01.into(), 0x42.into(), 0x7f.into()),
+            MidiMessage::NoteOn(0x02.into(), 0x43.into(), 0x7f.into()),
+        ]
+    }
+
+    #[test]
+    fn should_force_channel_and_save_status_bytes() {
+        let mut out = MidiOut::new(mock_writes(&[
+            0x90, 0x40, 0x7f, 0x41, 0x7f, 0x42, 0x7f, 0x43, 0x7f,
+        ]))
+        .with_forced_channel(Channel::C1);
+        for message in alternating_notes().iter() {
+            out.write(message).unwrap();
+        }
+        out.release().release().done();
+    }
+
+    #[test]
+    fn should_not_force_channel_when_disabled() {
+        let mut out = MidiOut::new(mock_writes(&[
+            0x91, 0x40, 0x7f, 0x92, 0x41, 0x7f, 0x91, 0x42, 0x7f, 0x92, 0x43, 0x7f,
+        ]))
+        .with_forced_channel(Channel::C1);
+        out.set_channel(None);
+        for message in alternating_notes().iter() {
+            out.write(message).unwrap();
+        }
+        out.release().release().done();
+    }
+
+    #[test]
+    fn should_pass_system_messages_untouched() {
+        let mut out = MidiOut::new(mock_writes(&[0x95, 0x40, 0x7f, 0xf8, 0xf3, 0x05]))
+            .with_forced_channel(Channel::C6);
+        out.write(&MidiMessage::NoteOn(0x0c.into(), 0x40.into(), 0x7f.into()))
+            .unwrap();
+        out.write(&MidiMessage::TimingClock).unwrap();
+        out.write(&MidiMessage::SongSelect(0x05.into())).unwrap();
+        out.release().release().done();
+    }
+}
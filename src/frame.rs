@@ -0,0 +1,358 @@
+//! Packing messages into fixed size frames for lossy links like radios
+//!
+//! A frame of `N` bytes starts with a sequence number, followed by complete messages without
+//! running status and padded with zeroes, and ends with a CRC-8 of all bytes before it. Every
+//! frame can be decoded on its own, lost frames are detected from gaps in the sequence numbers.
+
+use crate::message::{from_bytes, to_bytes};
+use core::fmt::{self, Display, Formatter};
+use midi_convert::midi_types::MidiMessage;
+
+/// Sequence number and checksum bytes in every frame
+const OVERHEAD: usize = 2;
+
+/// CRC-8 with polynomial 0x07 and initial value 0
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Packs messages into frames of `N` bytes, `N` must be at least 5 to fit every message
+#[derive(Debug, Clone)]
+pub struct FrameEncoder<const N: usize> {
+    frame: [u8; N],
+    len: usize,
+    sequence: u8,
+}
+
+impl<const N: usize> Default for FrameEncoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FrameEncoder<N> {
+    const FITS_MESSAGE: () = assert!(N >= OVERHEAD + 3, "frames must fit a 3 byte message");
+
+    pub fn new() -> Self {
+        let () = Self::FITS_MESSAGE;
+        FrameEncoder {
+            frame: [0; N],
+            len: 1,
+            sequence: 0,
+        }
+    }
+
+    /// Add a message to the current frame
+    ///
+    /// When the message doesn't fit, the current frame is returned and the message starts the
+    /// next frame.
+    pub fn push(&mut self, message: &MidiMessage) -> Option<[u8; N]> {
+        let (bytes, len) = to_bytes(message);
+        let len = len as usize;
+        let full = if self.len + len > N - 1 {
+            self.flush()
+        } else {
+            None
+        };
+        self.frame[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+        full
+    }
+
+    /// The current frame, `None` if it has no messages
+    pub fn flush(&mut self) -> Option<[u8; N]> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut frame = self.frame;
+        frame[0] = self.sequence;
+        frame[self.len..].fill(0);
+        frame[N - 1] = crc8(&frame[..N - 1]);
+
+        self.sequence = self.sequence.wrapping_add(1);
+        self.len = 1;
+        Some(frame)
+    }
+
+    /// The current frame has no messages
+    pub fn is_empty(&self) -> bool {
+        self.len == 1
+    }
+}
+
+/// Result of decoding a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameStatus {
+    /// The frame followed the previous frame
+    Received,
+    /// This many frames between the previous frame and this one were lost
+    FramesLost(u8),
+}
+
+/// Reason a frame was rejected, its messages are dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The checksum doesn't match, the frame was damaged
+    Checksum,
+    /// The frame was already received
+    Duplicate,
+    /// The frame arrived after a later frame
+    OutOfOrder,
+    /// The frame has the wrong size or doesn't contain valid messages
+    Malformed,
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Checksum => f.write_str("frame checksum mismatch"),
+            FrameError::Duplicate => f.write_str("duplicate frame"),
+            FrameError::OutOfOrder => f.write_str("frame out of order"),
+            FrameError::Malformed => f.write_str("malformed frame"),
+        }
+    }
+}
+
+impl core::error::Error for FrameError {}
+
+/// Unpacks messages from frames of `N` bytes made by a `FrameEncoder`
+#[derive(Debug, Clone, Default)]
+pub struct FrameDecoder<const N: usize> {
+    last_sequence: Option<u8>,
+}
+
+impl<const N: usize> FrameDecoder<N> {
+    pub fn new() -> Self {
+        FrameDecoder {
+            last_sequence: None,
+        }
+    }
+
+    /// Check a received frame and call `emit` with each of its messages
+    ///
+    /// Frames up to 128 sequence numbers ahead of the previous frame count as up to 127 lost
+    /// frames, frames further ahead are treated as arriving late.
+    pub fn decode(
+        &mut self,
+        frame: &[u8],
+        emit: &mut dyn FnMut(MidiMessage),
+    ) -> Result<FrameStatus, FrameError> {
+        if frame.len() != N || N < OVERHEAD {
+            return Err(FrameError::Malformed);
+        }
+        if crc8(&frame[..N - 1]) != frame[N - 1] {
+            return Err(FrameError::Checksum);
+        }
+
+        let sequence = frame[0];
+        let status = match self.last_sequence {
+            None => FrameStatus::Received,
+            Some(last) => match sequence.wrapping_sub(last) {
+                0 => return Err(FrameError::Duplicate),
+                1 => FrameStatus::Received,
+                ahead @ 2..=128 => FrameStatus::FramesLost(ahead - 1),
+                _ => return Err(FrameError::OutOfOrder),
+            },
+        };
+
+        let payload = &frame[1..N - 1];
+        Self::messages(payload, &mut |_| {})?;
+        Self::messages(payload, emit)?;
+        self.last_sequence = Some(sequence);
+        Ok(status)
+    }
+
+    /// Forget the previous frame, the next frame is accepted with any sequence number
+    pub fn reset(&mut self) {
+        self.last_sequence = None;
+    }
+
+    fn messages(mut payload: &[u8], emit: &mut dyn FnMut(MidiMessage)) -> Result<(), FrameError> {
+        while let Some(&first) = payload.first() {
+            if first == 0 {
+                // Padding fills the rest of the frame
+                if payload.iter().any(|byte| *byte != 0) {
+                    return Err(FrameError::Malformed);
+                }
+                return Ok(());
+            }
+            let (message, len) = from_bytes(payload).map_err(|_| FrameError::Malformed)?;
+            emit(message);
+            payload = &payload[len..];
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_off, note_on};
+    use std::vec::Vec;
+
+    fn encode_all<const N: usize>(messages: &[MidiMessage]) -> Vec<[u8; N]> {
+        let mut encoder = FrameEncoder::<N>::new();
+        let mut frames: Vec<_> = messages
+            .iter()
+            .filter_map(|message| encoder.push(message))
+            .collect();
+        frames.extend(encoder.flush());
+        frames
+    }
+
+    fn decode(
+        decoder: &mut FrameDecoder<8>,
+        frame: &[u8],
+    ) -> (Result<FrameStatus, FrameError>, Vec<MidiMessage>) {
+        let mut messages = Vec::new();
+        let result = decoder.decode(frame, &mut |message| messages.push(message));
+        (result, messages)
+    }
+
+    #[test]
+    fn should_compute_crc8() {
+        assert_eq!(crc8(b"123456789"), 0xf4);
+    }
+
+    #[test]
+    fn should_pack_complete_messages_without_running_status() {
+        let frames = encode_all::<8>(&[
+            note_on(0, 60, 100),
+            note_on(0, 62, 100),
+            MidiMessage::TimingClock,
+            note_off(0, 60, 0),
+        ]);
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0][..7], [0, 0x90, 60, 100, 0x90, 62, 100]);
+        assert_eq!(frames[1][..7], [1, 0xf8, 0x80, 60, 0, 0, 0]);
+
+        let mut decoder = FrameDecoder::<8>::new();
+        assert_eq!(
+            decode(&mut decoder, &frames[1]),
+            (
+                Ok(FrameStatus::Received),
+                [MidiMessage::TimingClock, note_off(0, 60, 0)].to_vec()
+            )
+        );
+    }
+
+    #[test]
+    fn should_report_lost_frames() {
+        let messages: Vec<_> = (0..5).map(|value| cc(0, 7, value)).collect();
+        let frames = encode_all::<8>(&messages);
+        let mut decoder = FrameDecoder::<8>::new();
+
+        assert_eq!(frames.len(), 3);
+        assert_eq!(
+            decode(&mut decoder, &frames[0]).0,
+            Ok(FrameStatus::Received)
+        );
+        assert_eq!(
+            decode(&mut decoder, &frames[2]),
+            (Ok(FrameStatus::FramesLost(1)), [cc(0, 7, 4)].to_vec())
+        );
+    }
+
+    #[test]
+    fn should_count_frames_up_to_128_ahead_as_lost() {
+        let messages: Vec<_> = (0..260u32)
+            .map(|value| cc(0, 7, value as u8 & 0x7f))
+            .collect();
+        let frames = encode_all::<8>(&messages);
+
+        let mut decoder = FrameDecoder::<8>::new();
+        assert_eq!(
+            decode(&mut decoder, &frames[0]).0,
+            Ok(FrameStatus::Received)
+        );
+        assert_eq!(
+            decode(&mut decoder, &frames[128]).0,
+            Ok(FrameStatus::FramesLost(127))
+        );
+
+        let mut decoder = FrameDecoder::<8>::new();
+        assert_eq!(
+            decode(&mut decoder, &frames[0]).0,
+            Ok(FrameStatus::Received)
+        );
+        assert_eq!(
+            decode(&mut decoder, &frames[129]),
+            (Err(FrameError::OutOfOrder), Vec::new())
+        );
+    }
+
+    #[test]
+    fn should_drop_duplicate_and_reordered_frames() {
+        let messages: Vec<_> = (0..6).map(|value| cc(0, 7, value)).collect();
+        let frames = encode_all::<8>(&messages);
+        let mut decoder = FrameDecoder::<8>::new();
+
+        assert_eq!(
+            decode(&mut decoder, &frames[0]).0,
+            Ok(FrameStatus::Received)
+        );
+        assert_eq!(
+            decode(&mut decoder, &frames[0]),
+            (Err(FrameError::Duplicate), Vec::new())
+        );
+        assert_eq!(
+            decode(&mut decoder, &frames[2]).0,
+            Ok(FrameStatus::FramesLost(1))
+        );
+        assert_eq!(
+            decode(&mut decoder, &frames[1]),
+            (Err(FrameError::OutOfOrder), Vec::new())
+        );
+    }
+
+    #[test]
+    fn should_follow_wrapping_sequence_numbers() {
+        let messages: Vec<_> = (0..600u32)
+            .map(|value| cc(0, 7, value as u8 & 0x7f))
+            .collect();
+        let frames = encode_all::<8>(&messages);
+        let mut decoder = FrameDecoder::<8>::new();
+
+        for frame in frames.iter() {
+            assert_eq!(decode(&mut decoder, frame).0, Ok(FrameStatus::Received));
+        }
+    }
+
+    #[test]
+    fn should_reject_damaged_frames() {
+        let mut frame = encode_all::<8>(&[note_on(0, 60, 100)])[0];
+        let mut decoder = FrameDecoder::<8>::new();
+
+        frame[2] ^= 0x01;
+        assert_eq!(
+            decode(&mut decoder, &frame),
+            (Err(FrameError::Checksum), Vec::new())
+        );
+        assert_eq!(
+            decode(&mut decoder, &frame[..7]).0,
+            Err(FrameError::Malformed)
+        );
+    }
+
+    #[test]
+    fn should_reject_frames_with_invalid_messages() {
+        let mut frame = [0, 0x90, 60, 100, 0x90, 62, 0];
+        frame[6] = crc8(&frame[..6]);
+        let mut decoder = FrameDecoder::<7>::new();
+
+        assert_eq!(
+            decoder.decode(&frame, &mut |_| panic!("no messages expected")),
+            Err(FrameError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,393 @@
+//! Firmware updates sent as system exclusive messages, enabled with the `fwup` feature
+//!
+//! The packets are laid out like the configuration dump packets described at
+//! [`ConfigSysex`](crate::ConfigSysex), with the commands `BEGIN`, `DATA`, `END`, `ACK` and `NAK`.
+//!
+//! A transfer starts with `BEGIN` at index 0, followed by the image in `DATA` packets numbered
+//! from 0 and `END` with the number of data packets as index. The index wraps around after
+//! 16383. The receiver answers every packet with `ACK` and its index, or with `NAK` and the index
+//! of the data packet it expects when a packet is corrupted or out of sequence. The sender sends
+//! the next packet after an `ACK`, goes back to the requested packet after a `NAK` and repeats
+//! the packet when no answer arrives in time.
+
+use crate::{
+    packet::{send_packet, PacketDecoder, Received, INDEX_MASK},
+    MidiError, MidiOut, SysexHandler,
+};
+use core::fmt::Debug;
+use embedded_hal_nb::serial;
+
+/// Start of a transfer
+pub const BEGIN: u8 = 0x01;
+/// A chunk of the image
+pub const DATA: u8 = 0x02;
+/// End of a transfer
+pub const END: u8 = 0x03;
+/// The packet with the index was received
+pub const ACK: u8 = 0x04;
+/// The packet was rejected, the index is the data packet expected next
+pub const NAK: u8 = 0x05;
+
+/// What `FwupReceiver::process` did with a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FwupEvent {
+    /// A transfer started
+    Started,
+    /// A chunk of `len` bytes was written at `offset`
+    Written { offset: u32, len: usize },
+    /// A packet was rejected and the data packet `expected` requested
+    Rejected { expected: u16 },
+    /// The transfer of an image of `len` bytes completed
+    Complete { len: u32 },
+}
+
+/// Receives a firmware image in data packets of up to `CHUNK` bytes
+///
+/// Feed the system exclusive messages to the receiver, for example through a `SysexStream`, and
+/// call `process` after every message to write the received chunk and answer the sender.
+#[derive(Debug)]
+pub struct FwupReceiver<const CHUNK: usize = 64> {
+    decoder: PacketDecoder<CHUNK>,
+    /// Index of the next data packet
+    next: u16,
+    offset: u32,
+}
+
+impl<const CHUNK: usize> FwupReceiver<CHUNK> {
+    /// Receive packets with the `manufacturer` id
+    pub fn new(manufacturer: &'static [u8]) -> Self {
+        FwupReceiver {
+            decoder: PacketDecoder::new(manufacturer),
+            next: 0,
+            offset: 0,
+        }
+    }
+
+    /// Number of image bytes received
+    pub fn received(&self) -> u32 {
+        self.offset
+    }
+
+    /// Handle the last received packet, calling `write` with the offset and bytes of a new chunk
+    /// and answering on `out`
+    ///
+    /// A repeated data packet, sent again because its `ACK` got lost, is acknowledged without
+    /// writing it again.
+    pub fn process<TX, E>(
+        &mut self,
+        out: &mut MidiOut<TX>,
+        write: &mut dyn FnMut(u32, &[u8]),
+    ) -> Result<Option<FwupEvent>, MidiError<E>>
+    where
+        TX: serial::Write<u8, Error = E>,
+        E: Debug,
+    {
+        let packet = match self.decoder.take() {
+            Some(Received::Valid(packet)) => packet,
+            Some(Received::Corrupt) => return self.reject(out).map(Some),
+            None => return Ok(None),
+        };
+        let manufacturer = self.decoder.manufacturer;
+        let previous = self.next.wrapping_sub(1) & INDEX_MASK;
+        let event = match packet.command {
+            BEGIN => {
+                self.next = 0;
+                self.offset = 0;
+                Some(FwupEvent::Started)
+            }
+            DATA if packet.index == self.next => {
+                let payload = self.decoder.payload();
+                let offset = self.offset;
+                write(offset, payload);
+                self.offset += payload.len() as u32;
+                self.next = (self.next + 1) & INDEX_MASK;
+                Some(FwupEvent::Written {
+                    offset,
+                    len: payload.len(),
+                })
+            }
+            DATA if packet.index == previous && self.offset > 0 => None,
+            END if packet.index == self.next => Some(FwupEvent::Complete { len: self.offset }),
+            DATA | END => return self.reject(out).map(Some),
+            _ => return Ok(None),
+        };
+        send_packet(out, manufacturer, ACK, packet.index, &[])?;
+        Ok(event)
+    }
+
+    fn reject<TX, E>(&mut self, out: &mut MidiOut<TX>) -> Result<FwupEvent, MidiError<E>>
+    where
+        TX: serial::Write<u8, Error = E>,
+        E: Debug,
+    {
+        send_packet(out, self.decoder.manufacturer, NAK, self.next, &[])?;
+        Ok(FwupEvent::Rejected {
+            expected: self.next,
+        })
+    }
+}
+
+impl<const CHUNK: usize> SysexHandler for FwupReceiver<CHUNK> {
+    fn on_sysex_chunk(&mut self, chunk: &[u8], first: bool, last: bool) {
+        self.decoder.on_sysex_chunk(chunk, first, last);
+    }
+
+    fn on_sysex_abort(&mut self) {
+        self.decoder.on_sysex_abort();
+    }
+}
+
+/// Answer of the receiver handled by `FwupSender::process`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FwupReply {
+    Ack(u16),
+    Nak(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Begin,
+    Data(usize),
+    End,
+    Done,
+}
+
+/// Sends a firmware image in data packets of `CHUNK` bytes, `CHUNK` must not be 0, which is
+/// checked at compile time
+///
+/// `send` sends the current packet, call it again to repeat the packet when no answer arrives in
+/// time. Feed the answers of the receiver to the sender and call `process` after every message
+/// to move on to the next packet.
+#[derive(Debug)]
+pub struct FwupSender<'a, const CHUNK: usize = 64> {
+    image: &'a [u8],
+    step: Step,
+    decoder: PacketDecoder<0>,
+}
+
+impl<'a, const CHUNK: usize> FwupSender<'a, CHUNK> {
+    const HAS_CHUNK: () = assert!(CHUNK > 0, "data packets must carry at least one byte");
+
+    /// Send `image` in packets with the `manufacturer` id
+    pub fn new(manufacturer: &'static [u8], image: &'a [u8]) -> Self {
+        let () = Self::HAS_CHUNK;
+        FwupSender {
+            image,
+            step: Step::Begin,
+            decoder: PacketDecoder::new(manufacturer),
+        }
+    }
+
+    /// Number of data packets of the image
+    pub fn packets(&self) -> usize {
+        self.image.len().div_ceil(CHUNK)
+    }
+
+    /// The receiver acknowledged the end of the transfer
+    pub fn is_done(&self) -> bool {
+        self.step == Step::Done
+    }
+
+    /// Send the current packet, nothing once the transfer is done
+    pub fn send<TX, E>(&mut self, out: &mut MidiOut<TX>) -> Result<(), MidiError<E>>
+    where
+        TX: serial::Write<u8, Error = E>,
+        E: Debug,
+    {
+        let manufacturer = self.decoder.manufacturer;
+        match self.step {
+            Step::Begin => send_packet(out, manufacturer, BEGIN, 0, &[]),
+            Step::Data(packet) => {
+                let start = packet * CHUNK;
+                let end = self.image.len().min(start + CHUNK);
+                let payload = &self.image[start..end];
+                send_packet(out, manufacturer, DATA, packet as u16, payload)
+            }
+            Step::End => send_packet(out, manufacturer, END, self.packets() as u16, &[]),
+            Step::Done => Ok(()),
+        }
+    }
+
+    /// Handle the last received answer
+    pub fn process(&mut self) -> Option<FwupReply> {
+        let packet = match self.decoder.take() {
+            Some(Received::Valid(packet)) => packet,
+            _ => return None,
+        };
+        let packets = self.packets();
+        let current = match self.step {
+            Step::Begin => 0,
+            Step::Data(packet) => packet,
+            Step::End => packets,
+            Step::Done => return None,
+        };
+        match packet.command {
+            ACK if packet.index == current as u16 & INDEX_MASK => {
+                self.step = match self.step {
+                    Step::Begin if packets > 0 => Step::Data(0),
+                    Step::Data(packet) if packet + 1 < packets => Step::Data(packet + 1),
+                    Step::Begin | Step::Data(_) => Step::End,
+                    Step::End | Step::Done => Step::Done,
+                };
+                Some(FwupReply::Ack(packet.index))
+            }
+            ACK => Some(FwupReply::Ack(packet.index)),
+            NAK => {
+                // Go back to the latest packet with the requested index
+                let back = (current as u16).wrapping_sub(packet.index) & INDEX_MASK;
+                if self.step != Step::Begin && usize::from(back) <= current {
+                    self.step = match current - usize::from(back) {
+                        packet if packet < packets => Step::Data(packet),
+                        _ => Step::End,
+                    };
+                }
+                Some(FwupReply::Nak(packet.index))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<const CHUNK: usize> SysexHandler for FwupSender<'_, CHUNK> {
+    fn on_sysex_chunk(&mut self, chunk: &[u8], first: bool, last: bool) {
+        self.decoder.on_sysex_chunk(chunk, first, last);
+    }
+
+    fn on_sysex_abort(&mut self) {
+        self.decoder.on_sysex_abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::test_util::Wire;
+    use crate::SysexStream;
+    use std::vec::Vec;
+
+    /// Non-commercial manufacturer id
+    const MANUFACTURER: &[u8] = &[0x7d];
+
+    fn take_bytes(out: &mut MidiOut<Wire>) -> Vec<u8> {
+        out.tx.0.drain(..).collect()
+    }
+
+    fn deliver(bytes: &[u8], stream: &mut SysexStream<16>, handler: &mut impl SysexHandler) {
+        for byte in bytes {
+            stream.feed(0, *byte, handler);
+        }
+    }
+
+    #[test]
+    fn should_encode_packets() {
+        let mut out = MidiOut::new(Wire::default());
+        send_packet(&mut out, MANUFACTURER, DATA, 130, &[0x80, 0x01]).unwrap();
+
+        assert_eq!(
+            take_bytes(&mut out),
+            [0xf0, 0x7d, 0x02, 0x02, 0x01, 0x01, 0x00, 0x01, 0x79, 0xf7]
+        );
+    }
+
+    #[test]
+    fn should_transfer_image_with_retries() {
+        let image: Vec<u8> = (0..1000).map(|i| (i * 31 % 256) as u8).collect();
+        let mut sender = FwupSender::<'_, 64>::new(MANUFACTURER, &image);
+        let mut receiver = FwupReceiver::<64>::new(MANUFACTURER);
+        let mut host_out = MidiOut::new(Wire::default());
+        let mut device_out = MidiOut::new(Wire::default());
+        let mut host_stream = SysexStream::new();
+        let mut device_stream = SysexStream::new();
+        let mut flash = std::vec![0xff; 1024];
+        let mut events = Vec::new();
+        let mut replies = Vec::new();
+
+        let mut sends = 0;
+        while !sender.is_done() && sends < 100 {
+            sender.send(&mut host_out).unwrap();
+            sends += 1;
+            let mut packet = take_bytes(&mut host_out);
+            if sends == 4 {
+                // Corrupt the payload of data packet 2
+                packet[20] ^= 0x01;
+            }
+            deliver(&packet, &mut device_stream, &mut receiver);
+            let mut write = |offset: u32, chunk: &[u8]| {
+                flash[offset as usize..][..chunk.len()].copy_from_slice(chunk)
+            };
+            events.extend(receiver.process(&mut device_out, &mut write).unwrap());
+
+            let reply = take_bytes(&mut device_out);
+            if sends == 7 {
+                // The ACK of data packet 4 gets lost, the sender repeats it
+                continue;
+            }
+            deliver(&reply, &mut host_stream, &mut sender);
+            replies.extend(sender.process());
+        }
+
+        assert_eq!(sender.packets(), 16);
+        assert_eq!(sends, 1 + 16 + 1 + 1 + 1);
+        assert_eq!(&flash[..1000], &image[..]);
+        assert_eq!(receiver.received(), 1000);
+        assert_eq!(events[0], FwupEvent::Started);
+        assert_eq!(events[3], FwupEvent::Rejected { expected: 2 });
+        assert_eq!(
+            events[4],
+            FwupEvent::Written {
+                offset: 128,
+                len: 64
+            }
+        );
+        assert_eq!(
+            events
+                .iter()
+                .filter(|event| matches!(event, FwupEvent::Written { .. }))
+                .count(),
+            16
+        );
+        assert_eq!(events.last(), Some(&FwupEvent::Complete { len: 1000 }));
+        assert_eq!(replies[3], FwupReply::Nak(2));
+        assert_eq!(replies.last(), Some(&FwupReply::Ack(16)));
+    }
+
+    #[test]
+    fn should_reject_out_of_sequence_packets() {
+        let image = [0x55; 10];
+        let mut sender = FwupSender::<'_, 4>::new(MANUFACTURER, &image);
+        let mut receiver = FwupReceiver::<4>::new(MANUFACTURER);
+        let mut host_out = MidiOut::new(Wire::default());
+        let mut device_out = MidiOut::new(Wire::default());
+        let mut stream = SysexStream::new();
+
+        // Data packet 1 without data packet 0
+        sender.step = Step::Data(1);
+        sender.send(&mut host_out).unwrap();
+        deliver(&take_bytes(&mut host_out), &mut stream, &mut receiver);
+        assert_eq!(
+            receiver.process(&mut device_out, &mut |_, _| panic!()),
+            Ok(Some(FwupEvent::Rejected { expected: 0 }))
+        );
+
+        deliver(&take_bytes(&mut device_out), &mut stream, &mut sender);
+        assert_eq!(sender.process(), Some(FwupReply::Nak(0)));
+        assert_eq!(sender.step, Step::Data(0));
+    }
+
+    #[test]
+    fn should_ignore_other_manufacturers() {
+        let mut receiver = FwupReceiver::<4>::new(&[0x00, 0x21, 0x7f]);
+        let mut device_out = MidiOut::new(Wire::default());
+        let mut stream = SysexStream::new();
+        let mut host_out = MidiOut::new(Wire::default());
+
+        send_packet(&mut host_out, &[0x00, 0x21, 0x7e], BEGIN, 0, &[]).unwrap();
+        send_packet(&mut host_out, MANUFACTURER, BEGIN, 0, &[]).unwrap();
+        host_out.write_sysex(&[0x00, 0x21]).unwrap();
+        deliver(&take_bytes(&mut host_out), &mut stream, &mut receiver);
+
+        assert_eq!(receiver.process(&mut device_out, &mut |_, _| {}), Ok(None));
+        assert!(take_bytes(&mut device_out).is_empty());
+    }
+}
@@ -0,0 +1,283 @@
+//! Harmonizer processor adding notes at fixed intervals to every played note
+
+use crate::MidiProcessor;
+use midi_convert::midi_types::{Channel, MidiMessage, Note, Value7};
+
+#[derive(Debug, Clone, Copy)]
+struct Interval {
+    semitones: i8,
+    enabled: bool,
+    /// Velocity of the added note in percent of the played note's velocity
+    velocity_percent: u8,
+}
+
+/// Notes added for a note that is still held
+#[derive(Debug, Clone, Copy)]
+struct Held<const VOICES: usize> {
+    channel: Channel,
+    note: Note,
+    added: [Option<Note>; VOICES],
+}
+
+/// Adds a note at each of `VOICES` intervals to every note on, and releases them again with the
+/// played note
+///
+/// Added notes that fall outside the midi note range are dropped. The notes added for each held
+/// note are remembered, so changing intervals while notes are held never leaves notes hanging. At
+/// most `HELD` notes are harmonized at the same time, notes played beyond that are passed on
+/// without harmony.
+#[derive(Debug, Clone)]
+pub struct Harmonizer<const VOICES: usize, const HELD: usize = 16> {
+    intervals: [Interval; VOICES],
+    held: [Option<Held<VOICES>>; HELD],
+}
+
+impl<const VOICES: usize, const HELD: usize> Harmonizer<VOICES, HELD> {
+    /// Create a harmonizer adding notes `semitones` away from the played note, all intervals
+    /// start enabled at full velocity
+    pub fn new(semitones: [i8; VOICES]) -> Self {
+        let mut intervals = [Interval {
+            semitones: 0,
+            enabled: true,
+            velocity_percent: 100,
+        }; VOICES];
+        for (interval, semitones) in intervals.iter_mut().zip(semitones.iter()) {
+            interval.semitones = *semitones;
+        }
+
+        Harmonizer {
+            intervals,
+            held: [None; HELD],
+        }
+    }
+
+    /// Set the interval of `voice`, voices out of range are ignored
+    pub fn set_interval(&mut self, voice: usize, semitones: i8) {
+        if let Some(interval) = self.intervals.get_mut(voice) {
+            interval.semitones = semitones;
+        }
+    }
+
+    /// Enable or disable `voice`, voices out of range are ignored
+    pub fn set_enabled(&mut self, voice: usize, enabled: bool) {
+        if let Some(interval) = self.intervals.get_mut(voice) {
+            interval.enabled = enabled;
+        }
+    }
+
+    /// Scale the velocity of notes added for `voice`, in percent of the played note's velocity
+    pub fn set_velocity_percent(&mut self, voice: usize, percent: u8) {
+        if let Some(interval) = self.intervals.get_mut(voice) {
+            interval.velocity_percent = percent;
+        }
+    }
+
+    fn note_on(
+        &mut self,
+        channel: Channel,
+        note: Note,
+        velocity: Value7,
+        emit: &mut dyn FnMut(MidiMessage),
+    ) {
+        emit(MidiMessage::NoteOn(channel, note, velocity));
+
+        let slot = match self.held.iter_mut().find(|held| held.is_none()) {
+            Some(slot) => slot,
+            None => return,
+        };
+
+        let mut added = [None; VOICES];
+        for (interval, added) in self.intervals.iter().zip(added.iter_mut()) {
+            if !interval.enabled {
+                continue;
+            }
+
+            let target = i16::from(u8::from(note)) + i16::from(interval.semitones);
+            if !(0..=127).contains(&target) {
+                continue;
+            }
+
+            let scaled = u16::from(u8::from(velocity)) * u16::from(interval.velocity_percent) / 100;
+            let velocity = scaled.clamp(1, 127) as u8;
+            let target = Note::from(target as u8);
+            emit(MidiMessage::NoteOn(channel, target, velocity.into()));
+            *added = Some(target);
+        }
+
+        *slot = Some(Held {
+            channel,
+            note,
+            added,
+        });
+    }
+
+    fn note_off(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        emit(message);
+
+        let (channel, note) = match message {
+            MidiMessage::NoteOff(channel, note, _) | MidiMessage::NoteOn(channel, note, _) => {
+                (channel, note)
+            }
+            _ => return,
+        };
+
+        let held = self.held.iter_mut().find(
+            |held| matches!(held, Some(held) if held.channel == channel && held.note == note),
+        );
+
+        if let Some(Some(held)) = held.map(|held| held.take()) {
+            for added in held.added.iter().flatten() {
+                emit(match message {
+                    MidiMessage::NoteOff(_, _, velocity) => {
+                        MidiMessage::NoteOff(channel, *added, velocity)
+                    }
+                    _ => MidiMessage::NoteOn(channel, *added, 0.into()),
+                });
+            }
+        }
+    }
+}
+
+impl<const VOICES: usize, const HELD: usize> MidiProcessor for Harmonizer<VOICES, HELD> {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        match message {
+            MidiMessage::NoteOn(channel, note, velocity) if u8::from(velocity) > 0 => {
+                self.note_on(channel, note, velocity, emit)
+            }
+            MidiMessage::NoteOn(..) | MidiMessage::NoteOff(..) => self.note_off(message, emit),
+            _ => emit(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{note_off, note_on};
+    use crate::test_util::process;
+
+    /// Count note ons and note offs per note, velocity 0 note ons count as note offs
+    fn balance(messages: &[MidiMessage]) -> [i32; 128] {
+        let mut balance = [0; 128];
+        for message in messages {
+            match *message {
+                MidiMessage::NoteOn(_, note, velocity) if u8::from(velocity) > 0 => {
+                    balance[u8::from(note) as usize] += 1
+                }
+                MidiMessage::NoteOn(_, note, _) | MidiMessage::NoteOff(_, note, _) => {
+                    balance[u8::from(note) as usize] -= 1
+                }
+                _ => {}
+            }
+        }
+        balance
+    }
+
+    #[test]
+    fn should_add_notes_at_intervals() {
+        let mut harmonizer = Harmonizer::<2>::new([7, 12]);
+
+        assert_eq!(
+            process(&mut harmonizer, &[note_on(0, 60, 100), note_off(0, 60, 64)]),
+            [
+                note_on(0, 60, 100),
+                note_on(0, 67, 100),
+                note_on(0, 72, 100),
+                note_off(0, 60, 64),
+                note_off(0, 67, 64),
+                note_off(0, 72, 64),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_scale_velocity_and_skip_disabled_intervals() {
+        let mut harmonizer = Harmonizer::<2>::new([7, 12]);
+        harmonizer.set_enabled(0, false);
+        harmonizer.set_velocity_percent(1, 50);
+        // Voices out of range are ignored
+        harmonizer.set_interval(2, 5);
+        harmonizer.set_enabled(2, true);
+        harmonizer.set_velocity_percent(2, 10);
+
+        assert_eq!(
+            process(&mut harmonizer, &[note_on(0, 60, 100)]),
+            [note_on(0, 60, 100), note_on(0, 72, 50)]
+        );
+    }
+
+    #[test]
+    fn should_drop_notes_outside_note_range() {
+        let mut harmonizer = Harmonizer::<2>::new([12, -12]);
+
+        assert_eq!(
+            process(&mut harmonizer, &[note_on(0, 120, 100), note_on(0, 5, 100)]),
+            [
+                note_on(0, 120, 100),
+                note_on(0, 108, 100),
+                note_on(0, 5, 100),
+                note_on(0, 17, 100),
+            ]
+        );
+        assert_eq!(
+            process(&mut harmonizer, &[note_off(0, 120, 0), note_off(0, 5, 0)]),
+            [
+                note_off(0, 120, 0),
+                note_off(0, 108, 0),
+                note_off(0, 5, 0),
+                note_off(0, 17, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_release_every_added_note_of_overlapping_chords() {
+        let mut harmonizer = Harmonizer::<2>::new([4, 7]);
+        let mut output = process(
+            &mut harmonizer,
+            &[note_on(0, 60, 100), note_on(0, 64, 100), note_on(1, 60, 90)],
+        );
+
+        harmonizer.set_interval(0, 3);
+        output.extend(process(
+            &mut harmonizer,
+            &[
+                note_on(0, 67, 100),
+                note_off(0, 60, 0),
+                note_on(0, 64, 0),
+                note_off(1, 60, 0),
+                note_off(0, 67, 0),
+            ],
+        ));
+
+        assert_eq!(balance(&output), [0; 128]);
+    }
+
+    #[test]
+    fn should_pass_other_messages() {
+        let mut harmonizer = Harmonizer::<1>::new([7]);
+
+        assert_eq!(
+            process(&mut harmonizer, &[MidiMessage::TimingClock]),
+            [MidiMessage::TimingClock]
+        );
+    }
+
+    #[test]
+    fn should_not_harmonize_when_too_many_notes_held() {
+        let mut harmonizer = Harmonizer::<1, 1>::new([7]);
+
+        assert_eq!(
+            process(
+                &mut harmonizer,
+                &[note_on(0, 60, 100), note_on(0, 62, 100), note_off(0, 62, 0)]
+            ),
+            [
+                note_on(0, 60, 100),
+                note_on(0, 67, 100),
+                note_on(0, 62, 100),
+                note_off(0, 62, 0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,207 @@
+//! Endpoints on the midi ports of a desktop computer through `midir`, enabled with the
+//! `host-midir` feature
+//!
+//! Meant for prototyping and hardware in the loop tests: the processors and routers running on a
+//! device can run on a computer connected to real midi interfaces. Needs the standard library.
+
+use crate::wire::Receiver;
+use crate::{family, MidiSink, MidiSource, ParseEvent, RenderedMessage, SinkError, SourceError};
+use core::fmt;
+use midi_convert::midi_types::MidiMessage;
+use midir::{
+    ConnectError, MidiInput, MidiInputConnection, MidiInputPort, MidiOutput, MidiOutputConnection,
+    MidiOutputPort,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver as ChannelReceiver, SyncSender, TryRecvError};
+use std::sync::Arc;
+
+/// Parses the bytes `midir` hands to its input callback and queues the messages
+///
+/// Used by `MidirMidiIn`, runs on the thread of the callback. When the queue is full the message
+/// is dropped and the overrun reported by the next `poll`.
+#[derive(Debug)]
+pub struct MidirInputAdapter {
+    receiver: Receiver<{ family::ALL }>,
+    queue: SyncSender<MidiMessage>,
+    overrun: Arc<AtomicBool>,
+}
+
+impl MidirInputAdapter {
+    /// Adapter queueing up to `capacity` messages, with the source polling them
+    pub fn new(capacity: usize) -> (Self, MidirMidiIn<()>) {
+        let (queue, messages) = sync_channel(capacity);
+        let overrun = Arc::new(AtomicBool::new(false));
+        let adapter = MidirInputAdapter {
+            receiver: Receiver::new(),
+            queue,
+            overrun: overrun.clone(),
+        };
+        let source = MidirMidiIn {
+            connection: (),
+            messages,
+            overrun,
+        };
+        (adapter, source)
+    }
+
+    /// Parse the bytes of a callback, which may hold part of a message or several
+    pub fn on_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            if let Some(ParseEvent::Message(message)) = self.receiver.push(*byte) {
+                if self.queue.try_send(message).is_err() {
+                    self.overrun.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+/// Source receiving from a `midir` input port
+///
+/// `C` is the connection kept open while the source lives, `()` for a source fed by a
+/// `MidirInputAdapter` directly.
+pub struct MidirMidiIn<C = MidiInputConnection<()>> {
+    connection: C,
+    messages: ChannelReceiver<MidiMessage>,
+    overrun: Arc<AtomicBool>,
+}
+
+impl<C> fmt::Debug for MidirMidiIn<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MidirMidiIn")
+            .field("overrun", &self.overrun)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MidirMidiIn {
+    /// Connect to `port`, queueing up to `capacity` received messages
+    pub fn connect(
+        input: MidiInput,
+        port: &MidiInputPort,
+        name: &str,
+        capacity: usize,
+    ) -> Result<Self, ConnectError<MidiInput>> {
+        let (mut adapter, source) = MidirInputAdapter::new(capacity);
+        let connection = input.connect(
+            port,
+            name,
+            move |_timestamp, bytes, _| adapter.on_bytes(bytes),
+            (),
+        )?;
+        Ok(MidirMidiIn {
+            connection,
+            messages: source.messages,
+            overrun: source.overrun,
+        })
+    }
+
+    /// Close the connection, returns the input to connect again
+    pub fn close(self) -> MidiInput {
+        self.connection.close().0
+    }
+}
+
+impl<C> MidiSource for MidirMidiIn<C> {
+    fn poll(&mut self) -> Result<Option<MidiMessage>, SourceError> {
+        if self.overrun.swap(false, Ordering::Relaxed) {
+            return Err(SourceError::Overrun);
+        }
+        match self.messages.try_recv() {
+            Ok(message) => Ok(Some(message)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(SourceError::Transport),
+        }
+    }
+}
+
+/// Sink sending to a `midir` output port, every message is sent with its status byte
+pub struct MidirMidiOut {
+    connection: MidiOutputConnection,
+}
+
+impl fmt::Debug for MidirMidiOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MidirMidiOut").finish_non_exhaustive()
+    }
+}
+
+impl MidirMidiOut {
+    pub fn connect(
+        output: MidiOutput,
+        port: &MidiOutputPort,
+        name: &str,
+    ) -> Result<Self, ConnectError<MidiOutput>> {
+        Ok(MidirMidiOut {
+            connection: output.connect(port, name)?,
+        })
+    }
+
+    /// Send a system exclusive message, `data` are the bytes between 0xf0 and 0xf7
+    pub fn send_sysex(&mut self, data: &[u8]) -> Result<(), SinkError> {
+        if data.iter().any(|byte| *byte > 0x7f) {
+            return Err(SinkError::Invalid);
+        }
+        let mut message = std::vec::Vec::with_capacity(data.len() + 2);
+        message.push(0xf0);
+        message.extend_from_slice(data);
+        message.push(0xf7);
+        self.connection
+            .send(&message)
+            .map_err(|_| SinkError::Transport)
+    }
+
+    /// Close the connection, returns the output to connect again
+    pub fn close(self) -> MidiOutput {
+        self.connection.close()
+    }
+}
+
+impl MidiSink for MidirMidiOut {
+    fn send(&mut self, message: &MidiMessage) -> Result<(), SinkError> {
+        self.connection
+            .send(RenderedMessage::from(message).as_bytes())
+            .map_err(|_| SinkError::Transport)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{cc, note_on};
+
+    #[test]
+    fn should_parse_callback_bytes() {
+        let (mut adapter, mut source) = MidirInputAdapter::new(8);
+        assert_eq!(source.poll(), Ok(None));
+        // Running status and a message split across callbacks
+        adapter.on_bytes(&[0x90, 60, 100, 62]);
+        adapter.on_bytes(&[100, 0xf8, 0xb1]);
+        adapter.on_bytes(&[7, 1]);
+        assert_eq!(source.poll(), Ok(Some(note_on(0, 60, 100))));
+        assert_eq!(source.poll(), Ok(Some(note_on(0, 62, 100))));
+        assert_eq!(source.poll(), Ok(Some(MidiMessage::TimingClock)));
+        assert_eq!(source.poll(), Ok(Some(cc(1, 7, 1))));
+        assert_eq!(source.poll(), Ok(None));
+    }
+
+    #[test]
+    fn should_report_overrun_when_queue_is_full() {
+        let (mut adapter, mut source) = MidirInputAdapter::new(2);
+        adapter.on_bytes(&[0xf8, 0xf8, 0xf8]);
+        assert_eq!(source.poll(), Err(SourceError::Overrun));
+        assert_eq!(source.poll(), Ok(Some(MidiMessage::TimingClock)));
+        assert_eq!(source.poll(), Ok(Some(MidiMessage::TimingClock)));
+        assert_eq!(source.poll(), Ok(None));
+    }
+
+    #[test]
+    fn should_fail_once_the_adapter_is_gone() {
+        let (mut adapter, mut source) = MidirInputAdapter::new(2);
+        adapter.on_bytes(&[0xfa]);
+        drop(adapter);
+        assert_eq!(source.poll(), Ok(Some(MidiMessage::Start)));
+        assert_eq!(source.poll(), Err(SourceError::Transport));
+    }
+}
@@ -0,0 +1,196 @@
+//! Midi input and output on blocking `embedded-io` streams, enabled with the `io` feature
+//!
+//! ```
+//! use embedded_midi::{IoMidiIn, IoMidiOut};
+//! use embedded_midi::message::note_on;
+//!
+//! let mut buffer = [0; 8];
+//! let mut midi_out = IoMidiOut::new(&mut buffer[..]);
+//! midi_out.write(&note_on(0, 60, 100)).unwrap();
+//! midi_out.write(&note_on(0, 64, 100)).unwrap();
+//!
+//! let mut midi_in = IoMidiIn::new(&buffer[..5]);
+//! assert_eq!(midi_in.read().unwrap(), Some(note_on(0, 60, 100)));
+//! assert_eq!(midi_in.read().unwrap(), Some(note_on(0, 64, 100)));
+//! assert_eq!(midi_in.read().unwrap(), None);
+//! ```
+
+use crate::diag::log_trace;
+use crate::wire::{Receiver, Transmitter};
+use crate::{family, MidiError, ParseEvent, RenderedMessage};
+use core::task::Poll;
+use embedded_io::{Read, Write};
+use midi_convert::midi_types::MidiMessage;
+
+/// Midi input parsing messages read from an `embedded_io::Read` stream
+///
+/// Bytes are read one at a time, so no byte following a message is read before it is needed.
+#[derive(Debug)]
+pub struct IoMidiIn<R> {
+    reader: R,
+    receiver: Receiver<{ family::ALL }>,
+}
+
+impl<R: Read> IoMidiIn<R> {
+    pub fn new(reader: R) -> Self {
+        IoMidiIn {
+            reader,
+            receiver: Receiver::new(),
+        }
+    }
+
+    pub fn release(self) -> R {
+        self.reader
+    }
+
+    /// See `MidiIn::set_strict_system_common`
+    pub fn set_strict_system_common(&mut self, strict: bool) {
+        self.receiver.strict_system_common = strict;
+    }
+
+    /// Read until a message is complete, returns `None` at the end of the stream
+    pub fn read(&mut self) -> Result<Option<MidiMessage>, MidiError<R::Error>> {
+        let mut byte = [0];
+        loop {
+            if self.reader.read(&mut byte).map_err(MidiError::Serial)? == 0 {
+                return Ok(None);
+            }
+            if let Some(ParseEvent::Message(message)) = self.receiver.push(byte[0]) {
+                return Ok(Some(message));
+            }
+        }
+    }
+}
+
+/// Midi output writing messages with running status to an `embedded_io::Write` stream
+#[derive(Debug)]
+pub struct IoMidiOut<W> {
+    writer: W,
+    transmitter: Transmitter,
+}
+
+impl<W: Write> IoMidiOut<W> {
+    pub fn new(writer: W) -> Self {
+        IoMidiOut {
+            writer,
+            transmitter: Transmitter::default(),
+        }
+    }
+
+    pub fn release(self) -> W {
+        self.writer
+    }
+
+    /// See `MidiOut::set_running_status`
+    pub fn set_running_status(&mut self, enabled: bool) {
+        self.transmitter.running_status.disabled = !enabled;
+        self.transmitter.running_status.status = None;
+    }
+
+    /// Write a message, after a failed write the next message carries its status byte
+    pub fn write(&mut self, message: &MidiMessage) -> Result<(), MidiError<W::Error>> {
+        log_trace!("midi out: {:?}", message);
+        let len = self
+            .transmitter
+            .load(RenderedMessage::from(message).as_bytes());
+        let mut bytes = [0; 3];
+        for slot in &mut bytes[..len] {
+            if let Poll::Ready(byte) = self.transmitter.pull() {
+                *slot = byte;
+            }
+        }
+        self.writer.write_all(&bytes[..len]).map_err(|error| {
+            self.transmitter.running_status.interrupt();
+            MidiError::Serial(error)
+        })
+    }
+
+    pub fn flush(&mut self) -> Result<(), MidiError<W::Error>> {
+        self.writer.flush().map_err(MidiError::Serial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_on, program_change};
+    use crate::MidiOut;
+    use embedded_hal_mock::eh1::serial;
+    use std::vec::Vec;
+
+    fn messages() -> [MidiMessage; 5] {
+        [
+            note_on(2, 0x76, 0x34),
+            MidiMessage::TimingClock,
+            note_on(2, 0x33, 0x00),
+            MidiMessage::TuneRequest,
+            program_change(0, 5),
+        ]
+    }
+
+    #[test]
+    fn should_write_like_serial_output() {
+        let mut io_out = IoMidiOut::new(Vec::new());
+        for message in messages() {
+            io_out.write(&message).unwrap();
+        }
+        let written = io_out.release();
+
+        let expectations: Vec<_> = written
+            .iter()
+            .map(|byte| serial::Transaction::write(*byte))
+            .collect();
+        let mut midi_out = MidiOut::new(serial::Mock::new(&expectations));
+        for message in messages() {
+            midi_out.write(&message).unwrap();
+        }
+        midi_out.release().done();
+        // The second note on uses running status
+        assert_eq!(written.len(), 9);
+    }
+
+    #[test]
+    fn should_read_written_messages() {
+        let mut io_out = IoMidiOut::new(Vec::new());
+        io_out.write(&note_on(1, 60, 100)).unwrap();
+        io_out.write(&note_on(1, 62, 100)).unwrap();
+        io_out.set_running_status(false);
+        io_out.write(&cc(1, 7, 20)).unwrap();
+        io_out.write(&cc(1, 7, 10)).unwrap();
+        io_out.flush().unwrap();
+        let written = io_out.release();
+        assert_eq!(written.len(), 11);
+
+        let mut io_in = IoMidiIn::new(&written[..]);
+        let mut received = Vec::new();
+        while let Some(message) = io_in.read().unwrap() {
+            received.push(message);
+        }
+        assert_eq!(
+            received,
+            [
+                note_on(1, 60, 100),
+                note_on(1, 62, 100),
+                cc(1, 7, 20),
+                cc(1, 7, 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn should_resend_status_after_failed_write() {
+        let mut buffer = [0; 4];
+        let mut io_out = IoMidiOut::new(&mut buffer[..]);
+        io_out.write(&note_on(0, 60, 100)).unwrap();
+        assert!(io_out.write(&note_on(0, 62, 100)).is_err());
+
+        let mut buffer = [0; 3];
+        let mut io_out = IoMidiOut {
+            writer: &mut buffer[..],
+            transmitter: io_out.transmitter,
+        };
+        io_out.write(&note_on(0, 62, 100)).unwrap();
+        assert_eq!(buffer, [0x90, 62, 100]);
+    }
+}
@@ -0,0 +1,136 @@
+//! Latency statistics for tuning queue sizes and interrupt handlers, enabled with the
+//! `instrumentation` feature
+
+/// Default upper bounds of the histogram buckets, in the unit of the recorded timestamps
+///
+/// With microsecond timestamps these are 100 µs, 500 µs, 1 ms, 5 ms and 10 ms, the last bucket
+/// counts everything above.
+pub const LATENCY_BUCKETS: [u32; 5] = [100, 500, 1_000, 5_000, 10_000];
+
+/// Minimum, maximum, mean and a histogram of recorded latencies
+///
+/// Bucket `i` counts latencies below `bounds[i]` and not counted by an earlier bucket, the last
+/// bucket counts latencies of at least the highest bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyStats {
+    bounds: [u32; 5],
+    buckets: [u32; 6],
+    count: u32,
+    total: u64,
+    min: u32,
+    max: u32,
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyStats {
+    /// Stats using the `LATENCY_BUCKETS` bounds
+    pub const fn new() -> Self {
+        Self::with_bounds(LATENCY_BUCKETS)
+    }
+
+    /// Stats using other bucket bounds, in increasing order
+    pub const fn with_bounds(bounds: [u32; 5]) -> Self {
+        LatencyStats {
+            bounds,
+            buckets: [0; 6],
+            count: 0,
+            total: 0,
+            min: u32::MAX,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, latency: u32) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| latency < *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket] = self.buckets[bucket].saturating_add(1);
+        self.count = self.count.saturating_add(1);
+        self.total = self.total.saturating_add(latency as u64);
+        self.min = self.min.min(latency);
+        self.max = self.max.max(latency);
+    }
+
+    /// Record the time between `arrival` and `now`, timestamps may wrap around
+    pub fn record_at(&mut self, now: u32, arrival: u32) {
+        self.record(now.wrapping_sub(arrival));
+    }
+
+    /// Forget all recorded latencies, keeping the bucket bounds
+    pub fn reset(&mut self) {
+        *self = Self::with_bounds(self.bounds);
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn min(&self) -> Option<u32> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    pub fn max(&self) -> Option<u32> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    /// Mean latency, rounded down
+    pub fn mean(&self) -> Option<u32> {
+        (self.count > 0).then(|| (self.total / self.count as u64) as u32)
+    }
+
+    pub fn bounds(&self) -> &[u32; 5] {
+        &self.bounds
+    }
+
+    /// Number of latencies counted by each bucket
+    pub fn buckets(&self) -> &[u32; 6] {
+        &self.buckets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_count_latencies_in_buckets() {
+        let mut stats = LatencyStats::new();
+        for latency in [0, 99, 100, 499, 700, 4_999, 5_000, 10_000, 250_000] {
+            stats.record(latency);
+        }
+
+        assert_eq!(stats.buckets(), &[2, 2, 1, 1, 1, 2]);
+        assert_eq!(stats.count(), 9);
+        assert_eq!(stats.min(), Some(0));
+        assert_eq!(stats.max(), Some(250_000));
+        assert_eq!(stats.mean(), Some(271_397 / 9));
+    }
+
+    #[test]
+    fn should_record_wrapping_timestamps() {
+        let mut stats = LatencyStats::with_bounds([10, 20, 30, 40, 50]);
+        stats.record_at(5, u32::MAX - 4);
+        stats.record_at(1_000, 975);
+
+        assert_eq!(stats.buckets(), &[0, 1, 1, 0, 0, 0]);
+        assert_eq!(stats.mean(), Some(17));
+    }
+
+    #[test]
+    fn should_reset_keeping_bounds() {
+        let mut stats = LatencyStats::with_bounds([10, 20, 30, 40, 50]);
+        stats.record(15);
+        stats.reset();
+
+        assert_eq!(stats, LatencyStats::with_bounds([10, 20, 30, 40, 50]));
+        assert_eq!(stats.min(), None);
+        assert_eq!(stats.mean(), None);
+    }
+}
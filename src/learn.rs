@@ -0,0 +1,329 @@
+//! Midi learn, binding controllers to parameters by moving them
+
+use crate::{
+    family,
+    persist::{Reader, Writer},
+    DecodeError, FullTable, TooSmall,
+};
+use midi_convert::midi_types::{Channel, Control, MidiMessage, Note};
+
+/// Message a parameter is bound to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LearnSource {
+    Control(Control),
+    /// Note ons toggle the parameter between 0.0 and 1.0
+    Note(Note),
+    PitchBend,
+}
+
+/// A parameter bound to a source on a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Binding {
+    pub channel: Channel,
+    pub source: LearnSource,
+    pub param: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    binding: Binding,
+    /// Toggle state of note sources
+    on: bool,
+}
+
+/// Source and value of a message that can be bound, values are normalized to 0.0 to 1.0
+fn source_of(message: &MidiMessage) -> Option<(Channel, LearnSource, u16, f32)> {
+    match *message {
+        MidiMessage::ControlChange(channel, control, value) => Some((
+            channel,
+            LearnSource::Control(control),
+            family::CONTROL_CHANGE,
+            u8::from(value) as f32 / 127.0,
+        )),
+        MidiMessage::NoteOn(channel, note, velocity) if u8::from(velocity) > 0 => {
+            Some((channel, LearnSource::Note(note), family::NOTE_ON, 0.0))
+        }
+        MidiMessage::PitchBendChange(channel, value) => Some((
+            channel,
+            LearnSource::PitchBend,
+            family::PITCH_BEND,
+            u16::from(value) as f32 / 16383.0,
+        )),
+        _ => None,
+    }
+}
+
+/// Binds up to `PARAMS` parameters to control changes, notes or pitch bend
+///
+/// After `start_learn` the next message of a learnable family binds its source to the parameter,
+/// replacing the earlier binding of the parameter and any other parameter bound to the source.
+/// Bound messages are then resolved into parameter values by `process`.
+#[derive(Debug, Clone)]
+pub struct MidiLearn<const PARAMS: usize = 16> {
+    slots: [Option<Slot>; PARAMS],
+    learning: Option<u16>,
+    families: u16,
+}
+
+impl<const PARAMS: usize> Default for MidiLearn<PARAMS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const PARAMS: usize> MidiLearn<PARAMS> {
+    /// Largest number of bytes written by `save`
+    pub const MAX_SAVED_LEN: usize = 5 + 5 * PARAMS;
+
+    /// Learn control changes, notes and pitch bend
+    pub fn new() -> Self {
+        MidiLearn {
+            slots: [None; PARAMS],
+            learning: None,
+            families: family::CONTROL_CHANGE | family::NOTE_ON | family::PITCH_BEND,
+        }
+    }
+
+    /// Only learn sources of the families in `families`, a mask of `family` constants
+    ///
+    /// Only `CONTROL_CHANGE`, `NOTE_ON` and `PITCH_BEND` can be learned.
+    pub fn set_learn_families(&mut self, families: u16) {
+        self.families = families;
+    }
+
+    /// Bind the source of the next learnable message to `param`
+    ///
+    /// Returns `FullTable` when `param` has no binding and all `PARAMS` bindings are used.
+    pub fn start_learn(&mut self, param: u16) -> Result<(), FullTable> {
+        if self.position(|binding| binding.param == param).is_none()
+            && self.slots.iter().all(Option::is_some)
+        {
+            return Err(FullTable);
+        }
+        self.learning = Some(param);
+        Ok(())
+    }
+
+    pub fn cancel_learn(&mut self) {
+        self.learning = None;
+    }
+
+    /// Parameter waiting for a source to be learned
+    pub fn learning(&self) -> Option<u16> {
+        self.learning
+    }
+
+    /// Resolve a message into a parameter and its new value, learning its source when armed
+    pub fn process(&mut self, message: &MidiMessage) -> Option<(u16, f32)> {
+        let (channel, source, family, value) = source_of(message)?;
+        let matches = |binding: &Binding| binding.channel == channel && binding.source == source;
+
+        if let Some(param) = self.learning.filter(|_| self.families & family != 0) {
+            self.learning = None;
+            self.slots.iter_mut().for_each(|slot| {
+                if matches!(slot, Some(slot) if slot.binding.param == param || matches(&slot.binding))
+                {
+                    *slot = None;
+                }
+            });
+            // `start_learn` made sure there is room
+            let slot = self.slots.iter_mut().find(|slot| slot.is_none())?;
+            *slot = Some(Slot {
+                binding: Binding {
+                    channel,
+                    source,
+                    param,
+                },
+                on: false,
+            });
+        }
+
+        let slot = self
+            .slots
+            .iter_mut()
+            .flatten()
+            .find(|slot| matches(&slot.binding))?;
+        let value = match source {
+            LearnSource::Note(_) => {
+                slot.on = !slot.on;
+                if slot.on {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            _ => value,
+        };
+        Some((slot.binding.param, value))
+    }
+
+    pub fn bindings(&self) -> impl Iterator<Item = &Binding> {
+        self.slots.iter().flatten().map(|slot| &slot.binding)
+    }
+
+    /// Remove the binding of `param`, returns the removed binding
+    pub fn remove(&mut self, param: u16) -> Option<Binding> {
+        let index = self.position(|binding| binding.param == param)?;
+        self.slots[index].take().map(|slot| slot.binding)
+    }
+
+    pub fn clear(&mut self) {
+        self.slots = [None; PARAMS];
+    }
+
+    /// Save the bindings and learnable families into `buffer`, returns the number of bytes written
+    pub fn save(&self, buffer: &mut [u8]) -> Result<usize, TooSmall> {
+        let mut writer = Writer::new(buffer)?;
+        writer.u16(self.families)?;
+        writer.u16(self.bindings().count() as u16)?;
+        for binding in self.bindings() {
+            writer.byte(binding.channel.into())?;
+            let (kind, data) = match binding.source {
+                LearnSource::Control(control) => (0, control.into()),
+                LearnSource::Note(note) => (1, note.into()),
+                LearnSource::PitchBend => (2, 0),
+            };
+            writer.byte(kind)?;
+            writer.byte(data)?;
+            writer.u16(binding.param)?;
+        }
+        Ok(writer.finish())
+    }
+
+    /// Load bindings saved by `save`, note toggles start off
+    pub fn load(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes)?;
+        let mut learn = Self::new();
+        learn.families = reader.u16()?;
+        let count = reader.u16()? as usize;
+        if count > PARAMS {
+            return Err(DecodeError::Invalid);
+        }
+        for slot in learn.slots.iter_mut().take(count) {
+            let channel = reader.channel()?;
+            let source = match (reader.byte()?, reader.value7()?) {
+                (0, control) => LearnSource::Control(control.into()),
+                (1, note) => LearnSource::Note(note.into()),
+                (2, 0) => LearnSource::PitchBend,
+                _ => return Err(DecodeError::Invalid),
+            };
+            let binding = Binding {
+                channel,
+                source,
+                param: reader.u16()?,
+            };
+            *slot = Some(Slot { binding, on: false });
+        }
+        Ok(learn)
+    }
+
+    fn position(&self, predicate: impl Fn(&Binding) -> bool) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|slot| matches!(slot, Some(slot) if predicate(&slot.binding)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_off, note_on, pitch_bend};
+    use std::vec::Vec;
+
+    #[test]
+    fn should_learn_next_message_while_ignoring_realtime() {
+        let mut learn = MidiLearn::<4>::new();
+        assert_eq!(learn.process(&cc(0, 74, 127)), None);
+
+        learn.start_learn(7).unwrap();
+        assert_eq!(learn.process(&MidiMessage::ActiveSensing), None);
+        assert_eq!(learn.process(&MidiMessage::TimingClock), None);
+        assert_eq!(learn.learning(), Some(7));
+
+        assert_eq!(learn.process(&cc(2, 74, 127)), Some((7, 1.0)));
+        assert_eq!(learn.learning(), None);
+        assert_eq!(learn.process(&cc(2, 74, 0)), Some((7, 0.0)));
+        assert_eq!(learn.process(&cc(3, 74, 0)), None);
+        assert_eq!(
+            learn.bindings().copied().collect::<Vec<_>>(),
+            [Binding {
+                channel: 2.into(),
+                source: LearnSource::Control(74.into()),
+                param: 7,
+            }]
+        );
+    }
+
+    #[test]
+    fn should_only_learn_enabled_families() {
+        let mut learn = MidiLearn::<4>::new();
+        learn.set_learn_families(family::PITCH_BEND);
+        learn.start_learn(1).unwrap();
+
+        assert_eq!(learn.process(&cc(0, 1, 64)), None);
+        assert_eq!(learn.process(&note_on(0, 60, 100)), None);
+        assert_eq!(learn.process(&pitch_bend(0, 8191)), Some((1, 1.0)));
+    }
+
+    #[test]
+    fn should_rebind_conflicting_sources_and_params() {
+        let mut learn = MidiLearn::<2>::new();
+        learn.start_learn(1).unwrap();
+        learn.process(&cc(0, 10, 0));
+        learn.start_learn(2).unwrap();
+        learn.process(&cc(0, 11, 0));
+        assert_eq!(learn.start_learn(3), Err(FullTable));
+
+        // The source of parameter 1 moves to parameter 2, which loses its own source
+        learn.start_learn(2).unwrap();
+        assert_eq!(learn.process(&cc(0, 10, 127)), Some((2, 1.0)));
+        assert_eq!(learn.process(&cc(0, 11, 127)), None);
+        assert_eq!(learn.bindings().count(), 1);
+
+        assert_eq!(learn.remove(2).map(|binding| binding.param), Some(2));
+        assert_eq!(learn.process(&cc(0, 10, 127)), None);
+    }
+
+    #[test]
+    fn should_toggle_note_sources() {
+        let mut learn = MidiLearn::<4>::new();
+        learn.start_learn(5).unwrap();
+
+        assert_eq!(learn.process(&note_on(9, 36, 100)), Some((5, 1.0)));
+        assert_eq!(learn.process(&note_off(9, 36, 0)), None);
+        assert_eq!(learn.process(&note_on(9, 36, 0)), None);
+        assert_eq!(learn.process(&note_on(9, 36, 20)), Some((5, 0.0)));
+        assert_eq!(learn.process(&note_on(9, 36, 20)), Some((5, 1.0)));
+    }
+
+    #[test]
+    fn should_round_trip_saved_bindings() {
+        let mut learn = MidiLearn::<4>::new();
+        learn.set_learn_families(family::CONTROL_CHANGE | family::NOTE_ON);
+        learn.start_learn(1).unwrap();
+        learn.process(&cc(3, 74, 0));
+        learn.start_learn(300).unwrap();
+        learn.process(&note_on(9, 36, 100));
+
+        let mut buffer = [0; MidiLearn::<4>::MAX_SAVED_LEN];
+        let len = learn.save(&mut buffer).unwrap();
+        assert_eq!(len, 15);
+
+        let mut loaded = MidiLearn::<4>::load(&buffer[..len]).unwrap();
+        assert!(loaded.bindings().eq(learn.bindings()));
+        assert_eq!(loaded.process(&note_on(9, 36, 100)), Some((300, 1.0)));
+        loaded.start_learn(2).unwrap();
+        assert_eq!(loaded.process(&pitch_bend(0, 0)), None);
+
+        assert_eq!(
+            MidiLearn::<1>::load(&buffer[..len]).err(),
+            Some(DecodeError::Invalid)
+        );
+        buffer[0] = 2;
+        assert_eq!(
+            MidiLearn::<4>::load(&buffer).err(),
+            Some(DecodeError::UnsupportedVersion(2))
+        );
+    }
+}
@@ -0,0 +1,381 @@
+//! Low frequency oscillator sending modulation as control changes or pitch bend
+
+use midi_convert::midi_types::{Channel, Control, MidiMessage, Value14};
+
+/// First quarter of a sine wave with amplitude 8191 in 64 steps
+#[rustfmt::skip]
+const QUARTER_SINE: [i16; 65] = [
+    0, 201, 402, 603, 803, 1003, 1202, 1400, 1598, 1795, 1990, 2185, 2378, 2569, 2759, 2948, 3135,
+    3319, 3502, 3683, 3861, 4037, 4211, 4382, 4551, 4716, 4879, 5039, 5196, 5350, 5501, 5648, 5792,
+    5932, 6069, 6202, 6332, 6457, 6579, 6697, 6811, 6920, 7026, 7127, 7224, 7316, 7405, 7488, 7567,
+    7642, 7712, 7778, 7838, 7894, 7946, 7992, 8034, 8070, 8102, 8129, 8152, 8169, 8181, 8189, 8191,
+];
+
+/// Highest 14 bit value
+const MAX_VALUE14: u16 = 0x3fff;
+
+/// Shape of the modulation, every shape but the saw starts a cycle at the center
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    /// Rising from the bottom to the top
+    Saw,
+    /// Top for the first half of a cycle, bottom for the second
+    Square,
+    /// A random value held for a cycle
+    SampleAndHold,
+}
+
+/// Where the modulation is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LfoTarget {
+    /// Control changes with the top 7 bits of the modulation
+    Control(Channel, Control),
+    PitchBend(Channel),
+}
+
+/// Speed of an `MidiLfo`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LfoRate {
+    /// Free running at this many thousandths of a hertz
+    MilliHz(u32),
+    /// Synced to the tempo, one cycle lasts this many timing clocks, 24 is a quarter note
+    Clocks(u16),
+}
+
+/// Sine value for `position` from 0 to 65536 across the first quarter of a cycle
+fn quarter_sine(position: u32) -> i32 {
+    let index = (position >> 10) as usize;
+    let fraction = (position & 0x3ff) as i32;
+    let low = i32::from(QUARTER_SINE[index]);
+    match QUARTER_SINE.get(index + 1) {
+        Some(high) => low + (((i32::from(*high) - low) * fraction) >> 10),
+        None => low,
+    }
+}
+
+impl Waveform {
+    /// Value from -8192 to 8191 at `phase`, a full cycle spans the range of `u32`
+    fn sample(self, phase: u32, held: i32) -> i32 {
+        let position = (phase >> 14) & 0xffff;
+        match self {
+            Waveform::Sine => match phase >> 30 {
+                0 => quarter_sine(position),
+                1 => quarter_sine(0x10000 - position),
+                2 => -quarter_sine(position),
+                _ => -quarter_sine(0x10000 - position),
+            },
+            Waveform::Triangle => {
+                let position = (phase >> 16) as i32;
+                let rising = match position {
+                    0..=0x3fff => position,
+                    0x4000..=0xbfff => 0x8000 - position,
+                    _ => position - 0x10000,
+                };
+                rising * 8191 / 0x4000
+            }
+            Waveform::Saw => (phase >> 18) as i32 - 8192,
+            Waveform::Square if phase < 0x8000_0000 => 8191,
+            Waveform::Square => -8192,
+            Waveform::SampleAndHold => held,
+        }
+    }
+}
+
+/// Low frequency oscillator sending its waveform as control changes or pitch bend
+///
+/// The oscillator swings by `depth` around `center`, both 14 bit values, a depth of 16383 uses the
+/// full range. Free running oscillators advance with the time passed to `tick`, synced ones with
+/// the timing clocks passed to `clock`. `tick` sends the current value at most every
+/// `interval_ms` and only when it changed, so slow or square waves don't flood the output.
+#[derive(Debug, Clone)]
+pub struct MidiLfo {
+    waveform: Waveform,
+    target: LfoTarget,
+    rate: LfoRate,
+    center: u16,
+    depth: u16,
+    interval_ms: u32,
+    phase: u32,
+    /// Remainder of the division advancing the phase, so no fraction of a step gets lost
+    remainder: u64,
+    last_tick_ms: Option<u32>,
+    last_sent_ms: Option<u32>,
+    /// Last value sent, 7 bit for control changes and 14 bit for pitch bend
+    last_sent: Option<u16>,
+    held: i32,
+    random: u32,
+}
+
+impl MidiLfo {
+    /// Oscillator swinging over the full range of `target`, sending at most every 10 ms
+    pub fn new(waveform: Waveform, target: LfoTarget, rate: LfoRate) -> Self {
+        MidiLfo {
+            waveform,
+            target,
+            rate,
+            center: 0x2000,
+            depth: MAX_VALUE14,
+            interval_ms: 10,
+            phase: 0,
+            remainder: 0,
+            last_tick_ms: None,
+            last_sent_ms: None,
+            last_sent: None,
+            held: 0,
+            random: 0x2545_f491,
+        }
+    }
+
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.waveform = waveform;
+    }
+
+    pub fn set_target(&mut self, target: LfoTarget) {
+        self.target = target;
+        self.last_sent = None;
+    }
+
+    pub fn set_rate(&mut self, rate: LfoRate) {
+        self.rate = rate;
+        self.remainder = 0;
+    }
+
+    pub fn set_center(&mut self, center: Value14) {
+        self.center = center.into();
+    }
+
+    /// Swing from the lowest to the highest value, 16383 spans the full range
+    pub fn set_depth(&mut self, depth: Value14) {
+        self.depth = depth.into();
+    }
+
+    /// Shortest time between two messages
+    pub fn set_interval_ms(&mut self, interval_ms: u32) {
+        self.interval_ms = interval_ms;
+    }
+
+    /// Restart the cycle and send the next value even if it didn't change
+    pub fn reset(&mut self) {
+        self.phase = 0;
+        self.remainder = 0;
+        self.last_sent = None;
+    }
+
+    /// Current 14 bit value
+    pub fn value(&self) -> u16 {
+        let sample = self.waveform.sample(self.phase, self.held);
+        let offset = sample * i32::from(self.depth) / i32::from(MAX_VALUE14);
+        (i32::from(self.center) + offset).clamp(0, i32::from(MAX_VALUE14)) as u16
+    }
+
+    /// Advance a synced oscillator on a timing clock, a start restarts the cycle
+    pub fn clock(&mut self, message: &MidiMessage) {
+        match (message, self.rate) {
+            (MidiMessage::TimingClock, LfoRate::Clocks(clocks)) => {
+                self.advance(1, u64::from(clocks.max(1)));
+            }
+            (MidiMessage::Start, _) => self.reset(),
+            _ => {}
+        }
+    }
+
+    /// Advance a free running oscillator to `now_ms` and call `emit` with the current value if it
+    /// is due
+    pub fn tick(&mut self, now_ms: u32, emit: &mut dyn FnMut(MidiMessage)) {
+        if let (Some(last), LfoRate::MilliHz(millihertz)) = (self.last_tick_ms, self.rate) {
+            let elapsed = u64::from(now_ms.wrapping_sub(last));
+            self.advance(elapsed * u64::from(millihertz), 1_000_000);
+        }
+        self.last_tick_ms = Some(now_ms);
+
+        let due = self
+            .last_sent_ms
+            .map_or(true, |last| now_ms.wrapping_sub(last) >= self.interval_ms);
+        if !due {
+            return;
+        }
+        let value = match self.target {
+            LfoTarget::Control(..) => self.value() >> 7,
+            LfoTarget::PitchBend(_) => self.value(),
+        };
+        if self.last_sent == Some(value) {
+            return;
+        }
+        self.last_sent = Some(value);
+        self.last_sent_ms = Some(now_ms);
+        emit(match self.target {
+            LfoTarget::Control(channel, control) => {
+                MidiMessage::ControlChange(channel, control, (value as u8).into())
+            }
+            LfoTarget::PitchBend(channel) => MidiMessage::PitchBendChange(channel, value.into()),
+        });
+    }
+
+    /// Advance the phase by `units / per_cycle` cycles, picking a new random value when a cycle
+    /// ends
+    fn advance(&mut self, units: u64, per_cycle: u64) {
+        let steps = ((units % per_cycle) << 32) + self.remainder;
+        self.remainder = steps % per_cycle;
+        let steps = steps / per_cycle;
+        let (phase, wrapped) = self.phase.overflowing_add(steps as u32);
+        self.phase = phase;
+        if wrapped || units >= per_cycle || steps >> 32 != 0 {
+            // xorshift32
+            self.random ^= self.random << 13;
+            self.random ^= self.random >> 17;
+            self.random ^= self.random << 5;
+            self.held = (self.random >> 18) as i32 - 8192;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::cc;
+    use std::vec::Vec;
+
+    const QUARTER: u32 = 1 << 30;
+
+    fn samples(waveform: Waveform) -> [i32; 5] {
+        let mut samples = [0; 5];
+        for (index, sample) in samples.iter_mut().enumerate() {
+            *sample = waveform.sample((index as u32).wrapping_mul(QUARTER / 2), 0);
+        }
+        samples
+    }
+
+    #[test]
+    fn should_sample_waveforms_at_known_phases() {
+        for (phase, expected) in [
+            (0, 0),
+            (QUARTER, 8191),
+            (2 * QUARTER, 0),
+            (3 * QUARTER, -8191),
+        ] {
+            assert_eq!(Waveform::Sine.sample(phase, 0), expected);
+        }
+        assert_eq!(samples(Waveform::Sine)[1], 5792);
+        // Interpolated between table entries, close to 8191 * sin(pi / 3)
+        assert!((Waveform::Sine.sample(QUARTER / 3 * 2, 0) - 7094).abs() <= 2);
+
+        assert_eq!(samples(Waveform::Triangle), [0, 4095, 8191, 4095, 0]);
+        assert_eq!(Waveform::Triangle.sample(3 * QUARTER, 0), -8191);
+        assert_eq!(samples(Waveform::Saw), [-8192, -6144, -4096, -2048, 0]);
+        assert_eq!(Waveform::Saw.sample(u32::MAX, 0), 8191);
+        assert_eq!(samples(Waveform::Square), [8191, 8191, 8191, 8191, -8192]);
+    }
+
+    #[test]
+    fn should_scale_around_center() {
+        let mut lfo = MidiLfo::new(
+            Waveform::Square,
+            LfoTarget::PitchBend(Channel::C1),
+            LfoRate::MilliHz(1_000),
+        );
+        assert_eq!(lfo.value(), 16383);
+        lfo.set_depth(4096u16.into());
+        lfo.set_center(10_000u16.into());
+        assert_eq!(lfo.value(), 12047);
+        lfo.phase = 2 * QUARTER;
+        assert_eq!(lfo.value(), 7952);
+    }
+
+    #[test]
+    fn should_limit_rate_and_suppress_repeated_values() {
+        let mut lfo = MidiLfo::new(
+            Waveform::Sine,
+            LfoTarget::Control(Channel::C1, Control::from(1)),
+            LfoRate::MilliHz(1_000),
+        );
+        let mut output = Vec::new();
+        for now in 0..1_000 {
+            lfo.tick(now, &mut |message| output.push((now, message)));
+        }
+        assert!(output.len() <= 100);
+        assert!(output.len() > 50);
+        assert!(output.windows(2).all(|pair| pair[1].0 - pair[0].0 >= 10));
+        assert!(output.windows(2).all(|pair| pair[1].1 != pair[0].1));
+        assert_eq!(output[0], (0, cc(0, 1, 64)));
+        // The peaks are reached around a quarter and three quarters of a second
+        let peak = output
+            .iter()
+            .position(|(_, message)| *message == cc(0, 1, 127));
+        let trough = output
+            .iter()
+            .position(|(_, message)| *message == cc(0, 1, 0));
+        assert!((230..=250).contains(&output[peak.unwrap()].0));
+        assert!((730..=750).contains(&output[trough.unwrap()].0));
+
+        let mut lfo = MidiLfo::new(
+            Waveform::Square,
+            LfoTarget::Control(Channel::C1, Control::from(1)),
+            LfoRate::MilliHz(2_000),
+        );
+        output.clear();
+        for now in 0..1_000 {
+            lfo.tick(now, &mut |message| output.push((now, message)));
+        }
+        assert_eq!(
+            output,
+            [
+                (0, cc(0, 1, 127)),
+                (250, cc(0, 1, 0)),
+                (500, cc(0, 1, 127)),
+                (750, cc(0, 1, 0))
+            ]
+        );
+    }
+
+    #[test]
+    fn should_follow_timing_clocks() {
+        let mut lfo = MidiLfo::new(
+            Waveform::Triangle,
+            LfoTarget::PitchBend(Channel::C3),
+            LfoRate::Clocks(96),
+        );
+        let mut output = Vec::new();
+        lfo.set_interval_ms(0);
+        lfo.clock(&MidiMessage::Start);
+        for now in 0..24 {
+            lfo.clock(&MidiMessage::TimingClock);
+            lfo.tick(now, &mut |message| output.push(message));
+        }
+
+        assert_eq!(lfo.value(), 16383);
+        assert_eq!(
+            output.last(),
+            Some(&MidiMessage::PitchBendChange(Channel::C3, 16383u16.into()))
+        );
+        // Time alone doesn't move a synced oscillator
+        lfo.tick(1_000, &mut |message| output.push(message));
+        assert_eq!(output.len(), 24);
+
+        lfo.clock(&MidiMessage::Start);
+        assert_eq!(lfo.value(), 8192);
+    }
+
+    #[test]
+    fn should_hold_random_value_for_a_cycle() {
+        let mut lfo = MidiLfo::new(
+            Waveform::SampleAndHold,
+            LfoTarget::PitchBend(Channel::C1),
+            LfoRate::Clocks(4),
+        );
+        let mut values = Vec::new();
+        for _ in 0..12 {
+            lfo.clock(&MidiMessage::TimingClock);
+            values.push(lfo.value());
+        }
+
+        // A new value is picked when a cycle ends at every fourth clock
+        assert!(values[..3].iter().all(|value| *value == 8192));
+        for cycle in values[3..11].chunks(4) {
+            assert!(cycle.iter().all(|value| *value == cycle[0]));
+        }
+        assert_ne!(values[3], values[7]);
+    }
+}
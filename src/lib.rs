@@ -2,66 +2,439 @@
 
 #![no_std]
 #![warn(missing_debug_implementations)]
+
+#[cfg(feature = "host-midir")]
+extern crate std;
+
 use core::fmt::Debug;
+use core::task::Poll;
+use diag::{log_debug, log_trace, log_warn};
 use embedded_hal_nb::serial;
 use midi_convert::midi_types::MidiMessage;
+use wire::{Receiver, Transmitter};
 
-use midi_convert::{
-    parse::MidiParser,
-    render::{MidiRenderer, MidiTransport},
-};
 use nb::block;
 
 pub use midi_convert::midi_types;
+pub use midi_convert::parse::{MidiParseError, MidiParser, MidiTryParseSlice};
+pub use midi_convert::render::{MidiRenderer, MidiTransport};
+
+mod activity;
+mod activity_led;
+mod aftertouch;
+mod analyzer;
+#[cfg(feature = "arbitrary")]
+pub mod arbitrary;
+mod block;
+mod broadcast;
+mod capture;
+mod cc_remap;
+mod channel;
+mod channel_mode;
+mod chord_memory;
+mod clock_regen;
+mod config_sysex;
+#[cfg(feature = "console")]
+pub mod console;
+mod dedup;
+mod device_filter;
+mod diag;
+mod din_sync;
+mod dma_ring;
+#[cfg(feature = "eh0")]
+mod eh02;
+#[cfg(feature = "embassy")]
+pub mod embassy;
+mod endpoint;
+mod error;
+pub mod family;
+mod fixed_channel;
+mod frame;
+#[cfg(feature = "fwup")]
+pub mod fwup;
+mod harmonizer;
+#[cfg(feature = "host-midir")]
+mod host;
+#[cfg(feature = "io")]
+mod io;
+#[cfg(feature = "instrumentation")]
+mod latency;
+mod learn;
+mod lfo;
+mod line_diagnostics;
+mod local_control;
+mod logger;
+mod matrix;
+mod merge;
+pub mod message;
+mod metronome;
+mod microtune;
+mod mono;
+mod mpe_downmix;
+#[cfg(feature = "msc")]
+pub mod msc;
+pub mod mts;
+mod note_gate;
+mod note_tracker;
+mod omni;
+mod packet;
+#[doc(hidden)]
+pub mod parse;
+mod pc_debounce;
+mod persist;
+pub mod pipelines;
+mod position;
+mod processor;
+mod program_map;
+mod quantize;
+mod queue;
+mod ramp;
+mod render_sink;
+mod rendered;
+mod reset;
+mod router;
+mod scale;
+pub mod seven_bit;
+#[cfg(feature = "critical-section")]
+mod shared;
+mod sink;
+#[cfg(feature = "smf")]
+mod smf;
+mod startup;
+#[cfg(feature = "instrumentation")]
+mod stats;
+mod step_recorder;
+mod stream;
+mod stuck_note;
+mod sysex;
+mod sysex_router;
+mod takeover;
+mod tap;
+mod tap_tempo;
+#[cfg(test)]
+mod test_util;
+mod thru;
+mod timecode;
+mod value14;
+mod velocity_switch;
+mod voice;
+mod wire;
 
+pub use activity::{ChannelActivity, ChannelCounts};
+pub use activity_led::MidiActivityLed;
+pub use aftertouch::{AftertouchToCc, CcToAftertouch};
+pub use analyzer::{AnalyzerParser, ParsedWithMeta};
+pub use block::BlockScheduler;
+pub use broadcast::{Broadcast, Subscriber};
+pub use capture::{CapturePlayer, CaptureRecord, CaptureWriter, CAPTURE_RECORD_LEN};
+pub use cc_remap::{CcRemap, CcRule, CcTarget};
+pub use channel::ChannelExt;
+pub use channel_mode::ChannelModeEvent;
+pub use chord_memory::{ChordMemory, ChordShape, MAX_CHORD_NOTES};
+pub use clock_regen::ClockRegenerator;
+pub use config_sysex::{
+    ConfigError, ConfigEvent, ConfigSysex, ConfigTarget, SectionReader, SectionWriter, DUMP_BEGIN,
+    DUMP_DATA, DUMP_END, DUMP_REQUEST,
+};
+pub use dedup::Dedup;
+pub use device_filter::DeviceFilter;
+pub use din_sync::DinSyncBridge;
+pub use dma_ring::{DmaRingMidiIn, DmaRingNotifier, DmaRingReader};
+#[cfg(feature = "eh0")]
+pub use eh02::{Eh02Error, Eh02Rx, Eh02Tx};
+pub use endpoint::{MidiSink, MidiSource, RecordingMidiOut, SliceMidiIn, Tee, TeePolicy};
+pub use error::{
+    DecodeError, FullTable, MidiError, Overflow, ParseErrorKind, SinkError, SourceError, TooSmall,
+};
+pub use fixed_channel::FixedChannelOut;
+pub use frame::{FrameDecoder, FrameEncoder, FrameError, FrameStatus};
+pub use harmonizer::Harmonizer;
+#[cfg(feature = "host-midir")]
+pub use host::{MidirInputAdapter, MidirMidiIn, MidirMidiOut};
+#[cfg(feature = "io")]
+pub use io::{IoMidiIn, IoMidiOut};
+#[cfg(feature = "instrumentation")]
+pub use latency::{LatencyStats, LATENCY_BUCKETS};
+pub use learn::{Binding, LearnSource, MidiLearn};
+pub use lfo::{LfoRate, LfoTarget, MidiLfo, Waveform};
+pub use line_diagnostics::{LineDiagnostics, LineHealth};
+pub use local_control::LocalControl;
+pub use logger::{CompactMessage, MidiLogger, Timestamped};
+pub use matrix::{RouteFilter, RoutingMatrix};
+pub use merge::{MergeScheduler, RateConverter, WireRate};
+pub use metronome::Metronome;
+pub use microtune::MicrotuneAllocator;
+pub use mono::{MonoPriority, NotePriority, Transition};
+pub use mpe_downmix::{BendPolicy, MpeDownmix, MpeZone};
+pub use note_gate::NoteGate;
+pub use note_tracker::NoteTracker;
+pub use omni::Omni;
+pub use pc_debounce::PcDebounce;
+pub use position::{SongPosition, TimeSignature, TICKS_PER_MIDI_BEAT};
+pub use processor::MidiProcessor;
+pub use program_map::{ProgramMap, ProgramMapping};
+pub use quantize::Quantize;
+pub use ramp::{Ramp, RampShape, RampTarget};
+pub use render_sink::SliceSink;
+#[cfg(feature = "heapless")]
+pub use render_sink::VecSink;
+pub use rendered::RenderedMessage;
+pub use reset::{NoCallback, ResetPolicy};
+pub use router::{Cable, MidiRouter, PortId, Routed};
+pub use scale::{Scale, TieBreak};
+#[cfg(feature = "critical-section")]
+pub use shared::{MidiSender, SharedMidiOut};
+pub use sink::{FnSink, MessageSink, PriorityQueue, PrioritySink};
+#[cfg(feature = "smf")]
+pub use smf::{SmfError, SmfEvent, SmfReader, SmfWriter, TRACK_LENGTH_OFFSET};
+pub use startup::{StartupSequencer, StartupStatus};
+#[cfg(feature = "instrumentation")]
+pub use stats::{ParserStats, ParserStatsSnapshot};
+pub use step_recorder::{RecordMode, StepNote, StepRecorder};
+pub use stream::MidiStream;
+pub use stuck_note::StuckNoteGuard;
+pub use sysex::{SysexHandler, SysexStream};
+pub use sysex_router::{ManufacturerId, SysexRouter, MAX_SUB_ID_LEN};
+pub use takeover::{SoftTakeover, TakeoverMode};
+pub use tap::{TapMidiIn, TeeTransport};
+pub use tap_tempo::TapTempo;
+pub use thru::SoftThru;
+pub use timecode::{QuarterFrameExt, QuarterFrameType, SmpteType};
+pub use value14::Value14Ext;
+pub use velocity_switch::VelocitySwitch;
+pub use voice::{AssignMode, StealPolicy, VoiceAllocator, VoiceEvent, VoiceEventKind};
+
+/// Midi input parsing messages received on a serial port
+///
+/// Only messages of the families in the `FAMILIES` mask are parsed, see [`family`].
 #[derive(Debug)]
-pub struct MidiIn<RX> {
+pub struct MidiIn<RX, const FAMILIES: u16 = { family::ALL }> {
     rx: RX,
-    parser: MidiParser,
+    receiver: Receiver<FAMILIES>,
+    overruns: u32,
+    on_error: Option<fn(serial::ErrorKind)>,
+}
+
+/// `MidiIn` reading from a serial port trait object
+///
+/// Inputs on serial ports of different types share one copy of the input code instead of one per
+/// port type, which saves flash on devices with several ports.
+pub type DynMidiIn<'a, E> = MidiIn<&'a mut dyn serial::Read<u8, Error = E>>;
+
+/// Handling of the undefined status bytes 0xf4, 0xf5, 0xf9 and 0xfd
+///
+/// Either way 0xf4 and 0xf5 end the message they interrupt like other system common status bytes,
+/// while 0xf9 and 0xfd are real time bytes that don't interrupt a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UndefinedStatus {
+    #[default]
+    Drop,
+    /// Report them as `ParseEvent::UndefinedStatus` from `MidiIn::read_event`
+    Report,
+}
+
+/// Event read by `MidiIn::read_event`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseEvent {
+    Message(MidiMessage),
+    /// An undefined status byte, some devices use these for proprietary messages
+    UndefinedStatus(u8),
 }
 
 impl<RX, E> MidiIn<RX>
 where
     RX: serial::Read<u8, Error = E>,
-    E: Debug,
+    E: serial::Error,
 {
     pub fn new(rx: RX) -> Self {
+        Self::with_families(rx)
+    }
+}
+
+impl<RX, E, const FAMILIES: u16> MidiIn<RX, FAMILIES>
+where
+    RX: serial::Read<u8, Error = E>,
+    E: serial::Error,
+{
+    /// Create an input only parsing the message families in `FAMILIES`
+    pub fn with_families(rx: RX) -> Self {
         MidiIn {
             rx,
-            parser: MidiParser::new(),
+            receiver: Receiver::new(),
+            overruns: 0,
+            on_error: None,
         }
     }
 
-    pub fn read(&mut self) -> nb::Result<MidiMessage, E> {
-        let byte = self.rx.read()?;
+    /// Register a callback that is called with the kind of every serial error
+    pub fn set_on_error(&mut self, on_error: Option<fn(serial::ErrorKind)>) {
+        self.on_error = on_error;
+    }
+
+    /// Number of receive overruns since the input was created
+    pub fn overruns(&self) -> u32 {
+        self.overruns
+    }
+
+    /// Drop data bytes following a complete system common message, enabled by default
+    ///
+    /// Running status only applies to channel voice messages, so such data bytes are not part of
+    /// any message. With strict handling disabled they repeat the last quarter frame, song position
+    /// or song select message, as earlier versions did.
+    pub fn set_strict_system_common(&mut self, strict: bool) {
+        self.receiver.strict_system_common = strict;
+    }
+
+    /// Number of data bytes dropped because they followed a complete system common message
+    pub fn orphan_bytes(&self) -> u32 {
+        self.receiver.orphan_bytes
+    }
+
+    /// Drop undefined status bytes or report them from `read_event`, dropped by default
+    pub fn set_undefined_status(&mut self, handling: UndefinedStatus) {
+        self.receiver.undefined_status = handling;
+    }
+
+    /// Drop the message being received and the running status when a system reset arrives,
+    /// disabled by default
+    ///
+    /// Data bytes after the reset are then ignored until the next status byte, as the sender
+    /// starts over from a known state.
+    pub fn set_resync_on_reset(&mut self, resync: bool) {
+        self.receiver.resync_on_reset = resync;
+    }
 
-        match self.parser.parse(byte) {
-            Some(event) => Ok(event),
-            None => Err(nb::Error::WouldBlock),
+    /// Read a message, returns `MidiError::Overrun` when the serial port lost bytes
+    ///
+    /// After an overrun the parser is reset so it resyncs on the next status byte. Clearing the
+    /// overrun condition of the serial port itself is up to the HAL.
+    pub fn read(&mut self) -> nb::Result<MidiMessage, MidiError<E>> {
+        match self.read_event()? {
+            ParseEvent::Message(message) => Ok(message),
+            ParseEvent::UndefinedStatus(_) => Err(nb::Error::WouldBlock),
         }
     }
-}
 
-#[derive(Debug)]
-struct SerialTransport<TX>(TX);
+    /// Wait until a complete message was received, like `nb::block!(midi_in.read())`
+    ///
+    /// Spins while the serial port has no byte and while received bytes don't complete a message
+    /// yet. Errors are returned like `read` does.
+    pub fn read_blocking(&mut self) -> Result<MidiMessage, MidiError<E>> {
+        block!(self.read())
+    }
 
-impl<TX, E> MidiTransport for SerialTransport<TX>
-where
-    TX: serial::Write<u8, Error = E>,
-    E: Debug,
-{
-    type Error = E;
+    /// Read a message if one is complete, returns `Ok(None)` instead of `WouldBlock` for use in a
+    /// main loop
+    pub fn try_read(&mut self) -> Result<Option<MidiMessage>, MidiError<E>> {
+        match self.read() {
+            Ok(message) => Ok(Some(message)),
+            Err(nb::Error::WouldBlock) => Ok(None),
+            Err(nb::Error::Other(error)) => Err(error),
+        }
+    }
 
-    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
-        bytes
-            .iter()
-            .try_for_each(|value| block!(self.0.write(*value)))
+    /// Like `read`, also returning undefined status bytes when set to report them
+    pub fn read_event(&mut self) -> nb::Result<ParseEvent, MidiError<E>> {
+        let byte = self.read_byte()?;
+        self.receiver.push(byte).ok_or(nb::Error::WouldBlock)
+    }
+
+    /// Like `read`, also streaming system exclusive messages to `handler` in chunks
+    ///
+    /// An overrun aborts the system exclusive message being received.
+    pub fn read_sysex<const CHUNK: usize>(
+        &mut self,
+        now_ms: u32,
+        sysex: &mut SysexStream<CHUNK>,
+        handler: &mut impl SysexHandler,
+    ) -> nb::Result<MidiMessage, MidiError<E>> {
+        let byte = self.read_byte().inspect_err(|error| {
+            if matches!(error, nb::Error::Other(MidiError::Overrun)) {
+                sysex.abort(handler);
+            }
+        })?;
+        sysex.feed(now_ms, byte, handler);
+        match self.receiver.push(byte) {
+            Some(ParseEvent::Message(message)) => Ok(message),
+            _ => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    /// Read all bytes available from the serial port into `sink`, returns the number of messages
+    /// added
+    ///
+    /// Stops before reading another byte once the sink is full, so no message is lost. Messages
+    /// added before an error stay in the sink.
+    pub fn poll_into(&mut self, sink: &mut impl MessageSink) -> Result<usize, MidiError<E>> {
+        let mut count = 0;
+        while !sink.is_full() {
+            let byte = match self.read_byte() {
+                Ok(byte) => byte,
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(error)) => return Err(error),
+            };
+            if let Some(ParseEvent::Message(message)) = self.receiver.push(byte) {
+                sink.push(message);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn read_byte(&mut self) -> nb::Result<u8, MidiError<E>> {
+        self.rx.read().map_err(|error| {
+            error.map(|error| {
+                let kind = serial::Error::kind(&error);
+                if let Some(on_error) = self.on_error {
+                    on_error(kind);
+                }
+
+                if kind == serial::ErrorKind::Overrun {
+                    log_warn!("midi in: receive overrun, resyncing on the next status byte");
+                    self.receiver.resync();
+                    self.overruns = self.overruns.wrapping_add(1);
+                    MidiError::Overrun
+                } else {
+                    log_warn!("midi in: serial error {:?}", kind);
+                    MidiError::Serial(error)
+                }
+            })
+        })
     }
 }
 
+/// Policy for re-sending the status byte even when running status would allow eliding it
+///
+/// Some receivers, optically isolated ones in particular, lose track of the running status when
+/// the line idles and then misinterpret the next data-only message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefreshPolicy {
+    /// Re-send the status byte after it has been used for this many messages
+    EveryMessages(u16),
+    /// Re-send the status byte when no message was written for this many milliseconds, idle time
+    /// is measured using the timestamps passed to `MidiOut::write_at` and `MidiOut::tick`
+    IdleMs(u32),
+}
+
+/// `MidiOut` writing to a serial port trait object, see `DynMidiIn`
+pub type DynMidiOut<'a, E> = MidiOut<&'a mut dyn serial::Write<u8, Error = E>>;
+
+/// How `MidiOut` renders note offs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReleasePolicy {
+    /// Always send note offs as note off messages, keeping their release velocity
+    #[default]
+    PreserveNoteOff,
+    /// Send a note off with `default_velocity` as a note on with velocity 0 when that lets
+    /// running status leave out the status byte, note offs with any other release velocity are
+    /// sent unchanged
+    OptimizeRunningStatus { default_velocity: u8 },
+}
+
 #[derive(Debug)]
 pub struct MidiOut<TX> {
-    renderer: MidiRenderer<SerialTransport<TX>, true>,
+    tx: TX,
+    transmitter: Transmitter,
 }
 
 impl<TX, E> MidiOut<TX>
@@ -71,16 +444,145 @@ where
 {
     pub fn new(tx: TX) -> Self {
         MidiOut {
-            renderer: MidiRenderer::new(SerialTransport(tx)),
+            tx,
+            transmitter: Transmitter::default(),
         }
     }
 
     pub fn release(self) -> TX {
-        self.renderer.release().0
+        self.tx
+    }
+
+    /// Set a policy for periodically re-sending the status byte, `None` disables refreshing
+    pub fn set_status_refresh(&mut self, refresh: Option<RefreshPolicy>) {
+        self.transmitter.running_status.refresh = refresh;
+    }
+
+    /// Enable or disable running status, it is enabled by default
+    ///
+    /// The next message carries its status byte either way.
+    pub fn set_running_status(&mut self, enabled: bool) {
+        self.transmitter.running_status.disabled = !enabled;
+        self.transmitter.running_status.status = None;
+    }
+
+    /// Set how note offs are rendered, they are sent as note offs by default
+    pub fn set_release_policy(&mut self, policy: ReleasePolicy) {
+        self.transmitter.running_status.release = policy;
+    }
+
+    /// Send the status byte with the next message, like after a receiver was power cycled
+    pub fn reset_running_status(&mut self) {
+        log_debug!("midi out: running status reset");
+        self.transmitter.running_status.status = None;
+    }
+
+    /// Write a message, waiting for the serial port to accept every byte
+    pub fn write(&mut self, message: &MidiMessage) -> Result<(), MidiError<E>> {
+        self.write_counted(message).map(|_| ())
+    }
+
+    /// Same as `write`, named to pair with `MidiIn::read_blocking`
+    pub fn write_blocking(&mut self, message: &MidiMessage) -> Result<(), MidiError<E>> {
+        self.write(message)
+    }
+
+    /// Like `write`, returns the number of bytes written, which is one less when running status
+    /// elides the status byte
+    pub fn write_counted(&mut self, message: &MidiMessage) -> Result<usize, MidiError<E>> {
+        log_trace!("midi out: {:?}", message);
+        let written = self
+            .transmitter
+            .load(RenderedMessage::from(message).as_bytes());
+        self.send_loaded()?;
+        Ok(written)
+    }
+
+    /// Write the bytes of the message loaded into the transmitter
+    fn send_loaded(&mut self) -> Result<(), MidiError<E>> {
+        while let Poll::Ready(byte) = self.transmitter.pull() {
+            if let Err(error) = block!(self.tx.write(byte)) {
+                self.transmitter.fail();
+                return Err(MidiError::Serial(error));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a pre-rendered message, the status byte is always sent
+    pub fn write_rendered(&mut self, message: &RenderedMessage) -> Result<(), MidiError<E>> {
+        self.transmitter.load_complete(message.as_bytes());
+        self.send_loaded()
+    }
+
+    pub fn write_rendered_slice(
+        &mut self,
+        messages: &[RenderedMessage],
+    ) -> Result<(), MidiError<E>> {
+        messages
+            .iter()
+            .try_for_each(|message| self.write_rendered(message))
+    }
+
+    /// Write a system exclusive message, `data` are the bytes between 0xf0 and 0xf7
+    ///
+    /// Fails with `MidiError::ValueOutOfRange` without writing anything if a byte of `data` is not
+    /// a data byte.
+    pub fn write_sysex(&mut self, data: &[u8]) -> Result<(), MidiError<E>> {
+        if data.iter().any(|byte| *byte > 0x7f) {
+            return Err(MidiError::ValueOutOfRange);
+        }
+        self.write_sysex_bytes(data.iter().copied())
     }
 
-    pub fn write(&mut self, message: &MidiMessage) -> Result<(), E> {
-        self.renderer.render(message)
+    /// Write a system exclusive message with the data bytes produced by `data`
+    pub(crate) fn write_sysex_bytes(
+        &mut self,
+        data: impl Iterator<Item = u8>,
+    ) -> Result<(), MidiError<E>> {
+        log_trace!("midi out: system exclusive");
+        let tx = &mut self.tx;
+        let result = core::iter::once(0xf0)
+            .chain(data)
+            .chain(core::iter::once(0xf7))
+            .try_for_each(|byte| block!(tx.write(byte)));
+        self.transmitter.running_status.guard(result)?;
+        self.transmitter.running_status.update(&[0xf0], &[0xf0]);
+        Ok(())
+    }
+
+    /// Writing the last message failed part way through, the next message carries its status
+    /// byte
+    pub fn is_interrupted(&self) -> bool {
+        self.transmitter.running_status.interrupted
+    }
+
+    /// Write an end of exclusive byte, which makes receivers drop the rest of an interrupted
+    /// message without acting on it
+    ///
+    /// After a failed write the next message carries its status byte, which also re-frames the
+    /// receiver. Sending this first additionally ends a system exclusive message that was cut
+    /// off.
+    pub fn resync_sequence(&mut self) -> Result<(), MidiError<E>> {
+        let result = block!(self.tx.write(0xf7));
+        self.transmitter.running_status.guard(result)?;
+        self.transmitter.running_status.update(&[0xf7], &[0xf7]);
+        Ok(())
+    }
+
+    /// Write a message at the current time `now_ms`, used to measure idle time for
+    /// `RefreshPolicy::IdleMs`
+    pub fn write_at(&mut self, now_ms: u32, message: &MidiMessage) -> Result<(), MidiError<E>> {
+        self.tick(now_ms);
+        self.write(message)?;
+        self.transmitter.running_status.last_write_ms = Some(now_ms);
+        Ok(())
+    }
+
+    /// Let the output know the current time `now_ms` without writing, if the line has been idle
+    /// for too long the next message will carry its status byte again
+    pub fn tick(&mut self, now_ms: u32) {
+        self.transmitter.running_status.tick(now_ms);
     }
 }
 
@@ -88,21 +590,493 @@ where
 mod tests {
     extern crate std;
     use super::*;
+    use crate::message::{cc, note_off, note_on};
+    use crate::test_util::mock_writes;
+    use crate::test_util::FlakyWire;
     use embedded_hal_mock::eh1::serial;
+    use embedded_hal_nb::serial::ErrorKind;
     use std::vec::Vec;
 
     fn verify_writes(messages: &[MidiMessage], bytes: &[u8]) {
+        let mut midi_out = MidiOut::new(mock_writes(bytes));
+        for message in messages {
+            midi_out.write(message).unwrap();
+        }
+        let mut serial = midi_out.release();
+        serial.done();
+    }
+
+    fn verify_reads(bytes: &[u8], messages: &[MidiMessage]) {
         let expectations: Vec<serial::Transaction<u8>> = bytes
-            .into_iter()
-            .map(|byte| serial::Transaction::write(*byte))
+            .iter()
+            .map(|byte| serial::Transaction::read(*byte))
             .collect();
         let serial = serial::Mock::new(&expectations);
-        let mut midi_out = MidiOut::new(serial);
-        for message in messages {
+        let mut midi_in = MidiIn::new(serial);
+        let received: Vec<MidiMessage> = bytes.iter().filter_map(|_| midi_in.read().ok()).collect();
+        assert_eq!(received, messages, "reading {:x?}", bytes);
+        midi_in.rx.done();
+    }
+
+    #[test]
+    fn should_read_midi_message() {
+        verify_reads(
+            &[0x92, 0x76, 0x34],
+            &[MidiMessage::NoteOn(0x02.into(), 0x76.into(), 0x34.into())],
+        );
+    }
+
+    #[test]
+    fn should_read_blocking_through_would_block() {
+        let mut midi_in = MidiIn::new(serial::Mock::new(&[
+            serial::Transaction::read_error(nb::Error::WouldBlock),
+            serial::Transaction::read_many([0x92, 0x76]),
+            serial::Transaction::read_error(nb::Error::WouldBlock),
+            serial::Transaction::read_many([0x34, 0xf8, 0x77, 0x35]),
+            serial::Transaction::read_error(nb::Error::Other(ErrorKind::Parity)),
+        ]));
+
+        assert_eq!(midi_in.read_blocking(), Ok(note_on(2, 0x76, 0x34)));
+        assert_eq!(midi_in.read_blocking(), Ok(MidiMessage::TimingClock));
+        assert_eq!(midi_in.read_blocking(), Ok(note_on(2, 0x77, 0x35)));
+        assert_eq!(
+            midi_in.read_blocking(),
+            Err(MidiError::Serial(ErrorKind::Parity))
+        );
+        midi_in.rx.done();
+    }
+
+    #[test]
+    fn should_try_read_without_would_block() {
+        let mut midi_in = MidiIn::new(serial::Mock::new(&[
+            serial::Transaction::read_error(nb::Error::WouldBlock),
+            serial::Transaction::read_many([0x92, 0x76, 0x34]),
+            serial::Transaction::read_error(nb::Error::Other(ErrorKind::Overrun)),
+        ]));
+
+        let results: Vec<_> = (0..5).map(|_| midi_in.try_read()).collect();
+        assert_eq!(
+            results,
+            [
+                Ok(None),
+                Ok(None),
+                Ok(None),
+                Ok(Some(note_on(2, 0x76, 0x34))),
+                Err(MidiError::Overrun)
+            ]
+        );
+        midi_in.rx.done();
+    }
+
+    /// Sink holding at most `capacity` messages
+    struct LimitedSink {
+        messages: Vec<MidiMessage>,
+        capacity: usize,
+    }
+
+    impl MessageSink for LimitedSink {
+        fn is_full(&self) -> bool {
+            self.messages.len() == self.capacity
+        }
+
+        fn push(&mut self, message: MidiMessage) {
+            self.messages.push(message);
+        }
+    }
+
+    #[test]
+    fn should_poll_available_messages_into_sink() {
+        let mut midi_in = MidiIn::new(serial::Mock::new(&[
+            serial::Transaction::read_many([0x92, 0x76, 0x34, 0xf8, 0x77]),
+            serial::Transaction::read_error(nb::Error::WouldBlock),
+            serial::Transaction::read(0x35),
+            serial::Transaction::read_error(nb::Error::WouldBlock),
+        ]));
+        let mut received = Vec::new();
+        let mut sink = FnSink(|message| received.push(message));
+
+        assert_eq!(midi_in.poll_into(&mut sink), Ok(2));
+        assert_eq!(midi_in.poll_into(&mut sink), Ok(1));
+        assert_eq!(
+            received,
+            [
+                MidiMessage::NoteOn(0x02.into(), 0x76.into(), 0x34.into()),
+                MidiMessage::TimingClock,
+                MidiMessage::NoteOn(0x02.into(), 0x77.into(), 0x35.into()),
+            ]
+        );
+        midi_in.rx.done();
+    }
+
+    #[test]
+    fn should_stop_polling_when_sink_is_full() {
+        let mut midi_in = MidiIn::new(serial::Mock::new(&[
+            serial::Transaction::read_many([0xf8, 0x92, 0x76, 0x34, 0xf8]),
+            serial::Transaction::read_error(nb::Error::WouldBlock),
+        ]));
+        let mut sink = LimitedSink {
+            messages: Vec::new(),
+            capacity: 2,
+        };
+
+        assert_eq!(midi_in.poll_into(&mut sink), Ok(2));
+        assert_eq!(
+            sink.messages,
+            [
+                MidiMessage::TimingClock,
+                MidiMessage::NoteOn(0x02.into(), 0x76.into(), 0x34.into()),
+            ]
+        );
+
+        sink.messages.clear();
+        assert_eq!(midi_in.poll_into(&mut sink), Ok(1));
+        assert_eq!(sink.messages, [MidiMessage::TimingClock]);
+        midi_in.rx.done();
+    }
+
+    #[test]
+    fn should_read_timing_clock_inside_song_position_pointer() {
+        verify_reads(
+            &[0xf2, 0x12, 0xf8, 0x34],
+            &[
+                MidiMessage::TimingClock,
+                MidiMessage::SongPositionPointer((0x34, 0x12).into()),
+            ],
+        );
+    }
+
+    #[test]
+    fn should_drop_data_bytes_after_system_common_messages() {
+        let bytes = [
+            0xf1, 0x23, 0x24, 0xf2, 0x12, 0x34, 0x56, 0x78, 0xf3, 0x05, 0xf8, 0x06, 0xf6, 0x01,
+        ];
+        let expectations: Vec<serial::Transaction<u8>> = bytes
+            .iter()
+            .map(|byte| serial::Transaction::read(*byte))
+            .collect();
+        let mut midi_in = MidiIn::new(serial::Mock::new(&expectations));
+
+        let received: Vec<MidiMessage> = bytes.iter().filter_map(|_| midi_in.read().ok()).collect();
+        assert_eq!(
+            received,
+            [
+                MidiMessage::QuarterFrame(0x23.into()),
+                MidiMessage::SongPositionPointer((0x34, 0x12).into()),
+                MidiMessage::SongSelect(0x05.into()),
+                MidiMessage::TimingClock,
+                MidiMessage::TuneRequest,
+            ]
+        );
+        assert_eq!(midi_in.orphan_bytes(), 5);
+        midi_in.rx.done();
+    }
+
+    #[test]
+    fn should_clear_channel_running_status_after_system_common_message() {
+        verify_reads(
+            &[0x92, 0x40, 0x10, 0xf3, 0x05, 0x41, 0x11, 0x92, 0x42, 0x12],
+            &[
+                MidiMessage::NoteOn(0x02.into(), 0x40.into(), 0x10.into()),
+                MidiMessage::SongSelect(0x05.into()),
+                MidiMessage::NoteOn(0x02.into(), 0x42.into(), 0x12.into()),
+            ],
+        );
+    }
+
+    #[test]
+    fn should_repeat_system_common_messages_when_not_strict() {
+        let bytes = [0xf1, 0x23, 0x24, 0xf3, 0x05, 0x06];
+        let expectations: Vec<serial::Transaction<u8>> = bytes
+            .iter()
+            .map(|byte| serial::Transaction::read(*byte))
+            .collect();
+        let mut midi_in = MidiIn::new(serial::Mock::new(&expectations));
+        midi_in.set_strict_system_common(false);
+
+        let received: Vec<MidiMessage> = bytes.iter().filter_map(|_| midi_in.read().ok()).collect();
+        assert_eq!(
+            received,
+            [
+                MidiMessage::QuarterFrame(0x23.into()),
+                MidiMessage::QuarterFrame(0x24.into()),
+                MidiMessage::SongSelect(0x05.into()),
+                MidiMessage::SongSelect(0x06.into()),
+            ]
+        );
+        assert_eq!(midi_in.orphan_bytes(), 0);
+        midi_in.rx.done();
+    }
+
+    fn verify_filtered_reads<const FAMILIES: u16>(bytes: &[u8], messages: &[MidiMessage]) {
+        let expectations: Vec<serial::Transaction<u8>> = bytes
+            .iter()
+            .map(|byte| serial::Transaction::read(*byte))
+            .collect();
+        let mut midi_in = MidiIn::<_, FAMILIES>::with_families(serial::Mock::new(&expectations));
+        let received: Vec<MidiMessage> = bytes.iter().filter_map(|_| midi_in.read().ok()).collect();
+        assert_eq!(received, messages, "reading {:x?}", bytes);
+        midi_in.rx.done();
+    }
+
+    #[test]
+    fn should_skip_excluded_messages() {
+        verify_filtered_reads::<{ family::NOTES }>(
+            &[
+                0xe2, 0x12, 0x34, 0x56, 0x78, 0x92, 0x40, 0x10, 0xf2, 0x12, 0x34, 0x41, 0x11, 0xb2,
+                0x07, 0x40, 0x82, 0x40, 0x00,
+            ],
+            &[
+                MidiMessage::NoteOn(0x02.into(), 0x40.into(), 0x10.into()),
+                MidiMessage::NoteOff(0x02.into(), 0x40.into(), 0x00.into()),
+            ],
+        );
+    }
+
+    #[test]
+    fn should_skip_excluded_realtime_inside_included_messages() {
+        verify_filtered_reads::<{ family::PROGRAM_CHANGE }>(
+            &[0xc2, 0xf8, 0x05, 0xf8, 0x06, 0xfe],
+            &[
+                MidiMessage::ProgramChange(0x02.into(), 0x05.into()),
+                MidiMessage::ProgramChange(0x02.into(), 0x06.into()),
+            ],
+        );
+    }
+
+    #[test]
+    fn should_keep_excluded_messages_from_continuing_running_status() {
+        verify_filtered_reads::<{ family::NOTES | family::REALTIME }>(
+            &[0x92, 0x40, 0x10, 0xe2, 0xf8, 0x41, 0x11, 0x92, 0x42, 0x12],
+            &[
+                MidiMessage::NoteOn(0x02.into(), 0x40.into(), 0x10.into()),
+                MidiMessage::TimingClock,
+                MidiMessage::NoteOn(0x02.into(), 0x42.into(), 0x12.into()),
+            ],
+        );
+    }
+
+    #[test]
+    fn should_read_realtime_at_every_position_of_multi_byte_messages() {
+        let multi_byte: [(&[u8], MidiMessage); 10] = [
+            (
+                &[0x82, 0x76, 0x34],
+                MidiMessage::NoteOff(0x02.into(), 0x76.into(), 0x34.into()),
+            ),
+            (
+                &[0x92, 0x76, 0x34],
+                MidiMessage::NoteOn(0x02.into(), 0x76.into(), 0x34.into()),
+            ),
+            (
+                &[0xa2, 0x76, 0x34],
+                MidiMessage::KeyPressure(0x02.into(), 0x76.into(), 0x34.into()),
+            ),
+            (
+                &[0xb2, 0x07, 0x34],
+                MidiMessage::ControlChange(0x02.into(), 0x07.into(), 0x34.into()),
+            ),
+            (
+                &[0xc2, 0x05],
+                MidiMessage::ProgramChange(0x02.into(), 0x05.into()),
+            ),
+            (
+                &[0xd2, 0x34],
+                MidiMessage::ChannelPressure(0x02.into(), 0x34.into()),
+            ),
+            (
+                &[0xe2, 0x12, 0x34],
+                MidiMessage::PitchBendChange(0x02.into(), (0x34, 0x12).into()),
+            ),
+            (&[0xf1, 0x23], MidiMessage::QuarterFrame(0x23.into())),
+            (
+                &[0xf2, 0x12, 0x34],
+                MidiMessage::SongPositionPointer((0x34, 0x12).into()),
+            ),
+            (&[0xf3, 0x05], MidiMessage::SongSelect(0x05.into())),
+        ];
+        let realtime = [
+            (0xf8, MidiMessage::TimingClock),
+            (0xfa, MidiMessage::Start),
+            (0xfb, MidiMessage::Continue),
+            (0xfc, MidiMessage::Stop),
+            (0xfe, MidiMessage::ActiveSensing),
+            (0xff, MidiMessage::Reset),
+        ];
+
+        for (message_bytes, message) in multi_byte.iter() {
+            for (realtime_byte, realtime_message) in realtime.iter() {
+                for position in 1..message_bytes.len() {
+                    let mut bytes = message_bytes.to_vec();
+                    bytes.insert(position, *realtime_byte);
+                    verify_reads(&bytes, &[*realtime_message, *message]);
+                }
+            }
+        }
+    }
+
+    /// Every variant with its wire bytes, the match fails to compile when a variant is added so
+    /// it can't ship without an entry
+    fn every_variant() -> Vec<(MidiMessage, Vec<u8>)> {
+        let messages = [
+            MidiMessage::NoteOff(0x02.into(), 0x76.into(), 0x34.into()),
+            MidiMessage::NoteOn(0x02.into(), 0x76.into(), 0x34.into()),
+            MidiMessage::KeyPressure(0x02.into(), 0x76.into(), 0x34.into()),
+            MidiMessage::ControlChange(0x02.into(), 0x07.into(), 0x34.into()),
+            MidiMessage::ProgramChange(0x02.into(), 0x05.into()),
+            MidiMessage::ChannelPressure(0x02.into(), 0x34.into()),
+            MidiMessage::PitchBendChange(0x02.into(), (0x34, 0x12).into()),
+            MidiMessage::QuarterFrame(0x23.into()),
+            MidiMessage::SongPositionPointer((0x34, 0x12).into()),
+            MidiMessage::SongSelect(0x05.into()),
+            MidiMessage::TuneRequest,
+            MidiMessage::TimingClock,
+            MidiMessage::Start,
+            MidiMessage::Continue,
+            MidiMessage::Stop,
+            MidiMessage::ActiveSensing,
+            MidiMessage::Reset,
+        ];
+        messages
+            .iter()
+            .map(|message| {
+                let bytes: &[u8] = match message {
+                    MidiMessage::NoteOff(..) => &[0x82, 0x76, 0x34],
+                    MidiMessage::NoteOn(..) => &[0x92, 0x76, 0x34],
+                    MidiMessage::KeyPressure(..) => &[0xa2, 0x76, 0x34],
+                    MidiMessage::ControlChange(..) => &[0xb2, 0x07, 0x34],
+                    MidiMessage::ProgramChange(..) => &[0xc2, 0x05],
+                    MidiMessage::ChannelPressure(..) => &[0xd2, 0x34],
+                    // Least significant 7 bits first
+                    MidiMessage::PitchBendChange(..) => &[0xe2, 0x12, 0x34],
+                    MidiMessage::QuarterFrame(..) => &[0xf1, 0x23],
+                    MidiMessage::SongPositionPointer(..) => &[0xf2, 0x12, 0x34],
+                    MidiMessage::SongSelect(..) => &[0xf3, 0x05],
+                    MidiMessage::TuneRequest => &[0xf6],
+                    MidiMessage::TimingClock => &[0xf8],
+                    MidiMessage::Start => &[0xfa],
+                    MidiMessage::Continue => &[0xfb],
+                    MidiMessage::Stop => &[0xfc],
+                    MidiMessage::ActiveSensing => &[0xfe],
+                    MidiMessage::Reset => &[0xff],
+                };
+                (*message, bytes.to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_write_every_variant() {
+        for (message, bytes) in every_variant() {
+            verify_writes(&[message], &bytes);
+            assert_eq!(RenderedMessage::from(message).as_bytes(), bytes);
+            let (rendered, len) = message::to_bytes(&message);
+            assert_eq!(rendered[..usize::from(len)], bytes[..]);
+            assert_eq!(message.len(), bytes.len(), "{:?}", message);
+
+            // Pre-rendered messages always carry their status byte
+            let mut expected = bytes.clone();
+            expected.extend_from_slice(&bytes);
+            let mut midi_out = MidiOut::new(mock_writes(&expected));
+            let rendered = RenderedMessage::from(message);
+            midi_out
+                .write_rendered_slice(&[rendered, rendered])
+                .unwrap();
+            midi_out.release().done();
+
+            let mut midi_out = MidiOut::new(mock_writes(&bytes));
+            assert_eq!(midi_out.write_counted(&message), Ok(bytes.len()));
+            midi_out.release().done();
+        }
+    }
+
+    #[test]
+    fn should_keep_running_status_over_realtime_and_clear_it_with_system_common() {
+        let first = note_on(2, 0x40, 0x10);
+        let next = note_on(2, 0x41, 0x11);
+        for (message, bytes) in every_variant() {
+            let mut expected = Vec::from([0x92, 0x40, 0x10]);
+            match bytes[0] {
+                // The same status byte is left out, the next message continues it
+                0x92 => expected.extend_from_slice(&bytes[1..]),
+                // Real time messages leave the running status alone
+                0xf8..=0xff => expected.extend_from_slice(&bytes),
+                // Other channel messages and system common messages replace or clear it
+                _ => {
+                    expected.extend_from_slice(&bytes);
+                    expected.push(0x92);
+                }
+            }
+            expected.extend_from_slice(&[0x41, 0x11]);
+            verify_writes(&[first, message, next], &expected);
+
+            // Read back as the same messages
+            let expectations: Vec<serial::Transaction<u8>> = expected
+                .iter()
+                .map(|byte| serial::Transaction::read(*byte))
+                .collect();
+            let mut midi_in = MidiIn::new(serial::Mock::new(&expectations));
+            let received: Vec<MidiMessage> = expected
+                .iter()
+                .filter_map(|_| midi_in.read().ok())
+                .collect();
+            assert_eq!(received, [first, message, next], "{:x?}", expected);
+            midi_in.rx.done();
+        }
+    }
+
+    #[test]
+    fn should_write_every_variant_complete_without_running_status() {
+        for (message, bytes) in every_variant() {
+            let mut expected = bytes.clone();
+            expected.extend_from_slice(&bytes);
+            let mut midi_out = MidiOut::new(mock_writes(&expected));
+            midi_out.set_running_status(false);
             midi_out.write(&message).unwrap();
+            midi_out.write(&message).unwrap();
+            midi_out.release().done();
         }
-        let mut serial = midi_out.release();
-        serial.done();
+    }
+
+    fn read_events(handling: UndefinedStatus, bytes: &[u8]) -> Vec<ParseEvent> {
+        let expectations: Vec<serial::Transaction<u8>> = bytes
+            .iter()
+            .map(|byte| serial::Transaction::read(*byte))
+            .collect();
+        let mut midi_in = MidiIn::new(serial::Mock::new(&expectations));
+        midi_in.set_undefined_status(handling);
+        let events = bytes
+            .iter()
+            .filter_map(|_| midi_in.read_event().ok())
+            .collect();
+        midi_in.rx.done();
+        events
+    }
+
+    #[test]
+    fn should_not_interrupt_messages_with_undefined_realtime_bytes() {
+        let note = MidiMessage::NoteOn(0x02.into(), 0x76.into(), 0x34.into());
+        verify_reads(&[0x92, 0xf9, 0x76, 0xfd, 0x34], &[note]);
+        assert_eq!(
+            read_events(UndefinedStatus::Report, &[0x92, 0xf9, 0x76, 0xfd, 0x34]),
+            [
+                ParseEvent::UndefinedStatus(0xf9),
+                ParseEvent::UndefinedStatus(0xfd),
+                ParseEvent::Message(note),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_interrupt_messages_with_undefined_system_common_bytes() {
+        let bytes = [0x92, 0x76, 0xf4, 0x34, 0x92, 0x76, 0xf5, 0x34, 0x35];
+        verify_reads(&bytes, &[]);
+        assert_eq!(read_events(UndefinedStatus::Drop, &bytes), []);
+        assert_eq!(
+            read_events(UndefinedStatus::Report, &bytes),
+            [
+                ParseEvent::UndefinedStatus(0xf4),
+                ParseEvent::UndefinedStatus(0xf5),
+            ]
+        );
     }
 
     #[test]
@@ -123,4 +1097,372 @@ mod tests {
             &[0x92, 0x76, 0x34, 0x33, 0x65],
         );
     }
+
+    #[test]
+    fn should_write_blocking_like_write() {
+        let mut midi_out = MidiOut::new(mock_writes(&[0x92, 0x76, 0x34, 0x33, 0x65, 0xf8]));
+        midi_out.write_blocking(&note_on(2, 0x76, 0x34)).unwrap();
+        midi_out.write_blocking(&note_on(2, 0x33, 0x65)).unwrap();
+        midi_out.write_blocking(&MidiMessage::TimingClock).unwrap();
+        midi_out.release().done();
+    }
+
+    #[test]
+    fn should_count_written_bytes() {
+        let mut midi_out = MidiOut::new(mock_writes(&[
+            0x92, 0x76, 0x34, 0x33, 0x65, 0xf8, 0x82, 0x34, 0x00, 0x20, 0x10, 0xf6, 0x82, 0x21,
+            0x10,
+        ]));
+
+        assert_eq!(midi_out.write_counted(&note_on(2, 0x76, 0x34)), Ok(3));
+        assert_eq!(midi_out.write_counted(&note_on(2, 0x33, 0x65)), Ok(2));
+        assert_eq!(midi_out.write_counted(&MidiMessage::TimingClock), Ok(1));
+        assert_eq!(midi_out.write_counted(&note_off(2, 0x34, 0)), Ok(3));
+        assert_eq!(midi_out.write_counted(&note_off(2, 0x20, 0x10)), Ok(2));
+        assert_eq!(midi_out.write_counted(&MidiMessage::TuneRequest), Ok(1));
+        assert_eq!(midi_out.write_counted(&note_off(2, 0x21, 0x10)), Ok(3));
+        midi_out.release().done();
+    }
+
+    #[test]
+    fn should_write_sysex_and_reset_running_status() {
+        let mut midi_out = MidiOut::new(mock_writes(&[
+            0x92, 0x76, 0x34, 0xf0, 0x7d, 0x01, 0xf7, 0x92, 0x33, 0x65,
+        ]));
+
+        midi_out.write(&note_on(2, 0x76, 0x34)).unwrap();
+        midi_out.write_sysex(&[0x7d, 0x01]).unwrap();
+        assert_eq!(
+            midi_out.write_sysex(&[0x7d, 0x80]),
+            Err(MidiError::ValueOutOfRange)
+        );
+        midi_out.write(&note_on(2, 0x33, 0x65)).unwrap();
+        midi_out.release().done();
+    }
+
+    #[test]
+    fn should_resend_status_after_interrupted_message() {
+        let mut midi_out = MidiOut::new(FlakyWire {
+            failing: std::vec![4],
+            ..FlakyWire::default()
+        });
+
+        midi_out.write(&note_on(2, 0x76, 0x34)).unwrap();
+        assert_eq!(
+            midi_out.write(&note_on(2, 0x33, 0x65)),
+            Err(MidiError::Serial(ErrorKind::Other))
+        );
+        assert!(midi_out.is_interrupted());
+        midi_out.write(&note_on(2, 0x33, 0x65)).unwrap();
+        assert!(!midi_out.is_interrupted());
+        midi_out.write(&note_on(2, 0x20, 0x10)).unwrap();
+
+        let bytes = midi_out.release().bytes;
+        assert_eq!(
+            bytes,
+            [0x92, 0x76, 0x34, 0x33, 0x92, 0x33, 0x65, 0x20, 0x10]
+        );
+        let mut parser = parse::MidiParser::new();
+        let parsed: Vec<_> = bytes
+            .iter()
+            .filter_map(|byte| parser.parse(*byte))
+            .collect();
+        assert_eq!(
+            parsed,
+            [
+                note_on(2, 0x76, 0x34),
+                note_on(2, 0x33, 0x65),
+                note_on(2, 0x20, 0x10)
+            ]
+        );
+    }
+
+    #[test]
+    fn should_end_interrupted_sysex_with_resync_sequence() {
+        let mut midi_out = MidiOut::new(FlakyWire {
+            failing: std::vec![3],
+            ..FlakyWire::default()
+        });
+
+        assert!(midi_out.write_sysex(&[0x7d, 0x01, 0x02, 0x03]).is_err());
+        midi_out.resync_sequence().unwrap();
+        assert!(!midi_out.is_interrupted());
+        midi_out.write(&cc(0, 7, 100)).unwrap();
+        midi_out.write(&cc(0, 7, 90)).unwrap();
+
+        let bytes = midi_out.release().bytes;
+        assert_eq!(
+            bytes,
+            [0xf0, 0x7d, 0x01, 0xf7, 0xb0, 0x07, 0x64, 0x07, 0x5a]
+        );
+        let mut parser = parse::MidiParser::new();
+        let parsed: Vec<_> = bytes
+            .iter()
+            .filter_map(|byte| parser.parse(*byte))
+            .collect();
+        assert_eq!(parsed, [cc(0, 7, 100), cc(0, 7, 90)]);
+    }
+
+    #[test]
+    fn should_refresh_running_status_every_n_messages() {
+        let mut midi_out = MidiOut::new(mock_writes(&[
+            0x92, 0x76, 0x34, 0x33, 0x65, 0x92, 0x40, 0x10, 0x41, 0x11, 0x92, 0x42, 0x12,
+        ]));
+        midi_out.set_status_refresh(Some(RefreshPolicy::EveryMessages(2)));
+        for (note, velocity) in [
+            (0x76, 0x34),
+            (0x33, 0x65),
+            (0x40, 0x10),
+            (0x41, 0x11),
+            (0x42, 0x12),
+        ] {
+            let message = MidiMessage::NoteOn(0x02.into(), note.into(), velocity.into());
+            midi_out.write(&message).unwrap();
+        }
+        midi_out.release().done();
+    }
+
+    #[test]
+    fn should_share_code_through_trait_objects() {
+        let mut rx = serial::Mock::new(&[serial::Transaction::read_many([0xc1, 0x05])]);
+        let mut tx = mock_writes(&[0xc1, 0x05]);
+        let mut midi_in: DynMidiIn<'_, ErrorKind> = MidiIn::new(&mut rx);
+        let mut midi_out: DynMidiOut<'_, ErrorKind> = MidiOut::new(&mut tx);
+        let message = block!(midi_in.read()).unwrap();
+        midi_out.write(&message).unwrap();
+        rx.done();
+        tx.done();
+    }
+
+    fn release_messages() -> [MidiMessage; 5] {
+        [
+            note_on(0, 60, 100),
+            note_on(0, 64, 90),
+            note_off(0, 60, 64),
+            note_off(0, 64, 17),
+            note_off(0, 67, 64),
+        ]
+    }
+
+    #[test]
+    fn should_preserve_release_velocity_by_default() {
+        let mut midi_out = MidiOut::new(mock_writes(&[
+            0x90, 60, 100, 64, 90, 0x80, 60, 64, 64, 17, 67, 64,
+        ]));
+        for message in release_messages() {
+            midi_out.write(&message).unwrap();
+        }
+        midi_out.release().done();
+    }
+
+    #[test]
+    fn should_send_default_note_offs_as_note_ons_with_running_status() {
+        let mut midi_out = MidiOut::new(mock_writes(&[
+            0x90, 60, 100, 64, 90, 60, 0, 0x80, 64, 17, 67, 64,
+        ]));
+        midi_out.set_release_policy(ReleasePolicy::OptimizeRunningStatus {
+            default_velocity: 64,
+        });
+        for message in release_messages() {
+            midi_out.write(&message).unwrap();
+        }
+        midi_out.release().done();
+    }
+
+    #[test]
+    fn should_refresh_running_status_after_idle() {
+        let message = MidiMessage::NoteOn(0x02.into(), 0x76.into(), 0x34.into());
+        let mut midi_out = MidiOut::new(mock_writes(&[
+            0x92, 0x76, 0x34, 0x76, 0x34, 0x92, 0x76, 0x34, 0x92, 0x76, 0x34,
+        ]));
+        midi_out.set_status_refresh(Some(RefreshPolicy::IdleMs(100)));
+        midi_out.write_at(0, &message).unwrap();
+        midi_out.write_at(99, &message).unwrap();
+        midi_out.write_at(199, &message).unwrap();
+        midi_out.tick(300);
+        midi_out.write(&message).unwrap();
+        midi_out.release().done();
+    }
+
+    #[test]
+    fn should_not_refresh_running_status_by_default() {
+        let message = MidiMessage::NoteOn(0x02.into(), 0x76.into(), 0x34.into());
+        let mut midi_out = MidiOut::new(mock_writes(&[0x92, 0x76, 0x34, 0x76, 0x34, 0x76, 0x34]));
+        midi_out.write_at(0, &message).unwrap();
+        midi_out.write_at(1000, &message).unwrap();
+        midi_out.write(&message).unwrap();
+        midi_out.release().done();
+    }
+
+    #[test]
+    fn should_toggle_running_status_mid_stream() {
+        let message = MidiMessage::NoteOn(0x02.into(), 0x76.into(), 0x34.into());
+        let mut midi_out = MidiOut::new(mock_writes(&[
+            0x92, 0x76, 0x34, 0x76, 0x34, 0x92, 0x76, 0x34, 0x92, 0x76, 0x34, 0x92, 0x76, 0x34,
+            0x76, 0x34,
+        ]));
+        midi_out.write(&message).unwrap();
+        midi_out.write(&message).unwrap();
+        midi_out.set_running_status(false);
+        midi_out.write(&message).unwrap();
+        midi_out.write(&message).unwrap();
+        midi_out.set_running_status(true);
+        midi_out.write(&message).unwrap();
+        midi_out.write(&message).unwrap();
+        midi_out.release().done();
+    }
+
+    #[test]
+    fn should_resend_status_once_after_reset() {
+        let message = MidiMessage::NoteOn(0x02.into(), 0x76.into(), 0x34.into());
+        let mut midi_out = MidiOut::new(mock_writes(&[
+            0x92, 0x76, 0x34, 0x76, 0x34, 0x92, 0x76, 0x34, 0x76, 0x34,
+        ]));
+        midi_out.write(&message).unwrap();
+        midi_out.write(&message).unwrap();
+        midi_out.reset_running_status();
+        midi_out.write(&message).unwrap();
+        midi_out.write(&message).unwrap();
+        midi_out.release().done();
+    }
+
+    #[test]
+    fn should_keep_running_status_after_rendered_messages() {
+        let mut midi_out = MidiOut::new(mock_writes(&[
+            0x92, 0x76, 0x34, 0x92, 0x40, 0x10, 0x41, 0x11, 0xf3, 0x05, 0x92, 0x42, 0x12,
+        ]));
+        midi_out
+            .write(&MidiMessage::NoteOn(0x02.into(), 0x76.into(), 0x34.into()))
+            .unwrap();
+        midi_out
+            .write_rendered(&RenderedMessage::note_on(0x02, 0x40, 0x10))
+            .unwrap();
+        midi_out
+            .write(&MidiMessage::NoteOn(0x02.into(), 0x41.into(), 0x11.into()))
+            .unwrap();
+        midi_out
+            .write_rendered_slice(&[RenderedMessage::song_select(0x05)])
+            .unwrap();
+        midi_out
+            .write(&MidiMessage::NoteOn(0x02.into(), 0x42.into(), 0x12.into()))
+            .unwrap();
+        midi_out.release().done();
+    }
+
+    #[test]
+    fn should_be_send_when_transport_is_send() {
+        fn assert_send<T: Send>() {}
+        fn assert_sync<T: Sync>() {}
+
+        assert_send::<MidiIn<serial::Mock<u8>>>();
+        assert_send::<MidiOut<serial::Mock<u8>>>();
+        assert_send::<FixedChannelOut<serial::Mock<u8>>>();
+        assert_send::<Omni>();
+        assert_send::<VoiceAllocator<8>>();
+        assert_send::<RenderedMessage>();
+        assert_send::<MidiError<core::convert::Infallible>>();
+        assert_sync::<RenderedMessage>();
+
+        #[cfg(feature = "critical-section")]
+        {
+            assert_send::<SharedMidiOut<serial::Mock<u8>, 16>>();
+            assert_sync::<SharedMidiOut<serial::Mock<u8>, 16>>();
+            assert_send::<MidiSender<'static, serial::Mock<u8>, 16>>();
+        }
+    }
+
+    #[test]
+    fn should_use_value_types_with_value_semantics() {
+        use midi_types::{Channel, Control, Note, Program, QuarterFrame, Value14, Value7};
+
+        fn assert_value<T: Copy + Eq + Debug>() {}
+
+        assert_value::<MidiMessage>();
+        assert_value::<Channel>();
+        assert_value::<Note>();
+        assert_value::<Control>();
+        assert_value::<Program>();
+        assert_value::<Value7>();
+        assert_value::<Value14>();
+        assert_value::<QuarterFrame>();
+    }
+
+    #[test]
+    fn should_only_write_valid_bytes_for_out_of_range_values() {
+        use midi_types::{Channel, Control, Note, Program, QuarterFrame, Value14, Value7};
+
+        // The value types clamp out of range values, so messages can't carry invalid bytes
+        let messages = [
+            MidiMessage::NoteOn(Channel::new(0xff), Note::new(0xff), Value7::new(0xff)),
+            MidiMessage::ControlChange(Channel::new(0x10), Control::new(0x80), Value7::new(0x80)),
+            MidiMessage::ProgramChange(Channel::new(0x10), Program::new(0xff)),
+            MidiMessage::PitchBendChange(Channel::new(0x10), Value14::from(0xffffu16)),
+            MidiMessage::QuarterFrame(QuarterFrame::new(0xff)),
+        ];
+        for message in messages.iter() {
+            let rendered = RenderedMessage::from(message);
+            let (status, data) = rendered.as_bytes().split_first().unwrap();
+
+            assert!(*status >= 0x80, "{:?}", message);
+            assert!(data.iter().all(|byte| *byte <= 0x7f), "{:?}", message);
+        }
+        assert_eq!(
+            RenderedMessage::from(messages[0]).as_bytes(),
+            [0x9f, 0x7f, 0x7f]
+        );
+    }
+
+    #[test]
+    fn should_resync_after_overrun() {
+        let expectations = [
+            serial::Transaction::read(0x92),
+            serial::Transaction::read(0x76),
+            serial::Transaction::read_error(nb::Error::Other(ErrorKind::Overrun)),
+            serial::Transaction::read(0x34),
+            serial::Transaction::read(0x93),
+            serial::Transaction::read(0x40),
+            serial::Transaction::read(0x10),
+        ];
+        let mut midi_in = MidiIn::new(serial::Mock::new(&expectations));
+
+        let results: Vec<_> = expectations.iter().map(|_| midi_in.read()).collect();
+        assert_eq!(
+            results,
+            [
+                Err(nb::Error::WouldBlock),
+                Err(nb::Error::WouldBlock),
+                Err(nb::Error::Other(MidiError::Overrun)),
+                Err(nb::Error::WouldBlock),
+                Err(nb::Error::WouldBlock),
+                Err(nb::Error::WouldBlock),
+                Ok(MidiMessage::NoteOn(0x03.into(), 0x40.into(), 0x10.into())),
+            ]
+        );
+        assert_eq!(midi_in.overruns(), 1);
+        midi_in.rx.done();
+    }
+
+    #[test]
+    fn should_report_serial_errors() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+        static ERRORS: AtomicU32 = AtomicU32::new(0);
+
+        let expectations = [
+            serial::Transaction::read_error(nb::Error::Other(ErrorKind::Parity)),
+            serial::Transaction::read_error(nb::Error::Other(ErrorKind::Overrun)),
+        ];
+        let mut midi_in = MidiIn::new(serial::Mock::new(&expectations));
+        midi_in.set_on_error(Some(|_| {
+            ERRORS.fetch_add(1, Ordering::Relaxed);
+        }));
+
+        assert_eq!(
+            midi_in.read(),
+            Err(nb::Error::Other(MidiError::Serial(ErrorKind::Parity)))
+        );
+        assert_eq!(midi_in.read(), Err(nb::Error::Other(MidiError::Overrun)));
+        assert_eq!(ERRORS.load(Ordering::Relaxed), 2);
+        assert_eq!(midi_in.overruns(), 1);
+        midi_in.rx.done();
+    }
 }
@@ -0,0 +1,271 @@
+//! Guessing the cause of receive errors on a midi input
+
+use crate::parse::data_length;
+use embedded_hal_nb::serial::{self, ErrorKind};
+
+/// Bytes and errors seen before anything but `LineHealth::NoSignal` is diagnosed
+const MIN_SAMPLES: u32 = 32;
+/// Framing, parity and noise errors suggesting a baud rate or polarity problem
+const MISMATCH_ERRORS: u32 = 8;
+/// Overruns suggesting the input isn't read often enough
+const OVERLOAD_OVERRUNS: u32 = 4;
+/// Corrupted messages or line errors suggesting a marginal connection
+const MARGINAL_ERRORS: u32 = 4;
+
+/// Likely state of a midi input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineHealth {
+    /// Nothing was received
+    NoSignal,
+    /// Too little was received to tell
+    Unknown,
+    /// Messages arrive intact
+    Healthy,
+    /// Mostly framing errors and hardly any intact message, the baud rate is wrong or the
+    /// optocoupler is wired the wrong way around
+    BaudOrPolarity,
+    /// Single byte real time messages arrive but longer messages are corrupted, or line errors
+    /// show up between intact messages, the cable or optocoupler circuit is marginal
+    MarginalCable,
+    /// Bytes are lost because the input isn't read often enough
+    Overloaded,
+}
+
+/// Counts received bytes and classified serial errors to diagnose problems with a midi input
+///
+/// Feed it every byte and error read from the serial port, for example with `record_read`, and
+/// check `diagnosis` after enough traffic.
+#[derive(Debug, Clone, Default)]
+pub struct LineDiagnostics {
+    bytes: u32,
+    realtime: u32,
+    messages: u32,
+    corrupted: u32,
+    framing_errors: u32,
+    noise_errors: u32,
+    overruns: u32,
+    /// Status of the message being received and its missing data bytes
+    status: u8,
+    missing: u8,
+}
+
+impl LineDiagnostics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the result of a serial read, `WouldBlock` is ignored
+    pub fn record_read<E: serial::Error>(&mut self, result: &nb::Result<u8, E>) {
+        match result {
+            Ok(byte) => self.record_byte(*byte),
+            Err(nb::Error::Other(error)) => self.record_error(error.kind()),
+            Err(nb::Error::WouldBlock) => {}
+        }
+    }
+
+    pub fn record_byte(&mut self, byte: u8) {
+        self.bytes = self.bytes.wrapping_add(1);
+        match byte {
+            0xf8..=0xff => self.realtime = self.realtime.wrapping_add(1),
+            0x80..=0xf7 => {
+                if self.missing > 0 {
+                    self.corrupt();
+                }
+                self.status = byte;
+                self.missing = data_length(byte);
+                if byte == 0xf6 {
+                    self.messages = self.messages.wrapping_add(1);
+                }
+            }
+            _ => match self.status {
+                // Running status starts another message
+                0x80..=0xef if self.missing == 0 => {
+                    self.missing = data_length(self.status) - 1;
+                    self.complete();
+                }
+                _ if self.missing > 0 => {
+                    self.missing -= 1;
+                    self.complete();
+                }
+                // System exclusive data
+                0xf0 => {}
+                _ => self.corrupt(),
+            },
+        }
+    }
+
+    pub fn record_error(&mut self, kind: ErrorKind) {
+        match kind {
+            ErrorKind::Overrun => self.overruns = self.overruns.wrapping_add(1),
+            ErrorKind::FrameFormat => self.framing_errors = self.framing_errors.wrapping_add(1),
+            _ => self.noise_errors = self.noise_errors.wrapping_add(1),
+        }
+        // The byte of the message being received is lost
+        if self.missing > 0 {
+            self.corrupt();
+        }
+    }
+
+    fn complete(&mut self) {
+        if self.missing == 0 {
+            self.messages = self.messages.wrapping_add(1);
+        }
+    }
+
+    /// Count a message that lost bytes and wait for the next status byte
+    fn corrupt(&mut self) {
+        self.corrupted = self.corrupted.wrapping_add(1);
+        self.status = 0;
+        self.missing = 0;
+    }
+
+    /// Number of bytes received
+    pub fn bytes(&self) -> u32 {
+        self.bytes
+    }
+
+    /// Number of real time bytes received
+    pub fn realtime(&self) -> u32 {
+        self.realtime
+    }
+
+    /// Number of complete messages received, other than real time messages
+    pub fn messages(&self) -> u32 {
+        self.messages
+    }
+
+    /// Number of messages that lost bytes or data bytes without a status
+    pub fn corrupted(&self) -> u32 {
+        self.corrupted
+    }
+
+    pub fn framing_errors(&self) -> u32 {
+        self.framing_errors
+    }
+
+    /// Number of parity, noise and other errors
+    pub fn noise_errors(&self) -> u32 {
+        self.noise_errors
+    }
+
+    pub fn overruns(&self) -> u32 {
+        self.overruns
+    }
+
+    /// Most likely state of the input from the bytes and errors recorded
+    pub fn diagnosis(&self) -> LineHealth {
+        let line_errors = self.framing_errors.saturating_add(self.noise_errors);
+        let samples = self.bytes.saturating_add(line_errors);
+        if samples == 0 && self.overruns == 0 {
+            LineHealth::NoSignal
+        } else if samples < MIN_SAMPLES {
+            LineHealth::Unknown
+        } else if line_errors >= MISMATCH_ERRORS
+            && (self.messages == 0 || line_errors >= self.bytes / 4)
+        {
+            LineHealth::BaudOrPolarity
+        } else if self.overruns >= OVERLOAD_OVERRUNS {
+            LineHealth::Overloaded
+        } else if line_errors >= MARGINAL_ERRORS
+            || (self.corrupted >= MARGINAL_ERRORS && self.corrupted >= self.messages / 20)
+        {
+            LineHealth::MarginalCable
+        } else {
+            LineHealth::Healthy
+        }
+    }
+
+    /// Forget everything recorded
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::vec::Vec;
+
+    #[derive(Debug, Clone, Copy)]
+    enum Event {
+        Bytes(&'static [u8], usize),
+        Error(ErrorKind, usize),
+    }
+
+    fn diagnose(pattern: &[Event]) -> LineHealth {
+        let mut diagnostics = LineDiagnostics::new();
+        for event in pattern {
+            match *event {
+                Event::Bytes(bytes, times) => {
+                    for _ in 0..times {
+                        bytes.iter().for_each(|byte| diagnostics.record_byte(*byte));
+                    }
+                }
+                Event::Error(kind, times) => {
+                    (0..times).for_each(|_| diagnostics.record_error(kind));
+                }
+            }
+        }
+        diagnostics.diagnosis()
+    }
+
+    #[test]
+    fn should_diagnose_error_patterns() {
+        use Event::*;
+        const NOTES: &[u8] = &[0x90, 0x40, 0x7f, 0x40, 0x00];
+        const CLOCK: &[u8] = &[0xf8];
+        // A note on losing its last byte, followed by a note off
+        const CUT_NOTE: &[u8] = &[0x90, 0x40, 0x80, 0x40, 0x00];
+        // What 31250 baud looks like to a port at another rate
+        const GARBAGE: &[u8] = &[0x00, 0x7e, 0xff, 0x3c, 0x00];
+
+        #[rustfmt::skip]
+        let table: &[(&[Event], LineHealth)] = &[
+            (&[], LineHealth::NoSignal),
+            (&[Bytes(NOTES, 4)], LineHealth::Unknown),
+            (&[Bytes(NOTES, 50), Bytes(CLOCK, 100)], LineHealth::Healthy),
+            (&[Error(ErrorKind::FrameFormat, 40), Bytes(GARBAGE, 10)], LineHealth::BaudOrPolarity),
+            (&[Error(ErrorKind::FrameFormat, 10)], LineHealth::Unknown),
+            (&[Error(ErrorKind::FrameFormat, 32)], LineHealth::BaudOrPolarity),
+            (&[Bytes(NOTES, 20), Error(ErrorKind::FrameFormat, 40), Bytes(NOTES, 5)], LineHealth::BaudOrPolarity),
+            (&[Bytes(CLOCK, 200), Bytes(CUT_NOTE, 10), Bytes(NOTES, 20)], LineHealth::MarginalCable),
+            (&[Bytes(NOTES, 200), Error(ErrorKind::Parity, 5)], LineHealth::MarginalCable),
+            (&[Bytes(NOTES, 200), Error(ErrorKind::Noise, 1), Bytes(CUT_NOTE, 1)], LineHealth::Healthy),
+            (&[Bytes(NOTES, 100), Error(ErrorKind::Overrun, 6), Bytes(NOTES, 100)], LineHealth::Overloaded),
+            (&[Bytes(NOTES, 100), Error(ErrorKind::Overrun, 2)], LineHealth::Healthy),
+        ];
+
+        let diagnoses: Vec<_> = table.iter().map(|(pattern, _)| diagnose(pattern)).collect();
+        let expected: Vec<_> = table.iter().map(|(_, health)| *health).collect();
+        assert_eq!(diagnoses, expected);
+    }
+
+    #[test]
+    fn should_count_messages_and_corruption() {
+        let mut diagnostics = LineDiagnostics::new();
+        let bytes = [
+            0x90, 0x40, 0xf8, 0x7f, 0x41, 0x7f, 0xc0, 0x05, 0xb0, 0x07, 0x90, 0x42, 0x00, 0x10,
+            0xf0, 0x01, 0x02, 0xf7, 0xf6,
+        ];
+        for byte in bytes.iter().take(12) {
+            diagnostics.record_read::<ErrorKind>(&Ok(*byte));
+        }
+        diagnostics.record_read(&Err(nb::Error::Other(ErrorKind::FrameFormat)));
+        diagnostics.record_read::<ErrorKind>(&Err(nb::Error::WouldBlock));
+        for byte in bytes.iter().skip(12) {
+            diagnostics.record_byte(*byte);
+        }
+
+        assert_eq!(diagnostics.bytes(), 19);
+        assert_eq!(diagnostics.realtime(), 1);
+        // Two note ons, the program change and the tune request
+        assert_eq!(diagnostics.messages(), 4);
+        // The cut control change, the note on losing a byte and its two orphan data bytes
+        assert_eq!(diagnostics.corrupted(), 4);
+        assert_eq!(diagnostics.framing_errors(), 1);
+
+        diagnostics.reset();
+        assert_eq!(diagnostics.diagnosis(), LineHealth::NoSignal);
+    }
+}
@@ -0,0 +1,175 @@
+//! Local control handling for keyboards with a built in sound engine
+
+use crate::NoteTracker;
+use midi_convert::midi_types::{Channel, MidiMessage};
+
+/// Control number of the Local Control channel mode message
+const LOCAL_CONTROL: u8 = 122;
+
+/// Connects a keybed, an internal sound engine and the midi ports according to Local Control
+///
+/// Keybed messages always go to midi out and only go to the engine while local control is on.
+/// Messages from midi in always go to the engine. Local Control messages received on the base
+/// channel switch local control on or off. When local control is switched off the notes the
+/// keybed is holding in the engine are released.
+#[derive(Debug, Clone)]
+pub struct LocalControl {
+    local: bool,
+    base_channel: Channel,
+    engine_notes: NoteTracker,
+}
+
+impl LocalControl {
+    /// Create with local control on
+    pub fn new(base_channel: Channel) -> Self {
+        LocalControl {
+            local: true,
+            base_channel,
+            engine_notes: NoteTracker::new(),
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.local
+    }
+
+    /// Switch local control on or off, releasing notes held by the keybed in the engine when
+    /// switching off
+    pub fn set_local(&mut self, local: bool, to_engine: &mut dyn FnMut(MidiMessage)) {
+        if self.local && !local {
+            self.engine_notes.release_all(to_engine);
+        }
+        self.local = local;
+    }
+
+    /// Handle a message from the keybed
+    pub fn from_keybed(
+        &mut self,
+        message: MidiMessage,
+        to_engine: &mut dyn FnMut(MidiMessage),
+        to_out: &mut dyn FnMut(MidiMessage),
+    ) {
+        to_out(message);
+
+        match message {
+            MidiMessage::NoteOn(channel, note, velocity) if u8::from(velocity) > 0 => {
+                if !self.local {
+                    return;
+                }
+                self.engine_notes.note_on(channel, note);
+            }
+            // Only release notes the keybed started in the engine, local control might have been
+            // switched in between
+            MidiMessage::NoteOn(channel, note, _) | MidiMessage::NoteOff(channel, note, _) => {
+                if !self.engine_notes.is_held(channel, note) {
+                    return;
+                }
+                self.engine_notes.note_off(channel, note);
+            }
+            _ if !self.local => return,
+            _ => {}
+        }
+        to_engine(message);
+    }
+
+    /// Handle a message from midi in
+    pub fn from_midi_in(&mut self, message: MidiMessage, to_engine: &mut dyn FnMut(MidiMessage)) {
+        match message {
+            MidiMessage::ControlChange(channel, control, value)
+                if channel == self.base_channel && u8::from(control) == LOCAL_CONTROL =>
+            {
+                self.set_local(u8::from(value) >= 64, to_engine)
+            }
+            _ => to_engine(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_off, note_on};
+    use std::vec::Vec;
+
+    #[derive(Default)]
+    struct Outputs {
+        engine: Vec<MidiMessage>,
+        out: Vec<MidiMessage>,
+    }
+
+    impl Outputs {
+        fn keybed(&mut self, local: &mut LocalControl, message: MidiMessage) {
+            let Outputs { engine, out } = self;
+            local.from_keybed(
+                message,
+                &mut |message| engine.push(message),
+                &mut |message| out.push(message),
+            );
+        }
+
+        fn midi_in(&mut self, local: &mut LocalControl, message: MidiMessage) {
+            local.from_midi_in(message, &mut |message| self.engine.push(message));
+        }
+    }
+
+    #[test]
+    fn should_route_keybed_to_engine_and_out_when_local() {
+        let mut local = LocalControl::new(Channel::C1);
+        let mut outputs = Outputs::default();
+
+        outputs.keybed(&mut local, note_on(0, 60, 100));
+        outputs.midi_in(&mut local, note_on(1, 62, 100));
+
+        assert_eq!(outputs.engine, [note_on(0, 60, 100), note_on(1, 62, 100)]);
+        assert_eq!(outputs.out, [note_on(0, 60, 100)]);
+    }
+
+    #[test]
+    fn should_release_held_notes_when_switching_local_off() {
+        let mut local = LocalControl::new(Channel::C1);
+        let mut outputs = Outputs::default();
+
+        outputs.keybed(&mut local, note_on(0, 60, 100));
+        outputs.keybed(&mut local, note_on(0, 64, 100));
+        outputs.midi_in(&mut local, cc(0, LOCAL_CONTROL, 0));
+        assert!(!local.is_local());
+        outputs.keybed(&mut local, note_on(0, 67, 100));
+        outputs.keybed(&mut local, note_off(0, 60, 0));
+        outputs.midi_in(&mut local, cc(0, LOCAL_CONTROL, 127));
+        outputs.keybed(&mut local, note_off(0, 64, 0));
+        outputs.keybed(&mut local, note_off(0, 67, 0));
+
+        assert_eq!(
+            outputs.engine,
+            [
+                note_on(0, 60, 100),
+                note_on(0, 64, 100),
+                note_off(0, 60, 0),
+                note_off(0, 64, 0),
+            ]
+        );
+        assert_eq!(
+            outputs.out,
+            [
+                note_on(0, 60, 100),
+                note_on(0, 64, 100),
+                note_on(0, 67, 100),
+                note_off(0, 60, 0),
+                note_off(0, 64, 0),
+                note_off(0, 67, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_ignore_local_control_on_other_channels() {
+        let mut local = LocalControl::new(Channel::C1);
+        let mut outputs = Outputs::default();
+
+        outputs.midi_in(&mut local, cc(3, LOCAL_CONTROL, 0));
+
+        assert!(local.is_local());
+        assert_eq!(outputs.engine, [cc(3, LOCAL_CONTROL, 0)]);
+    }
+}
@@ -0,0 +1,252 @@
+//! Recording the last messages for post-mortem debugging
+
+use crate::{
+    parse::{data_length, MidiParser},
+    RenderedMessage,
+};
+use midi_convert::midi_types::MidiMessage;
+
+/// A midi message packed into its 3 byte wire format
+///
+/// Unused data bytes are 0. The status byte is always present, so the number of data bytes follows
+/// from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactMessage {
+    bytes: [u8; 3],
+}
+
+impl CompactMessage {
+    /// The wire format, including the status byte
+    pub fn as_bytes(&self) -> &[u8] {
+        let len = data_length(self.bytes[0]) as usize + 1;
+        &self.bytes[..len]
+    }
+
+    pub fn to_message(&self) -> MidiMessage {
+        let mut parser = MidiParser::new();
+        let mut message = None;
+        for byte in self.as_bytes() {
+            message = parser.parse(*byte);
+        }
+        // Compact messages are only built from complete messages
+        message.unwrap_or(MidiMessage::Reset)
+    }
+}
+
+impl From<&MidiMessage> for CompactMessage {
+    fn from(message: &MidiMessage) -> Self {
+        let (bytes, _) = RenderedMessage::from(message).to_parts();
+        CompactMessage { bytes }
+    }
+}
+
+impl From<MidiMessage> for CompactMessage {
+    fn from(message: MidiMessage) -> Self {
+        (&message).into()
+    }
+}
+
+impl From<CompactMessage> for MidiMessage {
+    fn from(message: CompactMessage) -> Self {
+        message.to_message()
+    }
+}
+
+/// A value with the time it was recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamped<T> {
+    pub timestamp: u32,
+    pub message: T,
+}
+
+/// Ring buffer of the last `N` messages with their timestamps
+///
+/// Messages are stored as `CompactMessage`, once full the oldest message is overwritten.
+#[derive(Debug, Clone)]
+pub struct MidiLogger<const N: usize> {
+    entries: [Timestamped<CompactMessage>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> Default for MidiLogger<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> MidiLogger<N> {
+    pub fn new() -> Self {
+        MidiLogger {
+            entries: [Timestamped {
+                timestamp: 0,
+                message: CompactMessage::from(MidiMessage::Reset),
+            }; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Record `message` at time `now`, overwriting the oldest message when full
+    pub fn record(&mut self, now: u32, message: &MidiMessage) {
+        if N == 0 {
+            return;
+        }
+        self.entries[(self.head + self.len) % N] = Timestamped {
+            timestamp: now,
+            message: message.into(),
+        };
+        if self.len == N {
+            self.head = (self.head + 1) % N;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    /// Recorded messages, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &Timestamped<CompactMessage>> + '_ {
+        (0..self.len).map(move |index| &self.entries[(self.head + index) % N])
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Call `emit` with every recorded message, oldest first
+    pub fn dump(&self, emit: &mut impl FnMut(&Timestamped<MidiMessage>)) {
+        for entry in self.iter() {
+            emit(&Timestamped {
+                timestamp: entry.timestamp,
+                message: entry.message.to_message(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_on};
+    use midi_convert::midi_types::{Channel, Value14};
+    use std::vec::Vec;
+
+    fn verify_round_trip(message: MidiMessage) {
+        let compact = CompactMessage::from(message);
+        assert_eq!(compact.to_message(), message);
+        assert_eq!(
+            compact.as_bytes(),
+            RenderedMessage::from(message).as_bytes()
+        );
+    }
+
+    #[test]
+    fn should_round_trip_channel_voice_messages() {
+        for channel in 0..16u8 {
+            let channel = Channel::from(channel);
+            for first in 0..128u8 {
+                verify_round_trip(MidiMessage::ProgramChange(channel, first.into()));
+                verify_round_trip(MidiMessage::ChannelPressure(channel, first.into()));
+                for second in 0..128u8 {
+                    verify_round_trip(MidiMessage::NoteOff(channel, first.into(), second.into()));
+                    verify_round_trip(MidiMessage::NoteOn(channel, first.into(), second.into()));
+                    verify_round_trip(MidiMessage::KeyPressure(
+                        channel,
+                        first.into(),
+                        second.into(),
+                    ));
+                    verify_round_trip(MidiMessage::ControlChange(
+                        channel,
+                        first.into(),
+                        second.into(),
+                    ));
+                }
+            }
+            for value in 0..0x4000u16 {
+                verify_round_trip(MidiMessage::PitchBendChange(channel, Value14::from(value)));
+            }
+        }
+    }
+
+    #[test]
+    fn should_round_trip_system_messages() {
+        for value in 0..128u8 {
+            verify_round_trip(MidiMessage::QuarterFrame(value.into()));
+            verify_round_trip(MidiMessage::SongSelect(value.into()));
+        }
+        for value in 0..0x4000u16 {
+            verify_round_trip(MidiMessage::SongPositionPointer(Value14::from(value)));
+        }
+        for message in [
+            MidiMessage::TuneRequest,
+            MidiMessage::TimingClock,
+            MidiMessage::Start,
+            MidiMessage::Continue,
+            MidiMessage::Stop,
+            MidiMessage::ActiveSensing,
+            MidiMessage::Reset,
+        ] {
+            verify_round_trip(message);
+        }
+    }
+
+    #[test]
+    fn should_keep_last_messages_oldest_first() {
+        let mut logger = MidiLogger::<3>::new();
+        for note in 0..5 {
+            logger.record(note as u32 * 10, &note_on(0, note, 100));
+        }
+
+        let mut dumped = Vec::new();
+        logger.dump(&mut |entry| dumped.push(*entry));
+        assert_eq!(
+            dumped,
+            [
+                Timestamped {
+                    timestamp: 20,
+                    message: note_on(0, 2, 100)
+                },
+                Timestamped {
+                    timestamp: 30,
+                    message: note_on(0, 3, 100)
+                },
+                Timestamped {
+                    timestamp: 40,
+                    message: note_on(0, 4, 100)
+                },
+            ]
+        );
+        assert_eq!(
+            logger
+                .iter()
+                .map(|entry| entry.timestamp)
+                .collect::<Vec<_>>(),
+            [20, 30, 40]
+        );
+    }
+
+    #[test]
+    fn should_clear_recorded_messages() {
+        let mut logger = MidiLogger::<3>::new();
+        logger.record(0, &cc(0, 7, 100));
+        logger.record(1, &cc(0, 7, 90));
+        logger.clear();
+        assert!(logger.is_empty());
+
+        logger.record(2, &cc(0, 7, 80));
+        assert_eq!(logger.len(), 1);
+        assert_eq!(
+            logger.iter().next().map(|entry| entry.message.to_message()),
+            Some(cc(0, 7, 80))
+        );
+    }
+}
@@ -0,0 +1,280 @@
+//! Patchbay style routing of every input to any number of outputs
+
+use crate::{
+    message::{channel, with_channel},
+    persist::{Reader, Writer},
+    DecodeError, PortId, TooSmall,
+};
+use midi_convert::midi_types::{Channel, MidiMessage};
+
+/// Channel filter and remap of a route between an input and an output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouteFilter {
+    /// Channels passed on, bit 0 is channel 1
+    pub channels: u16,
+    /// Channel the passed channel messages are moved to
+    pub remap: Option<Channel>,
+}
+
+impl Default for RouteFilter {
+    /// Pass all channels unchanged
+    fn default() -> Self {
+        RouteFilter {
+            channels: 0xffff,
+            remap: None,
+        }
+    }
+}
+
+impl RouteFilter {
+    /// Only pass `channel`
+    pub fn channel(channel: Channel) -> Self {
+        RouteFilter {
+            channels: 1 << u8::from(channel),
+            remap: None,
+        }
+    }
+
+    /// The message to send for `message`, `None` if it is filtered out
+    fn apply(&self, message: &MidiMessage) -> Option<MidiMessage> {
+        match channel(message) {
+            Some(channel) if self.channels & (1 << u8::from(channel)) == 0 => None,
+            Some(_) => Some(match self.remap {
+                Some(remap) => with_channel(*message, remap),
+                None => *message,
+            }),
+            None => Some(*message),
+        }
+    }
+}
+
+/// Routes messages from `IN` input ports to any of `OUT` output ports
+///
+/// Every pair of an input and an output is a route that is either disabled or enabled with a
+/// `RouteFilter` for channel messages. System messages pass every enabled route. Initially all
+/// routes are disabled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutingMatrix<const IN: usize, const OUT: usize> {
+    routes: [[Option<RouteFilter>; OUT]; IN],
+}
+
+impl<const IN: usize, const OUT: usize> Default for RoutingMatrix<IN, OUT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const IN: usize, const OUT: usize> RoutingMatrix<IN, OUT> {
+    /// Largest number of bytes written by `save`
+    pub const MAX_SAVED_LEN: usize = 3 + 4 * IN * OUT;
+
+    pub fn new() -> Self {
+        RoutingMatrix {
+            routes: [[None; OUT]; IN],
+        }
+    }
+
+    /// Enable the route from `input` to `output` with `filter`, `None` disables it
+    ///
+    /// Ports outside of the matrix are ignored.
+    pub fn set_route(&mut self, input: PortId, output: PortId, filter: Option<RouteFilter>) {
+        if let Some(route) = self
+            .routes
+            .get_mut(input.0 as usize)
+            .and_then(|routes| routes.get_mut(output.0 as usize))
+        {
+            *route = filter;
+        }
+    }
+
+    /// Enable the route from `input` to `output`, passing all channels
+    pub fn connect(&mut self, input: PortId, output: PortId) {
+        self.set_route(input, output, Some(RouteFilter::default()));
+    }
+
+    pub fn disconnect(&mut self, input: PortId, output: PortId) {
+        self.set_route(input, output, None);
+    }
+
+    /// Filter of the route from `input` to `output`, `None` if it is disabled
+    pub fn route_filter(&self, input: PortId, output: PortId) -> Option<RouteFilter> {
+        self.routes
+            .get(input.0 as usize)
+            .and_then(|routes| routes.get(output.0 as usize))
+            .copied()
+            .flatten()
+    }
+
+    /// Call `emit` with every output a message received on `input` is sent to, and the message
+    /// to send there
+    pub fn route(
+        &self,
+        input: PortId,
+        message: &MidiMessage,
+        emit: &mut impl FnMut(PortId, &MidiMessage),
+    ) {
+        let routes = match self.routes.get(input.0 as usize) {
+            Some(routes) => routes,
+            None => return,
+        };
+        for (output, filter) in routes.iter().enumerate() {
+            if let Some(routed) = filter.and_then(|filter| filter.apply(message)) {
+                emit(PortId(output as u8), &routed);
+            }
+        }
+    }
+
+    /// Save the routes into `buffer`, returns the number of bytes written
+    pub fn save(&self, buffer: &mut [u8]) -> Result<usize, TooSmall> {
+        let mut writer = Writer::new(buffer)?;
+        writer.byte(IN as u8)?;
+        writer.byte(OUT as u8)?;
+        for filter in self.routes.iter().flatten() {
+            match filter {
+                Some(filter) => {
+                    // 0xff stands for no remap
+                    writer.byte(filter.remap.map_or(0xff, u8::from))?;
+                    writer.u16(filter.channels)?;
+                }
+                None => writer.byte(0xfe)?,
+            }
+        }
+        Ok(writer.finish())
+    }
+
+    /// Load routes saved by `save` from a matrix of the same size
+    pub fn load(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes)?;
+        if reader.byte()? as usize != IN || reader.byte()? as usize != OUT {
+            return Err(DecodeError::Invalid);
+        }
+        let mut matrix = Self::new();
+        for route in matrix.routes.iter_mut().flatten() {
+            let remap = match reader.byte()? {
+                0xfe => continue,
+                0xff => None,
+                channel @ 0..=0x0f => Some(channel.into()),
+                _ => return Err(DecodeError::Invalid),
+            };
+            *route = Some(RouteFilter {
+                channels: reader.u16()?,
+                remap,
+            });
+        }
+        Ok(matrix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_on};
+    use std::vec::Vec;
+
+    fn route<const IN: usize, const OUT: usize>(
+        matrix: &RoutingMatrix<IN, OUT>,
+        input: u8,
+        message: MidiMessage,
+    ) -> Vec<(PortId, MidiMessage)> {
+        let mut output = Vec::new();
+        matrix.route(PortId(input), &message, &mut |port, message| {
+            output.push((port, *message))
+        });
+        output
+    }
+
+    /// Both inputs connected to all three outputs, input 1 only sends channel 10 to output 2
+    fn patchbay() -> RoutingMatrix<2, 3> {
+        let mut matrix = RoutingMatrix::new();
+        for input in 0..2 {
+            for output in 0..3 {
+                matrix.connect(PortId(input), PortId(output));
+            }
+        }
+        matrix.set_route(
+            PortId(1),
+            PortId(2),
+            Some(RouteFilter::channel(Channel::C10)),
+        );
+        matrix
+    }
+
+    #[test]
+    fn should_fan_out_to_all_enabled_outputs() {
+        let matrix = patchbay();
+
+        assert_eq!(
+            route(&matrix, 0, note_on(0, 60, 100)),
+            [
+                (PortId(0), note_on(0, 60, 100)),
+                (PortId(1), note_on(0, 60, 100)),
+                (PortId(2), note_on(0, 60, 100)),
+            ]
+        );
+        assert_eq!(
+            route(&matrix, 1, note_on(0, 60, 100)),
+            [
+                (PortId(0), note_on(0, 60, 100)),
+                (PortId(1), note_on(0, 60, 100)),
+            ]
+        );
+        assert_eq!(route(&matrix, 1, cc(9, 7, 90)).len(), 3);
+        assert_eq!(route(&matrix, 1, MidiMessage::TimingClock).len(), 3);
+        assert_eq!(route(&matrix, 2, MidiMessage::TimingClock), []);
+    }
+
+    #[test]
+    fn should_edit_routes_at_runtime() {
+        let mut matrix = patchbay();
+        matrix.disconnect(PortId(0), PortId(1));
+        matrix.set_route(
+            PortId(0),
+            PortId(2),
+            Some(RouteFilter {
+                channels: 0xffff,
+                remap: Some(Channel::C4),
+            }),
+        );
+        matrix.connect(PortId(7), PortId(0));
+
+        assert_eq!(matrix.route_filter(PortId(0), PortId(1)), None);
+        assert_eq!(
+            route(&matrix, 0, note_on(0, 60, 100)),
+            [
+                (PortId(0), note_on(0, 60, 100)),
+                (PortId(2), note_on(3, 60, 100)),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_save_and_load_routes() {
+        let mut matrix = patchbay();
+        matrix.disconnect(PortId(0), PortId(0));
+        matrix.set_route(
+            PortId(0),
+            PortId(1),
+            Some(RouteFilter {
+                channels: 0x00f0,
+                remap: Some(Channel::C16),
+            }),
+        );
+
+        let mut buffer = [0; RoutingMatrix::<2, 3>::MAX_SAVED_LEN];
+        let len = matrix.save(&mut buffer).unwrap();
+        assert_eq!(len, 3 + 1 + 5 * 3);
+        assert_eq!(matrix.save(&mut buffer[..len - 1]), Err(TooSmall));
+        assert_eq!(RoutingMatrix::<2, 3>::load(&buffer[..len]), Ok(matrix));
+
+        assert_eq!(
+            RoutingMatrix::<3, 2>::load(&buffer[..len]),
+            Err(DecodeError::Invalid)
+        );
+        buffer[3] = 0x10;
+        assert_eq!(
+            RoutingMatrix::<2, 3>::load(&buffer[..len]),
+            Err(DecodeError::Invalid)
+        );
+    }
+}
@@ -0,0 +1,470 @@
+//! Pacing of locally originated messages merged into a forwarded stream
+
+use crate::{diag::log_debug, thru::Framing, RenderedMessage};
+use midi_convert::midi_types::MidiMessage;
+
+/// Wire budget of one byte, budget is kept in thousandths of a byte
+const BYTE: i32 = 1000;
+
+/// Budget of the longest rendered message
+const MAX_MESSAGE: i32 = 3 * BYTE;
+
+/// Bit rate of the wire a pacing component sends to
+///
+/// Every byte takes 10 bits on the wire, a start bit, 8 data bits and a stop bit, so a wire
+/// carries `baud / 10` bytes per second. That is `baud / 10` thousandths of a byte per
+/// millisecond, the unit budget is kept in: 3125 at 31250 baud, 11520 at 115200 baud and 100000
+/// at 1 Mbaud.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireRate {
+    /// The 31250 baud of a DIN or TRS midi connection
+    #[default]
+    Midi31250,
+    /// Any other baud rate, like the 115200 baud or 1 Mbaud of links between boards
+    Custom(u32),
+}
+
+impl WireRate {
+    pub fn baud(self) -> u32 {
+        match self {
+            WireRate::Midi31250 => 31_250,
+            WireRate::Custom(baud) => baud,
+        }
+    }
+
+    /// Budget gained per millisecond, in thousandths of a byte
+    ///
+    /// At least one thousandth, so a wire below 10 baud still drains eventually.
+    fn per_ms(self) -> i32 {
+        (self.baud() / 10).clamp(1, i32::MAX as u32) as i32
+    }
+
+    /// Most budget saved up while the wire is idle
+    ///
+    /// A millisecond of budget on top of what may be left over after sending as much as
+    /// possible, so calling `elapsed` every millisecond doesn't lose any budget.
+    fn max_credit(self) -> i32 {
+        self.per_ms().saturating_add(MAX_MESSAGE - 1)
+    }
+}
+
+/// Fixed size queue of rendered messages
+#[derive(Debug)]
+struct MessageQueue<const N: usize> {
+    messages: [Option<RenderedMessage>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> MessageQueue<N> {
+    fn new() -> Self {
+        MessageQueue {
+            messages: [None; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, message: RenderedMessage) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.messages[(self.head + self.len) % N] = Some(message);
+        self.len += 1;
+        true
+    }
+
+    fn peek(&self) -> Option<RenderedMessage> {
+        if self.len == 0 {
+            None
+        } else {
+            self.messages[self.head]
+        }
+    }
+
+    fn pop(&mut self) {
+        self.messages[self.head] = None;
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+    }
+}
+
+/// Decides when locally originated messages can be merged into a forwarded stream without
+/// exceeding the midi wire rate
+///
+/// Forwarded bytes always go out, they are reported with `thru` and use up wire budget. Local
+/// messages are queued with `queue` and handed out by `poll` once there is budget for them. Real
+/// time messages are handed out first and may go out between the bytes of a forwarded message,
+/// other local messages wait until the forwarded stream is between messages. Up to `N` local
+/// messages of each kind are queued, messages that don't fit are dropped and counted.
+#[derive(Debug)]
+pub struct MergeScheduler<const N: usize = 8> {
+    rate: WireRate,
+    credit: i32,
+    framing: Framing,
+    realtime: MessageQueue<N>,
+    local: MessageQueue<N>,
+    dropped: u32,
+}
+
+impl<const N: usize> Default for MergeScheduler<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> MergeScheduler<N> {
+    /// Scheduler for a 31250 baud midi wire
+    pub fn new() -> Self {
+        Self::with_rate(WireRate::Midi31250)
+    }
+
+    pub fn with_rate(rate: WireRate) -> Self {
+        MergeScheduler {
+            rate,
+            credit: rate.max_credit(),
+            framing: Framing::default(),
+            realtime: MessageQueue::new(),
+            local: MessageQueue::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Report the time passed since the last call, in milliseconds
+    pub fn elapsed(&mut self, ms: u32) {
+        let max_credit = self.rate.max_credit();
+        let gained = (ms.min(max_credit as u32) as i32).saturating_mul(self.rate.per_ms());
+        self.credit = self.credit.saturating_add(gained).min(max_credit);
+    }
+
+    pub fn rate(&self) -> WireRate {
+        self.rate
+    }
+
+    /// Report a forwarded byte, it is sent right away regardless of the budget
+    ///
+    /// Returns a status byte to send before `byte` when local messages interrupted the running
+    /// status of the forwarded stream.
+    pub fn thru(&mut self, byte: u8) -> Option<u8> {
+        if byte >= 0xf8 {
+            self.credit = self.credit.saturating_sub(BYTE);
+            return None;
+        }
+
+        let status = self.framing.resend_status(byte);
+        let cost = if status.is_some() { 2 * BYTE } else { BYTE };
+        self.credit = self.credit.saturating_sub(cost);
+        self.framing.track(byte);
+        status
+    }
+
+    /// Queue a local message, it is dropped if the queue is full
+    pub fn queue(&mut self, message: &MidiMessage) {
+        let rendered = RenderedMessage::from(message);
+        let queue = match rendered.as_bytes().first() {
+            Some(status) if *status >= 0xf8 => &mut self.realtime,
+            Some(_) => &mut self.local,
+            None => return,
+        };
+        if !queue.push(rendered) {
+            log_debug!("merge: queue full, dropped {:?}", message);
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+    }
+
+    /// Next local message to send, if there is wire budget for it
+    pub fn poll(&mut self) -> Option<RenderedMessage> {
+        let (queue, message, realtime) = match (self.realtime.peek(), self.local.peek()) {
+            (Some(message), _) => (&mut self.realtime, message, true),
+            (None, Some(message)) if self.framing.at_boundary() => {
+                (&mut self.local, message, false)
+            }
+            _ => return None,
+        };
+
+        let cost = message.len() as i32 * BYTE;
+        if self.credit < cost {
+            return None;
+        }
+        self.credit -= cost;
+        queue.pop();
+        if !realtime {
+            self.framing.interrupt();
+        }
+        Some(message)
+    }
+
+    /// Number of local messages waiting to be sent
+    pub fn pending(&self) -> usize {
+        self.realtime.len + self.local.len
+    }
+
+    /// Number of local messages dropped because the queue was full
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+}
+
+/// Paces messages received from a fast link for a slower wire, like a 31250 baud DIN output fed
+/// from a 1 Mbaud link between boards
+///
+/// Received messages are queued with `push` and handed out by `poll` at the rate of the output
+/// wire. Real time messages have their own queue and go out first, so the clock stays regular
+/// while a flood of other messages is thinned out. Up to `N` messages of each kind are buffered,
+/// messages that don't fit are dropped and counted.
+#[derive(Debug)]
+pub struct RateConverter<const N: usize = 32> {
+    scheduler: MergeScheduler<N>,
+}
+
+impl<const N: usize> RateConverter<N> {
+    /// Converter sending to a wire running at `output`
+    pub fn new(output: WireRate) -> Self {
+        RateConverter {
+            scheduler: MergeScheduler::with_rate(output),
+        }
+    }
+
+    /// Queue a received message, it is dropped if the queue is full
+    pub fn push(&mut self, message: &MidiMessage) {
+        self.scheduler.queue(message);
+    }
+
+    /// Report the time passed since the last call, in milliseconds
+    pub fn elapsed(&mut self, ms: u32) {
+        self.scheduler.elapsed(ms);
+    }
+
+    /// Next message to send, if the output wire has budget for it
+    pub fn poll(&mut self) -> Option<RenderedMessage> {
+        self.scheduler.poll()
+    }
+
+    pub fn rate(&self) -> WireRate {
+        self.scheduler.rate()
+    }
+
+    /// Number of messages waiting to be sent
+    pub fn pending(&self) -> usize {
+        self.scheduler.pending()
+    }
+
+    /// Number of messages dropped because the queue was full
+    pub fn dropped(&self) -> u32 {
+        self.scheduler.dropped()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::note_on;
+    use crate::test_util::parse_all;
+    use std::vec::Vec;
+
+    /// Run a millisecond of a forwarded stream, then send the local messages the scheduler allows
+    fn run_ms<const N: usize>(
+        scheduler: &mut MergeScheduler<N>,
+        thru: &mut impl Iterator<Item = u8>,
+        bytes: usize,
+        wire: &mut Vec<u8>,
+    ) {
+        scheduler.elapsed(1);
+        for byte in thru.take(bytes) {
+            wire.extend(scheduler.thru(byte));
+            wire.push(byte);
+            while let Some(message) = scheduler.poll() {
+                wire.extend_from_slice(message.as_bytes());
+            }
+        }
+        while let Some(message) = scheduler.poll() {
+            wire.extend_from_slice(message.as_bytes());
+        }
+    }
+
+    /// Note ons using running status
+    fn note_stream() -> impl Iterator<Item = u8> {
+        core::iter::once(0x90).chain((0u32..).flat_map(|note| [(note % 128) as u8, 0x40]))
+    }
+
+    #[test]
+    fn should_delay_local_messages_while_thru_saturates_wire() {
+        let mut scheduler = MergeScheduler::<8>::new();
+        let mut thru = note_stream();
+        let mut wire = Vec::new();
+
+        // Use up the saved budget, then keep the wire full
+        run_ms(&mut scheduler, &mut thru, 7, &mut wire);
+        scheduler.queue(&note_on(1, 60, 100));
+        for _ in 0..10 {
+            run_ms(&mut scheduler, &mut thru, 3, &mut wire);
+        }
+        assert_eq!(scheduler.pending(), 1);
+        assert!(!wire.contains(&0x91));
+
+        // Once the forwarded stream slows down the note goes out between messages
+        run_ms(&mut scheduler, &mut thru, 0, &mut wire);
+        run_ms(&mut scheduler, &mut thru, 0, &mut wire);
+        assert_eq!(scheduler.pending(), 0);
+        assert_eq!(wire[wire.len() - 3..], [0x91, 60, 100]);
+
+        // The forwarded stream continues with its status byte sent again
+        run_ms(&mut scheduler, &mut thru, 2, &mut wire);
+        assert_eq!(wire[wire.len() - 3..], [0x90, 18, 0x40]);
+        let mut messages = parse_all(&wire);
+        assert_eq!(messages.remove(18), note_on(1, 60, 100));
+        assert_eq!(messages.len(), 19);
+        assert!(messages
+            .iter()
+            .zip(0..)
+            .all(|(message, note)| *message == note_on(0, note, 0x40)));
+    }
+
+    #[test]
+    fn should_not_interleave_local_messages_with_thru_messages() {
+        let mut scheduler = MergeScheduler::<8>::new();
+        let mut wire = Vec::new();
+
+        for byte in [0x92, 0x40] {
+            assert_eq!(scheduler.thru(byte), None);
+            wire.push(byte);
+        }
+        scheduler.elapsed(10);
+        scheduler.queue(&note_on(1, 60, 100));
+        assert_eq!(scheduler.poll(), None);
+
+        assert_eq!(scheduler.thru(0x7f), None);
+        wire.push(0x7f);
+        wire.extend_from_slice(scheduler.poll().unwrap().as_bytes());
+
+        assert_eq!(
+            parse_all(&wire),
+            [note_on(2, 0x40, 0x7f), note_on(1, 60, 100)]
+        );
+    }
+
+    #[test]
+    fn should_send_realtime_first_and_inside_thru_messages() {
+        let mut scheduler = MergeScheduler::<8>::new();
+        scheduler.thru(0x92);
+        scheduler.queue(&note_on(1, 60, 100));
+        scheduler.queue(&MidiMessage::TimingClock);
+
+        assert_eq!(
+            scheduler.poll(),
+            Some(RenderedMessage::from(MidiMessage::TimingClock))
+        );
+        assert_eq!(scheduler.poll(), None);
+    }
+
+    #[test]
+    fn should_wait_for_budget() {
+        let mut scheduler = MergeScheduler::<8>::new();
+        for _ in 0..4 {
+            scheduler.thru(0xf8);
+        }
+        scheduler.queue(&note_on(1, 60, 100));
+
+        assert_eq!(scheduler.poll(), None);
+        scheduler.elapsed(1);
+        assert_eq!(scheduler.poll(), Some(RenderedMessage::note_on(1, 60, 100)));
+    }
+
+    #[test]
+    fn should_not_overflow_budget_on_long_thru_stream() {
+        let mut scheduler = MergeScheduler::<8>::new();
+        // Never reporting elapsed time, the budget goes below `i32::MIN` thousandths of a byte
+        for _ in 0..2_200_000 {
+            scheduler.thru(0xf8);
+        }
+        scheduler.queue(&note_on(1, 60, 100));
+
+        assert_eq!(scheduler.poll(), None);
+        scheduler.elapsed(1);
+        assert_eq!(scheduler.poll(), None);
+    }
+
+    #[test]
+    fn should_count_dropped_messages() {
+        let mut scheduler = MergeScheduler::<2>::new();
+        scheduler.thru(0x92);
+        for note in 0..4 {
+            scheduler.queue(&note_on(1, note, 100));
+        }
+        scheduler.queue(&MidiMessage::TimingClock);
+
+        assert_eq!(scheduler.pending(), 3);
+        assert_eq!(scheduler.dropped(), 2);
+    }
+
+    #[test]
+    fn should_compute_budget_from_baud() {
+        assert_eq!(WireRate::Midi31250.per_ms(), 3125);
+        assert_eq!(WireRate::Custom(31_250).per_ms(), 3125);
+        assert_eq!(WireRate::Custom(115_200).per_ms(), 11_520);
+        assert_eq!(WireRate::Custom(1_000_000).per_ms(), 100_000);
+        assert_eq!(WireRate::Custom(0).per_ms(), 1);
+
+        assert_eq!(WireRate::Midi31250.max_credit(), 6124);
+        assert_eq!(WireRate::Custom(1_000_000).max_credit(), 102_999);
+    }
+
+    /// Bytes sent in `ms` milliseconds while there is always more to send
+    fn saturated_bytes(rate: WireRate, ms: u32) -> usize {
+        let mut scheduler = MergeScheduler::<1>::with_rate(rate);
+        let mut sent = 0;
+        for _ in 0..ms {
+            scheduler.elapsed(1);
+            scheduler.queue(&note_on(1, 60, 100));
+            while let Some(message) = scheduler.poll() {
+                sent += message.len();
+                scheduler.queue(&note_on(1, 60, 100));
+            }
+        }
+        sent
+    }
+
+    #[test]
+    fn should_send_at_wire_rate() {
+        // A saved up budget of up to a millisecond, then the rate of the wire
+        let slow = saturated_bytes(WireRate::Midi31250, 1000);
+        assert!((3122..=3125 + 6).contains(&slow), "{} bytes", slow);
+        let fast = saturated_bytes(WireRate::Custom(115_200), 1000);
+        assert!((11_517..=11_520 + 14).contains(&fast), "{} bytes", fast);
+        let big = saturated_bytes(WireRate::Custom(10_000_000), 10);
+        assert!((9_997..=10_000 + 1002).contains(&big), "{} bytes", big);
+    }
+
+    #[test]
+    fn should_keep_clock_regular_when_down_converting_flood() {
+        const CLOCK_INTERVAL: u32 = 20;
+        let mut converter = RateConverter::<16>::new(WireRate::Midi31250);
+        let mut clocks = Vec::new();
+        let mut sent = 0;
+
+        for ms in 0..1000 {
+            converter.elapsed(1);
+            // 1 Mbaud fills a millisecond with 33 note ons
+            for note in 0..33 {
+                converter.push(&note_on(0, note, 100));
+            }
+            if ms % CLOCK_INTERVAL == 0 {
+                converter.push(&MidiMessage::TimingClock);
+            }
+            while let Some(message) = converter.poll() {
+                sent += message.len();
+                if message == RenderedMessage::from(MidiMessage::TimingClock) {
+                    clocks.push(ms);
+                }
+            }
+        }
+
+        assert_eq!(clocks.len(), 50);
+        assert!(clocks
+            .windows(2)
+            .all(|ticks| ticks[1] - ticks[0] == CLOCK_INTERVAL));
+        assert!(sent <= 3125 + 6, "{} bytes", sent);
+        assert!(converter.dropped() > 30_000 - 1100);
+    }
+}
@@ -0,0 +1,329 @@
+//! Constructors and helpers for midi messages
+//!
+//! The constructors take plain `u8` values and are `const fn` so message tables can be declared as
+//! statics. Out of range values are clamped, channels are 0 based.
+//!
+//! ```
+//! use embedded_midi::message::{cc, note_on};
+//! use embedded_midi::messages;
+//! use embedded_midi::midi_types::MidiMessage;
+//!
+//! const NOTE: MidiMessage = note_on(0, 60, 100);
+//! static INIT: [MidiMessage; 2] = messages![program_change(0, 5), cc(0, 7, 100)];
+//! ```
+
+use crate::{
+    parse::{data_length, MidiParser},
+    ParseErrorKind, RenderedMessage,
+};
+use midi_convert::midi_types::{
+    Channel, Control, MidiMessage, Note, Program, QuarterFrame, Value14, Value7,
+};
+
+pub const fn note_on(channel: u8, note: u8, velocity: u8) -> MidiMessage {
+    MidiMessage::NoteOn(
+        Channel::new(channel),
+        Note::new(note),
+        Value7::new(velocity),
+    )
+}
+
+pub const fn note_off(channel: u8, note: u8, velocity: u8) -> MidiMessage {
+    MidiMessage::NoteOff(
+        Channel::new(channel),
+        Note::new(note),
+        Value7::new(velocity),
+    )
+}
+
+pub const fn key_pressure(channel: u8, note: u8, value: u8) -> MidiMessage {
+    MidiMessage::KeyPressure(Channel::new(channel), Note::new(note), Value7::new(value))
+}
+
+/// Control change message
+pub const fn cc(channel: u8, control: u8, value: u8) -> MidiMessage {
+    MidiMessage::ControlChange(
+        Channel::new(channel),
+        Control::new(control),
+        Value7::new(value),
+    )
+}
+
+pub const fn program_change(channel: u8, program: u8) -> MidiMessage {
+    MidiMessage::ProgramChange(Channel::new(channel), Program::new(program))
+}
+
+/// Bank select control changes, `bank` is sent as msb (CC 0) followed by lsb (CC 32)
+///
+/// Follow with a program change to switch to a program in the bank.
+pub const fn bank_select(channel: u8, bank: u16) -> [MidiMessage; 2] {
+    let bank = if bank > 0x3fff { 0x3fff } else { bank };
+    [
+        cc(channel, 0, (bank >> 7) as u8),
+        cc(channel, 32, (bank & 0x7f) as u8),
+    ]
+}
+
+pub const fn channel_pressure(channel: u8, value: u8) -> MidiMessage {
+    MidiMessage::ChannelPressure(Channel::new(channel), Value7::new(value))
+}
+
+/// Pitch bend message, `value` ranges from -8192 to 8191 with 0 meaning no bend
+pub const fn pitch_bend(channel: u8, value: i16) -> MidiMessage {
+    MidiMessage::PitchBendChange(Channel::new(channel), Value14::new(value))
+}
+
+pub const fn quarter_frame(value: u8) -> MidiMessage {
+    MidiMessage::QuarterFrame(QuarterFrame::new(value))
+}
+
+/// Song position pointer message, `beats` counts sixteenth notes from the start of the song
+pub const fn song_position(beats: u16) -> MidiMessage {
+    let beats = if beats > 0x3fff { 0x3fff } else { beats };
+    MidiMessage::SongPositionPointer(Value14::new(beats as i16 - 8192))
+}
+
+pub const fn song_select(song: u8) -> MidiMessage {
+    MidiMessage::SongSelect(Value7::new(song))
+}
+
+/// Declare an array of midi messages using the constructors in `embedded_midi::message`
+///
+/// ```
+/// use embedded_midi::messages;
+/// use embedded_midi::midi_types::MidiMessage;
+///
+/// static PATCH: [MidiMessage; 3] = messages![
+///     program_change(0, 12),
+///     cc(0, 74, 64),
+///     pitch_bend(0, 0),
+/// ];
+/// ```
+#[macro_export]
+macro_rules! messages {
+    ($($name:ident($($arg:expr),* $(,)?)),* $(,)?) => {
+        [$($crate::message::$name($($arg),*)),*]
+    };
+}
+
+/// Wire format of a message, padded with zeroes, and the number of bytes used
+///
+/// The status byte is always included.
+pub fn to_bytes(message: &MidiMessage) -> ([u8; 3], u8) {
+    RenderedMessage::from(message).to_parts()
+}
+
+/// Parse one complete message from the start of `bytes`, returns the message and the number of
+/// bytes used
+///
+/// Returns `ParseErrorKind::BufferTooShort` when `bytes` ends before the message is complete and
+/// `ParseErrorKind::MessageNotFound` when `bytes` doesn't start with the status byte of a
+/// supported message or the message is interrupted by another status byte.
+pub fn from_bytes(bytes: &[u8]) -> Result<(MidiMessage, usize), ParseErrorKind> {
+    let status = *bytes.first().ok_or(ParseErrorKind::BufferTooShort)?;
+    if matches!(
+        status,
+        0x00..=0x7f | 0xf0 | 0xf4 | 0xf5 | 0xf7 | 0xf9 | 0xfd
+    ) {
+        return Err(ParseErrorKind::MessageNotFound);
+    }
+
+    let len = data_length(status) as usize + 1;
+    let message = bytes.get(..len).ok_or(ParseErrorKind::BufferTooShort)?;
+    if message[1..].iter().any(|byte| *byte > 0x7f) {
+        return Err(ParseErrorKind::MessageNotFound);
+    }
+
+    let mut parser = MidiParser::new();
+    message
+        .iter()
+        .filter_map(|byte| parser.parse(*byte))
+        .next()
+        .map(|message| (message, len))
+        .ok_or(ParseErrorKind::MessageNotFound)
+}
+
+/// Channel of a channel voice message, `None` for system messages
+pub(crate) fn channel(message: &MidiMessage) -> Option<Channel> {
+    match *message {
+        MidiMessage::NoteOff(channel, ..)
+        | MidiMessage::NoteOn(channel, ..)
+        | MidiMessage::KeyPressure(channel, ..)
+        | MidiMessage::ControlChange(channel, ..)
+        | MidiMessage::ProgramChange(channel, ..)
+        | MidiMessage::ChannelPressure(channel, ..)
+        | MidiMessage::PitchBendChange(channel, ..) => Some(channel),
+        _ => None,
+    }
+}
+
+/// Move a channel voice message to another channel, system messages are returned unchanged
+pub(crate) fn with_channel(message: MidiMessage, channel: Channel) -> MidiMessage {
+    match message {
+        MidiMessage::NoteOff(_, note, velocity) => MidiMessage::NoteOff(channel, note, velocity),
+        MidiMessage::NoteOn(_, note, velocity) => MidiMessage::NoteOn(channel, note, velocity),
+        MidiMessage::KeyPressure(_, note, value) => MidiMessage::KeyPressure(channel, note, value),
+        MidiMessage::ControlChange(_, control, value) => {
+            MidiMessage::ControlChange(channel, control, value)
+        }
+        MidiMessage::ProgramChange(_, program) => MidiMessage::ProgramChange(channel, program),
+        MidiMessage::ChannelPressure(_, value) => MidiMessage::ChannelPressure(channel, value),
+        MidiMessage::PitchBendChange(_, value) => MidiMessage::PitchBendChange(channel, value),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_construct_channel_voice_messages() {
+        assert_eq!(
+            note_on(0x02, 0x76, 0x34),
+            MidiMessage::NoteOn(0x02.into(), 0x76.into(), 0x34.into())
+        );
+        assert_eq!(
+            note_off(0x02, 0x76, 0x34),
+            MidiMessage::NoteOff(0x02.into(), 0x76.into(), 0x34.into())
+        );
+        assert_eq!(
+            key_pressure(0x02, 0x76, 0x34),
+            MidiMessage::KeyPressure(0x02.into(), 0x76.into(), 0x34.into())
+        );
+        assert_eq!(
+            cc(0x02, 0x07, 0x34),
+            MidiMessage::ControlChange(0x02.into(), 0x07.into(), 0x34.into())
+        );
+        assert_eq!(
+            program_change(0x02, 0x05),
+            MidiMessage::ProgramChange(0x02.into(), 0x05.into())
+        );
+        assert_eq!(
+            channel_pressure(0x02, 0x34),
+            MidiMessage::ChannelPressure(0x02.into(), 0x34.into())
+        );
+        assert_eq!(
+            pitch_bend(0x02, 0),
+            MidiMessage::PitchBendChange(0x02.into(), (0x40, 0x00).into())
+        );
+        assert_eq!(
+            pitch_bend(0x02, -8192),
+            MidiMessage::PitchBendChange(0x02.into(), (0x00, 0x00).into())
+        );
+    }
+
+    #[test]
+    fn should_construct_bank_select() {
+        assert_eq!(
+            bank_select(0x02, 0x0181),
+            [cc(0x02, 0, 0x03), cc(0x02, 32, 0x01)]
+        );
+        assert_eq!(
+            bank_select(0x02, 0xffff),
+            [cc(0x02, 0, 0x7f), cc(0x02, 32, 0x7f)]
+        );
+    }
+
+    #[test]
+    fn should_construct_system_common_messages() {
+        assert_eq!(quarter_frame(0x23), MidiMessage::QuarterFrame(0x23.into()));
+        assert_eq!(
+            song_position(0x0934),
+            MidiMessage::SongPositionPointer((0x12, 0x34).into())
+        );
+        assert_eq!(song_select(0x05), MidiMessage::SongSelect(0x05.into()));
+    }
+
+    #[test]
+    fn should_clamp_out_of_range_values() {
+        assert_eq!(
+            note_on(0x12, 0x80, 0xff),
+            MidiMessage::NoteOn(0x0f.into(), 0x7f.into(), 0x7f.into())
+        );
+        assert_eq!(
+            song_position(0xffff),
+            MidiMessage::SongPositionPointer((0x7f, 0x7f).into())
+        );
+    }
+
+    #[test]
+    fn should_declare_message_tables() {
+        const TABLE: [MidiMessage; 2] = messages![note_on(0, 60, 100), cc(1, 7, 64,),];
+
+        assert_eq!(
+            TABLE,
+            [
+                MidiMessage::NoteOn(0x00.into(), 60.into(), 100.into()),
+                MidiMessage::ControlChange(0x01.into(), 7.into(), 64.into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_round_trip_wire_format() {
+        let statuses = (0x80..=0xffu8)
+            .filter(|status| !matches!(status, 0xf0 | 0xf4 | 0xf5 | 0xf7 | 0xf9 | 0xfd));
+        for status in statuses {
+            for first in 0..128u8 {
+                for second in [0x00, 0x01, 0x40, 0x7f] {
+                    let bytes = [status, first, second];
+                    let (message, len) = from_bytes(&bytes).unwrap();
+                    let (rendered, rendered_len) = to_bytes(&message);
+
+                    assert_eq!(rendered_len as usize, len);
+                    assert_eq!(rendered[..len], bytes[..len], "{:?}", message);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn should_parse_message_from_start_of_bytes() {
+        assert_eq!(
+            from_bytes(&[0x92, 0x40, 0x7f, 0x41]),
+            Ok((note_on(0x02, 0x40, 0x7f), 3))
+        );
+        assert_eq!(
+            from_bytes(&[0xc2, 0x05, 0xf8]),
+            Ok((program_change(0x02, 0x05), 2))
+        );
+        assert_eq!(from_bytes(&[0xf8, 0x92]), Ok((MidiMessage::TimingClock, 1)));
+        assert_eq!(from_bytes(&[0xf6]), Ok((MidiMessage::TuneRequest, 1)));
+    }
+
+    #[test]
+    fn should_report_truncated_messages() {
+        assert_eq!(from_bytes(&[]), Err(ParseErrorKind::BufferTooShort));
+        assert_eq!(from_bytes(&[0x92]), Err(ParseErrorKind::BufferTooShort));
+        assert_eq!(
+            from_bytes(&[0x92, 0x40]),
+            Err(ParseErrorKind::BufferTooShort)
+        );
+        assert_eq!(
+            from_bytes(&[0xf2, 0x40]),
+            Err(ParseErrorKind::BufferTooShort)
+        );
+        assert_eq!(from_bytes(&[0xc2]), Err(ParseErrorKind::BufferTooShort));
+    }
+
+    #[test]
+    fn should_report_invalid_status() {
+        for bytes in [
+            &[0x40, 0x7f, 0x00][..],
+            &[0xf0, 0x01, 0xf7],
+            &[0xf7],
+            &[0xf4],
+            &[0xfd],
+            &[0x92, 0x40, 0xf8],
+            &[0x92, 0x93, 0x40],
+        ] {
+            assert_eq!(
+                from_bytes(bytes),
+                Err(ParseErrorKind::MessageNotFound),
+                "{:x?}",
+                bytes
+            );
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! Metronome clicks following a midi clock
+
+use crate::{SongPosition, TimeSignature};
+use midi_convert::midi_types::{Channel, MidiMessage, Note, Value7};
+
+/// Sends a click on every beat of a `TimeSignature`, following the transport of a midi clock
+///
+/// The transport is driven by start, continue, stop, song position pointer and timing clock
+/// messages. The first clock after start is the downbeat of the first bar, after continue the
+/// clock at the song position, so after a song position pointer the accent still falls on the
+/// first beat of each bar. Beat 1 plays the accent note, the other beats the beat note. A click
+/// lasts `gate_ticks` clocks or until the next click. Stop, start and song position pointer end a
+/// sounding click right away.
+///
+/// By default the clicks are the metronome click, 33, at velocity 80 and the metronome bell, 34,
+/// at velocity 110, as in general midi 2, and last 6 ticks, a sixteenth note.
+///
+/// [`pipelines::Click`](crate::pipelines::Click) clicks at its own tempo instead.
+#[derive(Debug, Clone)]
+pub struct Metronome {
+    channel: Channel,
+    signature: TimeSignature,
+    beat: (Note, Value7),
+    accent: (Note, Value7),
+    gate_ticks: u32,
+    playing: bool,
+    /// Position of the next clock
+    position: SongPosition,
+    /// Click sounding and the tick it started at
+    sounding: Option<(Note, u32)>,
+}
+
+impl Metronome {
+    pub fn new(channel: Channel, signature: TimeSignature) -> Self {
+        Metronome {
+            channel,
+            signature,
+            beat: (33.into(), 80.into()),
+            accent: (34.into(), 110.into()),
+            gate_ticks: 6,
+            playing: false,
+            position: SongPosition::START,
+            sounding: None,
+        }
+    }
+
+    /// Channel of the clicks, takes effect at the next click
+    pub fn set_channel(&mut self, channel: Channel) {
+        self.channel = channel;
+    }
+
+    /// Time signature, the beats are counted from the start of the song
+    pub fn set_signature(&mut self, signature: TimeSignature) {
+        self.signature = signature;
+    }
+
+    /// Note and velocity of the beats other than beat 1
+    pub fn set_beat(&mut self, note: Note, velocity: Value7) {
+        self.beat = (note, velocity);
+    }
+
+    /// Note and velocity of beat 1
+    pub fn set_accent(&mut self, note: Note, velocity: Value7) {
+        self.accent = (note, velocity);
+    }
+
+    /// Set the click length in midi clock ticks, at least 1
+    pub fn set_gate_ticks(&mut self, ticks: u32) {
+        self.gate_ticks = ticks.max(1);
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Position the next clock plays
+    pub fn position(&self) -> SongPosition {
+        self.position
+    }
+
+    /// Handle a received message, calling `emit` with the note ons and offs of the clicks
+    pub fn receive(&mut self, message: &MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        match *message {
+            MidiMessage::Start => {
+                self.end_click(emit);
+                self.playing = true;
+                self.position = SongPosition::START;
+            }
+            MidiMessage::Continue => self.playing = true,
+            MidiMessage::Stop => {
+                self.end_click(emit);
+                self.playing = false;
+            }
+            MidiMessage::SongPositionPointer(midi_beats) => {
+                self.end_click(emit);
+                self.position = SongPosition::from_value14(midi_beats);
+            }
+            MidiMessage::TimingClock if self.playing => self.clock(emit),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self, emit: &mut dyn FnMut(MidiMessage)) {
+        let tick = self.position.to_ticks();
+        if let Some((_, since)) = self.sounding {
+            if tick.wrapping_sub(since) >= self.gate_ticks {
+                self.end_click(emit);
+            }
+        }
+        let (_, beat, into_beat) = self.position.to_bar_beat(self.signature);
+        if into_beat == 0 {
+            self.end_click(emit);
+            let (note, velocity) = if beat == 0 { self.accent } else { self.beat };
+            emit(MidiMessage::NoteOn(self.channel, note, velocity));
+            self.sounding = Some((note, tick));
+        }
+        self.position.advance_ticks(1);
+    }
+
+    fn end_click(&mut self, emit: &mut dyn FnMut(MidiMessage)) {
+        if let Some((note, _)) = self.sounding.take() {
+            emit(MidiMessage::NoteOff(self.channel, note, 0.into()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{note_off, note_on};
+    use midi_convert::midi_types::Value14;
+    use std::vec::Vec;
+
+    /// Run `ticks` clocks, returns the messages sent with the tick they were sent at
+    fn run(metronome: &mut Metronome, ticks: u32) -> Vec<(u32, MidiMessage)> {
+        let mut sent = Vec::new();
+        for _ in 0..ticks {
+            let tick = metronome.position().to_ticks();
+            metronome.receive(&MidiMessage::TimingClock, &mut |message| {
+                sent.push((tick, message))
+            });
+        }
+        sent
+    }
+
+    /// Ticks of the note ons, and whether they were accents
+    fn clicks(sent: &[(u32, MidiMessage)]) -> Vec<(u32, bool)> {
+        sent.iter()
+            .filter_map(|(tick, message)| match message {
+                MidiMessage::NoteOn(_, note, _) => Some((*tick, u8::from(*note) == 34)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn receive(metronome: &mut Metronome, message: MidiMessage) -> Vec<MidiMessage> {
+        let mut sent = Vec::new();
+        metronome.receive(&message, &mut |message| sent.push(message));
+        sent
+    }
+
+    #[test]
+    fn should_accent_the_first_beat_in_four_four() {
+        let mut metronome = Metronome::new(9.into(), TimeSignature::new(4, 4).unwrap());
+        assert!(receive(&mut metronome, MidiMessage::Start).is_empty());
+        let sent = run(&mut metronome, 192);
+
+        assert_eq!(
+            clicks(&sent),
+            [
+                (0, true),
+                (24, false),
+                (48, false),
+                (72, false),
+                (96, true),
+                (120, false),
+                (144, false),
+                (168, false),
+            ]
+        );
+        assert_eq!(
+            sent[..4],
+            [
+                (0, note_on(9, 34, 110)),
+                (6, note_off(9, 34, 0)),
+                (24, note_on(9, 33, 80)),
+                (30, note_off(9, 33, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_keep_the_accent_on_the_bar_after_a_jump_in_six_eight() {
+        let mut metronome = Metronome::new(0.into(), TimeSignature::new(6, 8).unwrap());
+        receive(&mut metronome, MidiMessage::Start);
+        let sent = run(&mut metronome, 40);
+        assert_eq!(
+            clicks(&sent),
+            [(0, true), (12, false), (24, false), (36, false)]
+        );
+
+        // Jump to the middle of the third eighth of the second bar
+        assert_eq!(
+            receive(&mut metronome, MidiMessage::Stop),
+            [note_off(0, 33, 0)]
+        );
+        let position = MidiMessage::SongPositionPointer(Value14::from(17u16));
+        assert!(receive(&mut metronome, position).is_empty());
+        assert_eq!(metronome.position().to_ticks(), 102);
+        receive(&mut metronome, MidiMessage::Continue);
+        let sent = run(&mut metronome, 50);
+        assert_eq!(
+            clicks(&sent),
+            [(108, false), (120, false), (132, false), (144, true)]
+        );
+    }
+
+    #[test]
+    fn should_end_the_click_when_stopped() {
+        let mut metronome = Metronome::new(0.into(), TimeSignature::new(3, 4).unwrap());
+        metronome.set_gate_ticks(48);
+        metronome.set_accent(76.into(), 127.into());
+        receive(&mut metronome, MidiMessage::Start);
+        let sent = run(&mut metronome, 30);
+        // The next click cuts the long gate of the accent
+        assert_eq!(
+            sent,
+            [
+                (0, note_on(0, 76, 127)),
+                (24, note_off(0, 76, 0)),
+                (24, note_on(0, 33, 80)),
+            ]
+        );
+
+        assert_eq!(
+            receive(&mut metronome, MidiMessage::Stop),
+            [note_off(0, 33, 0)]
+        );
+        assert!(!metronome.is_playing());
+        assert!(run(&mut metronome, 100).is_empty());
+        assert!(receive(&mut metronome, MidiMessage::Stop).is_empty());
+
+        // Start goes back to the downbeat
+        receive(&mut metronome, MidiMessage::Start);
+        assert_eq!(run(&mut metronome, 1), [(0, note_on(0, 76, 127))]);
+        assert_eq!(
+            receive(&mut metronome, MidiMessage::Start),
+            [note_off(0, 76, 0)]
+        );
+    }
+}
@@ -0,0 +1,316 @@
+//! Microtuning for synths without it, one note per channel bent by a tuning table
+
+use crate::message::with_channel;
+use crate::{AssignMode, MidiProcessor, StealPolicy, VoiceAllocator, VoiceEventKind};
+use midi_convert::midi_types::{Channel, MidiMessage, Note, Value14};
+
+/// Control number of the sustain pedal, handled by the allocator
+const SUSTAIN: u8 = 64;
+
+/// Plays every note on its own channel, bent by the offset of the note in a tuning table
+///
+/// Notes are spread over the `N` channels from the first channel on, stealing the oldest note
+/// when all of them are playing. Every note on is sent as a pitch bend with the offset of the
+/// note, in cents from equal temperament, then the note on, both on the channel of the note. The
+/// synth must bend by the configured bend range, 2 semitones by default, on all of these
+/// channels.
+///
+/// Messages should be filtered by channel before they are passed in. Note offs are sent on the
+/// channel of the note, key pressure too. Other control changes, program changes and channel
+/// pressure are sent on all channels, pitch bend is dropped as the channels are bent by the
+/// tuning. Changes to the tuning table apply to the next notes, `retune` applies them to the
+/// notes sounding.
+#[derive(Debug, Clone)]
+pub struct MicrotuneAllocator<const N: usize = 15> {
+    allocator: VoiceAllocator<N>,
+    first: u8,
+    bend_range: u8,
+    /// Offset of each note in cents
+    tuning: [i16; 128],
+    notes: [Option<Note>; N],
+}
+
+impl<const N: usize> MicrotuneAllocator<N> {
+    /// Allocator playing on `N` channels from `first`, channels past 16 are not used
+    pub fn new(first: Channel) -> Self {
+        let mut allocator = VoiceAllocator::new(StealPolicy::Oldest);
+        // Let the release of a note ring before its channel is bent for another
+        allocator.set_assign_mode(AssignMode::RoundRobin);
+        MicrotuneAllocator {
+            allocator,
+            first: first.into(),
+            bend_range: 2,
+            tuning: [0; 128],
+            notes: [None; N],
+        }
+    }
+
+    /// Pitch bend range of the synth in semitones
+    pub fn set_bend_range(&mut self, semitones: u8) {
+        self.bend_range = semitones.max(1);
+    }
+
+    /// Offset of `note` from equal temperament in cents
+    pub fn tuning(&self, note: Note) -> i16 {
+        self.tuning[usize::from(u8::from(note))]
+    }
+
+    /// Change the offset of `note`, notes sounding keep their tuning until `retune`
+    pub fn set_tuning(&mut self, note: Note, cents: i16) {
+        self.tuning[usize::from(u8::from(note))] = cents;
+    }
+
+    /// Replace the tuning table, notes sounding keep their tuning until `retune`
+    pub fn set_tuning_table(&mut self, cents: &[i16; 128]) {
+        self.tuning = *cents;
+    }
+
+    /// Send the pitch bend of the notes sounding again from the tuning table
+    pub fn retune(&self, emit: &mut dyn FnMut(MidiMessage)) {
+        for (voice, note) in self.notes.iter().enumerate() {
+            if let (Some(channel), Some(note)) = (self.channel(voice), note) {
+                emit(MidiMessage::PitchBendChange(channel, self.bend(*note)));
+            }
+        }
+    }
+
+    /// Pitch bend for the offset of `note`, clamped to the bend range
+    pub fn bend(&self, note: Note) -> Value14 {
+        let cents = i32::from(self.tuning(note));
+        let range = i32::from(self.bend_range) * 100;
+        // Rounded to the nearest step
+        let bend = (cents * 8192 + cents.signum() * range / 2) / range;
+        Value14::from(bend.clamp(-8192, 8191) as i16)
+    }
+
+    fn channel(&self, voice: usize) -> Option<Channel> {
+        let channel = usize::from(self.first) + voice;
+        (channel < 16).then(|| Channel::from(channel as u8))
+    }
+
+    fn all_channels(&self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        for voice in 0..N {
+            if let Some(channel) = self.channel(voice) {
+                emit(with_channel(message, channel));
+            }
+        }
+    }
+}
+
+impl<const N: usize> MidiProcessor for MicrotuneAllocator<N> {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        match message {
+            MidiMessage::NoteOn(..) | MidiMessage::NoteOff(..) => {}
+            MidiMessage::ControlChange(_, control, _) if u8::from(control) == SUSTAIN => {}
+            MidiMessage::ControlChange(..)
+            | MidiMessage::ProgramChange(..)
+            | MidiMessage::ChannelPressure(..) => return self.all_channels(message, emit),
+            MidiMessage::KeyPressure(_, note, value) => {
+                let voice = self.notes.iter().position(|held| *held == Some(note));
+                if let Some(channel) = voice.and_then(|voice| self.channel(voice)) {
+                    emit(MidiMessage::KeyPressure(channel, note, value));
+                }
+                return;
+            }
+            MidiMessage::PitchBendChange(..) => return,
+            message => return emit(message),
+        }
+
+        let mut events = [None; N];
+        let mut count = 0;
+        self.allocator.process(&message, |event| {
+            if let Some(slot) = events.get_mut(count) {
+                *slot = Some(event);
+                count += 1;
+            }
+        });
+        for event in events.iter().flatten() {
+            let channel = match self.channel(event.voice) {
+                Some(channel) => channel,
+                None => continue,
+            };
+            match event.kind {
+                VoiceEventKind::Start { note, velocity } => {
+                    self.notes[event.voice] = Some(note);
+                    emit(MidiMessage::PitchBendChange(channel, self.bend(note)));
+                    emit(MidiMessage::NoteOn(channel, note, velocity));
+                }
+                VoiceEventKind::Steal { note, velocity } => {
+                    if let Some(stolen) = self.notes[event.voice].replace(note) {
+                        emit(MidiMessage::NoteOff(channel, stolen, 0.into()));
+                    }
+                    emit(MidiMessage::PitchBendChange(channel, self.bend(note)));
+                    emit(MidiMessage::NoteOn(channel, note, velocity));
+                }
+                VoiceEventKind::Stop => {
+                    if let Some(note) = self.notes[event.voice].take() {
+                        emit(MidiMessage::NoteOff(channel, note, 0.into()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_off, note_on, pitch_bend, program_change};
+    use std::vec::Vec;
+
+    fn run<const N: usize>(
+        microtune: &mut MicrotuneAllocator<N>,
+        messages: &[MidiMessage],
+    ) -> Vec<MidiMessage> {
+        let mut output = Vec::new();
+        for message in messages {
+            microtune.process(*message, &mut |message| output.push(message));
+        }
+        output
+    }
+
+    #[test]
+    fn should_bend_by_fifty_cents() {
+        let mut microtune = MicrotuneAllocator::<15>::new(Channel::C2);
+        microtune.set_tuning(60.into(), 50);
+        microtune.set_tuning(61.into(), -50);
+        // A quarter of the range of 2 semitones
+        assert_eq!(microtune.bend(60.into()), Value14::from(2048i16));
+        assert_eq!(microtune.bend(61.into()), Value14::from(-2048i16));
+        assert_eq!(microtune.bend(62.into()), Value14::from(0i16));
+        microtune.set_tuning(62.into(), 1);
+        assert_eq!(microtune.bend(62.into()), Value14::from(41i16));
+        microtune.set_tuning(63.into(), 400);
+        assert_eq!(microtune.bend(63.into()), Value14::from(8191i16));
+        microtune.set_bend_range(12);
+        assert_eq!(microtune.bend(60.into()), Value14::from(341i16));
+
+        microtune.set_bend_range(2);
+        let output = run(&mut microtune, &[note_on(0, 60, 100), note_on(0, 61, 90)]);
+        assert_eq!(
+            output,
+            [
+                pitch_bend(1, 2048),
+                note_on(1, 60, 100),
+                pitch_bend(2, -2048),
+                note_on(2, 61, 90),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_free_channels_on_note_off() {
+        let mut microtune = MicrotuneAllocator::<2>::new(Channel::C1);
+        let output = run(
+            &mut microtune,
+            &[
+                note_on(0, 60, 100),
+                note_on(0, 62, 100),
+                note_off(0, 60, 0),
+                note_on(0, 64, 100),
+                note_on(0, 62, 0),
+                note_off(0, 62, 0),
+            ],
+        );
+        assert_eq!(
+            output,
+            [
+                pitch_bend(0, 0),
+                note_on(0, 60, 100),
+                pitch_bend(1, 0),
+                note_on(1, 62, 100),
+                note_off(0, 60, 0),
+                pitch_bend(0, 0),
+                note_on(0, 64, 100),
+                note_off(1, 62, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_steal_oldest_channel() {
+        let mut microtune = MicrotuneAllocator::<2>::new(Channel::C1);
+        microtune.set_tuning(64.into(), -14);
+        let output = run(
+            &mut microtune,
+            &[
+                note_on(0, 60, 100),
+                note_on(0, 62, 100),
+                note_on(0, 64, 100),
+            ],
+        );
+        assert_eq!(
+            output[4..],
+            [note_off(0, 60, 0), pitch_bend(0, -573), note_on(0, 64, 100)]
+        );
+        assert_eq!(run(&mut microtune, &[note_off(0, 60, 0)]), []);
+    }
+
+    #[test]
+    fn should_retune_sounding_notes_only_when_asked() {
+        let mut microtune = MicrotuneAllocator::<4>::new(Channel::C1);
+        run(&mut microtune, &[note_on(0, 60, 100), note_on(0, 67, 100)]);
+        let mut table = [0; 128];
+        table[67] = 2;
+        microtune.set_tuning_table(&table);
+        assert_eq!(
+            run(&mut microtune, &[note_on(0, 67, 0)]),
+            [note_off(1, 67, 0)]
+        );
+
+        microtune.set_tuning(60.into(), -16);
+        let mut output = Vec::new();
+        microtune.retune(&mut |message| output.push(message));
+        assert_eq!(output, [pitch_bend(0, -655)]);
+    }
+
+    #[test]
+    fn should_spread_channel_messages() {
+        let mut microtune = MicrotuneAllocator::<3>::new(Channel::C14);
+        let output = run(
+            &mut microtune,
+            &[
+                note_on(5, 60, 100),
+                cc(5, 1, 20),
+                program_change(5, 3),
+                pitch_bend(5, 100),
+                MidiMessage::KeyPressure(Channel::C6, 60.into(), 9.into()),
+                MidiMessage::TimingClock,
+            ],
+        );
+        assert_eq!(
+            output,
+            [
+                pitch_bend(13, 0),
+                note_on(13, 60, 100),
+                cc(13, 1, 20),
+                cc(14, 1, 20),
+                cc(15, 1, 20),
+                program_change(13, 3),
+                program_change(14, 3),
+                program_change(15, 3),
+                MidiMessage::KeyPressure(Channel::C14, 60.into(), 9.into()),
+                MidiMessage::TimingClock,
+            ]
+        );
+    }
+
+    #[test]
+    fn should_release_sustained_notes_with_the_pedal() {
+        let mut microtune = MicrotuneAllocator::<4>::new(Channel::C1);
+        let output = run(
+            &mut microtune,
+            &[
+                cc(0, 64, 127),
+                note_on(0, 60, 100),
+                note_off(0, 60, 0),
+                cc(0, 64, 0),
+            ],
+        );
+        assert_eq!(
+            output,
+            [pitch_bend(0, 0), note_on(0, 60, 100), note_off(0, 60, 0)]
+        );
+    }
+}
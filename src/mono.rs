@@ -0,0 +1,338 @@
+//! Monophonic processor reducing polyphonic input to a single sounding note
+
+use crate::MidiProcessor;
+use midi_convert::midi_types::{Channel, MidiMessage, Note, Value7};
+
+/// Which of the held notes sounds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotePriority {
+    /// The most recently played note
+    Last,
+    /// The lowest held note
+    Low,
+    /// The highest held note
+    High,
+}
+
+/// How to move from one sounding note to the next while notes overlap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// Release the old note before playing the new one, retriggering the envelopes
+    Retrigger,
+    /// Play the new note before releasing the old one, synths with legato detection glide
+    Legato,
+    /// Only play the new note, for synths that treat a note on without note off as legato
+    LegatoWithoutNoteOff,
+}
+
+/// A held key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Key {
+    channel: Channel,
+    note: Note,
+    velocity: Value7,
+}
+
+/// Reduces note input to a single sounding note, like a classic monophonic synth
+///
+/// Held notes are kept on a stack of `STACK` notes, when the sounding note is released the
+/// processor falls back to the held note chosen by the priority. When the stack is full the oldest
+/// held note that is not sounding is forgotten. Messages other than notes are passed on.
+#[derive(Debug, Clone)]
+pub struct MonoPriority<const STACK: usize = 10> {
+    priority: NotePriority,
+    transition: Transition,
+    stack: [Option<Key>; STACK],
+    len: usize,
+    sounding: Option<Key>,
+}
+
+impl<const STACK: usize> MonoPriority<STACK> {
+    pub fn new(priority: NotePriority) -> Self {
+        MonoPriority {
+            priority,
+            transition: Transition::Retrigger,
+            stack: [None; STACK],
+            len: 0,
+            sounding: None,
+        }
+    }
+
+    pub fn set_priority(&mut self, priority: NotePriority) {
+        self.priority = priority;
+    }
+
+    pub fn set_transition(&mut self, transition: Transition) {
+        self.transition = transition;
+    }
+
+    /// The note that is currently sounding
+    pub fn sounding(&self) -> Option<(Channel, Note)> {
+        self.sounding.map(|key| (key.channel, key.note))
+    }
+
+    /// Held notes, oldest first
+    pub fn held(&self) -> impl Iterator<Item = (Channel, Note)> + '_ {
+        self.stack[..self.len]
+            .iter()
+            .flatten()
+            .map(|key| (key.channel, key.note))
+    }
+
+    fn remove(&mut self, channel: Channel, note: Note) {
+        if let Some(index) = self.stack[..self.len]
+            .iter()
+            .position(|key| matches!(key, Some(key) if key.channel == channel && key.note == note))
+        {
+            self.stack[index..self.len].rotate_left(1);
+            self.len -= 1;
+            self.stack[self.len] = None;
+        }
+    }
+
+    fn push(&mut self, key: Key) {
+        if STACK == 0 {
+            return;
+        }
+        if self.len == STACK {
+            let sounding = self.sounding;
+            let oldest = self.stack[..self.len]
+                .iter()
+                .position(|held| *held != sounding)
+                .unwrap_or(0);
+            self.stack[oldest..self.len].rotate_left(1);
+            self.len -= 1;
+        }
+        self.stack[self.len] = Some(key);
+        self.len += 1;
+    }
+
+    fn select(&self) -> Option<Key> {
+        let held = self.stack[..self.len].iter().flatten().copied();
+        match self.priority {
+            NotePriority::Last => held.last(),
+            NotePriority::Low => held.min_by_key(|key| u8::from(key.note)),
+            NotePriority::High => held.max_by_key(|key| u8::from(key.note)),
+        }
+    }
+
+    /// Switch to the selected note, `release` is the velocity for note offs
+    fn update(&mut self, release: Value7, emit: &mut dyn FnMut(MidiMessage)) {
+        let selected = self.select();
+        let note_off = |key: Key| MidiMessage::NoteOff(key.channel, key.note, release);
+        let note_on = |key: Key| MidiMessage::NoteOn(key.channel, key.note, key.velocity);
+
+        match (self.sounding, selected) {
+            (Some(old), Some(new)) if old.channel == new.channel && old.note == new.note => {}
+            (None, Some(new)) => emit(note_on(new)),
+            (Some(old), None) => emit(note_off(old)),
+            (Some(old), Some(new)) => match self.transition {
+                Transition::Retrigger => {
+                    emit(note_off(old));
+                    emit(note_on(new));
+                }
+                Transition::Legato => {
+                    emit(note_on(new));
+                    emit(note_off(old));
+                }
+                Transition::LegatoWithoutNoteOff => emit(note_on(new)),
+            },
+            (None, None) => {}
+        }
+        self.sounding = selected;
+    }
+}
+
+impl<const STACK: usize> MidiProcessor for MonoPriority<STACK> {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        match message {
+            MidiMessage::NoteOn(channel, note, velocity) if u8::from(velocity) > 0 => {
+                self.remove(channel, note);
+                self.push(Key {
+                    channel,
+                    note,
+                    velocity,
+                });
+                self.update(0.into(), emit);
+            }
+            MidiMessage::NoteOn(channel, note, velocity)
+            | MidiMessage::NoteOff(channel, note, velocity) => {
+                self.remove(channel, note);
+                self.update(velocity, emit);
+            }
+            _ => emit(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{note_off, note_on};
+    use crate::test_util::process;
+    use std::vec::Vec;
+
+    fn held<const STACK: usize>(mono: &MonoPriority<STACK>) -> Vec<u8> {
+        mono.held().map(|(_, note)| u8::from(note)).collect()
+    }
+
+    #[test]
+    fn should_fall_back_to_last_held_note() {
+        let mut mono = MonoPriority::<10>::new(NotePriority::Last);
+
+        assert_eq!(
+            process(
+                &mut mono,
+                &[
+                    note_on(0, 60, 100),
+                    note_on(0, 64, 90),
+                    note_on(0, 62, 80),
+                    note_off(0, 62, 0),
+                    note_off(0, 60, 0),
+                    note_off(0, 64, 10),
+                ]
+            ),
+            [
+                note_on(0, 60, 100),
+                note_off(0, 60, 0),
+                note_on(0, 64, 90),
+                note_off(0, 64, 0),
+                note_on(0, 62, 80),
+                note_off(0, 62, 0),
+                note_on(0, 64, 90),
+                note_off(0, 64, 10),
+            ]
+        );
+        assert_eq!(mono.sounding(), None);
+    }
+
+    #[test]
+    fn should_play_lowest_held_note() {
+        let mut mono = MonoPriority::<10>::new(NotePriority::Low);
+
+        assert_eq!(
+            process(
+                &mut mono,
+                &[
+                    note_on(0, 64, 100),
+                    note_on(0, 67, 100),
+                    note_on(0, 60, 100),
+                    note_off(0, 60, 0),
+                ]
+            ),
+            [
+                note_on(0, 64, 100),
+                note_off(0, 64, 0),
+                note_on(0, 60, 100),
+                note_off(0, 60, 0),
+                note_on(0, 64, 100),
+            ]
+        );
+        assert_eq!(held(&mono), [64, 67]);
+    }
+
+    #[test]
+    fn should_play_highest_held_note() {
+        let mut mono = MonoPriority::<10>::new(NotePriority::High);
+
+        assert_eq!(
+            process(
+                &mut mono,
+                &[
+                    note_on(0, 64, 100),
+                    note_on(0, 60, 100),
+                    note_on(0, 67, 100),
+                    note_off(0, 67, 0),
+                    note_off(0, 60, 0),
+                ]
+            ),
+            [
+                note_on(0, 64, 100),
+                note_off(0, 64, 0),
+                note_on(0, 67, 100),
+                note_off(0, 67, 0),
+                note_on(0, 64, 100),
+            ]
+        );
+        assert_eq!(held(&mono), [64]);
+    }
+
+    #[test]
+    fn should_move_replayed_note_to_top_of_stack() {
+        let mut mono = MonoPriority::<10>::new(NotePriority::Last);
+        process(
+            &mut mono,
+            &[
+                note_on(0, 60, 100),
+                note_on(0, 62, 100),
+                note_on(0, 60, 100),
+            ],
+        );
+
+        assert_eq!(held(&mono), [62, 60]);
+        assert_eq!(mono.sounding(), Some((Channel::C1, 60.into())));
+    }
+
+    #[test]
+    fn should_overlap_notes_for_legato() {
+        let mut mono = MonoPriority::<10>::new(NotePriority::Last);
+        mono.set_transition(Transition::Legato);
+
+        assert_eq!(
+            process(&mut mono, &[note_on(0, 60, 100), note_on(0, 62, 90)]),
+            [note_on(0, 60, 100), note_on(0, 62, 90), note_off(0, 60, 0)]
+        );
+
+        mono.set_transition(Transition::LegatoWithoutNoteOff);
+        assert_eq!(
+            process(&mut mono, &[note_off(0, 62, 0), note_off(0, 60, 0)]),
+            [note_on(0, 60, 100), note_off(0, 60, 0)]
+        );
+    }
+
+    #[test]
+    fn should_evict_oldest_note_when_stack_is_full() {
+        let mut mono = MonoPriority::<3>::new(NotePriority::Last);
+        process(
+            &mut mono,
+            &[
+                note_on(0, 60, 100),
+                note_on(0, 62, 100),
+                note_on(0, 64, 100),
+                note_on(0, 65, 100),
+            ],
+        );
+        assert_eq!(held(&mono), [62, 64, 65]);
+
+        // releasing an evicted note does nothing
+        assert_eq!(process(&mut mono, &[note_off(0, 60, 0)]), []);
+    }
+
+    #[test]
+    fn should_not_evict_sounding_note() {
+        let mut mono = MonoPriority::<3>::new(NotePriority::Low);
+        process(
+            &mut mono,
+            &[
+                note_on(0, 60, 100),
+                note_on(0, 62, 100),
+                note_on(0, 64, 100),
+                note_on(0, 65, 100),
+            ],
+        );
+
+        assert_eq!(held(&mono), [60, 64, 65]);
+        assert_eq!(mono.sounding(), Some((Channel::C1, 60.into())));
+    }
+
+    #[test]
+    fn should_pass_other_messages() {
+        let mut mono = MonoPriority::<10>::new(NotePriority::Last);
+
+        assert_eq!(
+            process(&mut mono, &[MidiMessage::TimingClock]),
+            [MidiMessage::TimingClock]
+        );
+    }
+}
@@ -0,0 +1,378 @@
+//! Folding an MPE zone onto one channel for synths without MPE
+
+use crate::message::{channel, with_channel};
+use crate::MidiProcessor;
+use midi_convert::midi_types::{Channel, MidiMessage, Note};
+
+const BEND_CENTER: i32 = 0x2000;
+const BEND_MAX: i32 = 0x3fff;
+
+const DATA_ENTRY_MSB: u8 = 6;
+const DATA_ENTRY_LSB: u8 = 38;
+const RPN_LSB: u8 = 100;
+const RPN_MSB: u8 = 101;
+/// Registered parameter number of the MPE configuration message, MSB and LSB
+const MPE_CONFIGURATION: (u8, u8) = (0, 6);
+
+/// Layout of an MPE zone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MpeZone {
+    /// Master channel 1 with member channels from 2 up
+    Lower { members: u8 },
+    /// Master channel 16 with member channels from 15 down
+    Upper { members: u8 },
+}
+
+impl MpeZone {
+    pub fn master(self) -> Channel {
+        match self {
+            MpeZone::Lower { .. } => Channel::C1,
+            MpeZone::Upper { .. } => Channel::C16,
+        }
+    }
+
+    pub fn is_member(self, channel: Channel) -> bool {
+        let channel = u8::from(channel);
+        match self {
+            MpeZone::Lower { members } => (1..=members.min(15)).contains(&channel),
+            MpeZone::Upper { members } => (15 - members.min(15)..15).contains(&channel),
+        }
+    }
+
+    fn with_members(self, members: u8) -> Self {
+        match self {
+            MpeZone::Lower { .. } => MpeZone::Lower { members },
+            MpeZone::Upper { .. } => MpeZone::Upper { members },
+        }
+    }
+}
+
+/// What happens to the pitch bend of member channels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BendPolicy {
+    /// Drop member channel pitch bend, only the master channel bends
+    DropMemberBends,
+    /// Bend the master channel by the pitch bend of the channel of the most recent note, added to
+    /// the master channel pitch bend
+    FollowLastNote,
+}
+
+/// Rewrites the messages of an MPE zone onto its master channel, for synths without MPE
+///
+/// Notes and key pressure of member channels move to the master channel. A note played on two
+/// member channels at once is released when the last of them releases it. Channel pressure of a
+/// member channel becomes key pressure of the note playing on it. Member pitch bend is handled as
+/// the `BendPolicy` says, member bends are scaled from the member to the output pitch bend range,
+/// 48 and 2 semitones by default. Other member channel messages are per note expression the
+/// synth can't follow and are dropped.
+///
+/// Master channel messages are passed on, an MPE configuration message on the master channel
+/// changes the number of member channels and is not passed on. Messages on channels outside the
+/// zone and system messages are passed on untouched.
+#[derive(Debug, Clone)]
+pub struct MpeDownmix {
+    zone: MpeZone,
+    policy: BendPolicy,
+    member_bend_range: u8,
+    output_bend_range: u8,
+    /// Number of member channels holding each note
+    held: [u8; 128],
+    /// Note last played on each channel
+    notes: [Option<Note>; 16],
+    bends: [u16; 16],
+    followed: Option<Channel>,
+    sent_bend: Option<u16>,
+    /// Registered parameter number selected on the master channel
+    rpn: (u8, u8),
+}
+
+impl MpeDownmix {
+    pub fn new(zone: MpeZone) -> Self {
+        MpeDownmix {
+            zone,
+            policy: BendPolicy::DropMemberBends,
+            member_bend_range: 48,
+            output_bend_range: 2,
+            held: [0; 128],
+            notes: [None; 16],
+            bends: [BEND_CENTER as u16; 16],
+            followed: None,
+            sent_bend: None,
+            rpn: (0x7f, 0x7f),
+        }
+    }
+
+    pub fn zone(&self) -> MpeZone {
+        self.zone
+    }
+
+    pub fn set_bend_policy(&mut self, policy: BendPolicy) {
+        self.policy = policy;
+    }
+
+    /// Pitch bend ranges in semitones of the member channels and of the synth
+    pub fn set_bend_ranges(&mut self, member_semitones: u8, output_semitones: u8) {
+        self.member_bend_range = member_semitones;
+        self.output_bend_range = output_semitones.max(1);
+    }
+
+    fn master(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        match message {
+            MidiMessage::ControlChange(_, control, value) => {
+                let value = u8::from(value);
+                match u8::from(control) {
+                    RPN_MSB => self.rpn.0 = value,
+                    RPN_LSB => self.rpn.1 = value,
+                    DATA_ENTRY_MSB if self.rpn == MPE_CONFIGURATION => {
+                        self.zone = self.zone.with_members(value);
+                        return;
+                    }
+                    DATA_ENTRY_LSB if self.rpn == MPE_CONFIGURATION => return,
+                    _ => {}
+                }
+                emit(message);
+            }
+            MidiMessage::PitchBendChange(channel, value) => {
+                self.bends[usize::from(u8::from(channel))] = value.into();
+                match self.policy {
+                    BendPolicy::DropMemberBends => emit(message),
+                    BendPolicy::FollowLastNote => self.send_bend(emit),
+                }
+            }
+            message => emit(message),
+        }
+    }
+
+    fn member(
+        &mut self,
+        channel: Channel,
+        message: MidiMessage,
+        emit: &mut dyn FnMut(MidiMessage),
+    ) {
+        let master = self.zone.master();
+        let index = usize::from(u8::from(channel));
+        match message {
+            MidiMessage::NoteOn(_, note, velocity) if u8::from(velocity) > 0 => {
+                self.notes[index] = Some(note);
+                let held = &mut self.held[usize::from(u8::from(note))];
+                *held = held.saturating_add(1);
+                if self.policy == BendPolicy::FollowLastNote {
+                    self.followed = Some(channel);
+                    self.send_bend(emit);
+                }
+                emit(with_channel(message, master));
+            }
+            MidiMessage::NoteOn(_, note, _) | MidiMessage::NoteOff(_, note, _) => {
+                if self.notes[index] == Some(note) {
+                    self.notes[index] = None;
+                }
+                let held = &mut self.held[usize::from(u8::from(note))];
+                match *held {
+                    0 => {}
+                    1 => {
+                        *held = 0;
+                        emit(with_channel(message, master));
+                    }
+                    _ => *held -= 1,
+                }
+            }
+            MidiMessage::KeyPressure(..) => emit(with_channel(message, master)),
+            MidiMessage::ChannelPressure(_, value) => {
+                if let Some(note) = self.notes[index] {
+                    emit(MidiMessage::KeyPressure(master, note, value));
+                }
+            }
+            MidiMessage::PitchBendChange(_, value) => {
+                self.bends[index] = value.into();
+                if self.policy == BendPolicy::FollowLastNote && self.followed == Some(channel) {
+                    self.send_bend(emit);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Send the master bend plus the scaled bend of the followed channel if it changed
+    fn send_bend(&mut self, emit: &mut dyn FnMut(MidiMessage)) {
+        let master = self.zone.master();
+        let offset = |bend: u16| i32::from(bend) - BEND_CENTER;
+        let member = self.followed.map_or(0, |channel| {
+            offset(self.bends[usize::from(u8::from(channel))]) * i32::from(self.member_bend_range)
+                / i32::from(self.output_bend_range)
+        });
+        let master_offset = offset(self.bends[usize::from(u8::from(master))]);
+        let value = (BEND_CENTER + master_offset + member).clamp(0, BEND_MAX) as u16;
+        if self.sent_bend != Some(value) {
+            self.sent_bend = Some(value);
+            emit(MidiMessage::PitchBendChange(master, value.into()));
+        }
+    }
+}
+
+impl MidiProcessor for MpeDownmix {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        match channel(&message) {
+            Some(channel) if channel == self.zone.master() => self.master(message, emit),
+            Some(channel) if self.zone.is_member(channel) => self.member(channel, message, emit),
+            _ => emit(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_off, note_on, pitch_bend};
+    use std::vec::Vec;
+
+    fn run(downmix: &mut MpeDownmix, messages: &[MidiMessage]) -> Vec<MidiMessage> {
+        let mut output = Vec::new();
+        for message in messages {
+            downmix.process(*message, &mut |message| output.push(message));
+        }
+        output
+    }
+
+    #[test]
+    fn should_know_zone_channels() {
+        let lower = MpeZone::Lower { members: 3 };
+        assert_eq!(lower.master(), Channel::C1);
+        assert!(!lower.is_member(Channel::C1));
+        assert!(lower.is_member(Channel::C2));
+        assert!(lower.is_member(Channel::C4));
+        assert!(!lower.is_member(Channel::C5));
+
+        let upper = MpeZone::Upper { members: 15 };
+        assert_eq!(upper.master(), Channel::C16);
+        assert!(upper.is_member(Channel::C1));
+        assert!(upper.is_member(Channel::C15));
+        assert!(!upper.is_member(Channel::C16));
+    }
+
+    #[test]
+    fn should_release_shared_notes_with_the_last_channel() {
+        let mut downmix = MpeDownmix::new(MpeZone::Lower { members: 15 });
+        let output = run(
+            &mut downmix,
+            &[
+                note_on(1, 60, 90),
+                note_on(2, 60, 80),
+                note_off(1, 60, 0),
+                note_on(2, 60, 0),
+                note_off(3, 61, 0),
+            ],
+        );
+        assert_eq!(
+            output,
+            [note_on(0, 60, 90), note_on(0, 60, 80), note_on(0, 60, 0)]
+        );
+    }
+
+    #[test]
+    fn should_turn_member_pressure_into_key_pressure() {
+        let mut downmix = MpeDownmix::new(MpeZone::Upper { members: 2 });
+        let output = run(
+            &mut downmix,
+            &[
+                MidiMessage::ChannelPressure(Channel::C15, 10.into()),
+                note_on(14, 72, 100),
+                MidiMessage::ChannelPressure(Channel::C15, 20.into()),
+                MidiMessage::KeyPressure(Channel::C14, 50.into(), 30.into()),
+                cc(14, 74, 64),
+                // Outside the zone and master channel messages pass
+                MidiMessage::ChannelPressure(Channel::C1, 5.into()),
+                MidiMessage::ChannelPressure(Channel::C16, 6.into()),
+            ],
+        );
+        assert_eq!(
+            output,
+            [
+                note_on(15, 72, 100),
+                MidiMessage::KeyPressure(Channel::C16, 72.into(), 20.into()),
+                MidiMessage::KeyPressure(Channel::C16, 50.into(), 30.into()),
+                MidiMessage::ChannelPressure(Channel::C1, 5.into()),
+                MidiMessage::ChannelPressure(Channel::C16, 6.into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_follow_bend_of_last_note() {
+        let mut downmix = MpeDownmix::new(MpeZone::Lower { members: 15 });
+        downmix.set_bend_policy(BendPolicy::FollowLastNote);
+        downmix.set_bend_ranges(48, 12);
+        let output = run(
+            &mut downmix,
+            &[
+                pitch_bend(1, 256),
+                note_on(1, 60, 100),
+                note_on(2, 64, 100),
+                // Not the last note
+                pitch_bend(1, 512),
+                pitch_bend(2, -256),
+                // Added to the member bend
+                pitch_bend(0, 1024),
+                // Clamped
+                pitch_bend(2, 8191),
+            ],
+        );
+        assert_eq!(
+            output,
+            [
+                // 256 in the member range is 1024 in the output range
+                pitch_bend(0, 1024),
+                note_on(0, 60, 100),
+                pitch_bend(0, 0),
+                note_on(0, 64, 100),
+                pitch_bend(0, -1024),
+                pitch_bend(0, 0),
+                pitch_bend(0, 8191),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_drop_member_bends() {
+        let mut downmix = MpeDownmix::new(MpeZone::Lower { members: 15 });
+        let output = run(
+            &mut downmix,
+            &[
+                pitch_bend(1, 256),
+                pitch_bend(0, 1024),
+                pitch_bend(4, -8192),
+            ],
+        );
+        assert_eq!(output, [pitch_bend(0, 1024)]);
+    }
+
+    #[test]
+    fn should_apply_mpe_configuration_message() {
+        let mut downmix = MpeDownmix::new(MpeZone::Lower { members: 15 });
+        let output = run(
+            &mut downmix,
+            &[
+                cc(0, 101, 0),
+                cc(0, 100, 6),
+                cc(0, 6, 3),
+                cc(0, 38, 0),
+                cc(0, 101, 127),
+                cc(0, 100, 127),
+                note_on(4, 60, 100),
+                cc(0, 6, 3),
+            ],
+        );
+        assert_eq!(downmix.zone(), MpeZone::Lower { members: 3 });
+        assert_eq!(
+            output,
+            [
+                cc(0, 101, 0),
+                cc(0, 100, 6),
+                cc(0, 101, 127),
+                cc(0, 100, 127),
+                note_on(4, 60, 100),
+                cc(0, 6, 3),
+            ]
+        );
+    }
+}
@@ -0,0 +1,691 @@
+//! MIDI Show Control, enabled with the `msc` feature
+//!
+//! Commands are universal realtime system exclusive messages with these data bytes:
+//!
+//! | Bytes | Content |
+//! |-------|---------|
+//! | 1 | `0x7f`, universal realtime |
+//! | 1 | Device id, `0x00` to `0x6f` for a device, `0x70` to `0x7e` for a group, `ALL_CALL` |
+//! | 1 | `0x02`, MIDI Show Control |
+//! | 1 | Command format, the kind of equipment addressed, for example `LIGHTING` or `SOUND` |
+//! | 1 | Command, `GO` to `GO_OFF` |
+//! | any | Command data |
+//!
+//! Cue numbers, lists and paths are ASCII digits with `.` separators, one field after the other
+//! separated by `0x00`. Trailing fields are left out.
+
+use crate::{MidiError, MidiOut, SysexHandler, TooSmall};
+use core::fmt::{self, Debug, Display, Formatter};
+use embedded_hal_nb::serial;
+
+/// Universal realtime system exclusive id
+const UNIVERSAL_REALTIME: u8 = 0x7f;
+/// Sub id of MIDI Show Control messages
+const SHOW_CONTROL: u8 = 0x02;
+/// Separates the cue fields
+const SEPARATOR: u8 = 0x00;
+
+/// Device id addressing every device
+pub const ALL_CALL: u8 = 0x7f;
+
+/// Command format of lighting equipment
+pub const LIGHTING: u8 = 0x01;
+/// Command format of sound equipment
+pub const SOUND: u8 = 0x10;
+/// Command format of machinery
+pub const MACHINERY: u8 = 0x20;
+/// Command format of video equipment
+pub const VIDEO: u8 = 0x30;
+/// Command format addressing every kind of equipment
+pub const ALL_TYPES: u8 = 0x7f;
+
+/// Start a cue
+pub const GO: u8 = 0x01;
+/// Stop a running cue
+pub const STOP: u8 = 0x02;
+/// Resume a stopped cue
+pub const RESUME: u8 = 0x03;
+/// Start a cue with a time
+pub const TIMED_GO: u8 = 0x04;
+/// Load a cue so it starts with the next go
+pub const LOAD: u8 = 0x05;
+/// Set a generic control to a value
+pub const SET: u8 = 0x06;
+/// Fire a macro
+pub const FIRE: u8 = 0x07;
+/// Switch every output off, keeping the state for `RESTORE`
+pub const ALL_OFF: u8 = 0x08;
+/// Restore the outputs switched off by `ALL_OFF`
+pub const RESTORE: u8 = 0x09;
+/// Stop all cues and go back to the start
+pub const RESET: u8 = 0x0a;
+/// Fade out a running cue
+pub const GO_OFF: u8 = 0x0b;
+
+/// Longest cue number, list or path
+///
+/// Three fields of this length with a time and the header still fit the 128 bytes a MIDI Show
+/// Control message may take.
+pub const CUE_FIELD_LEN: usize = 32;
+
+/// Longest system exclusive message the commands render to, without 0xf0 and 0xf7
+pub const MAX_DATA_LEN: usize = 126;
+
+/// A cue number, list or path
+pub type CueField = heapless::String<CUE_FIELD_LEN>;
+
+/// Reason a MIDI Show Control message or cue field was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MscError {
+    /// The system exclusive message is not a MIDI Show Control message
+    NotMsc,
+    /// The command is not one of the general commands
+    UnknownCommand(u8),
+    /// A cue field is empty, too long or has a byte other than a digit or `.`
+    InvalidCue,
+    /// The command data is too short or too long for the command
+    Malformed,
+}
+
+impl Display for MscError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MscError::NotMsc => f.write_str("not a show control message"),
+            MscError::UnknownCommand(command) => write!(f, "unknown command {:#04x}", command),
+            MscError::InvalidCue => f.write_str("invalid cue field"),
+            MscError::Malformed => f.write_str("malformed command data"),
+        }
+    }
+}
+
+impl core::error::Error for MscError {}
+
+fn cue_field(bytes: &[u8]) -> Result<CueField, MscError> {
+    if bytes.is_empty()
+        || bytes.len() > CUE_FIELD_LEN
+        || !bytes
+            .iter()
+            .all(|byte| byte.is_ascii_digit() || *byte == b'.')
+    {
+        return Err(MscError::InvalidCue);
+    }
+    let mut field = CueField::new();
+    for byte in bytes {
+        field
+            .push(char::from(*byte))
+            .map_err(|_| MscError::InvalidCue)?;
+    }
+    Ok(field)
+}
+
+/// A cue number with the cue list and path it is in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cue {
+    number: CueField,
+    list: Option<CueField>,
+    path: Option<CueField>,
+}
+
+impl Cue {
+    /// Cue `number`, like `"235.6"`
+    pub fn new(number: &str) -> Result<Self, MscError> {
+        Ok(Cue {
+            number: cue_field(number.as_bytes())?,
+            list: None,
+            path: None,
+        })
+    }
+
+    /// The cue in cue `list`
+    pub fn with_list(mut self, list: &str) -> Result<Self, MscError> {
+        self.list = Some(cue_field(list.as_bytes())?);
+        Ok(self)
+    }
+
+    /// The cue in cue `path`, fails if the cue has no list
+    pub fn with_path(mut self, path: &str) -> Result<Self, MscError> {
+        if self.list.is_none() {
+            return Err(MscError::InvalidCue);
+        }
+        self.path = Some(cue_field(path.as_bytes())?);
+        Ok(self)
+    }
+
+    /// Cue number
+    pub fn number(&self) -> &str {
+        &self.number
+    }
+
+    /// Cue list the cue is in, if given
+    pub fn list(&self) -> Option<&str> {
+        self.list.as_deref()
+    }
+
+    /// Cue path the cue list is in, if given
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// Parse the cue fields of command data, no data is no cue
+    fn parse(data: &[u8]) -> Result<Option<Self>, MscError> {
+        if data.is_empty() {
+            return Ok(None);
+        }
+        let mut fields = data.split(|byte| *byte == SEPARATOR);
+        let mut cue = Cue {
+            number: cue_field(fields.next().unwrap_or_default())?,
+            list: None,
+            path: None,
+        };
+        cue.list = fields.next().map(cue_field).transpose()?;
+        cue.path = fields.next().map(cue_field).transpose()?;
+        if fields.next().is_some() {
+            return Err(MscError::Malformed);
+        }
+        Ok(Some(cue))
+    }
+
+    fn encode(&self, buffer: &mut Encoder<'_>) -> Result<(), TooSmall> {
+        buffer.extend(self.number.as_bytes())?;
+        for field in self.list.iter().chain(&self.path) {
+            buffer.push(SEPARATOR)?;
+            buffer.extend(field.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Standard time of `TIMED_GO` and `SET`, the hours byte carries the frame rate in bits 5 and 6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MscTime {
+    /// Hours in bits 0 to 4, the frame rate in bits 5 and 6
+    pub hours: u8,
+    /// Minutes, 0 to 59
+    pub minutes: u8,
+    /// Seconds, 0 to 59
+    pub seconds: u8,
+    /// Frames, bit 5 tells what `fractional` carries
+    pub frames: u8,
+    /// Fractional frames, or the status bits if bit 5 of `frames` is set
+    pub fractional: u8,
+}
+
+impl MscTime {
+    const LEN: usize = 5;
+
+    fn parse(data: &[u8]) -> Result<Self, MscError> {
+        match *data {
+            [hours, minutes, seconds, frames, fractional] => Ok(MscTime {
+                hours,
+                minutes,
+                seconds,
+                frames,
+                fractional,
+            }),
+            _ => Err(MscError::Malformed),
+        }
+    }
+
+    fn bytes(&self) -> [u8; Self::LEN] {
+        [
+            self.hours,
+            self.minutes,
+            self.seconds,
+            self.frames,
+            self.fractional,
+        ]
+        .map(|byte| byte & 0x7f)
+    }
+}
+
+/// A general MIDI Show Control command, commands with an optional cue act on the current cue
+/// without one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MscCommand {
+    /// Start a cue
+    Go(Option<Cue>),
+    /// Stop a running cue
+    Stop(Option<Cue>),
+    /// Resume a stopped cue
+    Resume(Option<Cue>),
+    /// Start a cue at a time
+    TimedGo(MscTime, Option<Cue>),
+    /// Load a cue so it starts with the next go
+    Load(Cue),
+    /// Set generic control `control` to `value`, optionally at `time`
+    Set {
+        control: u16,
+        value: u16,
+        time: Option<MscTime>,
+    },
+    /// Fire macro `0` to `127`
+    Fire(u8),
+    /// Switch every output off, keeping the state for `Restore`
+    AllOff,
+    /// Restore the outputs switched off by `AllOff`
+    Restore,
+    /// Stop all cues and go back to the start
+    Reset,
+    /// Fade out a running cue
+    GoOff(Option<Cue>),
+}
+
+impl MscCommand {
+    /// The command byte
+    pub fn command(&self) -> u8 {
+        match self {
+            MscCommand::Go(_) => GO,
+            MscCommand::Stop(_) => STOP,
+            MscCommand::Resume(_) => RESUME,
+            MscCommand::TimedGo(..) => TIMED_GO,
+            MscCommand::Load(_) => LOAD,
+            MscCommand::Set { .. } => SET,
+            MscCommand::Fire(_) => FIRE,
+            MscCommand::AllOff => ALL_OFF,
+            MscCommand::Restore => RESTORE,
+            MscCommand::Reset => RESET,
+            MscCommand::GoOff(_) => GO_OFF,
+        }
+    }
+
+    /// Write the data bytes of the system exclusive message, without 0xf0 and 0xf7, to `buffer`,
+    /// returns the number of bytes written
+    pub fn encode(&self, device_id: u8, format: u8, buffer: &mut [u8]) -> Result<usize, TooSmall> {
+        let mut encoder = Encoder { buffer, len: 0 };
+        encoder.extend(&[
+            UNIVERSAL_REALTIME,
+            device_id & 0x7f,
+            SHOW_CONTROL,
+            format & 0x7f,
+            self.command(),
+        ])?;
+        match self {
+            MscCommand::Go(cue)
+            | MscCommand::Stop(cue)
+            | MscCommand::Resume(cue)
+            | MscCommand::GoOff(cue) => {
+                if let Some(cue) = cue {
+                    cue.encode(&mut encoder)?;
+                }
+            }
+            MscCommand::TimedGo(time, cue) => {
+                encoder.extend(&time.bytes())?;
+                if let Some(cue) = cue {
+                    cue.encode(&mut encoder)?;
+                }
+            }
+            MscCommand::Load(cue) => cue.encode(&mut encoder)?,
+            MscCommand::Set {
+                control,
+                value,
+                time,
+            } => {
+                for word in [*control, *value] {
+                    encoder.extend(&[(word & 0x7f) as u8, (word >> 7 & 0x7f) as u8])?;
+                }
+                if let Some(time) = time {
+                    encoder.extend(&time.bytes())?;
+                }
+            }
+            MscCommand::Fire(number) => encoder.push(number & 0x7f)?,
+            MscCommand::AllOff | MscCommand::Restore | MscCommand::Reset => {}
+        }
+        Ok(encoder.len)
+    }
+}
+
+/// Writes bytes to a buffer, failing when it is full
+struct Encoder<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Encoder<'_> {
+    fn push(&mut self, byte: u8) -> Result<(), TooSmall> {
+        *self.buffer.get_mut(self.len).ok_or(TooSmall)? = byte;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn extend(&mut self, bytes: &[u8]) -> Result<(), TooSmall> {
+        bytes.iter().try_for_each(|byte| self.push(*byte))
+    }
+}
+
+/// Send `command` to `device_id`, addressing equipment of `format`
+pub fn render<TX, E>(
+    device_id: u8,
+    format: u8,
+    command: &MscCommand,
+    out: &mut MidiOut<TX>,
+) -> Result<(), MidiError<E>>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+{
+    let mut buffer = [0; MAX_DATA_LEN];
+    let len = command
+        .encode(device_id, format, &mut buffer)
+        .map_err(|_| MidiError::ValueOutOfRange)?;
+    out.write_sysex_bytes(buffer[..len].iter().copied())
+}
+
+/// A received MIDI Show Control message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MscMessage {
+    /// Device id the message addresses, a device, a group or `ALL_CALL`
+    pub device_id: u8,
+    /// Command format, the kind of equipment addressed
+    pub format: u8,
+    /// The command with its cue, time or values
+    pub command: MscCommand,
+}
+
+impl MscMessage {
+    /// Parse the data bytes of a system exclusive message, without 0xf0 and 0xf7
+    pub fn parse(data: &[u8]) -> Result<Self, MscError> {
+        let (device_id, format, command, data) = match *data {
+            [UNIVERSAL_REALTIME, device_id, SHOW_CONTROL, format, command, ref data @ ..] => {
+                (device_id, format, command, data)
+            }
+            _ => return Err(MscError::NotMsc),
+        };
+        let command = match command {
+            GO => MscCommand::Go(Cue::parse(data)?),
+            STOP => MscCommand::Stop(Cue::parse(data)?),
+            RESUME => MscCommand::Resume(Cue::parse(data)?),
+            TIMED_GO if data.len() >= MscTime::LEN => MscCommand::TimedGo(
+                MscTime::parse(&data[..MscTime::LEN])?,
+                Cue::parse(&data[MscTime::LEN..])?,
+            ),
+            LOAD => MscCommand::Load(Cue::parse(data)?.ok_or(MscError::Malformed)?),
+            SET if data.len() == 4 || data.len() == 4 + MscTime::LEN => MscCommand::Set {
+                control: u16::from(data[0]) | u16::from(data[1]) << 7,
+                value: u16::from(data[2]) | u16::from(data[3]) << 7,
+                time: data
+                    .get(4..)
+                    .filter(|time| !time.is_empty())
+                    .map(MscTime::parse)
+                    .transpose()?,
+            },
+            FIRE => match *data {
+                [number] => MscCommand::Fire(number),
+                _ => return Err(MscError::Malformed),
+            },
+            ALL_OFF | RESTORE | RESET if !data.is_empty() => return Err(MscError::Malformed),
+            ALL_OFF => MscCommand::AllOff,
+            RESTORE => MscCommand::Restore,
+            RESET => MscCommand::Reset,
+            GO_OFF => MscCommand::GoOff(Cue::parse(data)?),
+            TIMED_GO | SET => return Err(MscError::Malformed),
+            command => return Err(MscError::UnknownCommand(command)),
+        };
+        Ok(MscMessage {
+            device_id,
+            format,
+            command,
+        })
+    }
+
+    /// The message addresses `device_id`, directly or with the all call id
+    pub fn is_for(&self, device_id: u8) -> bool {
+        self.device_id == device_id || self.device_id == ALL_CALL
+    }
+}
+
+/// Recognizes MIDI Show Control messages in received system exclusive messages
+///
+/// Feed the system exclusive messages to the receiver, for example through a `SysexStream`, and
+/// `take` the command after every message. Other system exclusive messages are ignored, malformed
+/// show control messages are counted and dropped.
+#[derive(Debug, Default)]
+pub struct MscReceiver {
+    data: heapless::Vec<u8, MAX_DATA_LEN>,
+    overflow: bool,
+    received: Option<MscMessage>,
+    rejected: u32,
+}
+
+impl MscReceiver {
+    /// Receiver with no message received yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The last show control message received, if it wasn't taken yet
+    pub fn take(&mut self) -> Option<MscMessage> {
+        self.received.take()
+    }
+
+    /// Number of malformed show control messages dropped
+    pub fn rejected(&self) -> u32 {
+        self.rejected
+    }
+
+    fn finish(&mut self) {
+        let is_msc = matches!(*self.data, [UNIVERSAL_REALTIME, _, SHOW_CONTROL, ..]);
+        if !is_msc {
+            return;
+        }
+        let parsed = if self.overflow {
+            Err(MscError::Malformed)
+        } else {
+            MscMessage::parse(&self.data)
+        };
+        match parsed {
+            Ok(message) => self.received = Some(message),
+            Err(_) => self.rejected = self.rejected.wrapping_add(1),
+        }
+    }
+}
+
+impl SysexHandler for MscReceiver {
+    fn on_sysex_chunk(&mut self, chunk: &[u8], first: bool, last: bool) {
+        if first {
+            self.data.clear();
+            self.overflow = false;
+        }
+        self.overflow |= self.data.extend_from_slice(chunk).is_err();
+        if last {
+            self.finish();
+        }
+    }
+
+    fn on_sysex_abort(&mut self) {
+        self.data.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::test_util::Wire;
+    use crate::SysexStream;
+    use std::vec::Vec;
+
+    fn rendered(device_id: u8, format: u8, command: &MscCommand) -> Vec<u8> {
+        let mut out = MidiOut::new(Wire::default());
+        render(device_id, format, command, &mut out).unwrap();
+        out.tx.0
+    }
+
+    fn receive(bytes: &[u8]) -> (Option<MscMessage>, u32) {
+        let mut stream = SysexStream::<16>::new();
+        let mut receiver = MscReceiver::new();
+        for byte in bytes {
+            stream.feed(0, *byte, &mut receiver);
+        }
+        (receiver.take(), receiver.rejected())
+    }
+
+    /// Go cue 235.6 in list 36.6 on lighting device 1
+    const GO_235_6: &[u8] = &[
+        0xf0, 0x7f, 0x01, 0x02, 0x01, 0x01, 0x32, 0x33, 0x35, 0x2e, 0x36, 0x00, 0x33, 0x36, 0x2e,
+        0x36, 0xf7,
+    ];
+
+    #[test]
+    fn should_render_go_with_cue_and_list() {
+        let cue = Cue::new("235.6").unwrap().with_list("36.6").unwrap();
+        assert_eq!(
+            rendered(0x01, LIGHTING, &MscCommand::Go(Some(cue))),
+            GO_235_6
+        );
+    }
+
+    #[test]
+    fn should_render_commands_without_cue() {
+        assert_eq!(
+            rendered(ALL_CALL, ALL_TYPES, &MscCommand::AllOff),
+            [0xf0, 0x7f, 0x7f, 0x02, 0x7f, 0x08, 0xf7]
+        );
+        assert_eq!(
+            rendered(0x10, SOUND, &MscCommand::Stop(None)),
+            [0xf0, 0x7f, 0x10, 0x02, 0x10, 0x02, 0xf7]
+        );
+        assert_eq!(
+            rendered(0x01, LIGHTING, &MscCommand::Fire(12)),
+            [0xf0, 0x7f, 0x01, 0x02, 0x01, 0x07, 0x0c, 0xf7]
+        );
+    }
+
+    #[test]
+    fn should_render_cue_path_and_set() {
+        let cue = Cue::new("1")
+            .unwrap()
+            .with_list("2")
+            .unwrap()
+            .with_path("3.1")
+            .unwrap();
+        assert_eq!(
+            rendered(0x02, LIGHTING, &MscCommand::Load(cue)),
+            [0xf0, 0x7f, 0x02, 0x02, 0x01, 0x05, 0x31, 0x00, 0x32, 0x00, 0x33, 0x2e, 0x31, 0xf7]
+        );
+        let set = MscCommand::Set {
+            control: 0x0181,
+            value: 0x3fff,
+            time: None,
+        };
+        assert_eq!(
+            rendered(0x02, LIGHTING, &set),
+            [0xf0, 0x7f, 0x02, 0x02, 0x01, 0x06, 0x01, 0x03, 0x7f, 0x7f, 0xf7]
+        );
+    }
+
+    #[test]
+    fn should_receive_go_with_cue_and_list() {
+        let (message, rejected) = receive(GO_235_6);
+        let message = message.unwrap();
+        assert_eq!(rejected, 0);
+        assert_eq!((message.device_id, message.format), (0x01, LIGHTING));
+        assert!(message.is_for(0x01));
+        assert!(!message.is_for(0x02));
+        match message.command {
+            MscCommand::Go(Some(cue)) => {
+                assert_eq!(cue.number(), "235.6");
+                assert_eq!(cue.list(), Some("36.6"));
+                assert_eq!(cue.path(), None);
+            }
+            command => panic!("unexpected {:?}", command),
+        }
+    }
+
+    #[test]
+    fn should_round_trip_commands() {
+        let time = MscTime {
+            hours: 0x61,
+            minutes: 2,
+            seconds: 3,
+            frames: 4,
+            fractional: 5,
+        };
+        let commands = [
+            MscCommand::Go(None),
+            MscCommand::Resume(Some(Cue::new("7").unwrap())),
+            MscCommand::TimedGo(time, Some(Cue::new("1.5").unwrap())),
+            MscCommand::TimedGo(time, None),
+            MscCommand::Set {
+                control: 12,
+                value: 1000,
+                time: Some(time),
+            },
+            MscCommand::Restore,
+            MscCommand::Reset,
+            MscCommand::GoOff(Some(Cue::new("10").unwrap().with_list("1").unwrap())),
+        ];
+        for command in commands {
+            let (message, _) = receive(&rendered(0x05, ALL_TYPES, &command));
+            assert_eq!(message.unwrap().command, command);
+        }
+    }
+
+    #[test]
+    fn should_reject_malformed_cue_fields() {
+        assert_eq!(Cue::new(""), Err(MscError::InvalidCue));
+        assert_eq!(Cue::new("1a"), Err(MscError::InvalidCue));
+        assert_eq!(
+            Cue::new(&"1".repeat(CUE_FIELD_LEN + 1)),
+            Err(MscError::InvalidCue)
+        );
+        assert_eq!(
+            Cue::new("1").unwrap().with_path("2"),
+            Err(MscError::InvalidCue)
+        );
+
+        let malformed: [&[u8]; 7] = [
+            // Letter in the cue number
+            &[0x7f, 0x01, 0x02, 0x01, 0x01, 0x31, 0x41],
+            // Empty list
+            &[0x7f, 0x01, 0x02, 0x01, 0x01, 0x31, 0x00],
+            // Fourth field
+            &[
+                0x7f, 0x01, 0x02, 0x01, 0x01, 0x31, 0x00, 0x31, 0x00, 0x31, 0x00, 0x31,
+            ],
+            // Load without cue
+            &[0x7f, 0x01, 0x02, 0x01, 0x05],
+            // Timed go with a short time
+            &[0x7f, 0x01, 0x02, 0x01, 0x04, 0x00, 0x00],
+            // All off with data
+            &[0x7f, 0x01, 0x02, 0x01, 0x08, 0x00],
+            // Fire without macro
+            &[0x7f, 0x01, 0x02, 0x01, 0x07],
+        ];
+        for data in malformed {
+            assert!(MscMessage::parse(data).is_err(), "{:02x?}", data);
+            let bytes: Vec<u8> = [0xf0].iter().chain(data).chain(&[0xf7]).copied().collect();
+            assert_eq!(receive(&bytes), (None, 1));
+        }
+
+        let mut long = std::vec![0x7f, 0x01, 0x02, 0x01, 0x01];
+        long.extend([0x31; CUE_FIELD_LEN + 1]);
+        assert_eq!(MscMessage::parse(&long), Err(MscError::InvalidCue));
+        assert_eq!(
+            MscMessage::parse(&[0x7f, 0x01, 0x02, 0x01, 0x40]),
+            Err(MscError::UnknownCommand(0x40))
+        );
+    }
+
+    #[test]
+    fn should_ignore_other_sysex() {
+        assert_eq!(
+            MscMessage::parse(&[0x7e, 0x01, 0x06, 0x01]),
+            Err(MscError::NotMsc)
+        );
+        assert_eq!(receive(&[0xf0, 0x7d, 0x01, 0x02, 0xf7]), (None, 0));
+        assert_eq!(receive(&[0xf0, 0x7f, 0x01, 0x01, 0x01, 0xf7]), (None, 0));
+        // Longer than any show control message
+        let mut long = std::vec![0xf0, 0x7f, 0x01, 0x02, 0x01, 0x01];
+        long.extend([0x31; MAX_DATA_LEN]);
+        long.push(0xf7);
+        assert_eq!(receive(&long), (None, 1));
+    }
+
+    #[test]
+    fn should_fail_to_encode_into_short_buffer() {
+        let mut buffer = [0; 6];
+        let go = MscCommand::Go(Some(Cue::new("12").unwrap()));
+        assert_eq!(go.encode(0, LIGHTING, &mut buffer), Err(TooSmall));
+        assert_eq!(MscCommand::Reset.encode(0, LIGHTING, &mut buffer), Ok(5));
+    }
+}
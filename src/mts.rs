@@ -0,0 +1,537 @@
+//! Single note tuning changes of the MIDI Tuning Standard
+//!
+//! A tuning change is a universal realtime system exclusive message with these data bytes:
+//!
+//! | Bytes | Content |
+//! |-------|---------|
+//! | 1 | `0x7f`, universal realtime |
+//! | 1 | Device id, `0x7f` for all devices |
+//! | 1 | `0x08`, MIDI tuning standard |
+//! | 1 | `0x02`, single note tuning change |
+//! | 1 | Tuning program |
+//! | 1 | Number of changes |
+//! | 4 per change | Note, then the semitone and the 14 bit fraction it is tuned to, high bits first |
+//!
+//! The frequency `0x7f 0x7f 0x7f` leaves the tuning of the note unchanged.
+
+use crate::{MidiError, MidiOut, SysexHandler};
+use core::convert::TryFrom;
+use core::fmt::{self, Debug, Display, Formatter};
+use embedded_hal_nb::serial;
+use midi_convert::midi_types::Note;
+
+/// Universal realtime system exclusive id
+const UNIVERSAL_REALTIME: u8 = 0x7f;
+/// Sub id of MIDI tuning standard messages
+const MIDI_TUNING: u8 = 0x08;
+/// Sub id of single note tuning changes
+const SINGLE_NOTE_TUNING_CHANGE: u8 = 0x02;
+/// Bytes before the changes
+const HEADER_LEN: usize = 6;
+
+/// Device id addressing every device
+pub const ALL_DEVICES: u8 = 0x7f;
+
+/// Most changes a message can carry, the count is a data byte
+pub const MAX_ENTRIES: usize = 127;
+
+/// Reason a tuning change was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MtsError {
+    /// The system exclusive message is not a single note tuning change
+    NotMts,
+    /// The message is shorter or longer than its number of changes
+    Malformed,
+    /// A byte of the message or a field to encode doesn't fit 7 bits
+    InvalidField,
+    /// More changes than fit the buffer or a message
+    TooManyEntries,
+}
+
+impl Display for MtsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MtsError::NotMts => f.write_str("not a single note tuning change"),
+            MtsError::Malformed => f.write_str("malformed tuning change"),
+            MtsError::InvalidField => f.write_str("tuning field out of range"),
+            MtsError::TooManyEntries => f.write_str("too many tuning changes"),
+        }
+    }
+}
+
+impl core::error::Error for MtsError {}
+
+/// Frequency a note is tuned to, a semitone of equal temperament plus a fraction of a semitone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TuningTarget {
+    semitone: u8,
+    fraction: u16,
+}
+
+impl TuningTarget {
+    /// Leaves the tuning of the note unchanged
+    pub const NO_CHANGE: TuningTarget = TuningTarget {
+        semitone: 0x7f,
+        fraction: 0x3fff,
+    };
+
+    /// Target `fraction` / 16384 semitones above `semitone`, `None` when a field doesn't fit
+    pub fn new(semitone: u8, fraction: u16) -> Option<Self> {
+        (semitone <= 0x7f && fraction <= 0x3fff).then_some(TuningTarget { semitone, fraction })
+    }
+
+    /// Target `cents` away from the equal temperament pitch of `note`, `None` when it is below
+    /// note 0 or above the highest target
+    pub fn from_offset(note: Note, cents: i16) -> Option<Self> {
+        let cents = i32::from(u8::from(note)) * 100 + i32::from(cents);
+        let semitone = u8::try_from(cents.div_euclid(100)).ok()?;
+        // Rounded to the nearest step
+        let fraction = (cents.rem_euclid(100) * 16384 + 50) / 100;
+        let (semitone, fraction) = if fraction == 16384 {
+            (semitone.checked_add(1)?, 0)
+        } else {
+            (semitone, fraction as u16)
+        };
+        Self::new(semitone, fraction).filter(|target| *target != Self::NO_CHANGE)
+    }
+
+    pub fn semitone(self) -> u8 {
+        self.semitone
+    }
+
+    /// Fraction of a semitone in 1/16384 semitones
+    pub fn fraction(self) -> u16 {
+        self.fraction
+    }
+
+    pub fn is_no_change(self) -> bool {
+        self == Self::NO_CHANGE
+    }
+
+    /// Distance from the equal temperament pitch of `note` in cents, rounded
+    pub fn offset_cents(self, note: Note) -> i32 {
+        let semitones = i32::from(self.semitone) - i32::from(u8::from(note));
+        semitones * 100 + (i32::from(self.fraction) * 100 + 8192) / 16384
+    }
+
+    fn bytes(self) -> [u8; 3] {
+        [
+            self.semitone,
+            (self.fraction >> 7) as u8,
+            (self.fraction & 0x7f) as u8,
+        ]
+    }
+}
+
+/// A single note tuning change, with its changes in a slice
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TuningChange<'a> {
+    pub device_id: u8,
+    pub program: u8,
+    pub entries: &'a [(Note, TuningTarget)],
+}
+
+impl<'a> TuningChange<'a> {
+    /// Parse the data bytes of a system exclusive message, without 0xf0 and 0xf7, keeping the
+    /// changes in `buffer`
+    pub fn parse(data: &[u8], buffer: &'a mut [(Note, TuningTarget)]) -> Result<Self, MtsError> {
+        let (device_id, program, count, changes) = match *data {
+            [UNIVERSAL_REALTIME, device_id, MIDI_TUNING, SINGLE_NOTE_TUNING_CHANGE, program, count, ref changes @ ..] => {
+                (device_id, program, usize::from(count), changes)
+            }
+            _ => return Err(MtsError::NotMts),
+        };
+        if data.iter().any(|byte| *byte > 0x7f) {
+            return Err(MtsError::InvalidField);
+        }
+        if changes.len() != count * 4 {
+            return Err(MtsError::Malformed);
+        }
+        let entries = buffer.get_mut(..count).ok_or(MtsError::TooManyEntries)?;
+        for (entry, change) in entries.iter_mut().zip(changes.chunks_exact(4)) {
+            *entry = parse_entry(change);
+        }
+        Ok(TuningChange {
+            device_id,
+            program,
+            entries,
+        })
+    }
+
+    /// The message addresses `device_id`, directly or with the all devices id
+    pub fn is_for(&self, device_id: u8) -> bool {
+        self.device_id == device_id || self.device_id == ALL_DEVICES
+    }
+
+    /// Write the data bytes of the system exclusive message, without 0xf0 and 0xf7, to `buffer`,
+    /// returns the number of bytes written
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, MtsError> {
+        let header = self.header()?;
+        let len = HEADER_LEN + self.entries.len() * 4;
+        let buffer = buffer.get_mut(..len).ok_or(MtsError::TooManyEntries)?;
+        buffer[..HEADER_LEN].copy_from_slice(&header);
+        for (change, (note, target)) in buffer[HEADER_LEN..].chunks_exact_mut(4).zip(self.entries) {
+            change[0] = u8::from(*note);
+            change[1..].copy_from_slice(&target.bytes());
+        }
+        Ok(len)
+    }
+
+    /// Send the tuning change
+    pub fn render<TX, E>(&self, out: &mut MidiOut<TX>) -> Result<(), MidiError<E>>
+    where
+        TX: serial::Write<u8, Error = E>,
+        E: Debug,
+    {
+        let header = self.header().map_err(|_| MidiError::ValueOutOfRange)?;
+        let changes = self.entries.iter().flat_map(|(note, target)| {
+            let [semitone, msb, lsb] = target.bytes();
+            [u8::from(*note), semitone, msb, lsb]
+        });
+        out.write_sysex_bytes(header.iter().copied().chain(changes))
+    }
+
+    fn header(&self) -> Result<[u8; HEADER_LEN], MtsError> {
+        if self.device_id > 0x7f || self.program > 0x7f {
+            return Err(MtsError::InvalidField);
+        }
+        if self.entries.len() > MAX_ENTRIES {
+            return Err(MtsError::TooManyEntries);
+        }
+        Ok([
+            UNIVERSAL_REALTIME,
+            self.device_id,
+            MIDI_TUNING,
+            SINGLE_NOTE_TUNING_CHANGE,
+            self.program,
+            self.entries.len() as u8,
+        ])
+    }
+
+    /// Apply the changes to a table of offsets from equal temperament in cents, the table of a
+    /// `MicrotuneAllocator`
+    pub fn apply_to(&self, table: &mut [i16; 128]) {
+        for (note, target) in self.entries {
+            if !target.is_no_change() {
+                let cents = target.offset_cents(*note);
+                table[usize::from(u8::from(*note))] =
+                    cents.clamp(i32::from(i16::MIN), i32::from(i16::MAX)) as i16;
+            }
+        }
+    }
+}
+
+fn parse_entry(change: &[u8]) -> (Note, TuningTarget) {
+    let target = TuningTarget {
+        semitone: change[1],
+        fraction: u16::from(change[2]) << 7 | u16::from(change[3]),
+    };
+    (Note::from(change[0]), target)
+}
+
+/// Recognizes single note tuning changes in received system exclusive messages and calls
+/// `on_change` with every one
+///
+/// Feed the system exclusive messages to the receiver, for example with `MidiIn::read_sysex`.
+/// The changes are collected while the message arrives, up to `N` of them, so the message is
+/// never buffered as a whole. Other system exclusive messages are ignored, malformed tuning
+/// changes and tuning changes with more than `N` changes are counted and dropped.
+#[derive(Debug)]
+pub struct MtsReceiver<F, const N: usize = 16> {
+    on_change: F,
+    header: [u8; HEADER_LEN],
+    /// Bytes of the message seen so far
+    position: usize,
+    matched: bool,
+    change: [u8; 4],
+    entries: [(Note, TuningTarget); N],
+    len: usize,
+    invalid: bool,
+    rejected: u32,
+}
+
+impl<F, const N: usize> MtsReceiver<F, N>
+where
+    F: FnMut(&TuningChange),
+{
+    pub fn new(on_change: F) -> Self {
+        MtsReceiver {
+            on_change,
+            header: [0; HEADER_LEN],
+            position: 0,
+            matched: false,
+            change: [0; 4],
+            entries: [(Note::from(0), TuningTarget::NO_CHANGE); N],
+            len: 0,
+            invalid: false,
+            rejected: 0,
+        }
+    }
+
+    /// Number of malformed tuning changes dropped
+    pub fn rejected(&self) -> u32 {
+        self.rejected
+    }
+
+    /// Release the callback
+    pub fn release(self) -> F {
+        self.on_change
+    }
+
+    fn push(&mut self, byte: u8) {
+        let position = self.position;
+        self.position += 1;
+        match position {
+            0 => self.matched = byte == UNIVERSAL_REALTIME,
+            2 => self.matched &= byte == MIDI_TUNING,
+            3 => self.matched &= byte == SINGLE_NOTE_TUNING_CHANGE,
+            _ => {}
+        }
+        if !self.matched {
+            return;
+        }
+        self.invalid |= byte > 0x7f;
+        if position < HEADER_LEN {
+            self.header[position] = byte;
+            return;
+        }
+        let offset = (position - HEADER_LEN) % 4;
+        self.change[offset] = byte;
+        if offset == 3 {
+            match self.entries.get_mut(self.len) {
+                Some(entry) => *entry = parse_entry(&self.change),
+                None => self.invalid = true,
+            }
+            self.len += 1;
+        }
+    }
+
+    fn finish(&mut self) {
+        let count = usize::from(self.header[5]);
+        if self.invalid || self.position < HEADER_LEN || self.position != HEADER_LEN + count * 4 {
+            self.rejected = self.rejected.wrapping_add(1);
+            return;
+        }
+        let change = TuningChange {
+            device_id: self.header[1],
+            program: self.header[4],
+            entries: &self.entries[..count],
+        };
+        (self.on_change)(&change);
+    }
+}
+
+impl<F, const N: usize> SysexHandler for MtsReceiver<F, N>
+where
+    F: FnMut(&TuningChange),
+{
+    fn on_sysex_chunk(&mut self, chunk: &[u8], first: bool, last: bool) {
+        if first {
+            self.position = 0;
+            self.matched = false;
+            self.len = 0;
+            self.invalid = false;
+        }
+        for byte in chunk {
+            self.push(*byte);
+        }
+        if last && self.matched {
+            self.finish();
+        }
+    }
+
+    fn on_sysex_abort(&mut self) {
+        self.matched = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::test_util::Wire;
+    use crate::{MicrotuneAllocator, SysexStream};
+    use midi_convert::midi_types::Channel;
+    use std::vec::Vec;
+
+    fn target(semitone: u8, fraction: u16) -> TuningTarget {
+        TuningTarget::new(semitone, fraction).unwrap()
+    }
+
+    #[test]
+    fn should_decode_frequency_bytes() {
+        // Frequency bytes with the note they are closest to
+        let examples: [(u8, [u8; 3]); 9] = [
+            (0, [0x00, 0x00, 0x00]),
+            (0, [0x00, 0x00, 0x01]),
+            (1, [0x01, 0x00, 0x00]),
+            (60, [0x3c, 0x00, 0x00]),
+            (69, [0x44, 0x7f, 0x7f]),
+            (69, [0x45, 0x00, 0x00]),
+            (69, [0x45, 0x00, 0x01]),
+            (120, [0x78, 0x00, 0x01]),
+            (127, [0x7f, 0x7f, 0x7e]),
+        ];
+        let mut data = Vec::from([0x7f, 0x10, 0x08, 0x02, 0x03, examples.len() as u8]);
+        for (note, bytes) in examples {
+            data.push(note);
+            data.extend_from_slice(&bytes);
+        }
+        let mut buffer = [(Note::from(0), TuningTarget::NO_CHANGE); 16];
+        let change = TuningChange::parse(&data, &mut buffer).unwrap();
+        assert_eq!(change.device_id, 0x10);
+        assert_eq!(change.program, 3);
+        assert!(change.is_for(0x10));
+        assert!(!change.is_for(0x11));
+
+        let offsets: Vec<_> = change
+            .entries
+            .iter()
+            .map(|(note, target)| target.offset_cents(*note))
+            .collect();
+        assert_eq!(offsets, [0, 0, 0, 0, 0, 0, 0, 0, 100]);
+        assert_eq!(change.entries[4].1, target(68, 0x3fff));
+        assert_eq!(change.entries[6].1, target(69, 1));
+        assert_eq!(change.entries[8].1, target(127, 0x3ffe));
+        assert!(!change.entries[8].1.is_no_change());
+
+        let mut encoded = [0; 64];
+        let len = change.encode(&mut encoded).unwrap();
+        assert_eq!(encoded[..len], data[..]);
+    }
+
+    #[test]
+    fn should_validate_fields() {
+        assert_eq!(TuningTarget::new(0x80, 0), None);
+        assert_eq!(TuningTarget::new(0x7f, 0x4000), None);
+        assert_eq!(TuningTarget::from_offset(Note::from(0), -1), None);
+        assert_eq!(TuningTarget::from_offset(Note::from(127), 100), None);
+
+        let mut buffer = [(Note::from(0), TuningTarget::NO_CHANGE); 1];
+        let parse = |data: &[u8], buffer: &mut [(Note, TuningTarget)]| {
+            TuningChange::parse(data, buffer).map(|change| change.entries.len())
+        };
+        let valid = [0x7f, 0x7f, 0x08, 0x02, 0x00, 0x01, 60, 0x3c, 0x20, 0x00];
+        assert_eq!(parse(&valid, &mut buffer), Ok(1));
+        assert_eq!(parse(&valid[..9], &mut buffer), Err(MtsError::Malformed));
+        assert_eq!(
+            parse(
+                &[0x7f, 0x7f, 0x08, 0x02, 0x00, 0x01, 60, 0x3c, 0x80, 0x00],
+                &mut buffer
+            ),
+            Err(MtsError::InvalidField)
+        );
+        assert_eq!(
+            parse(&[0x7f, 0x7f, 0x08, 0x01, 0x00], &mut buffer),
+            Err(MtsError::NotMts)
+        );
+        assert_eq!(parse(&valid, &mut []), Err(MtsError::TooManyEntries));
+
+        let entries = [(Note::from(60), TuningTarget::NO_CHANGE); 128];
+        let mut change = TuningChange {
+            device_id: 0,
+            program: 0x80,
+            entries: &entries[..1],
+        };
+        let mut encoded = [0; 600];
+        assert_eq!(change.encode(&mut encoded), Err(MtsError::InvalidField));
+        change.program = 0;
+        assert_eq!(
+            change.encode(&mut encoded[..9]),
+            Err(MtsError::TooManyEntries)
+        );
+        change.entries = &entries;
+        assert_eq!(change.encode(&mut encoded), Err(MtsError::TooManyEntries));
+        let mut out = MidiOut::new(Wire::default());
+        assert_eq!(change.render(&mut out), Err(MidiError::ValueOutOfRange));
+    }
+
+    #[test]
+    fn should_round_trip_microtune_table() {
+        let mut table = [0; 128];
+        table[60] = 50;
+        table[61] = -50;
+        table[64] = -14;
+        table[67] = 2;
+        table[70] = -31;
+        table[127] = 99;
+        let entries: Vec<_> = (0..128)
+            .filter(|note| table[*note] != 0)
+            .map(|note| {
+                let note = Note::from(note as u8);
+                (
+                    note,
+                    TuningTarget::from_offset(note, table[usize::from(u8::from(note))]).unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(entries[0].1, target(60, 8192));
+        assert_eq!(entries[1].1, target(60, 8192));
+
+        let change = TuningChange {
+            device_id: ALL_DEVICES,
+            program: 0,
+            entries: &entries,
+        };
+        let mut out = MidiOut::new(Wire::default());
+        change.render(&mut out).unwrap();
+        let bytes = out.release().0;
+        assert_eq!(bytes[..7], [0xf0, 0x7f, 0x7f, 0x08, 0x02, 0x00, 0x06]);
+        assert_eq!(bytes[bytes.len() - 1], 0xf7);
+
+        let mut buffer = [(Note::from(0), TuningTarget::NO_CHANGE); 8];
+        let parsed = TuningChange::parse(&bytes[1..bytes.len() - 1], &mut buffer).unwrap();
+        assert_eq!(parsed, change);
+        let mut applied = [0; 128];
+        // Notes without a target keep their tuning
+        applied[0] = 7;
+        parsed.apply_to(&mut applied);
+        assert_eq!(applied[1..], table[1..]);
+        assert_eq!(applied[0], 7);
+
+        let mut microtune = MicrotuneAllocator::<4>::new(Channel::C1);
+        microtune.set_tuning_table(&applied);
+        assert_eq!(microtune.tuning(Note::from(60)), 50);
+    }
+
+    #[test]
+    fn should_receive_tuning_changes() {
+        let entries = [
+            (Note::from(60), target(60, 4096)),
+            (Note::from(62), TuningTarget::NO_CHANGE),
+        ];
+        let mut out = MidiOut::new(Wire::default());
+        // Other system exclusive messages are ignored
+        out.write_sysex(&[0x7f, 0x7f, 0x02, 0x01, 0x01]).unwrap();
+        let change = TuningChange {
+            device_id: 0x05,
+            program: 0x02,
+            entries: &entries,
+        };
+        change.render(&mut out).unwrap();
+        // Too many changes for the receiver
+        let many = [(Note::from(1), target(1, 0)); 3];
+        let too_many = TuningChange {
+            entries: &many,
+            ..change
+        };
+        too_many.render(&mut out).unwrap();
+        // Shorter than its count
+        out.write_sysex(&[0x7f, 0x05, 0x08, 0x02, 0x02, 0x02, 60, 60, 0x20])
+            .unwrap();
+        change.render(&mut out).unwrap();
+        let bytes = out.release().0;
+
+        let mut received = Vec::new();
+        let mut receiver = MtsReceiver::<_, 2>::new(|change: &TuningChange| {
+            received.push((change.device_id, change.program, change.entries.to_vec()))
+        });
+        let mut stream = SysexStream::<4>::new();
+        for byte in &bytes {
+            stream.feed(0, *byte, &mut receiver);
+        }
+        assert_eq!(receiver.rejected(), 2);
+        let expected = (0x05, 0x02, entries.to_vec());
+        assert_eq!(received, [expected.clone(), expected]);
+    }
+}
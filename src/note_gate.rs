@@ -0,0 +1,234 @@
+//! Dropping ghost triggers and notes outside a range
+
+use crate::{
+    persist::{Reader, Writer},
+    DecodeError, MidiProcessor, NoteTracker, TooSmall,
+};
+use midi_convert::midi_types::{MidiMessage, Note};
+
+/// Processor dropping notes that are too soft or outside a note range
+///
+/// Note ons below the minimum velocity or outside the range are dropped together with their note
+/// offs. Per note velocity offsets calibrate pads before the minimum velocity is checked,
+/// calibrated velocities are clamped to 1 to 127. All other messages are passed on untouched.
+#[derive(Debug, Clone)]
+pub struct NoteGate {
+    min_velocity: u8,
+    lowest: Note,
+    highest: Note,
+    offsets: [i8; 128],
+    dropped: NoteTracker,
+}
+
+impl Default for NoteGate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NoteGate {
+    /// Number of bytes written by `save`
+    pub const SAVED_LEN: usize = 132;
+
+    /// Gate passing all notes
+    pub fn new() -> Self {
+        NoteGate {
+            min_velocity: 1,
+            lowest: Note::C2m,
+            highest: Note::G8,
+            offsets: [0; 128],
+            dropped: NoteTracker::new(),
+        }
+    }
+
+    /// Drop note ons with a calibrated velocity below `velocity`
+    pub fn set_min_velocity(&mut self, velocity: u8) {
+        self.min_velocity = velocity;
+    }
+
+    /// Drop notes below `lowest` or above `highest`, note offs of notes already passed still pass
+    pub fn set_note_range(&mut self, lowest: Note, highest: Note) {
+        self.lowest = lowest;
+        self.highest = highest;
+    }
+
+    /// Add `offset` to the velocity of note ons for `note`
+    pub fn set_velocity_offset(&mut self, note: Note, offset: i8) {
+        self.offsets[u8::from(note) as usize] = offset;
+    }
+
+    /// Set the velocity offsets of all notes, indexed by note number
+    pub fn set_velocity_offsets(&mut self, offsets: [i8; 128]) {
+        self.offsets = offsets;
+    }
+
+    /// Process a received message, returns `None` if the message is dropped
+    pub fn process(&mut self, message: MidiMessage) -> Option<MidiMessage> {
+        match message {
+            MidiMessage::NoteOn(channel, note, velocity) if u8::from(velocity) > 0 => {
+                let offset = self.offsets[u8::from(note) as usize] as i16;
+                let velocity = (u8::from(velocity) as i16 + offset).clamp(1, 127) as u8;
+
+                if velocity < self.min_velocity || !self.in_range(note) {
+                    self.dropped.note_on(channel, note);
+                    return None;
+                }
+                self.dropped.note_off(channel, note);
+                Some(MidiMessage::NoteOn(channel, note, velocity.into()))
+            }
+            MidiMessage::NoteOn(channel, note, _) | MidiMessage::NoteOff(channel, note, _) => {
+                if self.dropped.is_held(channel, note) {
+                    self.dropped.note_off(channel, note);
+                    return None;
+                }
+                Some(message)
+            }
+            _ => Some(message),
+        }
+    }
+
+    /// Save the settings into `buffer`, returns the number of bytes written
+    ///
+    /// Notes dropped so far are not saved, a loaded gate passes their note offs.
+    pub fn save(&self, buffer: &mut [u8]) -> Result<usize, TooSmall> {
+        let mut writer = Writer::new(buffer)?;
+        writer.byte(self.min_velocity)?;
+        writer.byte(self.lowest.into())?;
+        writer.byte(self.highest.into())?;
+        for offset in self.offsets {
+            writer.byte(offset as u8)?;
+        }
+        Ok(writer.finish())
+    }
+
+    /// Load settings saved by `save`
+    pub fn load(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes)?;
+        let mut gate = Self::new();
+        gate.min_velocity = reader.byte()?;
+        gate.lowest = reader.value7()?.into();
+        gate.highest = reader.value7()?.into();
+        for offset in gate.offsets.iter_mut() {
+            *offset = reader.byte()? as i8;
+        }
+        Ok(gate)
+    }
+
+    fn in_range(&self, note: Note) -> bool {
+        (u8::from(self.lowest)..=u8::from(self.highest)).contains(&u8::from(note))
+    }
+}
+
+impl MidiProcessor for NoteGate {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        if let Some(message) = NoteGate::process(self, message) {
+            emit(message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{cc, note_off, note_on};
+
+    #[test]
+    fn should_drop_soft_notes_with_their_note_offs() {
+        let mut gate = NoteGate::new();
+        gate.set_min_velocity(20);
+
+        assert_eq!(gate.process(note_on(0, 36, 10)), None);
+        assert_eq!(gate.process(note_on(0, 38, 20)), Some(note_on(0, 38, 20)));
+        assert_eq!(gate.process(note_off(0, 36, 0)), None);
+        assert_eq!(gate.process(note_on(0, 38, 0)), Some(note_on(0, 38, 0)));
+
+        // The pairing is forgotten once the note off was dropped
+        assert_eq!(gate.process(note_off(0, 36, 0)), Some(note_off(0, 36, 0)));
+    }
+
+    #[test]
+    fn should_pass_note_off_of_note_hit_again_hard_enough() {
+        let mut gate = NoteGate::new();
+        gate.set_min_velocity(20);
+
+        gate.process(note_on(0, 36, 10));
+        assert_eq!(gate.process(note_on(0, 36, 90)), Some(note_on(0, 36, 90)));
+        assert_eq!(gate.process(note_off(0, 36, 0)), Some(note_off(0, 36, 0)));
+    }
+
+    #[test]
+    fn should_drop_notes_outside_range() {
+        let mut gate = NoteGate::new();
+        gate.set_note_range(Note::C2, Note::B2);
+
+        assert_eq!(gate.process(note_on(0, 47, 100)), None);
+        assert_eq!(gate.process(note_off(0, 47, 0)), None);
+        assert_eq!(gate.process(note_on(0, 48, 100)), Some(note_on(0, 48, 100)));
+        assert_eq!(gate.process(note_on(0, 59, 100)), Some(note_on(0, 59, 100)));
+        assert_eq!(gate.process(note_on(0, 60, 100)), None);
+        assert_eq!(gate.process(cc(0, 60, 100)), Some(cc(0, 60, 100)));
+    }
+
+    #[test]
+    fn should_pass_note_off_of_held_note_after_narrowing_range() {
+        let mut gate = NoteGate::new();
+        assert_eq!(gate.process(note_on(0, 60, 100)), Some(note_on(0, 60, 100)));
+        gate.set_note_range(Note::C2, Note::B2);
+
+        assert_eq!(gate.process(note_off(0, 60, 0)), Some(note_off(0, 60, 0)));
+        assert_eq!(gate.process(note_on(0, 60, 100)), None);
+        assert_eq!(gate.process(note_on(0, 60, 0)), None);
+    }
+
+    #[test]
+    fn should_clamp_calibrated_velocities() {
+        let mut gate = NoteGate::new();
+        gate.set_velocity_offset(Note::C2, 40);
+        gate.set_velocity_offset(Note::D2, -40);
+
+        assert_eq!(gate.process(note_on(0, 48, 100)), Some(note_on(0, 48, 127)));
+        assert_eq!(gate.process(note_on(0, 48, 20)), Some(note_on(0, 48, 60)));
+        assert_eq!(gate.process(note_on(0, 50, 30)), Some(note_on(0, 50, 1)));
+        assert_eq!(gate.process(note_on(0, 50, 0)), Some(note_on(0, 50, 0)));
+    }
+
+    #[test]
+    fn should_gate_calibrated_velocity() {
+        let mut gate = NoteGate::new();
+        gate.set_min_velocity(20);
+        let mut offsets = [0; 128];
+        offsets[36] = -10;
+        offsets[38] = 10;
+        gate.set_velocity_offsets(offsets);
+
+        assert_eq!(gate.process(note_on(0, 36, 25)), None);
+        assert_eq!(gate.process(note_on(0, 38, 15)), Some(note_on(0, 38, 25)));
+    }
+
+    #[test]
+    fn should_round_trip_saved_settings() {
+        let mut gate = NoteGate::new();
+        gate.set_min_velocity(20);
+        gate.set_note_range(Note::C2, Note::B2);
+        gate.set_velocity_offset(Note::C2, -10);
+        gate.set_velocity_offset(Note::D2, 10);
+
+        let mut buffer = [0; NoteGate::SAVED_LEN];
+        assert_eq!(gate.save(&mut buffer), Ok(NoteGate::SAVED_LEN));
+        assert_eq!(
+            gate.save(&mut buffer[..NoteGate::SAVED_LEN - 1]),
+            Err(TooSmall)
+        );
+
+        let mut loaded = NoteGate::load(&buffer).unwrap();
+        assert_eq!(loaded.process(note_on(0, 48, 25)), None);
+        assert_eq!(loaded.process(note_on(0, 50, 15)), Some(note_on(0, 50, 25)));
+        assert_eq!(loaded.process(note_on(0, 60, 100)), None);
+
+        buffer[0] = 2;
+        assert_eq!(
+            NoteGate::load(&buffer).err(),
+            Some(DecodeError::UnsupportedVersion(2))
+        );
+    }
+}
@@ -0,0 +1,99 @@
+//! Bookkeeping of held notes
+
+use midi_convert::midi_types::{Channel, MidiMessage, Note};
+
+/// Tracks which notes are held on each channel
+///
+/// Velocity 0 note ons are treated as note offs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NoteTracker {
+    held: [u128; 16],
+}
+
+impl NoteTracker {
+    pub fn new() -> Self {
+        NoteTracker { held: [0; 16] }
+    }
+
+    pub fn note_on(&mut self, channel: Channel, note: Note) {
+        self.held[u8::from(channel) as usize] |= 1 << u8::from(note);
+    }
+
+    pub fn note_off(&mut self, channel: Channel, note: Note) {
+        self.held[u8::from(channel) as usize] &= !(1 << u8::from(note));
+    }
+
+    pub fn is_held(&self, channel: Channel, note: Note) -> bool {
+        self.held[u8::from(channel) as usize] & (1 << u8::from(note)) != 0
+    }
+
+    /// Number of notes held on all channels
+    pub fn held_count(&self) -> u32 {
+        self.held.iter().map(|notes| notes.count_ones()).sum()
+    }
+
+    /// Update the held notes from a note on or note off message, other messages are ignored
+    pub fn track(&mut self, message: &MidiMessage) {
+        match *message {
+            MidiMessage::NoteOn(channel, note, velocity) if u8::from(velocity) > 0 => {
+                self.note_on(channel, note)
+            }
+            MidiMessage::NoteOn(channel, note, _) | MidiMessage::NoteOff(channel, note, _) => {
+                self.note_off(channel, note)
+            }
+            _ => {}
+        }
+    }
+
+    /// Call `emit` with a note off for every held note and forget all notes
+    pub fn release_all(&mut self, emit: &mut dyn FnMut(MidiMessage)) {
+        for (channel, notes) in self.held.iter_mut().enumerate() {
+            for note in 0..128u8 {
+                if *notes & (1 << note) != 0 {
+                    emit(MidiMessage::NoteOff(
+                        (channel as u8).into(),
+                        note.into(),
+                        0.into(),
+                    ));
+                }
+            }
+            *notes = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{note_off, note_on};
+    use std::vec::Vec;
+
+    #[test]
+    fn should_track_held_notes() {
+        let mut tracker = NoteTracker::new();
+        tracker.track(&note_on(0, 60, 100));
+        tracker.track(&note_on(15, 127, 100));
+        tracker.track(&note_on(0, 64, 100));
+        tracker.track(&note_off(0, 60, 0));
+        tracker.track(&note_on(0, 64, 0));
+
+        assert!(!tracker.is_held(Channel::C1, 60.into()));
+        assert!(!tracker.is_held(Channel::C1, 64.into()));
+        assert!(tracker.is_held(Channel::C16, 127.into()));
+        assert_eq!(tracker.held_count(), 1);
+    }
+
+    #[test]
+    fn should_release_all_notes() {
+        let mut tracker = NoteTracker::new();
+        tracker.track(&note_on(3, 0, 100));
+        tracker.track(&note_on(1, 72, 100));
+
+        let mut released = Vec::new();
+        tracker.release_all(&mut |message| released.push(message));
+
+        assert_eq!(released, [note_off(1, 72, 0), note_off(3, 0, 0)]);
+        assert_eq!(tracker.held_count(), 0);
+    }
+}
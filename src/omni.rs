@@ -0,0 +1,177 @@
+//! Omni mode input normalization
+
+use crate::message::{channel, with_channel};
+use crate::MidiProcessor;
+use midi_convert::midi_types::{Channel, MidiMessage};
+
+/// Control number of the Omni Off channel mode message
+const OMNI_OFF: u8 = 124;
+/// Control number of the Omni On channel mode message
+const OMNI_ON: u8 = 125;
+
+/// Processor implementing Omni mode for received messages
+///
+/// With Omni On all channel voice messages are accepted and moved to the base channel so
+/// downstream code doesn't need per-channel handling. With Omni Off only messages on the base
+/// channel are accepted. System messages are always passed on untouched.
+#[derive(Debug, Clone)]
+pub struct Omni {
+    base_channel: Channel,
+    omni_on: bool,
+    follow_mode_messages: bool,
+    last_source_channel: Option<Channel>,
+}
+
+impl Default for Omni {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Omni {
+    /// Create a processor in Omni On mode with base channel 1
+    pub fn new() -> Self {
+        Omni {
+            base_channel: Channel::C1,
+            omni_on: true,
+            follow_mode_messages: false,
+            last_source_channel: None,
+        }
+    }
+
+    pub fn base_channel(&self) -> Channel {
+        self.base_channel
+    }
+
+    pub fn set_base_channel(&mut self, channel: Channel) {
+        self.base_channel = channel;
+    }
+
+    pub fn is_omni_on(&self) -> bool {
+        self.omni_on
+    }
+
+    pub fn set_omni_on(&mut self, omni_on: bool) {
+        self.omni_on = omni_on;
+    }
+
+    /// Switch Omni mode on or off when Omni On or Omni Off channel mode messages are received on
+    /// the base channel
+    pub fn set_follow_mode_messages(&mut self, follow: bool) {
+        self.follow_mode_messages = follow;
+    }
+
+    /// Channel the last accepted channel voice message was originally received on
+    pub fn last_source_channel(&self) -> Option<Channel> {
+        self.last_source_channel
+    }
+
+    /// Process a received message, returns `None` if the message should be ignored
+    pub fn process(&mut self, message: MidiMessage) -> Option<MidiMessage> {
+        let source = match channel(&message) {
+            Some(source) => source,
+            None => return Some(message),
+        };
+
+        if source == self.base_channel && self.follow_mode_messages {
+            if let MidiMessage::ControlChange(_, control, _) = message {
+                match control.into() {
+                    OMNI_OFF => self.omni_on = false,
+                    OMNI_ON => self.omni_on = true,
+                    _ => {}
+                }
+            }
+        }
+
+        if !self.omni_on && source != self.base_channel {
+            return None;
+        }
+
+        self.last_source_channel = Some(source);
+        Some(with_channel(message, self.base_channel))
+    }
+}
+
+impl MidiProcessor for Omni {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        if let Some(message) = Omni::process(self, message) {
+            emit(message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(channel: u8) -> MidiMessage {
+        MidiMessage::NoteOn(channel.into(), 0x40.into(), 0x7f.into())
+    }
+
+    fn control_change(channel: u8, control: u8) -> MidiMessage {
+        MidiMessage::ControlChange(channel.into(), control.into(), 0x00.into())
+    }
+
+    #[test]
+    fn should_move_messages_to_base_channel() {
+        let mut omni = Omni::new();
+        omni.set_base_channel(Channel::C3);
+
+        assert_eq!(omni.process(note_on(0x07)), Some(note_on(0x02)));
+        assert_eq!(omni.last_source_channel(), Some(Channel::C8));
+    }
+
+    #[test]
+    fn should_pass_system_messages_untouched() {
+        let mut omni = Omni::new();
+
+        assert_eq!(
+            omni.process(MidiMessage::TimingClock),
+            Some(MidiMessage::TimingClock)
+        );
+        assert_eq!(omni.last_source_channel(), None);
+    }
+
+    #[test]
+    fn should_ignore_other_channels_when_omni_off() {
+        let mut omni = Omni::new();
+        omni.set_omni_on(false);
+
+        assert_eq!(omni.process(note_on(0x01)), None);
+        assert_eq!(omni.process(note_on(0x00)), Some(note_on(0x00)));
+    }
+
+    #[test]
+    fn should_toggle_omni_by_mode_messages_on_base_channel() {
+        let mut omni = Omni::new();
+        omni.set_follow_mode_messages(true);
+
+        assert_eq!(
+            omni.process(control_change(0x00, OMNI_OFF)),
+            Some(control_change(0x00, OMNI_OFF))
+        );
+        assert!(!omni.is_omni_on());
+        assert_eq!(omni.process(note_on(0x04)), None);
+
+        omni.process(control_change(0x00, OMNI_ON));
+        assert!(omni.is_omni_on());
+        assert_eq!(omni.process(note_on(0x04)), Some(note_on(0x00)));
+    }
+
+    #[test]
+    fn should_ignore_mode_messages_on_other_channels() {
+        let mut omni = Omni::new();
+        omni.set_follow_mode_messages(true);
+
+        omni.process(control_change(0x05, OMNI_OFF));
+        assert!(omni.is_omni_on());
+    }
+
+    #[test]
+    fn should_ignore_mode_messages_unless_following() {
+        let mut omni = Omni::new();
+
+        omni.process(control_change(0x00, OMNI_OFF));
+        assert!(omni.is_omni_on());
+    }
+}
@@ -0,0 +1,212 @@
+//! Packets carried in system exclusive messages, shared by the firmware update and configuration
+//! dump protocols
+//!
+//! The packet layout is described at [`ConfigSysex`](crate::ConfigSysex).
+
+use crate::{
+    seven_bit::{encoded_len, SevenBitDecoder},
+    MidiError, MidiOut, SysexHandler,
+};
+use core::fmt::Debug;
+use embedded_hal_nb::serial;
+
+pub(crate) const INDEX_MASK: u16 = 0x3fff;
+
+/// The data bytes of a packet
+#[derive(Debug)]
+struct PacketBytes<'a> {
+    manufacturer: &'a [u8],
+    header: [u8; 3],
+    payload: &'a [u8],
+    position: usize,
+    sum: u8,
+}
+
+impl<'a> PacketBytes<'a> {
+    fn new(manufacturer: &'a [u8], command: u8, index: u16, payload: &'a [u8]) -> Self {
+        let index = index & INDEX_MASK;
+        PacketBytes {
+            manufacturer,
+            header: [command, (index & 0x7f) as u8, (index >> 7) as u8],
+            payload,
+            position: 0,
+            sum: 0,
+        }
+    }
+
+    fn encoded(&self, position: usize) -> u8 {
+        let start = position / 8 * 7;
+        let group = &self.payload[start..self.payload.len().min(start + 7)];
+        match position % 8 {
+            0 => group
+                .iter()
+                .enumerate()
+                .fold(0, |msbs, (bit, byte)| msbs | (byte >> 7) << bit),
+            byte => group[byte - 1] & 0x7f,
+        }
+    }
+}
+
+impl Iterator for PacketBytes<'_> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        let position = self.position;
+        self.position += 1;
+        let header_end = self.manufacturer.len() + self.header.len();
+        let payload_end = header_end + encoded_len(self.payload.len());
+        let byte = if position < self.manufacturer.len() {
+            return Some(self.manufacturer[position]);
+        } else if position < header_end {
+            self.header[position - self.manufacturer.len()]
+        } else if position < payload_end {
+            self.encoded(position - header_end)
+        } else if position == payload_end {
+            return Some(self.sum.wrapping_neg() & 0x7f);
+        } else {
+            return None;
+        };
+        self.sum = self.sum.wrapping_add(byte);
+        Some(byte)
+    }
+}
+
+pub(crate) fn send_packet<TX, E>(
+    out: &mut MidiOut<TX>,
+    manufacturer: &[u8],
+    command: u8,
+    index: u16,
+    payload: &[u8],
+) -> Result<(), MidiError<E>>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+{
+    out.write_sysex_bytes(PacketBytes::new(manufacturer, command, index, payload))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Packet {
+    pub command: u8,
+    pub index: u16,
+}
+
+/// A received system exclusive message with the manufacturer id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Received {
+    Valid(Packet),
+    Corrupt,
+}
+
+/// Decodes packets from streamed system exclusive chunks
+///
+/// The last byte of a message is the checksum, so every byte is only decoded once the next one
+/// arrived.
+#[derive(Debug)]
+pub(crate) struct PacketDecoder<const CHUNK: usize> {
+    pub manufacturer: &'static [u8],
+    position: usize,
+    pending: Option<u8>,
+    /// The manufacturer id matches so far
+    matched: bool,
+    /// The payload is larger than `CHUNK`
+    overflow: bool,
+    header: [u8; 3],
+    sum: u8,
+    payload_decoder: SevenBitDecoder,
+    payload: [u8; CHUNK],
+    len: usize,
+    received: Option<Received>,
+}
+
+impl<const CHUNK: usize> PacketDecoder<CHUNK> {
+    pub fn new(manufacturer: &'static [u8]) -> Self {
+        PacketDecoder {
+            manufacturer,
+            position: 0,
+            pending: None,
+            matched: false,
+            overflow: false,
+            header: [0; 3],
+            sum: 0,
+            payload_decoder: SevenBitDecoder::new(),
+            payload: [0; CHUNK],
+            len: 0,
+            received: None,
+        }
+    }
+
+    fn decode(&mut self, byte: u8) {
+        let position = self.position;
+        self.position += 1;
+        let manufacturer_len = self.manufacturer.len();
+        if position < manufacturer_len {
+            self.matched &= self.manufacturer[position] == byte;
+            return;
+        }
+        self.sum = self.sum.wrapping_add(byte);
+        let position = position - manufacturer_len;
+        if let Some(header) = self.header.get_mut(position) {
+            *header = byte;
+            return;
+        }
+        if let Some(byte) = self.payload_decoder.push(byte) {
+            match self.payload.get_mut(self.len) {
+                Some(slot) => {
+                    *slot = byte;
+                    self.len += 1;
+                }
+                None => self.overflow = true,
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        let complete = self.position >= self.manufacturer.len() + self.header.len();
+        if !self.matched || !complete {
+            return;
+        }
+        let valid = self
+            .pending
+            .is_some_and(|checksum| self.sum.wrapping_add(checksum) & 0x7f == 0);
+        self.received = Some(if valid && !self.overflow {
+            Received::Valid(Packet {
+                command: self.header[0],
+                index: u16::from(self.header[1]) | u16::from(self.header[2]) << 7,
+            })
+        } else {
+            Received::Corrupt
+        });
+    }
+
+    pub fn take(&mut self) -> Option<Received> {
+        self.received.take()
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..self.len]
+    }
+}
+
+impl<const CHUNK: usize> SysexHandler for PacketDecoder<CHUNK> {
+    fn on_sysex_chunk(&mut self, chunk: &[u8], first: bool, last: bool) {
+        if first {
+            *self = PacketDecoder {
+                matched: true,
+                ..PacketDecoder::new(self.manufacturer)
+            };
+        }
+        for byte in chunk {
+            if let Some(previous) = self.pending.replace(*byte) {
+                self.decode(previous);
+            }
+        }
+        if last {
+            self.finish();
+        }
+    }
+
+    fn on_sysex_abort(&mut self) {
+        self.matched = false;
+    }
+}
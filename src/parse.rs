@@ -0,0 +1,294 @@
+//! Table driven midi parser
+//!
+//! Parses the same way as the `midi-convert` parser, but looks up the number of data bytes and the
+//! message constructor by status instead of walking a state per message type.
+//!
+//! The parser only constructs messages of the families in its `FAMILIES` parameter. The checks are
+//! on the constant, so the constructors of excluded families are left out of the binary.
+//!
+//! Public only for the benchmark in `benches/parse.rs`, it is not part of the api.
+
+use crate::{family, Value14Ext};
+use midi_convert::midi_types::{Channel, MidiMessage, Value14};
+
+/// Constructor for a message from its status byte and data bytes
+#[cfg(not(feature = "opt-size"))]
+type Constructor = fn(u8, u8, u8) -> MidiMessage;
+
+/// Number of data bytes for each status byte high nibble, system messages are looked up in
+/// `SYSTEM_COMMON_LENGTH`
+const DATA_LENGTH: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 2, 2, 2, 2, 1, 1, 2, 0];
+
+/// Number of data bytes for system common status bytes 0xf0 to 0xf7, messages without data bytes
+/// are handled when the status byte is received
+const SYSTEM_COMMON_LENGTH: [u8; 8] = [0, 1, 2, 1, 0, 0, 0, 0];
+
+/// Constructors indexed by the status byte high nibble minus 8, which is also the bit of their
+/// family
+#[cfg(not(feature = "opt-size"))]
+const CONSTRUCTORS: [Constructor; 8] = [
+    |status, note, velocity| MidiMessage::NoteOff(channel(status), note.into(), velocity.into()),
+    |status, note, velocity| MidiMessage::NoteOn(channel(status), note.into(), velocity.into()),
+    |status, note, value| MidiMessage::KeyPressure(channel(status), note.into(), value.into()),
+    |status, control, value| {
+        MidiMessage::ControlChange(channel(status), control.into(), value.into())
+    },
+    |status, program, _| MidiMessage::ProgramChange(channel(status), program.into()),
+    |status, value, _| MidiMessage::ChannelPressure(channel(status), value.into()),
+    |status, lsb, msb| {
+        MidiMessage::PitchBendChange(channel(status), Value14::from_lsb_msb(lsb, msb))
+    },
+    |status, first, second| match status {
+        0xf1 => MidiMessage::QuarterFrame(first.into()),
+        0xf2 => MidiMessage::SongPositionPointer(Value14::from_lsb_msb(first, second)),
+        _ => MidiMessage::SongSelect(first.into()),
+    },
+];
+
+/// The constructors of the families in `families`, `None` for the others
+#[cfg(not(feature = "opt-size"))]
+const fn included(families: u16) -> [Option<Constructor>; 8] {
+    let mut included = [None; 8];
+    let mut index = 0;
+    while index < 8 {
+        if families & (1 << index) != 0 {
+            included[index] = Some(CONSTRUCTORS[index]);
+        }
+        index += 1;
+    }
+    included
+}
+
+/// Real time messages indexed by the status byte minus 0xf8
+const REALTIME: [Option<MidiMessage>; 8] = [
+    Some(MidiMessage::TimingClock),
+    None,
+    Some(MidiMessage::Start),
+    Some(MidiMessage::Continue),
+    Some(MidiMessage::Stop),
+    None,
+    Some(MidiMessage::ActiveSensing),
+    Some(MidiMessage::Reset),
+];
+
+/// Number of data bytes following a status byte, 0 for system exclusive and real time messages
+pub(crate) fn data_length(status: u8) -> u8 {
+    match status >> 4 {
+        0x0f if status < 0xf8 => SYSTEM_COMMON_LENGTH[(status & 0x07) as usize],
+        0x0f => 0,
+        nibble => DATA_LENGTH[nibble as usize],
+    }
+}
+
+/// Like the table of constructors, with one match that takes less flash than the closures
+///
+/// `parse` doesn't call this for excluded families, so their arms are left out and fall
+/// through to the last arm. Inlined into the parser it takes more flash.
+#[cfg(feature = "opt-size")]
+#[inline(never)]
+fn construct<const FAMILIES: u16>(status: u8, first: u8, second: u8) -> MidiMessage {
+    let channel = channel(status);
+    match status >> 4 {
+        0x8 if FAMILIES & family::NOTE_OFF != 0 => {
+            MidiMessage::NoteOff(channel, first.into(), second.into())
+        }
+        0x9 if FAMILIES & family::NOTE_ON != 0 => {
+            MidiMessage::NoteOn(channel, first.into(), second.into())
+        }
+        0xa if FAMILIES & family::KEY_PRESSURE != 0 => {
+            MidiMessage::KeyPressure(channel, first.into(), second.into())
+        }
+        0xb if FAMILIES & family::CONTROL_CHANGE != 0 => {
+            MidiMessage::ControlChange(channel, first.into(), second.into())
+        }
+        0xc if FAMILIES & family::PROGRAM_CHANGE != 0 => {
+            MidiMessage::ProgramChange(channel, first.into())
+        }
+        0xd if FAMILIES & family::CHANNEL_PRESSURE != 0 => {
+            MidiMessage::ChannelPressure(channel, first.into())
+        }
+        0xe if FAMILIES & family::PITCH_BEND != 0 => {
+            MidiMessage::PitchBendChange(channel, Value14::from_lsb_msb(first, second))
+        }
+        _ => match status {
+            0xf1 => MidiMessage::QuarterFrame(first.into()),
+            0xf2 => MidiMessage::SongPositionPointer(Value14::from_lsb_msb(first, second)),
+            _ => MidiMessage::SongSelect(first.into()),
+        },
+    }
+}
+
+fn channel(status: u8) -> Channel {
+    (status & 0x0f).into()
+}
+
+/// Parses midi messages a byte at a time
+///
+/// The status of the last message is kept, so messages using running status are parsed. Like the
+/// `midi-convert` parser this also repeats system common messages with data bytes.
+#[derive(Debug, Clone)]
+pub struct MidiParser<const FAMILIES: u16 = { family::ALL }> {
+    status: u8,
+    /// Number of data bytes of the current message, 0 while idle
+    length: u8,
+    /// First data byte of a two byte message, if received
+    first: Option<u8>,
+}
+
+impl MidiParser {
+    pub fn new() -> Self {
+        Self::with_families()
+    }
+}
+
+impl Default for MidiParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const FAMILIES: u16> MidiParser<FAMILIES> {
+    /// Constructors of the included families
+    #[cfg(not(feature = "opt-size"))]
+    const CONSTRUCTORS: [Option<Constructor>; 8] = included(FAMILIES);
+
+    /// Parser only constructing messages of the families in `FAMILIES`
+    pub fn with_families() -> Self {
+        MidiParser {
+            status: 0,
+            length: 0,
+            first: None,
+        }
+    }
+
+    /// Last status byte received, other than real time bytes, 0 before the first one
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+
+    /// Parse a byte, returns a message when the byte completes one
+    pub fn parse(&mut self, byte: u8) -> Option<MidiMessage> {
+        if byte >= 0xf8 {
+            if FAMILIES & family::REALTIME == 0 {
+                return None;
+            }
+            return REALTIME[(byte & 0x07) as usize];
+        }
+
+        if byte & 0x80 != 0 {
+            self.status = byte;
+            self.first = None;
+            self.length = data_length(byte);
+            return match byte {
+                0xf6 if FAMILIES & family::SYSTEM_COMMON != 0 => Some(MidiMessage::TuneRequest),
+                _ => None,
+            };
+        }
+
+        let (first, second) = match (self.length, self.first.take()) {
+            (0, _) => return None,
+            (2, None) => {
+                self.first = Some(byte);
+                return None;
+            }
+            (2, Some(first)) => (first, byte),
+            _ => (byte, 0),
+        };
+        #[cfg(not(feature = "opt-size"))]
+        return Self::construct(self.status, first, second);
+        #[cfg(feature = "opt-size")]
+        return if FAMILIES & family::of(self.status) == 0 {
+            None
+        } else {
+            Some(construct::<FAMILIES>(self.status, first, second))
+        };
+    }
+
+    /// Message with the status byte `status` and data bytes `first` and `second`, `None` for
+    /// excluded families
+    #[cfg(not(feature = "opt-size"))]
+    fn construct(status: u8, first: u8, second: u8) -> Option<MidiMessage> {
+        Self::CONSTRUCTORS[((status >> 4) & 0x07) as usize]
+            .map(|construct| construct(status, first, second))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{note_on, pitch_bend, to_bytes};
+    use proptest::prelude::*;
+    use std::vec::Vec;
+
+    fn parse_all<const FAMILIES: u16>(
+        parser: &mut MidiParser<FAMILIES>,
+        bytes: &[u8],
+    ) -> Vec<MidiMessage> {
+        bytes
+            .iter()
+            .filter_map(|byte| parser.parse(*byte))
+            .collect()
+    }
+
+    #[test]
+    fn should_parse_running_status() {
+        let mut parser = MidiParser::new();
+
+        assert_eq!(
+            parse_all(&mut parser, &[0x92, 0x40, 0x10, 0xf8, 0x41, 0x11]),
+            [
+                note_on(2, 0x40, 0x10),
+                MidiMessage::TimingClock,
+                note_on(2, 0x41, 0x11)
+            ]
+        );
+    }
+
+    #[test]
+    fn should_parse_pitch_bend_lsb_first() {
+        let mut parser = MidiParser::new();
+
+        assert_eq!(
+            parse_all(&mut parser, &[0xe0, 0x00, 0x40, 0xe1, 0x7f, 0x7f]),
+            [pitch_bend(0, 0), pitch_bend(1, 8191)]
+        );
+    }
+
+    #[test]
+    fn should_ignore_data_bytes_when_idle() {
+        let mut parser = MidiParser::new();
+
+        assert_eq!(
+            parse_all(
+                &mut parser,
+                &[0x40, 0xf0, 0x01, 0x02, 0xf7, 0x03, 0xf4, 0x04]
+            ),
+            []
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn should_parse_like_midi_convert(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+            let mut reference = midi_convert::parse::MidiParser::new();
+            let expected: Vec<_> = bytes.iter().filter_map(|byte| reference.parse(*byte)).collect();
+
+            prop_assert_eq!(parse_all(&mut MidiParser::new(), &bytes), expected);
+        }
+
+        #[test]
+        fn should_only_construct_included_families(
+            bytes in prop::collection::vec(any::<u8>(), 0..256)
+        ) {
+            const FAMILIES: u16 = family::NOTES | family::PITCH_BEND | family::REALTIME;
+            let expected: Vec<_> = parse_all(&mut MidiParser::new(), &bytes)
+                .into_iter()
+                .filter(|message| FAMILIES & family::of(to_bytes(message).0[0]) != 0)
+                .collect();
+
+            let mut parser = MidiParser::<FAMILIES>::with_families();
+            prop_assert_eq!(parse_all(&mut parser, &bytes), expected);
+        }
+    }
+}
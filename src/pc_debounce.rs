@@ -0,0 +1,124 @@
+//! Smoothing program changes sent while scrolling through programs
+
+use midi_convert::midi_types::{Channel, MidiMessage, Program};
+
+/// Holds back program changes until no newer program change arrives on the channel for a while
+///
+/// Each program change replaces the one held on its channel and restarts the hold window, so a
+/// burst of program changes results in only the last one. Program changes selecting the program
+/// that was last emitted on the channel are dropped. Held program changes are emitted by `flush`,
+/// timestamps are in milliseconds and may wrap around. Other messages are passed on.
+#[derive(Debug, Clone)]
+pub struct PcDebounce {
+    hold_ms: u32,
+    /// Held program and the time it arrived, per channel
+    held: [Option<(Program, u32)>; 16],
+    /// Last program emitted, per channel
+    current: [Option<Program>; 16],
+}
+
+impl PcDebounce {
+    pub fn new(hold_ms: u32) -> Self {
+        PcDebounce {
+            hold_ms,
+            held: [None; 16],
+            current: [None; 16],
+        }
+    }
+
+    pub fn set_hold_ms(&mut self, hold_ms: u32) {
+        self.hold_ms = hold_ms;
+    }
+
+    /// Process a message received at time `now_ms`, returns `None` for program changes
+    pub fn process(&mut self, now_ms: u32, message: MidiMessage) -> Option<MidiMessage> {
+        match message {
+            MidiMessage::ProgramChange(channel, program) => {
+                self.held[u8::from(channel) as usize] = Some((program, now_ms));
+                None
+            }
+            _ => Some(message),
+        }
+    }
+
+    /// Call `emit` with the program changes whose hold window ended at time `now_ms`
+    pub fn flush(&mut self, now_ms: u32, emit: &mut dyn FnMut(MidiMessage)) {
+        for (channel, held) in self.held.iter_mut().enumerate() {
+            let program = match *held {
+                Some((program, since)) if now_ms.wrapping_sub(since) >= self.hold_ms => program,
+                _ => continue,
+            };
+            *held = None;
+            if self.current[channel] != Some(program) {
+                self.current[channel] = Some(program);
+                emit(MidiMessage::ProgramChange(
+                    Channel::from(channel as u8),
+                    program,
+                ));
+            }
+        }
+    }
+
+    /// Forget the emitted programs, so the next program change is emitted even if it repeats
+    pub fn reset(&mut self) {
+        self.current = [None; 16];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{note_on, program_change};
+    use std::vec::Vec;
+
+    fn flush(debounce: &mut PcDebounce, now_ms: u32) -> Vec<MidiMessage> {
+        let mut output = Vec::new();
+        debounce.flush(now_ms, &mut |message| output.push(message));
+        output
+    }
+
+    #[test]
+    fn should_emit_only_last_program_of_scroll_burst() {
+        let mut debounce = PcDebounce::new(100);
+        for (i, program) in (10..20).enumerate() {
+            let now = i as u32 * 30;
+            assert_eq!(debounce.process(now, program_change(0, program)), None);
+            assert_eq!(flush(&mut debounce, now + 29), []);
+        }
+
+        assert_eq!(flush(&mut debounce, 369), []);
+        assert_eq!(flush(&mut debounce, 370), [program_change(0, 19)]);
+        assert_eq!(flush(&mut debounce, 1000), []);
+    }
+
+    #[test]
+    fn should_drop_repeats_of_current_program() {
+        let mut debounce = PcDebounce::new(50);
+        debounce.process(0, program_change(0, 5));
+        assert_eq!(flush(&mut debounce, 50), [program_change(0, 5)]);
+
+        // Scrolling away and back ends on the current program
+        debounce.process(100, program_change(0, 6));
+        debounce.process(120, program_change(0, 5));
+        assert_eq!(flush(&mut debounce, 200), []);
+
+        debounce.reset();
+        debounce.process(300, program_change(0, 5));
+        assert_eq!(flush(&mut debounce, 350), [program_change(0, 5)]);
+    }
+
+    #[test]
+    fn should_hold_channels_independently() {
+        let mut debounce = PcDebounce::new(50);
+        assert_eq!(
+            debounce.process(0, note_on(0, 60, 100)),
+            Some(note_on(0, 60, 100))
+        );
+        debounce.process(u32::MAX - 9, program_change(0, 1));
+        debounce.process(20, program_change(1, 1));
+
+        assert_eq!(flush(&mut debounce, 40), [program_change(0, 1)]);
+        assert_eq!(flush(&mut debounce, 70), [program_change(1, 1)]);
+    }
+}
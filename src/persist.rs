@@ -0,0 +1,136 @@
+//! Compact binary encoding of processor configurations, for storing them in EEPROM or flash
+//!
+//! Saved configurations start with a format version byte. Loading checks every value, so corrupt
+//! or foreign data is rejected instead of producing a broken configuration. Bytes after the end of
+//! a configuration are ignored.
+
+use crate::{DecodeError, TooSmall};
+use midi_convert::midi_types::Channel;
+
+/// Format version written by `save` and accepted by `load`
+pub(crate) const VERSION: u8 = 1;
+
+/// Writes a configuration into a buffer after the version byte
+pub(crate) struct Writer<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Result<Self, TooSmall> {
+        let mut writer = Writer { buffer, len: 0 };
+        writer.byte(VERSION)?;
+        Ok(writer)
+    }
+
+    pub fn byte(&mut self, byte: u8) -> Result<(), TooSmall> {
+        *self.buffer.get_mut(self.len).ok_or(TooSmall)? = byte;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn u16(&mut self, value: u16) -> Result<(), TooSmall> {
+        let [low, high] = value.to_le_bytes();
+        self.byte(low)?;
+        self.byte(high)
+    }
+
+    /// Number of bytes written, including the version byte
+    pub fn finish(self) -> usize {
+        self.len
+    }
+}
+
+/// Reads a configuration written by a `Writer`, checking the version byte
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader { bytes };
+        match reader.byte()? {
+            VERSION => Ok(reader),
+            version => Err(DecodeError::UnsupportedVersion(version)),
+        }
+    }
+
+    pub fn byte(&mut self) -> Result<u8, DecodeError> {
+        let (&byte, rest) = self.bytes.split_first().ok_or(DecodeError::TooShort)?;
+        self.bytes = rest;
+        Ok(byte)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes([self.byte()?, self.byte()?]))
+    }
+
+    /// A 14 bit value
+    pub fn value14(&mut self) -> Result<u16, DecodeError> {
+        match self.u16()? {
+            value @ 0..=0x3fff => Ok(value),
+            _ => Err(DecodeError::Invalid),
+        }
+    }
+
+    /// A 7 bit value
+    pub fn value7(&mut self) -> Result<u8, DecodeError> {
+        match self.byte()? {
+            value @ 0..=0x7f => Ok(value),
+            _ => Err(DecodeError::Invalid),
+        }
+    }
+
+    pub fn channel(&mut self) -> Result<Channel, DecodeError> {
+        match self.byte()? {
+            channel @ 0..=0x0f => Ok(channel.into()),
+            _ => Err(DecodeError::Invalid),
+        }
+    }
+
+    pub fn bool(&mut self) -> Result<bool, DecodeError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::Invalid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_write_version_header() {
+        let mut buffer = [0; 4];
+        let mut writer = Writer::new(&mut buffer).unwrap();
+        writer.u16(0x1234).unwrap();
+        assert_eq!(writer.finish(), 3);
+        assert_eq!(buffer[..3], [VERSION, 0x34, 0x12]);
+
+        assert!(Writer::new(&mut []).is_err());
+    }
+
+    #[test]
+    fn should_reject_other_versions() {
+        assert!(Reader::new(&[VERSION]).is_ok());
+        assert!(matches!(
+            Reader::new(&[VERSION + 1, 0]),
+            Err(DecodeError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(Reader::new(&[]), Err(DecodeError::TooShort)));
+    }
+
+    #[test]
+    fn should_check_value_ranges() {
+        let mut reader = Reader::new(&[VERSION, 0x7f, 0x80, 0x0f, 0x10, 1, 2]).unwrap();
+        assert_eq!(reader.value7(), Ok(0x7f));
+        assert_eq!(reader.value7(), Err(DecodeError::Invalid));
+        assert_eq!(reader.channel(), Ok(Channel::C16));
+        assert_eq!(reader.channel(), Err(DecodeError::Invalid));
+        assert_eq!(reader.bool(), Ok(true));
+        assert_eq!(reader.bool(), Err(DecodeError::Invalid));
+        assert_eq!(reader.byte(), Err(DecodeError::TooShort));
+    }
+}
@@ -0,0 +1,298 @@
+//! Loop bodies of common midi applications, independent of the board they run on
+//!
+//! Each function does the work due at one pass through the main loop, so a board only sets up
+//! its endpoints and calls the function in a loop.
+
+use crate::{MidiSink, MidiSource, SinkError, SourceError};
+use core::fmt::{self, Display, Formatter};
+use midi_convert::midi_types::{Channel, MidiMessage, Note, Value7};
+
+/// Reason a pipeline stopped before it handled all messages available
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipelineError {
+    Source(SourceError),
+    Sink(SinkError),
+    /// Writing to the text output failed
+    Format,
+}
+
+impl From<SourceError> for PipelineError {
+    fn from(error: SourceError) -> Self {
+        PipelineError::Source(error)
+    }
+}
+
+impl From<SinkError> for PipelineError {
+    fn from(error: SinkError) -> Self {
+        PipelineError::Sink(error)
+    }
+}
+
+impl From<fmt::Error> for PipelineError {
+    fn from(_: fmt::Error) -> Self {
+        PipelineError::Format
+    }
+}
+
+impl Display for PipelineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineError::Source(error) => write!(f, "source: {}", error),
+            PipelineError::Sink(error) => write!(f, "sink: {}", error),
+            PipelineError::Format => f.write_str("text output failed"),
+        }
+    }
+}
+
+impl core::error::Error for PipelineError {}
+
+/// Send the messages received from `source` to `sink`, returns the number of messages sent
+///
+/// `filter` is called with every message received and returns the message to send, if any, so it
+/// can drop and rewrite messages. Messages are handled until `source` has none left or fails. A
+/// message the sink fails to send is dropped.
+pub fn passthrough_poll(
+    source: &mut impl MidiSource,
+    sink: &mut impl MidiSink,
+    mut filter: impl FnMut(MidiMessage) -> Option<MidiMessage>,
+) -> Result<usize, PipelineError> {
+    let mut sent = 0;
+    while let Some(message) = source.poll()? {
+        if let Some(message) = filter(message) {
+            sink.send(&message)?;
+            sent += 1;
+        }
+    }
+    Ok(sent)
+}
+
+/// Write a line for every message received from `source` to `out`, returns the number of lines
+pub fn monitor_poll(
+    source: &mut impl MidiSource,
+    out: &mut impl fmt::Write,
+) -> Result<usize, PipelineError> {
+    let mut lines = 0;
+    while let Some(message) = source.poll()? {
+        writeln!(out, "{:?}", message)?;
+        lines += 1;
+    }
+    Ok(lines)
+}
+
+/// Tempo and sound of the clicks sent by `metronome_poll`
+///
+/// Defaults to a side stick, note 37 on channel 10, at 120 beats per minute, every click lasts
+/// 50 ms or half a beat at fast tempos.
+#[derive(Debug, Clone)]
+pub struct Click {
+    channel: Channel,
+    note: Note,
+    velocity: Value7,
+    interval_ms: u32,
+    gate_ms: u32,
+    next_ms: Option<u32>,
+    /// End of the click sounding
+    note_off_ms: Option<u32>,
+}
+
+impl Default for Click {
+    fn default() -> Self {
+        Click {
+            channel: Channel::C10,
+            note: 37.into(),
+            velocity: 100.into(),
+            interval_ms: 500,
+            gate_ms: 50,
+            next_ms: None,
+            note_off_ms: None,
+        }
+    }
+}
+
+impl Click {
+    pub fn new(bpm: u16) -> Self {
+        let mut metronome = Self::default();
+        metronome.set_tempo_bpm(bpm);
+        metronome
+    }
+
+    /// Change the tempo from the next click on
+    pub fn set_tempo_bpm(&mut self, bpm: u16) {
+        self.interval_ms = 60_000 / u32::from(bpm.max(1));
+    }
+
+    pub fn set_click(&mut self, channel: Channel, note: Note, velocity: Value7) {
+        self.channel = channel;
+        self.note = note;
+        self.velocity = velocity;
+    }
+
+    pub fn set_gate_ms(&mut self, gate_ms: u32) {
+        self.gate_ms = gate_ms;
+    }
+
+    /// Interval between clicks in milliseconds
+    pub fn interval_ms(&self) -> u32 {
+        self.interval_ms
+    }
+
+    fn note_off(&mut self) -> MidiMessage {
+        self.note_off_ms = None;
+        MidiMessage::NoteOff(self.channel, self.note, 0.into())
+    }
+}
+
+/// Send the clicks of `metronome` due at `now_ms` to `sink`, returns the number of messages sent
+///
+/// The first click sounds at the first call. Clicks missed because the function was not called
+/// for more than a beat are skipped. Timestamps are in milliseconds and may wrap around.
+pub fn metronome_poll(
+    metronome: &mut Click,
+    now_ms: u32,
+    sink: &mut impl MidiSink,
+) -> Result<usize, SinkError> {
+    let due = |time: u32| now_ms.wrapping_sub(time) <= u32::MAX / 2;
+    let mut sent = 0;
+    if metronome.note_off_ms.is_some_and(due) {
+        sink.send(&metronome.note_off())?;
+        sent += 1;
+    }
+    let next = *metronome.next_ms.get_or_insert(now_ms);
+    if due(next) {
+        if metronome.note_off_ms.is_some() {
+            sink.send(&metronome.note_off())?;
+            sent += 1;
+        }
+        let interval = metronome.interval_ms;
+        let late = now_ms.wrapping_sub(next);
+        metronome.next_ms = Some(if late >= interval {
+            now_ms.wrapping_add(interval)
+        } else {
+            next.wrapping_add(interval)
+        });
+        let gate = metronome.gate_ms.min(interval / 2);
+        metronome.note_off_ms = Some(now_ms.wrapping_add(gate));
+        sink.send(&MidiMessage::NoteOn(
+            metronome.channel,
+            metronome.note,
+            metronome.velocity,
+        ))?;
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_off, note_on};
+    use crate::{RecordingMidiOut, SliceMidiIn};
+    use std::string::String;
+    use std::vec::Vec;
+
+    fn recorded<const N: usize>(sink: &RecordingMidiOut<N>) -> Vec<MidiMessage> {
+        sink.messages().copied().collect()
+    }
+
+    #[test]
+    fn should_pass_filtered_messages_through() {
+        let bytes = [0x90, 60, 100, 0xf8, 0xb0, 7, 90, 0x80, 60, 0];
+        let mut source = SliceMidiIn::new(&bytes);
+        let mut sink = RecordingMidiOut::<8>::new();
+        let sent = passthrough_poll(&mut source, &mut sink, |message| match message {
+            MidiMessage::TimingClock => None,
+            MidiMessage::ControlChange(_, control, value) => {
+                Some(MidiMessage::ControlChange(Channel::C2, control, value))
+            }
+            message => Some(message),
+        });
+        assert_eq!(sent, Ok(3));
+        assert_eq!(
+            recorded(&sink),
+            [note_on(0, 60, 100), cc(1, 7, 90), note_off(0, 60, 0)]
+        );
+        assert_eq!(passthrough_poll(&mut source, &mut sink, Some), Ok(0));
+    }
+
+    #[test]
+    fn should_stop_passthrough_at_errors() {
+        let bytes = [0x90, 60, 100, 0x90, 61, 100, 0x90, 62, 100];
+        let mut source = SliceMidiIn::new(&bytes);
+        let mut sink = RecordingMidiOut::<1>::new();
+        assert_eq!(
+            passthrough_poll(&mut source, &mut sink, Some),
+            Err(PipelineError::Sink(SinkError::Full))
+        );
+        // The message that failed is dropped, the rest is still there
+        assert_eq!(source.remaining(), [0x90, 62, 100]);
+        assert_eq!(recorded(&sink), [note_on(0, 60, 100)]);
+    }
+
+    #[test]
+    fn should_write_a_line_per_message() {
+        let bytes = [0x90, 60, 100, 0xfa];
+        let mut source = SliceMidiIn::new(&bytes);
+        let mut text = String::new();
+        assert_eq!(monitor_poll(&mut source, &mut text), Ok(2));
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("NoteOn"), "{}", lines[0]);
+        assert_eq!(lines[1], "Start");
+    }
+
+    #[test]
+    fn should_click_on_every_beat() {
+        let mut metronome = Click::new(120);
+        let mut sink = RecordingMidiOut::<16>::new();
+        let mut clicks = Vec::new();
+        for now in (1_000..3_000).step_by(10) {
+            let before = sink.messages().count();
+            metronome_poll(&mut metronome, now, &mut sink).unwrap();
+            for message in sink.messages().skip(before) {
+                clicks.push((now, *message));
+            }
+        }
+        let on = MidiMessage::NoteOn(Channel::C10, 37.into(), 100.into());
+        let off = MidiMessage::NoteOff(Channel::C10, 37.into(), 0.into());
+        assert_eq!(
+            clicks,
+            [
+                (1_000, on),
+                (1_050, off),
+                (1_500, on),
+                (1_550, off),
+                (2_000, on),
+                (2_050, off),
+                (2_500, on),
+                (2_550, off),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_skip_missed_clicks_and_end_long_gates() {
+        let mut metronome = Click::new(240);
+        metronome.set_gate_ms(1_000);
+        metronome.set_click(Channel::C1, 76.into(), 90.into());
+        let mut sink = RecordingMidiOut::<16>::new();
+        assert_eq!(metronome_poll(&mut metronome, 0, &mut sink), Ok(1));
+        // Half a beat at most
+        assert_eq!(metronome_poll(&mut metronome, 124, &mut sink), Ok(0));
+        assert_eq!(metronome_poll(&mut metronome, 125, &mut sink), Ok(1));
+        // Not called for several beats
+        assert_eq!(metronome_poll(&mut metronome, 1_010, &mut sink), Ok(1));
+        assert_eq!(metronome_poll(&mut metronome, 1_259, &mut sink), Ok(1));
+        assert_eq!(metronome_poll(&mut metronome, 1_260, &mut sink), Ok(1));
+        assert_eq!(
+            recorded(&sink),
+            [
+                note_on(0, 76, 90),
+                note_off(0, 76, 0),
+                note_on(0, 76, 90),
+                note_off(0, 76, 0),
+                note_on(0, 76, 90),
+            ]
+        );
+    }
+}
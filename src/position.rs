@@ -0,0 +1,182 @@
+//! Musical positions for song position pointers and midi clock
+
+use midi_convert::midi_types::Value14;
+
+/// Midi clock ticks per midi beat, a sixteenth note
+pub const TICKS_PER_MIDI_BEAT: u32 = 6;
+
+/// Midi clock ticks per whole note, at 24 ticks per quarter note
+const TICKS_PER_WHOLE: u32 = 96;
+
+/// Highest midi beat a song position pointer can carry
+const MAX_MIDI_BEATS: u32 = 0x3fff;
+
+/// Highest position in ticks, the last tick of the highest midi beat
+const MAX_TICKS: u32 = MAX_MIDI_BEATS * TICKS_PER_MIDI_BEAT + TICKS_PER_MIDI_BEAT - 1;
+
+/// Time signature, `denominator` is the note value of a beat like 4 for quarter notes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSignature {
+    numerator: u8,
+    denominator: u8,
+}
+
+impl TimeSignature {
+    /// `None` when `numerator` is 0 or `denominator` isn't a power of two up to 32
+    pub const fn new(numerator: u8, denominator: u8) -> Option<Self> {
+        if numerator == 0 || !denominator.is_power_of_two() || denominator > 32 {
+            return None;
+        }
+        Some(TimeSignature {
+            numerator,
+            denominator,
+        })
+    }
+
+    pub const fn numerator(&self) -> u8 {
+        self.numerator
+    }
+
+    pub const fn denominator(&self) -> u8 {
+        self.denominator
+    }
+
+    pub const fn ticks_per_beat(&self) -> u32 {
+        TICKS_PER_WHOLE / self.denominator as u32
+    }
+
+    pub const fn ticks_per_bar(&self) -> u32 {
+        self.ticks_per_beat() * self.numerator as u32
+    }
+}
+
+/// Position in a song, in midi clock ticks from the start
+///
+/// Song position pointers count midi beats of 6 ticks, a sixteenth note. Positions saturate at
+/// the last tick of midi beat 16383, the highest a song position pointer can carry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct SongPosition {
+    ticks: u32,
+}
+
+impl SongPosition {
+    pub const START: SongPosition = SongPosition { ticks: 0 };
+
+    /// Position at `ticks` from the start, saturating at the highest position
+    pub const fn from_ticks(ticks: u32) -> Self {
+        SongPosition {
+            ticks: if ticks > MAX_TICKS { MAX_TICKS } else { ticks },
+        }
+    }
+
+    /// Position of the midi beat in a song position pointer
+    pub fn from_value14(midi_beats: Value14) -> Self {
+        Self::from_ticks(u16::from(midi_beats) as u32 * TICKS_PER_MIDI_BEAT)
+    }
+
+    /// Position of `beat` in `bar`, both counting from 0, saturating at the highest position
+    pub fn from_bar_beat(bar: u32, beat: u32, signature: TimeSignature) -> Self {
+        let ticks = bar
+            .saturating_mul(signature.ticks_per_bar())
+            .saturating_add(beat.saturating_mul(signature.ticks_per_beat()));
+        Self::from_ticks(ticks)
+    }
+
+    /// Midi beat for a song position pointer, rounded down to the start of the midi beat
+    pub fn to_value14(&self) -> Value14 {
+        Value14::from((self.ticks / TICKS_PER_MIDI_BEAT) as u16)
+    }
+
+    pub const fn to_ticks(&self) -> u32 {
+        self.ticks
+    }
+
+    /// Bar and beat, both counting from 0, and the ticks into the beat
+    pub fn to_bar_beat(&self, signature: TimeSignature) -> (u32, u32, u32) {
+        let bar = self.ticks / signature.ticks_per_bar();
+        let in_bar = self.ticks % signature.ticks_per_bar();
+        (
+            bar,
+            in_bar / signature.ticks_per_beat(),
+            in_bar % signature.ticks_per_beat(),
+        )
+    }
+
+    /// Move forward by `ticks`, saturating at the highest position
+    pub fn advance_ticks(&mut self, ticks: u32) {
+        *self = Self::from_ticks(self.ticks.saturating_add(ticks));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_convert_song_position_pointer_values() {
+        let position = SongPosition::from_value14(Value14::from(0x0934u16));
+
+        assert_eq!(position.to_ticks(), 0x0934 * 6);
+        assert_eq!(u16::from(position.to_value14()), 0x0934);
+    }
+
+    #[test]
+    fn should_convert_bars_and_beats_in_three_four() {
+        let signature = TimeSignature::new(3, 4).unwrap();
+        let position = SongPosition::from_bar_beat(2, 1, signature);
+
+        assert_eq!(position.to_ticks(), 2 * 72 + 24);
+        assert_eq!(u16::from(position.to_value14()), 28);
+        assert_eq!(position.to_bar_beat(signature), (2, 1, 0));
+    }
+
+    #[test]
+    fn should_convert_bars_and_beats_in_seven_eight() {
+        let signature = TimeSignature::new(7, 8).unwrap();
+        let mut position = SongPosition::from_bar_beat(1, 6, signature);
+
+        assert_eq!(position.to_ticks(), 84 + 6 * 12);
+        assert_eq!(u16::from(position.to_value14()), 26);
+
+        position.advance_ticks(13);
+        assert_eq!(position.to_bar_beat(signature), (2, 0, 1));
+    }
+
+    #[test]
+    fn should_round_value14_down_to_midi_beat() {
+        let mut position = SongPosition::from_value14(Value14::from(4u16));
+        position.advance_ticks(5);
+
+        assert_eq!(u16::from(position.to_value14()), 4);
+        position.advance_ticks(1);
+        assert_eq!(u16::from(position.to_value14()), 5);
+    }
+
+    #[test]
+    fn should_reject_invalid_time_signatures() {
+        assert_eq!(TimeSignature::new(0, 4), None);
+        for denominator in [0, 3, 6, 12, 64, 128] {
+            assert_eq!(TimeSignature::new(4, denominator), None);
+        }
+        for denominator in [1, 2, 4, 8, 16, 32] {
+            let signature = TimeSignature::new(255, denominator).unwrap();
+            assert_eq!(signature.denominator(), denominator);
+            assert_eq!(signature.ticks_per_bar(), 255 * 96 / denominator as u32);
+        }
+    }
+
+    #[test]
+    fn should_saturate_at_highest_song_position() {
+        let mut position = SongPosition::from_value14(Value14::from(0x3fffu16));
+        position.advance_ticks(5);
+        assert_eq!(u16::from(position.to_value14()), 0x3fff);
+
+        position.advance_ticks(u32::MAX);
+        assert_eq!(position.to_ticks(), 0x3fff * 6 + 5);
+        assert_eq!(u16::from(position.to_value14()), 0x3fff);
+        assert_eq!(
+            SongPosition::from_bar_beat(u32::MAX, 0, TimeSignature::new(4, 4).unwrap()),
+            position
+        );
+    }
+}
@@ -0,0 +1,11 @@
+//! Common interface for message processors
+
+use midi_convert::midi_types::MidiMessage;
+
+/// A processor transforms incoming messages into zero or more outgoing messages
+///
+/// Processors can be chained by calling the next processor from the `emit` callback.
+pub trait MidiProcessor {
+    /// Process a message, calling `emit` for every resulting message
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage));
+}
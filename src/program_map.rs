@@ -0,0 +1,287 @@
+//! Program change mapping for live rigs
+
+use crate::{
+    message::bank_select,
+    persist::{Reader, Writer},
+    DecodeError, FullTable, MidiProcessor, TooSmall,
+};
+use midi_convert::midi_types::{Channel, MidiMessage, Program, Value14};
+
+/// Replaces a program change received on `in_channel` with a bank select and program change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramMapping {
+    pub in_channel: Channel,
+    pub in_program: Program,
+    pub out_channel: Channel,
+    /// Bank to select before the program change, `None` leaves the bank unchanged
+    pub out_bank: Option<Value14>,
+    pub out_program: Program,
+}
+
+/// Translates program changes using a table of up to `ENTRIES` mappings
+///
+/// A mapped program change is replaced by the bank select control changes, if the mapping has a
+/// bank, followed by the program change. Unmapped program changes are passed on unless dropping
+/// them is enabled, other messages are always passed on.
+#[derive(Debug, Clone)]
+pub struct ProgramMap<const ENTRIES: usize = 16> {
+    mappings: [Option<ProgramMapping>; ENTRIES],
+    drop_unmatched: bool,
+}
+
+impl<const ENTRIES: usize> Default for ProgramMap<ENTRIES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const ENTRIES: usize> ProgramMap<ENTRIES> {
+    /// Largest number of bytes written by `save`
+    pub const MAX_SAVED_LEN: usize = 4 + 7 * ENTRIES;
+
+    pub fn new() -> Self {
+        ProgramMap {
+            mappings: [None; ENTRIES],
+            drop_unmatched: false,
+        }
+    }
+
+    /// Drop program changes without a mapping instead of passing them on
+    pub fn set_drop_unmatched(&mut self, drop_unmatched: bool) {
+        self.drop_unmatched = drop_unmatched;
+    }
+
+    /// Add a mapping, replacing the mapping for the same input program
+    pub fn add(&mut self, mapping: ProgramMapping) -> Result<(), FullTable> {
+        let slot = match self.position(mapping.in_channel, mapping.in_program) {
+            Some(index) => &mut self.mappings[index],
+            None => self
+                .mappings
+                .iter_mut()
+                .find(|slot| slot.is_none())
+                .ok_or(FullTable)?,
+        };
+        *slot = Some(mapping);
+        Ok(())
+    }
+
+    /// Remove the mapping for an input program, returns the removed mapping
+    pub fn remove(&mut self, in_channel: Channel, in_program: Program) -> Option<ProgramMapping> {
+        self.position(in_channel, in_program)
+            .and_then(|index| self.mappings[index].take())
+    }
+
+    pub fn clear(&mut self) {
+        self.mappings = [None; ENTRIES];
+    }
+
+    pub fn get(&self, in_channel: Channel, in_program: Program) -> Option<&ProgramMapping> {
+        self.position(in_channel, in_program)
+            .and_then(|index| self.mappings[index].as_ref())
+    }
+
+    /// Save the mappings into `buffer`, returns the number of bytes written
+    pub fn save(&self, buffer: &mut [u8]) -> Result<usize, TooSmall> {
+        let mut writer = Writer::new(buffer)?;
+        writer.byte(self.drop_unmatched as u8)?;
+        writer.u16(self.mappings.iter().flatten().count() as u16)?;
+        for mapping in self.mappings.iter().flatten() {
+            writer.byte(mapping.in_channel.into())?;
+            writer.byte(mapping.in_program.into())?;
+            writer.byte(mapping.out_channel.into())?;
+            match mapping.out_bank {
+                Some(bank) => {
+                    writer.byte(1)?;
+                    writer.u16(bank.into())?;
+                }
+                None => writer.byte(0)?,
+            }
+            writer.byte(mapping.out_program.into())?;
+        }
+        Ok(writer.finish())
+    }
+
+    /// Load mappings saved by `save`
+    pub fn load(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes)?;
+        let mut map = Self::new();
+        map.drop_unmatched = reader.bool()?;
+        for _ in 0..reader.u16()? {
+            let mapping = ProgramMapping {
+                in_channel: reader.channel()?,
+                in_program: reader.value7()?.into(),
+                out_channel: reader.channel()?,
+                out_bank: match reader.bool()? {
+                    true => Some(reader.value14()?.into()),
+                    false => None,
+                },
+                out_program: reader.value7()?.into(),
+            };
+            map.add(mapping).map_err(|_| DecodeError::Invalid)?;
+        }
+        Ok(map)
+    }
+
+    fn position(&self, channel: Channel, program: Program) -> Option<usize> {
+        self.mappings.iter().position(
+            |m| matches!(m, Some(m) if m.in_channel == channel && m.in_program == program),
+        )
+    }
+}
+
+impl<const ENTRIES: usize> MidiProcessor for ProgramMap<ENTRIES> {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        let (channel, program) = match message {
+            MidiMessage::ProgramChange(channel, program) => (channel, program),
+            _ => return emit(message),
+        };
+
+        match self.get(channel, program) {
+            Some(mapping) => {
+                if let Some(bank) = mapping.out_bank {
+                    for message in bank_select(mapping.out_channel.into(), bank.into()) {
+                        emit(message);
+                    }
+                }
+                emit(MidiMessage::ProgramChange(
+                    mapping.out_channel,
+                    mapping.out_program,
+                ));
+            }
+            None if self.drop_unmatched => {}
+            None => emit(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{bank_select, cc, program_change};
+    use crate::test_util::process;
+
+    fn mapping(in_program: u8, out_bank: Option<u16>, out_program: u8) -> ProgramMapping {
+        ProgramMapping {
+            in_channel: Channel::C1,
+            in_program: in_program.into(),
+            out_channel: Channel::C3,
+            out_bank: out_bank.map(Value14::from),
+            out_program: out_program.into(),
+        }
+    }
+
+    #[test]
+    fn should_expand_to_bank_select_and_program_change() {
+        let mut map = ProgramMap::<4>::new();
+        map.add(mapping(5, Some(2), 17)).unwrap();
+        map.add(mapping(6, None, 18)).unwrap();
+
+        let [msb, lsb] = bank_select(2, 2);
+        assert_eq!(
+            process(
+                &mut map,
+                &[program_change(0, 5), program_change(0, 6), cc(0, 7, 100)]
+            ),
+            [
+                msb,
+                lsb,
+                program_change(2, 17),
+                program_change(2, 18),
+                cc(0, 7, 100)
+            ]
+        );
+    }
+
+    #[test]
+    fn should_pass_or_drop_unmatched_program_changes() {
+        let mut map = ProgramMap::<4>::new();
+        map.add(mapping(5, None, 17)).unwrap();
+
+        assert_eq!(
+            process(&mut map, &[program_change(1, 5), program_change(0, 4)]),
+            [program_change(1, 5), program_change(0, 4)]
+        );
+
+        map.set_drop_unmatched(true);
+        assert_eq!(process(&mut map, &[program_change(0, 4)]), []);
+    }
+
+    #[test]
+    fn should_report_full_table() {
+        let mut map = ProgramMap::<2>::new();
+        map.add(mapping(1, None, 10)).unwrap();
+        map.add(mapping(2, None, 20)).unwrap();
+
+        assert_eq!(map.add(mapping(3, None, 30)), Err(FullTable));
+        // replacing an existing mapping still works
+        assert_eq!(map.add(mapping(2, None, 21)), Ok(()));
+        assert_eq!(
+            map.get(Channel::C1, 2.into())
+                .map(|mapping| mapping.out_program),
+            Some(21.into())
+        );
+
+        assert_eq!(
+            map.remove(Channel::C1, 1.into()),
+            Some(mapping(1, None, 10))
+        );
+        assert_eq!(map.add(mapping(3, None, 30)), Ok(()));
+
+        map.clear();
+        assert_eq!(map.get(Channel::C1, 3.into()), None);
+    }
+
+    #[test]
+    fn should_round_trip_saved_mappings() {
+        let mut map = ProgramMap::<4>::new();
+        map.add(mapping(5, Some(0x3fff), 17)).unwrap();
+        map.add(mapping(6, None, 18)).unwrap();
+        map.set_drop_unmatched(true);
+
+        let mut buffer = [0; ProgramMap::<4>::MAX_SAVED_LEN];
+        let len = map.save(&mut buffer).unwrap();
+        assert_eq!(len, 16);
+        assert_eq!(map.save(&mut buffer[..len - 1]), Err(TooSmall));
+
+        let mut loaded = ProgramMap::<4>::load(&buffer[..len]).unwrap();
+        assert_eq!(
+            loaded.get(Channel::C1, 5.into()),
+            Some(&mapping(5, Some(0x3fff), 17))
+        );
+        assert_eq!(
+            loaded.get(Channel::C1, 6.into()),
+            Some(&mapping(6, None, 18))
+        );
+        assert_eq!(process(&mut loaded, &[program_change(0, 4)]), []);
+        assert_eq!(
+            ProgramMap::<4>::load(&buffer[..len - 1]).err(),
+            Some(DecodeError::TooShort)
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_saved_mappings() {
+        let mut map = ProgramMap::<2>::new();
+        map.add(mapping(1, None, 10)).unwrap();
+        map.add(mapping(2, None, 20)).unwrap();
+        let mut buffer = [0; ProgramMap::<2>::MAX_SAVED_LEN];
+        let len = map.save(&mut buffer).unwrap();
+
+        assert_eq!(
+            ProgramMap::<1>::load(&buffer[..len]).err(),
+            Some(DecodeError::Invalid)
+        );
+        let mut future = buffer;
+        future[0] += 1;
+        assert_eq!(
+            ProgramMap::<2>::load(&future).err(),
+            Some(DecodeError::UnsupportedVersion(2))
+        );
+        let mut corrupt = buffer;
+        corrupt[5] = 0x80;
+        assert_eq!(
+            ProgramMap::<2>::load(&corrupt).err(),
+            Some(DecodeError::Invalid)
+        );
+    }
+}
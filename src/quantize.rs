@@ -0,0 +1,172 @@
+//! Quantize processor snapping notes to a scale
+
+use crate::{MidiProcessor, Scale, TieBreak};
+use midi_convert::midi_types::{Channel, MidiMessage, Note};
+
+/// Quantized note for a note that is still held
+#[derive(Debug, Clone, Copy)]
+struct Held {
+    channel: Channel,
+    note: Note,
+    quantized: Note,
+}
+
+/// Snaps the notes of note on, note off and key pressure messages to the nearest tone of a scale
+///
+/// The quantized note is remembered for every held note, so the note off is quantized the same way
+/// as the note on even if the scale changed in between. At most `HELD` notes are remembered, when
+/// more notes are held the note off is quantized using the current scale.
+#[derive(Debug, Clone)]
+pub struct Quantize<const HELD: usize = 16> {
+    scale: Scale,
+    tie_break: TieBreak,
+    held: [Option<Held>; HELD],
+}
+
+impl<const HELD: usize> Quantize<HELD> {
+    pub fn new(scale: Scale) -> Self {
+        Quantize {
+            scale,
+            tie_break: TieBreak::Down,
+            held: [None; HELD],
+        }
+    }
+
+    pub fn scale(&self) -> Scale {
+        self.scale
+    }
+
+    /// Switch to another scale, held notes are still released using the old scale
+    pub fn set_scale(&mut self, scale: Scale) {
+        self.scale = scale;
+    }
+
+    pub fn set_tie_break(&mut self, tie_break: TieBreak) {
+        self.tie_break = tie_break;
+    }
+
+    fn held(&self, channel: Channel, note: Note) -> Option<usize> {
+        self.held.iter().position(
+            |held| matches!(held, Some(held) if held.channel == channel && held.note == note),
+        )
+    }
+
+    fn note_on(&mut self, channel: Channel, note: Note) -> Note {
+        let quantized = self.scale.nearest_with(note, self.tie_break);
+        if let Some(slot) = self.held.iter_mut().find(|held| held.is_none()) {
+            *slot = Some(Held {
+                channel,
+                note,
+                quantized,
+            });
+        }
+        quantized
+    }
+
+    fn note_off(&mut self, channel: Channel, note: Note) -> Note {
+        match self.held(channel, note) {
+            Some(index) => self.held[index].take().map_or(note, |held| held.quantized),
+            None => self.scale.nearest_with(note, self.tie_break),
+        }
+    }
+
+    fn key_pressure(&self, channel: Channel, note: Note) -> Note {
+        match self.held(channel, note).and_then(|index| self.held[index]) {
+            Some(held) => held.quantized,
+            None => self.scale.nearest_with(note, self.tie_break),
+        }
+    }
+}
+
+impl<const HELD: usize> MidiProcessor for Quantize<HELD> {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        emit(match message {
+            MidiMessage::NoteOn(channel, note, velocity) if u8::from(velocity) > 0 => {
+                MidiMessage::NoteOn(channel, self.note_on(channel, note), velocity)
+            }
+            MidiMessage::NoteOn(channel, note, velocity) => {
+                MidiMessage::NoteOn(channel, self.note_off(channel, note), velocity)
+            }
+            MidiMessage::NoteOff(channel, note, velocity) => {
+                MidiMessage::NoteOff(channel, self.note_off(channel, note), velocity)
+            }
+            MidiMessage::KeyPressure(channel, note, value) => {
+                MidiMessage::KeyPressure(channel, self.key_pressure(channel, note), value)
+            }
+            other => other,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{key_pressure, note_off, note_on};
+    use crate::test_util::process;
+
+    #[test]
+    fn should_quantize_notes() {
+        let mut quantize = Quantize::<4>::new(Scale::MAJOR);
+
+        assert_eq!(
+            process(
+                &mut quantize,
+                &[
+                    note_on(0, 61, 100),
+                    key_pressure(0, 61, 20),
+                    note_off(0, 61, 0)
+                ]
+            ),
+            [
+                note_on(0, 60, 100),
+                key_pressure(0, 60, 20),
+                note_off(0, 60, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn should_break_ties_upward() {
+        let mut quantize = Quantize::<4>::new(Scale::MAJOR);
+        quantize.set_tie_break(TieBreak::Up);
+
+        assert_eq!(
+            process(&mut quantize, &[note_on(0, 66, 100)]),
+            [note_on(0, 67, 100)]
+        );
+    }
+
+    #[test]
+    fn should_release_held_notes_with_scale_they_were_played_in() {
+        let mut quantize = Quantize::<4>::new(Scale::MAJOR);
+        let mut output = process(&mut quantize, &[note_on(0, 63, 100), note_on(1, 63, 100)]);
+        quantize.set_scale(Scale::NATURAL_MINOR);
+        output.extend(process(
+            &mut quantize,
+            &[note_on(0, 64, 100), note_off(0, 63, 0), note_on(1, 63, 0)],
+        ));
+        output.extend(process(&mut quantize, &[note_off(0, 64, 0)]));
+
+        assert_eq!(
+            output,
+            [
+                note_on(0, 62, 100),
+                note_on(1, 62, 100),
+                note_on(0, 63, 100),
+                note_off(0, 62, 0),
+                note_on(1, 62, 0),
+                note_off(0, 63, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_pass_other_messages() {
+        let mut quantize = Quantize::<4>::new(Scale::MAJOR);
+
+        assert_eq!(
+            process(&mut quantize, &[MidiMessage::TimingClock]),
+            [MidiMessage::TimingClock]
+        );
+    }
+}
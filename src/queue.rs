@@ -0,0 +1,58 @@
+//! Fixed size byte queue shared by the buffered inputs and outputs
+
+/// Fixed size ring buffer of bytes
+#[derive(Debug)]
+pub(crate) struct ByteQueue<const N: usize> {
+    bytes: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> ByteQueue<N> {
+    pub(crate) fn new() -> Self {
+        ByteQueue {
+            bytes: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn free(&self) -> usize {
+        N - self.len()
+    }
+
+    /// Add a byte to the back of the queue, the queue must not be full
+    pub(crate) fn push(&mut self, byte: u8) {
+        self.bytes[(self.head + self.len) % N] = byte;
+        self.len += 1;
+    }
+
+    /// Add a byte to the back of the queue, dropping the oldest byte if the queue is full
+    pub(crate) fn push_overwrite(&mut self, byte: u8) {
+        if N == 0 {
+            return;
+        }
+        if self.free() == 0 {
+            self.pop();
+        }
+        self.push(byte);
+    }
+
+    pub(crate) fn peek(&self) -> Option<u8> {
+        if self.len == 0 {
+            None
+        } else {
+            Some(self.bytes[self.head])
+        }
+    }
+
+    /// Remove the byte at the front of the queue, the queue must not be empty
+    pub(crate) fn pop(&mut self) {
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+    }
+}
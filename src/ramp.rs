@@ -0,0 +1,267 @@
+//! Gliding a controller to a new value over time
+
+use midi_convert::midi_types::{Channel, Control, MidiMessage};
+
+/// One in 16.16 fixed point
+const ONE: u32 = 1 << 16;
+
+/// Controller moved by a `Ramp`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RampTarget {
+    /// Control changes with values from 0 to 127
+    Control(Channel, Control),
+    /// Pitch bend with values from 0 to 16383
+    PitchBend(Channel),
+    /// Channel pressure with values from 0 to 127
+    ChannelPressure(Channel),
+}
+
+impl RampTarget {
+    fn max(self) -> u16 {
+        match self {
+            RampTarget::PitchBend(_) => 0x3fff,
+            _ => 0x7f,
+        }
+    }
+
+    fn message(self, value: u16) -> MidiMessage {
+        match self {
+            RampTarget::Control(channel, control) => {
+                MidiMessage::ControlChange(channel, control, (value as u8).into())
+            }
+            RampTarget::PitchBend(channel) => MidiMessage::PitchBendChange(channel, value.into()),
+            RampTarget::ChannelPressure(channel) => {
+                MidiMessage::ChannelPressure(channel, (value as u8).into())
+            }
+        }
+    }
+}
+
+/// How a `Ramp` moves between its start and target values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RampShape {
+    /// Constant speed
+    #[default]
+    Linear,
+    /// Slow at first and fast at the end, like a fade in decibels
+    Exponential,
+}
+
+impl RampShape {
+    /// Share of the way covered at `progress`, both in 16.16 fixed point from 0 to `ONE`
+    fn curve(self, progress: u32) -> u32 {
+        match self {
+            RampShape::Linear => progress,
+            RampShape::Exponential => {
+                // (2^(8 * progress) - 1) / 255, with 2 to the fractional power approximated
+                // linearly between the powers of two
+                let exponent = progress * 8;
+                let power = (ONE + (exponent & (ONE - 1))) << (exponent >> 16);
+                (power - ONE) / 255
+            }
+        }
+    }
+}
+
+/// Moves a controller from its current value to a target over a duration
+///
+/// `start` begins a ramp at the time of the next `tick`, `tick` sends the intermediate values at
+/// most every `interval_ms` and only when they changed. The target value is always sent when the
+/// duration has passed. Starting a ramp while another one is active continues from the value
+/// reached.
+#[derive(Debug, Clone)]
+pub struct Ramp {
+    target: RampTarget,
+    shape: RampShape,
+    interval_ms: u32,
+    value: u16,
+    from: u16,
+    to: u16,
+    duration_ms: u32,
+    /// Time of the first tick of the ramp, `None` before it
+    start_ms: Option<u32>,
+    active: bool,
+    last_sent: Option<u16>,
+    last_sent_ms: Option<u32>,
+}
+
+impl Ramp {
+    /// Ramp for `target` which is currently at `value`, sending at most every 10 ms
+    pub fn new(target: RampTarget, value: u16) -> Self {
+        Ramp {
+            target,
+            shape: RampShape::Linear,
+            interval_ms: 10,
+            value: value.min(target.max()),
+            from: 0,
+            to: 0,
+            duration_ms: 0,
+            start_ms: None,
+            active: false,
+            last_sent: None,
+            last_sent_ms: None,
+        }
+    }
+
+    pub fn set_shape(&mut self, shape: RampShape) {
+        self.shape = shape;
+    }
+
+    /// Shortest time between two intermediate messages
+    pub fn set_interval_ms(&mut self, interval_ms: u32) {
+        self.interval_ms = interval_ms;
+    }
+
+    /// Jump to `value` without sending it, such as when the controller was moved by hand
+    pub fn set_value(&mut self, value: u16) {
+        self.value = value.min(self.target.max());
+        self.active = false;
+    }
+
+    /// Current value of the controller
+    pub fn value(&self) -> u16 {
+        self.value
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Ramp from the current value to `target` over `duration_ms`, values above the range of the
+    /// controller are limited to it
+    pub fn start(&mut self, target: u16, duration_ms: u32) {
+        self.from = self.value;
+        self.to = target.min(self.target.max());
+        self.duration_ms = duration_ms;
+        self.start_ms = None;
+        self.active = true;
+    }
+
+    /// Stop the ramp at the value reached
+    pub fn stop(&mut self) {
+        self.active = false;
+    }
+
+    /// Advance the ramp to `now_ms` and call `emit` with the value if it is due
+    pub fn tick(&mut self, now_ms: u32, emit: &mut impl FnMut(MidiMessage)) {
+        if !self.active {
+            return;
+        }
+        let start = *self.start_ms.get_or_insert(now_ms);
+        let elapsed = now_ms.wrapping_sub(start);
+        let finished = elapsed >= self.duration_ms;
+        self.value = if finished {
+            self.active = false;
+            self.to
+        } else {
+            let progress = (u64::from(elapsed) << 16) / u64::from(self.duration_ms);
+            let covered = i64::from(self.shape.curve(progress as u32));
+            let distance = i64::from(self.to) - i64::from(self.from);
+            (i64::from(self.from) + ((distance * covered) >> 16)) as u16
+        };
+
+        let due = self
+            .last_sent_ms
+            .map_or(true, |last| now_ms.wrapping_sub(last) >= self.interval_ms);
+        if (due || finished) && self.last_sent != Some(self.value) {
+            self.last_sent = Some(self.value);
+            self.last_sent_ms = Some(now_ms);
+            emit(self.target.message(self.value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::vec::Vec;
+
+    fn run(ramp: &mut Ramp, from_ms: u32, to_ms: u32, step_ms: u32) -> Vec<(u32, u16)> {
+        let mut output = Vec::new();
+        for now in (from_ms..to_ms).step_by(step_ms as usize) {
+            ramp.tick(now, &mut |message| {
+                let value = match message {
+                    MidiMessage::ControlChange(_, _, value) => u8::from(value).into(),
+                    MidiMessage::PitchBendChange(_, value) => value.into(),
+                    MidiMessage::ChannelPressure(_, value) => u8::from(value).into(),
+                    _ => unreachable!(),
+                };
+                output.push((now, value))
+            });
+        }
+        output
+    }
+
+    fn volume() -> Ramp {
+        Ramp::new(RampTarget::Control(Channel::C1, Control::from(7)), 0)
+    }
+
+    #[test]
+    fn should_end_exactly_on_target() {
+        for shape in [RampShape::Linear, RampShape::Exponential] {
+            for (from, to) in [(0, 127), (127, 0), (100, 37)] {
+                let mut ramp = volume();
+                ramp.set_shape(shape);
+                ramp.set_value(from);
+                ramp.start(to, 1_000);
+
+                let output = run(&mut ramp, 5, 1_100, 7);
+                assert_eq!(output.last().map(|(_, value)| *value), Some(to));
+                assert!(!ramp.is_active());
+            }
+        }
+
+        let mut bend = Ramp::new(RampTarget::PitchBend(Channel::C2), 8192);
+        bend.start(16383, 333);
+        let output = run(&mut bend, 0, 400, 3);
+        assert_eq!(output.last(), Some(&(333, 16383)));
+    }
+
+    #[test]
+    fn should_move_monotonically_at_bounded_rate() {
+        let mut ramp = volume();
+        ramp.start(127, 500);
+        let up = run(&mut ramp, 0, 600, 1);
+        assert!(up.windows(2).all(|pair| pair[1].1 > pair[0].1));
+        assert!(up.windows(2).all(|pair| pair[1].0 - pair[0].0 >= 10));
+        assert!(up.len() <= 51);
+
+        let mut ramp = Ramp::new(RampTarget::ChannelPressure(Channel::C1), 127);
+        ramp.set_interval_ms(0);
+        ramp.start(0, 2_000);
+        let down = run(&mut ramp, 0, 2_100, 1);
+        assert!(down.windows(2).all(|pair| pair[1].1 < pair[0].1));
+        // Every value is sent without a rate limit
+        assert_eq!(down.len(), 128);
+    }
+
+    #[test]
+    fn should_start_slowly_when_exponential() {
+        let mut ramp = Ramp::new(RampTarget::PitchBend(Channel::C1), 0);
+        ramp.set_shape(RampShape::Exponential);
+        ramp.start(16383, 1_000);
+        run(&mut ramp, 0, 501, 10);
+        // 2^4 - 1 of 255
+        assert_eq!(ramp.value(), 963);
+
+        let output = run(&mut ramp, 510, 1_001, 10);
+        assert!(output.windows(2).all(|pair| pair[1].1 > pair[0].1));
+    }
+
+    #[test]
+    fn should_restart_from_current_value_on_retrigger() {
+        let mut ramp = volume();
+        ramp.start(127, 1_000);
+        run(&mut ramp, 0, 501, 10);
+        assert_eq!(ramp.value(), 63);
+
+        ramp.start(0, 100);
+        let output = run(&mut ramp, 600, 800, 10);
+        assert_eq!(output.first(), Some(&(610, 56)));
+        assert_eq!(output.last(), Some(&(700, 0)));
+
+        ramp.set_value(90);
+        assert!(run(&mut ramp, 800, 900, 10).is_empty());
+    }
+}
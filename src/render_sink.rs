@@ -0,0 +1,124 @@
+//! Rendering messages into buffers with a `MidiRenderer`, for packets sent over radio or USB
+
+use crate::Overflow;
+use midi_convert::render::MidiTransport;
+
+/// Transport writing rendered messages into a byte slice
+///
+/// A message that doesn't fit in the rest of the slice is not written at all, so the slice only
+/// ever holds whole messages.
+#[derive(Debug)]
+pub struct SliceSink<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SliceSink<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        SliceSink { buffer, len: 0 }
+    }
+
+    /// Number of bytes written
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The bytes written
+    pub fn written(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    /// Start over at the beginning of the slice
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl MidiTransport for SliceSink<'_> {
+    type Error = Overflow;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Overflow> {
+        let end = self.len + bytes.len();
+        self.buffer
+            .get_mut(self.len..end)
+            .ok_or(Overflow)?
+            .copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Transport appending rendered messages to a `heapless::Vec`, enabled with the `heapless`
+/// feature
+///
+/// A message that doesn't fit in the rest of the vector is not appended at all.
+#[cfg(feature = "heapless")]
+#[derive(Debug)]
+pub struct VecSink<'a, const N: usize>(pub &'a mut heapless::Vec<u8, N>);
+
+#[cfg(feature = "heapless")]
+impl<const N: usize> MidiTransport for VecSink<'_, N> {
+    type Error = Overflow;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Overflow> {
+        self.0.extend_from_slice(bytes).map_err(|_| Overflow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{note_off, note_on, pitch_bend};
+    use crate::MidiRenderer;
+    use midi_convert::midi_types::MidiMessage;
+
+    #[test]
+    fn should_render_into_a_slice() {
+        let mut buffer = [0; 16];
+        let mut renderer: MidiRenderer<_> = MidiRenderer::new(SliceSink::new(&mut buffer));
+        for message in [
+            note_on(0, 60, 100),
+            note_off(0, 60, 0),
+            pitch_bend(1, -8192),
+        ] {
+            renderer.render(&message).unwrap();
+        }
+        let sink = renderer.release();
+        assert_eq!(sink.written(), [0x90, 60, 100, 0x80, 60, 0, 0xe1, 0, 0]);
+        assert_eq!(sink.len(), 9);
+    }
+
+    #[test]
+    fn should_not_write_part_of_a_message_to_a_full_slice() {
+        let mut buffer = [0; 5];
+        let mut renderer: MidiRenderer<_> = MidiRenderer::new(SliceSink::new(&mut buffer));
+        renderer.render(&note_on(0, 60, 100)).unwrap();
+        assert_eq!(renderer.render(&note_on(1, 62, 100)), Err(Overflow));
+        // Running status is kept, so the data bytes still fit
+        renderer.render(&note_on(0, 62, 100)).unwrap();
+        assert_eq!(renderer.render(&MidiMessage::TimingClock), Err(Overflow));
+
+        let mut sink = renderer.release();
+        assert_eq!(sink.written(), [0x90, 60, 100, 62, 100]);
+        sink.clear();
+        assert!(sink.is_empty());
+        assert_eq!(SliceSink::new(&mut []).write(&[0xf8]), Err(Overflow));
+    }
+
+    #[cfg(feature = "heapless")]
+    #[test]
+    fn should_not_append_part_of_a_message_to_a_full_vec() {
+        let mut vec = heapless::Vec::<u8, 7>::new();
+        let mut renderer: MidiRenderer<_> = MidiRenderer::new(VecSink(&mut vec));
+        renderer.render(&note_on(0, 60, 100)).unwrap();
+        renderer.render(&pitch_bend(0, 0)).unwrap();
+        assert_eq!(renderer.render(&note_off(0, 60, 0)), Err(Overflow));
+        renderer.render(&MidiMessage::Start).unwrap();
+        assert_eq!(renderer.render(&MidiMessage::Stop), Err(Overflow));
+        assert_eq!(*renderer.release().0, [0x90, 60, 100, 0xe0, 0, 0x40, 0xfa]);
+    }
+}
@@ -0,0 +1,347 @@
+//! Pre-rendered midi messages that can be evaluated at compile time
+//!
+//! The value types in `midi-types` can't be converted to bytes in a `const fn`, so the const
+//! constructors on `RenderedMessage` mirror the constructors in `embedded_midi::message` and take
+//! plain `u8` values instead. Out of range values are clamped, channels are 0 based.
+//!
+//! ```
+//! use embedded_midi::rendered;
+//! use embedded_midi::RenderedMessage;
+//!
+//! static INIT: [RenderedMessage; 2] = rendered![program_change(0, 5), cc(0, 7, 100)];
+//! assert_eq!(INIT[1].as_bytes(), &[0xb0, 0x07, 0x64]);
+//! ```
+
+#[cfg(not(feature = "opt-size"))]
+use core::convert::Infallible;
+#[cfg(feature = "opt-size")]
+use midi_convert::midi_types::Channel;
+use midi_convert::midi_types::{status::*, MidiMessage};
+#[cfg(not(feature = "opt-size"))]
+use midi_convert::render::{MidiRenderer, MidiTransport};
+
+const fn data(value: u8) -> u8 {
+    if value > 0x7f {
+        0x7f
+    } else {
+        value
+    }
+}
+
+const fn channel(status: u8, channel: u8) -> u8 {
+    status | if channel > 0x0f { 0x0f } else { channel }
+}
+
+/// Split a 14 bit value into lsb and msb data bytes
+const fn value14(value: u16) -> (u8, u8) {
+    let value = if value > 0x3fff { 0x3fff } else { value };
+    ((value & 0x7f) as u8, (value >> 7) as u8)
+}
+
+/// A midi message rendered to its complete wire format, including the status byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderedMessage {
+    bytes: [u8; 3],
+    len: u8,
+}
+
+impl RenderedMessage {
+    const fn new(bytes: [u8; 3], len: u8) -> Self {
+        RenderedMessage { bytes, len }
+    }
+
+    pub const fn note_on(channel_: u8, note: u8, velocity: u8) -> Self {
+        Self::new([channel(NOTE_ON, channel_), data(note), data(velocity)], 3)
+    }
+
+    pub const fn note_off(channel_: u8, note: u8, velocity: u8) -> Self {
+        Self::new([channel(NOTE_OFF, channel_), data(note), data(velocity)], 3)
+    }
+
+    pub const fn key_pressure(channel_: u8, note: u8, value: u8) -> Self {
+        Self::new(
+            [channel(KEY_PRESSURE, channel_), data(note), data(value)],
+            3,
+        )
+    }
+
+    /// Control change message
+    pub const fn cc(channel_: u8, control: u8, value: u8) -> Self {
+        Self::new(
+            [
+                channel(CONTROL_CHANGE, channel_),
+                data(control),
+                data(value),
+            ],
+            3,
+        )
+    }
+
+    pub const fn program_change(channel_: u8, program: u8) -> Self {
+        Self::new([channel(PROGRAM_CHANGE, channel_), data(program), 0], 2)
+    }
+
+    pub const fn channel_pressure(channel_: u8, value: u8) -> Self {
+        Self::new([channel(CHANNEL_PRESSURE, channel_), data(value), 0], 2)
+    }
+
+    /// Pitch bend message, `value` ranges from -8192 to 8191 with 0 meaning no bend
+    pub const fn pitch_bend(channel_: u8, value: i16) -> Self {
+        let value = if value < -8192 {
+            -8192
+        } else if value > 8191 {
+            8191
+        } else {
+            value
+        };
+        let (lsb, msb) = value14((value + 8192) as u16);
+        Self::new([channel(PITCH_BEND_CHANGE, channel_), lsb, msb], 3)
+    }
+
+    pub const fn quarter_frame(value: u8) -> Self {
+        Self::new([QUARTER_FRAME, data(value), 0], 2)
+    }
+
+    /// Song position pointer message, `beats` counts sixteenth notes from the start of the song
+    pub const fn song_position(beats: u16) -> Self {
+        let (lsb, msb) = value14(beats);
+        Self::new([SONG_POSITION_POINTER, lsb, msb], 3)
+    }
+
+    pub const fn song_select(song: u8) -> Self {
+        Self::new([SONG_SELECT, data(song), 0], 2)
+    }
+
+    pub const fn tune_request() -> Self {
+        Self::new([TUNE_REQUEST, 0, 0], 1)
+    }
+
+    pub const fn timing_clock() -> Self {
+        Self::new([TIMING_CLOCK, 0, 0], 1)
+    }
+
+    pub const fn start() -> Self {
+        Self::new([START, 0, 0], 1)
+    }
+
+    /// Continue message, `continue` is a keyword
+    pub const fn continue_() -> Self {
+        Self::new([CONTINUE, 0, 0], 1)
+    }
+
+    pub const fn stop() -> Self {
+        Self::new([STOP, 0, 0], 1)
+    }
+
+    pub const fn active_sensing() -> Self {
+        Self::new([ACTIVE_SENSING, 0, 0], 1)
+    }
+
+    pub const fn reset() -> Self {
+        Self::new([RESET, 0, 0], 1)
+    }
+
+    /// The rendered bytes, including the status byte
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    /// The rendered bytes padded with zeroes and the number of bytes used
+    pub const fn to_parts(&self) -> ([u8; 3], u8) {
+        (self.bytes, self.len)
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Rendered messages always contain at least a status byte
+    pub const fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+/// Transport that stores a single rendered message
+#[cfg(not(feature = "opt-size"))]
+struct Buffer(RenderedMessage);
+
+#[cfg(not(feature = "opt-size"))]
+impl MidiTransport for Buffer {
+    type Error = Infallible;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.0.bytes[..bytes.len()].copy_from_slice(bytes);
+        self.0.len = bytes.len() as u8;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "opt-size")]
+impl From<&MidiMessage> for RenderedMessage {
+    /// Renders without the generic `midi-convert` renderer, which is larger
+    fn from(message: &MidiMessage) -> Self {
+        let voice = |status: u8, channel: Channel, first: u8, second: u8| {
+            Self::new([status | u8::from(channel), first, second], 3)
+        };
+        match *message {
+            MidiMessage::NoteOff(c, note, velocity) => {
+                voice(NOTE_OFF, c, note.into(), velocity.into())
+            }
+            MidiMessage::NoteOn(c, note, velocity) => {
+                voice(NOTE_ON, c, note.into(), velocity.into())
+            }
+            MidiMessage::KeyPressure(c, note, value) => {
+                voice(KEY_PRESSURE, c, note.into(), value.into())
+            }
+            MidiMessage::ControlChange(c, control, value) => {
+                voice(CONTROL_CHANGE, c, control.into(), value.into())
+            }
+            MidiMessage::ProgramChange(c, program) => {
+                Self::new([PROGRAM_CHANGE | u8::from(c), program.into(), 0], 2)
+            }
+            MidiMessage::ChannelPressure(c, value) => {
+                Self::new([CHANNEL_PRESSURE | u8::from(c), value.into(), 0], 2)
+            }
+            MidiMessage::PitchBendChange(c, value) => {
+                let (lsb, msb) = value14(value.into());
+                voice(PITCH_BEND_CHANGE, c, lsb, msb)
+            }
+            MidiMessage::QuarterFrame(frame) => Self::new([QUARTER_FRAME, frame.into(), 0], 2),
+            MidiMessage::SongPositionPointer(position) => {
+                let (lsb, msb) = value14(position.into());
+                Self::new([SONG_POSITION_POINTER, lsb, msb], 3)
+            }
+            MidiMessage::SongSelect(song) => Self::new([SONG_SELECT, song.into(), 0], 2),
+            MidiMessage::TuneRequest => Self::new([TUNE_REQUEST, 0, 0], 1),
+            MidiMessage::TimingClock => Self::new([TIMING_CLOCK, 0, 0], 1),
+            MidiMessage::Start => Self::new([START, 0, 0], 1),
+            MidiMessage::Continue => Self::new([CONTINUE, 0, 0], 1),
+            MidiMessage::Stop => Self::new([STOP, 0, 0], 1),
+            MidiMessage::ActiveSensing => Self::new([ACTIVE_SENSING, 0, 0], 1),
+            MidiMessage::Reset => Self::new([RESET, 0, 0], 1),
+        }
+    }
+}
+
+#[cfg(not(feature = "opt-size"))]
+impl From<&MidiMessage> for RenderedMessage {
+    fn from(message: &MidiMessage) -> Self {
+        // `MidiRenderSlice` renders 14 bit values msb first, so use the same renderer as `MidiOut`
+        let mut renderer = MidiRenderer::<_, false>::new(Buffer(Self::new([0; 3], 0)));
+        match renderer.render(message) {
+            Ok(()) => renderer.release().0,
+            Err(never) => match never {},
+        }
+    }
+}
+
+impl From<MidiMessage> for RenderedMessage {
+    fn from(message: MidiMessage) -> Self {
+        (&message).into()
+    }
+}
+
+/// Declare an array of pre-rendered midi messages using the const constructors on
+/// `RenderedMessage`
+///
+/// ```
+/// use embedded_midi::rendered;
+/// use embedded_midi::RenderedMessage;
+///
+/// static PATCH: [RenderedMessage; 3] = rendered![
+///     program_change(0, 12),
+///     cc(0, 74, 64),
+///     pitch_bend(0, 0),
+/// ];
+/// ```
+#[macro_export]
+macro_rules! rendered {
+    ($($name:ident($($arg:expr),* $(,)?)),* $(,)?) => {
+        [$($crate::RenderedMessage::$name($($arg),*)),*]
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message;
+
+    fn verify_rendered(rendered: RenderedMessage, message: MidiMessage) {
+        assert_eq!(rendered, RenderedMessage::from(message), "{:?}", message);
+    }
+
+    #[test]
+    fn should_match_runtime_renderer() {
+        verify_rendered(
+            RenderedMessage::note_on(0x02, 0x76, 0x34),
+            message::note_on(0x02, 0x76, 0x34),
+        );
+        verify_rendered(
+            RenderedMessage::note_off(0x02, 0x76, 0x34),
+            message::note_off(0x02, 0x76, 0x34),
+        );
+        verify_rendered(
+            RenderedMessage::key_pressure(0x02, 0x76, 0x34),
+            message::key_pressure(0x02, 0x76, 0x34),
+        );
+        verify_rendered(
+            RenderedMessage::cc(0x02, 0x07, 0x34),
+            message::cc(0x02, 0x07, 0x34),
+        );
+        verify_rendered(
+            RenderedMessage::program_change(0x02, 0x05),
+            message::program_change(0x02, 0x05),
+        );
+        verify_rendered(
+            RenderedMessage::channel_pressure(0x02, 0x34),
+            message::channel_pressure(0x02, 0x34),
+        );
+        for bend in [-9000, -8192, -1, 0, 1, 0x1234, 8191, 9000].iter() {
+            verify_rendered(
+                RenderedMessage::pitch_bend(0x02, *bend),
+                message::pitch_bend(0x02, *bend),
+            );
+        }
+        verify_rendered(
+            RenderedMessage::quarter_frame(0x23),
+            message::quarter_frame(0x23),
+        );
+        for beats in [0, 0x0934, 0x3fff, 0xffff].iter() {
+            verify_rendered(
+                RenderedMessage::song_position(*beats),
+                message::song_position(*beats),
+            );
+        }
+        verify_rendered(
+            RenderedMessage::song_select(0x05),
+            message::song_select(0x05),
+        );
+        verify_rendered(RenderedMessage::tune_request(), MidiMessage::TuneRequest);
+        verify_rendered(RenderedMessage::timing_clock(), MidiMessage::TimingClock);
+        verify_rendered(RenderedMessage::start(), MidiMessage::Start);
+        verify_rendered(RenderedMessage::continue_(), MidiMessage::Continue);
+        verify_rendered(RenderedMessage::stop(), MidiMessage::Stop);
+        verify_rendered(
+            RenderedMessage::active_sensing(),
+            MidiMessage::ActiveSensing,
+        );
+        verify_rendered(RenderedMessage::reset(), MidiMessage::Reset);
+    }
+
+    #[test]
+    fn should_clamp_like_runtime_constructors() {
+        verify_rendered(
+            RenderedMessage::note_on(0x12, 0x80, 0xff),
+            message::note_on(0x12, 0x80, 0xff),
+        );
+    }
+
+    #[test]
+    fn should_render_at_compile_time() {
+        const TABLE: [RenderedMessage; 3] = rendered![note_on(1, 60, 100), song_select(3), start()];
+
+        assert_eq!(TABLE[0].as_bytes(), &[0x91, 60, 100]);
+        assert_eq!(TABLE[1].to_parts(), ([0xf3, 3, 0], 2));
+        assert_eq!(TABLE[2].as_bytes(), &[0xfa]);
+    }
+}
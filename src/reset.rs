@@ -0,0 +1,239 @@
+//! Putting a device into a known state when it receives a system reset
+
+use crate::{MidiError, MidiOut, NoteTracker};
+use core::fmt::Debug;
+use embedded_hal_nb::serial;
+use midi_convert::midi_types::MidiMessage;
+
+/// Callback of a `ResetPolicy` without a callback set
+pub type NoCallback = fn(&mut dyn FnMut(MidiMessage));
+
+/// What a device does when it receives a system reset
+///
+/// Nothing takes part by default, the `with_` functions choose what does. On a reset `handle`
+/// first sends note offs for the held notes and forgets them, then resets the running status of
+/// the output so the next message starts with its status byte, then calls the callback. The
+/// callback can send messages through the function it gets, they are written to the output
+/// directly, so a reset it sends on doesn't trigger the policy again. Resetting the parser is
+/// set on the input with `MidiIn::set_resync_on_reset`.
+#[derive(Debug, Clone)]
+pub struct ResetPolicy<F = NoCallback> {
+    release_notes: bool,
+    reset_running_status: bool,
+    callback: Option<F>,
+}
+
+impl Default for ResetPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResetPolicy {
+    pub fn new() -> Self {
+        ResetPolicy {
+            release_notes: false,
+            reset_running_status: false,
+            callback: None,
+        }
+    }
+}
+
+impl<F> ResetPolicy<F>
+where
+    F: FnMut(&mut dyn FnMut(MidiMessage)),
+{
+    /// Send note offs for the notes held in the tracker and clear it
+    pub fn with_note_release(mut self) -> Self {
+        self.release_notes = true;
+        self
+    }
+
+    /// Reset the running status of the output
+    pub fn with_running_status_reset(mut self) -> Self {
+        self.reset_running_status = true;
+        self
+    }
+
+    /// Call `callback` last, to reset the state of the application
+    pub fn with_callback<G>(self, callback: G) -> ResetPolicy<G>
+    where
+        G: FnMut(&mut dyn FnMut(MidiMessage)),
+    {
+        ResetPolicy {
+            release_notes: self.release_notes,
+            reset_running_status: self.reset_running_status,
+            callback: Some(callback),
+        }
+    }
+
+    /// Handle a received message, returns whether it was a reset
+    ///
+    /// Everything taking part is reset even if writing to `out` fails, the first error is
+    /// returned.
+    pub fn handle<TX, E>(
+        &mut self,
+        message: &MidiMessage,
+        tracker: &mut NoteTracker,
+        out: &mut MidiOut<TX>,
+    ) -> Result<bool, MidiError<E>>
+    where
+        TX: serial::Write<u8, Error = E>,
+        E: Debug,
+    {
+        if *message != MidiMessage::Reset {
+            return Ok(false);
+        }
+        let mut result = Ok(());
+        let mut send = |message: MidiMessage| {
+            let written = out.write(&message);
+            if result.is_ok() {
+                result = written;
+            }
+        };
+        if self.release_notes {
+            tracker.release_all(&mut send);
+        }
+        if self.reset_running_status {
+            out.reset_running_status();
+        }
+        if let Some(callback) = &mut self.callback {
+            callback(&mut |message| {
+                let written = out.write(&message);
+                if result.is_ok() {
+                    result = written;
+                }
+            });
+        }
+        result.map(|()| true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_on};
+    use crate::test_util::Wire;
+    use crate::{MidiIn, ReleasePolicy};
+    use core::convert::Infallible;
+    use std::vec::Vec;
+
+    #[derive(Debug)]
+    struct Input(Vec<u8>);
+
+    impl serial::ErrorType for Input {
+        type Error = Infallible;
+    }
+
+    impl serial::Read<u8> for Input {
+        fn read(&mut self) -> nb::Result<u8, Self::Error> {
+            if self.0.is_empty() {
+                Err(nb::Error::WouldBlock)
+            } else {
+                Ok(self.0.remove(0))
+            }
+        }
+    }
+
+    /// Pass the messages of `bytes` through to `out`, tracking notes and handling resets
+    fn thru<F: FnMut(&mut dyn FnMut(MidiMessage))>(
+        bytes: &[u8],
+        resync: bool,
+        policy: &mut ResetPolicy<F>,
+        tracker: &mut NoteTracker,
+        out: &mut MidiOut<Wire>,
+    ) -> Vec<MidiMessage> {
+        let mut midi_in = MidiIn::new(Input(Vec::from(bytes)));
+        midi_in.set_resync_on_reset(resync);
+        let mut received = Vec::new();
+        for _ in bytes {
+            let message = match midi_in.try_read().unwrap() {
+                Some(message) => message,
+                None => continue,
+            };
+            received.push(message);
+            if !policy.handle(&message, tracker, out).unwrap() {
+                tracker.track(&message);
+                out.write(&message).unwrap();
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn should_release_notes_before_resetting_running_status() {
+        let mut policy = ResetPolicy::new()
+            .with_note_release()
+            .with_running_status_reset();
+        let mut tracker = NoteTracker::new();
+        let mut out = MidiOut::new(Wire::default());
+        out.set_release_policy(ReleasePolicy::OptimizeRunningStatus {
+            default_velocity: 0,
+        });
+        // Two notes with running status, a note cut off by the reset and data bytes after it
+        let bytes = [0x90, 60, 100, 62, 100, 0x90, 64, 0xff, 100, 0x90, 60, 90];
+        let received = thru(&bytes, true, &mut policy, &mut tracker, &mut out);
+
+        assert_eq!(
+            received,
+            [
+                note_on(0, 60, 100),
+                note_on(0, 62, 100),
+                MidiMessage::Reset,
+                note_on(0, 60, 90)
+            ]
+        );
+        assert_eq!(
+            out.release().0,
+            [
+                0x90, 60, 100, 62, 100, // Held notes
+                60, 0, 62, 0, // Released with running status before it was reset
+                0x90, 60, 90, // The next note starts with its status byte
+            ]
+        );
+        assert_eq!(tracker.held_count(), 1);
+        assert!(tracker.is_held(0.into(), 60.into()));
+    }
+
+    #[test]
+    fn should_only_reset_what_takes_part() {
+        let mut policy = ResetPolicy::new();
+        let mut tracker = NoteTracker::new();
+        let mut out = MidiOut::new(Wire::default());
+        // Without resync the note the reset interrupted completes
+        let bytes = [0x90, 60, 100, 0x90, 64, 0xff, 100, 62, 100];
+        let received = thru(&bytes, false, &mut policy, &mut tracker, &mut out);
+        assert_eq!(
+            received,
+            [
+                note_on(0, 60, 100),
+                MidiMessage::Reset,
+                note_on(0, 64, 100),
+                note_on(0, 62, 100)
+            ]
+        );
+        assert_eq!(out.release().0, [0x90, 60, 100, 64, 100, 62, 100]);
+        assert_eq!(tracker.held_count(), 3);
+    }
+
+    #[test]
+    fn should_call_back_without_triggering_again() {
+        let mut calls = 0;
+        let mut policy = ResetPolicy::new()
+            .with_running_status_reset()
+            .with_callback(|send: &mut dyn FnMut(MidiMessage)| {
+                calls += 1;
+                // Pass the reset on and restore the volume
+                send(MidiMessage::Reset);
+                send(cc(0, 7, 100));
+            });
+        let mut tracker = NoteTracker::new();
+        let mut out = MidiOut::new(Wire::default());
+        let bytes = [0xb0, 7, 20, 0xff, 0xb0, 7, 30];
+        thru(&bytes, true, &mut policy, &mut tracker, &mut out);
+
+        assert_eq!(calls, 1);
+        assert_eq!(out.release().0, [0xb0, 7, 20, 0xff, 0xb0, 7, 100, 7, 30]);
+    }
+}
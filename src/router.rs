@@ -0,0 +1,262 @@
+//! Port tagging and routing for devices with several midi ports
+
+use crate::{message::channel, MidiError, MidiOut, MidiSink, RoutingMatrix, SinkError};
+use core::fmt::Debug;
+use embedded_hal_nb::serial;
+use midi_convert::midi_types::{Channel, MidiMessage};
+
+/// Identifies a midi port of a device, like a DIN socket or a virtual USB cable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PortId(pub u8);
+
+/// Virtual cable of a USB midi interface, the same type as `PortId` so USB and DIN ports can be
+/// bridged directly
+pub type Cable = PortId;
+
+impl From<u8> for PortId {
+    fn from(port: u8) -> Self {
+        PortId(port)
+    }
+}
+
+impl From<PortId> for u8 {
+    fn from(port: PortId) -> Self {
+        port.0
+    }
+}
+
+/// A message tagged with the port it was received on or should be sent to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Routed<M> {
+    pub port: PortId,
+    pub message: M,
+}
+
+impl<M> Routed<M> {
+    pub fn new(port: PortId, message: M) -> Self {
+        Routed { port, message }
+    }
+}
+
+/// Dispatches messages received on `INPUTS` input ports to `PORTS` outputs
+///
+/// Every input port has an output for system messages and an output for each channel, messages
+/// without a route are dropped. Initially every input port is routed to the output with the same
+/// number.
+///
+/// The outputs are `MidiOut`s written with `dispatch`, or any `MidiSink` written with `forward`,
+/// including `&mut dyn MidiSink` to route between different kinds of endpoints.
+#[derive(Debug)]
+pub struct MidiRouter<O, const PORTS: usize, const INPUTS: usize = PORTS> {
+    outputs: [O; PORTS],
+    system_routes: [Option<PortId>; INPUTS],
+    channel_routes: [[Option<PortId>; 16]; INPUTS],
+}
+
+impl<O, const PORTS: usize, const INPUTS: usize> MidiRouter<O, PORTS, INPUTS> {
+    pub fn new(outputs: [O; PORTS]) -> Self {
+        let mut router = MidiRouter {
+            outputs,
+            system_routes: [None; INPUTS],
+            channel_routes: [[None; 16]; INPUTS],
+        };
+        for input in 0..INPUTS.min(PORTS) {
+            router.set_route(PortId(input as u8), Some(PortId(input as u8)));
+        }
+        router
+    }
+
+    pub fn release(self) -> [O; PORTS] {
+        self.outputs
+    }
+
+    pub fn output(&mut self, port: PortId) -> Option<&mut O> {
+        self.outputs.get_mut(port.0 as usize)
+    }
+
+    /// Route all messages from `input` to `output`, `None` drops them
+    pub fn set_route(&mut self, input: PortId, output: Option<PortId>) {
+        if let Some(routes) = self.channel_routes.get_mut(input.0 as usize) {
+            *routes = [output; 16];
+            self.system_routes[input.0 as usize] = output;
+        }
+    }
+
+    /// Route channel voice messages for `channel` from `input` to `output`, `None` drops them
+    pub fn set_channel_route(&mut self, input: PortId, channel: Channel, output: Option<PortId>) {
+        if let Some(routes) = self.channel_routes.get_mut(input.0 as usize) {
+            routes[u8::from(channel) as usize] = output;
+        }
+    }
+
+    /// Output for channel voice messages on `channel` received on `input`
+    pub fn route(&self, input: PortId, channel: Channel) -> Option<PortId> {
+        self.channel_routes
+            .get(input.0 as usize)
+            .and_then(|routes| routes[u8::from(channel) as usize])
+    }
+
+    /// Output for system messages received on `input`
+    pub fn system_route(&self, input: PortId) -> Option<PortId> {
+        self.system_routes.get(input.0 as usize).copied().flatten()
+    }
+
+    /// Output for a message received on an input port
+    pub fn route_message(&self, routed: &Routed<MidiMessage>) -> Option<PortId> {
+        match channel(&routed.message) {
+            Some(channel) => self.route(routed.port, channel),
+            None => self.system_route(routed.port),
+        }
+    }
+}
+
+impl<O: MidiSink, const PORTS: usize, const INPUTS: usize> MidiRouter<O, PORTS, INPUTS> {
+    /// Send a message received on an input port to the output it is routed to
+    pub fn forward(&mut self, routed: &Routed<MidiMessage>) -> Result<(), SinkError> {
+        match self
+            .route_message(routed)
+            .and_then(|port| self.outputs.get_mut(port.0 as usize))
+        {
+            Some(output) => output.send(&routed.message),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<TX, E, const PORTS: usize, const INPUTS: usize> MidiRouter<MidiOut<TX>, PORTS, INPUTS>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+{
+    /// Write a message received on an input port to the output it is routed to
+    pub fn dispatch(&mut self, routed: &Routed<MidiMessage>) -> Result<(), MidiError<E>> {
+        match self
+            .route_message(routed)
+            .and_then(|port| self.outputs.get_mut(port.0 as usize))
+        {
+            Some(output) => output.write(&routed.message),
+            None => Ok(()),
+        }
+    }
+
+    /// Write a message received on an input port to every output `matrix` routes it to, instead
+    /// of the routes of the router
+    ///
+    /// Each output renders the message itself, so running status is kept per output. Stops at the
+    /// first output returning an error.
+    pub fn dispatch_matrix<const IN: usize>(
+        &mut self,
+        matrix: &RoutingMatrix<IN, PORTS>,
+        routed: &Routed<MidiMessage>,
+    ) -> Result<(), MidiError<E>> {
+        let mut result = Ok(());
+        matrix.route(routed.port, &routed.message, &mut |port, message| {
+            if let (Ok(()), Some(output)) = (&result, self.outputs.get_mut(port.0 as usize)) {
+                result = output.write(message);
+            }
+        });
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{cc, note_on};
+    use crate::test_util::mock_writes;
+    use crate::RouteFilter;
+    use embedded_hal_mock::eh1::serial::Mock;
+
+    fn done<const PORTS: usize>(router: MidiRouter<MidiOut<Mock<u8>>, PORTS>) {
+        for output in router.release() {
+            output.release().done();
+        }
+    }
+
+    #[test]
+    fn should_route_inputs_to_same_output_by_default() {
+        let mut router = MidiRouter::new([
+            MidiOut::new(mock_writes(&[0x90, 0x40, 0x7f])),
+            MidiOut::new(mock_writes(&[0x91, 0x41, 0x7f, 0xf8])),
+        ]);
+
+        router
+            .dispatch(&Routed::new(PortId(0), note_on(0, 0x40, 0x7f)))
+            .unwrap();
+        router
+            .dispatch(&Routed::new(PortId(1), note_on(1, 0x41, 0x7f)))
+            .unwrap();
+        router
+            .dispatch(&Routed::new(PortId(1), MidiMessage::TimingClock))
+            .unwrap();
+        done(router);
+    }
+
+    #[test]
+    fn should_route_channels_with_overrides() {
+        let mut router = MidiRouter::new([
+            MidiOut::new(mock_writes(&[0x90, 0x40, 0x7f, 0x92, 0x43, 0x7f])),
+            MidiOut::new(mock_writes(&[0x91, 0x41, 0x7f, 0xf8])),
+        ]);
+        router.set_route(PortId(0), Some(PortId(1)));
+        router.set_channel_route(PortId(0), Channel::C1, Some(PortId(0)));
+        router.set_channel_route(PortId(1), Channel::C2, None);
+        router.set_channel_route(PortId(1), Channel::C3, Some(PortId(0)));
+
+        assert_eq!(router.route(PortId(0), Channel::C1), Some(PortId(0)));
+        assert_eq!(router.route(PortId(0), Channel::C2), Some(PortId(1)));
+        assert_eq!(router.system_route(PortId(0)), Some(PortId(1)));
+        assert_eq!(router.route(PortId(2), Channel::C1), None);
+
+        for routed in [
+            Routed::new(PortId(0), note_on(0, 0x40, 0x7f)),
+            Routed::new(PortId(0), note_on(1, 0x41, 0x7f)),
+            Routed::new(PortId(1), note_on(1, 0x42, 0x7f)),
+            Routed::new(PortId(1), note_on(2, 0x43, 0x7f)),
+            Routed::new(PortId(0), MidiMessage::TimingClock),
+            Routed::new(PortId(5), MidiMessage::TimingClock),
+        ]
+        .iter()
+        {
+            router.dispatch(routed).unwrap();
+        }
+        done(router);
+    }
+
+    #[test]
+    fn should_dispatch_to_every_output_of_matrix() {
+        let mut router = MidiRouter::<_, 3, 2>::new([
+            MidiOut::new(mock_writes(&[
+                0x90, 0x40, 0x7f, 0xb9, 0x07, 0x5a, 0x91, 0x41, 0x7f, 0xf8,
+            ])),
+            MidiOut::new(mock_writes(&[
+                0x90, 0x40, 0x7f, 0xb9, 0x07, 0x5a, 0x91, 0x41, 0x7f, 0xf8,
+            ])),
+            MidiOut::new(mock_writes(&[0x90, 0x40, 0x7f, 0xb9, 0x07, 0x5a, 0xf8])),
+        ]);
+        let mut matrix = RoutingMatrix::<2, 3>::new();
+        for output in 0..3 {
+            matrix.connect(PortId(0), PortId(output));
+            matrix.connect(PortId(1), PortId(output));
+        }
+        matrix.set_route(
+            PortId(1),
+            PortId(2),
+            Some(RouteFilter::channel(Channel::C10)),
+        );
+
+        for routed in [
+            Routed::new(PortId(0), note_on(0, 0x40, 0x7f)),
+            Routed::new(PortId(1), cc(9, 7, 90)),
+            Routed::new(PortId(1), note_on(1, 0x41, 0x7f)),
+            Routed::new(PortId(1), MidiMessage::TimingClock),
+        ]
+        .iter()
+        {
+            router.dispatch_matrix(&matrix, routed).unwrap();
+        }
+        for output in router.release() {
+            output.release().done();
+        }
+    }
+}
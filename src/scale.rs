@@ -0,0 +1,142 @@
+//! Musical scales for quantizing notes
+
+use midi_convert::midi_types::Note;
+
+/// Which scale tone to pick when a note is exactly between two scale tones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TieBreak {
+    Down,
+    Up,
+}
+
+/// A scale as a root pitch class and a mask of the intervals above the root it contains
+///
+/// Bit `n` of the mask is set when the scale contains the tone `n` semitones above the root. The
+/// scale constants all have C as their root, use `with_root` to transpose them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scale {
+    root: u8,
+    mask: u16,
+}
+
+impl Scale {
+    pub const CHROMATIC: Scale = Scale::new(0, 0b1111_1111_1111);
+    pub const MAJOR: Scale = Scale::new(0, 0b1010_1011_0101);
+    pub const NATURAL_MINOR: Scale = Scale::new(0, 0b0101_1010_1101);
+    pub const HARMONIC_MINOR: Scale = Scale::new(0, 0b1001_1010_1101);
+    pub const MAJOR_PENTATONIC: Scale = Scale::new(0, 0b0010_1001_0101);
+    pub const MINOR_PENTATONIC: Scale = Scale::new(0, 0b0100_1010_1001);
+
+    /// Create a scale from the pitch class of its root, 0 being C, and a 12 bit interval mask
+    pub const fn new(root: u8, mask: u16) -> Self {
+        Scale {
+            root: root % 12,
+            mask: mask & 0x0fff,
+        }
+    }
+
+    /// The same scale starting at another root pitch class
+    pub const fn with_root(self, root: u8) -> Self {
+        Scale::new(root, self.mask)
+    }
+
+    pub const fn root(&self) -> u8 {
+        self.root
+    }
+
+    pub const fn mask(&self) -> u16 {
+        self.mask
+    }
+
+    pub fn contains(&self, note: Note) -> bool {
+        self.contains_value(u8::from(note))
+    }
+
+    fn contains_value(&self, note: u8) -> bool {
+        let interval = (note + 12 - self.root) % 12;
+        self.mask & (1 << interval) != 0
+    }
+
+    /// The scale tone closest to `note`, ties are broken downward
+    pub fn nearest(&self, note: Note) -> Note {
+        self.nearest_with(note, TieBreak::Down)
+    }
+
+    /// The scale tone closest to `note`, notes outside the scale that are exactly between two scale
+    /// tones are moved in the direction of `tie_break`
+    ///
+    /// Scale tones outside the midi note range are never returned, if the scale is empty the note
+    /// is returned unchanged.
+    pub fn nearest_with(&self, note: Note, tie_break: TieBreak) -> Note {
+        let note = u8::from(note);
+        for distance in 0..12u8 {
+            let down = note.checked_sub(distance);
+            let up = Some(note + distance).filter(|up| *up <= 127);
+            let candidates = match tie_break {
+                TieBreak::Down => [down, up],
+                TieBreak::Up => [up, down],
+            };
+
+            if let Some(found) = candidates
+                .iter()
+                .flatten()
+                .find(|candidate| self.contains_value(**candidate))
+            {
+                return (*found).into();
+            }
+        }
+        note.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nearest(scale: Scale, note: u8) -> u8 {
+        scale.nearest(note.into()).into()
+    }
+
+    #[test]
+    fn should_contain_scale_tones() {
+        let d_major = Scale::MAJOR.with_root(2);
+
+        assert!(d_major.contains(62.into()));
+        assert!(d_major.contains(66.into()));
+        assert!(d_major.contains(73.into()));
+        assert!(!d_major.contains(65.into()));
+        assert!(!d_major.contains(72.into()));
+    }
+
+    #[test]
+    fn should_find_nearest_tone() {
+        assert_eq!(nearest(Scale::MAJOR, 61), 60);
+        assert_eq!(nearest(Scale::MAJOR, 62), 62);
+        assert_eq!(nearest(Scale::MAJOR, 66), 65);
+        assert_eq!(
+            u8::from(Scale::MAJOR.nearest_with(66.into(), TieBreak::Up)),
+            67
+        );
+        assert_eq!(nearest(Scale::MAJOR_PENTATONIC, 65), 64);
+        assert_eq!(nearest(Scale::MAJOR_PENTATONIC, 66), 67);
+    }
+
+    #[test]
+    fn should_find_nearest_tone_across_octave_boundaries() {
+        let c_pentatonic = Scale::MAJOR_PENTATONIC;
+        assert_eq!(nearest(c_pentatonic, 71), 72);
+        assert_eq!(nearest(c_pentatonic, 70), 69);
+
+        let f_sharp_major = Scale::MAJOR.with_root(6);
+        assert_eq!(nearest(f_sharp_major, 60), 59);
+        assert_eq!(nearest(f_sharp_major, 72), 71);
+    }
+
+    #[test]
+    fn should_stay_in_note_range() {
+        let b_only = Scale::new(11, 0b1);
+        assert_eq!(nearest(b_only, 0), 11);
+        assert_eq!(nearest(b_only, 127), 119);
+        assert_eq!(nearest(Scale::new(0, 0), 61), 61);
+    }
+}
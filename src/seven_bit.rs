@@ -0,0 +1,203 @@
+//! Carrying 8 bit data in the 7 bit data bytes of system exclusive messages
+//!
+//! Every group of up to 7 bytes is encoded as a byte collecting the top bits of the group, bit 0
+//! for the first byte, followed by the low 7 bits of each byte. A group at the end of the data
+//! may be shorter than 7 bytes, so `n` bytes encode into `n + ceil(n / 7)` bytes.
+
+use crate::TooSmall;
+
+/// Number of bytes `len` bytes are encoded into
+pub const fn encoded_len(len: usize) -> usize {
+    len + len.div_ceil(7)
+}
+
+/// Number of bytes `len` encoded bytes decode into
+pub const fn decoded_len(len: usize) -> usize {
+    len - len.div_ceil(8)
+}
+
+/// Encode `src` into `dst`, returns the number of bytes written
+///
+/// Fails without writing if `dst` is shorter than `encoded_len(src.len())`.
+pub fn encode_7bit(src: &[u8], dst: &mut [u8]) -> Result<usize, TooSmall> {
+    let len = encoded_len(src.len());
+    let dst = dst.get_mut(..len).ok_or(TooSmall)?;
+    let mut encoder = SevenBitEncoder::new();
+    let mut written = 0;
+    let mut emit = |byte| {
+        dst[written] = byte;
+        written += 1;
+    };
+    for byte in src {
+        encoder.push(*byte, &mut emit);
+    }
+    encoder.finish(&mut emit);
+    Ok(written)
+}
+
+/// Decode `src` into `dst`, returns the number of bytes written
+///
+/// Only the low 7 bits of the bytes of `src` are used. Fails without writing if `dst` is shorter
+/// than `decoded_len(src.len())`.
+pub fn decode_7bit(src: &[u8], dst: &mut [u8]) -> Result<usize, TooSmall> {
+    let dst = dst.get_mut(..decoded_len(src.len())).ok_or(TooSmall)?;
+    let mut decoder = SevenBitDecoder::new();
+    let decoded = src.iter().filter_map(|byte| decoder.push(*byte));
+    for (slot, byte) in dst.iter_mut().zip(decoded) {
+        *slot = byte;
+    }
+    Ok(dst.len())
+}
+
+/// Encodes data handed over in pieces, a group is written once its 7 bytes arrived
+#[derive(Debug, Clone, Default)]
+pub struct SevenBitEncoder {
+    group: [u8; 7],
+    len: usize,
+}
+
+impl SevenBitEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encode `byte`, calling `emit` with the encoded bytes of a completed group
+    pub fn push(&mut self, byte: u8, emit: &mut dyn FnMut(u8)) {
+        self.group[self.len] = byte;
+        self.len += 1;
+        if self.len == self.group.len() {
+            self.finish(emit);
+        }
+    }
+
+    /// Call `emit` with the encoded bytes of the last, incomplete group
+    pub fn finish(&mut self, emit: &mut dyn FnMut(u8)) {
+        let group = &self.group[..self.len];
+        if group.is_empty() {
+            return;
+        }
+        emit(
+            group
+                .iter()
+                .enumerate()
+                .fold(0, |msbs, (bit, byte)| msbs | (byte >> 7) << bit),
+        );
+        for byte in group {
+            emit(byte & 0x7f);
+        }
+        self.len = 0;
+    }
+}
+
+/// Decodes encoded data handed over in pieces, such as system exclusive chunks
+#[derive(Debug, Clone, Default)]
+pub struct SevenBitDecoder {
+    msbs: u8,
+    /// Position in the current group, 0 for the top bits byte
+    position: u8,
+}
+
+impl SevenBitDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode the next encoded byte, returns `None` for the top bits byte of a group
+    pub fn push(&mut self, byte: u8) -> Option<u8> {
+        let position = self.position;
+        self.position = (position + 1) % 8;
+        if position == 0 {
+            self.msbs = byte;
+            return None;
+        }
+        Some(byte & 0x7f | (self.msbs >> (position - 1) & 1) << 7)
+    }
+
+    /// Start decoding new data
+    pub fn reset(&mut self) {
+        self.position = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use proptest::prelude::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn should_encode_groups_with_top_bits_first() {
+        let mut encoded = [0; 11];
+        let src = [0x80, 0x01, 0xff, 0x7f, 0x00, 0x00, 0x81, 0xc0, 0x02];
+        assert_eq!(encode_7bit(&src, &mut encoded), Ok(11));
+        assert_eq!(
+            encoded,
+            [0x45, 0x00, 0x01, 0x7f, 0x7f, 0x00, 0x00, 0x01, 0x01, 0x40, 0x02]
+        );
+
+        let mut decoded = [0; 9];
+        assert_eq!(decode_7bit(&encoded, &mut decoded), Ok(9));
+        assert_eq!(decoded, src);
+    }
+
+    #[test]
+    fn should_calculate_lengths() {
+        let lengths: Vec<_> = [0, 1, 6, 7, 8, 14, 15]
+            .iter()
+            .map(|len| encoded_len(*len))
+            .collect();
+        assert_eq!(lengths, [0, 2, 7, 8, 10, 16, 18]);
+        for len in 0..100 {
+            assert_eq!(decoded_len(encoded_len(len)), len);
+        }
+    }
+
+    #[test]
+    fn should_reject_short_buffers() {
+        let mut buffer = [0xaa; 8];
+        assert_eq!(encode_7bit(&[0; 7], &mut buffer[..7]), Err(TooSmall));
+        assert_eq!(decode_7bit(&[0; 10], &mut buffer[..7]), Err(TooSmall));
+        assert_eq!(buffer, [0xaa; 8]);
+    }
+
+    proptest! {
+        #[test]
+        fn should_round_trip_every_length(data in prop::collection::vec(any::<u8>(), 64)) {
+            for len in 0..=data.len() {
+                let data = &data[..len];
+                let mut encoded = [0xff; encoded_len(64)];
+                let len = encode_7bit(data, &mut encoded).unwrap();
+                prop_assert_eq!(len, encoded_len(data.len()));
+                prop_assert!(encoded[..len].iter().all(|byte| *byte <= 0x7f));
+
+                let mut decoded = [0; 64];
+                prop_assert_eq!(decode_7bit(&encoded[..len], &mut decoded), Ok(data.len()));
+                prop_assert_eq!(&decoded[..data.len()], data);
+            }
+        }
+
+        #[test]
+        fn should_stream_across_chunk_boundaries(
+            data in prop::collection::vec(any::<u8>(), 0..=64),
+            chunk in 1usize..20,
+        ) {
+            let mut encoded = Vec::new();
+            let mut encoder = SevenBitEncoder::new();
+            for piece in data.chunks(chunk) {
+                for byte in piece {
+                    encoder.push(*byte, &mut |byte| encoded.push(byte));
+                }
+            }
+            encoder.finish(&mut |byte| encoded.push(byte));
+            prop_assert!(encoded.iter().all(|byte| *byte <= 0x7f));
+
+            let mut decoder = SevenBitDecoder::new();
+            let decoded: Vec<u8> = encoded
+                .chunks(chunk)
+                .flat_map(|piece| piece.iter().filter_map(|byte| decoder.push(*byte)).collect::<Vec<_>>())
+                .collect();
+            prop_assert_eq!(decoded, data);
+        }
+    }
+}
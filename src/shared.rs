@@ -0,0 +1,454 @@
+//! `MidiOut` shared between tasks and interrupt handlers, enabled with the `critical-section`
+//! feature
+//!
+//! Senders render messages into a byte queue guarded by a critical section, a single `pump` call
+//! from the idle loop or the TX interrupt drains the queue to the serial port. Senders never
+//! block on the serial port and the bytes of a message are always queued together, so messages
+//! from different senders never interleave.
+
+#[cfg(feature = "instrumentation")]
+use crate::LatencyStats;
+use crate::{
+    diag::log_debug, queue::ByteQueue, wire::Transmitter, MidiError, MidiOut, RenderedMessage,
+};
+use core::cell::RefCell;
+use core::fmt::Debug;
+use core::task::Poll;
+use critical_section::Mutex;
+use embedded_hal_nb::serial;
+use midi_convert::midi_types::MidiMessage;
+
+#[derive(Debug)]
+struct Shared<TX, const N: usize> {
+    tx: TX,
+    transmitter: Transmitter,
+    queue: ByteQueue<N>,
+    #[cfg(feature = "instrumentation")]
+    residency: Residency,
+}
+
+/// Number of queued messages that can be timed at once
+#[cfg(feature = "instrumentation")]
+const TIMED: usize = 8;
+
+/// Queue residency of messages sent with a timestamp
+#[cfg(feature = "instrumentation")]
+#[derive(Debug, Default)]
+struct Residency {
+    stats: LatencyStats,
+    /// Bytes ever queued and sent, wrapping
+    queued: u32,
+    sent: u32,
+    /// Queued count after the last byte and arrival time of timed messages, oldest first
+    timed: [(u32, u32); TIMED],
+    head: usize,
+    len: usize,
+}
+
+#[cfg(feature = "instrumentation")]
+impl Residency {
+    fn queued(&mut self, bytes: usize, arrival: Option<u32>) {
+        self.queued = self.queued.wrapping_add(bytes as u32);
+        // Messages are not timed when too many timed messages are queued already
+        if let (Some(arrival), true) = (arrival, self.len < TIMED) {
+            self.timed[(self.head + self.len) % TIMED] = (self.queued, arrival);
+            self.len += 1;
+        }
+    }
+
+    fn sent(&mut self, now: Option<u32>) {
+        self.sent = self.sent.wrapping_add(1);
+        if self.len > 0 && self.timed[self.head].0 == self.sent {
+            if let Some(now) = now {
+                self.stats.record_at(now, self.timed[self.head].1);
+            }
+            self.head = (self.head + 1) % TIMED;
+            self.len -= 1;
+        }
+    }
+}
+
+/// A `MidiOut` that can be written to from several tasks through `MidiSender` handles
+///
+/// `N` is the size of the byte queue.
+#[derive(Debug)]
+pub struct SharedMidiOut<TX, const N: usize> {
+    shared: Mutex<RefCell<Shared<TX, N>>>,
+}
+
+impl<TX, E, const N: usize> SharedMidiOut<TX, N>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+{
+    pub fn new(out: MidiOut<TX>) -> Self {
+        SharedMidiOut {
+            shared: Mutex::new(RefCell::new(Shared {
+                tx: out.tx,
+                transmitter: out.transmitter,
+                queue: ByteQueue::new(),
+                #[cfg(feature = "instrumentation")]
+                residency: Residency::default(),
+            })),
+        }
+    }
+
+    /// Release the `MidiOut`, bytes that were not sent yet are dropped
+    pub fn release(self) -> MidiOut<TX> {
+        let shared = self.shared.into_inner().into_inner();
+        MidiOut {
+            tx: shared.tx,
+            transmitter: shared.transmitter,
+        }
+    }
+
+    /// Create a handle for sending messages
+    pub fn sender(&self) -> MidiSender<'_, TX, N> {
+        MidiSender { out: self }
+    }
+
+    /// Queue a message, fails with `MidiError::BufferFull` when the message doesn't fit
+    pub fn send(&self, message: &MidiMessage) -> Result<(), MidiError<E>> {
+        self.queue(message, None).map(|_| ())
+    }
+
+    /// Like `send`, returns the number of bytes queued, which is one less when running status
+    /// elides the status byte
+    pub fn send_counted(&self, message: &MidiMessage) -> Result<usize, MidiError<E>> {
+        self.queue(message, None)
+    }
+
+    /// Queue a message at time `now`, its queue residency is recorded when it is sent by
+    /// `pump_at`
+    #[cfg(feature = "instrumentation")]
+    pub fn send_at(&self, now: u32, message: &MidiMessage) -> Result<(), MidiError<E>> {
+        self.queue(message, Some(now)).map(|_| ())
+    }
+
+    /// Write queued bytes to the serial port until the queue is empty or the port would block
+    ///
+    /// When the serial port fails the byte stays queued and is retried by the next call, so the
+    /// message is completed. Messages queued after the failure carry their status byte again, in
+    /// case the receiver lost track.
+    pub fn pump(&self) -> Result<(), MidiError<E>> {
+        self.drain(None)
+    }
+
+    /// Like `pump`, recording the queue residency of timed messages sent at time `now`
+    #[cfg(feature = "instrumentation")]
+    pub fn pump_at(&self, now: u32) -> Result<(), MidiError<E>> {
+        self.drain(Some(now))
+    }
+
+    /// Queue residency of messages sent with `send_at` and `pump_at`
+    #[cfg(feature = "instrumentation")]
+    pub fn stats(&self) -> LatencyStats {
+        critical_section::with(|cs| self.shared.borrow_ref(cs).residency.stats)
+    }
+
+    #[cfg(feature = "instrumentation")]
+    pub fn reset_stats(&self) {
+        critical_section::with(|cs| self.shared.borrow_ref_mut(cs).residency.stats.reset())
+    }
+
+    /// Number of bytes waiting to be sent
+    pub fn pending(&self) -> usize {
+        critical_section::with(|cs| self.shared.borrow_ref(cs).queue.len())
+    }
+
+    #[cfg_attr(not(feature = "instrumentation"), allow(unused_variables))]
+    fn queue(&self, message: &MidiMessage, now: Option<u32>) -> Result<usize, MidiError<E>> {
+        critical_section::with(|cs| {
+            let mut shared = self.shared.borrow_ref_mut(cs);
+            let shared = &mut *shared;
+            // Only queue the message if all of its bytes fit
+            let queued = shared
+                .transmitter
+                .load(RenderedMessage::from(message).as_bytes());
+            if queued > shared.queue.free() {
+                shared.transmitter.clear();
+                log_debug!("shared midi out: queue full, rejected {:?}", message);
+                return Err(MidiError::BufferFull);
+            }
+            while let Poll::Ready(byte) = shared.transmitter.pull() {
+                shared.queue.push(byte);
+            }
+            #[cfg(feature = "instrumentation")]
+            shared.residency.queued(queued, now);
+            Ok(queued)
+        })
+    }
+
+    #[cfg_attr(not(feature = "instrumentation"), allow(unused_variables))]
+    fn drain(&self, now: Option<u32>) -> Result<(), MidiError<E>> {
+        critical_section::with(|cs| {
+            let mut shared = self.shared.borrow_ref_mut(cs);
+            while let Some(byte) = shared.queue.peek() {
+                match shared.tx.write(byte) {
+                    Ok(()) => {
+                        shared.queue.pop();
+                        #[cfg(feature = "instrumentation")]
+                        shared.residency.sent(now);
+                    }
+                    Err(nb::Error::WouldBlock) => break,
+                    Err(nb::Error::Other(error)) => {
+                        shared.transmitter.running_status.interrupt();
+                        return Err(MidiError::Serial(error));
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+impl<TX, E> MidiOut<TX>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+{
+    /// Turn this output into a `SharedMidiOut` with a queue of `N` bytes
+    pub fn split_shared<const N: usize>(self) -> SharedMidiOut<TX, N> {
+        SharedMidiOut::new(self)
+    }
+}
+
+/// Cheap handle for sending messages to a `SharedMidiOut`
+#[derive(Debug)]
+pub struct MidiSender<'a, TX, const N: usize> {
+    out: &'a SharedMidiOut<TX, N>,
+}
+
+impl<TX, const N: usize> Clone for MidiSender<'_, TX, N> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<TX, const N: usize> Copy for MidiSender<'_, TX, N> {}
+
+impl<TX, E, const N: usize> MidiSender<'_, TX, N>
+where
+    TX: serial::Write<u8, Error = E>,
+    E: Debug,
+{
+    /// Queue a message, fails with `MidiError::BufferFull` when the message doesn't fit
+    pub fn send(&self, message: &MidiMessage) -> Result<(), MidiError<E>> {
+        self.out.send(message)
+    }
+
+    /// Queue a message, returns the number of bytes queued, see `SharedMidiOut::send_counted`
+    pub fn send_counted(&self, message: &MidiMessage) -> Result<usize, MidiError<E>> {
+        self.out.send_counted(message)
+    }
+
+    /// Queue a message at time `now`, see `SharedMidiOut::send_at`
+    #[cfg(feature = "instrumentation")]
+    pub fn send_at(&self, now: u32, message: &MidiMessage) -> Result<(), MidiError<E>> {
+        self.out.send_at(now, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_on};
+    use crate::test_util::parse_all;
+    use crate::test_util::FlakyWire;
+    use core::convert::Infallible;
+    use embedded_hal_nb::serial::ErrorKind;
+    use std::{thread, vec::Vec};
+
+    /// Serial transmitter that collects written bytes, accepting at most `limit` bytes
+    #[derive(Debug, Default)]
+    struct Wire {
+        bytes: Vec<u8>,
+        limit: Option<usize>,
+    }
+
+    impl serial::ErrorType for Wire {
+        type Error = Infallible;
+    }
+
+    impl serial::Write<u8> for Wire {
+        fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+            match self.limit {
+                Some(0) => return Err(nb::Error::WouldBlock),
+                Some(ref mut limit) => *limit -= 1,
+                None => {}
+            }
+            self.bytes.push(word);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_send_queued_messages_on_pump() {
+        let shared = MidiOut::new(Wire::default()).split_shared::<16>();
+        let sender = shared.sender();
+
+        sender.send(&note_on(2, 0x76, 0x34)).unwrap();
+        sender.send(&note_on(2, 0x33, 0x65)).unwrap();
+        assert_eq!(shared.pending(), 5);
+
+        shared.pump().unwrap();
+        assert_eq!(shared.pending(), 0);
+        assert_eq!(
+            shared.release().release().bytes,
+            [0x92, 0x76, 0x34, 0x33, 0x65]
+        );
+    }
+
+    #[test]
+    fn should_count_queued_bytes() {
+        let shared = MidiOut::new(Wire::default()).split_shared::<16>();
+        let sender = shared.sender();
+
+        assert_eq!(sender.send_counted(&note_on(2, 0x76, 0x34)), Ok(3));
+        assert_eq!(sender.send_counted(&note_on(2, 0x33, 0x65)), Ok(2));
+        assert_eq!(shared.send_counted(&note_on(3, 0x33, 0x65)), Ok(3));
+        assert_eq!(shared.pending(), 8);
+    }
+
+    #[test]
+    fn should_resume_and_resend_status_after_serial_error() {
+        let shared = MidiOut::new(FlakyWire {
+            failing: std::vec![4],
+            ..FlakyWire::default()
+        })
+        .split_shared::<16>();
+
+        shared.send(&note_on(2, 0x76, 0x34)).unwrap();
+        shared.send(&note_on(2, 0x33, 0x65)).unwrap();
+        assert_eq!(shared.pump(), Err(MidiError::Serial(ErrorKind::Other)));
+        shared.send(&note_on(2, 0x20, 0x10)).unwrap();
+        shared.pump().unwrap();
+
+        let bytes = shared.release().release().bytes;
+        assert_eq!(bytes, [0x92, 0x76, 0x34, 0x33, 0x65, 0x92, 0x20, 0x10]);
+        assert_eq!(
+            parse_all(&bytes),
+            [
+                note_on(2, 0x76, 0x34),
+                note_on(2, 0x33, 0x65),
+                note_on(2, 0x20, 0x10)
+            ]
+        );
+    }
+
+    #[test]
+    fn should_reject_messages_that_do_not_fit() {
+        let shared = MidiOut::new(Wire::default()).split_shared::<4>();
+
+        shared.send(&note_on(2, 0x76, 0x34)).unwrap();
+        assert_eq!(
+            shared.send(&note_on(3, 0x33, 0x65)),
+            Err(MidiError::BufferFull)
+        );
+        shared.send(&MidiMessage::TimingClock).unwrap();
+
+        shared.pump().unwrap();
+        assert_eq!(shared.release().release().bytes, [0x92, 0x76, 0x34, 0xf8]);
+    }
+
+    #[test]
+    fn should_keep_bytes_queued_when_serial_would_block() {
+        let wire = Wire {
+            bytes: Vec::new(),
+            limit: Some(2),
+        };
+        let shared = MidiOut::new(wire).split_shared::<8>();
+
+        shared.send(&note_on(2, 0x76, 0x34)).unwrap();
+        shared.pump().unwrap();
+        assert_eq!(shared.pending(), 1);
+    }
+
+    #[test]
+    fn should_not_interleave_messages_from_racing_senders() {
+        const COUNT: u8 = 100;
+        let shared = MidiOut::new(Wire::default()).split_shared::<32>();
+
+        thread::scope(|scope| {
+            let notes = shared.sender();
+            let controls = shared.sender();
+            let send = |sender: MidiSender<'_, Wire, 32>, message: MidiMessage| {
+                while sender.send(&message).is_err() {
+                    thread::yield_now();
+                }
+            };
+
+            let notes = scope
+                .spawn(move || (0..COUNT).for_each(|value| send(notes, note_on(1, value, 0x40))));
+            let controls = scope
+                .spawn(move || (0..COUNT).for_each(|value| send(controls, cc(2, 0x07, value))));
+
+            while !(notes.is_finished() && controls.is_finished()) || shared.pending() > 0 {
+                shared.pump().unwrap();
+            }
+        });
+
+        let received = parse_all(&shared.release().release().bytes);
+        let notes: Vec<MidiMessage> = (0..COUNT).map(|value| note_on(1, value, 0x40)).collect();
+        let controls: Vec<MidiMessage> = (0..COUNT).map(|value| cc(2, 0x07, value)).collect();
+        assert_eq!(received.len(), notes.len() + controls.len());
+        assert_eq!(
+            received
+                .iter()
+                .filter(|message| matches!(message, MidiMessage::NoteOn(..)))
+                .copied()
+                .collect::<Vec<_>>(),
+            notes
+        );
+        assert_eq!(
+            received
+                .iter()
+                .filter(|message| matches!(message, MidiMessage::ControlChange(..)))
+                .copied()
+                .collect::<Vec<_>>(),
+            controls
+        );
+    }
+
+    #[cfg(feature = "instrumentation")]
+    #[test]
+    fn should_record_queue_residency_of_timed_messages() {
+        let shared = MidiOut::new(Wire::default()).split_shared::<16>();
+
+        shared.send_at(100, &note_on(2, 0x76, 0x34)).unwrap();
+        shared.send(&note_on(2, 0x33, 0x65)).unwrap();
+        shared.sender().send_at(250, &cc(2, 0x07, 0x40)).unwrap();
+        shared.pump_at(1_000).unwrap();
+
+        let stats = shared.stats();
+        assert_eq!(stats.count(), 2);
+        assert_eq!(stats.min(), Some(750));
+        assert_eq!(stats.max(), Some(900));
+
+        shared.reset_stats();
+        assert_eq!(shared.stats().count(), 0);
+    }
+
+    #[cfg(feature = "instrumentation")]
+    #[test]
+    fn should_record_residency_once_the_last_byte_is_sent() {
+        let wire = Wire {
+            bytes: Vec::new(),
+            limit: Some(4),
+        };
+        let shared = MidiOut::new(wire).split_shared::<16>();
+
+        shared.send_at(0, &note_on(2, 0x76, 0x34)).unwrap();
+        shared.send_at(10, &note_on(3, 0x33, 0x65)).unwrap();
+        shared.pump_at(500).unwrap();
+
+        assert_eq!(shared.pending(), 2);
+        assert_eq!(shared.stats().count(), 1);
+        assert_eq!(shared.stats().max(), Some(500));
+    }
+}
@@ -0,0 +1,345 @@
+//! Destinations for the messages read by `MidiIn::poll_into`
+
+use midi_convert::midi_types::MidiMessage;
+
+/// A queue or buffer that `MidiIn::poll_into` adds messages to
+pub trait MessageSink {
+    /// No more messages can be added
+    fn is_full(&self) -> bool;
+
+    /// Add a message, only called when the sink is not full
+    fn push(&mut self, message: MidiMessage);
+}
+
+/// Sink calling a closure with every message, it is never full
+#[derive(Debug)]
+pub struct FnSink<F>(pub F);
+
+impl<F: FnMut(MidiMessage)> MessageSink for FnSink<F> {
+    fn is_full(&self) -> bool {
+        false
+    }
+
+    fn push(&mut self, message: MidiMessage) {
+        (self.0)(message)
+    }
+}
+
+/// Realtime messages handed out ahead of queued messages, active sensing only when `active_sensing`
+fn is_priority(message: &MidiMessage, active_sensing: bool) -> bool {
+    match message {
+        MidiMessage::TimingClock
+        | MidiMessage::Start
+        | MidiMessage::Continue
+        | MidiMessage::Stop
+        | MidiMessage::Reset => true,
+        MidiMessage::ActiveSensing => active_sensing,
+        _ => false,
+    }
+}
+
+/// Sink adding realtime messages to `realtime` and all other messages to `other`, so a backed up
+/// queue of notes doesn't delay the clock
+///
+/// The consumer drains the realtime sink first, for example a small `spsc` queue of its own. With
+/// an `FnSink` the realtime messages are handled right away in the context calling `poll_into`,
+/// bypassing queues entirely. Active sensing stays in order with the other messages unless set
+/// otherwise. The order of the messages in each sink is the order they were received in. The
+/// sink is full when either of the sinks is full.
+#[derive(Debug)]
+pub struct PrioritySink<R, S> {
+    realtime: R,
+    other: S,
+    active_sensing: bool,
+}
+
+impl<R: MessageSink, S: MessageSink> PrioritySink<R, S> {
+    pub fn new(realtime: R, other: S) -> Self {
+        PrioritySink {
+            realtime,
+            other,
+            active_sensing: false,
+        }
+    }
+
+    /// Add active sensing to the realtime sink too
+    pub fn set_active_sensing_priority(&mut self, priority: bool) {
+        self.active_sensing = priority;
+    }
+
+    /// Release the realtime and other sinks
+    pub fn release(self) -> (R, S) {
+        (self.realtime, self.other)
+    }
+}
+
+impl<R: MessageSink, S: MessageSink> MessageSink for PrioritySink<R, S> {
+    fn is_full(&self) -> bool {
+        self.realtime.is_full() || self.other.is_full()
+    }
+
+    fn push(&mut self, message: MidiMessage) {
+        if is_priority(&message, self.active_sensing) {
+            self.realtime.push(message);
+        } else {
+            self.other.push(message);
+        }
+    }
+}
+
+/// Fixed size ring of messages
+#[derive(Debug)]
+struct Ring<const N: usize> {
+    messages: [Option<MidiMessage>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> Ring<N> {
+    fn new() -> Self {
+        Ring {
+            messages: [None; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, message: MidiMessage) {
+        if self.len < N {
+            self.messages[(self.head + self.len) % N] = Some(message);
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<MidiMessage> {
+        if self.len == 0 {
+            return None;
+        }
+        let message = self.messages[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        message
+    }
+}
+
+/// Queue of up to `N` messages with a separate queue of up to `RT` realtime messages that `recv`
+/// hands out first
+///
+/// Other messages are handed out in the order they were received in. For sharing with an
+/// interrupt handler put the queue in a critical section mutex, or use a `PrioritySink` over two
+/// `spsc` queues.
+#[derive(Debug)]
+pub struct PriorityQueue<const N: usize = 32, const RT: usize = 4> {
+    realtime: Ring<RT>,
+    other: Ring<N>,
+    active_sensing: bool,
+}
+
+impl<const N: usize, const RT: usize> Default for PriorityQueue<N, RT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const RT: usize> PriorityQueue<N, RT> {
+    pub fn new() -> Self {
+        PriorityQueue {
+            realtime: Ring::new(),
+            other: Ring::new(),
+            active_sensing: false,
+        }
+    }
+
+    /// Queue active sensing with the realtime messages too
+    pub fn set_active_sensing_priority(&mut self, priority: bool) {
+        self.active_sensing = priority;
+    }
+
+    /// Next message, realtime messages first
+    pub fn recv(&mut self) -> Option<MidiMessage> {
+        self.realtime.pop().or_else(|| self.other.pop())
+    }
+
+    /// Number of queued messages
+    pub fn len(&self) -> usize {
+        self.realtime.len + self.other.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<const N: usize, const RT: usize> MessageSink for PriorityQueue<N, RT> {
+    fn is_full(&self) -> bool {
+        self.realtime.len == RT || self.other.len == N
+    }
+
+    fn push(&mut self, message: MidiMessage) {
+        if is_priority(&message, self.active_sensing) {
+            self.realtime.push(message);
+        } else {
+            self.other.push(message);
+        }
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<const N: usize> MessageSink for heapless::Vec<MidiMessage, N> {
+    fn is_full(&self) -> bool {
+        heapless::Vec::is_full(self)
+    }
+
+    fn push(&mut self, message: MidiMessage) {
+        let _ = heapless::Vec::push(self, message);
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<const N: usize> MessageSink for heapless::Deque<MidiMessage, N> {
+    fn is_full(&self) -> bool {
+        heapless::Deque::is_full(self)
+    }
+
+    fn push(&mut self, message: MidiMessage) {
+        let _ = self.push_back(message);
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<const N: usize> MessageSink for heapless::spsc::Producer<'_, MidiMessage, N> {
+    fn is_full(&self) -> bool {
+        !self.ready()
+    }
+
+    fn push(&mut self, message: MidiMessage) {
+        let _ = self.enqueue(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::note_on;
+    use crate::MidiIn;
+    use embedded_hal_mock::eh1::serial::{Mock, Transaction};
+    use std::vec::Vec;
+
+    #[cfg(feature = "heapless")]
+    fn fill(sink: &mut impl MessageSink) {
+        while !sink.is_full() {
+            sink.push(MidiMessage::TimingClock);
+        }
+    }
+
+    #[cfg(feature = "heapless")]
+    #[test]
+    fn should_fill_heapless_collections() {
+        let mut vec = heapless::Vec::<MidiMessage, 2>::new();
+        fill(&mut vec);
+        assert_eq!(vec.len(), 2);
+
+        let mut deque = heapless::Deque::<MidiMessage, 3>::new();
+        fill(&mut deque);
+        assert_eq!(deque.len(), 3);
+
+        let mut queue = heapless::spsc::Queue::<MidiMessage, 4>::new();
+        let (mut producer, consumer) = queue.split();
+        fill(&mut producer);
+        assert_eq!(consumer.len(), 3);
+    }
+
+    /// 30 notes with running status and a clock after every tenth note
+    fn flood() -> Vec<u8> {
+        let mut bytes = std::vec![0x90];
+        for note in 0..30 {
+            bytes.extend([note, 100]);
+            if note % 10 == 9 {
+                bytes.push(0xf8);
+            }
+        }
+        bytes
+    }
+
+    fn notes(range: core::ops::Range<u8>) -> impl Iterator<Item = MidiMessage> {
+        range.map(|note| note_on(0, note, 100))
+    }
+
+    #[test]
+    fn should_hand_out_realtime_messages_first() {
+        let bytes = flood();
+        let expectations = [
+            Transaction::read_many(&bytes),
+            Transaction::read_error(nb::Error::WouldBlock),
+        ];
+        let mut midi_in = MidiIn::new(Mock::new(&expectations));
+        let mut queue = PriorityQueue::<32, 4>::new();
+        assert_eq!(midi_in.poll_into(&mut queue).unwrap(), 33);
+        midi_in.rx.done();
+
+        let received: Vec<_> = core::iter::from_fn(|| queue.recv()).collect();
+        let expected: Vec<_> = core::iter::repeat(MidiMessage::TimingClock)
+            .take(3)
+            .chain(notes(0..30))
+            .collect();
+        assert_eq!(received, expected);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn should_keep_active_sensing_in_order_unless_set() {
+        let mut queue = PriorityQueue::<4, 2>::new();
+        queue.push(note_on(0, 60, 100));
+        queue.push(MidiMessage::ActiveSensing);
+        queue.push(MidiMessage::Start);
+        assert_eq!(queue.recv(), Some(MidiMessage::Start));
+        assert_eq!(queue.recv(), Some(note_on(0, 60, 100)));
+        assert_eq!(queue.recv(), Some(MidiMessage::ActiveSensing));
+
+        queue.set_active_sensing_priority(true);
+        queue.push(note_on(0, 60, 100));
+        queue.push(MidiMessage::ActiveSensing);
+        assert_eq!(queue.recv(), Some(MidiMessage::ActiveSensing));
+        assert!(!queue.is_full());
+        queue.push(MidiMessage::Stop);
+        queue.push(MidiMessage::Stop);
+        assert!(queue.is_full());
+    }
+
+    #[test]
+    fn should_handle_realtime_messages_while_polling() {
+        let bytes = flood();
+        let expectations = [
+            Transaction::read_many(&bytes),
+            Transaction::read_error(nb::Error::WouldBlock),
+        ];
+        let mut midi_in = MidiIn::new(Mock::new(&expectations));
+        // The clock callback sees how many notes were queued before it
+        let mut queued = PriorityQueue::<32, 1>::new();
+        let mut clocks = Vec::new();
+        {
+            let queued_len = core::cell::Cell::new(0);
+            let mut sink = PrioritySink::new(
+                FnSink(|message| clocks.push((message, queued_len.get()))),
+                FnSink(|message| {
+                    queued.push(message);
+                    queued_len.set(queued_len.get() + 1);
+                }),
+            );
+            midi_in.poll_into(&mut sink).unwrap();
+        }
+        midi_in.rx.done();
+
+        assert_eq!(
+            clocks,
+            [
+                (MidiMessage::TimingClock, 10),
+                (MidiMessage::TimingClock, 20),
+                (MidiMessage::TimingClock, 30)
+            ]
+        );
+        let received: Vec<_> = core::iter::from_fn(|| queued.recv()).collect();
+        assert_eq!(received, notes(0..30).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,668 @@
+//! Standard midi file writer and reader for logging performances to storage and playing them
+//! back, enabled with the `smf` feature
+//!
+//! Files are written in format 0, a single track holding all channels, in one pass. The length
+//! of the track is only known once it is finished. A writer that can seek gets it patched in with
+//! `finish_and_patch`. Otherwise write the length returned by `finish` as 4 big endian bytes at
+//! `TRACK_LENGTH_OFFSET`, for example by rewriting the first block of the file on an SD card.
+
+use crate::{
+    logger::Timestamped,
+    parse::{data_length, MidiParser},
+    RenderedMessage,
+};
+use core::fmt::{self, Debug, Display, Formatter};
+use embedded_io::{Read, Seek, SeekFrom, Write};
+use midi_convert::midi_types::MidiMessage;
+
+/// Offset of the track length in the file
+pub const TRACK_LENGTH_OFFSET: u64 = 18;
+
+/// Default tempo of a standard midi file, 120 beats per minute
+const DEFAULT_TEMPO_US: u32 = 500_000;
+
+/// Largest value a variable length quantity can hold
+const MAX_VARIABLE_LENGTH: u32 = 0x0fff_ffff;
+
+/// End of track meta event
+const END_OF_TRACK: [u8; 3] = [0xff, 0x2f, 0x00];
+
+/// Writes timestamped messages as the track of a format 0 standard midi file
+///
+/// Timestamps are in milliseconds and converted to ticks of `ppq` pulses per quarter note at the
+/// current tempo, 120 beats per minute until `set_tempo` changes it. The first event sets the
+/// start of the track. Only channel messages are written, other messages have no place in a file.
+#[derive(Debug)]
+pub struct SmfWriter<W> {
+    writer: W,
+    ppq: u16,
+    tempo_us: u32,
+    /// Time and track ticks the current tempo took effect at
+    anchor: Option<(u32, u64)>,
+    ticks: u64,
+    track_len: u32,
+}
+
+impl<W: Write> SmfWriter<W> {
+    /// Write the file header and the start of the track, `ppq` is clamped to 1..=0x7fff
+    pub fn new(mut writer: W, ppq: u16) -> Result<Self, W::Error> {
+        let ppq = ppq.clamp(1, 0x7fff);
+        writer.write_all(b"MThd")?;
+        writer.write_all(&6u32.to_be_bytes())?;
+        // Format 0 with one track
+        writer.write_all(&[0, 0, 0, 1])?;
+        writer.write_all(&ppq.to_be_bytes())?;
+        writer.write_all(b"MTrk")?;
+        writer.write_all(&0u32.to_be_bytes())?;
+
+        Ok(SmfWriter {
+            writer,
+            ppq,
+            tempo_us: DEFAULT_TEMPO_US,
+            anchor: None,
+            ticks: 0,
+            track_len: 0,
+        })
+    }
+
+    pub fn ppq(&self) -> u16 {
+        self.ppq
+    }
+
+    /// Write a message, messages other than channel messages are skipped
+    pub fn write(&mut self, event: &Timestamped<MidiMessage>) -> Result<(), W::Error> {
+        let rendered = RenderedMessage::from(event.message);
+        match rendered.as_bytes() {
+            [status, ..] if *status < 0xf0 => {
+                self.write_event(event.timestamp, rendered.as_bytes())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Change the tempo from time `now`, in microseconds per quarter note
+    ///
+    /// Writes a tempo meta event, so the file plays back at the tempo it was recorded at. A tempo
+    /// estimated from midi clock is `60_000_000 / bpm`.
+    pub fn set_tempo(&mut self, now: u32, tempo_us: u32) -> Result<(), W::Error> {
+        let tempo_us = tempo_us.clamp(1, 0x00ff_ffff);
+        let [_, high, mid, low] = tempo_us.to_be_bytes();
+        self.write_event(now, &[0xff, 0x51, 0x03, high, mid, low])?;
+        self.tempo_us = tempo_us;
+        self.anchor = Some((now, self.ticks));
+        Ok(())
+    }
+
+    /// Write the end of the track, returns the writer and the track length
+    pub fn finish(mut self) -> Result<(W, u32), W::Error> {
+        self.write_delta(0)?;
+        self.write_bytes(&END_OF_TRACK)?;
+        self.writer.flush()?;
+        Ok((self.writer, self.track_len))
+    }
+
+    /// Track ticks at time `now`
+    fn ticks_at(&mut self, now: u32) -> u64 {
+        let (start, start_ticks) = *self.anchor.get_or_insert((now, self.ticks));
+        let elapsed_us = now.wrapping_sub(start) as u64 * 1000;
+        start_ticks + elapsed_us * self.ppq as u64 / self.tempo_us as u64
+    }
+
+    fn write_event(&mut self, now: u32, bytes: &[u8]) -> Result<(), W::Error> {
+        let ticks = self.ticks_at(now).max(self.ticks);
+        let delta = (ticks - self.ticks).min(MAX_VARIABLE_LENGTH as u64);
+        self.ticks += delta;
+        self.write_delta(delta as u32)?;
+        self.write_bytes(bytes)
+    }
+
+    /// Write a delta time as a variable length quantity, 7 bits per byte with the most
+    /// significant first and the top bit set on all but the last byte
+    fn write_delta(&mut self, delta: u32) -> Result<(), W::Error> {
+        let mut bytes = [0u8; 4];
+        let mut len = 0;
+        let mut value = delta;
+        loop {
+            bytes[3 - len] = (value & 0x7f) as u8 | if len == 0 { 0 } else { 0x80 };
+            len += 1;
+            value >>= 7;
+            if value == 0 {
+                break;
+            }
+        }
+        self.write_bytes(&bytes[4 - len..])
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), W::Error> {
+        self.writer.write_all(bytes)?;
+        self.track_len = self.track_len.saturating_add(bytes.len() as u32);
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> SmfWriter<W> {
+    /// Write the end of the track and patch its length into the header, returns the writer
+    /// positioned at the end of the file
+    pub fn finish_and_patch(self) -> Result<W, W::Error> {
+        let (mut writer, track_len) = self.finish()?;
+        let end = writer.seek(SeekFrom::Current(0))?;
+        writer.seek(SeekFrom::Start(TRACK_LENGTH_OFFSET))?;
+        writer.write_all(&track_len.to_be_bytes())?;
+        writer.seek(SeekFrom::Start(end))?;
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+/// Error reading a standard midi file, `E` is the error type of the reader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmfError<E> {
+    /// The reader returned an error
+    Read(E),
+    /// The file ends in the middle of a chunk or event
+    UnexpectedEof,
+    /// The file is not a format 0 file with ticks per quarter note, the format is returned
+    Unsupported(u16),
+    /// The file is not a standard midi file or is damaged
+    Invalid,
+}
+
+impl<E: Debug> Display for SmfError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SmfError::Read(error) => write!(f, "read error: {:?}", error),
+            SmfError::UnexpectedEof => f.write_str("unexpected end of midi file"),
+            SmfError::Unsupported(format) => write!(f, "unsupported midi file format {}", format),
+            SmfError::Invalid => f.write_str("invalid midi file"),
+        }
+    }
+}
+
+impl<E: Debug> core::error::Error for SmfError<E> {}
+
+/// Event read from a standard midi file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmfEvent {
+    /// A channel message
+    Message(MidiMessage),
+    /// A tempo meta event, the new tempo in microseconds per quarter note
+    Tempo(u32),
+}
+
+/// Reads the events of a format 0 standard midi file a few bytes at a time
+///
+/// Reading a byte slice works as well, `embedded_io::Read` is implemented for `&[u8]`. Channel
+/// messages and tempo changes are returned with their delta time in ticks, including running
+/// status within the file. Other meta and system exclusive events are skipped, their delta times
+/// are added to the next event.
+#[derive(Debug)]
+pub struct SmfReader<R> {
+    reader: R,
+    buffer: [u8; 16],
+    start: usize,
+    end: usize,
+    ppq: u16,
+    /// Bytes of the track not read yet
+    remaining: u32,
+    running_status: Option<u8>,
+}
+
+impl<R: Read> SmfReader<R> {
+    /// Read the file header and find the track
+    pub fn new(reader: R) -> Result<Self, SmfError<R::Error>> {
+        let mut smf = SmfReader {
+            reader,
+            buffer: [0; 16],
+            start: 0,
+            end: 0,
+            ppq: 0,
+            remaining: 0,
+            running_status: None,
+        };
+
+        let (id, len) = smf.chunk_header()?;
+        if id != *b"MThd" || len < 6 {
+            return Err(SmfError::Invalid);
+        }
+        let format = smf.u16()?;
+        let tracks = smf.u16()?;
+        let division = smf.u16()?;
+        if format != 0 || division & 0x8000 != 0 || division == 0 {
+            return Err(SmfError::Unsupported(format));
+        }
+        if tracks != 1 {
+            return Err(SmfError::Invalid);
+        }
+        smf.ppq = division;
+        smf.skip(len - 6)?;
+
+        // Chunks of unknown types are skipped
+        loop {
+            let (id, len) = smf.chunk_header()?;
+            if id == *b"MTrk" {
+                smf.remaining = len;
+                return Ok(smf);
+            }
+            smf.skip(len)?;
+        }
+    }
+
+    /// Ticks per quarter note
+    pub fn ppq(&self) -> u16 {
+        self.ppq
+    }
+
+    /// Next event with the ticks since the previous one, `None` at the end of the track
+    pub fn next_event(&mut self) -> Result<Option<(u32, SmfEvent)>, SmfError<R::Error>> {
+        let mut delta: u32 = 0;
+        while self.remaining > 0 {
+            delta = delta.saturating_add(self.track_variable_length()?);
+            let mut status = self.track_byte()?;
+            let mut first = None;
+            if status < 0x80 {
+                first = Some(status);
+                status = self.running_status.ok_or(SmfError::Invalid)?;
+            }
+
+            match status {
+                0xff => {
+                    self.running_status = None;
+                    let kind = self.track_byte()?;
+                    let len = self.track_variable_length()?;
+                    match (kind, len) {
+                        (0x2f, _) => {
+                            self.skip_track(len)?;
+                            self.skip_track(self.remaining)?;
+                        }
+                        (0x51, 3) => {
+                            let tempo = (0..3).try_fold(0, |tempo, _| {
+                                Ok((tempo << 8) | self.track_byte()? as u32)
+                            })?;
+                            return Ok(Some((delta, SmfEvent::Tempo(tempo))));
+                        }
+                        _ => self.skip_track(len)?,
+                    }
+                }
+                0xf0 | 0xf7 => {
+                    self.running_status = None;
+                    let len = self.track_variable_length()?;
+                    self.skip_track(len)?;
+                }
+                0x80..=0xef => {
+                    self.running_status = Some(status);
+                    let mut parser = MidiParser::new();
+                    parser.parse(status);
+                    let mut message = None;
+                    for _ in 0..data_length(status) {
+                        let byte = match first.take() {
+                            Some(byte) => byte,
+                            None => self.track_byte()?,
+                        };
+                        if byte >= 0x80 {
+                            return Err(SmfError::Invalid);
+                        }
+                        message = parser.parse(byte);
+                    }
+                    return message
+                        .map(|message| Some((delta, SmfEvent::Message(message))))
+                        .ok_or(SmfError::Invalid);
+                }
+                _ => return Err(SmfError::Invalid),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Release the reader, positioned after the bytes read so far and the internal buffer
+    pub fn release(self) -> R {
+        self.reader
+    }
+
+    fn byte(&mut self) -> Result<u8, SmfError<R::Error>> {
+        if self.start == self.end {
+            self.start = 0;
+            self.end = self.reader.read(&mut self.buffer).map_err(SmfError::Read)?;
+            if self.end == 0 {
+                return Err(SmfError::UnexpectedEof);
+            }
+        }
+        self.start += 1;
+        Ok(self.buffer[self.start - 1])
+    }
+
+    fn u16(&mut self) -> Result<u16, SmfError<R::Error>> {
+        Ok(u16::from_be_bytes([self.byte()?, self.byte()?]))
+    }
+
+    fn chunk_header(&mut self) -> Result<([u8; 4], u32), SmfError<R::Error>> {
+        let id = [self.byte()?, self.byte()?, self.byte()?, self.byte()?];
+        let len = u32::from_be_bytes([self.byte()?, self.byte()?, self.byte()?, self.byte()?]);
+        Ok((id, len))
+    }
+
+    fn skip(&mut self, len: u32) -> Result<(), SmfError<R::Error>> {
+        for _ in 0..len {
+            self.byte()?;
+        }
+        Ok(())
+    }
+
+    /// Read a byte of the track, the track ending early is an error
+    fn track_byte(&mut self) -> Result<u8, SmfError<R::Error>> {
+        if self.remaining == 0 {
+            return Err(SmfError::Invalid);
+        }
+        self.remaining -= 1;
+        self.byte()
+    }
+
+    fn skip_track(&mut self, len: u32) -> Result<(), SmfError<R::Error>> {
+        for _ in 0..len {
+            self.track_byte()?;
+        }
+        Ok(())
+    }
+
+    /// Read a variable length quantity of up to 4 bytes
+    fn track_variable_length(&mut self) -> Result<u32, SmfError<R::Error>> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.track_byte()?;
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(SmfError::Invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_off, note_on, pitch_bend};
+    use core::convert::Infallible;
+    use std::vec::Vec;
+
+    fn at(timestamp: u32, message: MidiMessage) -> Timestamped<MidiMessage> {
+        Timestamped { timestamp, message }
+    }
+
+    /// Read a variable length quantity, returns it with the number of bytes it took
+    fn read_variable_length(bytes: &[u8]) -> (u32, usize) {
+        let mut value = 0;
+        for (index, byte) in bytes.iter().enumerate() {
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return (value, index + 1);
+            }
+        }
+        panic!("unterminated variable length quantity");
+    }
+
+    /// Parse a format 0 file, returning the ticks per quarter note and the events with their
+    /// delta times
+    fn parse(file: &[u8]) -> (u16, Vec<(u32, Vec<u8>)>) {
+        assert_eq!(&file[..8], b"MThd\x00\x00\x00\x06");
+        assert_eq!(&file[8..12], [0, 0, 0, 1]);
+        let ppq = u16::from_be_bytes([file[12], file[13]]);
+        assert_eq!(&file[14..18], b"MTrk");
+        let len = u32::from_be_bytes([file[18], file[19], file[20], file[21]]) as usize;
+        let mut track = &file[22..];
+        assert_eq!(track.len(), len);
+
+        let mut events = Vec::new();
+        while !track.is_empty() {
+            let (delta, used) = read_variable_length(track);
+            track = &track[used..];
+            let len = match track[0] {
+                0xff => 3 + track[2] as usize,
+                0xc0..=0xdf => 2,
+                _ => 3,
+            };
+            events.push((delta, track[..len].to_vec()));
+            track = &track[len..];
+        }
+        assert_eq!(events.last().unwrap().1, END_OF_TRACK);
+        (ppq, events)
+    }
+
+    /// In memory file that can seek
+    #[derive(Default)]
+    struct File {
+        bytes: Vec<u8>,
+        position: usize,
+    }
+
+    impl embedded_io::ErrorType for File {
+        type Error = Infallible;
+    }
+
+    impl Write for File {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            let end = self.position + buf.len();
+            if self.bytes.len() < end {
+                self.bytes.resize(end, 0);
+            }
+            self.bytes[self.position..end].copy_from_slice(buf);
+            self.position = end;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    impl Seek for File {
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+            self.position = match pos {
+                SeekFrom::Start(offset) => offset as usize,
+                SeekFrom::End(offset) => (self.bytes.len() as i64 + offset) as usize,
+                SeekFrom::Current(offset) => (self.position as i64 + offset) as usize,
+            };
+            Ok(self.position as u64)
+        }
+    }
+
+    #[test]
+    fn should_write_events_with_delta_times() {
+        let mut smf = SmfWriter::new(File::default(), 96).unwrap();
+        for event in [
+            at(1_000, note_on(0, 60, 100)),
+            at(1_000, MidiMessage::TimingClock),
+            at(1_500, note_off(0, 60, 0)),
+            at(1_500, MidiMessage::ProgramChange(3.into(), 5.into())),
+            at(4_000, cc(1, 7, 90)),
+        ] {
+            smf.write(&event).unwrap();
+        }
+        let file = smf.finish_and_patch().unwrap();
+
+        // 120 beats per minute, a quarter note every 500 ms
+        let (ppq, events) = parse(&file.bytes);
+        assert_eq!(ppq, 96);
+        assert_eq!(
+            events,
+            [
+                (0, [0x90, 60, 100].to_vec()),
+                (96, [0x80, 60, 0].to_vec()),
+                (0, [0xc3, 5].to_vec()),
+                (480, [0xb1, 7, 90].to_vec()),
+                (0, END_OF_TRACK.to_vec()),
+            ]
+        );
+        assert_eq!(file.position, file.bytes.len());
+    }
+
+    #[test]
+    fn should_write_tempo_changes() {
+        let mut smf = SmfWriter::new(File::default(), 480).unwrap();
+        smf.write(&at(0, note_on(0, 60, 100))).unwrap();
+        // 100 beats per minute
+        smf.set_tempo(300, 600_000).unwrap();
+        smf.write(&at(900, note_off(0, 60, 0))).unwrap();
+        let file = smf.finish_and_patch().unwrap();
+
+        let (_, events) = parse(&file.bytes);
+        assert_eq!(
+            events,
+            [
+                (0, [0x90, 60, 100].to_vec()),
+                (288, [0xff, 0x51, 0x03, 0x09, 0x27, 0xc0].to_vec()),
+                (480, [0x80, 60, 0].to_vec()),
+                (0, END_OF_TRACK.to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_return_track_length_without_seeking() {
+        let mut smf = SmfWriter::new(Vec::new(), 96).unwrap();
+        smf.write(&at(0, note_on(0, 60, 100))).unwrap();
+        // Ten minutes later needs a three byte delta time
+        smf.write(&at(600_000, note_off(0, 60, 0))).unwrap();
+        let (mut bytes, len) = smf.finish().unwrap();
+
+        assert_eq!(len, 4 + 6 + 4);
+        assert_eq!(&bytes[18..22], [0, 0, 0, 0]);
+        bytes[18..22].copy_from_slice(&len.to_be_bytes());
+        let (_, events) = parse(&bytes);
+        assert_eq!(events[1], (115_200, [0x80, 60, 0].to_vec()));
+    }
+
+    #[test]
+    fn should_encode_variable_length_quantities() {
+        for (delta, encoded) in [
+            (0, &[0x00][..]),
+            (0x7f, &[0x7f]),
+            (0x80, &[0x81, 0x00]),
+            (0x3fff, &[0xff, 0x7f]),
+            (0x0fff_ffff, &[0xff, 0xff, 0xff, 0x7f]),
+        ] {
+            let mut smf = SmfWriter::new(Vec::new(), 96).unwrap();
+            smf.write_delta(delta).unwrap();
+            let (bytes, _) = smf.finish().unwrap();
+            assert_eq!(&bytes[22..22 + encoded.len()], encoded);
+        }
+    }
+
+    /// A file with running status, meta events and a system exclusive event
+    #[rustfmt::skip]
+    const FIXTURE: &[u8] = &[
+        b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0x01, 0xe0,
+        // A chunk of an unknown type
+        b'X', b'f', b'o', b'o', 0, 0, 0, 2, 0xaa, 0xbb,
+        b'M', b'T', b'r', b'k', 0, 0, 0, 53,
+        // Track name
+        0x00, 0xff, 0x03, 0x04, b'D', b'e', b'm', b'o',
+        // Tempo of 100 beats per minute
+        0x00, 0xff, 0x51, 0x03, 0x09, 0x27, 0xc0,
+        0x00, 0x90, 60, 100,
+        // Running status
+        0x60, 64, 100,
+        0x83, 0x60, 60, 0,
+        // System exclusive event, ends running status
+        0x10, 0xf0, 0x03, 0x7e, 0x01, 0xf7,
+        0x10, 0xc2, 0x05,
+        0x00, 0xb1, 7, 90,
+        // Key signature between messages
+        0x20, 0xff, 0x59, 0x02, 0x00, 0x00,
+        0x20, 0x80, 64, 0,
+        0x00, 0xff, 0x2f, 0x00,
+    ];
+
+    #[test]
+    fn should_read_fixture_events() {
+        let mut smf = SmfReader::new(FIXTURE).unwrap();
+        assert_eq!(smf.ppq(), 480);
+
+        let mut events = Vec::new();
+        while let Some(event) = smf.next_event().unwrap() {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            [
+                (0, SmfEvent::Tempo(600_000)),
+                (0, SmfEvent::Message(note_on(0, 60, 100))),
+                (96, SmfEvent::Message(note_on(0, 64, 100))),
+                (480, SmfEvent::Message(note_on(0, 60, 0))),
+                (
+                    32,
+                    SmfEvent::Message(MidiMessage::ProgramChange(2.into(), 5.into()))
+                ),
+                (0, SmfEvent::Message(cc(1, 7, 90))),
+                (64, SmfEvent::Message(note_off(0, 64, 0))),
+            ]
+        );
+        assert_eq!(smf.next_event(), Ok(None));
+    }
+
+    #[test]
+    fn should_reject_data_byte_without_running_status() {
+        let mut file = FIXTURE.to_vec();
+        // The delta time of the program change, which now follows the system exclusive event
+        // without a status byte
+        let position = file.iter().position(|byte| *byte == 0xc2).unwrap();
+        file.remove(position);
+
+        let mut smf = SmfReader::new(&file[..]).unwrap();
+        let result = loop {
+            match smf.next_event() {
+                Ok(Some(_)) => {}
+                other => break other,
+            }
+        };
+        assert_eq!(result, Err(SmfError::Invalid));
+    }
+
+    #[test]
+    fn should_reject_unsupported_files() {
+        let mut format_1 = FIXTURE.to_vec();
+        format_1[9] = 1;
+        assert_eq!(
+            SmfReader::new(&format_1[..]).err(),
+            Some(SmfError::Unsupported(1))
+        );
+        assert_eq!(
+            SmfReader::new(&FIXTURE[..20]).err(),
+            Some(SmfError::UnexpectedEof)
+        );
+        assert_eq!(
+            SmfReader::new(&FIXTURE[14..]).err(),
+            Some(SmfError::Invalid)
+        );
+    }
+
+    #[test]
+    fn should_read_written_file() {
+        let messages = [
+            at(0, note_on(3, 60, 100)),
+            at(250, pitch_bend(3, -200)),
+            at(500, note_off(3, 60, 0)),
+            at(
+                70_000,
+                MidiMessage::KeyPressure(3.into(), 61.into(), 20.into()),
+            ),
+        ];
+        let mut smf = SmfWriter::new(File::default(), 96).unwrap();
+        for message in &messages {
+            smf.write(message).unwrap();
+        }
+        let file = smf.finish_and_patch().unwrap();
+
+        let mut smf = SmfReader::new(&file.bytes[..]).unwrap();
+        let mut time = 0;
+        for message in messages {
+            let (delta, read) = smf.next_event().unwrap().unwrap();
+            time += delta * 500 / 96;
+            assert_eq!(
+                (time, read),
+                (message.timestamp, SmfEvent::Message(message.message))
+            );
+        }
+        assert_eq!(smf.next_event(), Ok(None));
+    }
+}
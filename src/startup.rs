@@ -0,0 +1,267 @@
+//! Sending the initial state of a device once the line went quiet
+
+use crate::{MidiError, MidiOut};
+use core::fmt::Debug;
+use embedded_hal_nb::serial;
+use midi_convert::midi_types::MidiMessage;
+
+/// Progress of a `StartupSequencer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartupStatus {
+    /// Waiting for the input to go quiet
+    Waiting,
+    /// Sending the messages, `sent` of them went out
+    Sending { sent: usize },
+    /// All messages were sent
+    Done,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    Waiting,
+    Sending { next: usize, due_ms: u32 },
+    Done,
+}
+
+/// Sends a list of initialization messages once no message was received for a while
+///
+/// Received messages are passed to `on_received`, timing clock and active sensing don't count as
+/// traffic. Once nothing was received for the quiet time and the delay after it, `poll` writes
+/// the messages in order, one every `interval_ms`, and reports `Done` after the last one. The
+/// messages are sent once, until `rearm` starts waiting again. With `set_rearm_on_reset` a
+/// received system reset rearms the sequencer, the device lost its state. Timestamps are in
+/// milliseconds and may wrap around.
+#[derive(Debug, Clone)]
+pub struct StartupSequencer<'a> {
+    messages: &'a [MidiMessage],
+    quiet_ms: u32,
+    delay_ms: u32,
+    interval_ms: u32,
+    rearm_on_reset: bool,
+    /// Time of the last traffic, or of the first poll while waiting
+    quiet_since: Option<u32>,
+    phase: Phase,
+}
+
+impl<'a> StartupSequencer<'a> {
+    /// Sequencer sending `messages` once no message was received for `quiet_ms`
+    pub fn new(messages: &'a [MidiMessage], quiet_ms: u32) -> Self {
+        StartupSequencer {
+            messages,
+            quiet_ms,
+            delay_ms: 0,
+            interval_ms: 0,
+            rearm_on_reset: false,
+            quiet_since: None,
+            phase: Phase::Waiting,
+        }
+    }
+
+    /// Wait `delay_ms` more after the line went quiet
+    pub fn set_delay_ms(&mut self, delay_ms: u32) {
+        self.delay_ms = delay_ms;
+    }
+
+    /// Time between two messages sent, 0 sends them all in one `poll`
+    pub fn set_interval_ms(&mut self, interval_ms: u32) {
+        self.interval_ms = interval_ms;
+    }
+
+    pub fn set_rearm_on_reset(&mut self, rearm: bool) {
+        self.rearm_on_reset = rearm;
+    }
+
+    pub fn status(&self) -> StartupStatus {
+        match self.phase {
+            Phase::Waiting => StartupStatus::Waiting,
+            Phase::Sending { next, .. } => StartupStatus::Sending { sent: next },
+            Phase::Done => StartupStatus::Done,
+        }
+    }
+
+    /// Wait for the line to go quiet and send the messages again from the first one
+    pub fn rearm(&mut self) {
+        self.phase = Phase::Waiting;
+        self.quiet_since = None;
+    }
+
+    /// Record a message received at time `now_ms`
+    pub fn on_received(&mut self, now_ms: u32, message: &MidiMessage) {
+        match message {
+            MidiMessage::TimingClock | MidiMessage::ActiveSensing => return,
+            MidiMessage::Reset if self.rearm_on_reset => self.rearm(),
+            _ => {}
+        }
+        if let Phase::Waiting = self.phase {
+            self.quiet_since = Some(now_ms);
+        }
+    }
+
+    /// Write the messages due at time `now_ms` to `out`
+    ///
+    /// A message that failed to write is written again by the next `poll`.
+    pub fn poll<TX, E>(
+        &mut self,
+        now_ms: u32,
+        out: &mut MidiOut<TX>,
+    ) -> Result<StartupStatus, MidiError<E>>
+    where
+        TX: serial::Write<u8, Error = E>,
+        E: Debug,
+    {
+        if let Phase::Waiting = self.phase {
+            let since = *self.quiet_since.get_or_insert(now_ms);
+            if now_ms.wrapping_sub(since) < self.quiet_ms.saturating_add(self.delay_ms) {
+                return Ok(StartupStatus::Waiting);
+            }
+            self.phase = Phase::Sending {
+                next: 0,
+                due_ms: now_ms,
+            };
+        }
+        while let Phase::Sending { next, due_ms } = self.phase {
+            if now_ms.wrapping_sub(due_ms) > u32::MAX / 2 {
+                break;
+            }
+            let message = match self.messages.get(next) {
+                Some(message) => message,
+                None => {
+                    self.phase = Phase::Done;
+                    break;
+                }
+            };
+            out.write_at(now_ms, message)?;
+            self.phase = if next + 1 == self.messages.len() {
+                Phase::Done
+            } else {
+                Phase::Sending {
+                    next: next + 1,
+                    due_ms: now_ms.wrapping_add(self.interval_ms),
+                }
+            };
+        }
+        Ok(self.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_on, program_change};
+    use crate::test_util::Wire;
+    use std::vec::Vec;
+
+    const INIT: [MidiMessage; 3] = [program_change(0, 5), cc(0, 7, 100), cc(0, 10, 64)];
+
+    /// With running status
+    const INIT_BYTES: [u8; 7] = [0xc0, 5, 0xb0, 7, 100, 10, 64];
+
+    fn sent(status: StartupStatus, len: usize) -> usize {
+        match status {
+            StartupStatus::Waiting => 0,
+            StartupStatus::Sending { sent } => sent,
+            StartupStatus::Done => len,
+        }
+    }
+
+    /// Poll every millisecond from `from_ms` to `to_ms`, returns the times messages were sent
+    fn run(
+        sequencer: &mut StartupSequencer,
+        out: &mut MidiOut<Wire>,
+        traffic: &[(u32, MidiMessage)],
+        from_ms: u32,
+        to_ms: u32,
+    ) -> Vec<u32> {
+        let mut writes = Vec::new();
+        for now in from_ms..to_ms {
+            for (_, message) in traffic.iter().filter(|(time, _)| *time == now) {
+                sequencer.on_received(now, message);
+            }
+            let before = sequencer.status();
+            let after = sequencer.poll(now, out).unwrap();
+            if sent(after, sequencer.messages.len()) > sent(before, sequencer.messages.len()) {
+                writes.push(now);
+            }
+        }
+        writes
+    }
+
+    #[test]
+    fn should_wait_for_quiet_input_then_pace_messages() {
+        let mut sequencer = StartupSequencer::new(&INIT, 100);
+        sequencer.set_delay_ms(50);
+        sequencer.set_interval_ms(10);
+        let mut out = MidiOut::new(Wire::default());
+        // A startup burst until 300 ms, clocks don't count
+        let mut traffic: Vec<(u32, MidiMessage)> =
+            (0..=30).map(|n| (n * 10, note_on(0, 60, 100))).collect();
+        traffic.extend((0..100).map(|n| (300 + n * 5, MidiMessage::TimingClock)));
+
+        let writes = run(&mut sequencer, &mut out, &traffic, 0, 1_000);
+        assert_eq!(writes, [450, 460, 470]);
+        assert_eq!(sequencer.status(), StartupStatus::Done);
+        assert_eq!(out.release().0, INIT_BYTES);
+    }
+
+    #[test]
+    fn should_report_progress_and_send_once() {
+        let mut sequencer = StartupSequencer::new(&INIT, 100);
+        sequencer.set_interval_ms(10);
+        let mut out = MidiOut::new(Wire::default());
+        assert_eq!(sequencer.poll(0, &mut out), Ok(StartupStatus::Waiting));
+        assert_eq!(sequencer.poll(99, &mut out), Ok(StartupStatus::Waiting));
+        assert_eq!(
+            sequencer.poll(100, &mut out),
+            Ok(StartupStatus::Sending { sent: 1 })
+        );
+        assert_eq!(
+            sequencer.poll(109, &mut out),
+            Ok(StartupStatus::Sending { sent: 1 })
+        );
+        assert_eq!(
+            sequencer.poll(110, &mut out),
+            Ok(StartupStatus::Sending { sent: 2 })
+        );
+        // Traffic while sending doesn't stop it
+        sequencer.on_received(115, &note_on(0, 60, 100));
+        assert_eq!(sequencer.poll(120, &mut out), Ok(StartupStatus::Done));
+
+        let writes = run(
+            &mut sequencer,
+            &mut out,
+            &[(500, note_on(0, 1, 1))],
+            121,
+            2_000,
+        );
+        assert_eq!(writes, []);
+        assert_eq!(out.release().0, INIT_BYTES);
+    }
+
+    #[test]
+    fn should_rearm_on_reset() {
+        let mut sequencer = StartupSequencer::new(&INIT, 100);
+        let mut out = MidiOut::new(Wire::default());
+        assert_eq!(run(&mut sequencer, &mut out, &[], 0, 200), [100]);
+
+        // Ignored unless enabled
+        let reset = [(300, MidiMessage::Reset)];
+        assert_eq!(run(&mut sequencer, &mut out, &reset, 200, 600), []);
+
+        sequencer.set_rearm_on_reset(true);
+        let reset = [(600, MidiMessage::Reset), (650, cc(0, 1, 0))];
+        assert_eq!(run(&mut sequencer, &mut out, &reset, 600, 1_000), [750]);
+        assert_eq!(sequencer.status(), StartupStatus::Done);
+
+        sequencer.rearm();
+        assert_eq!(run(&mut sequencer, &mut out, &[], 1_000, 1_200), [1_100]);
+    }
+
+    #[test]
+    fn should_finish_an_empty_list() {
+        let mut sequencer = StartupSequencer::new(&[], 0);
+        let mut out = MidiOut::new(Wire::default());
+        assert_eq!(sequencer.poll(0, &mut out), Ok(StartupStatus::Done));
+        assert_eq!(out.release().0, []);
+    }
+}
@@ -0,0 +1,192 @@
+//! Receive counters that can be updated in an interrupt handler and read elsewhere, enabled with
+//! the `instrumentation` feature
+
+use crate::MidiError;
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+
+/// Plain copy of the counters of `ParserStats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ParserStatsSnapshot {
+    /// Bytes read from the serial port
+    pub bytes: u32,
+    /// Messages parsed
+    pub messages: u32,
+    /// Serial and parse errors
+    pub errors: u32,
+    /// Bytes lost by the serial port because they weren't read in time
+    pub overruns: u32,
+    /// Messages dropped because a buffer was full
+    pub dropped: u32,
+}
+
+/// Receive counters updated by one context, such as the interrupt handler reading the serial
+/// port, and read from any other
+///
+/// The counters are kept in atomics behind a sequence number, so `snapshot` never sees a
+/// partially applied update. Only loads and stores of 32 bit atomics are used, which are also
+/// available on targets without compare and swap like thumbv6. Updates must all come from the same
+/// context. `snapshot` retries while an update is in progress, so it must not be called from a
+/// context that interrupts the updating one.
+///
+/// ```
+/// use embedded_midi::ParserStats;
+///
+/// static STATS: ParserStats = ParserStats::new();
+///
+/// // In the receive interrupt handler
+/// STATS.record(|stats| {
+///     stats.bytes += 3;
+///     stats.messages += 1;
+/// });
+///
+/// // In the main loop
+/// assert_eq!(STATS.snapshot().messages, 1);
+/// ```
+#[derive(Debug, Default)]
+pub struct ParserStats {
+    /// Odd while an update is in progress
+    sequence: AtomicU32,
+    bytes: AtomicU32,
+    messages: AtomicU32,
+    errors: AtomicU32,
+    overruns: AtomicU32,
+    dropped: AtomicU32,
+}
+
+impl ParserStats {
+    pub const fn new() -> Self {
+        ParserStats {
+            sequence: AtomicU32::new(0),
+            bytes: AtomicU32::new(0),
+            messages: AtomicU32::new(0),
+            errors: AtomicU32::new(0),
+            overruns: AtomicU32::new(0),
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    /// Apply `update` to the counters as a single change
+    pub fn record(&self, update: impl FnOnce(&mut ParserStatsSnapshot)) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        let mut stats = self.load();
+        update(&mut stats);
+
+        self.sequence
+            .store(sequence.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.bytes.store(stats.bytes, Ordering::Relaxed);
+        self.messages.store(stats.messages, Ordering::Relaxed);
+        self.errors.store(stats.errors, Ordering::Relaxed);
+        self.overruns.store(stats.overruns, Ordering::Relaxed);
+        self.dropped.store(stats.dropped, Ordering::Relaxed);
+        self.sequence
+            .store(sequence.wrapping_add(2), Ordering::Release);
+    }
+
+    pub fn record_bytes(&self, count: u32) {
+        self.record(|stats| stats.bytes = stats.bytes.wrapping_add(count));
+    }
+
+    pub fn record_message(&self) {
+        self.record(|stats| stats.messages = stats.messages.wrapping_add(1));
+    }
+
+    /// Count an error returned by a midi input, as an overrun, a dropped message or an error
+    pub fn record_error<E>(&self, error: &MidiError<E>) {
+        self.record(|stats| match error {
+            MidiError::Overrun => stats.overruns = stats.overruns.wrapping_add(1),
+            MidiError::BufferFull | MidiError::SysexOverflow => {
+                stats.dropped = stats.dropped.wrapping_add(1)
+            }
+            _ => stats.errors = stats.errors.wrapping_add(1),
+        });
+    }
+
+    /// Consistent copy of the counters
+    pub fn snapshot(&self) -> ParserStatsSnapshot {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before % 2 == 0 {
+                let stats = self.load();
+                fence(Ordering::Acquire);
+                if self.sequence.load(Ordering::Relaxed) == before {
+                    return stats;
+                }
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn load(&self) -> ParserStatsSnapshot {
+        ParserStatsSnapshot {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            messages: self.messages.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            overruns: self.overruns.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::ParseErrorKind;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    #[test]
+    fn should_classify_errors() {
+        let stats = ParserStats::new();
+        stats.record_bytes(5);
+        stats.record_message();
+        stats.record_error(&MidiError::<()>::Overrun);
+        stats.record_error(&MidiError::<()>::SysexOverflow);
+        stats.record_error(&MidiError::<()>::BufferFull);
+        stats.record_error(&MidiError::<()>::Parse(ParseErrorKind::MessageNotFound));
+        stats.record_error(&MidiError::Serial(()));
+
+        assert_eq!(
+            stats.snapshot(),
+            ParserStatsSnapshot {
+                bytes: 5,
+                messages: 1,
+                errors: 2,
+                overruns: 1,
+                dropped: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn should_take_consistent_snapshots_while_updated() {
+        static STATS: ParserStats = ParserStats::new();
+        static DONE: AtomicBool = AtomicBool::new(false);
+        const UPDATES: u32 = 200_000;
+
+        let feeder = thread::spawn(|| {
+            for _ in 0..UPDATES {
+                STATS.record(|stats| {
+                    stats.bytes += 3;
+                    stats.messages += 1;
+                    stats.dropped = stats.messages / 2;
+                });
+            }
+            DONE.store(true, Ordering::Release);
+        });
+
+        let mut last = ParserStatsSnapshot::default();
+        let mut snapshots = 0;
+        while !DONE.load(Ordering::Acquire) || snapshots == 0 {
+            let stats = STATS.snapshot();
+            assert_eq!(stats.bytes, stats.messages * 3);
+            assert_eq!(stats.dropped, stats.messages / 2);
+            assert!(stats.messages >= last.messages);
+            last = stats;
+            snapshots += 1;
+        }
+        feeder.join().unwrap();
+        assert_eq!(STATS.snapshot().messages, UPDATES);
+    }
+}
@@ -0,0 +1,353 @@
+//! Recording played notes into sequencer steps
+
+use crate::{FullTable, TICKS_PER_MIDI_BEAT};
+use midi_convert::midi_types::{Channel, MidiMessage, Note, Value7};
+
+/// How recorded notes combine with notes already in a step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordMode {
+    /// Recorded notes are added to the notes in the step
+    Overdub,
+    /// The first note recorded into a step in a pass replaces the notes in the step
+    Replace,
+}
+
+/// A recorded note
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepNote {
+    pub channel: Channel,
+    pub note: Note,
+    pub velocity: Value7,
+    /// Length in midi clock ticks, counted up to the note off
+    pub length: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    note: StepNote,
+    /// Tick the note was played at while it is still held
+    held_since: Option<u32>,
+}
+
+/// Records notes played while the transport runs into `STEPS` steps of up to `POLY` notes
+///
+/// The transport is driven by start, continue, stop and timing clock messages. Note ons are
+/// quantized to the nearest step, the pattern wraps around after the last step. Steps are 6 clock
+/// ticks, a sixteenth note, unless set otherwise.
+#[derive(Debug, Clone)]
+pub struct StepRecorder<const STEPS: usize, const POLY: usize> {
+    steps: [[Option<Slot>; POLY]; STEPS],
+    /// Steps recorded into since the quantization window of the step was entered
+    recorded: [bool; STEPS],
+    mode: RecordMode,
+    ticks_per_step: u32,
+    playing: bool,
+    /// The next clock is the first after start
+    starting: bool,
+    /// Ticks into the pattern
+    tick: u32,
+    /// Ticks since start, for measuring note lengths
+    elapsed: u32,
+}
+
+impl<const STEPS: usize, const POLY: usize> Default for StepRecorder<STEPS, POLY> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const STEPS: usize, const POLY: usize> StepRecorder<STEPS, POLY> {
+    /// Empty recorder in overdub mode
+    pub fn new() -> Self {
+        StepRecorder {
+            steps: [[None; POLY]; STEPS],
+            recorded: [false; STEPS],
+            mode: RecordMode::Overdub,
+            ticks_per_step: TICKS_PER_MIDI_BEAT,
+            playing: false,
+            starting: false,
+            tick: 0,
+            elapsed: 0,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: RecordMode) {
+        self.mode = mode;
+    }
+
+    /// Set the step length in midi clock ticks, at least 1
+    pub fn set_ticks_per_step(&mut self, ticks: u32) {
+        self.ticks_per_step = ticks.max(1);
+        self.tick %= self.pattern_ticks();
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Step the transport is in, rounded down
+    pub fn current_step(&self) -> usize {
+        (self.tick / self.ticks_per_step) as usize
+    }
+
+    /// Handle transport messages and record notes
+    ///
+    /// Notes are only recorded while the transport runs. Returns `FullTable` when a note on falls
+    /// into a step that already holds `POLY` notes, the note is not recorded.
+    pub fn receive(&mut self, message: &MidiMessage) -> Result<(), FullTable> {
+        match *message {
+            MidiMessage::Start => {
+                self.release_all();
+                self.playing = true;
+                self.starting = true;
+                self.tick = 0;
+                self.recorded = [false; STEPS];
+            }
+            MidiMessage::Continue => self.playing = true,
+            MidiMessage::Stop => {
+                self.release_all();
+                self.playing = false;
+            }
+            MidiMessage::TimingClock if self.playing => self.clock(),
+            MidiMessage::NoteOn(channel, note, velocity)
+                if u8::from(velocity) > 0 && self.playing =>
+            {
+                self.release(channel, note);
+                return self.record(channel, note, velocity);
+            }
+            MidiMessage::NoteOn(channel, note, _) | MidiMessage::NoteOff(channel, note, _) => {
+                self.release(channel, note)
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Notes recorded in `step`
+    pub fn notes(&self, step: usize) -> impl Iterator<Item = StepNote> + '_ {
+        self.steps
+            .get(step)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|slot| slot.note)
+    }
+
+    /// Note ons for the notes recorded in `step`, note offs are due after the note lengths
+    pub fn play(&self, step: usize) -> impl Iterator<Item = MidiMessage> + '_ {
+        self.notes(step)
+            .map(|note| MidiMessage::NoteOn(note.channel, note.note, note.velocity))
+    }
+
+    pub fn clear_step(&mut self, step: usize) {
+        if let Some(slots) = self.steps.get_mut(step) {
+            *slots = [None; POLY];
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.steps = [[None; POLY]; STEPS];
+    }
+
+    fn pattern_ticks(&self) -> u32 {
+        self.ticks_per_step * STEPS.max(1) as u32
+    }
+
+    fn clock(&mut self) {
+        if self.starting {
+            self.starting = false;
+        } else {
+            self.tick = (self.tick + 1) % self.pattern_ticks();
+            self.elapsed = self.elapsed.wrapping_add(1);
+        }
+
+        // Entering the quantization window of a step starts a new pass over it
+        let window = self.tick + self.ticks_per_step / 2;
+        if window % self.ticks_per_step == 0 {
+            let step = (window / self.ticks_per_step) as usize % STEPS.max(1);
+            if let Some(recorded) = self.recorded.get_mut(step) {
+                *recorded = false;
+            }
+        }
+    }
+
+    fn record(&mut self, channel: Channel, note: Note, velocity: Value7) -> Result<(), FullTable> {
+        if STEPS == 0 {
+            return Err(FullTable);
+        }
+        let step = ((self.tick + self.ticks_per_step / 2) / self.ticks_per_step) as usize % STEPS;
+
+        if self.mode == RecordMode::Replace && !self.recorded[step] {
+            self.clear_step(step);
+        }
+        let slot = self.steps[step]
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(FullTable)?;
+        *slot = Some(Slot {
+            note: StepNote {
+                channel,
+                note,
+                velocity,
+                length: 0,
+            },
+            held_since: Some(self.elapsed),
+        });
+        self.recorded[step] = true;
+        Ok(())
+    }
+
+    fn release(&mut self, channel: Channel, note: Note) {
+        let elapsed = self.elapsed;
+        let slots = self.steps.iter_mut().flatten().flatten();
+        for slot in slots.filter(|slot| slot.note.channel == channel && slot.note.note == note) {
+            if let Some(since) = slot.held_since.take() {
+                slot.note.length = elapsed.wrapping_sub(since).min(u16::MAX as u32) as u16;
+            }
+        }
+    }
+
+    fn release_all(&mut self) {
+        let elapsed = self.elapsed;
+        for slot in self.steps.iter_mut().flatten().flatten() {
+            if let Some(since) = slot.held_since.take() {
+                slot.note.length = elapsed.wrapping_sub(since).min(u16::MAX as u32) as u16;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{note_off, note_on};
+    use std::vec::Vec;
+
+    /// Start the transport and run `ticks` clocks, playing the messages due at each tick
+    fn run<const STEPS: usize, const POLY: usize>(
+        recorder: &mut StepRecorder<STEPS, POLY>,
+        ticks: u32,
+        events: &[(u32, MidiMessage)],
+    ) {
+        recorder.receive(&MidiMessage::Start).unwrap();
+        for tick in 0..ticks {
+            recorder.receive(&MidiMessage::TimingClock).unwrap();
+            for (_, message) in events.iter().filter(|(at, _)| *at == tick) {
+                recorder.receive(message).unwrap();
+            }
+        }
+    }
+
+    fn step_notes<const STEPS: usize, const POLY: usize>(
+        recorder: &StepRecorder<STEPS, POLY>,
+        step: usize,
+    ) -> Vec<(u8, u8, u16)> {
+        recorder
+            .notes(step)
+            .map(|note| (u8::from(note.note), u8::from(note.velocity), note.length))
+            .collect()
+    }
+
+    #[test]
+    fn should_quantize_notes_to_nearest_step() {
+        let mut recorder = StepRecorder::<16, 4>::new();
+        run(
+            &mut recorder,
+            96,
+            &[
+                (0, note_on(0, 36, 100)),
+                (5, note_off(0, 36, 0)),
+                (8, note_on(0, 38, 90)),
+                (10, note_on(0, 42, 80)),
+                (11, note_off(0, 38, 0)),
+                (13, note_on(0, 42, 0)),
+                (94, note_on(0, 46, 70)),
+                (95, note_off(0, 46, 0)),
+            ],
+        );
+
+        assert_eq!(step_notes(&recorder, 0), [(36, 100, 5), (46, 70, 1)]);
+        assert_eq!(step_notes(&recorder, 1), [(38, 90, 3)]);
+        assert_eq!(step_notes(&recorder, 2), [(42, 80, 3)]);
+        assert!(step_notes(&recorder, 15).is_empty());
+        assert_eq!(
+            recorder.play(0).collect::<Vec<_>>(),
+            [note_on(0, 36, 100), note_on(0, 46, 70)]
+        );
+    }
+
+    #[test]
+    fn should_not_record_while_stopped() {
+        let mut recorder = StepRecorder::<16, 4>::new();
+        recorder.receive(&note_on(0, 36, 100)).unwrap();
+        assert!(step_notes(&recorder, 0).is_empty());
+
+        run(&mut recorder, 3, &[(1, note_on(0, 36, 100))]);
+        recorder.receive(&MidiMessage::Stop).unwrap();
+        recorder.receive(&note_on(0, 38, 100)).unwrap();
+        assert_eq!(step_notes(&recorder, 0), [(36, 100, 1)]);
+    }
+
+    #[test]
+    fn should_refuse_notes_beyond_polyphony() {
+        let mut recorder = StepRecorder::<16, 2>::new();
+        run(&mut recorder, 1, &[]);
+
+        assert_eq!(recorder.receive(&note_on(0, 36, 100)), Ok(()));
+        assert_eq!(recorder.receive(&note_on(0, 38, 100)), Ok(()));
+        assert_eq!(recorder.receive(&note_on(0, 42, 100)), Err(FullTable));
+        assert_eq!(step_notes(&recorder, 0).len(), 2);
+    }
+
+    #[test]
+    fn should_overdub_or_replace_on_next_pass() {
+        let mut overdub = StepRecorder::<4, 4>::new();
+        let mut replace = StepRecorder::<4, 4>::new();
+        replace.set_mode(RecordMode::Replace);
+
+        for recorder in [&mut overdub, &mut replace] {
+            run(
+                recorder,
+                24,
+                &[(0, note_on(0, 36, 100)), (1, note_on(0, 38, 100))],
+            );
+            run(
+                recorder,
+                24,
+                &[(0, note_on(0, 40, 100)), (1, note_on(0, 41, 100))],
+            );
+        }
+
+        assert_eq!(
+            overdub
+                .notes(0)
+                .map(|note| u8::from(note.note))
+                .collect::<Vec<_>>(),
+            [36, 38, 40, 41]
+        );
+        assert_eq!(
+            replace
+                .notes(0)
+                .map(|note| u8::from(note.note))
+                .collect::<Vec<_>>(),
+            [40, 41]
+        );
+    }
+
+    #[test]
+    fn should_clear_steps() {
+        let mut recorder = StepRecorder::<4, 4>::new();
+        run(
+            &mut recorder,
+            24,
+            &[(0, note_on(0, 36, 100)), (6, note_on(0, 38, 100))],
+        );
+
+        recorder.clear_step(0);
+        assert!(step_notes(&recorder, 0).is_empty());
+        assert_eq!(step_notes(&recorder, 1).len(), 1);
+        recorder.clear();
+        assert!(step_notes(&recorder, 1).is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+//! Parsing and rendering midi for byte sources and sinks that are not serial ports
+
+use crate::wire::{Receiver, Transmitter};
+use crate::{family, ParseEvent, RenderedMessage, TooSmall};
+use core::task::Poll;
+use midi_convert::midi_types::MidiMessage;
+
+/// Midi input and output for bytes received and sent by other means than a serial port, like
+/// shared memory or a USB endpoint
+///
+/// Received bytes are parsed like `MidiIn` does and messages are rendered with running status like
+/// `MidiOut` does.
+#[derive(Debug)]
+pub struct MidiStream {
+    receiver: Receiver<{ family::ALL }>,
+    transmitter: Transmitter,
+}
+
+impl Default for MidiStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MidiStream {
+    pub fn new() -> Self {
+        MidiStream {
+            receiver: Receiver::new(),
+            transmitter: Transmitter::default(),
+        }
+    }
+
+    /// See `MidiIn::set_strict_system_common`
+    pub fn set_strict_system_common(&mut self, strict: bool) {
+        self.receiver.strict_system_common = strict;
+    }
+
+    /// See `MidiOut::set_running_status`
+    pub fn set_running_status(&mut self, enabled: bool) {
+        self.transmitter.running_status.disabled = !enabled;
+        self.transmitter.running_status.status = None;
+    }
+
+    /// Parse a received byte, returns the message it completes
+    pub fn push_byte(&mut self, byte: u8) -> Option<MidiMessage> {
+        match self.receiver.push(byte)? {
+            ParseEvent::Message(message) => Some(message),
+            ParseEvent::UndefinedStatus(_) => None,
+        }
+    }
+
+    /// Parse received bytes, calling `emit` with every message they complete
+    pub fn push_slice(&mut self, bytes: &[u8], emit: &mut dyn FnMut(MidiMessage)) {
+        for byte in bytes {
+            if let Some(message) = self.push_byte(*byte) {
+                emit(message);
+            }
+        }
+    }
+
+    /// Render a message into `buffer` for sending, returns the number of bytes written
+    ///
+    /// The status byte is left out when running status allows it, so the rendered bytes must be
+    /// sent in the order they were rendered. Nothing is written when the message doesn't fit.
+    pub fn render_to(
+        &mut self,
+        message: &MidiMessage,
+        buffer: &mut [u8],
+    ) -> Result<usize, TooSmall> {
+        let len = self
+            .transmitter
+            .load(RenderedMessage::from(message).as_bytes());
+        let buffer = match buffer.get_mut(..len) {
+            Some(buffer) => buffer,
+            None => {
+                self.transmitter.clear();
+                return Err(TooSmall);
+            }
+        };
+        for slot in buffer {
+            if let Poll::Ready(byte) = self.transmitter.pull() {
+                *slot = byte;
+            }
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_on};
+    use std::vec::Vec;
+
+    #[test]
+    fn should_parse_byte_slices() {
+        let mut stream = MidiStream::new();
+        let mut received = Vec::new();
+        stream.push_slice(&[0x92, 0x76], &mut |message| received.push(message));
+        stream.push_slice(&[0x34, 0xf8, 0x77, 0x35], &mut |message| {
+            received.push(message)
+        });
+
+        assert_eq!(
+            received,
+            [
+                note_on(2, 0x76, 0x34),
+                MidiMessage::TimingClock,
+                note_on(2, 0x77, 0x35)
+            ]
+        );
+        assert_eq!(stream.push_byte(0xfa), Some(MidiMessage::Start));
+    }
+
+    #[test]
+    fn should_render_with_running_status() {
+        let mut stream = MidiStream::new();
+        let mut buffer = [0; 8];
+        let mut len = 0;
+        for message in [note_on(2, 0x76, 0x34), note_on(2, 0x33, 0x65), cc(2, 7, 1)] {
+            len += stream.render_to(&message, &mut buffer[len..]).unwrap();
+        }
+
+        assert_eq!(buffer, [0x92, 0x76, 0x34, 0x33, 0x65, 0xb2, 0x07, 0x01]);
+        assert_eq!(
+            stream.render_to(&cc(2, 7, 2), &mut buffer[..1]),
+            Err(TooSmall)
+        );
+
+        stream.set_running_status(false);
+        assert_eq!(stream.render_to(&cc(2, 7, 2), &mut buffer), Ok(3));
+    }
+
+    #[test]
+    fn should_parse_rendered_bytes() {
+        let messages = [note_on(0, 60, 100), cc(0, 1, 64), MidiMessage::Stop];
+        let mut stream = MidiStream::new();
+        let mut buffer = [0; 16];
+        let mut len = 0;
+        for message in messages.iter() {
+            len += stream.render_to(message, &mut buffer[len..]).unwrap();
+        }
+
+        let mut received = Vec::new();
+        stream.push_slice(&buffer[..len], &mut |message| received.push(message));
+        assert_eq!(received, messages);
+    }
+}
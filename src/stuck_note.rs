@@ -0,0 +1,206 @@
+//! Releasing notes whose note off got lost
+
+use crate::NoteTracker;
+use midi_convert::midi_types::{Channel, MidiMessage, Note};
+
+/// A held note and the time its note on arrived
+#[derive(Debug, Clone, Copy)]
+struct HeldNote {
+    channel: Channel,
+    note: Note,
+    since: u32,
+}
+
+/// Sends a note off for notes held longer than a maximum duration
+///
+/// Messages pass through `process`, which records when each note started. `poll` releases the
+/// notes held for `max_ms` or longer. The note off that arrives for a released note later on is
+/// dropped, so the note is not released twice. Notes on the channels in the exempt mask, bit 0
+/// for channel 1, are never released. Up to `N` notes are timed, notes starting while `N` notes
+/// are held are passed on without a time limit. Timestamps are in milliseconds and may wrap
+/// around.
+#[derive(Debug, Clone)]
+pub struct StuckNoteGuard<const N: usize = 32> {
+    max_ms: u32,
+    exempt: u16,
+    held: [Option<HeldNote>; N],
+    /// Notes released by the guard whose note off did not arrive yet
+    released: NoteTracker,
+}
+
+impl<const N: usize> StuckNoteGuard<N> {
+    pub fn new(max_ms: u32) -> Self {
+        StuckNoteGuard {
+            max_ms,
+            exempt: 0,
+            held: [None; N],
+            released: NoteTracker::new(),
+        }
+    }
+
+    pub fn set_max_ms(&mut self, max_ms: u32) {
+        self.max_ms = max_ms;
+    }
+
+    /// Never release notes on the channels in `mask`, bit 0 is channel 1
+    pub fn set_exempt_channels(&mut self, mask: u16) {
+        self.exempt = mask;
+    }
+
+    /// Process a message received at time `now_ms`, returns `None` for the late note off of a
+    /// released note
+    pub fn process(&mut self, now_ms: u32, message: MidiMessage) -> Option<MidiMessage> {
+        let (channel, note, on) = match message {
+            MidiMessage::NoteOn(channel, note, velocity) => (channel, note, u8::from(velocity) > 0),
+            MidiMessage::NoteOff(channel, note, _) => (channel, note, false),
+            _ => return Some(message),
+        };
+        if self.exempt & (1 << u8::from(channel)) != 0 {
+            return Some(message);
+        }
+
+        let index = self.position(channel, note);
+        if on {
+            self.released.note_off(channel, note);
+            let slot = index.or_else(|| self.held.iter().position(Option::is_none));
+            if let Some(slot) = slot {
+                self.held[slot] = Some(HeldNote {
+                    channel,
+                    note,
+                    since: now_ms,
+                });
+            }
+        } else if self.released.is_held(channel, note) {
+            self.released.note_off(channel, note);
+            return None;
+        } else if let Some(index) = index {
+            self.held[index] = None;
+        }
+        Some(message)
+    }
+
+    /// Call `emit` with a note off for every note held for the maximum duration at time `now_ms`
+    pub fn poll(&mut self, now_ms: u32, emit: &mut dyn FnMut(MidiMessage)) {
+        for slot in self.held.iter_mut() {
+            let held = match *slot {
+                Some(held) if now_ms.wrapping_sub(held.since) >= self.max_ms => held,
+                _ => continue,
+            };
+            *slot = None;
+            self.released.note_on(held.channel, held.note);
+            emit(MidiMessage::NoteOff(held.channel, held.note, 0.into()));
+        }
+    }
+
+    /// Number of notes timed by the guard
+    pub fn held_count(&self) -> usize {
+        self.held.iter().flatten().count()
+    }
+
+    /// Forget all held and released notes
+    pub fn reset(&mut self) {
+        self.held = [None; N];
+        self.released = NoteTracker::new();
+    }
+
+    fn position(&self, channel: Channel, note: Note) -> Option<usize> {
+        self.held
+            .iter()
+            .position(|held| held.is_some_and(|held| held.channel == channel && held.note == note))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_off, note_on};
+    use std::vec::Vec;
+
+    fn poll<const N: usize>(guard: &mut StuckNoteGuard<N>, now_ms: u32) -> Vec<MidiMessage> {
+        let mut output = Vec::new();
+        guard.poll(now_ms, &mut |message| output.push(message));
+        output
+    }
+
+    #[test]
+    fn should_release_notes_held_too_long() {
+        let mut guard = StuckNoteGuard::<4>::new(30_000);
+        assert_eq!(
+            guard.process(0, note_on(0, 60, 100)),
+            Some(note_on(0, 60, 100))
+        );
+        guard.process(1_000, note_on(0, 64, 100));
+        guard.process(2_000, note_on(0, 67, 100));
+        guard.process(3_000, note_off(0, 67, 0));
+
+        assert_eq!(poll(&mut guard, 29_999), []);
+        assert_eq!(poll(&mut guard, 30_000), [note_off(0, 60, 0)]);
+        assert_eq!(poll(&mut guard, 31_000), [note_off(0, 64, 0)]);
+        assert_eq!(poll(&mut guard, 100_000), []);
+        assert_eq!(guard.held_count(), 0);
+    }
+
+    #[test]
+    fn should_restart_time_on_retrigger() {
+        let mut guard = StuckNoteGuard::<4>::new(100);
+        guard.process(u32::MAX - 50, note_on(2, 60, 100));
+        guard.process(20, note_on(2, 60, 90));
+
+        assert_eq!(poll(&mut guard, 100), []);
+        assert_eq!(poll(&mut guard, 120), [note_off(2, 60, 0)]);
+    }
+
+    #[test]
+    fn should_not_release_notes_on_exempt_channels() {
+        let mut guard = StuckNoteGuard::<4>::new(100);
+        guard.set_exempt_channels(1 << 15);
+        guard.process(0, note_on(15, 36, 100));
+        guard.process(0, note_on(14, 36, 100));
+
+        assert_eq!(poll(&mut guard, 1_000), [note_off(14, 36, 0)]);
+        assert_eq!(
+            guard.process(2_000, note_off(15, 36, 0)),
+            Some(note_off(15, 36, 0))
+        );
+    }
+
+    #[test]
+    fn should_drop_late_note_off_of_released_note() {
+        let mut guard = StuckNoteGuard::<4>::new(100);
+        guard.process(0, note_on(0, 60, 100));
+        guard.process(0, note_on(0, 62, 100));
+        assert_eq!(poll(&mut guard, 100).len(), 2);
+
+        assert_eq!(guard.process(150, note_off(0, 60, 0)), None);
+        assert_eq!(guard.process(150, note_on(0, 62, 0)), None);
+        // Only the first note off after the release is dropped
+        assert_eq!(
+            guard.process(160, note_off(0, 60, 0)),
+            Some(note_off(0, 60, 0))
+        );
+
+        // A new note on clears the release, its note off is passed on
+        poll(&mut guard, 300);
+        guard.process(300, note_on(1, 48, 100));
+        assert_eq!(poll(&mut guard, 400), [note_off(1, 48, 0)]);
+        guard.process(410, note_on(1, 48, 100));
+        assert_eq!(
+            guard.process(420, note_off(1, 48, 0)),
+            Some(note_off(1, 48, 0))
+        );
+        assert_eq!(guard.process(420, cc(1, 64, 0)), Some(cc(1, 64, 0)));
+    }
+
+    #[test]
+    fn should_pass_on_notes_that_do_not_fit() {
+        let mut guard = StuckNoteGuard::<1>::new(100);
+        guard.process(0, note_on(0, 60, 100));
+        assert_eq!(
+            guard.process(0, note_on(0, 61, 100)),
+            Some(note_on(0, 61, 100))
+        );
+
+        assert_eq!(poll(&mut guard, 100), [note_off(0, 60, 0)]);
+    }
+}
@@ -0,0 +1,300 @@
+//! Streaming of system exclusive messages in bounded chunks
+
+use crate::diag::log_warn;
+
+/// Receiver of the chunks of streamed system exclusive messages
+pub trait SysexHandler {
+    /// Data bytes of a message, without the 0xf0 and 0xf7 framing
+    ///
+    /// `first` is set for the first chunk of a message and `last` for the chunk ended by 0xf7. A
+    /// message without data bytes is a single empty chunk with both set.
+    fn on_sysex_chunk(&mut self, chunk: &[u8], first: bool, last: bool);
+
+    /// The message whose chunks were handed out did not end, chunks of it should be discarded
+    fn on_sysex_abort(&mut self);
+}
+
+/// Splits system exclusive messages into chunks of up to `CHUNK` data bytes, so large dumps can be
+/// written to flash without holding the whole dump
+///
+/// A full chunk is only handed out when the next data byte arrives, so the last chunk of a
+/// message is never empty unless the message is. Real time bytes are skipped, any other status
+/// byte but 0xf7 aborts the message, as do an overrun and, if set, a timeout between bytes.
+///
+/// `CHUNK` must be at least 1, which is checked at compile time.
+#[derive(Debug)]
+pub struct SysexStream<const CHUNK: usize = 32> {
+    buffer: [u8; CHUNK],
+    len: usize,
+    receiving: bool,
+    first: bool,
+    timeout_ms: Option<u32>,
+    last_byte_ms: u32,
+}
+
+impl<const CHUNK: usize> Default for SysexStream<CHUNK> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const CHUNK: usize> SysexStream<CHUNK> {
+    const HAS_CHUNK: () = assert!(CHUNK > 0, "chunks must hold at least one byte");
+
+    pub fn new() -> Self {
+        let () = Self::HAS_CHUNK;
+        SysexStream {
+            buffer: [0; CHUNK],
+            len: 0,
+            receiving: false,
+            first: false,
+            timeout_ms: None,
+            last_byte_ms: 0,
+        }
+    }
+
+    /// Abort a message when no byte arrives for `timeout_ms`, no timeout by default
+    pub fn set_timeout(&mut self, timeout_ms: Option<u32>) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    /// A message is being received
+    pub fn is_receiving(&self) -> bool {
+        self.receiving
+    }
+
+    /// Handle a received byte, other than system exclusive bytes they are ignored
+    pub fn feed(&mut self, now_ms: u32, byte: u8, handler: &mut impl SysexHandler) {
+        self.tick(now_ms, handler);
+        self.last_byte_ms = now_ms;
+
+        match byte {
+            0xf8..=0xff => {}
+            0xf0 => {
+                self.abort(handler);
+                self.receiving = true;
+                self.first = true;
+            }
+            0xf7 if self.receiving => {
+                handler.on_sysex_chunk(&self.buffer[..self.len], self.first, true);
+                self.len = 0;
+                self.receiving = false;
+            }
+            0x80..=0xf7 => self.abort(handler),
+            _ if self.receiving => {
+                if self.len == CHUNK {
+                    handler.on_sysex_chunk(&self.buffer, self.first, false);
+                    self.first = false;
+                    self.len = 0;
+                }
+                if let Some(slot) = self.buffer.get_mut(self.len) {
+                    *slot = byte;
+                    self.len += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Abort the message being received if the timeout passed since its last byte
+    pub fn tick(&mut self, now_ms: u32, handler: &mut impl SysexHandler) {
+        let expired = self
+            .timeout_ms
+            .is_some_and(|timeout| now_ms.wrapping_sub(self.last_byte_ms) > timeout);
+        if expired {
+            self.abort(handler);
+        }
+    }
+
+    /// Abort the message being received, `on_sysex_abort` is only called if chunks of it were
+    /// handed out
+    pub fn abort(&mut self, handler: &mut impl SysexHandler) {
+        if self.receiving && !self.first {
+            log_warn!("sysex: message aborted after its first chunk");
+            handler.on_sysex_abort();
+        }
+        self.len = 0;
+        self.receiving = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::MidiIn;
+    use embedded_hal_mock::eh1::serial;
+    use embedded_hal_nb::serial::ErrorKind;
+    use midi_convert::midi_types::MidiMessage;
+    use proptest::prelude::*;
+    use std::vec::Vec;
+
+    /// Reassembles streamed messages like a flash writer that rolls back aborted writes
+    #[derive(Debug, Default)]
+    struct Reassembler {
+        current: Vec<u8>,
+        complete: Vec<Vec<u8>>,
+        aborts: usize,
+        largest_chunk: usize,
+    }
+
+    impl SysexHandler for Reassembler {
+        fn on_sysex_chunk(&mut self, chunk: &[u8], first: bool, last: bool) {
+            assert_eq!(first, self.current.is_empty());
+            self.largest_chunk = self.largest_chunk.max(chunk.len());
+            self.current.extend_from_slice(chunk);
+            if last {
+                self.complete.push(core::mem::take(&mut self.current));
+            }
+        }
+
+        fn on_sysex_abort(&mut self) {
+            assert!(!self.current.is_empty());
+            self.current.clear();
+            self.aborts += 1;
+        }
+    }
+
+    fn dump(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 128) as u8).collect()
+    }
+
+    fn framed(data: &[u8]) -> Vec<u8> {
+        let mut bytes = std::vec![0xf0];
+        bytes.extend_from_slice(data);
+        bytes.push(0xf7);
+        bytes
+    }
+
+    fn feed_all<const CHUNK: usize>(bytes: &[u8]) -> Reassembler {
+        let mut stream = SysexStream::<CHUNK>::new();
+        let mut handler = Reassembler::default();
+        for byte in bytes {
+            stream.feed(0, *byte, &mut handler);
+        }
+        handler
+    }
+
+    #[test]
+    fn should_stream_in_bounded_chunks() {
+        let data = dump(100);
+        let handler = feed_all::<32>(&framed(&data));
+
+        assert_eq!(handler.complete, [data]);
+        assert_eq!(handler.largest_chunk, 32);
+        assert_eq!(handler.aborts, 0);
+    }
+
+    #[test]
+    fn should_end_with_non_empty_chunk_at_chunk_boundary() {
+        #[derive(Default)]
+        struct Chunks(Vec<(usize, bool, bool)>);
+
+        impl SysexHandler for Chunks {
+            fn on_sysex_chunk(&mut self, chunk: &[u8], first: bool, last: bool) {
+                self.0.push((chunk.len(), first, last));
+            }
+
+            fn on_sysex_abort(&mut self) {}
+        }
+
+        let mut stream = SysexStream::<4>::new();
+        let mut chunks = Chunks::default();
+        for byte in framed(&dump(8)).into_iter().chain(framed(&[])) {
+            stream.feed(0, byte, &mut chunks);
+        }
+
+        assert_eq!(
+            chunks.0,
+            [(4, true, false), (4, false, true), (0, true, true)]
+        );
+    }
+
+    #[test]
+    fn should_abort_on_interrupting_status() {
+        let mut bytes = framed(&dump(50));
+        bytes.insert(40, 0x90);
+        bytes.extend(framed(&dump(10)));
+        let handler = feed_all::<32>(&bytes);
+
+        assert_eq!(handler.aborts, 1);
+        assert_eq!(handler.complete, [dump(10)]);
+    }
+
+    #[test]
+    fn should_not_report_abort_before_first_chunk() {
+        let handler = feed_all::<32>(&[0xf0, 0x01, 0x02, 0xf0, 0x03, 0xf7, 0xf0, 0x04, 0xf4]);
+
+        assert_eq!(handler.aborts, 0);
+        assert_eq!(handler.complete, [[0x03]]);
+    }
+
+    #[test]
+    fn should_abort_after_timeout() {
+        let mut stream = SysexStream::<4>::new();
+        stream.set_timeout(Some(100));
+        let mut handler = Reassembler::default();
+        for (now, byte) in framed(&dump(6)).into_iter().enumerate().take(7) {
+            stream.feed(now as u32, byte, &mut handler);
+        }
+
+        stream.tick(106, &mut handler);
+        assert!(stream.is_receiving());
+        stream.tick(107, &mut handler);
+        assert!(!stream.is_receiving());
+        assert_eq!(handler.aborts, 1);
+
+        // The end of the message arriving late is ignored
+        stream.feed(108, 0xf7, &mut handler);
+        assert!(handler.complete.is_empty());
+    }
+
+    #[test]
+    fn should_stream_through_midi_in_and_abort_on_overrun() {
+        let mut expectations: Vec<_> = framed(&dump(40))
+            .into_iter()
+            .take(37)
+            .map(serial::Transaction::read)
+            .collect();
+        expectations.push(serial::Transaction::read_error(nb::Error::Other(
+            ErrorKind::Overrun,
+        )));
+        expectations.extend(
+            [0xf0, 0x01, 0xf8, 0x02, 0xf7]
+                .iter()
+                .map(|byte| serial::Transaction::read(*byte)),
+        );
+        let mut midi_in = MidiIn::new(serial::Mock::new(&expectations));
+        let mut stream = SysexStream::<16>::new();
+        let mut handler = Reassembler::default();
+
+        let messages: Vec<_> = expectations
+            .iter()
+            .filter_map(|_| midi_in.read_sysex(0, &mut stream, &mut handler).ok())
+            .collect();
+
+        assert_eq!(messages, [MidiMessage::TimingClock]);
+        assert_eq!(handler.aborts, 1);
+        assert_eq!(handler.complete, [[0x01, 0x02]]);
+        midi_in.rx.done();
+    }
+
+    proptest! {
+        #[test]
+        fn should_reassemble_dump_with_realtime_bytes(
+            len in 1024usize..4096,
+            clocks in prop::collection::vec(any::<prop::sample::Index>(), 0..32),
+        ) {
+            let data = dump(len);
+            let mut bytes = framed(&data);
+            for clock in clocks {
+                bytes.insert(1 + clock.index(len), 0xf8);
+            }
+
+            for handler in [feed_all::<1>(&bytes), feed_all::<7>(&bytes), feed_all::<32>(&bytes)] {
+                prop_assert_eq!(&handler.complete, core::slice::from_ref(&data));
+                prop_assert_eq!(handler.aborts, 0);
+            }
+        }
+    }
+}
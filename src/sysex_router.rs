@@ -0,0 +1,422 @@
+//! Dispatching received system exclusive messages by manufacturer id
+
+use crate::{FullTable, SysexHandler};
+use core::fmt::{self, Debug, Formatter};
+
+/// Longest sub-id prefix a route can match
+pub const MAX_SUB_ID_LEN: usize = 4;
+
+/// Bytes of a manufacturer id, a device id and the longest sub-id prefix
+const HEADER_LEN: usize = 3 + 1 + MAX_SUB_ID_LEN;
+
+/// Manufacturer id starting a system exclusive message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManufacturerId {
+    /// One byte id
+    Short(u8),
+    /// Three byte id, 0x00 followed by these two bytes
+    Extended(u8, u8),
+}
+
+impl ManufacturerId {
+    /// Id reserved for non commercial use, like in house protocols
+    pub const NON_COMMERCIAL: ManufacturerId = ManufacturerId::Short(0x7d);
+    pub const UNIVERSAL_NON_REALTIME: ManufacturerId = ManufacturerId::Short(0x7e);
+    pub const UNIVERSAL_REALTIME: ManufacturerId = ManufacturerId::Short(0x7f);
+
+    /// Id at the start of the data bytes of a message
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        match data {
+            [0, first, second, ..] => Some(ManufacturerId::Extended(*first, *second)),
+            [0, ..] | [] => None,
+            [id, ..] => Some(ManufacturerId::Short(*id)),
+        }
+    }
+
+    /// Universal messages have a device id after the manufacturer id
+    pub fn is_universal(&self) -> bool {
+        *self == Self::UNIVERSAL_NON_REALTIME || *self == Self::UNIVERSAL_REALTIME
+    }
+
+    fn matches(&self, header: &[u8]) -> bool {
+        match self {
+            ManufacturerId::Short(id) => header.first() == Some(id),
+            ManufacturerId::Extended(first, second) => {
+                header.get(..3) == Some(&[0, *first, *second])
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            ManufacturerId::Short(_) => 1,
+            ManufacturerId::Extended(..) => 3,
+        }
+    }
+}
+
+struct Route<'a> {
+    id: ManufacturerId,
+    sub_ids: &'a [u8],
+    handler: &'a mut dyn SysexHandler,
+}
+
+impl Route<'_> {
+    /// Bytes to receive before the route can be matched
+    fn header_len(&self) -> usize {
+        self.id.len() + usize::from(self.id.is_universal()) + self.sub_ids.len()
+    }
+
+    fn matches(&self, header: &[u8]) -> bool {
+        let start = self.id.len() + usize::from(self.id.is_universal());
+        self.id.matches(header) && header.get(start..self.header_len()) == Some(self.sub_ids)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    /// Collecting the header of a message
+    Pending,
+    Route(usize),
+    Default,
+    /// Not matched and no default handler, or no message
+    Dropped,
+}
+
+/// Hands the chunks of received system exclusive messages to the handler registered for their
+/// manufacturer id
+///
+/// A route matches a manufacturer id and the sub-ids following it, for the universal ids after
+/// the device id, which any device id matches. The route matching the most sub-ids gets the
+/// message, the first one registered of equal routes. Messages no route matches go to the default
+/// handler, or are dropped without one. The bytes needed to match a route are held back until
+/// they arrived, so handlers get the whole message from its first byte with the usual `first`
+/// and `last` flags. Up to `ROUTES` routes can be registered, set up with the `with_` functions.
+pub struct SysexRouter<'a, const ROUTES: usize = 4> {
+    routes: [Option<Route<'a>>; ROUTES],
+    default: Option<&'a mut dyn SysexHandler>,
+    header: [u8; HEADER_LEN],
+    header_len: usize,
+    target: Target,
+}
+
+impl<const ROUTES: usize> Debug for SysexRouter<'_, ROUTES> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut routes = [None; ROUTES];
+        for (route, slot) in self.routes.iter().zip(routes.iter_mut()) {
+            *slot = route.as_ref().map(|route| (route.id, route.sub_ids));
+        }
+        f.debug_struct("SysexRouter")
+            .field("routes", &routes)
+            .field("default", &self.default.is_some())
+            .field("target", &self.target)
+            .finish()
+    }
+}
+
+impl<const ROUTES: usize> Default for SysexRouter<'_, ROUTES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, const ROUTES: usize> SysexRouter<'a, ROUTES> {
+    /// Router without routes, dropping all messages
+    pub fn new() -> Self {
+        SysexRouter {
+            routes: [(); ROUTES].map(|_| None),
+            default: None,
+            header: [0; HEADER_LEN],
+            header_len: 0,
+            target: Target::Dropped,
+        }
+    }
+
+    /// Send the messages of manufacturer `id` starting with `sub_ids` to `handler`
+    ///
+    /// Fails if all routes are taken or `sub_ids` is longer than `MAX_SUB_ID_LEN`.
+    pub fn with_route(
+        mut self,
+        id: ManufacturerId,
+        sub_ids: &'a [u8],
+        handler: &'a mut dyn SysexHandler,
+    ) -> Result<Self, FullTable> {
+        if sub_ids.len() > MAX_SUB_ID_LEN {
+            return Err(FullTable);
+        }
+        let slot = self.routes.iter_mut().find(|route| route.is_none());
+        *slot.ok_or(FullTable)? = Some(Route {
+            id,
+            sub_ids,
+            handler,
+        });
+        Ok(self)
+    }
+
+    /// Send the messages no route matches to `handler`
+    pub fn with_default(mut self, handler: &'a mut dyn SysexHandler) -> Self {
+        self.default = Some(handler);
+        self
+    }
+
+    /// Bytes to receive before all routes can be matched
+    fn header_needed(&self) -> usize {
+        let routes = self.routes.iter().flatten();
+        routes.map(Route::header_len).max().unwrap_or(0)
+    }
+
+    fn select(&self) -> Target {
+        let header = &self.header[..self.header_len];
+        let mut best: Option<(usize, usize)> = None;
+        for (index, route) in self.routes.iter().enumerate() {
+            if let Some(route) = route.as_ref().filter(|route| route.matches(header)) {
+                if !matches!(best, Some((_, len)) if len >= route.sub_ids.len()) {
+                    best = Some((index, route.sub_ids.len()));
+                }
+            }
+        }
+        match best {
+            Some((index, _)) => Target::Route(index),
+            None if self.default.is_some() => Target::Default,
+            None => Target::Dropped,
+        }
+    }
+
+    fn handler(&mut self) -> Option<&mut dyn SysexHandler> {
+        match self.target {
+            Target::Route(index) => match &mut self.routes[index] {
+                Some(route) => Some(&mut *route.handler),
+                None => None,
+            },
+            Target::Default => match &mut self.default {
+                Some(handler) => Some(&mut **handler),
+                None => None,
+            },
+            Target::Pending | Target::Dropped => None,
+        }
+    }
+}
+
+impl<const ROUTES: usize> SysexHandler for SysexRouter<'_, ROUTES> {
+    fn on_sysex_chunk(&mut self, chunk: &[u8], first: bool, last: bool) {
+        if first {
+            self.header_len = 0;
+            self.target = Target::Pending;
+        }
+        if self.target != Target::Pending {
+            if let Some(handler) = self.handler() {
+                handler.on_sysex_chunk(chunk, false, last);
+            }
+            return;
+        }
+
+        let needed = self.header_needed();
+        let take = needed.saturating_sub(self.header_len).min(chunk.len());
+        let (start, rest) = chunk.split_at(take);
+        self.header[self.header_len..self.header_len + take].copy_from_slice(start);
+        self.header_len += take;
+        if self.header_len < needed && !last {
+            return;
+        }
+        self.target = self.select();
+        let (header, header_len) = (self.header, self.header_len);
+        if let Some(handler) = self.handler() {
+            handler.on_sysex_chunk(&header[..header_len], true, last && rest.is_empty());
+            if !rest.is_empty() {
+                handler.on_sysex_chunk(rest, false, last);
+            }
+        }
+    }
+
+    fn on_sysex_abort(&mut self) {
+        if let Some(handler) = self.handler() {
+            handler.on_sysex_abort();
+        }
+        self.target = Target::Dropped;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::SysexStream;
+    use std::vec::Vec;
+
+    /// Reassembles the messages handed to it, `None` for an aborted one
+    #[derive(Debug, Default)]
+    struct Collector {
+        messages: Vec<Option<Vec<u8>>>,
+        receiving: Option<Vec<u8>>,
+    }
+
+    impl SysexHandler for Collector {
+        fn on_sysex_chunk(&mut self, chunk: &[u8], first: bool, last: bool) {
+            if first {
+                assert!(self.receiving.is_none(), "message did not end");
+                self.receiving = Some(Vec::new());
+            }
+            let message = self.receiving.as_mut().expect("message did not start");
+            message.extend_from_slice(chunk);
+            if last {
+                self.messages.push(self.receiving.take());
+            }
+        }
+
+        fn on_sysex_abort(&mut self) {
+            assert!(self.receiving.take().is_some(), "no message to abort");
+            self.messages.push(None);
+        }
+    }
+
+    const OWN: ManufacturerId = ManufacturerId::Extended(0x21, 0x1d);
+    const OWN_DUMP: [u8; 12] = [0xf0, 0, 0x21, 0x1d, 1, 2, 3, 4, 5, 6, 7, 0xf7];
+    /// Single note tuning change, universal realtime, sub-id 0x08 0x02
+    const MTS: [u8; 12] = [0xf0, 0x7f, 0x05, 0x08, 0x02, 0, 1, 60, 60, 0x20, 0, 0xf7];
+    /// Identity request, universal non realtime, sub-id 0x06 0x01
+    const IDENTITY: [u8; 6] = [0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7];
+
+    fn feed<const CHUNK: usize>(router: &mut impl SysexHandler, bytes: &[u8]) {
+        let mut stream = SysexStream::<CHUNK>::new();
+        for byte in bytes {
+            stream.feed(0, *byte, router);
+        }
+    }
+
+    fn data(message: &[u8]) -> Option<Vec<u8>> {
+        Some(Vec::from(&message[1..message.len() - 1]))
+    }
+
+    #[test]
+    fn should_dispatch_back_to_back_dumps() {
+        let (mut own, mut mts) = (Collector::default(), Collector::default());
+        let (mut identity, mut other) = (Collector::default(), Collector::default());
+        let mut router = SysexRouter::<4>::new()
+            .with_route(OWN, &[], &mut own)
+            .unwrap()
+            .with_route(ManufacturerId::UNIVERSAL_REALTIME, &[0x08], &mut mts)
+            .unwrap()
+            .with_route(
+                ManufacturerId::UNIVERSAL_NON_REALTIME,
+                &[0x06, 0x01],
+                &mut identity,
+            )
+            .unwrap()
+            .with_default(&mut other);
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&OWN_DUMP);
+        bytes.extend_from_slice(&MTS);
+        bytes.extend_from_slice(&IDENTITY);
+        // A universal non realtime message of another kind
+        bytes.extend_from_slice(&[0xf0, 0x7e, 0x7f, 0x06, 0x02, 1, 0xf7]);
+        bytes.extend_from_slice(&OWN_DUMP);
+        feed::<2>(&mut router, &bytes);
+
+        assert_eq!(own.messages, [data(&OWN_DUMP), data(&OWN_DUMP)]);
+        assert_eq!(mts.messages, [data(&MTS)]);
+        assert_eq!(identity.messages, [data(&IDENTITY)]);
+        assert_eq!(
+            other.messages,
+            [Some(Vec::from([0x7e, 0x7f, 0x06, 0x02, 1]))]
+        );
+    }
+
+    #[test]
+    fn should_prefer_routes_matching_more_sub_ids() {
+        let (mut realtime, mut mts) = (Collector::default(), Collector::default());
+        let mut tuning_change = Collector::default();
+        let mut router = SysexRouter::<3>::new()
+            .with_route(ManufacturerId::UNIVERSAL_REALTIME, &[], &mut realtime)
+            .unwrap()
+            .with_route(
+                ManufacturerId::UNIVERSAL_REALTIME,
+                &[0x08, 0x02],
+                &mut tuning_change,
+            )
+            .unwrap()
+            .with_route(ManufacturerId::UNIVERSAL_REALTIME, &[0x08], &mut mts)
+            .unwrap();
+        // Other device ids match too
+        let mut other_device = MTS;
+        other_device[2] = 0x7f;
+        let mut bulk_dump = MTS;
+        bulk_dump[4] = 0x01;
+        let msc = [0xf0, 0x7f, 0x7f, 0x02, 0x01, 0x01, 0xf7];
+        for message in [&MTS[..], &other_device, &bulk_dump, &msc] {
+            feed::<32>(&mut router, message);
+        }
+        // Messages shorter than the longest header are matched when they end, universal ones
+        // need a device id
+        feed::<32>(&mut router, &[0xf0, 0x7f, 0x01, 0xf7]);
+        feed::<32>(&mut router, &[0xf0, 0x7f, 0xf7]);
+        feed::<32>(&mut router, &[0xf0, 0xf7]);
+
+        assert_eq!(tuning_change.messages, [data(&MTS), data(&other_device)]);
+        assert_eq!(mts.messages, [data(&bulk_dump)]);
+        assert_eq!(
+            realtime.messages,
+            [data(&msc), Some(Vec::from([0x7f, 0x01]))]
+        );
+    }
+
+    #[test]
+    fn should_pass_aborts_to_the_handler_of_the_message() {
+        let (mut own, mut other) = (Collector::default(), Collector::default());
+        let mut router = SysexRouter::<1>::new()
+            .with_route(OWN, &[], &mut own)
+            .unwrap()
+            .with_default(&mut other);
+        // Cut off by a note on, then a complete dump
+        let mut bytes = Vec::from(&OWN_DUMP[..8]);
+        bytes.extend_from_slice(&[0x90, 60, 100]);
+        bytes.extend_from_slice(&OWN_DUMP);
+        // Cut off before the id was complete, nothing was handed out
+        bytes.extend_from_slice(&[0xf0, 0, 0x21, 0x90]);
+        feed::<2>(&mut router, &bytes);
+
+        assert_eq!(own.messages, [None, data(&OWN_DUMP)]);
+        assert_eq!(other.messages, []);
+    }
+
+    #[test]
+    fn should_drop_unmatched_messages_without_default() {
+        let mut own = Collector::default();
+        let mut router = SysexRouter::<1>::new()
+            .with_route(OWN, &[], &mut own)
+            .unwrap();
+        let long = [0x43; 100];
+        let mut bytes = Vec::from([0xf0]);
+        bytes.extend_from_slice(&long);
+        bytes.push(0xf7);
+        bytes.extend_from_slice(&OWN_DUMP);
+        feed::<4>(&mut router, &bytes);
+        assert_eq!(own.messages, [data(&OWN_DUMP)]);
+    }
+
+    #[test]
+    fn should_bound_routes() {
+        let (mut first, mut second) = (Collector::default(), Collector::default());
+        let router = SysexRouter::<1>::new()
+            .with_route(OWN, &[], &mut first)
+            .unwrap();
+        assert!(router
+            .with_route(ManufacturerId::NON_COMMERCIAL, &[], &mut second)
+            .is_err());
+        assert!(SysexRouter::<1>::new()
+            .with_route(OWN, &[1, 2, 3, 4, 5], &mut Collector::default())
+            .is_err());
+    }
+
+    #[test]
+    fn should_parse_manufacturer_ids() {
+        assert_eq!(
+            ManufacturerId::parse(&[0x43, 1]),
+            Some(ManufacturerId::Short(0x43))
+        );
+        assert_eq!(ManufacturerId::parse(&[0, 0x21, 0x1d]), Some(OWN));
+        assert_eq!(ManufacturerId::parse(&[0, 0x21]), None);
+        assert_eq!(ManufacturerId::parse(&[]), None);
+        assert!(ManufacturerId::parse(&[0x7e]).is_some_and(|id| id.is_universal()));
+        assert!(!ManufacturerId::NON_COMMERCIAL.is_universal());
+    }
+}
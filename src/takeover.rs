@@ -0,0 +1,321 @@
+//! Keeping knobs from jumping parameters they don't match
+
+use crate::MidiProcessor;
+use midi_convert::midi_types::{Channel, Control, MidiMessage};
+
+/// How a knob takes over a parameter it doesn't match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TakeoverMode {
+    /// Drop the values of the knob until it reaches or crosses the parameter value
+    Pickup,
+    /// Move the parameter by the knob travel scaled onto the remaining range in the direction
+    /// of the knob, until the two meet
+    Scale,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    channel: Channel,
+    controller: u8,
+    /// Parameter value, the target until the knob took over
+    value: u8,
+    /// Last position of the knob
+    knob: Option<u8>,
+    engaged: bool,
+    used: u32,
+}
+
+/// Processor holding back control changes of a knob until it matches the parameter it controls
+///
+/// The application calls `set_target` with the value of a parameter whenever it changes other than
+/// through the knob, like after a patch change. Control changes for that channel and controller
+/// are then held back as the `TakeoverMode` says, after that they pass through until the next
+/// `set_target`. The position of a knob is only known once it sent a value, until then a knob
+/// only takes over by sending the target value exactly.
+///
+/// Up to `ENTRIES` controllers are tracked, a new target replaces a controller that took over or
+/// else the least recently used one, which then passes through. Other messages pass through.
+#[derive(Debug, Clone)]
+pub struct SoftTakeover<const ENTRIES: usize = 16> {
+    entries: [Option<Entry>; ENTRIES],
+    mode: TakeoverMode,
+    clock: u32,
+}
+
+impl<const ENTRIES: usize> SoftTakeover<ENTRIES> {
+    pub fn new(mode: TakeoverMode) -> Self {
+        SoftTakeover {
+            entries: [None; ENTRIES],
+            mode,
+            clock: 0,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: TakeoverMode) {
+        self.mode = mode;
+    }
+
+    /// The parameter of `control` on `channel` is now `value`, hold the knob back until it
+    /// takes over
+    pub fn set_target(&mut self, channel: Channel, control: Control, value: u8) {
+        let controller = u8::from(control);
+        let value = value.min(0x7f);
+        let used = self.tick();
+        if let Some(entry) = self.find(channel, controller) {
+            entry.value = value;
+            entry.engaged = entry.knob == Some(value);
+            entry.used = used;
+            return;
+        }
+        let entry = Entry {
+            channel,
+            controller,
+            value,
+            knob: None,
+            engaged: false,
+            used,
+        };
+        let slot = self.entries.iter_mut().max_by_key(|slot| match slot {
+            None => (2, 0),
+            Some(entry) if entry.engaged => (1, used.wrapping_sub(entry.used)),
+            Some(entry) => (0, used.wrapping_sub(entry.used)),
+        });
+        if let Some(slot) = slot {
+            *slot = Some(entry);
+        }
+    }
+
+    /// The knob of `control` on `channel` is held back
+    pub fn is_pending(&self, channel: Channel, control: Control) -> bool {
+        let controller = u8::from(control);
+        self.entries.iter().flatten().any(|entry| {
+            entry.channel == channel && entry.controller == controller && !entry.engaged
+        })
+    }
+
+    /// Forget all targets, every knob passes through
+    pub fn clear(&mut self) {
+        self.entries = [None; ENTRIES];
+    }
+
+    /// Process a received message, returns `None` while the knob is held back
+    pub fn process(&mut self, message: MidiMessage) -> Option<MidiMessage> {
+        let (channel, control, knob) = match message {
+            MidiMessage::ControlChange(channel, control, value) => {
+                (channel, control, u8::from(value))
+            }
+            _ => return Some(message),
+        };
+        let mode = self.mode;
+        let used = self.tick();
+        let entry = match self.find(channel, control.into()) {
+            Some(entry) => entry,
+            None => return Some(message),
+        };
+        entry.used = used;
+        let last = entry.knob.replace(knob);
+        if entry.engaged {
+            return Some(message);
+        }
+        let target = entry.value;
+        let crossed = last.is_some_and(|last| (last < target) != (knob < target));
+        if knob == target || mode == TakeoverMode::Pickup && crossed {
+            entry.engaged = true;
+            return Some(message);
+        }
+        if mode == TakeoverMode::Pickup {
+            return None;
+        }
+
+        let last = last?;
+        let value = scale(last, knob, target);
+        entry.engaged = value == knob;
+        if value == target {
+            return None;
+        }
+        entry.value = value;
+        Some(MidiMessage::ControlChange(channel, control, value.into()))
+    }
+
+    fn tick(&mut self) -> u32 {
+        self.clock = self.clock.wrapping_add(1);
+        self.clock
+    }
+
+    fn find(&mut self, channel: Channel, controller: u8) -> Option<&mut Entry> {
+        self.entries
+            .iter_mut()
+            .flatten()
+            .find(|entry| entry.channel == channel && entry.controller == controller)
+    }
+}
+
+/// Parameter value after the knob moved from `last` to `knob`, mapping the knob travel left in
+/// that direction onto the value range left
+fn scale(last: u8, knob: u8, value: u8) -> u8 {
+    let (last, knob, value) = (u32::from(last), u32::from(knob), u32::from(value));
+    let scaled = if knob > last {
+        value + (knob - last) * (0x7f - value) / (0x7f - last)
+    } else if knob < last {
+        value - (last - knob) * value / last
+    } else {
+        value
+    };
+    scaled as u8
+}
+
+impl<const ENTRIES: usize> MidiProcessor for SoftTakeover<ENTRIES> {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        if let Some(message) = SoftTakeover::process(self, message) {
+            emit(message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_on};
+    use std::vec::Vec;
+
+    const CUTOFF: u8 = 74;
+
+    fn turn<const N: usize>(
+        takeover: &mut SoftTakeover<N>,
+        channel: u8,
+        controller: u8,
+        values: &[u8],
+    ) -> Vec<Option<u8>> {
+        values
+            .iter()
+            .map(|value| {
+                takeover
+                    .process(cc(channel, controller, *value))
+                    .map(|message| match message {
+                        MidiMessage::ControlChange(_, _, value) => u8::from(value),
+                        _ => panic!("{:?}", message),
+                    })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_pick_up_from_below() {
+        let mut takeover = SoftTakeover::<4>::new(TakeoverMode::Pickup);
+        takeover.set_target(Channel::C1, CUTOFF.into(), 64);
+        assert!(takeover.is_pending(Channel::C1, CUTOFF.into()));
+        assert_eq!(
+            turn(&mut takeover, 0, CUTOFF, &[10, 30, 60, 70, 50]),
+            [None, None, None, Some(70), Some(50)]
+        );
+        assert!(!takeover.is_pending(Channel::C1, CUTOFF.into()));
+        // Other controllers and channels pass
+        assert_eq!(turn(&mut takeover, 0, 7, &[1]), [Some(1)]);
+        assert_eq!(turn(&mut takeover, 1, CUTOFF, &[2]), [Some(2)]);
+        assert_eq!(takeover.process(note_on(0, 60, 1)), Some(note_on(0, 60, 1)));
+    }
+
+    #[test]
+    fn should_pick_up_from_above() {
+        let mut takeover = SoftTakeover::<4>::new(TakeoverMode::Pickup);
+        takeover.set_target(Channel::C3, CUTOFF.into(), 64);
+        assert_eq!(
+            turn(&mut takeover, 2, CUTOFF, &[120, 100, 64, 63]),
+            [None, None, Some(64), Some(63)]
+        );
+
+        // A new target holds the knob back again, its position is known now
+        takeover.set_target(Channel::C3, CUTOFF.into(), 20);
+        assert_eq!(
+            turn(&mut takeover, 2, CUTOFF, &[40, 19, 25]),
+            [None, Some(19), Some(25)]
+        );
+        // Nothing to take over when the knob is already there
+        takeover.set_target(Channel::C3, CUTOFF.into(), 25);
+        assert!(!takeover.is_pending(Channel::C3, CUTOFF.into()));
+    }
+
+    #[test]
+    fn should_only_pick_up_exact_value_of_unknown_knob() {
+        let mut takeover = SoftTakeover::<4>::new(TakeoverMode::Pickup);
+        takeover.set_target(Channel::C1, CUTOFF.into(), 64);
+        assert_eq!(
+            turn(&mut takeover, 0, CUTOFF, &[64, 65]),
+            [Some(64), Some(65)]
+        );
+    }
+
+    #[test]
+    fn should_scale_remaining_travel() {
+        let mut takeover = SoftTakeover::<4>::new(TakeoverMode::Scale);
+        takeover.set_target(Channel::C1, CUTOFF.into(), 100);
+        // Learning the knob position, then 20 of the 100 steps up to the top move the value 20
+        // of the 27 steps left
+        assert_eq!(
+            turn(&mut takeover, 0, CUTOFF, &[27, 47, 87, 127]),
+            [None, Some(105), Some(116), Some(127)]
+        );
+        // The two met at the top
+        assert!(!takeover.is_pending(Channel::C1, CUTOFF.into()));
+        assert_eq!(turn(&mut takeover, 0, CUTOFF, &[90]), [Some(90)]);
+
+        takeover.set_target(Channel::C1, CUTOFF.into(), 20);
+        // Down to the bottom from 90, the value lags behind until both reach 0
+        assert_eq!(
+            turn(&mut takeover, 0, CUTOFF, &[45, 1, 0, 5]),
+            [Some(10), Some(1), Some(0), Some(5)]
+        );
+    }
+
+    #[test]
+    fn should_scale_at_the_edges() {
+        assert_eq!(scale(126, 127, 0), 127);
+        assert_eq!(scale(1, 0, 127), 0);
+        assert_eq!(scale(0, 127, 0), 127);
+        assert_eq!(scale(127, 0, 127), 0);
+        assert_eq!(scale(0, 1, 127), 127);
+        assert_eq!(scale(127, 126, 0), 0);
+        assert_eq!(scale(64, 64, 10), 10);
+        // Small steps far from the end only move the value once they add up
+        assert_eq!(scale(10, 11, 120), 120);
+    }
+
+    #[test]
+    fn should_drop_unchanged_scaled_values() {
+        let mut takeover = SoftTakeover::<4>::new(TakeoverMode::Scale);
+        takeover.set_target(Channel::C1, CUTOFF.into(), 120);
+        assert_eq!(
+            turn(&mut takeover, 0, CUTOFF, &[10, 11, 12, 60, 121]),
+            [None, None, None, Some(122), Some(126)]
+        );
+        assert!(takeover.is_pending(Channel::C1, CUTOFF.into()));
+        // They meet at the top
+        assert_eq!(
+            turn(&mut takeover, 0, CUTOFF, &[127, 125]),
+            [Some(127), Some(125)]
+        );
+        assert!(!takeover.is_pending(Channel::C1, CUTOFF.into()));
+    }
+
+    #[test]
+    fn should_evict_least_recently_used_target() {
+        let mut takeover = SoftTakeover::<2>::new(TakeoverMode::Pickup);
+        takeover.set_target(Channel::C1, 1.into(), 64);
+        takeover.set_target(Channel::C1, 2.into(), 64);
+        assert_eq!(turn(&mut takeover, 0, 1, &[0]), [None]);
+        takeover.set_target(Channel::C1, 3.into(), 64);
+        assert!(takeover.is_pending(Channel::C1, 1.into()));
+        assert!(!takeover.is_pending(Channel::C1, 2.into()));
+        assert_eq!(turn(&mut takeover, 0, 2, &[0]), [Some(0)]);
+
+        // Controllers that took over are replaced first
+        assert_eq!(turn(&mut takeover, 0, 1, &[64]), [Some(64)]);
+        takeover.set_target(Channel::C1, 4.into(), 64);
+        assert!(takeover.is_pending(Channel::C1, 3.into()));
+        assert!(takeover.is_pending(Channel::C1, 4.into()));
+
+        takeover.clear();
+        assert!(!takeover.is_pending(Channel::C1, 3.into()));
+    }
+}
@@ -0,0 +1,226 @@
+//! Taps for inspecting the raw bytes sent and received, for debugging without a logic analyzer
+
+use crate::{queue::ByteQueue, MidiError, MidiIn};
+use core::fmt::Debug;
+use embedded_hal_nb::serial;
+use midi_convert::{midi_types::MidiMessage, render::MidiTransport};
+
+/// Wraps a transport or serial transmitter and calls `tap` with every slice of bytes written
+///
+/// Can be used as a `MidiTransport` for a `MidiRenderer` or as the serial transmitter of a
+/// `MidiOut`. In the latter case the tap sees the bytes exactly as they are sent, after running
+/// status is applied. The tap can mirror the bytes to a debug log or to a second transport.
+#[derive(Debug)]
+pub struct TeeTransport<T, F> {
+    inner: T,
+    tap: F,
+}
+
+impl<T, F> TeeTransport<T, F>
+where
+    F: FnMut(&[u8]),
+{
+    pub fn new(inner: T, tap: F) -> Self {
+        TeeTransport { inner, tap }
+    }
+
+    /// Release the wrapped transport and the tap
+    pub fn release(self) -> (T, F) {
+        (self.inner, self.tap)
+    }
+}
+
+impl<T, F> MidiTransport for TeeTransport<T, F>
+where
+    T: MidiTransport,
+    F: FnMut(&[u8]),
+{
+    type Error = T::Error;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.inner.write(bytes)?;
+        (self.tap)(bytes);
+        Ok(())
+    }
+}
+
+impl<T, F> serial::ErrorType for TeeTransport<T, F>
+where
+    T: serial::ErrorType,
+{
+    type Error = T::Error;
+}
+
+impl<T, F> serial::Write<u8> for TeeTransport<T, F>
+where
+    T: serial::Write<u8>,
+    F: FnMut(&[u8]),
+{
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.inner.write(word)?;
+        (self.tap)(&[word]);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        self.inner.flush()
+    }
+}
+
+/// Serial receiver that keeps the last `N` bytes read
+#[derive(Debug)]
+struct TapRx<RX, const N: usize> {
+    rx: RX,
+    raw: ByteQueue<N>,
+}
+
+impl<RX: serial::ErrorType, const N: usize> serial::ErrorType for TapRx<RX, N> {
+    type Error = RX::Error;
+}
+
+impl<RX: serial::Read<u8>, const N: usize> serial::Read<u8> for TapRx<RX, N> {
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        let byte = self.rx.read()?;
+        self.raw.push_overwrite(byte);
+        Ok(byte)
+    }
+}
+
+/// `MidiIn` that also keeps the raw bytes it consumed
+///
+/// The last `N` bytes read are kept in a ring buffer until they are drained with `take_raw`, older
+/// bytes are dropped. With `N` set to 0 no bytes are kept.
+#[derive(Debug)]
+pub struct TapMidiIn<RX, const N: usize> {
+    midi_in: MidiIn<TapRx<RX, N>>,
+}
+
+impl<RX, E, const N: usize> TapMidiIn<RX, N>
+where
+    RX: serial::Read<u8, Error = E>,
+    E: serial::Error,
+{
+    pub fn new(rx: RX) -> Self {
+        TapMidiIn {
+            midi_in: MidiIn::new(TapRx {
+                rx,
+                raw: ByteQueue::new(),
+            }),
+        }
+    }
+
+    pub fn read(&mut self) -> nb::Result<MidiMessage, MidiError<E>> {
+        self.midi_in.read()
+    }
+
+    /// Drain the bytes read so far into `buffer`, oldest first, returns the number of bytes copied
+    pub fn take_raw(&mut self, buffer: &mut [u8]) -> usize {
+        let raw = &mut self.midi_in.rx.raw;
+        let mut count = 0;
+        for slot in buffer.iter_mut() {
+            match raw.peek() {
+                Some(byte) => {
+                    *slot = byte;
+                    raw.pop();
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::{message::note_on, MidiOut};
+    use embedded_hal_mock::eh1::serial::{Mock, Transaction};
+    use std::vec::Vec;
+
+    #[test]
+    fn should_tap_bytes_written_by_midi_out() {
+        let bytes = [0x92, 0x76, 0x34, 0x33, 0x65, 0xf8];
+        let expectations: Vec<Transaction<u8>> =
+            bytes.iter().map(|byte| Transaction::write(*byte)).collect();
+        let mut tapped = Vec::new();
+
+        let mut midi_out = MidiOut::new(TeeTransport::new(Mock::new(&expectations), |bytes| {
+            tapped.extend_from_slice(bytes)
+        }));
+        midi_out.write(&note_on(2, 0x76, 0x34)).unwrap();
+        midi_out.write(&note_on(2, 0x33, 0x65)).unwrap();
+        midi_out.write(&MidiMessage::TimingClock).unwrap();
+        midi_out.release().release().0.done();
+
+        assert_eq!(tapped, bytes);
+    }
+
+    #[test]
+    fn should_tap_messages_written_to_transport() {
+        struct Discard;
+
+        impl MidiTransport for Discard {
+            type Error = ();
+
+            fn write(&mut self, _bytes: &[u8]) -> Result<(), ()> {
+                Ok(())
+            }
+        }
+
+        let mut tapped = Vec::new();
+        {
+            let mut tee = TeeTransport::new(Discard, |bytes: &[u8]| tapped.push(bytes.to_vec()));
+            MidiTransport::write(&mut tee, &[0x92, 0x76, 0x34]).unwrap();
+            MidiTransport::write(&mut tee, &[0xf8]).unwrap();
+        }
+
+        assert_eq!(tapped, [[0x92, 0x76, 0x34].to_vec(), [0xf8].to_vec()]);
+    }
+
+    #[test]
+    fn should_keep_raw_bytes_read() {
+        let bytes = [0x92, 0x76, 0xf8, 0x34];
+        let expectations: Vec<Transaction<u8>> =
+            bytes.iter().map(|byte| Transaction::read(*byte)).collect();
+        let mut midi_in = TapMidiIn::<_, 8>::new(Mock::new(&expectations));
+
+        let received: Vec<MidiMessage> = bytes.iter().filter_map(|_| midi_in.read().ok()).collect();
+        assert_eq!(received, [MidiMessage::TimingClock, note_on(2, 0x76, 0x34)]);
+
+        let mut raw = [0; 8];
+        assert_eq!(midi_in.take_raw(&mut raw), 4);
+        assert_eq!(raw[..4], bytes);
+        assert_eq!(midi_in.take_raw(&mut raw), 0);
+        midi_in.midi_in.rx.rx.done();
+    }
+
+    #[test]
+    fn should_drop_oldest_raw_bytes() {
+        let bytes = [0x92, 0x76, 0x34, 0x33, 0x65];
+        let expectations: Vec<Transaction<u8>> =
+            bytes.iter().map(|byte| Transaction::read(*byte)).collect();
+        let mut midi_in = TapMidiIn::<_, 2>::new(Mock::new(&expectations));
+        bytes.iter().for_each(|_| {
+            midi_in.read().ok();
+        });
+
+        let mut raw = [0; 1];
+        assert_eq!(midi_in.take_raw(&mut raw), 1);
+        assert_eq!(raw, [0x33]);
+        assert_eq!(midi_in.take_raw(&mut raw), 1);
+        assert_eq!(raw, [0x65]);
+        midi_in.midi_in.rx.rx.done();
+    }
+
+    #[test]
+    fn should_keep_nothing_without_buffer() {
+        let expectations = [Transaction::read(0xf8)];
+        let mut midi_in = TapMidiIn::<_, 0>::new(Mock::new(&expectations));
+
+        assert_eq!(midi_in.read(), Ok(MidiMessage::TimingClock));
+        assert_eq!(midi_in.take_raw(&mut [0; 4]), 0);
+        midi_in.midi_in.rx.rx.done();
+    }
+}
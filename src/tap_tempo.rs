@@ -0,0 +1,312 @@
+//! Tempo from a tap footswitch, driving an outgoing midi clock
+
+use crate::diag::log_debug;
+use midi_convert::midi_types::MidiMessage;
+
+/// Timing clocks per quarter note
+const CLOCKS_PER_BEAT: u32 = 24;
+
+/// Intervals further than this fraction from the average are outliers, in percent
+const OUTLIER_PERCENT: u32 = 30;
+
+fn within_tolerance(interval: u32, reference: u32) -> bool {
+    let tolerance = reference * OUTLIER_PERCENT / 100;
+    interval.abs_diff(reference) <= tolerance
+}
+
+/// Works out the tempo from the last `N` intervals between taps and sends midi clock at it
+///
+/// Call `tap` every time the footswitch is hit. Intervals more than 30% off the average are
+/// ignored as outliers, two outliers in a row that agree with each other start a new tempo. No tap
+/// for the timeout forgets the taps so far.
+///
+/// Once the tempo is known `tick` sends timing clocks at 24 per quarter note. A new tempo doesn't
+/// change the clock period at once, it ramps to the new period over one beat so sequencers
+/// following the clock don't stutter.
+#[derive(Debug)]
+pub struct TapTempo<const N: usize = 4> {
+    intervals: [u32; N],
+    /// Number of intervals in `intervals`, the oldest is overwritten once it is full
+    len: usize,
+    next: usize,
+    last_tap_ms: Option<u32>,
+    outlier: Option<u32>,
+    /// Length of a beat at the current tempo in microseconds
+    beat_us: Option<u32>,
+    timeout_ms: u32,
+    /// Clock period the ramp started from and goes to, in microseconds
+    ramp_from_us: u32,
+    ramp_to_us: u32,
+    /// Clocks sent since the ramp started, up to `CLOCKS_PER_BEAT`
+    ramp_clocks: u32,
+    next_clock_us: Option<u32>,
+}
+
+impl<const N: usize> Default for TapTempo<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> TapTempo<N> {
+    pub fn new() -> Self {
+        TapTempo {
+            intervals: [0; N],
+            len: 0,
+            next: 0,
+            last_tap_ms: None,
+            outlier: None,
+            beat_us: None,
+            timeout_ms: 2000,
+            ramp_from_us: 0,
+            ramp_to_us: 0,
+            ramp_clocks: CLOCKS_PER_BEAT,
+            next_clock_us: None,
+        }
+    }
+
+    /// Forget the taps when there was no tap for `timeout_ms`, 2 seconds by default
+    pub fn set_timeout_ms(&mut self, timeout_ms: u32) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    /// The footswitch was hit at `now_ms`
+    pub fn tap(&mut self, now_ms: u32) {
+        let last = self.last_tap_ms.replace(now_ms);
+        let interval = match last {
+            Some(last) => now_ms.wrapping_sub(last),
+            None => return,
+        };
+        if interval == 0 || interval > self.timeout_ms {
+            log_debug!("tap tempo: timed out after {} ms", interval);
+            self.clear_history();
+            return;
+        }
+        match self.history_average_ms() {
+            Some(average) if !within_tolerance(interval, average) => {
+                match self.outlier.replace(interval) {
+                    Some(outlier) if within_tolerance(interval, outlier) => {
+                        log_debug!("tap tempo: new tempo, {} ms", interval);
+                        self.clear_history();
+                        self.push(outlier);
+                        self.push(interval);
+                    }
+                    _ => {
+                        log_debug!("tap tempo: ignored {} ms interval", interval);
+                        return;
+                    }
+                }
+            }
+            _ => self.push(interval),
+        }
+        self.retune();
+    }
+
+    /// Length of a beat at the current tempo in milliseconds, once there were two taps
+    pub fn beat_ms(&self) -> Option<u32> {
+        self.beat_us.map(|beat| beat / 1000)
+    }
+
+    /// Tempo in thousandths of a beat per minute, once there were two taps
+    pub fn bpm_millis(&self) -> Option<u32> {
+        self.beat_us
+            .map(|beat| (60_000_000_000 / u64::from(beat.max(1))) as u32)
+    }
+
+    /// Period of the next timing clock in microseconds, once the tempo is known
+    pub fn clock_period_us(&self) -> Option<u32> {
+        self.next_clock_us.map(|_| self.ramped_period_us())
+    }
+
+    /// Send a timing clock if one is due at `now_us`
+    ///
+    /// Call at least once per clock period. When the calls fall behind by a whole period the
+    /// clocks missed are skipped rather than sent in a burst.
+    pub fn tick(&mut self, now_us: u32, emit: &mut dyn FnMut(MidiMessage)) {
+        let next = match self.next_clock_us {
+            Some(next) => next,
+            None => return,
+        };
+        let late = now_us.wrapping_sub(next);
+        if late > u32::MAX / 2 {
+            return;
+        }
+        emit(MidiMessage::TimingClock);
+        self.ramp_clocks = (self.ramp_clocks + 1).min(CLOCKS_PER_BEAT);
+        let period = self.ramped_period_us();
+        let start = if late >= period { now_us } else { next };
+        self.next_clock_us = Some(start.wrapping_add(period));
+    }
+
+    fn history_average_ms(&self) -> Option<u32> {
+        let sum: u64 = self.intervals[..self.len]
+            .iter()
+            .map(|i| u64::from(*i))
+            .sum();
+        (self.len > 0).then(|| (sum / self.len as u64) as u32)
+    }
+
+    fn clear_history(&mut self) {
+        self.len = 0;
+        self.next = 0;
+        self.outlier = None;
+    }
+
+    fn push(&mut self, interval: u32) {
+        if N == 0 {
+            return;
+        }
+        self.intervals[self.next] = interval;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+        self.outlier = None;
+    }
+
+    /// Start ramping the clock to the tempo of the taps
+    fn retune(&mut self) {
+        let sum: u64 = self.intervals[..self.len]
+            .iter()
+            .map(|i| u64::from(*i))
+            .sum();
+        if self.len == 0 {
+            return;
+        }
+        let beat = (sum * 1000 / self.len as u64).min(u64::from(u32::MAX)) as u32;
+        self.beat_us = Some(beat);
+        let target = beat / CLOCKS_PER_BEAT;
+        if self.next_clock_us.is_none() {
+            self.ramp_from_us = target;
+            self.ramp_clocks = CLOCKS_PER_BEAT;
+            // The first clock goes out with the next tick
+            self.next_clock_us = self.last_tap_ms.map(|ms| ms.wrapping_mul(1000));
+        } else {
+            self.ramp_from_us = self.ramped_period_us();
+            self.ramp_clocks = 0;
+        }
+        self.ramp_to_us = target;
+    }
+
+    fn ramped_period_us(&self) -> u32 {
+        let from = i64::from(self.ramp_from_us);
+        let to = i64::from(self.ramp_to_us);
+        let step = i64::from(self.ramp_clocks);
+        (from + (to - from) * step / i64::from(CLOCKS_PER_BEAT)) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::vec::Vec;
+
+    /// Times of the clocks sent while ticking every microsecond until `end_us`
+    fn clocks<const N: usize>(tempo: &mut TapTempo<N>, start_us: u32, end_us: u32) -> Vec<u32> {
+        let mut times = Vec::new();
+        for now in start_us..end_us {
+            tempo.tick(now, &mut |message| {
+                assert_eq!(message, MidiMessage::TimingClock);
+                times.push(now);
+            });
+        }
+        times
+    }
+
+    #[test]
+    fn should_converge_on_jittery_taps() {
+        let mut tempo = TapTempo::<4>::new();
+        assert_eq!(tempo.bpm_millis(), None);
+        let jitter = [0, 12, -9, 15, -14, 6, -3, 10];
+        let mut now = 1000;
+        for offset in jitter {
+            tempo.tap((now + offset) as u32);
+            now += 500;
+        }
+        let bpm = tempo.bpm_millis().unwrap();
+        assert!((118_000..=122_000).contains(&bpm), "{}", bpm);
+    }
+
+    #[test]
+    fn should_ignore_outliers() {
+        let mut tempo = TapTempo::<4>::new();
+        for tap in [0, 500, 1000, 1500] {
+            tempo.tap(tap);
+        }
+        // A missed tap and a double tap
+        tempo.tap(2500);
+        tempo.tap(2600);
+        tempo.tap(3100);
+        assert_eq!(tempo.bpm_millis(), Some(120_000));
+    }
+
+    #[test]
+    fn should_follow_deliberate_tempo_change() {
+        let mut tempo = TapTempo::<4>::new();
+        for tap in [0, 500, 1000, 1500, 1800, 2100] {
+            tempo.tap(tap);
+        }
+        assert_eq!(tempo.bpm_millis(), Some(200_000));
+        tempo.tap(2400);
+        assert_eq!(tempo.beat_ms(), Some(300));
+    }
+
+    #[test]
+    fn should_forget_taps_after_timeout() {
+        let mut tempo = TapTempo::<4>::new();
+        tempo.set_timeout_ms(1000);
+        for tap in [0, 500, 1000] {
+            tempo.tap(tap);
+        }
+        tempo.tap(3000);
+        assert_eq!(tempo.beat_ms(), Some(500));
+        // The history starts over, the old tempo doesn't make 800 ms an outlier
+        tempo.tap(3800);
+        tempo.tap(4600);
+        assert_eq!(tempo.beat_ms(), Some(800));
+        assert_eq!(tempo.bpm_millis(), Some(75_000));
+    }
+
+    #[test]
+    fn should_send_clock_at_tapped_tempo() {
+        let mut tempo = TapTempo::<4>::new();
+        assert_eq!(clocks(&mut tempo, 0, 10_000), []);
+        tempo.tap(0);
+        tempo.tap(480);
+        assert_eq!(tempo.clock_period_us(), Some(20_000));
+        let times = clocks(&mut tempo, 480_000, 580_000);
+        assert_eq!(times, [480_000, 500_000, 520_000, 540_000, 560_000]);
+    }
+
+    #[test]
+    fn should_ramp_clock_period_over_a_beat() {
+        let mut tempo = TapTempo::<1>::new();
+        tempo.tap(0);
+        tempo.tap(480);
+        assert_eq!(clocks(&mut tempo, 480_000, 912_000).len(), 22);
+
+        // A tap 10% faster
+        tempo.tap(912);
+        assert_eq!(tempo.beat_ms(), Some(432));
+        let times = clocks(&mut tempo, 912_000, 1_500_000);
+        assert_eq!(times[0], 920_000);
+        let periods: Vec<u32> = times.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        // Every clock moves an equal step from 20 ms to 18 ms over a beat, then the period holds
+        assert_eq!(periods[0], 19_917);
+        assert!(periods[..24].windows(2).all(|pair| pair[1] < pair[0]));
+        assert!(periods[..24].windows(2).all(|pair| pair[0] - pair[1] <= 84));
+        assert!(periods[23..].iter().all(|period| *period == 18_000));
+    }
+
+    #[test]
+    fn should_skip_clocks_when_ticks_fall_behind() {
+        let mut tempo = TapTempo::<4>::new();
+        tempo.tap(0);
+        tempo.tap(480);
+        let mut count = 0;
+        tempo.tick(480_000, &mut |_| count += 1);
+        tempo.tick(600_000, &mut |_| count += 1);
+        tempo.tick(610_000, &mut |_| count += 1);
+        tempo.tick(620_000, &mut |_| count += 1);
+        assert_eq!(count, 3);
+    }
+}
@@ -0,0 +1,83 @@
+//! Helpers shared by the unit tests
+
+extern crate std;
+use crate::{parse::MidiParser, MidiProcessor};
+use core::convert::Infallible;
+use embedded_hal_mock::eh1::serial::{Mock, Transaction};
+use embedded_hal_nb::serial::{self, ErrorKind};
+use midi_convert::midi_types::MidiMessage;
+use std::vec::Vec;
+
+/// Serial transmitter that collects written bytes
+#[derive(Debug, Default)]
+pub(crate) struct Wire(pub(crate) Vec<u8>);
+
+impl serial::ErrorType for Wire {
+    type Error = Infallible;
+}
+
+impl serial::Write<u8> for Wire {
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.0.push(word);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Serial transmitter that collects written bytes, failing the writes with the given indices
+#[derive(Debug, Default)]
+pub(crate) struct FlakyWire {
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) writes: usize,
+    pub(crate) failing: Vec<usize>,
+}
+
+impl serial::ErrorType for FlakyWire {
+    type Error = ErrorKind;
+}
+
+impl serial::Write<u8> for FlakyWire {
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.writes += 1;
+        if self.failing.contains(&(self.writes - 1)) {
+            return Err(nb::Error::Other(ErrorKind::Other));
+        }
+        self.bytes.push(word);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Run `messages` through `processor`, returns everything it emitted
+pub(crate) fn process<P: MidiProcessor>(
+    processor: &mut P,
+    messages: &[MidiMessage],
+) -> Vec<MidiMessage> {
+    let mut output = Vec::new();
+    for message in messages {
+        processor.process(*message, &mut |message| output.push(message));
+    }
+    output
+}
+
+/// Parse `bytes`, returns the messages they complete
+pub(crate) fn parse_all(bytes: &[u8]) -> Vec<MidiMessage> {
+    let mut parser = MidiParser::new();
+    bytes
+        .iter()
+        .filter_map(|byte| parser.parse(*byte))
+        .collect()
+}
+
+/// Serial mock expecting `bytes` to be written
+pub(crate) fn mock_writes(bytes: &[u8]) -> Mock<u8> {
+    let expectations: Vec<Transaction<u8>> =
+        bytes.iter().map(|byte| Transaction::write(*byte)).collect();
+    Mock::new(&expectations)
+}
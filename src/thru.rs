@@ -0,0 +1,365 @@
+//! Byte level soft thru, forwarding received bytes without waiting for complete messages
+
+use crate::{
+    diag::log_debug, parse::data_length, queue::ByteQueue, MidiError, MidiIn, RenderedMessage,
+};
+use embedded_hal_nb::serial;
+use midi_convert::midi_types::MidiMessage;
+use nb::block;
+
+/// Serial receiver that remembers the last byte read
+#[derive(Debug)]
+struct ThruRx<RX> {
+    rx: RX,
+    last: Option<u8>,
+}
+
+impl<RX: serial::ErrorType> serial::ErrorType for ThruRx<RX> {
+    type Error = RX::Error;
+}
+
+impl<RX: serial::Read<u8>> serial::Read<u8> for ThruRx<RX> {
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        let byte = self.rx.read()?;
+        self.last = Some(byte);
+        Ok(byte)
+    }
+}
+
+/// Message framing of a forwarded stream, real time bytes must not be tracked
+#[derive(Debug, Default)]
+pub(crate) struct Framing {
+    /// Running status of the forwarded stream
+    status: Option<u8>,
+    /// Data bytes left in the current message
+    remaining: u8,
+    sysex: bool,
+    /// Local messages were written since the last forwarded status byte
+    interrupted: bool,
+}
+
+impl Framing {
+    pub(crate) fn at_boundary(&self) -> bool {
+        !self.sysex && self.remaining == 0
+    }
+
+    /// Local messages were sent between forwarded messages
+    pub(crate) fn interrupt(&mut self) {
+        self.interrupted = true;
+    }
+
+    /// Status byte to send again before `byte`, when `byte` continues the running status after
+    /// local messages were sent
+    pub(crate) fn resend_status(&mut self, byte: u8) -> Option<u8> {
+        if byte < 0x80 && self.at_boundary() && self.interrupted {
+            self.interrupted = false;
+            self.status
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn track(&mut self, byte: u8) {
+        match byte {
+            0xf0 => {
+                self.status = None;
+                self.remaining = 0;
+                self.sysex = true;
+            }
+            0x80..=0xf7 => {
+                self.status = if byte < 0xf0 { Some(byte) } else { None };
+                self.remaining = data_length(byte);
+                self.sysex = false;
+                self.interrupted = false;
+            }
+            _ if self.sysex => {}
+            _ if self.remaining > 0 => self.remaining -= 1,
+            _ => {
+                if let Some(status) = self.status {
+                    self.remaining = data_length(status) - 1;
+                }
+            }
+        }
+    }
+}
+
+/// Forwards every received byte to an output as soon as it arrives, while parsing the received
+/// messages for local use
+///
+/// Forwarding bytes instead of complete messages removes up to three bytes of latency. Messages
+/// sent locally are merged into the forwarded stream between messages, real time messages are
+/// merged right away. Up to `N` bytes of local messages are queued while a forwarded message is
+/// incomplete. When forwarded messages use running status after a local message, the status byte
+/// is sent again.
+#[derive(Debug)]
+pub struct SoftThru<RX, TX, const N: usize = 16> {
+    midi_in: MidiIn<ThruRx<RX>>,
+    tx: TX,
+    filter: Option<fn(u8) -> bool>,
+    framing: Framing,
+    pending: ByteQueue<N>,
+}
+
+impl<RX, TX, E, const N: usize> SoftThru<RX, TX, N>
+where
+    RX: serial::Read<u8, Error = E>,
+    TX: serial::Write<u8, Error = E>,
+    E: serial::Error,
+{
+    pub fn new(rx: RX, tx: TX) -> Self {
+        SoftThru {
+            midi_in: MidiIn::new(ThruRx { rx, last: None }),
+            tx,
+            filter: None,
+            framing: Framing::default(),
+            pending: ByteQueue::new(),
+        }
+    }
+
+    /// Only forward bytes for which `filter` returns true
+    ///
+    /// Dropping a status byte leaves its data bytes without a message, so filters should only
+    /// drop real time bytes, like active sensing.
+    pub fn set_filter(&mut self, filter: Option<fn(u8) -> bool>) {
+        self.filter = filter;
+    }
+
+    /// Read a byte, forwarding it and returning the message it completes
+    pub fn read(&mut self) -> nb::Result<MidiMessage, MidiError<E>> {
+        let result = self.midi_in.read();
+        if let Some(byte) = self.midi_in.rx.last.take() {
+            self.forward(byte).map_err(nb::Error::Other)?;
+        }
+        result
+    }
+
+    /// Send a local message, it is queued until the forwarded stream reaches a message boundary
+    ///
+    /// Returns `MidiError::BufferFull` when the message does not fit in the queue.
+    pub fn send(&mut self, message: &MidiMessage) -> Result<(), MidiError<E>> {
+        let rendered = RenderedMessage::from(message);
+        let bytes = rendered.as_bytes();
+
+        if bytes.first().is_some_and(|status| *status >= 0xf8) {
+            return self.write(bytes);
+        }
+
+        if self.framing.at_boundary() && self.pending.len() == 0 {
+            self.framing.interrupt();
+            return self.write(bytes);
+        }
+
+        if self.pending.free() < bytes.len() {
+            log_debug!("soft thru: queue full, rejected {:?}", message);
+            return Err(MidiError::BufferFull);
+        }
+        bytes.iter().for_each(|byte| self.pending.push(*byte));
+        Ok(())
+    }
+
+    /// Number of local message bytes waiting for a message boundary
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn forward(&mut self, byte: u8) -> Result<(), MidiError<E>> {
+        if self.filter.is_some_and(|filter| !filter(byte)) {
+            if byte < 0xf8 {
+                self.framing.track(byte);
+            }
+            return Ok(());
+        }
+
+        if byte >= 0xf8 {
+            return self.write(&[byte]);
+        }
+
+        if let Some(status) = self.framing.resend_status(byte) {
+            self.write(&[status])?;
+        }
+        self.framing.track(byte);
+        self.write(&[byte])?;
+
+        if self.framing.at_boundary() && self.pending.len() > 0 {
+            self.framing.interrupt();
+            while let Some(byte) = self.pending.peek() {
+                self.write(&[byte])?;
+                self.pending.pop();
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), MidiError<E>> {
+        for byte in bytes {
+            block!(self.tx.write(*byte))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_on};
+    use crate::test_util::parse_all;
+    use core::convert::Infallible;
+    use std::{cell::RefCell, rc::Rc, vec::Vec};
+
+    /// Serial receiver for a fixed list of bytes
+    #[derive(Debug)]
+    struct Input(Vec<u8>);
+
+    impl serial::ErrorType for Input {
+        type Error = Infallible;
+    }
+
+    impl serial::Read<u8> for Input {
+        fn read(&mut self) -> nb::Result<u8, Self::Error> {
+            if self.0.is_empty() {
+                Err(nb::Error::WouldBlock)
+            } else {
+                Ok(self.0.remove(0))
+            }
+        }
+    }
+
+    /// Serial transmitter collecting bytes in a shared buffer
+    #[derive(Debug, Default, Clone)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl serial::ErrorType for Output {
+        type Error = Infallible;
+    }
+
+    impl serial::Write<u8> for Output {
+        fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+            self.0.borrow_mut().push(word);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_forward_bytes_as_they_arrive() {
+        let output = Output::default();
+        let mut thru = SoftThru::<_, _>::new(Input([0x90, 0x40, 0x7f].to_vec()), output.clone());
+
+        assert_eq!(thru.read(), Err(nb::Error::WouldBlock));
+        assert_eq!(*output.0.borrow(), [0x90]);
+        assert_eq!(thru.read(), Err(nb::Error::WouldBlock));
+        assert_eq!(thru.read(), Ok(note_on(0, 0x40, 0x7f)));
+        assert_eq!(*output.0.borrow(), [0x90, 0x40, 0x7f]);
+    }
+
+    #[test]
+    fn should_merge_local_messages_at_message_boundaries() {
+        let output = Output::default();
+        let mut thru = SoftThru::<_, _>::new(
+            Input([0x90, 0x40, 0x7f, 0x41, 0xf8, 0x7f, 0x42, 0x7f].to_vec()),
+            output.clone(),
+        );
+
+        let mut received = Vec::new();
+        let mut read = |thru: &mut SoftThru<Input, Output>| {
+            if let Ok(message) = thru.read() {
+                received.push(message);
+            }
+        };
+
+        read(&mut thru);
+        read(&mut thru);
+        thru.send(&cc(1, 7, 100)).unwrap();
+        thru.send(&MidiMessage::TimingClock).unwrap();
+        assert_eq!(thru.pending(), 3);
+        read(&mut thru);
+        assert_eq!(thru.pending(), 0);
+        read(&mut thru);
+        read(&mut thru);
+        thru.send(&cc(1, 7, 90)).unwrap();
+        read(&mut thru);
+        read(&mut thru);
+        read(&mut thru);
+
+        assert_eq!(
+            *output.0.borrow(),
+            [
+                0x90, 0x40, 0xf8, 0x7f, 0xb1, 0x07, 0x64, 0x90, 0x41, 0xf8, 0x7f, 0xb1, 0x07, 0x5a,
+                0x90, 0x42, 0x7f
+            ]
+        );
+        assert_eq!(
+            parse_all(&output.0.borrow()),
+            [
+                MidiMessage::TimingClock,
+                note_on(0, 0x40, 0x7f),
+                cc(1, 7, 100),
+                MidiMessage::TimingClock,
+                note_on(0, 0x41, 0x7f),
+                cc(1, 7, 90),
+                note_on(0, 0x42, 0x7f),
+            ]
+        );
+        assert_eq!(
+            received,
+            [
+                note_on(0, 0x40, 0x7f),
+                MidiMessage::TimingClock,
+                note_on(0, 0x41, 0x7f),
+                note_on(0, 0x42, 0x7f),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_hold_local_messages_during_system_exclusive() {
+        let output = Output::default();
+        let mut thru =
+            SoftThru::<_, _>::new(Input([0xf0, 0x01, 0x02, 0xf7].to_vec()), output.clone());
+
+        thru.read().ok();
+        thru.send(&note_on(0, 0x40, 0x7f)).unwrap();
+        thru.read().ok();
+        thru.read().ok();
+        assert_eq!(thru.pending(), 3);
+        thru.read().ok();
+
+        assert_eq!(
+            *output.0.borrow(),
+            [0xf0, 0x01, 0x02, 0xf7, 0x90, 0x40, 0x7f]
+        );
+    }
+
+    #[test]
+    fn should_report_full_queue() {
+        let output = Output::default();
+        let mut thru = SoftThru::<_, _, 4>::new(Input([0x90].to_vec()), output.clone());
+
+        thru.read().ok();
+        assert_eq!(thru.send(&note_on(0, 0x40, 0x7f)), Ok(()));
+        assert_eq!(
+            thru.send(&note_on(0, 0x41, 0x7f)),
+            Err(MidiError::BufferFull)
+        );
+    }
+
+    #[test]
+    fn should_filter_forwarded_bytes() {
+        let output = Output::default();
+        let mut thru =
+            SoftThru::<_, _>::new(Input([0x90, 0xfe, 0x40, 0x7f].to_vec()), output.clone());
+        thru.set_filter(Some(|byte| byte != 0xfe));
+
+        let received: Vec<_> = (0..4).filter_map(|_| thru.read().ok()).collect();
+
+        assert_eq!(*output.0.borrow(), [0x90, 0x40, 0x7f]);
+        assert_eq!(
+            received,
+            [MidiMessage::ActiveSensing, note_on(0, 0x40, 0x7f)]
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! Building and taking apart midi time code quarter frames
+
+use midi_convert::midi_types::QuarterFrame;
+
+/// The SMPTE type used, this indicates the number of frames per second
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpteType {
+    /// 24 frames per second
+    Frames24,
+    /// 25 frames per second
+    Frames25,
+    /// 29.97 frames per second
+    DropFrame30,
+    /// 30 frames per second
+    Frames30,
+}
+
+impl SmpteType {
+    const fn from_bits(bits: u8) -> Self {
+        match bits & 0x03 {
+            0 => SmpteType::Frames24,
+            1 => SmpteType::Frames25,
+            2 => SmpteType::DropFrame30,
+            _ => SmpteType::Frames30,
+        }
+    }
+}
+
+/// Part of the time code carried by a quarter frame message
+///
+/// Each of the eight quarter frame types carries a 4 bit part of the time code. As one is sent
+/// every quarter frame, the complete time code is sent every two frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuarterFrameType {
+    /// Frame number low nibble
+    FramesLS,
+    /// Frame count high nibble
+    FramesMS,
+    /// Seconds low nibble
+    SecondsLS,
+    /// Seconds high nibble
+    SecondsMS,
+    /// Minutes low nibble
+    MinutesLS,
+    /// Minutes high nibble
+    MinutesMS,
+    /// Hours low nibble
+    HoursLS,
+    /// Combined hours high bit and smpte type
+    HoursMS,
+}
+
+impl QuarterFrameType {
+    /// All types in the order they are sent
+    pub const ALL: [QuarterFrameType; 8] = [
+        QuarterFrameType::FramesLS,
+        QuarterFrameType::FramesMS,
+        QuarterFrameType::SecondsLS,
+        QuarterFrameType::SecondsMS,
+        QuarterFrameType::MinutesLS,
+        QuarterFrameType::MinutesMS,
+        QuarterFrameType::HoursLS,
+        QuarterFrameType::HoursMS,
+    ];
+}
+
+/// Constructors and accessors for the parts of a `QuarterFrame`
+///
+/// ```
+/// use embedded_midi::midi_types::QuarterFrame;
+/// use embedded_midi::{QuarterFrameExt, QuarterFrameType, SmpteType};
+///
+/// let frame = QuarterFrame::with_type(QuarterFrameType::SecondsLS, 9);
+/// assert_eq!(frame.frame_type(), QuarterFrameType::SecondsLS);
+/// assert_eq!(frame.value(), 9);
+///
+/// let frame = QuarterFrame::from_parts(SmpteType::Frames25, true);
+/// assert_eq!(frame.smpte_type(), Some(SmpteType::Frames25));
+/// ```
+pub trait QuarterFrameExt: Sized {
+    /// Quarter frame carrying `value` for `frame_type`, only the low 4 bits of `value` are used
+    fn with_type(frame_type: QuarterFrameType, value: u8) -> Self;
+
+    /// Hours high nibble quarter frame, carrying the SMPTE type and bit 4 of the hours
+    fn from_parts(smpte_type: SmpteType, hours_high_bit: bool) -> Self;
+
+    fn frame_type(&self) -> QuarterFrameType;
+
+    /// The 4 bit value
+    fn value(&self) -> u8;
+
+    /// SMPTE type of an hours high nibble quarter frame, `None` for other types
+    fn smpte_type(&self) -> Option<SmpteType>;
+}
+
+impl QuarterFrameExt for QuarterFrame {
+    fn with_type(frame_type: QuarterFrameType, value: u8) -> Self {
+        QuarterFrame::new(((frame_type as u8) << 4) | (value & 0x0f))
+    }
+
+    fn from_parts(smpte_type: SmpteType, hours_high_bit: bool) -> Self {
+        Self::with_type(
+            QuarterFrameType::HoursMS,
+            ((smpte_type as u8) << 1) | hours_high_bit as u8,
+        )
+    }
+
+    fn frame_type(&self) -> QuarterFrameType {
+        QuarterFrameType::ALL[((u8::from(*self) >> 4) & 0x07) as usize]
+    }
+
+    fn value(&self) -> u8 {
+        u8::from(*self) & 0x0f
+    }
+
+    fn smpte_type(&self) -> Option<SmpteType> {
+        match self.frame_type() {
+            QuarterFrameType::HoursMS => Some(SmpteType::from_bits(self.value() >> 1)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_round_trip_every_type_and_value() {
+        for (index, frame_type) in QuarterFrameType::ALL.iter().enumerate() {
+            for value in 0..16 {
+                let frame = QuarterFrame::with_type(*frame_type, value);
+
+                assert_eq!(u8::from(frame), (index as u8) << 4 | value);
+                assert_eq!(frame.frame_type(), *frame_type);
+                assert_eq!(frame.value(), value);
+            }
+        }
+    }
+
+    #[test]
+    fn should_mask_value_to_four_bits() {
+        let frame = QuarterFrame::with_type(QuarterFrameType::FramesLS, 0x1f);
+
+        assert_eq!(frame.frame_type(), QuarterFrameType::FramesLS);
+        assert_eq!(frame.value(), 0x0f);
+    }
+
+    #[test]
+    fn should_build_hours_high_nibble() {
+        for smpte_type in [
+            SmpteType::Frames24,
+            SmpteType::Frames25,
+            SmpteType::DropFrame30,
+            SmpteType::Frames30,
+        ] {
+            for hours_high_bit in [false, true] {
+                let frame = QuarterFrame::from_parts(smpte_type, hours_high_bit);
+
+                assert_eq!(frame.frame_type(), QuarterFrameType::HoursMS);
+                assert_eq!(frame.smpte_type(), Some(smpte_type));
+                assert_eq!(frame.value() & 0x01 == 1, hours_high_bit);
+            }
+        }
+        assert_eq!(
+            u8::from(QuarterFrame::from_parts(SmpteType::Frames30, true)),
+            0x77
+        );
+    }
+
+    #[test]
+    fn should_only_have_smpte_type_in_hours_high_nibble() {
+        assert_eq!(
+            QuarterFrame::with_type(QuarterFrameType::HoursLS, 0x06).smpte_type(),
+            None
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! Named constructors and accessors for the two 7 bit halves of `Value14`
+
+use midi_convert::midi_types::Value14;
+
+/// Builds and splits 14 bit values by their named halves
+///
+/// Prefer these over the `(u8, u8)` conversions of `Value14`, the tuple holds the msb first while
+/// pitch bend and song position pointer messages send the lsb first: `0xe0 lsb msb` and
+/// `0xf2 lsb msb`. Halves above 0x7f are clamped to 0x7f.
+///
+/// ```
+/// use embedded_midi::midi_types::Value14;
+/// use embedded_midi::Value14Ext;
+///
+/// let value = Value14::from_lsb_msb(0x00, 0x40);
+/// assert_eq!(u16::from(value), 0x2000);
+/// assert_eq!((value.lsb(), value.msb()), (0x00, 0x40));
+/// assert_eq!(value, Value14::from_msb_lsb(0x40, 0x00));
+/// ```
+pub trait Value14Ext: Sized {
+    /// Value from its data bytes in wire order, least significant first
+    fn from_lsb_msb(lsb: u8, msb: u8) -> Self;
+
+    fn from_msb_lsb(msb: u8, lsb: u8) -> Self;
+
+    /// The low 7 bits
+    fn lsb(&self) -> u8;
+
+    /// The high 7 bits
+    fn msb(&self) -> u8;
+}
+
+impl Value14Ext for Value14 {
+    fn from_lsb_msb(lsb: u8, msb: u8) -> Self {
+        Value14::from(((msb.min(0x7f) as u16) << 7) | lsb.min(0x7f) as u16)
+    }
+
+    fn from_msb_lsb(msb: u8, lsb: u8) -> Self {
+        Self::from_lsb_msb(lsb, msb)
+    }
+
+    fn lsb(&self) -> u8 {
+        (u16::from(*self) & 0x7f) as u8
+    }
+
+    fn msb(&self) -> u8 {
+        (u16::from(*self) >> 7) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse::MidiParser, RenderedMessage};
+    use midi_convert::midi_types::MidiMessage;
+
+    /// Values where swapping the halves changes the value
+    const VALUES: [u16; 7] = [0x0001, 0x007f, 0x0080, 0x1fff, 0x2000, 0x2001, 0x3fff];
+
+    fn round_trip(message: MidiMessage) -> MidiMessage {
+        let mut parser = MidiParser::new();
+        let mut parsed = None;
+        for byte in RenderedMessage::from(message).as_bytes() {
+            parsed = parser.parse(*byte);
+        }
+        parsed.unwrap()
+    }
+
+    #[test]
+    fn should_split_into_named_halves() {
+        for value in VALUES {
+            let value14 = Value14::from(value);
+            assert_eq!(value14.lsb() as u16, value & 0x7f);
+            assert_eq!(value14.msb() as u16, value >> 7);
+            assert_eq!(Value14::from_lsb_msb(value14.lsb(), value14.msb()), value14);
+            assert_eq!(Value14::from_msb_lsb(value14.msb(), value14.lsb()), value14);
+        }
+        assert_eq!(u16::from(Value14::from_lsb_msb(0xff, 0x80)), 0x3fff);
+    }
+
+    #[test]
+    fn should_render_lsb_first() {
+        let message = MidiMessage::PitchBendChange(0.into(), Value14::from(0x2001u16));
+        assert_eq!(
+            RenderedMessage::from(message).as_bytes(),
+            [0xe0, 0x01, 0x40]
+        );
+        let message = MidiMessage::SongPositionPointer(Value14::from(0x2001u16));
+        assert_eq!(
+            RenderedMessage::from(message).as_bytes(),
+            [0xf2, 0x01, 0x40]
+        );
+    }
+
+    #[test]
+    fn should_parse_rendered_values_back() {
+        for value in VALUES {
+            let bend = MidiMessage::PitchBendChange(3.into(), Value14::from(value));
+            let position = MidiMessage::SongPositionPointer(Value14::from(value));
+            match (round_trip(bend), round_trip(position)) {
+                (
+                    MidiMessage::PitchBendChange(_, bend),
+                    MidiMessage::SongPositionPointer(position),
+                ) => {
+                    assert_eq!(u16::from(bend), value);
+                    assert_eq!(u16::from(position), value);
+                }
+                other => panic!("parsed {:?}", other),
+            }
+        }
+    }
+}
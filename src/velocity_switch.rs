@@ -0,0 +1,287 @@
+//! Velocity switch processor playing soft and hard notes on different channels
+
+use crate::MidiProcessor;
+use midi_convert::midi_types::{Channel, MidiMessage, Note, Value7};
+
+/// Channels a held note was sent to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    Low,
+    High,
+    Both,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Held {
+    channel: Channel,
+    note: Note,
+    route: Route,
+}
+
+/// Sends note ons below a velocity threshold to a low channel and the others to a high channel,
+/// like the velocity layers of a stage piano
+///
+/// With a crossfade band notes with velocities inside the band go to both channels, the low
+/// channel getting less and the high channel more of the velocity the closer it is to the top of
+/// the band. The band starts `width / 2` below the threshold. Note offs follow the routing of their
+/// note on, even when the threshold changed in between, for up to `HELD` notes at the same time.
+/// Note offs of other notes are sent to both channels. Other messages are passed on unchanged.
+#[derive(Debug, Clone)]
+pub struct VelocitySwitch<const HELD: usize = 16> {
+    low: Channel,
+    high: Channel,
+    threshold: u8,
+    width: u8,
+    held: [Option<Held>; HELD],
+}
+
+impl<const HELD: usize> VelocitySwitch<HELD> {
+    /// Switch sending velocities below `threshold` to `low` and the others to `high`, without
+    /// crossfade
+    pub fn new(low: Channel, high: Channel, threshold: u8) -> Self {
+        VelocitySwitch {
+            low,
+            high,
+            threshold: threshold.min(127),
+            width: 0,
+            held: [None; HELD],
+        }
+    }
+
+    pub fn set_threshold(&mut self, threshold: u8) {
+        self.threshold = threshold.min(127);
+    }
+
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    /// Set the width of the crossfade band in velocity steps, 0 switches without crossfade
+    pub fn set_crossfade(&mut self, width: u8) {
+        self.width = width.min(127);
+    }
+
+    pub fn crossfade(&self) -> u8 {
+        self.width
+    }
+
+    /// Velocities of a note on for the low and high channels, 0 when it isn't sent there
+    fn velocities(&self, velocity: u8) -> (u8, u8) {
+        let bottom = self.threshold.saturating_sub(self.width / 2);
+        if velocity < bottom {
+            return (velocity, 0);
+        }
+        let position = velocity - bottom;
+        if position >= self.width {
+            return (0, velocity);
+        }
+        let high = u16::from(velocity) * u16::from(position) / u16::from(self.width);
+        let low = u16::from(velocity) - high;
+        (low.max(1) as u8, high.max(1) as u8)
+    }
+
+    fn note_on(
+        &mut self,
+        channel: Channel,
+        note: Note,
+        velocity: Value7,
+        emit: &mut dyn FnMut(MidiMessage),
+    ) {
+        // A repeated note on replaces the routing of the note
+        if let Some(held) = self.take(channel, note) {
+            self.release(
+                held.route,
+                MidiMessage::NoteOff(channel, note, 0.into()),
+                emit,
+            );
+        }
+
+        let route = match self.velocities(velocity.into()) {
+            (low, 0) => {
+                emit(MidiMessage::NoteOn(self.low, note, low.into()));
+                Route::Low
+            }
+            (0, high) => {
+                emit(MidiMessage::NoteOn(self.high, note, high.into()));
+                Route::High
+            }
+            (low, high) => {
+                emit(MidiMessage::NoteOn(self.low, note, low.into()));
+                emit(MidiMessage::NoteOn(self.high, note, high.into()));
+                Route::Both
+            }
+        };
+
+        if let Some(slot) = self.held.iter_mut().find(|held| held.is_none()) {
+            *slot = Some(Held {
+                channel,
+                note,
+                route,
+            });
+        }
+    }
+
+    fn take(&mut self, channel: Channel, note: Note) -> Option<Held> {
+        self.held
+            .iter_mut()
+            .find(|held| matches!(held, Some(held) if held.channel == channel && held.note == note))
+            .and_then(|held| held.take())
+    }
+
+    /// Send a note off to the channels of `route`
+    fn release(&self, route: Route, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        let routed = |channel| match message {
+            MidiMessage::NoteOff(_, note, velocity) => {
+                MidiMessage::NoteOff(channel, note, velocity)
+            }
+            MidiMessage::NoteOn(_, note, velocity) => MidiMessage::NoteOn(channel, note, velocity),
+            other => other,
+        };
+        if route != Route::High {
+            emit(routed(self.low));
+        }
+        if route != Route::Low {
+            emit(routed(self.high));
+        }
+    }
+}
+
+impl<const HELD: usize> MidiProcessor for VelocitySwitch<HELD> {
+    fn process(&mut self, message: MidiMessage, emit: &mut dyn FnMut(MidiMessage)) {
+        match message {
+            MidiMessage::NoteOn(channel, note, velocity) if u8::from(velocity) > 0 => {
+                self.note_on(channel, note, velocity, emit)
+            }
+            MidiMessage::NoteOn(channel, note, _) | MidiMessage::NoteOff(channel, note, _) => {
+                let route = self
+                    .take(channel, note)
+                    .map_or(Route::Both, |held| held.route);
+                self.release(route, message, emit);
+            }
+            _ => emit(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{note_off, note_on};
+    use crate::test_util::process;
+    use std::vec::Vec;
+
+    fn switch() -> VelocitySwitch {
+        VelocitySwitch::new(Channel::C1, Channel::C2, 64)
+    }
+
+    #[test]
+    fn should_switch_at_threshold() {
+        let mut switch = switch();
+        assert_eq!(
+            process(&mut switch, &[note_on(0, 60, 63), note_on(0, 62, 64)]),
+            [note_on(0, 60, 63), note_on(1, 62, 64)]
+        );
+    }
+
+    #[test]
+    fn should_crossfade_inside_band() {
+        let mut switch = switch();
+        switch.set_crossfade(40);
+
+        // The band covers velocities 44 to 83
+        let velocities: Vec<_> = [1, 43, 44, 54, 64, 74, 83, 84, 127]
+            .iter()
+            .map(|velocity| switch.velocities(*velocity))
+            .collect();
+        assert_eq!(
+            velocities,
+            [
+                (1, 0),
+                (43, 0),
+                (44, 1),
+                (41, 13),
+                (32, 32),
+                (19, 55),
+                (3, 80),
+                (0, 84),
+                (0, 127)
+            ]
+        );
+        for velocity in 44..84 {
+            let (low, high) = switch.velocities(velocity);
+            assert!(low > 0 && high > 0);
+            assert!(u16::from(low) + u16::from(high) <= u16::from(velocity) + 1);
+        }
+
+        assert_eq!(
+            process(&mut switch, &[note_on(3, 60, 64), note_off(3, 60, 10)]),
+            [
+                note_on(0, 60, 32),
+                note_on(1, 60, 32),
+                note_off(0, 60, 10),
+                note_off(1, 60, 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn should_release_notes_where_they_started_when_threshold_moves() {
+        let mut switch = switch();
+        let mut output = process(&mut switch, &[note_on(0, 60, 50), note_on(0, 62, 100)]);
+
+        switch.set_threshold(120);
+        switch.set_crossfade(10);
+        output.extend(process(
+            &mut switch,
+            &[note_on(0, 62, 0), note_off(0, 60, 0), note_on(0, 64, 100)],
+        ));
+        switch.set_threshold(20);
+        output.extend(process(&mut switch, &[note_off(0, 64, 5)]));
+
+        assert_eq!(
+            output,
+            [
+                note_on(0, 60, 50),
+                note_on(1, 62, 100),
+                note_on(1, 62, 0),
+                note_off(0, 60, 0),
+                note_on(0, 64, 100),
+                note_off(0, 64, 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_release_unknown_notes_on_both_channels() {
+        let mut switch = VelocitySwitch::<1>::new(Channel::C1, Channel::C2, 64);
+        let output = process(
+            &mut switch,
+            &[
+                note_on(0, 60, 100),
+                note_on(0, 61, 10),
+                note_off(0, 61, 0),
+                MidiMessage::TimingClock,
+            ],
+        );
+        assert_eq!(
+            output,
+            [
+                note_on(1, 60, 100),
+                note_on(0, 61, 10),
+                note_off(0, 61, 0),
+                note_off(1, 61, 0),
+                MidiMessage::TimingClock
+            ]
+        );
+    }
+
+    #[test]
+    fn should_release_retriggered_note_before_routing_it_again() {
+        let mut switch = switch();
+        assert_eq!(
+            process(&mut switch, &[note_on(0, 60, 10), note_on(0, 60, 100)]),
+            [note_on(0, 60, 10), note_off(0, 60, 0), note_on(1, 60, 100)]
+        );
+    }
+}
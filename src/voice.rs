@@ -0,0 +1,400 @@
+//! Note to voice assignment for polyphonic synth engines
+
+use midi_convert::midi_types::{MidiMessage, Note, Value7};
+
+/// Control number of the sustain (damper) pedal
+const SUSTAIN: u8 = 64;
+
+/// Which voice to take over when a note arrives while all voices are in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StealPolicy {
+    /// Steal the voice that was started longest ago
+    Oldest,
+    /// Steal the voice that was started with the lowest velocity
+    Quietest,
+    /// Retrigger the voice already playing the same note, otherwise steal the oldest voice
+    SameNote,
+}
+
+/// How free voices are picked for new notes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssignMode {
+    /// Always use the lowest numbered free voice
+    Lowest,
+    /// Cycle through the voices, starting after the last assigned voice
+    RoundRobin,
+}
+
+/// What a voice should do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoiceEventKind {
+    /// Start playing a note on an idle voice
+    Start { note: Note, velocity: Value7 },
+    /// Release the note playing on the voice
+    Stop,
+    /// Cut off the note playing on the voice and start playing another
+    Steal { note: Note, velocity: Value7 },
+}
+
+/// Event for a single voice produced by the `VoiceAllocator`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VoiceEvent {
+    pub voice: usize,
+    pub kind: VoiceEventKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VoiceState {
+    Idle,
+    Playing,
+    /// Note off was received while the sustain pedal was held
+    Sustained,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+    state: VoiceState,
+    note: Note,
+    velocity: Value7,
+    started: u32,
+}
+
+/// Assigns notes to `N` voices, stealing voices when all of them are in use
+///
+/// Note on and note off messages should be filtered by channel before they are passed to the
+/// allocator. Note offs received while the sustain pedal is held are deferred until the pedal is
+/// released. When stealing, voices held only by the sustain pedal are taken before voices that are
+/// still playing. `N` must be at least 1, which is checked at compile time.
+#[derive(Debug, Clone)]
+pub struct VoiceAllocator<const N: usize> {
+    voices: [Voice; N],
+    steal_policy: StealPolicy,
+    assign_mode: AssignMode,
+    sustain: bool,
+    counter: u32,
+    last_assigned: usize,
+}
+
+impl<const N: usize> VoiceAllocator<N> {
+    const HAS_VOICES: () = assert!(N > 0, "an allocator needs at least one voice");
+
+    pub fn new(steal_policy: StealPolicy) -> Self {
+        let () = Self::HAS_VOICES;
+        VoiceAllocator {
+            voices: [Voice {
+                state: VoiceState::Idle,
+                note: 0.into(),
+                velocity: 0.into(),
+                started: 0,
+            }; N],
+            steal_policy,
+            assign_mode: AssignMode::Lowest,
+            sustain: false,
+            counter: 0,
+            last_assigned: N.wrapping_sub(1),
+        }
+    }
+
+    pub fn set_steal_policy(&mut self, steal_policy: StealPolicy) {
+        self.steal_policy = steal_policy;
+    }
+
+    pub fn set_assign_mode(&mut self, assign_mode: AssignMode) {
+        self.assign_mode = assign_mode;
+    }
+
+    /// Is the sustain pedal held
+    pub fn is_sustained(&self) -> bool {
+        self.sustain
+    }
+
+    /// Note sounding on a voice, including notes held by the sustain pedal
+    pub fn note(&self, voice: usize) -> Option<Note> {
+        self.voices
+            .get(voice)
+            .filter(|voice| voice.state != VoiceState::Idle)
+            .map(|voice| voice.note)
+    }
+
+    /// Process a message, calling `emit` for every resulting voice event
+    ///
+    /// Note on, note off and the sustain pedal control change are handled, other messages are
+    /// ignored.
+    pub fn process(&mut self, message: &MidiMessage, mut emit: impl FnMut(VoiceEvent)) {
+        match *message {
+            MidiMessage::NoteOn(_, note, velocity) if u8::from(velocity) > 0 => {
+                emit(self.note_on(note, velocity))
+            }
+            MidiMessage::NoteOn(_, note, _) | MidiMessage::NoteOff(_, note, _) => {
+                if let Some(event) = self.note_off(note) {
+                    emit(event)
+                }
+            }
+            MidiMessage::ControlChange(_, control, value) if u8::from(control) == SUSTAIN => {
+                self.set_sustain(u8::from(value) >= 64, emit)
+            }
+            _ => {}
+        }
+    }
+
+    fn note_on(&mut self, note: Note, velocity: Value7) -> VoiceEvent {
+        self.counter = self.counter.wrapping_add(1);
+
+        let (voice, kind) = match self.retrigger_voice(note).or_else(|| self.free_voice()) {
+            Some(voice) if self.voices[voice].state == VoiceState::Idle => {
+                (voice, VoiceEventKind::Start { note, velocity })
+            }
+            Some(voice) => (voice, VoiceEventKind::Steal { note, velocity }),
+            None => (self.steal_voice(), VoiceEventKind::Steal { note, velocity }),
+        };
+
+        self.voices[voice] = Voice {
+            state: VoiceState::Playing,
+            note,
+            velocity,
+            started: self.counter,
+        };
+        self.last_assigned = voice;
+
+        VoiceEvent { voice, kind }
+    }
+
+    fn note_off(&mut self, note: Note) -> Option<VoiceEvent> {
+        let voice = self
+            .voices
+            .iter()
+            .position(|voice| voice.state == VoiceState::Playing && voice.note == note)?;
+
+        if self.sustain {
+            self.voices[voice].state = VoiceState::Sustained;
+            None
+        } else {
+            self.voices[voice].state = VoiceState::Idle;
+            Some(VoiceEvent {
+                voice,
+                kind: VoiceEventKind::Stop,
+            })
+        }
+    }
+
+    fn set_sustain(&mut self, sustain: bool, mut emit: impl FnMut(VoiceEvent)) {
+        self.sustain = sustain;
+        if sustain {
+            return;
+        }
+
+        for (index, voice) in self.voices.iter_mut().enumerate() {
+            if voice.state == VoiceState::Sustained {
+                voice.state = VoiceState::Idle;
+                emit(VoiceEvent {
+                    voice: index,
+                    kind: VoiceEventKind::Stop,
+                });
+            }
+        }
+    }
+
+    /// Voice already sounding `note` when using the same note steal policy
+    fn retrigger_voice(&self, note: Note) -> Option<usize> {
+        if self.steal_policy != StealPolicy::SameNote {
+            return None;
+        }
+
+        self.voices
+            .iter()
+            .position(|voice| voice.state != VoiceState::Idle && voice.note == note)
+    }
+
+    fn free_voice(&self) -> Option<usize> {
+        let start = match self.assign_mode {
+            AssignMode::Lowest => 0,
+            AssignMode::RoundRobin => self.last_assigned.wrapping_add(1),
+        };
+
+        (0..N)
+            .map(|offset| (start + offset) % N)
+            .find(|voice| self.voices[*voice].state == VoiceState::Idle)
+    }
+
+    fn steal_voice(&self) -> usize {
+        let counter = self.counter;
+        let candidates = || {
+            let sustained = self
+                .voices
+                .iter()
+                .any(|voice| voice.state == VoiceState::Sustained);
+            self.voices
+                .iter()
+                .enumerate()
+                .filter(move |(_, voice)| !sustained || voice.state == VoiceState::Sustained)
+        };
+
+        let stolen = match self.steal_policy {
+            StealPolicy::Quietest => candidates().min_by_key(|(_, voice)| u8::from(voice.velocity)),
+            StealPolicy::Oldest | StealPolicy::SameNote => {
+                candidates().max_by_key(|(_, voice)| counter.wrapping_sub(voice.started))
+            }
+        };
+
+        stolen.map(|(index, _)| index).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::vec::Vec;
+
+    fn note_on(note: u8, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOn(0.into(), note.into(), velocity.into())
+    }
+
+    fn note_off(note: u8) -> MidiMessage {
+        MidiMessage::NoteOff(0.into(), note.into(), 0.into())
+    }
+
+    fn sustain(value: u8) -> MidiMessage {
+        MidiMessage::ControlChange(0.into(), SUSTAIN.into(), value.into())
+    }
+
+    fn process<const N: usize>(
+        allocator: &mut VoiceAllocator<N>,
+        message: MidiMessage,
+    ) -> Vec<VoiceEvent> {
+        let mut events = Vec::new();
+        allocator.process(&message, |event| events.push(event));
+        events
+    }
+
+    fn start(voice: usize, note: u8, velocity: u8) -> VoiceEvent {
+        VoiceEvent {
+            voice,
+            kind: VoiceEventKind::Start {
+                note: note.into(),
+                velocity: velocity.into(),
+            },
+        }
+    }
+
+    fn steal(voice: usize, note: u8, velocity: u8) -> VoiceEvent {
+        VoiceEvent {
+            voice,
+            kind: VoiceEventKind::Steal {
+                note: note.into(),
+                velocity: velocity.into(),
+            },
+        }
+    }
+
+    fn stop(voice: usize) -> VoiceEvent {
+        VoiceEvent {
+            voice,
+            kind: VoiceEventKind::Stop,
+        }
+    }
+
+    #[test]
+    fn should_start_and_stop_voices() {
+        let mut allocator = VoiceAllocator::<2>::new(StealPolicy::Oldest);
+
+        assert_eq!(
+            process(&mut allocator, note_on(60, 100)),
+            [start(0, 60, 100)]
+        );
+        assert_eq!(
+            process(&mut allocator, note_on(64, 100)),
+            [start(1, 64, 100)]
+        );
+        assert_eq!(process(&mut allocator, note_off(60)), [stop(0)]);
+        assert_eq!(process(&mut allocator, note_on(67, 0)), []);
+        assert_eq!(process(&mut allocator, note_on(64, 0)), [stop(1)]);
+    }
+
+    #[test]
+    fn should_steal_oldest_voice_under_full_load() {
+        let mut allocator = VoiceAllocator::<3>::new(StealPolicy::Oldest);
+        process(&mut allocator, note_on(60, 100));
+        process(&mut allocator, note_on(64, 10));
+        process(&mut allocator, note_on(67, 100));
+
+        assert_eq!(process(&mut allocator, note_on(72, 90)), [steal(0, 72, 90)]);
+        assert_eq!(process(&mut allocator, note_on(74, 90)), [steal(1, 74, 90)]);
+        assert_eq!(allocator.note(0), Some(72.into()));
+    }
+
+    #[test]
+    fn should_steal_quietest_voice_under_full_load() {
+        let mut allocator = VoiceAllocator::<3>::new(StealPolicy::Quietest);
+        process(&mut allocator, note_on(60, 100));
+        process(&mut allocator, note_on(64, 10));
+        process(&mut allocator, note_on(67, 100));
+
+        assert_eq!(process(&mut allocator, note_on(72, 90)), [steal(1, 72, 90)]);
+    }
+
+    #[test]
+    fn should_retrigger_same_note() {
+        let mut allocator = VoiceAllocator::<3>::new(StealPolicy::SameNote);
+        process(&mut allocator, note_on(60, 100));
+        process(&mut allocator, note_on(64, 100));
+
+        assert_eq!(process(&mut allocator, note_on(64, 80)), [steal(1, 64, 80)]);
+        assert_eq!(process(&mut allocator, note_on(67, 80)), [start(2, 67, 80)]);
+    }
+
+    #[test]
+    fn should_defer_release_while_sustained() {
+        let mut allocator = VoiceAllocator::<4>::new(StealPolicy::Oldest);
+        process(&mut allocator, note_on(60, 100));
+        process(&mut allocator, note_on(64, 100));
+        process(&mut allocator, note_on(67, 100));
+        process(&mut allocator, sustain(127));
+
+        assert_eq!(process(&mut allocator, note_off(60)), []);
+        assert_eq!(process(&mut allocator, note_off(67)), []);
+        assert_eq!(allocator.note(0), Some(60.into()));
+        assert_eq!(process(&mut allocator, sustain(0)), [stop(0), stop(2)]);
+        assert_eq!(allocator.note(0), None);
+        assert_eq!(process(&mut allocator, note_off(64)), [stop(1)]);
+    }
+
+    #[test]
+    fn should_steal_sustained_voices_first() {
+        let mut allocator = VoiceAllocator::<2>::new(StealPolicy::Oldest);
+        process(&mut allocator, note_on(60, 100));
+        process(&mut allocator, note_on(64, 100));
+        process(&mut allocator, sustain(127));
+        process(&mut allocator, note_off(64));
+
+        assert_eq!(
+            process(&mut allocator, note_on(67, 100)),
+            [steal(1, 67, 100)]
+        );
+    }
+
+    #[test]
+    fn should_assign_round_robin() {
+        let mut allocator = VoiceAllocator::<3>::new(StealPolicy::Oldest);
+        allocator.set_assign_mode(AssignMode::RoundRobin);
+
+        assert_eq!(
+            process(&mut allocator, note_on(60, 100)),
+            [start(0, 60, 100)]
+        );
+        process(&mut allocator, note_off(60));
+        assert_eq!(
+            process(&mut allocator, note_on(62, 100)),
+            [start(1, 62, 100)]
+        );
+        process(&mut allocator, note_off(62));
+        assert_eq!(
+            process(&mut allocator, note_on(64, 100)),
+            [start(2, 64, 100)]
+        );
+        assert_eq!(
+            process(&mut allocator, note_on(65, 100)),
+            [start(0, 65, 100)]
+        );
+    }
+}
@@ -0,0 +1,389 @@
+//! Receive and transmit state machines shared by all midi inputs and outputs
+//!
+//! `Receiver` turns bytes pushed into it into messages and `Transmitter` hands out the bytes of a
+//! message to send one at a time, neither knows where the bytes come from or go to. The frontends
+//! only move bytes: `MidiIn` and `MidiOut` to `embedded-hal-nb` serial ports, `MidiStream` to
+//! buffers, `SharedMidiOut` to its queue and `IoMidiIn` and `IoMidiOut` to `embedded-io` streams.
+
+use crate::diag::{log_debug, log_trace, log_warn};
+use crate::{family, parse, ParseEvent, RefreshPolicy, ReleasePolicy, UndefinedStatus};
+use core::task::Poll;
+
+/// Parser state of a midi input, only parsing the message families in `FAMILIES`
+#[derive(Debug)]
+pub(crate) struct Receiver<const FAMILIES: u16> {
+    parser: parse::MidiParser<FAMILIES>,
+    pub(crate) strict_system_common: bool,
+    /// Data bytes still expected by the last system common message, `None` outside of one
+    system_common_data: Option<u8>,
+    pub(crate) orphan_bytes: u32,
+    /// Skip data bytes until the next status byte, they belong to an excluded message
+    skipping: bool,
+    /// Orphan data bytes were dropped since the last status byte
+    dropping_orphans: bool,
+    pub(crate) undefined_status: UndefinedStatus,
+    /// Forget the message being received and the running status on a system reset
+    pub(crate) resync_on_reset: bool,
+}
+
+impl<const FAMILIES: u16> Receiver<FAMILIES> {
+    pub(crate) fn new() -> Self {
+        Receiver {
+            parser: parse::MidiParser::with_families(),
+            strict_system_common: true,
+            system_common_data: None,
+            orphan_bytes: 0,
+            skipping: false,
+            dropping_orphans: false,
+            undefined_status: UndefinedStatus::Drop,
+            resync_on_reset: false,
+        }
+    }
+
+    /// Parse a received byte, returns the event it completes
+    pub(crate) fn push(&mut self, byte: u8) -> Option<ParseEvent> {
+        if byte == 0xff && self.resync_on_reset {
+            self.resync();
+        }
+        if !self.accept(byte) {
+            return None;
+        }
+
+        match self.parser.parse(byte) {
+            Some(message) => {
+                log_trace!("midi in: {:?}", message);
+                Some(ParseEvent::Message(message))
+            }
+            None => match (self.undefined_status, byte) {
+                (UndefinedStatus::Report, 0xf4 | 0xf5 | 0xf9 | 0xfd) => {
+                    Some(ParseEvent::UndefinedStatus(byte))
+                }
+                _ => None,
+            },
+        }
+    }
+
+    /// Forget the message being received after bytes were lost, parsing resyncs on the next
+    /// status byte
+    pub(crate) fn resync(&mut self) {
+        self.parser = parse::MidiParser::with_families();
+        self.system_common_data = None;
+        self.skipping = false;
+    }
+
+    /// Check if a byte belongs to an included message, tracking the data bytes of system common
+    /// messages
+    fn accept(&mut self, byte: u8) -> bool {
+        if byte & 0x80 != 0 {
+            // Folded at compile time when all families are parsed
+            let included = FAMILIES == family::ALL || FAMILIES & family::of(byte) != 0;
+            // Real time messages don't interrupt the message they appear in
+            if byte < 0xf8 {
+                self.skipping = !included;
+                self.dropping_orphans = false;
+            }
+            if !included {
+                return false;
+            }
+        } else if self.skipping {
+            return false;
+        }
+
+        match byte {
+            // Real time messages can appear anywhere
+            0xf8..=0xff => true,
+            0xf1 | 0xf3 => {
+                self.system_common_data = Some(1);
+                true
+            }
+            0xf2 => {
+                self.system_common_data = Some(2);
+                true
+            }
+            0xf4..=0xf7 => {
+                self.system_common_data = Some(0);
+                true
+            }
+            0x80..=0xf0 => {
+                self.system_common_data = None;
+                true
+            }
+            _ => match self.system_common_data {
+                Some(0) if self.strict_system_common => {
+                    if !self.dropping_orphans {
+                        log_warn!("midi in: dropping data bytes after a system common message");
+                        self.dropping_orphans = true;
+                    }
+                    self.orphan_bytes = self.orphan_bytes.wrapping_add(1);
+                    false
+                }
+                Some(ref mut remaining) => {
+                    *remaining = remaining.saturating_sub(1);
+                    true
+                }
+                None => true,
+            },
+        }
+    }
+}
+
+/// Running status state for the output, tracks the last status byte sent
+#[derive(Debug, Default)]
+pub(crate) struct RunningStatus {
+    pub(crate) status: Option<u8>,
+    /// Always send the status byte
+    pub(crate) disabled: bool,
+    pub(crate) refresh: Option<RefreshPolicy>,
+    pub(crate) release: ReleasePolicy,
+    /// Number of messages written since the status byte was last sent
+    messages: u16,
+    /// Timestamp of the last message written with `write_at`
+    pub(crate) last_write_ms: Option<u32>,
+    /// Writing the last message failed part way through
+    pub(crate) interrupted: bool,
+}
+
+impl RunningStatus {
+    /// Strip the status byte from a message if running status allows it
+    fn elide<'a>(&self, bytes: &'a [u8]) -> &'a [u8] {
+        let refresh_due = match self.refresh {
+            Some(RefreshPolicy::EveryMessages(count)) => self.messages >= count,
+            _ => false,
+        };
+
+        match bytes.split_first() {
+            Some((status, data))
+                if self.status == Some(*status) && !refresh_due && !self.disabled =>
+            {
+                data
+            }
+            _ => bytes,
+        }
+    }
+
+    /// A note off rendered as a note on with velocity 0, if the release policy allows it and the
+    /// status byte can be left out that way
+    fn release(&self, bytes: &[u8]) -> Option<[u8; 3]> {
+        let default_velocity = match self.release {
+            ReleasePolicy::OptimizeRunningStatus { default_velocity } => default_velocity,
+            ReleasePolicy::PreserveNoteOff => return None,
+        };
+        match *bytes {
+            [status @ 0x80..=0x8f, note, velocity] if velocity == default_velocity => {
+                let note_on = [status | 0x10, note, 0];
+                (self.elide(&note_on).len() < note_on.len()).then_some(note_on)
+            }
+            _ => None,
+        }
+    }
+
+    /// Forget the running status after a write failed, the receiver may have got part of a
+    /// message
+    pub(crate) fn interrupt(&mut self) {
+        log_debug!("midi out: write failed, sending the next status byte");
+        self.status = None;
+        self.interrupted = true;
+    }
+
+    /// Mark the output as interrupted if `result` is an error
+    pub(crate) fn guard<T, E>(&mut self, result: Result<T, E>) -> Result<T, E> {
+        if result.is_err() {
+            self.interrupt();
+        }
+        result
+    }
+
+    /// Update the running status after a message was written
+    pub(crate) fn update(&mut self, bytes: &[u8], sent: &[u8]) {
+        self.interrupted = false;
+        match bytes.first() {
+            // Channel voice and channel mode messages use running status
+            Some(status @ 0x80..=0xef) => {
+                if sent.len() == bytes.len() {
+                    self.status = Some(*status);
+                    self.messages = 1;
+                } else {
+                    self.messages = self.messages.saturating_add(1);
+                }
+            }
+            // System common messages reset running status, real time messages leave it alone
+            Some(0xf0..=0xf7) => self.status = None,
+            _ => {}
+        }
+    }
+
+    /// Forget the running status if the line has been idle for longer than the refresh policy allows
+    pub(crate) fn tick(&mut self, now_ms: u32) {
+        if let (Some(RefreshPolicy::IdleMs(idle)), Some(last)) = (self.refresh, self.last_write_ms)
+        {
+            if now_ms.wrapping_sub(last) >= idle && self.status.take().is_some() {
+                log_debug!("midi out: running status reset after idle line");
+            }
+        }
+    }
+}
+
+/// Output state handing out the bytes of one message at a time
+///
+/// The running status is updated once the last byte of a message was pulled. A frontend that
+/// fails to send a pulled byte calls `fail`, so the next message carries its status byte.
+#[derive(Debug, Default)]
+pub(crate) struct Transmitter {
+    pub(crate) running_status: RunningStatus,
+    bytes: [u8; 3],
+    len: u8,
+    /// Index of the first byte to send, 1 when the status byte is elided
+    start: u8,
+    next: u8,
+}
+
+impl Transmitter {
+    /// Start sending a complete message, leaving out the status byte when running status allows
+    /// it, returns the number of bytes to send
+    ///
+    /// Bytes of the previous message that were not pulled are dropped.
+    pub(crate) fn load(&mut self, bytes: &[u8]) -> usize {
+        let released = self.running_status.release(bytes);
+        let bytes = released.as_ref().map_or(bytes, |bytes| &bytes[..]);
+        let sent = self.running_status.elide(bytes).len();
+        self.load_from(bytes, bytes.len() - sent)
+    }
+
+    /// Like `load`, always sending the status byte
+    pub(crate) fn load_complete(&mut self, bytes: &[u8]) -> usize {
+        self.load_from(bytes, 0)
+    }
+
+    fn load_from(&mut self, bytes: &[u8], start: usize) -> usize {
+        let len = bytes.len().min(self.bytes.len());
+        self.bytes[..len].copy_from_slice(&bytes[..len]);
+        self.len = len as u8;
+        self.start = start.min(len) as u8;
+        self.next = self.start;
+        self.pending()
+    }
+
+    /// Next byte to send, `Pending` once the message was handed out
+    pub(crate) fn pull(&mut self) -> Poll<u8> {
+        if self.next >= self.len {
+            return Poll::Pending;
+        }
+        let byte = self.bytes[self.next as usize];
+        self.next += 1;
+        if self.next == self.len {
+            let bytes = &self.bytes[..self.len as usize];
+            self.running_status
+                .update(bytes, &bytes[self.start as usize..]);
+        }
+        Poll::Ready(byte)
+    }
+
+    /// Number of bytes of the message not pulled yet
+    pub(crate) fn pending(&self) -> usize {
+        (self.len - self.next) as usize
+    }
+
+    /// Drop the rest of the message without sending it, the running status is left alone
+    pub(crate) fn clear(&mut self) {
+        self.len = 0;
+        self.next = 0;
+    }
+
+    /// Sending a pulled byte failed, the receiver may have got part of the message
+    pub(crate) fn fail(&mut self) {
+        self.clear();
+        self.running_status.interrupt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::message::{cc, note_off, note_on};
+    use crate::RenderedMessage;
+    use std::vec::Vec;
+
+    fn drain(transmitter: &mut Transmitter) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while let Poll::Ready(byte) = transmitter.pull() {
+            bytes.push(byte);
+        }
+        bytes
+    }
+
+    #[test]
+    fn should_pull_bytes_with_running_status() {
+        let mut transmitter = Transmitter::default();
+        let mut bytes = Vec::new();
+        for message in [note_on(2, 0x76, 0x34), note_on(2, 0x33, 0x65), cc(2, 7, 1)] {
+            transmitter.load(RenderedMessage::from(message).as_bytes());
+            bytes.extend(drain(&mut transmitter));
+        }
+        assert_eq!(bytes, [0x92, 0x76, 0x34, 0x33, 0x65, 0xb2, 0x07, 0x01]);
+
+        transmitter.load_complete(RenderedMessage::from(cc(2, 7, 2)).as_bytes());
+        assert_eq!(drain(&mut transmitter), [0xb2, 0x07, 0x02]);
+    }
+
+    #[test]
+    fn should_update_running_status_only_for_sent_messages() {
+        let mut transmitter = Transmitter::default();
+        let first = RenderedMessage::from(note_on(0, 60, 100));
+        transmitter.load(first.as_bytes());
+        assert_eq!(transmitter.pull(), Poll::Ready(0x90));
+        transmitter.fail();
+        assert_eq!(transmitter.pull(), Poll::Pending);
+        assert!(transmitter.running_status.interrupted);
+
+        assert_eq!(transmitter.load(first.as_bytes()), 3);
+        transmitter.clear();
+        assert_eq!(transmitter.load(first.as_bytes()), 3);
+        assert_eq!(drain(&mut transmitter).len(), 3);
+        assert_eq!(transmitter.load(first.as_bytes()), 2);
+        assert!(!transmitter.running_status.interrupted);
+    }
+
+    #[test]
+    fn should_only_turn_default_note_offs_into_elided_note_ons() {
+        let mut transmitter = Transmitter::default();
+        transmitter.running_status.release = ReleasePolicy::OptimizeRunningStatus {
+            default_velocity: 64,
+        };
+        let mut bytes = Vec::new();
+        for message in [
+            note_off(0, 60, 64),
+            note_on(0, 60, 100),
+            note_off(0, 60, 64),
+            note_off(0, 62, 30),
+            note_off(1, 62, 64),
+            note_on(0, 64, 100),
+            note_off(0, 64, 0),
+        ] {
+            transmitter.load(RenderedMessage::from(message).as_bytes());
+            bytes.extend(drain(&mut transmitter));
+        }
+        assert_eq!(
+            bytes,
+            [
+                0x80, 60, 64, 0x90, 60, 100, 60, 0, 0x80, 62, 30, 0x81, 62, 64, 0x90, 64, 100,
+                0x80, 64, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn should_resync_receiver_on_next_status_byte() {
+        let mut receiver = Receiver::<{ family::ALL }>::new();
+        assert_eq!(receiver.push(0x90), None);
+        assert_eq!(receiver.push(0x40), None);
+        receiver.resync();
+        assert_eq!(receiver.push(0x7f), None);
+        assert_eq!(receiver.push(0x91), None);
+        assert_eq!(receiver.push(0x40), None);
+        assert_eq!(
+            receiver.push(0x7f),
+            Some(ParseEvent::Message(note_on(1, 0x40, 0x7f)))
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Golden tests replaying the byte streams in `tests/corpus`
+//!
+//! Every `<name>.bin` stream is parsed like `MidiIn` does and the messages and system exclusive
+//! messages it contains are compared with the reviewed list in `<name>.txt`. The messages are then
+//! rendered with running status and parsed again, which has to give the same messages.
+//!
+//! After a deliberate change in parsing, regenerate the lists with `UPDATE_CORPUS=1 cargo test
+//! --test corpus` and review the changes before checking them in.
+
+use embedded_midi::midi_types::MidiMessage;
+use embedded_midi::{ChannelExt, MidiStream, SysexHandler, SysexStream};
+
+struct Fixture {
+    name: &'static str,
+    bytes: &'static [u8],
+    expected: &'static str,
+}
+
+macro_rules! fixture {
+    ($name:literal) => {
+        Fixture {
+            name: $name,
+            bytes: include_bytes!(concat!("corpus/", $name, ".bin")),
+            expected: include_str!(concat!("corpus/", $name, ".txt")),
+        }
+    };
+}
+
+const CORPUS: &[Fixture] = &[
+    fixture!("daw_session"),
+    fixture!("mpe_controller"),
+    fixture!("sysex_dump"),
+    fixture!("running_status_abuse"),
+];
+
+/// One line of the expected list, with values as they appear on the wire and channels from 1
+fn describe(message: &MidiMessage) -> String {
+    match *message {
+        MidiMessage::NoteOff(channel, note, velocity) => format!(
+            "note-off {} {} {}",
+            channel.display(),
+            u8::from(note),
+            u8::from(velocity)
+        ),
+        MidiMessage::NoteOn(channel, note, velocity) => format!(
+            "note-on {} {} {}",
+            channel.display(),
+            u8::from(note),
+            u8::from(velocity)
+        ),
+        MidiMessage::KeyPressure(channel, note, value) => format!(
+            "key-pressure {} {} {}",
+            channel.display(),
+            u8::from(note),
+            u8::from(value)
+        ),
+        MidiMessage::ControlChange(channel, control, value) => format!(
+            "cc {} {} {}",
+            channel.display(),
+            u8::from(control),
+            u8::from(value)
+        ),
+        MidiMessage::ProgramChange(channel, program) => {
+            format!("program {} {}", channel.display(), u8::from(program))
+        }
+        MidiMessage::ChannelPressure(channel, value) => {
+            format!("channel-pressure {} {}", channel.display(), u8::from(value))
+        }
+        MidiMessage::PitchBendChange(channel, value) => {
+            format!("pitch-bend {} {}", channel.display(), u16::from(value))
+        }
+        MidiMessage::QuarterFrame(frame) => format!("quarter-frame {:#04x}", u8::from(frame)),
+        MidiMessage::SongPositionPointer(position) => {
+            format!("song-position {}", u16::from(position))
+        }
+        MidiMessage::SongSelect(song) => format!("song-select {}", u8::from(song)),
+        MidiMessage::TuneRequest => "tune-request".into(),
+        MidiMessage::TimingClock => "clock".into(),
+        MidiMessage::Start => "start".into(),
+        MidiMessage::Continue => "continue".into(),
+        MidiMessage::Stop => "stop".into(),
+        MidiMessage::ActiveSensing => "active-sensing".into(),
+        MidiMessage::Reset => "reset".into(),
+    }
+}
+
+/// Adds a line for every complete or aborted system exclusive message
+#[derive(Default)]
+struct SysexLines {
+    data: Vec<u8>,
+    lines: Vec<String>,
+}
+
+impl SysexHandler for SysexLines {
+    fn on_sysex_chunk(&mut self, chunk: &[u8], first: bool, last: bool) {
+        if first {
+            self.data.clear();
+        }
+        self.data.extend_from_slice(chunk);
+        if last {
+            let hex: Vec<_> = self
+                .data
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            self.lines.push(format!("sysex {}", hex.join(" ")));
+        }
+    }
+
+    fn on_sysex_abort(&mut self) {
+        self.lines
+            .push(format!("sysex-abort after {} bytes", self.data.len()));
+    }
+}
+
+/// Parse a stream into messages and the lines describing them
+fn replay(bytes: &[u8]) -> (Vec<MidiMessage>, Vec<String>) {
+    let mut stream = MidiStream::new();
+    let mut sysex = SysexStream::<16>::new();
+    let mut handler = SysexLines::default();
+    let mut messages = Vec::new();
+    for byte in bytes {
+        sysex.feed(0, *byte, &mut handler);
+        handler.lines.extend(stream.push_byte(*byte).map(|message| {
+            messages.push(message);
+            describe(&message)
+        }));
+    }
+    (messages, handler.lines)
+}
+
+/// Lines of an expected list, leaving out blank lines and `#` comments
+fn expected_lines(expected: &str) -> Vec<&str> {
+    expected
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect()
+}
+
+/// Panic showing the lines around the first difference, if there is one
+fn assert_lines_eq(name: &str, expected: &[&str], actual: &[String]) {
+    let first_difference = expected
+        .iter()
+        .zip(actual)
+        .position(|(expected, actual)| *expected != actual)
+        .or_else(|| (expected.len() != actual.len()).then(|| expected.len().min(actual.len())));
+    let index = match first_difference {
+        Some(index) => index,
+        None => return,
+    };
+
+    let context = |lines: Vec<&str>| {
+        let start = index.saturating_sub(3);
+        let end = (index + 4).min(lines.len());
+        let mut text = String::new();
+        for (number, line) in lines.iter().enumerate().take(end).skip(start) {
+            let marker = if number == index { ">" } else { " " };
+            text += &format!("{} {:4} {}\n", marker, number + 1, line);
+        }
+        if index >= lines.len() {
+            text += &format!(">      <end after {} lines>\n", lines.len());
+        }
+        text
+    };
+    panic!(
+        "{}: message {} differs\nexpected:\n{}actual:\n{}\
+         run with UPDATE_CORPUS=1 to regenerate the list after a deliberate change",
+        name,
+        index + 1,
+        context(expected.to_vec()),
+        context(actual.iter().map(String::as_str).collect()),
+    );
+}
+
+/// Render messages with running status the way `MidiOut` does
+fn render(messages: &[MidiMessage]) -> Vec<u8> {
+    let mut stream = MidiStream::new();
+    let mut bytes = Vec::new();
+    for message in messages {
+        let mut buffer = [0; 3];
+        let len = stream.render_to(message, &mut buffer).unwrap();
+        bytes.extend_from_slice(&buffer[..len]);
+    }
+    bytes
+}
+
+#[test]
+fn should_parse_corpus_as_reviewed() {
+    let update = std::env::var_os("UPDATE_CORPUS").is_some();
+    for fixture in CORPUS {
+        let (_, lines) = replay(fixture.bytes);
+        if update {
+            let path = format!(
+                "{}/tests/corpus/{}.txt",
+                env!("CARGO_MANIFEST_DIR"),
+                fixture.name
+            );
+            std::fs::write(path, lines.join("\n") + "\n").unwrap();
+        } else {
+            assert_lines_eq(fixture.name, &expected_lines(fixture.expected), &lines);
+        }
+    }
+}
+
+#[test]
+fn should_parse_rerendered_corpus_to_same_messages() {
+    for fixture in CORPUS {
+        let (messages, _) = replay(fixture.bytes);
+        let (reparsed, _) = replay(&render(&messages));
+        let expected: Vec<_> = messages.iter().map(describe).collect();
+        let actual: Vec<_> = reparsed.iter().map(describe).collect();
+        let expected: Vec<_> = expected.iter().map(String::as_str).collect();
+        assert_lines_eq(fixture.name, &expected, &actual);
+    }
+}
+
+#[test]
+fn should_show_context_of_first_difference() {
+    let actual: Vec<_> = ["clock", "start", "note-on 1 60 100", "stop"]
+        .iter()
+        .map(|line| line.to_string())
+        .collect();
+    let message = std::panic::catch_unwind(|| {
+        assert_lines_eq(
+            "test",
+            &["clock", "start", "note-on 1 60 90", "stop"],
+            &actual,
+        )
+    })
+    .unwrap_err();
+    let message = message.downcast_ref::<String>().unwrap();
+    assert!(message.contains("message 3 differs"));
+    assert!(message.contains(">    3 note-on 1 60 90"));
+    assert!(message.contains(">    3 note-on 1 60 100"));
+
+    assert!(std::panic::catch_unwind(|| assert_lines_eq("test", &["clock"], &actual)).is_err());
+    assert_lines_eq(
+        "test",
+        &["clock", "start", "note-on 1 60 100", "stop"],
+        &actual,
+    );
+}
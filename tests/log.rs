@@ -0,0 +1,71 @@
+//! Diagnostic records emitted with the `log` feature
+#![cfg(feature = "log")]
+
+use core::convert::Infallible;
+use embedded_hal_nb::serial;
+use embedded_midi::midi_types::MidiMessage;
+use embedded_midi::MidiIn;
+use log::{Level, Log, Metadata, Record};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Logger keeping the level and text of every record
+struct Capture(Mutex<Vec<(Level, String)>>);
+
+impl Log for Capture {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        assert_eq!(record.target(), "embedded_midi");
+        let text = record.args().to_string();
+        self.0.lock().unwrap().push((record.level(), text));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Serial port receiving the given bytes
+struct Input(VecDeque<u8>);
+
+impl serial::ErrorType for Input {
+    type Error = Infallible;
+}
+
+impl serial::Read<u8> for Input {
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        self.0.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+#[test]
+fn should_warn_once_about_dropped_data_bytes() {
+    log::set_logger(&CAPTURE).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+
+    // Data bytes following a tune request belong to no message
+    let bytes = [0x92, 0x40, 0x10, 0xf6, 0x41, 0x11, 0x42, 0x93, 0x40, 0x10];
+    let mut midi_in = MidiIn::new(Input(bytes.iter().copied().collect()));
+    let received: Vec<MidiMessage> = bytes.iter().filter_map(|_| midi_in.read().ok()).collect();
+    assert_eq!(received.len(), 3);
+    assert_eq!(midi_in.orphan_bytes(), 3);
+
+    let records = CAPTURE.0.lock().unwrap();
+    let warnings: Vec<_> = records
+        .iter()
+        .filter(|(level, _)| *level == Level::Warn)
+        .collect();
+    assert_eq!(warnings.len(), 1, "{:?}", records);
+    assert!(warnings[0].1.contains("dropping data bytes"));
+    // Every message is traced
+    assert_eq!(
+        records
+            .iter()
+            .filter(|(level, _)| *level == Level::Trace)
+            .count(),
+        3
+    );
+}
@@ -0,0 +1,97 @@
+//! Downmixing the recorded MPE phrase in `tests/corpus/mpe_controller.bin` for a synth without MPE
+
+use embedded_midi::midi_types::{Channel, MidiMessage};
+use embedded_midi::{BendPolicy, MidiProcessor, MidiStream, MpeDownmix, MpeZone};
+
+const PHRASE: &[u8] = include_bytes!("corpus/mpe_controller.bin");
+
+fn downmix(policy: BendPolicy) -> Vec<MidiMessage> {
+    let mut stream = MidiStream::new();
+    let mut downmix = MpeDownmix::new(MpeZone::Lower { members: 15 });
+    downmix.set_bend_policy(policy);
+    // The phrase bends its member channels by 2 semitones
+    downmix.set_bend_ranges(2, 2);
+    let mut output = Vec::new();
+    for byte in PHRASE {
+        if let Some(message) = stream.push_byte(*byte) {
+            downmix.process(message, &mut |message| output.push(message));
+        }
+    }
+    output
+}
+
+fn channel(message: &MidiMessage) -> Option<Channel> {
+    match *message {
+        MidiMessage::NoteOff(channel, ..)
+        | MidiMessage::NoteOn(channel, ..)
+        | MidiMessage::KeyPressure(channel, ..)
+        | MidiMessage::ControlChange(channel, ..)
+        | MidiMessage::ProgramChange(channel, ..)
+        | MidiMessage::ChannelPressure(channel, ..)
+        | MidiMessage::PitchBendChange(channel, ..) => Some(channel),
+        _ => None,
+    }
+}
+
+/// Every note off releases a sounding note and no note is left sounding
+fn assert_notes_balanced(messages: &[MidiMessage]) {
+    let mut sounding = Vec::new();
+    for message in messages {
+        match *message {
+            MidiMessage::NoteOn(_, note, velocity) if u8::from(velocity) > 0 => {
+                assert!(!sounding.contains(&note), "{:?} played twice", note);
+                sounding.push(note);
+            }
+            MidiMessage::NoteOn(_, note, _) | MidiMessage::NoteOff(_, note, _) => {
+                let index = sounding.iter().position(|held| *held == note);
+                sounding.remove(index.expect("note off without note on"));
+            }
+            _ => {}
+        }
+    }
+    assert!(sounding.is_empty(), "{:?} left sounding", sounding);
+}
+
+#[test]
+fn should_downmix_phrase_onto_master_channel() {
+    for policy in [BendPolicy::DropMemberBends, BendPolicy::FollowLastNote] {
+        let output = downmix(policy);
+        assert!(output
+            .iter()
+            .filter_map(channel)
+            .all(|channel| channel == Channel::C1));
+        assert_notes_balanced(&output);
+        // Member pressure became key pressure, nothing is left of timbre and channel pressure
+        assert!(output.iter().any(|message| matches!(
+            message,
+            MidiMessage::KeyPressure(_, note, value)
+                if u8::from(*note) == 67 && u8::from(*value) == 62
+        )));
+        assert!(!output
+            .iter()
+            .any(|message| matches!(message, MidiMessage::ChannelPressure(..))));
+        assert!(!output.iter().any(|message| matches!(
+            message,
+            MidiMessage::ControlChange(_, control, _) if u8::from(*control) == 74
+        )));
+    }
+}
+
+#[test]
+fn should_drop_or_follow_member_bends() {
+    let bends = |output: Vec<MidiMessage>| -> Vec<u16> {
+        output
+            .iter()
+            .filter_map(|message| match message {
+                MidiMessage::PitchBendChange(_, value) => Some(u16::from(*value)),
+                _ => None,
+            })
+            .collect()
+    };
+    assert_eq!(bends(downmix(BendPolicy::DropMemberBends)), []);
+    // The bend of each new note, then only the bends of the last note on channel 4
+    assert_eq!(
+        bends(downmix(BendPolicy::FollowLastNote)),
+        [8483, 8646, 8809, 9065, 9406, 9747, 10088]
+    );
+}
@@ -0,0 +1,140 @@
+//! Property based round-trip tests, rendering messages through `MidiOut` and parsing them back
+
+use core::convert::Infallible;
+use embedded_hal_nb::serial;
+use embedded_midi::midi_types::{MidiMessage, Value14};
+use embedded_midi::{MidiIn, MidiOut, Value14Ext};
+use midi_convert::parse::MidiParser;
+use proptest::prelude::*;
+
+/// Serial port that collects all written bytes and reads them back
+#[derive(Debug, Default)]
+struct Wire(Vec<u8>);
+
+impl serial::ErrorType for Wire {
+    type Error = Infallible;
+}
+
+impl serial::Read<u8> for Wire {
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        if self.0.is_empty() {
+            Err(nb::Error::WouldBlock)
+        } else {
+            Ok(self.0.remove(0))
+        }
+    }
+}
+
+impl serial::Write<u8> for Wire {
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.0.push(word);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+fn parse_all(bytes: &[u8]) -> Vec<MidiMessage> {
+    let mut parser = MidiParser::new();
+    bytes
+        .iter()
+        .filter_map(|byte| parser.parse(*byte))
+        .collect()
+}
+
+/// Parse with the parser of `MidiIn`
+fn read_all(bytes: &[u8]) -> Vec<MidiMessage> {
+    let mut midi_in = MidiIn::new(Wire(bytes.to_vec()));
+    bytes.iter().filter_map(|_| midi_in.read().ok()).collect()
+}
+
+fn message() -> impl Strategy<Value = MidiMessage> {
+    let channel = 0..=15u8;
+    let data = || 0..=127u8;
+
+    prop_oneof![
+        (channel.clone(), data(), data()).prop_map(|(c, n, v)| MidiMessage::NoteOff(
+            c.into(),
+            n.into(),
+            v.into()
+        )),
+        (channel.clone(), data(), data()).prop_map(|(c, n, v)| MidiMessage::NoteOn(
+            c.into(),
+            n.into(),
+            v.into()
+        )),
+        (channel.clone(), data(), data()).prop_map(|(c, n, v)| MidiMessage::KeyPressure(
+            c.into(),
+            n.into(),
+            v.into()
+        )),
+        (channel.clone(), data(), data()).prop_map(|(c, n, v)| MidiMessage::ControlChange(
+            c.into(),
+            n.into(),
+            v.into()
+        )),
+        (channel.clone(), data()).prop_map(|(c, p)| MidiMessage::ProgramChange(c.into(), p.into())),
+        (channel.clone(), data())
+            .prop_map(|(c, v)| MidiMessage::ChannelPressure(c.into(), v.into())),
+        (channel, data(), data()).prop_map(|(c, m, l)| MidiMessage::PitchBendChange(
+            c.into(),
+            Value14::from_msb_lsb(m, l)
+        )),
+        data().prop_map(|v| MidiMessage::QuarterFrame(v.into())),
+        (data(), data())
+            .prop_map(|(m, l)| MidiMessage::SongPositionPointer(Value14::from_msb_lsb(m, l))),
+        data().prop_map(|v| MidiMessage::SongSelect(v.into())),
+        Just(MidiMessage::TuneRequest),
+        Just(MidiMessage::TimingClock),
+        Just(MidiMessage::Start),
+        Just(MidiMessage::Continue),
+        Just(MidiMessage::Stop),
+        Just(MidiMessage::ActiveSensing),
+        Just(MidiMessage::Reset),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn rendered_messages_parse_back(messages in prop::collection::vec(message(), 0..64)) {
+        let mut wire = Wire::default();
+        let mut midi_out = MidiOut::new(&mut wire);
+        for message in &messages {
+            midi_out.write(message).unwrap();
+        }
+
+        prop_assert_eq!(&parse_all(&wire.0), &messages);
+        prop_assert_eq!(read_all(&wire.0), messages);
+    }
+
+    #[test]
+    fn parser_resynchronizes_after_garbage(
+        garbage in prop::collection::vec(any::<u8>(), 0..128),
+        message in message(),
+    ) {
+        let mut parser = MidiParser::new();
+        for byte in &garbage {
+            parser.parse(*byte);
+        }
+
+        let mut wire = Wire::default();
+        MidiOut::new(&mut wire).write(&message).unwrap();
+        let parsed: Vec<_> = wire.0.iter().filter_map(|byte| parser.parse(*byte)).collect();
+
+        prop_assert_eq!(parsed, vec![message]);
+    }
+
+    #[test]
+    fn midi_in_resynchronizes_after_garbage(
+        garbage in prop::collection::vec(any::<u8>(), 0..128),
+        message in message(),
+    ) {
+        let mut wire = Wire(garbage);
+        MidiOut::new(&mut wire).write(&message).unwrap();
+        let received = read_all(&wire.0);
+
+        prop_assert_eq!(received.last(), Some(&message));
+    }
+}
@@ -0,0 +1,374 @@
+//! End to end scenarios connecting devices over a simulated wire with byte level fault injection
+
+use core::cell::{Cell, RefCell};
+use core::convert::Infallible;
+use embedded_hal_nb::serial;
+use embedded_midi::midi_types::{Channel, MidiMessage, Note, Value14};
+use embedded_midi::{MidiIn, MidiOut, NoteTracker, RefreshPolicy, SoftThru};
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// Byte level faults, positions count the bytes written to the wire
+#[derive(Debug, Clone, Copy, Default)]
+struct Faults {
+    /// Drop every `n`th byte
+    drop_every: Option<usize>,
+    /// Drop the byte at this position
+    drop_at: Option<usize>,
+    /// Deliver the byte at this position twice
+    duplicate_at: Option<usize>,
+    /// Deliver a random data byte before the byte at this position
+    inject_at: Option<usize>,
+    /// Ticks between writing a byte and the earliest tick it can be read
+    delay: u32,
+}
+
+/// A serial line carrying one byte per tick
+#[derive(Debug)]
+struct Wire {
+    clock: Rc<Cell<u32>>,
+    faults: Faults,
+    /// Bytes on the wire with the tick they can be read at
+    in_flight: VecDeque<(u32, u8)>,
+    last_ready: Option<u32>,
+    written: usize,
+    random: u32,
+}
+
+impl Wire {
+    fn send(&mut self, byte: u8) {
+        let now = self.clock.get() + self.faults.delay;
+        let ready = self.last_ready.map_or(now, |last| now.max(last + 1));
+        self.last_ready = Some(ready);
+        self.in_flight.push_back((ready, byte));
+    }
+
+    /// Xorshift, so injected bytes are the same on every run
+    fn random_data_byte(&mut self) -> u8 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        (self.random & 0x7f) as u8
+    }
+
+    fn write(&mut self, byte: u8) {
+        let position = self.written;
+        self.written += 1;
+        let faults = self.faults;
+
+        if faults.inject_at == Some(position) {
+            let injected = self.random_data_byte();
+            self.send(injected);
+        }
+        let dropped = faults.drop_at == Some(position)
+            || faults.drop_every.is_some_and(|n| (position + 1) % n == 0);
+        if !dropped {
+            self.send(byte);
+        }
+        if faults.duplicate_at == Some(position) {
+            self.send(byte);
+        }
+    }
+
+    /// A byte can be read
+    fn is_ready(&self) -> bool {
+        matches!(self.in_flight.front(), Some(&(ready, _)) if ready <= self.clock.get())
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        match self.in_flight.front() {
+            Some(&(ready, byte)) if ready <= self.clock.get() => {
+                self.in_flight.pop_front();
+                Some(byte)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct WireTx(Rc<RefCell<Wire>>);
+
+#[derive(Debug)]
+struct WireRx(Rc<RefCell<Wire>>);
+
+impl serial::ErrorType for WireTx {
+    type Error = Infallible;
+}
+
+impl serial::Write<u8> for WireTx {
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.0.borrow_mut().write(word);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl serial::ErrorType for WireRx {
+    type Error = Infallible;
+}
+
+impl serial::Read<u8> for WireRx {
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        self.0.borrow_mut().read().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+/// Wire with both ends, the wire itself is returned to check for bytes that can be read
+fn wire(clock: &Rc<Cell<u32>>, faults: Faults) -> (Rc<RefCell<Wire>>, WireTx, WireRx) {
+    let wire = Rc::new(RefCell::new(Wire {
+        clock: clock.clone(),
+        faults,
+        in_flight: VecDeque::new(),
+        last_ready: None,
+        written: 0,
+        random: 0x2545_f491,
+    }));
+    (wire.clone(), WireTx(wire.clone()), WireRx(wire))
+}
+
+/// Read all bytes that arrived, returning the messages they complete
+///
+/// `read` also blocks after bytes that don't complete a message, so the wire is checked instead.
+fn drain(wire: &RefCell<Wire>, midi_in: &mut MidiIn<WireRx>) -> Vec<MidiMessage> {
+    let mut received = Vec::new();
+    while wire.borrow().is_ready() {
+        if let Ok(message) = midi_in.read() {
+            received.push(message);
+        }
+    }
+    received
+}
+
+/// Send all messages, then read everything that arrives
+fn transfer(
+    faults: Faults,
+    refresh: Option<RefreshPolicy>,
+    messages: &[MidiMessage],
+) -> Vec<MidiMessage> {
+    let clock = Rc::new(Cell::new(0));
+    let (line, tx, rx) = wire(&clock, faults);
+    let mut midi_out = MidiOut::new(tx);
+    midi_out.set_status_refresh(refresh);
+    let mut midi_in = MidiIn::new(rx);
+
+    let mut received = Vec::new();
+    for message in messages {
+        midi_out.write(message).unwrap();
+        clock.set(clock.get() + 3);
+        received.extend(drain(&line, &mut midi_in));
+    }
+    clock.set(u32::MAX);
+    received.extend(drain(&line, &mut midi_in));
+    received
+}
+
+fn note_on(channel: u8, note: u8, velocity: u8) -> MidiMessage {
+    MidiMessage::NoteOn(channel.into(), note.into(), velocity.into())
+}
+
+fn note_off(channel: u8, note: u8) -> MidiMessage {
+    MidiMessage::NoteOff(channel.into(), note.into(), 0.into())
+}
+
+/// Notes on one channel, all sent with running status
+fn notes(count: u8) -> Vec<MidiMessage> {
+    (0..count).map(|i| note_on(0, 0x20 + i, 1 + i)).collect()
+}
+
+#[test]
+fn should_pass_messages_unchanged() {
+    let messages = [
+        note_on(0, 60, 100),
+        note_on(0, 64, 100),
+        MidiMessage::TimingClock,
+        note_on(0, 67, 100),
+        MidiMessage::ControlChange(Channel::C2, 7.into(), 90.into()),
+        MidiMessage::ControlChange(Channel::C2, 10.into(), 0.into()),
+        MidiMessage::PitchBendChange(Channel::C2, Value14::from(0x1234u16)),
+        MidiMessage::ProgramChange(Channel::C3, 12.into()),
+        MidiMessage::ChannelPressure(Channel::C3, 80.into()),
+        MidiMessage::KeyPressure(Channel::C3, Note::C4, 70.into()),
+        MidiMessage::SongPositionPointer(Value14::from(0x0555u16)),
+        MidiMessage::QuarterFrame(0x23.into()),
+        MidiMessage::Start,
+        note_off(0, 60),
+        note_on(0, 64, 0),
+        MidiMessage::Stop,
+    ];
+
+    for delay in [0, 1, 7] {
+        let faults = Faults {
+            delay,
+            ..Faults::default()
+        };
+        assert_eq!(transfer(faults, None, &messages), messages);
+    }
+}
+
+#[test]
+fn should_only_produce_sent_kinds_of_messages_when_bytes_are_dropped() {
+    for n in 2..10 {
+        let faults = Faults {
+            drop_every: Some(n),
+            ..Faults::default()
+        };
+        let received = transfer(faults, None, &notes(60));
+
+        let sent_kind =
+            |message: &MidiMessage| matches!(message, MidiMessage::NoteOn(Channel::C1, _, _));
+        assert!(
+            received.iter().all(sent_kind),
+            "dropping every {}th byte produced {:?}",
+            n,
+            received
+        );
+    }
+}
+
+#[test]
+fn should_resync_within_refresh_interval_after_corruption() {
+    const REFRESH: u16 = 4;
+    let sent = notes(40);
+
+    for position in 0..20 {
+        let corruptions = [
+            Faults {
+                drop_at: Some(position),
+                ..Faults::default()
+            },
+            Faults {
+                duplicate_at: Some(position),
+                ..Faults::default()
+            },
+            Faults {
+                inject_at: Some(position),
+                ..Faults::default()
+            },
+        ];
+        for faults in corruptions {
+            let received = transfer(faults, Some(RefreshPolicy::EveryMessages(REFRESH)), &sent);
+
+            // Every message takes at least 2 bytes, so the corrupted byte belongs to one of the
+            // first 10 messages, the next status byte follows within the refresh interval
+            let intact = &sent[10 + REFRESH as usize..];
+            assert!(
+                received.ends_with(intact),
+                "{:?} received {:?}",
+                faults,
+                received
+            );
+        }
+    }
+}
+
+#[test]
+fn should_keep_clock_regular_through_soft_thru() {
+    const CLOCK_INTERVAL: u32 = 10;
+    let clock = Rc::new(Cell::new(0));
+    let (source_line, source_tx, thru_rx) = wire(&clock, Faults::default());
+    let (sink_line, thru_tx, sink_rx) = wire(&clock, Faults::default());
+    let mut source = MidiOut::new(source_tx);
+    let mut thru = SoftThru::<_, _>::new(thru_rx, thru_tx);
+    let mut sink = MidiIn::new(sink_rx);
+
+    let mut clock_ticks = Vec::new();
+    for tick in 0..400 {
+        clock.set(tick);
+        if tick % CLOCK_INTERVAL == 0 {
+            source.write(&MidiMessage::TimingClock).unwrap();
+        } else if tick % 4 == 0 {
+            source.write(&note_on(1, (tick % 100) as u8, 100)).unwrap();
+        }
+
+        while source_line.borrow().is_ready() {
+            let _ = thru.read();
+        }
+        let received = drain(&sink_line, &mut sink);
+        if received.contains(&MidiMessage::TimingClock) {
+            clock_ticks.push(tick);
+        }
+    }
+
+    assert_eq!(clock_ticks.len(), 40);
+    let intervals: Vec<u32> = clock_ticks
+        .windows(2)
+        .map(|ticks| ticks[1] - ticks[0])
+        .collect();
+    // A clock may wait behind one message queued on the wire before it
+    assert!(
+        intervals
+            .iter()
+            .all(|interval| interval.abs_diff(CLOCK_INTERVAL) <= 6),
+        "clock intervals {:?}",
+        intervals
+    );
+}
+
+/// Releases held notes when active sensing stops, like a receiver should when the cable is pulled
+#[derive(Debug)]
+struct Watchdog {
+    timeout: u32,
+    armed: bool,
+    last_message: u32,
+}
+
+impl Watchdog {
+    fn receive(&mut self, now: u32, message: &MidiMessage) {
+        self.armed |= *message == MidiMessage::ActiveSensing;
+        self.last_message = now;
+    }
+
+    fn expired(&mut self, now: u32) -> bool {
+        let expired = self.armed && now - self.last_message > self.timeout;
+        if expired {
+            self.armed = false;
+        }
+        expired
+    }
+}
+
+#[test]
+fn should_release_notes_after_dropped_note_off_when_sender_goes_silent() {
+    let clock = Rc::new(Cell::new(0));
+    let faults = Faults {
+        // The velocity of the note off
+        drop_at: Some(6),
+        ..Faults::default()
+    };
+    let (line, tx, rx) = wire(&clock, faults);
+    let mut midi_out = MidiOut::new(tx);
+    let mut midi_in = MidiIn::new(rx);
+    let mut tracker = NoteTracker::new();
+    let mut watchdog = Watchdog {
+        timeout: 300,
+        armed: false,
+        last_message: 0,
+    };
+
+    midi_out.write(&MidiMessage::ActiveSensing).unwrap();
+    midi_out.write(&note_on(0, 60, 100)).unwrap();
+    midi_out.write(&note_off(0, 60)).unwrap();
+
+    let mut released = Vec::new();
+    for tick in 0..1000 {
+        clock.set(tick);
+        // The sender keeps the line alive for a while, then goes silent
+        if tick % 100 == 99 && tick < 400 {
+            midi_out.write(&MidiMessage::ActiveSensing).unwrap();
+        }
+        for message in drain(&line, &mut midi_in) {
+            tracker.track(&message);
+            watchdog.receive(tick, &message);
+        }
+        if watchdog.expired(tick) {
+            tracker.release_all(&mut |message| released.push((tick, message)));
+        }
+    }
+
+    assert_eq!(tracker.held_count(), 0);
+    assert_eq!(released, [(700, note_off(0, 60))]);
+}